@@ -0,0 +1,22 @@
+use std::path::Path;
+use std::process::Command;
+
+// Tell the binary which commit it was built from (CAPSTONE_GIT_DESCRIBE, see
+// runinfo::tool_version). Outside a git checkout, or without git, it is left
+// unset and only the package version is reported.
+fn main() {
+    for path in ["../.git/HEAD", "../.git/index"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    let describe = Command::new("git")
+        .args(["describe", "--always", "--dirty", "--tags"])
+        .output();
+    if let Ok(output) = describe {
+        let describe = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if output.status.success() && !describe.is_empty() {
+            println!("cargo:rustc-env=CAPSTONE_GIT_DESCRIBE={}", describe);
+        }
+    }
+}
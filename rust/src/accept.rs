@@ -0,0 +1,249 @@
+use crate::btc;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::fees;
+use bitcoincore_rpc::bitcoin::{Amount, Txid, Wtxid};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+
+// One entry of the testmempoolaccept response. The rpc crate's version drops the
+// wtxid, the package fields and the effective feerate, so we use our own.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MempoolAcceptResult {
+    pub txid: Txid,
+    pub wtxid: Option<Wtxid>,
+    // Set instead of `allowed` when a whole package fails validation
+    #[serde(rename = "package-error")]
+    pub package_error: Option<String>,
+    pub allowed: Option<bool>,
+    pub vsize: Option<u64>,
+    pub fees: Option<MempoolAcceptFees>,
+    #[serde(rename = "reject-reason")]
+    pub reject_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MempoolAcceptFees {
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub base: Amount,
+    // BTC/kvB, only reported by Core 25 and later
+    #[serde(
+        default,
+        rename = "effective-feerate",
+        with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc::opt"
+    )]
+    pub effective_feerate: Option<Amount>,
+}
+
+impl MempoolAcceptResult {
+    pub fn is_allowed(&self) -> bool {
+        self.allowed == Some(true)
+    }
+
+    pub fn rejection(&self) -> String {
+        self.reject_reason
+            .clone()
+            .or_else(|| self.package_error.clone())
+            .unwrap_or_else(|| "unknown reason".to_string())
+    }
+
+    pub fn to_error(&self) -> CapstoneError {
+        CapstoneError::MempoolRejected {
+            txid: self.txid,
+            reason: self.rejection(),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        if !self.is_allowed() {
+            return format!("{}: rejected ({})", self.txid, self.rejection());
+        }
+
+        let mut line = format!("{}: accepted", self.txid);
+        if let Some(vsize) = self.vsize {
+            line.push_str(&format!(", vsize {} vB", vsize));
+        }
+        if let Some(fees) = &self.fees {
+            line.push_str(&format!(", fee {}", btc::amount(fees.base)));
+            if let Some(rate) = fees.effective_feerate {
+                line.push_str(&format!(
+                    ", effective feerate {} BTC/kvB",
+                    btc::format_btc(rate)
+                ));
+            }
+        }
+        line
+    }
+}
+
+// Run testmempoolaccept on one transaction or a package of dependent ones
+// (parents first). Nothing is broadcast. `max_fee_rate` (BTC/kvB, 0 for no
+// limit) should match what the broadcast will use.
+pub fn test_mempool_accept(
+    rpc: &Client,
+    raw_txs: &[String],
+    max_fee_rate: Amount,
+) -> Result<Vec<MempoolAcceptResult>> {
+    Ok(rpc.call(
+        "testmempoolaccept",
+        &[json!(raw_txs), json!(max_fee_rate.to_btc())],
+    )?)
+}
+
+// Validate before broadcast and turn a rejection into an error naming the reason.
+pub fn ensure_accepted(
+    rpc: &Client,
+    raw_txs: &[String],
+    max_fee_rate: Amount,
+) -> Result<Vec<MempoolAcceptResult>> {
+    let results = test_mempool_accept(rpc, raw_txs, max_fee_rate)?;
+    for result in &results {
+        say!("testmempoolaccept {}", result.describe());
+    }
+
+    if let Some((i, rejected)) = first_rejected(&results) {
+        // Say by how much the cap was exceeded, the reject reason alone doesn't
+        if rejected.reject_reason.as_deref() == Some("max-fee-exceeded") {
+            if let Some(hex) = raw_txs.get(i) {
+                let (txid, fee_rate) = fees::transaction_fee_rate(rpc, hex)?;
+                return Err(CapstoneError::BroadcastFeeRateTooHigh {
+                    txid,
+                    fee_rate,
+                    max_fee_rate,
+                });
+            }
+        }
+        return Err(rejected.to_error());
+    }
+    Ok(results)
+}
+
+// The first transaction of a package the node turned away, and where it is
+fn first_rejected(results: &[MempoolAcceptResult]) -> Option<(usize, &MempoolAcceptResult)> {
+    results.iter().enumerate().find(|(_, r)| !r.is_allowed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results(json: &str) -> Vec<MempoolAcceptResult> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn accepted_with_an_effective_feerate() {
+        let results = results(
+            r#"[{
+                "txid": "54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af",
+                "wtxid": "54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af",
+                "allowed": true,
+                "vsize": 113,
+                "fees": {
+                    "base": 0.00001410,
+                    "effective-feerate": 0.00012477,
+                    "effective-includes": ["54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af"]
+                }
+            }]"#,
+        );
+        let result = &results[0];
+        assert!(result.is_allowed());
+        let fees = result.fees.as_ref().unwrap();
+        assert_eq!(fees.base, Amount::from_sat(1410));
+        assert_eq!(fees.effective_feerate, Some(Amount::from_sat(12_477)));
+        assert_eq!(
+            result.describe(),
+            "54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af: accepted, vsize 113 vB, fee 0.00001410 BTC, effective feerate 0.00012477 BTC/kvB"
+        );
+    }
+
+    #[test]
+    fn accepted_by_a_node_before_core_25() {
+        let results = results(
+            r#"[{
+                "txid": "54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af",
+                "allowed": true,
+                "vsize": 113,
+                "fees": { "base": 0.00001410 }
+            }]"#,
+        );
+        assert_eq!(results[0].wtxid, None);
+        assert_eq!(results[0].fees.as_ref().unwrap().effective_feerate, None);
+        assert!(results[0].describe().ends_with("fee 0.00001410 BTC"));
+    }
+
+    #[test]
+    fn rejected_with_a_reason() {
+        let results = results(
+            r#"[{
+                "txid": "54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af",
+                "wtxid": "54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af",
+                "allowed": false,
+                "reject-reason": "min relay fee not met"
+            }]"#,
+        );
+        assert!(!results[0].is_allowed());
+        assert_eq!(
+            results[0].describe(),
+            "54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af: rejected (min relay fee not met)"
+        );
+    }
+
+    // testmempoolaccept for a transaction that is fine and one whose
+    // signature was tampered with
+    const BAD_SIGNATURE: &str = r#"[
+        {
+            "txid": "54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af",
+            "wtxid": "54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af",
+            "allowed": true,
+            "vsize": 113,
+            "fees": { "base": 0.00001410, "effective-feerate": 0.00012477 }
+        },
+        {
+            "txid": "3b821acd7c32c2b3da143e2c6b0134e5aa8206aeae0a54bfa4963e73ac2857a0",
+            "wtxid": "3b821acd7c32c2b3da143e2c6b0134e5aa8206aeae0a54bfa4963e73ac2857a0",
+            "allowed": false,
+            "reject-reason": "mandatory-script-verify-flag-failed (Signature must be zero for failed CHECK(MULTI)SIG operation)"
+        }
+    ]"#;
+
+    #[test]
+    fn a_bad_signature_rejection_propagates() {
+        let results = results(BAD_SIGNATURE);
+        let (i, rejected) = first_rejected(&results).unwrap();
+        assert_eq!(i, 1);
+        assert_eq!(
+            rejected.to_error().to_string(),
+            "transaction 3b821acd7c32c2b3da143e2c6b0134e5aa8206aeae0a54bfa4963e73ac2857a0 would be rejected by the mempool: mandatory-script-verify-flag-failed (Signature must be zero for failed CHECK(MULTI)SIG operation)"
+        );
+        assert!(first_rejected(&results[..1]).is_none());
+    }
+
+    #[test]
+    fn package_error_without_allowed() {
+        let results = results(
+            r#"[
+                {
+                    "txid": "54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af",
+                    "wtxid": "54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af",
+                    "package-error": "package-not-child-with-unconfirmed-parents"
+                },
+                {
+                    "txid": "3b821acd7c32c2b3da143e2c6b0134e5aa8206aeae0a54bfa4963e73ac2857a0",
+                    "wtxid": "3b821acd7c32c2b3da143e2c6b0134e5aa8206aeae0a54bfa4963e73ac2857a0",
+                    "package-error": "package-not-child-with-unconfirmed-parents"
+                }
+            ]"#,
+        );
+        assert_eq!(results.len(), 2);
+        for result in &results {
+            assert_eq!(result.allowed, None);
+            assert!(!result.is_allowed());
+            assert_eq!(
+                result.rejection(),
+                "package-not-child-with-unconfirmed-parents"
+            );
+        }
+    }
+}
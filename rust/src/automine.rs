@@ -0,0 +1,125 @@
+use crate::cli::Config;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::session::{self, Session};
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::{Address, Network};
+use bitcoincore_rpc::RpcApi;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Attempts per RPC before a transient failure stops the loop
+const RPC_ATTEMPTS: u32 = 5;
+// How often the sleep between blocks checks for Ctrl-C
+const STOP_CHECK: Duration = Duration::from_millis(200);
+
+// Set by the SIGINT handler; the loop finishes the block it is on and returns
+static STOP: AtomicBool = AtomicBool::new(false);
+
+// Turn Ctrl-C into `stop_requested`, for loops that should finish what they
+// are doing and return rather than die mid-way. watch uses it too.
+#[cfg(unix)]
+pub fn catch_ctrl_c() {
+    const SIGINT: i32 = 2;
+    extern "C" fn on_sigint(_: i32) {
+        STOP.store(true, Ordering::SeqCst);
+    }
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        signal(SIGINT, on_sigint);
+    }
+}
+
+// Elsewhere Ctrl-C ends the process as usual
+#[cfg(not(unix))]
+pub fn catch_ctrl_c() {}
+
+pub fn stop_requested() -> bool {
+    STOP.load(Ordering::SeqCst)
+}
+
+// --to: an address, or the name of a wallet to take a fresh address from.
+// The Miner's address otherwise.
+fn reward_address(session: &Session, to: Option<&str>) -> Result<Address> {
+    let Some(to) = to else {
+        return Ok(session.miner_address.clone());
+    };
+    if let Ok(address) = to.parse::<Address<NetworkUnchecked>>() {
+        return address
+            .require_network(session.network)
+            .map_err(|e| CapstoneError::Usage(format!("invalid --to address '{}': {}", to, e)));
+    }
+    if !session.rpc.list_wallets()?.iter().any(|name| name == to) {
+        return Err(CapstoneError::Usage(format!(
+            "--to '{}' is neither an address nor a loaded wallet",
+            to
+        )));
+    }
+    Ok(session::wallet_client(to)?
+        .get_new_address(Some("automine"), None)?
+        .assume_checked())
+}
+
+// automine: mine a block every `interval` seconds, as the usual
+// `while true; do bitcoin-cli generatetoaddress ...` loop would, until Ctrl-C
+// or --max-blocks. With --only-when-txs an interval with an empty mempool is
+// skipped.
+pub fn run(session: &Session, config: &Config, interval: u64) -> Result<()> {
+    if session.network != Network::Regtest {
+        return Err(CapstoneError::Usage(
+            "automine mines blocks on its own, it only runs on regtest".into(),
+        ));
+    }
+    let to = reward_address(session, config.mine_to.as_deref())?;
+    let interval = Duration::from_secs(interval);
+    catch_ctrl_c();
+    say!(
+        "Mining a block every {}s to {}{}; Ctrl-C to stop.",
+        interval.as_secs(),
+        to,
+        if config.only_when_txs {
+            " when the mempool has transactions"
+        } else {
+            ""
+        }
+    );
+
+    let mut mined = 0;
+    let mut next = Instant::now() + interval;
+    while !stop_requested() {
+        if config.max_blocks.is_some_and(|max| mined >= max) {
+            say!("Reached --max-blocks {}.", mined);
+            break;
+        }
+        while !STOP.load(Ordering::SeqCst) && Instant::now() < next {
+            thread::sleep(STOP_CHECK.min(next.saturating_duration_since(Instant::now())));
+        }
+        if STOP.load(Ordering::SeqCst) {
+            break;
+        }
+        next += interval;
+
+        if config.only_when_txs {
+            let pending = session::retry("getmempoolinfo", RPC_ATTEMPTS, || {
+                Ok(session.rpc.get_mempool_info()?.size)
+            })?;
+            if pending == 0 {
+                continue;
+            }
+        }
+        let block_hash = session::retry("mining a block", RPC_ATTEMPTS, || {
+            Ok(session.generate(1, &to)?[0])
+        })?;
+        let height = session::retry("getblockheader", RPC_ATTEMPTS, || {
+            Ok(session.rpc.get_block_header_info(&block_hash)?.height)
+        })?;
+        mined += 1;
+        say!("Mined block {} at height {}.", block_hash, height);
+    }
+    say!("Stopped after mining {} block(s).", mined);
+    Ok(())
+}
@@ -0,0 +1,543 @@
+use crate::console::say;
+use crate::error::Result;
+use crate::prune;
+use crate::session;
+use crate::version::{self, Capability};
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::amount::serde::as_btc;
+use bitcoincore_rpc::bitcoin::{Address, Amount, BlockHash, Txid, Wtxid};
+use bitcoincore_rpc::json::GetBlockHeaderResult;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+use std::ops::RangeInclusive;
+use std::thread;
+
+// A block with every transaction decoded, from getblock verbosity 2 or 3.
+// Only the fields we use are kept; scripts come as hex strings and the asm
+// forms are never deserialized.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FullBlock {
+    pub hash: BlockHash,
+    pub height: u64,
+    pub time: u64,
+    #[serde(rename = "mediantime")]
+    pub median_time: u64,
+    #[serde(rename = "previousblockhash", default)]
+    pub previous_block_hash: Option<BlockHash>,
+    pub weight: u64,
+    pub tx: Vec<FullTransaction>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FullTransaction {
+    pub txid: Txid,
+    #[serde(rename = "hash")]
+    pub wtxid: Wtxid,
+    pub size: u64,
+    pub vsize: u64,
+    pub weight: u64,
+    pub vin: Vec<FullInput>,
+    pub vout: Vec<FullOutput>,
+    // Left out for the coinbase, and on verbosity 2 without undo data
+    #[serde(default, with = "as_btc::opt")]
+    pub fee: Option<Amount>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FullInput {
+    // Set on the coinbase input only, instead of txid/vout
+    #[serde(default)]
+    pub coinbase: Option<String>,
+    #[serde(default)]
+    pub txid: Option<Txid>,
+    #[serde(default)]
+    pub vout: Option<u32>,
+    pub sequence: u32,
+    // Verbosity 3 only
+    #[serde(default)]
+    pub prevout: Option<FullPrevout>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FullPrevout {
+    // Whether the spent output is a coinbase output
+    pub generated: bool,
+    pub height: u64,
+    #[serde(with = "as_btc")]
+    pub value: Amount,
+    #[serde(rename = "scriptPubKey")]
+    pub script_pub_key: FullScript,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FullOutput {
+    #[serde(with = "as_btc")]
+    pub value: Amount,
+    pub n: u32,
+    #[serde(rename = "scriptPubKey")]
+    pub script_pub_key: FullScript,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FullScript {
+    pub hex: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub address: Option<Address<NetworkUnchecked>>,
+}
+
+impl FullTransaction {
+    pub fn is_coinbase(&self) -> bool {
+        self.vin.first().is_some_and(|vin| vin.coinbase.is_some())
+    }
+
+    pub fn output_value(&self) -> Amount {
+        self.vout.iter().map(|vout| vout.value).sum()
+    }
+}
+
+impl FullBlock {
+    pub fn coinbase(&self) -> Option<&FullTransaction> {
+        self.tx.first().filter(|tx| tx.is_coinbase())
+    }
+
+    // Sum of the fees the node reported; transactions without one count as 0
+    pub fn total_fees(&self) -> Amount {
+        self.tx.iter().filter_map(|tx| tx.fee).sum()
+    }
+
+    // Whether the inputs carry their prevouts, i.e. this came from verbosity 3
+    pub fn has_prevouts(&self) -> bool {
+        self.tx
+            .iter()
+            .filter(|tx| !tx.is_coinbase())
+            .all(|tx| tx.vin.iter().all(|vin| vin.prevout.is_some()))
+    }
+}
+
+// How many getblockhash calls go in one batch
+const HASH_BATCH: usize = 500;
+// Attempts per getblock in `for_each_full_block`, for nodes that turn away
+// calls under load
+const FETCH_ATTEMPTS: u32 = 4;
+
+// One block of an `iter_blocks` range. The header is fetched as the iterator
+// reaches it; the full block only when asked for.
+#[derive(Debug, Clone)]
+pub struct BlockEntry<'a> {
+    pub height: u64,
+    pub hash: BlockHash,
+    pub header: GetBlockHeaderResult,
+    rpc: &'a Client,
+}
+
+impl BlockEntry<'_> {
+    pub fn full(&self) -> Result<FullBlock> {
+        get_block_full(self.rpc, &self.hash)
+    }
+}
+
+// Blocks `start..=end` of the active chain, oldest first, or newest first
+// with `.rev()`.
+pub struct BlockIter<'a> {
+    rpc: &'a Client,
+    hashes: std::vec::IntoIter<(u64, BlockHash)>,
+}
+
+impl<'a> BlockIter<'a> {
+    fn entry(&self, (height, hash): (u64, BlockHash)) -> Result<BlockEntry<'a>> {
+        Ok(BlockEntry {
+            height,
+            hash,
+            header: self.rpc.get_block_header_info(&hash)?,
+            rpc: self.rpc,
+        })
+    }
+}
+
+impl<'a> Iterator for BlockIter<'a> {
+    type Item = Result<BlockEntry<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.hashes.next()?;
+        Some(self.entry(next))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.hashes.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for BlockIter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let next = self.hashes.next_back()?;
+        Some(self.entry(next))
+    }
+}
+
+// Iterate over the blocks at heights `start..=end`. An `end` past the tip is
+// clamped to it and a `start` below the prune height up to that (both with a
+// warning); `start > end` is an empty range. The
+// hashes are resolved up front with batched getblockhash calls, since the
+// chain may move while the caller works through the range.
+pub fn iter_blocks(rpc: &Client, start: u64, end: u64) -> Result<BlockIter<'_>> {
+    Ok(BlockIter {
+        rpc,
+        hashes: block_hashes(rpc, start, end)?.into_iter(),
+    })
+}
+
+pub fn block_hashes(rpc: &Client, start: u64, end: u64) -> Result<Vec<(u64, BlockHash)>> {
+    let start = prune::clamp_start(rpc, start)?;
+    let tip = rpc.get_block_count()?;
+    if end > tip {
+        say!(
+            "Warning: height {} is past the tip, stopping at {}.",
+            end,
+            tip
+        );
+    }
+    let heights: Vec<u64> = heights(start, end, tip).collect();
+    let mut hashes = Vec::with_capacity(heights.len());
+    for chunk in heights.chunks(HASH_BATCH) {
+        let params: Vec<Vec<serde_json::Value>> =
+            chunk.iter().map(|height| vec![json!(height)]).collect();
+        let results = session::batch_call::<BlockHash>(rpc, "getblockhash", &params)?;
+        for (height, hash) in chunk.iter().zip(results) {
+            hashes.push((*height, hash.map_err(bitcoincore_rpc::Error::JsonRpc)?));
+        }
+    }
+    Ok(hashes)
+}
+
+// The heights `start..=end` with `end` clamped to `tip`
+fn heights(start: u64, end: u64, tip: u64) -> RangeInclusive<u64> {
+    start..=end.min(tip)
+}
+
+// Hand the full blocks at heights `start..=end` to `f` in height order
+// (clamped like `iter_blocks`), fetching up to `parallelism` of them at once.
+pub fn for_each_full_block(
+    rpc: &Client,
+    start: u64,
+    end: u64,
+    parallelism: usize,
+    f: impl FnMut(FullBlock) -> Result<()>,
+) -> Result<()> {
+    let hashes = block_hashes(rpc, start, end)?;
+    for_each_listed_block(rpc, &hashes, parallelism, f)
+}
+
+// `for_each_full_block` over blocks already picked out, e.g. the filter
+// matches of a scan. Blocks are fetched a window at a time, so at most
+// `parallelism` are held in memory; `f` returning an error stops the scan.
+pub fn for_each_listed_block(
+    rpc: &Client,
+    hashes: &[(u64, BlockHash)],
+    parallelism: usize,
+    mut f: impl FnMut(FullBlock) -> Result<()>,
+) -> Result<()> {
+    for window in hashes.chunks(parallelism.max(1)) {
+        let blocks: Vec<Result<FullBlock>> = thread::scope(|scope| {
+            let handles: Vec<_> = window
+                .iter()
+                .map(|(_, hash)| {
+                    scope.spawn(move || {
+                        session::retry(&format!("fetching block {}", hash), FETCH_ATTEMPTS, || {
+                            get_block_full(rpc, hash)
+                        })
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("block fetch thread panicked"))
+                .collect()
+        });
+        for block in blocks {
+            f(block?)?;
+        }
+    }
+    Ok(())
+}
+
+// getblock at the highest verbosity the node supports: 3 on Core 23+, so
+// callers can read prevouts without looking each one up, else 2.
+pub fn get_block_full(rpc: &Client, block_hash: &BlockHash) -> Result<FullBlock> {
+    let verbosity = if version::supports(rpc, Capability::BlockPrevouts)? {
+        3
+    } else {
+        2
+    };
+    let result = rpc.call(
+        "getblock",
+        &[json!(block_hash.to_string()), json!(verbosity)],
+    );
+    prune::explain_pruned(rpc, block_hash, "the block", result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::CapstoneError;
+    use crate::mockrpc::{self, Reply};
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn ranges_within_the_chain() {
+        assert_eq!(heights(0, 0, 200).collect::<Vec<_>>(), vec![0]);
+        assert_eq!(heights(200, 200, 200).collect::<Vec<_>>(), vec![200]);
+        assert_eq!(
+            heights(101, 103, 200).collect::<Vec<_>>(),
+            vec![101, 102, 103]
+        );
+        assert_eq!(heights(0, 200, 200).count(), 201);
+    }
+
+    #[test]
+    fn empty_and_inverted_ranges() {
+        assert_eq!(heights(5, 4, 200).count(), 0);
+        assert_eq!(heights(200, 0, 200).count(), 0);
+        // Starting past the tip leaves nothing once the end is clamped
+        assert_eq!(heights(201, 300, 200).count(), 0);
+    }
+
+    #[test]
+    fn end_past_the_tip_is_clamped() {
+        assert_eq!(
+            heights(198, 1_000, 200).collect::<Vec<_>>(),
+            vec![198, 199, 200]
+        );
+        assert_eq!(heights(0, u64::MAX, 0).collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn most_recent_first() {
+        assert_eq!(
+            heights(98, 500, 100).rev().collect::<Vec<_>>(),
+            vec![100, 99, 98]
+        );
+    }
+
+    fn fixture(json: &str) -> FullBlock {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn verbosity_2_block_from_core() {
+        let block = fixture(include_str!("../tests/fixtures/block-verbose2.json"));
+        assert_eq!(
+            block.hash.to_string(),
+            "2eb85235a4d182e698beacdd429de9867713892597084effb8aa837a2c843334"
+        );
+        assert_eq!(block.height, 102);
+        assert_eq!(block.median_time, 1_700_000_000);
+        assert!(block.previous_block_hash.is_some());
+        assert_eq!(block.weight, 1845);
+        assert_eq!(block.tx.len(), 3);
+        assert!(!block.has_prevouts());
+
+        let coinbase = block.coinbase().unwrap();
+        assert_eq!(
+            coinbase.txid.to_string(),
+            "b7dd64e5ec3b46232816876636275be8b6cccff476ce9484419294750acf55a1"
+        );
+        assert_eq!(coinbase.vin[0].coinbase.as_deref(), Some("016600"));
+        assert_eq!(coinbase.fee, None);
+        assert_eq!(coinbase.output_value(), Amount::from_sat(5_000_002_400));
+        // The witness commitment output carries no address
+        assert_eq!(coinbase.vout[1].script_pub_key.kind, "nulldata");
+        assert!(coinbase.vout[1].script_pub_key.address.is_none());
+
+        let spend = &block.tx[1];
+        assert!(!spend.is_coinbase());
+        assert_eq!(spend.vsize, 141);
+        assert_eq!(spend.fee, Some(Amount::from_sat(1410)));
+        assert_eq!(spend.vin[0].vout, Some(0));
+        assert!(spend.vin[0].prevout.is_none());
+        assert_eq!(block.tx[2].fee, Some(Amount::from_sat(990)));
+        assert_eq!(block.total_fees(), Amount::from_sat(2400));
+    }
+
+    #[test]
+    fn verbosity_3_block_from_core() {
+        let block = fixture(include_str!("../tests/fixtures/block-verbose3.json"));
+        assert_eq!(block.height, 102);
+        assert_eq!(block.tx.len(), 3);
+        assert!(block.has_prevouts());
+        assert_eq!(block.total_fees(), Amount::from_sat(2400));
+        assert_eq!(
+            block.coinbase().unwrap().output_value(),
+            Amount::from_sat(5_000_000_000) + block.total_fees()
+        );
+
+        let segwit = block.tx[1].vin[0].prevout.as_ref().unwrap();
+        assert!(!segwit.generated);
+        assert_eq!(segwit.height, 101);
+        assert_eq!(segwit.value, Amount::ONE_BTC);
+        assert_eq!(segwit.script_pub_key.kind, "witness_v0_keyhash");
+        let taproot = block.tx[2].vin[0].prevout.as_ref().unwrap();
+        assert_eq!(taproot.script_pub_key.kind, "witness_v1_taproot");
+        assert!(taproot.script_pub_key.hex.starts_with("5120"));
+
+        // Fees agree with what the prevouts say went in
+        for tx in &block.tx[1..] {
+            let spent: Amount = tx
+                .vin
+                .iter()
+                .map(|vin| vin.prevout.as_ref().unwrap().value)
+                .sum();
+            assert_eq!(tx.fee, Some(spent - tx.output_value()));
+        }
+    }
+
+    #[test]
+    fn verbosity_2_without_undo_data_has_no_fees() {
+        let mut json: Value =
+            serde_json::from_str(include_str!("../tests/fixtures/block-verbose2.json")).unwrap();
+        for tx in json["tx"].as_array_mut().unwrap() {
+            tx.as_object_mut().unwrap().remove("fee");
+        }
+        let block: FullBlock = serde_json::from_value(json).unwrap();
+        assert_eq!(block.total_fees(), Amount::ZERO);
+        // Without inputs besides the coinbase's, there is nothing to miss
+        let mut coinbase_only = block.clone();
+        coinbase_only.tx.truncate(1);
+        assert!(coinbase_only.has_prevouts());
+        assert!(!block.has_prevouts());
+    }
+
+    fn hash_at(height: u64) -> BlockHash {
+        BlockHash::from_byte_array([height as u8; 32])
+    }
+
+    fn height_of(hash: &Value) -> u64 {
+        let hash: BlockHash = hash.as_str().unwrap().parse().unwrap();
+        hash.to_byte_array()[0] as u64
+    }
+
+    fn full_block(height: u64) -> Value {
+        json!({
+            "hash": hash_at(height),
+            "height": height,
+            "time": 1700000000 + height,
+            "mediantime": 1700000000,
+            "weight": 0,
+            "tx": []
+        })
+    }
+
+    // The calls a scan makes before any getblock, on an unpruned Core 25
+    // node at height 20
+    fn chain(method: &str, params: &[Value]) -> Option<Reply> {
+        Some(Ok(match method {
+            "getnetworkinfo" => mockrpc::network_info(),
+            "getblockchaininfo" => mockrpc::blockchain_info(None),
+            "getblockcount" => json!(20),
+            "getblockhash" => json!(hash_at(params[0].as_u64().unwrap())),
+            _ => return None,
+        }))
+    }
+
+    #[test]
+    fn blocks_are_handed_over_in_height_order() {
+        const PARALLELISM: u64 = 4;
+        let arrived = Arc::new(Mutex::new(Vec::new()));
+        let (fetched, consumed, most_held) = (
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let (rpc, _) = mockrpc::concurrent_client({
+            let (arrived, fetched, consumed, most_held) = (
+                arrived.clone(),
+                fetched.clone(),
+                consumed.clone(),
+                most_held.clone(),
+            );
+            move |method, params| {
+                chain(method, params).unwrap_or_else(|| match method {
+                    "getblock" => {
+                        let height = height_of(&params[0]);
+                        // Within a window the later heights answer first
+                        let position = height % PARALLELISM;
+                        thread::sleep(Duration::from_millis(25 * (PARALLELISM - position)));
+                        arrived.lock().unwrap().push(height);
+                        let held = fetched.fetch_add(1, SeqCst) + 1 - consumed.load(SeqCst);
+                        most_held.fetch_max(held, SeqCst);
+                        Ok(full_block(height))
+                    }
+                    other => panic!("unexpected {}", other),
+                })
+            }
+        });
+
+        let mut seen = Vec::new();
+        for_each_full_block(&rpc, 4, 15, PARALLELISM as usize, |block| {
+            seen.push(block.height);
+            consumed.fetch_add(1, SeqCst);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(seen, (4..=15).collect::<Vec<_>>());
+        assert_eq!(arrived.lock().unwrap()[..4], [7, 6, 5, 4]);
+        assert!(most_held.load(SeqCst) <= PARALLELISM as usize);
+    }
+
+    // A node whose getblock for height 5 fails its first `failures` times
+    fn flaky(failures: usize) -> (Client, Arc<AtomicUsize>) {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let (rpc, _) = mockrpc::concurrent_client({
+            let attempts = attempts.clone();
+            move |method, params| {
+                chain(method, params).unwrap_or_else(|| match method {
+                    "getblock" if height_of(&params[0]) == 5 => {
+                        if attempts.fetch_add(1, SeqCst) < failures {
+                            Err((-32603, "Work queue depth exceeded"))
+                        } else {
+                            Ok(full_block(5))
+                        }
+                    }
+                    "getblock" => Ok(full_block(height_of(&params[0]))),
+                    other => panic!("unexpected {}", other),
+                })
+            }
+        });
+        (rpc, attempts)
+    }
+
+    #[test]
+    fn a_failing_fetch_is_retried() {
+        let (rpc, attempts) = flaky(2);
+        let mut seen = Vec::new();
+        for_each_full_block(&rpc, 4, 6, 2, |block| {
+            seen.push(block.height);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(seen, [4, 5, 6]);
+        assert_eq!(attempts.load(SeqCst), 3);
+    }
+
+    #[test]
+    fn the_error_comes_back_once_the_attempts_run_out() {
+        let (rpc, attempts) = flaky(usize::MAX);
+        let mut seen = Vec::new();
+        let error = for_each_full_block(&rpc, 4, 6, 2, |block| {
+            seen.push(block.height);
+            Ok(())
+        })
+        .unwrap_err();
+        assert!(matches!(error, CapstoneError::Rpc(_)), "{}", error);
+        assert!(error.to_string().contains("Work queue depth exceeded"));
+        assert_eq!(attempts.load(SeqCst), FETCH_ATTEMPTS as usize);
+        // The block before the failing one was still handed over
+        assert_eq!(seen, [4]);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::cli::Units;
+use bitcoincore_rpc::bitcoin::amount::ParseAmountError;
+use bitcoincore_rpc::bitcoin::{Amount, Denomination};
+use std::cell::Cell;
+use std::sync::OnceLock;
+
+// --units, set once at startup; BTC until then
+static UNITS: OnceLock<Units> = OnceLock::new();
+
+thread_local! {
+    // `with_units`' override of --units on this thread
+    static SCOPED_UNITS: Cell<Option<Units>> = const { Cell::new(None) };
+}
+
+pub fn set_units(units: Units) {
+    UNITS.get_or_init(|| units);
+}
+
+pub fn units() -> Units {
+    SCOPED_UNITS
+        .with(Cell::get)
+        .or_else(|| UNITS.get().copied())
+        .unwrap_or_default()
+}
+
+// Run `f` with everything it formats in `units`, whatever --units says, so
+// one report can be rendered both ways
+#[cfg(test)]
+pub fn with_units<T>(units: Units, f: impl FnOnce() -> T) -> T {
+    let outer = SCOPED_UNITS.with(|scoped| scoped.replace(Some(units)));
+    let result = f();
+    SCOPED_UNITS.with(|scoped| scoped.set(outer));
+    result
+}
+
+pub fn unit_in(units: Units) -> &'static str {
+    match units {
+        Units::Btc => "BTC",
+        Units::Sats => "sats",
+    }
+}
+
+// The label amounts from `format` carry, as in "Transaction Fees (in BTC)"
+pub fn unit() -> &'static str {
+    unit_in(units())
+}
+
+// `amount` in BTC with exactly 8 decimals, e.g. 0.00000001 or 20.00000000.
+// Worked out from satoshis: `Amount::to_btc` is an f64, which prints
+// 49.99985580000001 for some amounts and drops trailing zeros for others.
+pub fn format_btc(amount: Amount) -> String {
+    let sat = amount.to_sat();
+    format!(
+        "{}.{:08}",
+        sat / Amount::ONE_BTC.to_sat(),
+        sat % Amount::ONE_BTC.to_sat()
+    )
+}
+
+// The inverse of format_btc: a BTC amount with at most 8 decimals, as the
+// amount flags take it. Negative amounts and sub-satoshi precision are errors.
+pub fn parse_btc(value: &str) -> std::result::Result<Amount, ParseAmountError> {
+    Amount::from_str_in(value, Denomination::Bitcoin)
+}
+
+// `amount` in BTC with format_btc, or as integer satoshis
+pub fn format_in(amount: Amount, units: Units) -> String {
+    match units {
+        Units::Btc => format_btc(amount),
+        Units::Sats => amount.to_sat().to_string(),
+    }
+}
+
+// `amount` in the --units everything human-readable uses
+pub fn format(amount: Amount) -> String {
+    format_in(amount, units())
+}
+
+// `format` with the unit after it, e.g. "0.00001000 BTC" or "1000 sats"
+pub fn amount(amount: Amount) -> String {
+    format!("{} {}", format(amount), unit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_awkward_amounts_exactly() {
+        let cases = [
+            (1, "0.00000001"),
+            (2_000_000_000, "20.00000000"),
+            // to_btc gives 49.99985580000001 for this one
+            (4_999_985_580, "49.99985580"),
+            (2_099_999_999_999_999, "20999999.99999999"),
+            (2_100_000_000_000_000, "21000000.00000000"),
+            (0, "0.00000000"),
+        ];
+        for (sat, expected) in cases {
+            assert_eq!(format_btc(Amount::from_sat(sat)), expected);
+        }
+    }
+
+    #[test]
+    fn formats_in_either_unit() {
+        let fee = Amount::from_sat(1_410);
+        assert_eq!(format_in(fee, Units::Btc), "0.00001410");
+        assert_eq!(format_in(fee, Units::Sats), "1410");
+        assert_eq!(
+            format_in(Amount::MAX_MONEY, Units::Sats),
+            "2100000000000000"
+        );
+        assert_eq!(format_in(Amount::ZERO, Units::Sats), "0");
+        assert_eq!(unit_in(Units::Btc), "BTC");
+        assert_eq!(unit_in(Units::Sats), "sats");
+    }
+
+    #[test]
+    fn with_units_switches_this_thread_only() {
+        let fee = Amount::from_sat(1_410);
+        assert_eq!(amount(fee), "0.00001410 BTC");
+        let (inside, nested, other_thread) = with_units(Units::Sats, || {
+            (
+                amount(fee),
+                with_units(Units::Btc, || amount(fee)),
+                std::thread::spawn(move || amount(fee)).join().unwrap(),
+            )
+        });
+        assert_eq!(inside, "1410 sats");
+        assert_eq!(nested, "0.00001410 BTC");
+        assert_eq!(other_thread, "0.00001410 BTC");
+        assert_eq!(amount(fee), "0.00001410 BTC");
+    }
+
+    #[test]
+    fn parse_round_trips_format() {
+        for sat in [
+            1,
+            4_999_985_580,
+            2_099_999_999_999_999,
+            2_100_000_000_000_000,
+        ] {
+            let amount = Amount::from_sat(sat);
+            assert_eq!(parse_btc(&format_btc(amount)).unwrap(), amount);
+        }
+        assert_eq!(parse_btc("0.00000001").unwrap(), Amount::from_sat(1));
+        assert_eq!(parse_btc("21000000").unwrap(), Amount::MAX_MONEY);
+    }
+
+    #[test]
+    fn parse_rejects_sub_satoshi_and_negative_amounts() {
+        assert!(parse_btc("0.000000001").is_err());
+        assert!(parse_btc("1.123456789").is_err());
+        assert!(parse_btc("-1").is_err());
+        assert!(parse_btc("-0.00000001").is_err());
+    }
+}
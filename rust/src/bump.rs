@@ -0,0 +1,179 @@
+use crate::btc;
+use crate::cli::Config;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::fees::{self, FeeRateChoice};
+use crate::mine;
+use crate::relay::NodePolicy;
+use crate::session;
+use crate::txidlog;
+use bitcoincore_rpc::bitcoin::{Amount, BlockHash, Txid};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+// One version of the payment, the original or a bumpfee replacement.
+#[derive(Debug, Clone)]
+pub struct BumpAttempt {
+    pub txid: Txid,
+    pub fee: Amount,
+    pub sat_per_kvb: u64,
+}
+
+// How the bump loop ended: which version confirmed, where, and every version
+// that was broadcast along the way (oldest first).
+#[derive(Debug, Clone)]
+pub struct BumpOutcome {
+    pub txid: Txid,
+    pub block_hash: BlockHash,
+    pub attempts: Vec<BumpAttempt>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BumpFeeResult {
+    txid: Txid,
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    fee: Amount,
+    #[serde(default)]
+    errors: Vec<String>,
+}
+
+// Start low so there is room to escalate: the configured fallback rate, or
+// mempoolminfee if that is higher.
+pub fn initial_fee_rate(rpc: &Client, config: &Config) -> Result<FeeRateChoice> {
+    let mempool_min_fee = rpc.get_mempool_info()?.mempool_min_fee.to_sat();
+    let choice = fees::choose_fee_rate(None, config.fallback_fee_rate(), mempool_min_fee);
+    Ok(fees::clamp_to_max_fee(
+        choice,
+        config.max_fee(),
+        fees::TYPICAL_PAYMENT_VSIZE,
+    ))
+}
+
+// The next rate to try: half as much again as the current one, at least the
+// node's incremental relay fee more (a replacement must pay that much more per
+// vB), and never above what `max_fee` allows for `vsize`. None once the cap
+// leaves no room for a valid replacement.
+pub fn next_fee_rate(
+    current: u64,
+    vsize: u64,
+    max_fee: Amount,
+    policy: &NodePolicy,
+) -> Option<u64> {
+    let incremental = policy.incremental_relay_fee;
+    let wanted = current + (current / 2).max(incremental);
+    let capped = wanted.min(max_fee.to_sat() * 1000 / vsize.max(1));
+    (capped >= current + incremental).then_some(capped)
+}
+
+// Wait for `txid` to confirm, replacing it with bumpfee at increasing feerates
+// whenever it has sat unconfirmed for the configured interval. The wallet keeps
+// every version, so each poll checks all of them: an earlier one can still win.
+pub fn wait_until_confirmed(
+    rpc: &Client,
+    txid: Txid,
+    initial: &FeeRateChoice,
+    policy: &NodePolicy,
+    config: &Config,
+) -> Result<BumpOutcome> {
+    let poll = Duration::from_secs(config.poll_interval());
+    let bump_after = Duration::from_secs(config.bump_interval());
+
+    let fee = fees::wallet_fee(rpc, &txid)?;
+    let mut attempts = vec![BumpAttempt {
+        txid,
+        fee,
+        sat_per_kvb: initial.sat_per_kvb,
+    }];
+    let mut last_broadcast = Instant::now();
+    say!(
+        "Waiting for {} to confirm, bumping every {}s if it doesn't...",
+        txid,
+        bump_after.as_secs()
+    );
+
+    // Wake up on new blocks rather than sleeping through them
+    let waiter = session::long_poll_client(poll + mine::LONG_POLL_MARGIN)?;
+    let mut tip = rpc.get_best_block_hash()?;
+    loop {
+        if let Some(new_tip) = mine::wait_for_new_block(&waiter, &tip, Instant::now() + poll)? {
+            tip = new_tip.hash;
+        }
+
+        for attempt in attempts.iter().rev() {
+            let info = rpc.get_transaction(&attempt.txid, None)?.info;
+            if info.confirmations > 0 {
+                let block_hash = info.blockhash.ok_or_else(|| CapstoneError::BumpAborted {
+                    txid: attempt.txid,
+                    reason: "confirmed but the wallet reports no block hash".into(),
+                })?;
+                say!(
+                    "{} confirmed in block {} after {} replacement(s).",
+                    attempt.txid,
+                    block_hash,
+                    attempts.len() - 1
+                );
+                return Ok(BumpOutcome {
+                    txid: attempt.txid,
+                    block_hash,
+                    attempts,
+                });
+            }
+        }
+
+        let latest = attempts.last().expect("at least the original").clone();
+        let latest_tx = rpc.get_transaction(&latest.txid, None)?;
+        // Negative confirmations: something we didn't broadcast confirmed in its place
+        if latest_tx.info.confirmations < 0 {
+            return Err(CapstoneError::BumpAborted {
+                txid: latest.txid,
+                reason: format!("conflicted with {:?}", latest_tx.info.wallet_conflicts),
+            });
+        }
+        if last_broadcast.elapsed() < bump_after {
+            continue;
+        }
+
+        let vsize = latest_tx.transaction()?.vsize() as u64;
+        let rate = next_fee_rate(latest.sat_per_kvb, vsize, config.max_fee(), policy).ok_or_else(
+            || CapstoneError::BumpAborted {
+                txid: latest.txid,
+                reason: format!(
+                    "still unconfirmed at {} sat/vB and --max-fee of {} leaves no room to bump",
+                    fees::format_sat_per_vb(latest.sat_per_kvb),
+                    btc::amount(config.max_fee())
+                ),
+            },
+        )?;
+
+        attempts.push(bump_fee(rpc, &latest.txid, rate)?);
+        last_broadcast = Instant::now();
+    }
+}
+
+// Replace `txid` with bumpfee at `sat_per_kvb`.
+pub fn bump_fee(rpc: &Client, txid: &Txid, sat_per_kvb: u64) -> Result<BumpAttempt> {
+    let rate_str = fees::format_sat_per_vb(sat_per_kvb);
+    let result: BumpFeeResult =
+        rpc.call("bumpfee", &[json!(txid), json!({ "fee_rate": rate_str })])?;
+    if !result.errors.is_empty() {
+        return Err(CapstoneError::BumpAborted {
+            txid: *txid,
+            reason: result.errors.join("; "),
+        });
+    }
+    txidlog::record(&result.txid, "replacement");
+    say!(
+        "Replaced {} with {} at {} sat/vB (fee {}).",
+        txid,
+        result.txid,
+        rate_str,
+        btc::amount(result.fee)
+    );
+    Ok(BumpAttempt {
+        txid: result.txid,
+        fee: result.fee,
+        sat_per_kvb,
+    })
+}
@@ -0,0 +1,280 @@
+use crate::btc;
+use crate::cli::Config;
+use crate::console::say;
+use crate::dust;
+use crate::error::{CapstoneError, Result};
+use crate::family;
+use crate::fees;
+use crate::mempool::{self, MempoolEntry};
+use crate::mine;
+use crate::payment;
+use crate::relay;
+use crate::report;
+use crate::session::Session;
+use crate::utxo;
+use bitcoincore_rpc::bitcoin::{Address, Amount, OutPoint, ScriptBuf, Txid};
+use bitcoincore_rpc::RpcApi;
+use serde_json::json;
+use std::collections::HashSet;
+
+// One payment in the chain, the fee it paid and the outputs it created
+// (outpoint, value, scriptPubKey) for the UTXO check at the end. The mempool
+// entry and ancestors are taken once the whole chain is in the mempool.
+struct ChainLink {
+    txid: Txid,
+    fee: Amount,
+    outputs: Vec<(&'static str, OutPoint, Amount, ScriptBuf)>,
+    mempool: Option<MempoolEntry>,
+    ancestors: Vec<Txid>,
+}
+
+// Send `count` payments where each one spends the still unconfirmed change of
+// the previous one, then confirm them all with a single block. This exercises
+// unconfirmed input selection and the node's ancestor limits (25 by default).
+pub fn run(session: &Session, config: &Config, count: usize) -> Result<()> {
+    // Each link is a small payment so the change can keep the chain going
+    let amount = config.amount.unwrap_or(Amount::ONE_BTC);
+    session.fund_miner(amount * count as u64 + config.max_fee())?;
+    let mut links = Vec::new();
+    let mut change_outpoint: Option<OutPoint> = None;
+    let mut stopped_early = None;
+    // Every link pays the same amount, so the first one's policy does for all
+    let mut node_policy = None;
+
+    for i in 1..=count {
+        let trader_address = session
+            .trader_rpc
+            .get_new_address(None, None)?
+            .assume_checked();
+        let payment = [(trader_address.clone(), amount)];
+        if node_policy.is_none() {
+            node_policy = Some(relay::policy(&session.rpc, &relay::outputs(&payment))?);
+        }
+        dust::check_outputs(&payment, node_policy.as_ref().expect("set above"))?;
+
+        // Spend exactly the previous change output, nothing else
+        let mut options = payment::send_options(config);
+        if let Some(outpoint) = change_outpoint {
+            options.insert(
+                "inputs".into(),
+                json!([{ "txid": outpoint.txid.to_string(), "vout": outpoint.vout }]),
+            );
+            // The change is unconfirmed, allow the wallet to spend it
+            options.insert("include_unsafe".into(), json!(true));
+        }
+
+        let txid = match payment::send(
+            &session.miner_rpc,
+            &trader_address.to_string(),
+            amount,
+            config,
+            options,
+        ) {
+            Ok(txid) => txid,
+            Err(e) if e.to_string().contains("too-long-mempool-chain") => {
+                stopped_early = Some(format!(
+                    "node rejected transaction {} with too-long-mempool-chain",
+                    i
+                ));
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let wallet_tx = session.miner_rpc.get_transaction(&txid, None)?;
+        let fee = wallet_tx
+            .fee
+            .and_then(|fee| fee.abs().to_unsigned().ok())
+            .unwrap_or(Amount::ZERO);
+        say!(
+            "Chain transaction {}: {} (fee {})",
+            i,
+            txid,
+            btc::amount(fee)
+        );
+
+        // The change is whichever output does not pay the Trader
+        let tx = wallet_tx.transaction()?;
+        let trader_script = trader_address.script_pubkey();
+        change_outpoint = tx
+            .output
+            .iter()
+            .position(|output| output.script_pubkey != trader_script)
+            .map(|vout| OutPoint::new(txid, vout as u32));
+
+        let outputs = tx
+            .output
+            .iter()
+            .enumerate()
+            .map(|(vout, output)| {
+                let role = if output.script_pubkey == trader_script {
+                    "trader"
+                } else {
+                    "change"
+                };
+                (
+                    role,
+                    OutPoint::new(txid, vout as u32),
+                    output.value,
+                    output.script_pubkey.clone(),
+                )
+            })
+            .collect();
+        links.push(ChainLink {
+            txid,
+            fee,
+            outputs,
+            mempool: None,
+            ancestors: Vec::new(),
+        });
+
+        if change_outpoint.is_none() && i < count {
+            stopped_early = Some(format!("transaction {} has no change output to spend", i));
+            break;
+        }
+    }
+
+    if let Some(reason) = &stopped_early {
+        say!(
+            "Stopped after {} of {} transactions: {}",
+            links.len(),
+            count,
+            reason
+        );
+    }
+
+    // A single link's feerate says little when it spends unconfirmed parents;
+    // miners look at the package, so record the ancestor and descendant stats
+    for (i, link) in links.iter_mut().enumerate() {
+        let entry = mempool::get_mempool_entry(&session.rpc, &link.txid)?;
+        say!("Chain transaction {} feerate: {}", i + 1, entry.describe());
+        link.ancestors = mempool::get_mempool_ancestors(&session.rpc, &link.txid)?;
+        link.mempool = Some(entry);
+    }
+
+    // The package the block will take: the whole chain hangs off the first link
+    let package = match links.first() {
+        Some(first) => family::tx_family(&session.rpc, &first.txid)?.render_tree(),
+        None => Vec::new(),
+    };
+    for line in &package {
+        say!("{}", line);
+    }
+
+    // Mine 1 block to confirm the whole chain at once
+    let txids: Vec<Txid> = links.iter().map(|link| link.txid).collect();
+    let block_hash = mine::confirm(session, &txids, config)?;
+    let block_height = session.rpc.get_block_header_info(&block_hash)?.height;
+    say!("Block {} mined, confirming the chain.", block_hash);
+
+    let mut unconfirmed = Vec::new();
+    for link in &links {
+        let info = session.miner_rpc.get_transaction(&link.txid, None)?.info;
+        if info.blockhash != Some(block_hash) {
+            unconfirmed.push(link.txid);
+        }
+    }
+    if !unconfirmed.is_empty() {
+        return Err(CapstoneError::Unconfirmed(unconfirmed));
+    }
+    say!(
+        "All {} chained transactions confirmed together.",
+        links.len()
+    );
+    let block_txids: HashSet<Txid> = session
+        .rpc
+        .get_block(&block_hash)?
+        .txdata
+        .iter()
+        .map(|tx| tx.txid())
+        .collect();
+
+    // Every payment to the Trader must now be a confirmed UTXO; change outputs
+    // were spent by the next link, so for those "spent" is the expected answer
+    let mut utxo_lines = Vec::new();
+    for (i, link) in links.iter().enumerate() {
+        for (role, outpoint, value, script) in &link.outputs {
+            let status =
+                utxo::verify_utxo(&session.rpc, outpoint, *value, script, *role == "change")?;
+            utxo_lines.push(format!(
+                "Transaction {} {} output {}: {}\n",
+                i + 1,
+                role,
+                outpoint,
+                status.describe()
+            ));
+        }
+    }
+
+    let mut output_string = String::new();
+    output_string.push_str(&format!(
+        "Chain length: {} of {} requested\n",
+        links.len(),
+        count
+    ));
+    // The package as it sat in the mempool before the block
+    for line in &package {
+        output_string.push_str(&format!("Package: {}\n", line));
+    }
+    for (i, link) in links.iter().enumerate() {
+        output_string.push_str(&format!("Transaction {} (txid): {}\n", i + 1, link.txid));
+        output_string.push_str(&format!(
+            "Transaction {} fee (in {}): {}\n",
+            i + 1,
+            btc::unit(),
+            btc::format(link.fee)
+        ));
+        if let Some(entry) = &link.mempool {
+            output_string.push_str(&format!(
+                "Transaction {} feerate (sat/vB): {}\n",
+                i + 1,
+                fees::format_sat_per_vb(entry.individual_fee_rate())
+            ));
+            output_string.push_str(&format!(
+                "Transaction {} ancestor package feerate (sat/vB): {} over {} tx, {} vB\n",
+                i + 1,
+                fees::format_sat_per_vb(entry.ancestor_fee_rate()),
+                entry.ancestor_count,
+                entry.ancestor_size
+            ));
+            output_string.push_str(&format!(
+                "Transaction {} descendant package feerate (sat/vB): {} over {} tx, {} vB\n",
+                i + 1,
+                fees::format_sat_per_vb(entry.descendant_fee_rate()),
+                entry.descendant_count,
+                entry.descendant_size
+            ));
+        }
+        let together = link
+            .ancestors
+            .iter()
+            .all(|ancestor| block_txids.contains(ancestor));
+        output_string.push_str(&format!(
+            "Transaction {} confirmed in block {}, {} ancestor(s) {}\n",
+            i + 1,
+            block_hash,
+            link.ancestors.len(),
+            if together {
+                "confirmed in the same block"
+            } else {
+                "not all in the same block"
+            }
+        ));
+    }
+    if let Some(reason) = &stopped_early {
+        output_string.push_str(&format!("Stopped early: {}\n", reason));
+    }
+    for line in &utxo_lines {
+        output_string.push_str(line);
+    }
+    output_string.push_str(&format!(
+        "Block height at which the chain is confirmed: {}\n",
+        block_height
+    ));
+    output_string.push_str(&format!(
+        "Block hash at which the chain is confirmed: {}\n",
+        block_hash
+    ));
+
+    report::write_report(&output_string)
+}
@@ -0,0 +1,109 @@
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::session::Session;
+use bitcoincore_rpc::bitcoin::BlockHash;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+
+// getchaintxstats. The window fields after window_block_count are left out
+// by the node when they'd be meaningless: window_tx_count and window_interval
+// for an empty window, txrate when no time passed.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChainTxStats {
+    // Timestamp of the window's last block
+    pub time: u64,
+    pub txcount: u64,
+    pub window_final_block_hash: BlockHash,
+    pub window_final_block_height: u64,
+    pub window_block_count: u64,
+    #[serde(default)]
+    pub window_tx_count: Option<u64>,
+    // Seconds between the first and last block of the window, by their timestamps
+    #[serde(default)]
+    pub window_interval: Option<u64>,
+    // Transactions per second over the window
+    #[serde(default)]
+    pub txrate: Option<f64>,
+}
+
+impl ChainTxStats {
+    // The same rate counted in blocks, which unlike timestamps mocktime
+    // doesn't distort
+    pub fn tx_per_block(&self) -> Option<f64> {
+        match (self.window_tx_count, self.window_block_count) {
+            (Some(txs), blocks) if blocks > 0 => Some(txs as f64 / blocks as f64),
+            _ => None,
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} tx over the last {} blocks to height {}, {} tx/s, {} tx/block",
+            self.window_tx_count.unwrap_or(0),
+            self.window_block_count,
+            self.window_final_block_height,
+            self.txrate
+                .map_or("n/a".to_string(), |rate| format!("{:.4}", rate)),
+            self.tx_per_block()
+                .map_or("n/a".to_string(), |rate| format!("{:.2}", rate))
+        )
+    }
+}
+
+// getchaintxstats over the last `window` blocks, or the node's default of
+// about a month of blocks. The node refuses a window reaching back past the
+// genesis block, so it is capped at the tip height.
+pub fn chain_tx_stats(rpc: &Client, window: Option<u64>) -> Result<ChainTxStats> {
+    let tip = rpc.get_block_count()?;
+    let params = match window {
+        Some(0) => {
+            return Err(CapstoneError::Usage(
+                "--window must be at least one block".into(),
+            ))
+        }
+        Some(window) => vec![json!(window.min(tip.saturating_sub(1)).max(1))],
+        None => vec![],
+    };
+    Ok(rpc.call("getchaintxstats", &params)?)
+}
+
+// chain-stats: how busy the chain has been.
+pub fn run(session: &Session, window: Option<u64>) -> Result<()> {
+    let stats = chain_tx_stats(&session.rpc, window)?;
+    say!("Total transactions in the chain: {}", stats.txcount);
+    say!(
+        "Window: {} blocks ending at {} (height {})",
+        stats.window_block_count,
+        stats.window_final_block_hash,
+        stats.window_final_block_height
+    );
+    say!(
+        "Window transactions: {}",
+        stats
+            .window_tx_count
+            .map_or("none".to_string(), |txs| txs.to_string())
+    );
+    say!(
+        "Window interval (s): {}",
+        stats
+            .window_interval
+            .map_or("n/a".to_string(), |interval| interval.to_string())
+    );
+    say!(
+        "Window tx rate (tx/s): {}",
+        stats
+            .txrate
+            .map_or("n/a".to_string(), |rate| format!("{:.4}", rate))
+    );
+    say!(
+        "Window tx rate (tx/block): {}",
+        stats
+            .tx_per_block()
+            .map_or("n/a".to_string(), |rate| format!("{:.2}", rate))
+    );
+    if session.is_mock_time() {
+        say!("Block timestamps are mocked this run; the per-block rate is the meaningful one.");
+    }
+    Ok(())
+}
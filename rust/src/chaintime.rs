@@ -0,0 +1,340 @@
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::session::Session;
+use bitcoincore_rpc::bitcoin::absolute::LockTime;
+use bitcoincore_rpc::bitcoin::BlockHash;
+use bitcoincore_rpc::json::GetBlockHeaderResult;
+use bitcoincore_rpc::{Client, RpcApi};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Median time of the last 11 blocks. Time-based nLockTime and CSV are
+// measured against this (BIP113), not against the tip's own timestamp, which
+// miners can set well ahead.
+pub fn median_time_past(rpc: &Client) -> Result<u64> {
+    Ok(rpc.get_blockchain_info()?.median_time)
+}
+
+// The node's clock as it uses it for validation: local time plus the offset
+// it measured from its peers.
+pub fn adjusted_time(rpc: &Client) -> Result<u64> {
+    let offset = rpc.get_network_info()?.time_offset as i64;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64);
+    Ok((now + offset).max(0) as u64)
+}
+
+// Whether a transaction with `lock_time` could go in the next block: a height
+// lock needs the next block above it, a time lock needs the median time past
+// above it.
+pub fn lock_time_satisfied(rpc: &Client, lock_time: LockTime) -> Result<bool> {
+    Ok(match lock_time {
+        LockTime::Blocks(height) => {
+            u64::from(height.to_consensus_u32()) <= rpc.get_block_count()?
+        }
+        LockTime::Seconds(time) => u64::from(time.to_consensus_u32()) < median_time_past(rpc)?,
+    })
+}
+
+// A unix timestamp as RFC 3339 in UTC, e.g. 2011-09-13T19:16:26Z. Computed
+// by hand so it doesn't depend on the locale or time zone.
+pub fn rfc3339(unix: u64) -> String {
+    let days = (unix / 86_400) as i64;
+    let seconds = unix % 86_400;
+    // Days since 1970-01-01 to a civil date, after Howard Hinnant's civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3_600,
+        seconds % 3_600 / 60,
+        seconds % 60
+    )
+}
+
+// The inverse of `rfc3339`'s date arithmetic: days from 1970-01-01 to a
+// civil date, after Howard Hinnant's days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * mp + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// A time given on the command line: unix seconds, or RFC 3339 such as
+// 2011-09-13T19:16:26Z or 2011-09-13T21:16:26+02:00 (fractional seconds are
+// dropped).
+pub fn parse_time(s: &str) -> Result<u64> {
+    if let Ok(unix) = s.parse::<u64>() {
+        return Ok(unix);
+    }
+    let invalid = || {
+        CapstoneError::Usage(format!(
+            "'{}' is neither unix seconds nor an RFC 3339 time like 2011-09-13T19:16:26Z",
+            s
+        ))
+    };
+    let field = |range: std::ops::Range<usize>| -> Result<i64> {
+        s.get(range)
+            .filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|digits| digits.parse().ok())
+            .ok_or_else(invalid)
+    };
+    let separators_ok = s.len() >= 20
+        && &s[4..5] == "-"
+        && &s[7..8] == "-"
+        && matches!(&s[10..11], "T" | "t" | " ")
+        && &s[13..14] == ":"
+        && &s[16..17] == ":";
+    if !separators_ok {
+        return Err(invalid());
+    }
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return Err(invalid());
+    }
+
+    let mut rest = &s[19..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        rest = &fraction[digits..];
+    }
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && &rest[3..4] == ":" => {
+            let sign = match &rest[..1] {
+                "+" => 1,
+                "-" => -1,
+                _ => return Err(invalid()),
+            };
+            let hours: i64 = rest[1..3].parse().map_err(|_| invalid())?;
+            let minutes: i64 = rest[4..6].parse().map_err(|_| invalid())?;
+            sign * (hours * 3_600 + minutes * 60)
+        }
+        _ => return Err(invalid()),
+    };
+
+    let unix =
+        days_from_civil(year, month, day) * 86_400 + hour * 3_600 + minute * 60 + second - offset;
+    u64::try_from(unix).map_err(|_| invalid())
+}
+
+// A block found by `block_at_time`.
+#[derive(Debug, Clone)]
+pub struct BlockAtTime {
+    pub height: u64,
+    pub hash: BlockHash,
+    pub time: u64,
+    pub median_time: u64,
+    // The asked-for time is past the tip's median time, so later blocks may
+    // still come in before it
+    pub at_tip: bool,
+}
+
+// The last block whose median time past is at or before `time`. Header times
+// can go backwards from one block to the next, median times can't, so a
+// binary search over heights on the median time is well defined. None when
+// `time` is before the genesis block.
+pub fn block_at_time(rpc: &Client, time: u64) -> Result<Option<BlockAtTime>> {
+    let header_at = |height: u64| -> Result<(BlockHash, GetBlockHeaderResult)> {
+        let hash = rpc.get_block_hash(height)?;
+        Ok((hash, rpc.get_block_header_info(&hash)?))
+    };
+    let median_time =
+        |header: &GetBlockHeaderResult| header.median_time.unwrap_or(header.time) as u64;
+
+    let tip = rpc.get_block_count()?;
+    let Some(height) = search_heights(tip, time, |height| Ok(median_time(&header_at(height)?.1)))?
+    else {
+        return Ok(None);
+    };
+    let (hash, header) = header_at(height)?;
+    Ok(Some(BlockAtTime {
+        height,
+        hash,
+        time: header.time as u64,
+        median_time: median_time(&header),
+        at_tip: height == tip,
+    }))
+}
+
+// The binary search of `block_at_time` over heights `0..=tip`, given each
+// height's median time past
+fn search_heights(
+    tip: u64,
+    time: u64,
+    mut median_time_at: impl FnMut(u64) -> Result<u64>,
+) -> Result<Option<u64>> {
+    if median_time_at(0)? > time {
+        return Ok(None);
+    }
+    // Invariant: the block at `low` is at or before `time`, any above `high` is after
+    let (mut low, mut high) = (0, tip);
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+        if median_time_at(mid)? <= time {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    Ok(Some(low))
+}
+
+// block-at-time: print the last block at or before a given time.
+pub fn run(session: &Session, time: &str) -> Result<()> {
+    let time = parse_time(time)?;
+    if session.is_mock_time() {
+        say!("Mock time is active: block times follow the mocked clock, not the wall clock.");
+    }
+    let Some(found) = block_at_time(&session.rpc, time)? else {
+        let genesis = session
+            .rpc
+            .get_block_header_info(&session.rpc.get_block_hash(0)?)?;
+        return Err(CapstoneError::Usage(format!(
+            "{} is before the genesis block, which has time {}",
+            describe(time),
+            describe(genesis.time as u64)
+        )));
+    };
+    say!("Time: {}", describe(time));
+    say!("Block height: {}", found.height);
+    say!("Block hash: {}", found.hash);
+    say!("Block time: {}", describe(found.time));
+    say!("Block median time past: {}", describe(found.median_time));
+    if found.at_tip {
+        say!(
+            "This is the tip: the time is after every block so far, more may still come before it."
+        );
+    }
+    Ok(())
+}
+
+// "<unix> (<RFC 3339>)", how the report prints times
+pub fn describe(unix: u64) -> String {
+    format!("{} ({})", unix, rfc3339(unix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Median times of a synthetic regtest chain, 600 s apart, with a run of
+    // equal ones where header times went backwards
+    fn median_times() -> Vec<u64> {
+        let mut times: Vec<u64> = (0..50).map(|height| 1_296_688_602 + height * 600).collect();
+        for time in &mut times[20..25] {
+            *time = 1_296_688_602 + 20 * 600;
+        }
+        times
+    }
+
+    fn search(times: &[u64], time: u64) -> Option<u64> {
+        let tip = times.len() as u64 - 1;
+        search_heights(tip, time, |height| Ok(times[height as usize])).unwrap()
+    }
+
+    #[test]
+    fn before_genesis_finds_nothing() {
+        let times = median_times();
+        assert_eq!(search(&times, times[0] - 1), None);
+        assert_eq!(search(&times, 0), None);
+    }
+
+    #[test]
+    fn first_and_last_heights() {
+        let times = median_times();
+        assert_eq!(search(&times, times[0]), Some(0));
+        assert_eq!(search(&times, times[1] - 1), Some(0));
+        assert_eq!(search(&times, times[49]), Some(49));
+        // After the tip it's the tip
+        assert_eq!(search(&times, u64::MAX), Some(49));
+    }
+
+    #[test]
+    fn every_height_is_found_at_its_own_time() {
+        let times = median_times();
+        for height in (0..20).chain(25..50) {
+            assert_eq!(search(&times, times[height]), Some(height as u64));
+            assert_eq!(search(&times, times[height] + 1), Some(height as u64));
+        }
+    }
+
+    #[test]
+    fn equal_median_times_resolve_to_the_last_block() {
+        let times = median_times();
+        assert_eq!(search(&times, times[20]), Some(24));
+        assert_eq!(search(&times, times[20] - 1), Some(19));
+    }
+
+    #[test]
+    fn a_single_block_chain() {
+        assert_eq!(search(&[100], 100), Some(0));
+        assert_eq!(search(&[100], 99), None);
+    }
+
+    #[test]
+    fn the_search_reads_few_headers() {
+        let times: Vec<u64> = (0..100_000).collect();
+        let mut reads = 0;
+        let found = search_heights(99_999, 31_337, |height| {
+            reads += 1;
+            Ok(times[height as usize])
+        })
+        .unwrap();
+        assert_eq!(found, Some(31_337));
+        assert!(reads <= 18, "{} reads", reads);
+    }
+
+    #[test]
+    fn rfc3339_round_trips() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(1_296_688_602), "2011-02-02T23:16:42Z");
+        assert_eq!(rfc3339(951_782_400), "2000-02-29T00:00:00Z");
+        for unix in [0, 951_782_400, 1_296_688_602, 4_102_444_799] {
+            assert_eq!(parse_time(&rfc3339(unix)).unwrap(), unix);
+        }
+    }
+
+    #[test]
+    fn parse_time_forms() {
+        assert_eq!(parse_time("1296688602").unwrap(), 1_296_688_602);
+        assert_eq!(parse_time("2011-02-02T23:16:42Z").unwrap(), 1_296_688_602);
+        assert_eq!(
+            parse_time("2011-02-03T01:16:42+02:00").unwrap(),
+            1_296_688_602
+        );
+        assert_eq!(
+            parse_time("2011-02-02T23:16:42.999Z").unwrap(),
+            1_296_688_602
+        );
+        for bad in [
+            "yesterday",
+            "2011-02-02",
+            "2011-13-02T23:16:42Z",
+            "1969-12-31T23:59:59Z",
+        ] {
+            assert!(parse_time(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,1125 @@
+use crate::btc;
+use crate::error::{CapstoneError, Result};
+use crate::mempool;
+use crate::notify::NotifyUrl;
+use bitcoincore_rpc::bitcoin::{Amount, OutPoint, Sequence};
+use bitcoincore_rpc::json::{AddressType, EstimateMode};
+
+// Address type of the change output, as understood by the `change_type` option
+// of send/fundrawtransaction/walletcreatefundedpsbt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeType {
+    Legacy,
+    P2shSegwit,
+    Bech32,
+    Bech32m,
+}
+
+impl ChangeType {
+    pub fn as_rpc_str(self) -> &'static str {
+        match self {
+            ChangeType::Legacy => "legacy",
+            ChangeType::P2shSegwit => "p2sh-segwit",
+            ChangeType::Bech32 => "bech32",
+            ChangeType::Bech32m => "bech32m",
+        }
+    }
+
+    // The same value as the rpc crate's typed option, for fundrawtransaction
+    pub fn to_address_type(self) -> AddressType {
+        match self {
+            ChangeType::Legacy => AddressType::Legacy,
+            ChangeType::P2shSegwit => AddressType::P2shSegwit,
+            ChangeType::Bech32 => AddressType::Bech32,
+            ChangeType::Bech32m => AddressType::Bech32m,
+        }
+    }
+}
+
+impl std::str::FromStr for ChangeType {
+    type Err = CapstoneError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "legacy" => Ok(ChangeType::Legacy),
+            "p2sh-segwit" => Ok(ChangeType::P2shSegwit),
+            "bech32" => Ok(ChangeType::Bech32),
+            "bech32m" => Ok(ChangeType::Bech32m),
+            other => Err(CapstoneError::Usage(format!(
+                "unknown change type '{}' (expected legacy, p2sh-segwit, bech32 or bech32m)",
+                other
+            ))),
+        }
+    }
+}
+
+// How the report is echoed to stdout; out.txt is always the text. CSV also
+// goes to out.csv, see csvreport.rs, and HTML only to out.html, see
+// htmlreport.rs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Csv,
+    Html,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = CapstoneError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            "html" => Ok(OutputFormat::Html),
+            other => Err(CapstoneError::Usage(format!(
+                "unknown format '{}' (expected text, json, csv or html)",
+                other
+            ))),
+        }
+    }
+}
+
+// What amounts in the text reports and logs are given in; the JSON report
+// always has both. See btc::format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Units {
+    #[default]
+    Btc,
+    Sats,
+}
+
+impl std::str::FromStr for Units {
+    type Err = CapstoneError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "btc" => Ok(Units::Btc),
+            "sats" => Ok(Units::Sats),
+            other => Err(CapstoneError::Usage(format!(
+                "unknown units '{}' (expected btc or sats)",
+                other
+            ))),
+        }
+    }
+}
+
+// Signature hash type for the signing RPCs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SighashType {
+    // ALL for legacy/segwit v0 inputs, the 64-byte signature form for taproot
+    Default,
+    All,
+    None,
+    Single,
+    AllAnyoneCanPay,
+    NoneAnyoneCanPay,
+    SingleAnyoneCanPay,
+}
+
+impl SighashType {
+    pub fn as_rpc_str(self) -> &'static str {
+        match self {
+            SighashType::Default => "DEFAULT",
+            SighashType::All => "ALL",
+            SighashType::None => "NONE",
+            SighashType::Single => "SINGLE",
+            SighashType::AllAnyoneCanPay => "ALL|ANYONECANPAY",
+            SighashType::NoneAnyoneCanPay => "NONE|ANYONECANPAY",
+            SighashType::SingleAnyoneCanPay => "SINGLE|ANYONECANPAY",
+        }
+    }
+
+    pub fn anyone_can_pay(self) -> bool {
+        matches!(
+            self,
+            SighashType::AllAnyoneCanPay
+                | SighashType::NoneAnyoneCanPay
+                | SighashType::SingleAnyoneCanPay
+        )
+    }
+
+    pub fn is_single(self) -> bool {
+        matches!(self, SighashType::Single | SighashType::SingleAnyoneCanPay)
+    }
+}
+
+impl std::str::FromStr for SighashType {
+    type Err = CapstoneError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "default" => Ok(SighashType::Default),
+            "all" => Ok(SighashType::All),
+            "none" => Ok(SighashType::None),
+            "single" => Ok(SighashType::Single),
+            "all|anyonecanpay" => Ok(SighashType::AllAnyoneCanPay),
+            "none|anyonecanpay" => Ok(SighashType::NoneAnyoneCanPay),
+            "single|anyonecanpay" => Ok(SighashType::SingleAnyoneCanPay),
+            other => Err(CapstoneError::Usage(format!(
+                "unknown sighash type '{}' (expected default, all, none, single, or one of them with |anyonecanpay)",
+                other
+            ))),
+        }
+    }
+}
+
+// What the tool should do. `Pay` is the original assignment flow.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum Command {
+    #[default]
+    Pay,
+    // Send a chain of payments, each spending the previous one's unconfirmed change
+    ChainSpend {
+        count: usize,
+    },
+    // Break a wallet's funds into `count` outputs of `amount` each
+    Split {
+        wallet: String,
+        count: usize,
+        amount: Amount,
+    },
+    // Broadcast an externally signed PSBT and report on it like a normal payment
+    FinalizePsbt {
+        path: String,
+    },
+    // Show analyzepsbt/decodepsbt status for a PSBT file or base64 string
+    InspectPsbt {
+        source: String,
+    },
+    // Merge independently signed copies of a PSBT, then finalize and broadcast
+    CombinePsbt {
+        paths: Vec<String>,
+    },
+    // Add the Miner wallet's UTXO/derivation data to a foreign PSBT and sign it
+    EnrichPsbt {
+        path: String,
+    },
+    // Convert an unsigned raw transaction to a PSBT, sign, broadcast and report it
+    ToPsbt {
+        source: String,
+    },
+    // Miner and Trader pay each other in one joined transaction
+    CoinjoinDemo,
+    // Build (and optionally register) an M-of-N sortedmulti P2WSH address
+    MultisigAddress {
+        required: usize,
+        keys: Vec<String>,
+    },
+    // Compile a miniscript policy, lock coins to it and spend them back
+    PolicyDemo {
+        policy: String,
+    },
+    // Lock coins to the Trader until a block height and spend them once it is reached
+    VaultDemo {
+        unlock_height: u32,
+    },
+    // Send `count` small payments as fast as --rate allows and report aggregates
+    Stress {
+        count: usize,
+    },
+    // Two spends of one Miner coin, the second paying more: does it replace the first?
+    ConflictDemo,
+    // Predict size and fee of paying <address>=<btc> recipients, without funding
+    Estimate {
+        recipients: Vec<(String, Amount)>,
+    },
+    // Mine one block holding exactly the given txids or raw transactions
+    MineWith {
+        transactions: Vec<String>,
+    },
+    // Confirm a payment, invalidate its block and confirm it again on a longer chain
+    ReorgDemo,
+    // Mine across a subsidy halving and check the coinbases on both sides
+    HalvingDemo,
+    // getchaintxstats over the last --window blocks
+    ChainStats {
+        window: Option<u64>,
+    },
+    // verifychain at --checklevel over the last --nblocks blocks
+    VerifyChain {
+        checklevel: Option<u32>,
+        nblocks: Option<u64>,
+    },
+    // Summarize getblocktemplate and look for a pending txid (the Miner's, if none) in it
+    Template {
+        txid: Option<String>,
+    },
+    // List a wallet's immature coinbase outputs and when they can be spent
+    Maturity {
+        wallet: String,
+    },
+    // Load an assumeutxo snapshot and follow background validation
+    LoadSnapshot {
+        path: String,
+    },
+    // The last block at or before a unix or RFC 3339 time
+    BlockAtTime {
+        time: String,
+    },
+    // Regtest: mine a block every --interval seconds until Ctrl-C
+    Automine {
+        interval: u64,
+    },
+    // Summarize the mempool: sizes, fees, feerate percentiles, top transactions
+    Mempool,
+    // Store the mempool's txids, feerates, vsizes and inputs as JSON at --out
+    MempoolSnapshot {
+        out: String,
+    },
+    // What was added, confirmed, replaced or evicted since a snapshot
+    MempoolDiff {
+        old: String,
+        new: Option<String>,
+    },
+    // An unconfirmed transaction's in-mempool ancestors and descendants
+    TxFamily {
+        txid: String,
+    },
+    // Add a node-local fee delta to a transaction with prioritisetransaction
+    Prioritise {
+        txid: String,
+        fee_delta: i64,
+    },
+    // Regtest: a low-fee payment left out of the block template, then taken in
+    // once prioritised
+    PrioritiseDemo,
+    // Follow a transaction (or each --txids-from one) to --confirmations,
+    // rewriting the report on every block
+    Watch {
+        txid: Option<String>,
+    },
+    // Write the report for a confirmed transaction, or for each --txids-from one
+    Report {
+        txid: Option<String>,
+    },
+    // Find an address's funding and spending transactions through BIP158 block filters
+    FilterCheck {
+        address: String,
+        from: u64,
+    },
+    // Regtest: time a payment and its block travelling to a second node
+    PropagationTest {
+        peer_rpc: String,
+    },
+    // Core version, connections, relay fee, traffic and uptime of the node
+    NodeInfo,
+    // The --history file as a table, the last --last runs if given
+    HistoryShow {
+        path: String,
+        last: Option<usize>,
+    },
+    // The most recent runs in --db, the last --last (10 unless given)
+    DbQuery {
+        path: String,
+        last: Option<usize>,
+    },
+    // Fee statistics over every run in --db
+    DbStats {
+        path: String,
+    },
+}
+
+impl Command {
+    // Modes that only look at the chain and wallets, and so also work on an
+    // assumeutxo snapshot before it is validated
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Command::InspectPsbt { .. }
+                | Command::MultisigAddress { .. }
+                | Command::Estimate { .. }
+                | Command::ChainStats { .. }
+                | Command::VerifyChain { .. }
+                | Command::Template { .. }
+                | Command::Maturity { .. }
+                | Command::LoadSnapshot { .. }
+                | Command::BlockAtTime { .. }
+                | Command::Mempool
+                | Command::MempoolSnapshot { .. }
+                | Command::MempoolDiff { .. }
+                | Command::TxFamily { .. }
+                | Command::Watch { .. }
+                | Command::Report { .. }
+                | Command::HistoryShow { .. }
+                | Command::DbQuery { .. }
+                | Command::DbStats { .. }
+                | Command::FilterCheck { .. }
+                | Command::NodeInfo
+        )
+    }
+
+    // Modes that first rebroadcast what earlier runs left unconfirmed (see
+    // pending::resubmit): every mode that sends, and watch and report, which
+    // would otherwise wait on or report a transaction a restarted node lost
+    pub fn resubmits_pending(&self) -> bool {
+        !self.is_read_only() || matches!(self, Command::Watch { .. } | Command::Report { .. })
+    }
+}
+
+// Options controlling the capstone run. With no arguments the program behaves
+// exactly like the original assignment.
+#[derive(Debug, Default)]
+pub struct Config {
+    pub command: Command,
+    // Amount paid to the Trader, 20 BTC unless overridden
+    pub amount: Option<Amount>,
+    pub change_address: Option<String>,
+    pub change_type: Option<ChangeType>,
+    pub allow_external_change: bool,
+    pub conf_target: Option<u16>,
+    pub estimate_mode: Option<EstimateMode>,
+    // Upper bound on the fee we are willing to pay, 0.01 BTC unless overridden
+    pub max_fee: Option<Amount>,
+    // Build the payment with createrawtransaction/fundrawtransaction instead of `send`
+    pub raw: bool,
+    // Build, sign and testmempoolaccept the payment, but don't broadcast it
+    pub dry_run: bool,
+    // Print the decoded confirmed transaction
+    pub show_decoded: bool,
+    // Number of transactions for the modes that send several
+    pub count: Option<usize>,
+    // Mine a block after broadcasting in modes that don't confirm by default
+    pub confirm: bool,
+    // Take the fee out of the outputs rather than adding change
+    pub subtract_fee: bool,
+    // Where modes that produce UTXOs record them as JSON
+    pub outpoints_out: Option<String>,
+    // Pick the feerate ourselves (see fees::fee_estimate) instead of leaving it to the wallet
+    pub auto_fee: bool,
+    // sat/kvB used by --auto-fee when the node has no estimate, 1 sat/vB unless overridden
+    pub fallback_fee_rate: Option<u64>,
+    // Off regtest: start at a low feerate and bumpfee until the payment confirms
+    pub bump_until_confirmed: bool,
+    // Seconds between confirmation checks, 10 unless overridden
+    pub poll_interval: Option<u64>,
+    // Seconds a version may stay unconfirmed before it is bumped, 120 unless overridden
+    pub bump_interval: Option<u64>,
+    // Stop after funding a PSBT and write it here for an external signer
+    pub export_psbt: Option<String>,
+    // finalize-psbt: accept a PSBT even if none of its outputs pays the Trader wallet
+    pub allow_any_outputs: bool,
+    // Where commands that produce a PSBT write it, printed otherwise
+    pub psbt_out: Option<String>,
+    // to-psbt: accept a transaction that already carries signatures and drop them
+    pub force: bool,
+    // multisig-address: number of signatures required and the public keys (--keys a,b,c)
+    pub require: Option<usize>,
+    pub keys: Vec<String>,
+    // Wallet that should watch a generated address
+    pub register: Option<String>,
+    // Raw pipeline: sign with this WIF key (file:<path> or env:<VAR>) instead of the wallet
+    pub sign_with_key: Option<String>,
+    // maxfeerate for testmempoolaccept/sendrawtransaction in BTC/kvB, 0 disables it
+    pub max_broadcast_feerate: Option<Amount>,
+    // Sighash type for the raw and PSBT signing paths, DEFAULT unless overridden
+    pub sighash: Option<SighashType>,
+    // Output index for the change, random (the node's default) unless set
+    pub change_position: Option<u32>,
+    // policy-demo: the policy to compile, e.g. or(pk(A),and(pk(B),older(10)))
+    pub policy: Option<String>,
+    // vault-demo: block height the vault unlocks at
+    pub unlock_height: Option<u32>,
+    // stress: payments per second at most, unlimited unless set
+    pub rate: Option<u32>,
+    // conflict-demo: signal BIP125 replaceability on both spends
+    pub signal_rbf: bool,
+    // Pay with every coin on each address touched, see grouping::select_by_address
+    pub avoid_partial_spends: bool,
+    // Raw and PSBT paths: nSequence for named inputs (--input-sequence txid:vout=N),
+    // and for every other input
+    pub input_sequences: Vec<(OutPoint, Sequence)>,
+    pub default_sequence: Option<Sequence>,
+    // nLockTime of the payment, 0 unless set
+    pub locktime: Option<u32>,
+    // estimate: feerate in sat/kvB to predict at, the --fallback-fee-rate choice otherwise
+    pub fee_rate: Option<u64>,
+    // mine-with: the transactions (--tx, repeatable or comma separated) and the coinbase address
+    pub block_txs: Vec<String>,
+    pub mine_to: Option<String>,
+    // Confirm with a block holding only this run's transactions, see mine::confirm
+    pub exclusive_block: bool,
+    // Premine to this descriptor instead of the Miner address, see Session::mine_to_descriptor
+    pub mine_to_descriptor: Option<String>,
+    // Mock the node's clock from this unix time (the tip's if only --block-interval is given)
+    pub mocktime: Option<u64>,
+    // Seconds the mock clock moves before each block, 600 unless overridden
+    pub block_interval: Option<u64>,
+    // reorg-demo: leave the original block invalidated instead of reconsidering it
+    pub keep_invalidated: bool,
+    // Confirmations a payment needs before it is reported, 1 unless overridden
+    pub confirmations: Option<u32>,
+    // Seconds to wait for them, an hour unless overridden
+    pub confirm_timeout: Option<u64>,
+    // Premine and height advancement mine coinbase-only blocks, see Session::advance
+    pub empty_blocks: bool,
+    // Regtest: mine the blocks a confirmation wait needs rather than poll for them
+    pub auto_mine: bool,
+    // Mine to a separate Mining wallet that funds the Miner with a transfer
+    pub mining_wallet: bool,
+    // Spread the premine over this many labeled addresses
+    pub rotate_mining_addresses: Option<u64>,
+    // chain-stats: blocks to look back over, the node's default (a month) unless set
+    pub window: Option<u64>,
+    // verify-chain: how thoroughly, and how many blocks back (0 for all)
+    pub checklevel: Option<u32>,
+    pub nblocks: Option<u64>,
+    // Blocks fetched at once when scanning a height range, 4 unless overridden
+    pub parallelism: Option<usize>,
+    // automine: seconds between blocks, skip blocks while the mempool is empty,
+    // stop after this many (--to picks the reward address or wallet)
+    pub interval: Option<u64>,
+    pub only_when_txs: bool,
+    pub max_blocks: Option<u64>,
+    // stress: leave mining to something else, e.g. an automine running alongside
+    pub external_miner: bool,
+    // How long a broadcast payment may take to show up in the mempool, 10s
+    // unless overridden, and how often to look (milliseconds, 250 unless overridden)
+    pub mempool_timeout: Option<u64>,
+    pub mempool_poll_ms: Option<u64>,
+    // The node's hashblock ZMQ endpoint, or "auto" to ask getzmqnotifications
+    pub zmq: Option<String>,
+    // Print a one-line mempool summary before the payment picks its fee
+    pub mempool_summary: bool,
+    // mempool: a feerate histogram instead of the summary, its bucket edges
+    // (sat/vB, mempool::DEFAULT_HISTOGRAM_EDGES unless set), bucketing by
+    // ancestor feerate, and JSON output
+    pub histogram: bool,
+    pub histogram_edges: Vec<u64>,
+    pub ancestor_feerate: bool,
+    pub json: bool,
+    // tx-family: Graphviz DOT instead of the indented tree
+    pub dot: bool,
+    // Stop after broadcasting the payment: record it in pending.json and savemempool
+    pub no_confirm: bool,
+    // prioritise: the fee delta in sat, negative to deprioritise; also the
+    // delta prioritise-demo applies
+    pub fee_delta: Option<i64>,
+    // POST the confirmed payment here, see notify.rs
+    pub notify_url: Option<NotifyUrl>,
+    // Serve Prometheus metrics on this address (automine, watch and stress)
+    pub metrics_addr: Option<String>,
+    // Also write the payment's raw merkle proof to this file
+    pub proof_out: Option<String>,
+    // filter-check: the heights to scan, up to the tip unless --until is given
+    pub from: Option<u64>,
+    pub until: Option<u64>,
+    // Also find the confirmed payment through the block filters, as a light client would
+    pub verify_with_filters: bool,
+    // propagation-test: node B's RPC URL, and its P2P address for node A to
+    // connect to when they aren't connected yet
+    pub peer_rpc: Option<String>,
+    pub peer_p2p: Option<String>,
+    // Append `timestamp txid event` for every broadcast, see txidlog.rs
+    pub txid_log: Option<String>,
+    // watch and report: the txids to process instead of a <txid>, one per line
+    pub txids_from: Option<String>,
+    // mempool snapshot: where the snapshot goes
+    pub out: Option<String>,
+    // Also write the transaction report as JSON here, see TransactionReport::to_json
+    pub json_out: Option<String>,
+    pub format: OutputFormat,
+    // Render the transaction report through this template, see layout.rs
+    pub template: Option<String>,
+    // Append a line per reported transaction to this JSONL file, see history.rs
+    pub history: Option<String>,
+    // history show: only the most recent runs
+    pub last: Option<usize>,
+    pub units: Units,
+    // Write no out.txt/out.csv/out.html: the report goes to stdout, alone,
+    // and everything else to stderr
+    pub no_file: bool,
+    // Fail instead of creating missing parent directories of the files we write
+    pub strict_paths: bool,
+    // Also say what was skipped along the way, see console::verbose!
+    pub verbose: bool,
+    // Record each run in this SQLite database, see db.rs
+    pub db: Option<String>,
+    // End the text report with the raw transaction hex (the JSON always has it)
+    pub include_hex: bool,
+}
+
+impl Config {
+    pub fn amount(&self) -> Amount {
+        self.amount.unwrap_or(Amount::from_sat(20 * 100_000_000))
+    }
+
+    pub fn max_fee(&self) -> Amount {
+        self.max_fee.unwrap_or(Amount::from_sat(1_000_000))
+    }
+
+    pub fn fallback_fee_rate(&self) -> u64 {
+        self.fallback_fee_rate.unwrap_or(1000)
+    }
+
+    pub fn sighash(&self) -> SighashType {
+        self.sighash.unwrap_or(SighashType::Default)
+    }
+
+    // Core's own default is 0.10 BTC/kvB
+    pub fn max_broadcast_feerate(&self) -> Amount {
+        self.max_broadcast_feerate
+            .unwrap_or(Amount::from_sat(10_000_000))
+    }
+
+    pub fn poll_interval(&self) -> u64 {
+        self.poll_interval.unwrap_or(10)
+    }
+
+    pub fn bump_interval(&self) -> u64 {
+        self.bump_interval.unwrap_or(120)
+    }
+
+    pub fn confirmations(&self) -> u32 {
+        self.confirmations.unwrap_or(1).max(1)
+    }
+
+    pub fn confirm_timeout(&self) -> u64 {
+        self.confirm_timeout.unwrap_or(3600)
+    }
+
+    pub fn block_interval(&self) -> u64 {
+        self.block_interval.unwrap_or(600)
+    }
+
+    pub fn histogram_edges(&self) -> &[u64] {
+        if self.histogram_edges.is_empty() {
+            &mempool::DEFAULT_HISTOGRAM_EDGES
+        } else {
+            &self.histogram_edges
+        }
+    }
+
+    pub fn mempool_timeout(&self) -> u64 {
+        self.mempool_timeout.unwrap_or(10)
+    }
+
+    pub fn mempool_poll_ms(&self) -> u64 {
+        self.mempool_poll_ms.unwrap_or(250).max(1)
+    }
+
+    pub fn parallelism(&self) -> usize {
+        self.parallelism.unwrap_or(4).max(1)
+    }
+}
+
+fn parse_estimate_mode(s: &str) -> Result<EstimateMode> {
+    match s {
+        "economical" => Ok(EstimateMode::Economical),
+        "conservative" => Ok(EstimateMode::Conservative),
+        other => Err(CapstoneError::Usage(format!(
+            "unknown estimate mode '{}' (expected economical or conservative)",
+            other
+        ))),
+    }
+}
+
+fn parse_btc(flag: &str, value: &str) -> Result<Amount> {
+    btc::parse_btc(value).map_err(|e| {
+        CapstoneError::Usage(format!(
+            "{} expects a BTC amount, got '{}': {}",
+            flag, value, e
+        ))
+    })
+}
+
+// Parse a sat/vB rate with up to three decimals into sat/kvB, without going
+// through floats.
+fn parse_sat_per_vb(flag: &str, value: &str) -> Result<u64> {
+    let invalid = || {
+        CapstoneError::Usage(format!(
+            "{} expects a sat/vB rate with at most 3 decimals, got '{}'",
+            flag, value
+        ))
+    };
+    let (whole, frac) = value.split_once('.').unwrap_or((value, ""));
+    if whole.is_empty() && frac.is_empty() || frac.len() > 3 {
+        return Err(invalid());
+    }
+    let digits = |s: &str| s.is_empty() || s.bytes().all(|b| b.is_ascii_digit());
+    if !digits(whole) || !digits(frac) {
+        return Err(invalid());
+    }
+
+    let whole: u64 = if whole.is_empty() {
+        0
+    } else {
+        whole.parse().map_err(|_| invalid())?
+    };
+    let frac: u64 = format!("{:0<3}", frac).parse().map_err(|_| invalid())?;
+    whole
+        .checked_mul(1000)
+        .and_then(|w| w.checked_add(frac))
+        .ok_or_else(invalid)
+}
+
+// A sequence number in decimal or 0x-prefixed hex.
+fn parse_sequence(flag: &str, value: &str) -> Result<Sequence> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    };
+    parsed.map(Sequence).ok_or_else(|| {
+        CapstoneError::Usage(format!(
+            "{} expects a 32-bit sequence number, got '{}'",
+            flag, value
+        ))
+    })
+}
+
+// <txid>:<vout>=<sequence>
+fn parse_input_sequence(flag: &str, value: &str) -> Result<(OutPoint, Sequence)> {
+    let invalid = |reason: String| {
+        CapstoneError::Usage(format!(
+            "{} expects <txid>:<vout>=<sequence>, got '{}': {}",
+            flag, value, reason
+        ))
+    };
+    let (outpoint, sequence) = value
+        .split_once('=')
+        .ok_or_else(|| invalid("missing '='".into()))?;
+    let outpoint: OutPoint = outpoint.parse().map_err(|e| invalid(format!("{}", e)))?;
+    Ok((outpoint, parse_sequence(flag, sequence)?))
+}
+
+// Parse a numeric flag value, naming the flag in the error.
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| CapstoneError::Usage(format!("{} expects a number, got '{}'", flag, value)))
+}
+
+// Parse the command line (without the program name).
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config> {
+    let mut config = Config::default();
+    let mut args = args.into_iter();
+    let mut positionals = Vec::new();
+
+    while let Some(arg) = args.next() {
+        // Accept both `--flag value` and `--flag=value`
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                (flag.to_string(), Some(value.to_string()))
+            }
+            _ => (arg, None),
+        };
+        let mut value = || -> Result<String> {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| CapstoneError::Usage(format!("{} requires a value", flag)))
+        };
+
+        match flag.as_str() {
+            "--amount" => config.amount = Some(parse_btc(&flag, &value()?)?),
+            "--change-address" => config.change_address = Some(value()?),
+            "--change-type" => config.change_type = Some(value()?.parse()?),
+            "--allow-external-change" => config.allow_external_change = true,
+            "--conf-target" => config.conf_target = Some(parse_number(&flag, &value()?)?),
+            "--max-fee" => config.max_fee = Some(parse_btc(&flag, &value()?)?),
+            "--raw" => config.raw = true,
+            "--dry-run" => config.dry_run = true,
+            "--show-decoded" => config.show_decoded = true,
+            "--estimate-mode" => config.estimate_mode = Some(parse_estimate_mode(&value()?)?),
+            "--confirm" => config.confirm = true,
+            "--subtract-fee" => config.subtract_fee = true,
+            "--outpoints-out" => config.outpoints_out = Some(value()?),
+            "--count" => config.count = Some(parse_number(&flag, &value()?)?),
+            "--auto-fee" => config.auto_fee = true,
+            "--export-psbt" => config.export_psbt = Some(value()?),
+            "--allow-any-outputs" => config.allow_any_outputs = true,
+            "--psbt-out" => config.psbt_out = Some(value()?),
+            "--force" => config.force = true,
+            "--signal-rbf" => config.signal_rbf = true,
+            "--avoid-partial-spends" => config.avoid_partial_spends = true,
+            "--input-sequence" => config
+                .input_sequences
+                .push(parse_input_sequence(&flag, &value()?)?),
+            "--default-sequence" => {
+                config.default_sequence = Some(parse_sequence(&flag, &value()?)?)
+            }
+            "--locktime" => config.locktime = Some(parse_number(&flag, &value()?)?),
+            "--require" => config.require = Some(parse_number(&flag, &value()?)?),
+            "--keys" => config.keys.extend(
+                value()?
+                    .split(',')
+                    .filter(|k| !k.is_empty())
+                    .map(String::from),
+            ),
+            "--register" => config.register = Some(value()?),
+            "--tx" => config.block_txs.extend(
+                value()?
+                    .split(',')
+                    .filter(|t| !t.is_empty())
+                    .map(String::from),
+            ),
+            "--to" => config.mine_to = Some(value()?),
+            "--exclusive-block" => config.exclusive_block = true,
+            "--keep-invalidated" => config.keep_invalidated = true,
+            "--empty-blocks" => config.empty_blocks = true,
+            "--auto-mine" => config.auto_mine = true,
+            "--mining-wallet" => config.mining_wallet = true,
+            "--window" => config.window = Some(parse_number(&flag, &value()?)?),
+            "--checklevel" => config.checklevel = Some(parse_number(&flag, &value()?)?),
+            "--nblocks" => config.nblocks = Some(parse_number(&flag, &value()?)?),
+            "--interval" => config.interval = Some(parse_number(&flag, &value()?)?),
+            "--only-when-txs" => config.only_when_txs = true,
+            "--max-blocks" => config.max_blocks = Some(parse_number(&flag, &value()?)?),
+            "--external-miner" => config.external_miner = true,
+            "--zmq" => config.zmq = Some(value()?),
+            "--mempool-summary" => config.mempool_summary = true,
+            "--histogram" => config.histogram = true,
+            "--histogram-edges" => {
+                let edges = value()?;
+                for edge in edges.split(',').filter(|e| !e.is_empty()) {
+                    config.histogram_edges.push(parse_number(&flag, edge)?);
+                }
+            }
+            "--ancestor-feerate" => config.ancestor_feerate = true,
+            "--json" => config.json = true,
+            "--dot" => config.dot = true,
+            "--no-confirm" => config.no_confirm = true,
+            "--peer-rpc" => config.peer_rpc = Some(value()?),
+            "--peer-p2p" => config.peer_p2p = Some(value()?),
+            "--from" => config.from = Some(parse_number(&flag, &value()?)?),
+            "--until" => config.until = Some(parse_number(&flag, &value()?)?),
+            "--verify-with-filters" => config.verify_with_filters = true,
+            "--proof-out" => config.proof_out = Some(value()?),
+            "--metrics-addr" => config.metrics_addr = Some(value()?),
+            "--txid-log" => config.txid_log = Some(value()?),
+            "--txids-from" => config.txids_from = Some(value()?),
+            "--out" => config.out = Some(value()?),
+            "--json-out" => config.json_out = Some(value()?),
+            "--format" => config.format = value()?.parse()?,
+            "--units" => config.units = value()?.parse()?,
+            "--no-file" => config.no_file = true,
+            "--strict-paths" => config.strict_paths = true,
+            "--verbose" => config.verbose = true,
+            "--db" => config.db = Some(value()?),
+            "--include-hex" => config.include_hex = true,
+            "--template" => config.template = Some(value()?),
+            "--history" => config.history = Some(value()?),
+            "--last" => config.last = Some(parse_number(&flag, &value()?)?),
+            "--notify-url" => config.notify_url = Some(NotifyUrl::parse(&value()?)?),
+            "--fee-delta" => config.fee_delta = Some(parse_number(&flag, &value()?)?),
+            "--mempool-timeout" => config.mempool_timeout = Some(parse_number(&flag, &value()?)?),
+            "--mempool-poll-ms" => config.mempool_poll_ms = Some(parse_number(&flag, &value()?)?),
+            "--parallelism" => config.parallelism = Some(parse_number(&flag, &value()?)?),
+            "--rotate-mining-addresses" => {
+                config.rotate_mining_addresses = Some(parse_number(&flag, &value()?)?)
+            }
+            "--confirmations" => config.confirmations = Some(parse_number(&flag, &value()?)?),
+            "--confirm-timeout" => config.confirm_timeout = Some(parse_number(&flag, &value()?)?),
+            "--mine-to-descriptor" => config.mine_to_descriptor = Some(value()?),
+            "--mocktime" => config.mocktime = Some(parse_number(&flag, &value()?)?),
+            "--block-interval" => config.block_interval = Some(parse_number(&flag, &value()?)?),
+            "--policy" => config.policy = Some(value()?),
+            "--rate" => config.rate = Some(parse_number(&flag, &value()?)?),
+            "--unlock-height" => config.unlock_height = Some(parse_number(&flag, &value()?)?),
+            "--sign-with-key" => config.sign_with_key = Some(value()?),
+            "--sighash" => config.sighash = Some(value()?.parse()?),
+            "--change-position" => {
+                config.change_position = match value()?.as_str() {
+                    "random" => None,
+                    position => Some(parse_number(&flag, position)?),
+                }
+            }
+            "--max-broadcast-feerate" => {
+                config.max_broadcast_feerate = Some(parse_btc(&flag, &value()?)?)
+            }
+            "--bump-until-confirmed" => config.bump_until_confirmed = true,
+            "--poll-interval" => config.poll_interval = Some(parse_number(&flag, &value()?)?),
+            "--bump-interval" => config.bump_interval = Some(parse_number(&flag, &value()?)?),
+            "--fee-rate" => config.fee_rate = Some(parse_sat_per_vb(&flag, &value()?)?),
+            "--fallback-fee-rate" => {
+                config.fallback_fee_rate = Some(parse_sat_per_vb(&flag, &value()?)?)
+            }
+            other if !other.starts_with("--") => positionals.push(other.to_string()),
+            other => {
+                return Err(CapstoneError::Usage(format!(
+                    "unknown argument '{}'",
+                    other
+                )))
+            }
+        }
+    }
+
+    config.command = parse_command(&positionals, &config)?;
+    if config.sign_with_key.is_some() && !config.raw {
+        return Err(CapstoneError::Usage(
+            "--sign-with-key only works with --raw".into(),
+        ));
+    }
+    // `send` signs internally and takes no sighash type
+    if config.sighash.is_some() && config.command == Command::Pay && !config.raw {
+        return Err(CapstoneError::Usage(
+            "--sighash needs --raw for payments".into(),
+        ));
+    }
+    if config.mining_wallet && config.mine_to_descriptor.is_some() {
+        return Err(CapstoneError::Usage(
+            "--mining-wallet mines to the Mining wallet, drop --mine-to-descriptor".into(),
+        ));
+    }
+    if config.rotate_mining_addresses.is_some() && config.mine_to_descriptor.is_some() {
+        return Err(CapstoneError::Usage(
+            "--rotate-mining-addresses and --mine-to-descriptor both pick where rewards go, use one"
+                .into(),
+        ));
+    }
+    if config.metrics_addr.is_some()
+        && !matches!(
+            config.command,
+            Command::Automine { .. } | Command::Watch { .. } | Command::Stress { .. }
+        )
+    {
+        return Err(CapstoneError::Usage(
+            "--metrics-addr is for the long-running modes: automine, watch and stress".into(),
+        ));
+    }
+    if config.txids_from.is_some()
+        && !matches!(
+            config.command,
+            Command::Watch { .. } | Command::Report { .. }
+        )
+    {
+        return Err(CapstoneError::Usage(
+            "--txids-from is for the watch and report modes".into(),
+        ));
+    }
+    if config.no_confirm && config.bump_until_confirmed {
+        return Err(CapstoneError::Usage(
+            "--no-confirm stops before confirmation, drop --bump-until-confirmed".into(),
+        ));
+    }
+    if config
+        .histogram_edges
+        .windows(2)
+        .any(|pair| pair[0] >= pair[1])
+    {
+        return Err(CapstoneError::Usage(
+            "--histogram-edges must be increasing, e.g. 1,2,5,10,20".into(),
+        ));
+    }
+    if cfg!(not(feature = "sqlite")) && config.db.is_some() {
+        return Err(CapstoneError::Usage(
+            "--db needs a build with the sqlite feature (cargo build --features sqlite)".into(),
+        ));
+    }
+    if cfg!(not(feature = "zmq")) && config.zmq.is_some() {
+        return Err(CapstoneError::Usage(
+            "--zmq needs a build with the zmq feature (cargo build --features zmq)".into(),
+        ));
+    }
+    Ok(config)
+}
+
+// The first positional argument selects the mode.
+fn parse_command(positionals: &[String], config: &Config) -> Result<Command> {
+    let mut rest = positionals.iter();
+    let name = match rest.next() {
+        Some(name) => name.as_str(),
+        None => return Ok(Command::Pay),
+    };
+    let mut positional = |what: &str| {
+        rest.next()
+            .cloned()
+            .ok_or_else(|| CapstoneError::Usage(format!("{} requires {}", name, what)))
+    };
+
+    let command = match name {
+        "chain-spend" => Command::ChainSpend {
+            count: required(config.count, "chain-spend", "--count")?,
+        },
+        "split" => Command::Split {
+            wallet: positional("<wallet>")?,
+            count: required(config.count, "split", "--count")?,
+            amount: required(config.amount, "split", "--amount")?,
+        },
+        "finalize-psbt" => Command::FinalizePsbt {
+            path: positional("<path>")?,
+        },
+        "inspect-psbt" => Command::InspectPsbt {
+            source: positional("<path-or-base64>")?,
+        },
+        "enrich-psbt" => Command::EnrichPsbt {
+            path: positional("<path>")?,
+        },
+        "coinjoin-demo" => Command::CoinjoinDemo,
+        "conflict-demo" => Command::ConflictDemo,
+        "reorg-demo" => Command::ReorgDemo,
+        "halving-demo" => Command::HalvingDemo,
+        "chain-stats" => Command::ChainStats {
+            window: config.window,
+        },
+        "verify-chain" => {
+            if config.checklevel.is_some_and(|level| level > 4) {
+                return Err(CapstoneError::Usage("--checklevel goes from 0 to 4".into()));
+            }
+            Command::VerifyChain {
+                checklevel: config.checklevel,
+                nblocks: config.nblocks,
+            }
+        }
+        "mempool" => match rest.next().map(String::as_str) {
+            None => Command::Mempool,
+            Some("snapshot") => Command::MempoolSnapshot {
+                out: required(config.out.clone(), "mempool snapshot", "--out")?,
+            },
+            Some("diff") => Command::MempoolDiff {
+                old: rest
+                    .next()
+                    .cloned()
+                    .ok_or_else(|| CapstoneError::Usage("mempool diff requires <old>".into()))?,
+                new: rest.next().cloned(),
+            },
+            Some(other) => {
+                return Err(CapstoneError::Usage(format!(
+                    "unknown mempool subcommand '{}', expected snapshot or diff",
+                    other
+                )))
+            }
+        },
+        "prioritise" => Command::Prioritise {
+            txid: positional("<txid>")?,
+            fee_delta: required(config.fee_delta, "prioritise", "--fee-delta")?,
+        },
+        "prioritise-demo" => Command::PrioritiseDemo,
+        "filter-check" => Command::FilterCheck {
+            address: positional("<address>")?,
+            from: required(config.from, "filter-check", "--from")?,
+        },
+        "node-info" => Command::NodeInfo,
+        "history" => match rest.next().map(String::as_str) {
+            Some("show") => Command::HistoryShow {
+                path: required(config.history.clone(), "history show", "--history")?,
+                last: config.last,
+            },
+            _ => {
+                return Err(CapstoneError::Usage(
+                    "history takes the subcommand show".into(),
+                ))
+            }
+        },
+        "db" => match rest.next().map(String::as_str) {
+            Some("query") => Command::DbQuery {
+                path: required(config.db.clone(), "db query", "--db")?,
+                last: config.last,
+            },
+            Some("stats") => Command::DbStats {
+                path: required(config.db.clone(), "db stats", "--db")?,
+            },
+            _ => {
+                return Err(CapstoneError::Usage(
+                    "db takes the subcommand query or stats".into(),
+                ))
+            }
+        },
+        "propagation-test" => Command::PropagationTest {
+            peer_rpc: required(config.peer_rpc.clone(), "propagation-test", "--peer-rpc")?,
+        },
+        "watch" => Command::Watch {
+            txid: match config.txids_from {
+                Some(_) => None,
+                None => Some(positional("<txid> or --txids-from")?),
+            },
+        },
+        "report" => Command::Report {
+            txid: match config.txids_from {
+                Some(_) => None,
+                None => Some(positional("<txid> or --txids-from")?),
+            },
+        },
+        "tx-family" => Command::TxFamily {
+            txid: positional("<txid>")?,
+        },
+        "automine" => Command::Automine {
+            interval: config.interval.unwrap_or(10).max(1),
+        },
+        "block-at-time" => Command::BlockAtTime {
+            time: positional("<time>")?,
+        },
+        "load-snapshot" => Command::LoadSnapshot {
+            path: positional("<path>")?,
+        },
+        "maturity" => Command::Maturity {
+            wallet: positional("<wallet>")?,
+        },
+        "template" => Command::Template {
+            txid: rest.next().cloned(),
+        },
+        "mine-with" => {
+            if config.block_txs.is_empty() {
+                return Err(CapstoneError::Usage(
+                    "mine-with requires at least one --tx <txid-or-hex>".into(),
+                ));
+            }
+            Command::MineWith {
+                transactions: config.block_txs.clone(),
+            }
+        }
+        "multisig-address" => Command::MultisigAddress {
+            required: required(config.require, "multisig-address", "--require")?,
+            keys: config.keys.clone(),
+        },
+        "policy-demo" => Command::PolicyDemo {
+            policy: required(config.policy.clone(), "policy-demo", "--policy")?,
+        },
+        "stress" => Command::Stress {
+            count: required(config.count, "stress", "--count")?,
+        },
+        "vault-demo" => Command::VaultDemo {
+            unlock_height: required(config.unlock_height, "vault-demo", "--unlock-height")?,
+        },
+        "to-psbt" => Command::ToPsbt {
+            source: positional("<hex-or-file>")?,
+        },
+        "combine-psbt" => {
+            let paths: Vec<String> = rest.by_ref().cloned().collect();
+            if paths.len() < 2 {
+                return Err(CapstoneError::Usage(
+                    "combine-psbt requires at least two <file> arguments".into(),
+                ));
+            }
+            Command::CombinePsbt { paths }
+        }
+        "estimate" => {
+            let recipients = rest
+                .by_ref()
+                .map(|recipient| parse_recipient(recipient))
+                .collect::<Result<Vec<_>>>()?;
+            if recipients.is_empty() {
+                return Err(CapstoneError::Usage(
+                    "estimate requires at least one <address>=<btc> argument".into(),
+                ));
+            }
+            Command::Estimate { recipients }
+        }
+        other => return Err(CapstoneError::Usage(format!("unknown command '{}'", other))),
+    };
+
+    if let Some(extra) = rest.next() {
+        return Err(CapstoneError::Usage(format!(
+            "unexpected argument '{}' for {}",
+            extra, name
+        )));
+    }
+    Ok(command)
+}
+
+// <address>=<btc>, one recipient of the estimate command
+fn parse_recipient(recipient: &str) -> Result<(String, Amount)> {
+    let (address, amount) = recipient.split_once('=').ok_or_else(|| {
+        CapstoneError::Usage(format!(
+            "recipient '{}' should be <address>=<btc>",
+            recipient
+        ))
+    })?;
+    Ok((address.to_string(), parse_btc(address, amount)?))
+}
+
+fn required<T>(value: Option<T>, command: &str, flag: &str) -> Result<T> {
+    value.ok_or_else(|| CapstoneError::Usage(format!("{} requires {}", command, flag)))
+}
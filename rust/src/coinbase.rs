@@ -0,0 +1,154 @@
+use crate::block;
+use crate::btc;
+use crate::error::{CapstoneError, Result};
+use crate::session;
+use bitcoincore_rpc::bitcoin::hex::FromHex;
+use bitcoincore_rpc::bitcoin::opcodes::all::{OP_PUSHNUM_1, OP_PUSHNUM_16};
+use bitcoincore_rpc::bitcoin::{Address, Amount, BlockHash, Network, Txid};
+use bitcoincore_rpc::Client;
+
+// The coinbase of the block confirming the payment, taken apart.
+#[derive(Debug, Clone)]
+pub struct CoinbaseAnalysis {
+    pub txid: Txid,
+    pub total: Amount,
+    // What the height entitles the miner to, and the rest of what it claimed
+    pub subsidy: Amount,
+    pub fees: Amount,
+    // (amount, address, script type) of every output, the witness commitment included
+    pub outputs: Vec<(Amount, Option<Address>, String)>,
+    // The height pushed at the start of the coinbase scriptSig (BIP34)
+    pub bip34_height: Option<u64>,
+}
+
+// The number a BIP34 scriptSig starts with: OP_1..OP_16 for heights up to 16
+// (and OP_0 for 0), otherwise a little-endian push of up to 8 bytes whose
+// top bit is the sign.
+pub fn bip34_height(script_sig: &[u8]) -> Option<u64> {
+    let (&first, rest) = script_sig.split_first()?;
+    match first {
+        0x00 => Some(0),
+        op if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&op) => {
+            Some(u64::from(op - OP_PUSHNUM_1.to_u8() + 1))
+        }
+        len @ 1..=8 => {
+            let bytes = rest.get(..len as usize)?;
+            if bytes.last()? & 0x80 != 0 {
+                return None;
+            }
+            Some(
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0u64, |height, byte| (height << 8) | u64::from(*byte)),
+            )
+        }
+        _ => None,
+    }
+}
+
+// Fetch and check the coinbase of `block_hash`. The BIP34 height has to match
+// the block's, and the part of the coinbase beyond the subsidy, the fees, has
+// to cover at least `our_fee`; with fee stats for the block it has to equal
+// their total as well.
+pub fn analyze(
+    rpc: &Client,
+    block_hash: &BlockHash,
+    network: Network,
+    our_fee: Amount,
+    stats_total_fee: Option<Amount>,
+) -> Result<CoinbaseAnalysis> {
+    let block = block::get_block_full(rpc, block_hash)?;
+    let coinbase = block.coinbase().ok_or_else(|| {
+        CapstoneError::CoinbaseCheckFailed(format!("block {} has no coinbase", block_hash))
+    })?;
+
+    let total = coinbase.output_value();
+    let subsidy = session::block_subsidy(block.height, network);
+    let fees = total.checked_sub(subsidy).unwrap_or(Amount::ZERO);
+    if our_fee > fees {
+        return Err(CapstoneError::CoinbaseCheckFailed(format!(
+            "the coinbase collects {} of fees, less than the {} our transaction paid",
+            btc::amount(fees),
+            btc::amount(our_fee)
+        )));
+    }
+    if let Some(stats_total_fee) = stats_total_fee {
+        if stats_total_fee != fees {
+            return Err(CapstoneError::CoinbaseCheckFailed(format!(
+                "the coinbase collects {} of fees but getblockstats totals {}",
+                btc::amount(fees),
+                btc::amount(stats_total_fee)
+            )));
+        }
+    }
+
+    let script_sig = coinbase
+        .vin
+        .first()
+        .and_then(|vin| vin.coinbase.as_deref())
+        .and_then(|hex| Vec::<u8>::from_hex(hex).ok())
+        .unwrap_or_default();
+    let bip34_height = bip34_height(&script_sig);
+    if bip34_height != Some(block.height) {
+        return Err(CapstoneError::CoinbaseCheckFailed(format!(
+            "the coinbase scriptSig starts with height {:?}, the block is at {}",
+            bip34_height, block.height
+        )));
+    }
+
+    let outputs = coinbase
+        .vout
+        .iter()
+        .map(|vout| {
+            (
+                vout.value,
+                vout.script_pub_key
+                    .address
+                    .clone()
+                    .map(|address| address.assume_checked()),
+                vout.script_pub_key.kind.clone(),
+            )
+        })
+        .collect();
+    Ok(CoinbaseAnalysis {
+        txid: coinbase.txid,
+        total,
+        subsidy,
+        fees,
+        outputs,
+        bip34_height,
+    })
+}
+
+impl CoinbaseAnalysis {
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("Coinbase transaction ID (txid): {}", self.txid),
+            format!(
+                "Coinbase value (in {}): {} = subsidy {} + fees {}",
+                btc::unit(),
+                btc::format(self.total),
+                btc::format(self.subsidy),
+                btc::format(self.fees)
+            ),
+            format!(
+                "Coinbase BIP34 height: {}",
+                self.bip34_height
+                    .map_or("none".to_string(), |height| height.to_string())
+            ),
+        ];
+        for (i, (amount, address, kind)) in self.outputs.iter().enumerate() {
+            lines.push(format!(
+                "Coinbase output {}: {} to {} ({})",
+                i,
+                btc::amount(*amount),
+                address
+                    .as_ref()
+                    .map_or("(no address)".to_string(), |a| a.to_string()),
+                kind
+            ));
+        }
+        lines
+    }
+}
@@ -0,0 +1,196 @@
+use crate::accept;
+use crate::btc;
+use crate::cli::Config;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::inputs::PresetInputs;
+use crate::mine;
+use crate::prevout::PrevoutResolver;
+use crate::psbt;
+use crate::raw;
+use crate::report;
+use crate::session::Session;
+use crate::txidlog;
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::{Address, Amount, OutPoint};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde_json::json;
+use std::collections::HashSet;
+
+// Each party's payment in the demo, and what the Trader gets up front so it
+// has coins of its own to contribute.
+const DEFAULT_AMOUNT: Amount = Amount::ONE_BTC;
+const TRADER_FUNDING: Amount = Amount::from_sat(10 * 100_000_000);
+
+// Who an input or output of the joined transaction belongs to.
+fn owner(is_miner: bool, is_trader: bool) -> &'static str {
+    match (is_miner, is_trader) {
+        (true, _) => "Miner",
+        (_, true) => "Trader",
+        _ => "unknown",
+    }
+}
+
+fn outpoints(rpc: &Client, psbt: &str) -> Result<HashSet<OutPoint>> {
+    let decoded = psbt::decode_psbt(rpc, psbt)?;
+    Ok(decoded
+        .tx
+        .vin
+        .iter()
+        .filter_map(|vin| Some(OutPoint::new(vin.txid?, vin.vout?)))
+        .collect())
+}
+
+fn is_mine(wallet_rpc: &Client, address: &Address) -> Result<bool> {
+    Ok(wallet_rpc.get_address_info(address)?.is_mine == Some(true))
+}
+
+// Two-party coinjoin: Miner and Trader each fund a PSBT paying the other, the
+// PSBTs are merged with joinpsbts, both wallets sign their own inputs of the
+// joined PSBT, and the single transaction is broadcast and confirmed.
+pub fn run(session: &Session, config: &Config) -> Result<()> {
+    let rpc = &session.rpc;
+    let miner_rpc = &session.miner_rpc;
+    let trader_rpc = &session.trader_rpc;
+    if config.change_address.is_some() {
+        return Err(CapstoneError::Usage(
+            "coinjoin-demo lets each wallet pick its own change address, drop --change-address"
+                .into(),
+        ));
+    }
+
+    let amount = config.amount.unwrap_or(DEFAULT_AMOUNT);
+    session.fund_miner(amount + TRADER_FUNDING + config.max_fee())?;
+
+    // The Trader needs confirmed coins of its own to join with
+    if trader_rpc.get_balances()?.mine.trusted < amount + Amount::ONE_BTC {
+        let funding_address = trader_rpc.get_new_address(None, None)?.assume_checked();
+        let funding_txid = miner_rpc.send_to_address(
+            &funding_address,
+            TRADER_FUNDING,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )?;
+        txidlog::record(&funding_txid, "funding");
+        session.generate(1, &session.miner_address)?;
+        say!("Funded Trader with {}.", btc::amount(TRADER_FUNDING));
+    }
+
+    // Each side pays a fresh address of the other
+    let to_trader = trader_rpc.get_new_address(None, None)?.assume_checked();
+    let to_miner = miner_rpc.get_new_address(None, None)?.assume_checked();
+    let miner_psbt = psbt::fund_psbt(
+        miner_rpc,
+        &to_trader,
+        amount,
+        None,
+        &PresetInputs::default(),
+        config,
+    )?;
+    let trader_psbt = psbt::fund_psbt(
+        trader_rpc,
+        &to_miner,
+        amount,
+        None,
+        &PresetInputs::default(),
+        config,
+    )?;
+
+    // joinpsbts fails on shared inputs; check first so the error says why
+    let miner_inputs = outpoints(rpc, &miner_psbt.psbt)?;
+    let trader_inputs = outpoints(rpc, &trader_psbt.psbt)?;
+    if let Some(shared) = miner_inputs.intersection(&trader_inputs).next() {
+        return Err(CapstoneError::Usage(format!(
+            "both PSBTs spend {}, they can't be joined",
+            shared
+        )));
+    }
+
+    let joined: String = rpc.call("joinpsbts", &[json!([miner_psbt.psbt, trader_psbt.psbt])])?;
+    say!("Joined the Miner and Trader PSBTs.");
+
+    // Each wallet only signs its own inputs, so neither pass alone is complete
+    let signed = psbt::process_psbt(miner_rpc, &joined, config.sighash())?;
+    let signed = psbt::process_psbt(trader_rpc, &signed.psbt, config.sighash())?;
+    psbt::analyze_psbt(rpc, &signed.psbt)?.ensure_finalizable()?;
+
+    let hex = psbt::finalize_to_hex(rpc, &signed.psbt)?;
+    accept::ensure_accepted(
+        rpc,
+        std::slice::from_ref(&hex),
+        config.max_broadcast_feerate(),
+    )?;
+    let txid = raw::broadcast(rpc, &hex, config)?;
+    say!("Coinjoin transaction sent! TXID: {}", txid);
+
+    let block_hash = mine::confirm(session, &[txid], config)?;
+    let block_height = rpc.get_block_header_info(&block_hash)?.height;
+    say!("Block {} mined, confirming the coinjoin.", block_hash);
+
+    let tx = rpc.get_raw_transaction_info(&txid, Some(&block_hash))?;
+    let mut prevouts = PrevoutResolver::new(rpc)?;
+    let mut output_string = String::new();
+    output_string.push_str(&format!("Coinjoin transaction ID (txid): {}\n", txid));
+    for vin in &tx.vin {
+        if let (Some(prev_txid), Some(prev_vout)) = (vin.txid, vin.vout) {
+            let outpoint = OutPoint::new(prev_txid, prev_vout);
+            let value = prevouts.resolve(&outpoint)?.value;
+            output_string.push_str(&format!(
+                "Input {} ({}): {}\n",
+                outpoint,
+                owner(
+                    miner_inputs.contains(&outpoint),
+                    trader_inputs.contains(&outpoint)
+                ),
+                btc::amount(value)
+            ));
+        }
+    }
+    for vout in &tx.vout {
+        let address = vout
+            .script_pub_key
+            .address
+            .clone()
+            .map(|a: Address<NetworkUnchecked>| a.assume_checked());
+        let party = match &address {
+            Some(address) => owner(is_mine(miner_rpc, address)?, is_mine(trader_rpc, address)?),
+            None => "unknown",
+        };
+        output_string.push_str(&format!(
+            "Output {} ({}): {} to {}\n",
+            vout.n,
+            party,
+            btc::amount(vout.value),
+            address.map_or("(no address)".to_string(), |a| a.to_string())
+        ));
+    }
+    output_string.push_str(&format!(
+        "Miner's share of the fee (in {}): {}\n",
+        btc::unit(),
+        btc::format(miner_psbt.fee)
+    ));
+    output_string.push_str(&format!(
+        "Trader's share of the fee (in {}): {}\n",
+        btc::unit(),
+        btc::format(trader_psbt.fee)
+    ));
+    output_string.push_str(&format!(
+        "Total fee (in {}): {}\n",
+        btc::unit(),
+        btc::format(miner_psbt.fee + trader_psbt.fee)
+    ));
+    output_string.push_str(&format!(
+        "Block height at which the coinjoin is confirmed: {}\n",
+        block_height
+    ));
+    output_string.push_str(&format!(
+        "Block hash at which the coinjoin is confirmed: {}\n",
+        block_hash
+    ));
+
+    report::write_report(&output_string)
+}
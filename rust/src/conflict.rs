@@ -0,0 +1,286 @@
+use crate::accept;
+use crate::btc;
+use crate::bump;
+use crate::cli::{Config, SighashType};
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::fees;
+use crate::mempooldiff;
+use crate::mine;
+use crate::raw;
+use crate::reconcile;
+use crate::relay;
+use crate::report;
+use crate::session::Session;
+use crate::signing;
+use bitcoincore_rpc::bitcoin::{Address, Amount, OutPoint, Sequence, Txid};
+use bitcoincore_rpc::json::{CreateRawTransactionInput, ListUnspentResultEntry};
+use bitcoincore_rpc::{Client, RpcApi};
+use std::collections::HashMap;
+
+// What each of the two spends pays the Trader
+const DEFAULT_AMOUNT: Amount = Amount::ONE_BTC;
+
+// One of the two competing spends.
+struct Spend {
+    txid: Txid,
+    hex: String,
+    fee: Amount,
+    to: Address,
+}
+
+// Spend exactly `utxo`: `amount` to `to`, the rest minus `fee` back to the Miner.
+// Built by hand so the wallet has no say in which coin is used.
+fn build_spend(
+    miner_rpc: &Client,
+    utxo: &ListUnspentResultEntry,
+    to: &Address,
+    amount: Amount,
+    fee: Amount,
+    sequence: Sequence,
+) -> Result<Spend> {
+    let change = utxo.amount.checked_sub(amount + fee).ok_or_else(|| {
+        CapstoneError::Usage(format!(
+            "coin {}:{} holds {}, too little for {} plus fee",
+            utxo.txid,
+            utxo.vout,
+            btc::amount(utxo.amount),
+            btc::amount(amount)
+        ))
+    })?;
+    let change_address = miner_rpc.get_raw_change_address(None)?.assume_checked();
+    let input = CreateRawTransactionInput {
+        txid: utxo.txid,
+        vout: utxo.vout,
+        sequence: Some(sequence.to_consensus_u32()),
+    };
+    let mut outputs = HashMap::new();
+    outputs.insert(to.to_string(), amount);
+    outputs.insert(change_address.to_string(), change);
+    let unsigned = miner_rpc.create_raw_transaction_hex(&[input], &outputs, None, None)?;
+    let hex =
+        signing::sign_with_wallet(miner_rpc, &unsigned, SighashType::Default)?.into_signed_hex()?;
+    let txid = signing::decode_tx(&hex)?.txid();
+    Ok(Spend {
+        txid,
+        hex,
+        fee,
+        to: to.clone(),
+    })
+}
+
+fn wallet_status(miner_rpc: &Client, txid: &Txid) -> Result<String> {
+    let info = miner_rpc.get_transaction(txid, None)?.info;
+    Ok(match info.confirmations {
+        n if n > 0 => match info.blockhash {
+            Some(block_hash) => format!("confirmed in block {}", block_hash),
+            None => "confirmed".to_string(),
+        },
+        0 => "unconfirmed".to_string(),
+        _ => format!(
+            "conflicted (wallet conflicts: {})",
+            info.wallet_conflicts
+                .iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    })
+}
+
+// conflict-demo: two signed spends of the same Miner coin to different Trader
+// addresses, the second paying more. Whether the second replaces the first
+// depends on BIP125 signaling (--signal-rbf) or the node's -mempoolfullrbf.
+pub fn run(session: &Session, config: &Config) -> Result<()> {
+    let rpc = &session.rpc;
+    let miner_rpc = &session.miner_rpc;
+    let amount = config.amount.unwrap_or(DEFAULT_AMOUNT);
+    session.fund_miner(amount + config.max_fee())?;
+    let utxo = miner_rpc
+        .list_unspent(Some(1), None, None, None, None)?
+        .into_iter()
+        .filter(|utxo| utxo.spendable)
+        .max_by_key(|utxo| utxo.amount)
+        .ok_or_else(|| CapstoneError::Usage("the Miner has no confirmed coin to spend".into()))?;
+    let outpoint = OutPoint::new(utxo.txid, utxo.vout);
+
+    let node_policy = relay::policy(rpc, &[])?;
+    let first_rate = bump::initial_fee_rate(rpc, config)?.sat_per_kvb;
+    node_policy.require_fee_rate(first_rate, "the first spend")?;
+    let second_rate = bump::next_fee_rate(
+        first_rate,
+        fees::TYPICAL_PAYMENT_VSIZE,
+        config.max_fee(),
+        &node_policy,
+    )
+    .ok_or_else(|| {
+        CapstoneError::Usage("--max-fee leaves no room for a higher-fee second spend".into())
+    })?;
+    let fee_at = |rate: u64| Amount::from_sat(rate * fees::TYPICAL_PAYMENT_VSIZE / 1000);
+    let sequence = if config.signal_rbf {
+        Sequence::ENABLE_RBF_NO_LOCKTIME
+    } else {
+        Sequence::MAX
+    };
+
+    // Keep the coin out of the wallet's own coin selection while both spends
+    // are built; it is unlocked again before the first one goes out, since the
+    // wallet refuses to unlock a coin it already sees as spent
+    miner_rpc.lock_unspent(&[outpoint])?;
+    let first_to = session
+        .trader_rpc
+        .get_new_address(None, None)?
+        .assume_checked();
+    let second_to = session
+        .trader_rpc
+        .get_new_address(None, None)?
+        .assume_checked();
+    let built = build_spend(
+        miner_rpc,
+        &utxo,
+        &first_to,
+        amount,
+        fee_at(first_rate),
+        sequence,
+    )
+    .and_then(|first| {
+        let second = build_spend(
+            miner_rpc,
+            &utxo,
+            &second_to,
+            amount,
+            fee_at(second_rate),
+            sequence,
+        )?;
+        Ok((first, second))
+    });
+    miner_rpc.unlock_unspent(&[outpoint])?;
+    let (first, second) = built?;
+
+    let mut narrative = Vec::new();
+    narrative.push(format!(
+        "Both spends use {} ({}signaling RBF)",
+        outpoint,
+        if config.signal_rbf { "" } else { "not " }
+    ));
+    // Say up front what the node's policy predicts, so a surprise stands out
+    narrative.push(format!(
+        "Node policy (full RBF {}): the second spend should {}",
+        match node_policy.full_rbf {
+            Some(true) => "on",
+            Some(false) => "off",
+            None => "not reported",
+        },
+        if config.signal_rbf || node_policy.replaces_without_signaling() {
+            "replace the first"
+        } else {
+            "be rejected"
+        }
+    ));
+
+    accept::ensure_accepted(
+        rpc,
+        std::slice::from_ref(&first.hex),
+        config.max_broadcast_feerate(),
+    )?;
+    raw::broadcast(rpc, &first.hex, config)?;
+    // From here on the diff shows whether the second spend replaced the first
+    let mempool_before = mempooldiff::take(rpc)?;
+    narrative.push(format!(
+        "First spend {} paying {} fee to {}: accepted",
+        first.txid,
+        btc::amount(first.fee),
+        first.to
+    ));
+
+    let second_result = accept::test_mempool_accept(
+        rpc,
+        std::slice::from_ref(&second.hex),
+        config.max_broadcast_feerate(),
+    )?;
+    match second_result.first() {
+        Some(result) if result.is_allowed() => {
+            raw::broadcast(rpc, &second.hex, config)?;
+            narrative.push(format!(
+                "Second spend {} paying {} fee to {}: accepted, replacing the first",
+                second.txid,
+                btc::amount(second.fee),
+                second.to
+            ));
+        }
+        Some(result) => narrative.push(format!(
+            "Second spend {} paying {} fee to {}: rejected ({})",
+            second.txid,
+            btc::amount(second.fee),
+            second.to,
+            result.rejection()
+        )),
+        None => {
+            return Err(CapstoneError::Usage(
+                "testmempoolaccept returned no result".into(),
+            ))
+        }
+    }
+
+    let mempool_diff = mempooldiff::compare(rpc, &mempool_before, &mempooldiff::take(rpc)?)?;
+    let mempool = rpc.get_raw_mempool()?;
+    let in_mempool = [&first, &second]
+        .into_iter()
+        .find(|spend| mempool.contains(&spend.txid))
+        .map(|spend| spend.txid);
+    match in_mempool {
+        Some(txid) => narrative.push(format!("In the mempool: {}", txid)),
+        None => narrative.push("In the mempool: neither".to_string()),
+    }
+
+    let block_hash = mine::confirm(session, in_mempool.as_slice(), config)?;
+    let block_height = rpc.get_block_header_info(&block_hash)?.height;
+    narrative.push(format!(
+        "First spend after block {}: {}",
+        block_height,
+        wallet_status(miner_rpc, &first.txid)?
+    ));
+    // A rejected second spend never reached the wallet
+    match wallet_status(miner_rpc, &second.txid) {
+        Ok(status) => narrative.push(format!(
+            "Second spend after block {}: {}",
+            block_height, status
+        )),
+        Err(_) => narrative.push(format!(
+            "Second spend after block {}: never accepted, unknown to the wallet",
+            block_height
+        )),
+    }
+
+    // A second spend that won must have left the first conflicted behind it
+    let conflicts = reconcile::scan(session)?;
+    if in_mempool == Some(second.txid) {
+        reconcile::expect_conflict(&conflicts, &first.txid, Some(&second.txid))?;
+    }
+
+    let mut output_string = String::new();
+    for (i, line) in narrative.iter().enumerate() {
+        say!("{}", line);
+        output_string.push_str(&format!("Step {}: {}\n", i + 1, line));
+    }
+    output_string.push_str(&format!(
+        "Block height at which the conflict is settled: {}\n",
+        block_height
+    ));
+    output_string.push_str(&format!(
+        "Block hash at which the conflict is settled: {}\n",
+        block_hash
+    ));
+    output_string.push_str(&format!(
+        "Mempool diff after the second spend: {}\n",
+        mempool_diff.summary()
+    ));
+    for change in &mempool_diff.replaced {
+        output_string.push_str(&format!("Mempool replacement: {}\n", change.describe()));
+    }
+    for line in reconcile::describe(&conflicts) {
+        output_string.push_str(&line);
+        output_string.push('\n');
+    }
+    report::write_report(&output_string)
+}
@@ -0,0 +1,173 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// --no-file: no out.txt (or out.csv, out.html), and the report is the only
+// thing on stdout so it can be piped into jq and the like
+static REPORT_ONLY: AtomicBool = AtomicBool::new(false);
+
+// --verbose: also say what was skipped along the way, see `verbose!`
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+// What `capture` has collected on this thread, stdout then stderr
+type Captured = (String, String);
+
+thread_local! {
+    static CAPTURE: RefCell<Option<Captured>> = const { RefCell::new(None) };
+    // --verbose for this thread only, see `capture_verbose`
+    static SCOPED_VERBOSE: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn set_report_only(report_only: bool) {
+    REPORT_ONLY.store(report_only, Ordering::Relaxed);
+}
+
+pub fn report_only() -> bool {
+    REPORT_ONLY.load(Ordering::Relaxed) || CAPTURE.with(|capture| capture.borrow().is_some())
+}
+
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed) || SCOPED_VERBOSE.with(Cell::get)
+}
+
+// The report itself, on stdout
+pub fn out(text: &str) {
+    let captured = CAPTURE.with(|capture| match capture.borrow_mut().as_mut() {
+        Some((stdout, _)) => {
+            stdout.push_str(text);
+            true
+        }
+        None => false,
+    });
+    if !captured {
+        print!("{}", text);
+    }
+}
+
+// A line of `say!`
+pub fn chatter(args: fmt::Arguments) {
+    let captured = CAPTURE.with(|capture| match capture.borrow_mut().as_mut() {
+        Some((_, stderr)) => {
+            stderr.push_str(&format!("{}\n", args));
+            true
+        }
+        None => false,
+    });
+    if captured {
+        return;
+    }
+    if report_only() {
+        eprintln!("{}", args)
+    } else {
+        println!("{}", args)
+    }
+}
+
+// Run `f` as if --no-file were given, returning what it put on stdout and
+// stderr instead of printing it. Only this thread's output is taken.
+#[cfg(test)]
+pub fn capture(f: impl FnOnce()) -> Captured {
+    CAPTURE.with(|capture| *capture.borrow_mut() = Some(Captured::default()));
+    f();
+    CAPTURE.with(|capture| capture.borrow_mut().take().unwrap_or_default())
+}
+
+// `capture` with --verbose on for this thread
+#[cfg(test)]
+pub fn capture_verbose(f: impl FnOnce()) -> Captured {
+    let outer = SCOPED_VERBOSE.with(|scoped| scoped.replace(true));
+    let captured = capture(f);
+    SCOPED_VERBOSE.with(|scoped| scoped.set(outer));
+    captured
+}
+
+// println! for everything that isn't the report itself: progress, summaries,
+// what was written where. Goes to stderr with --no-file.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        $crate::console::chatter(format_args!($($arg)*))
+    };
+}
+
+// say! that only speaks with --verbose
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::console::is_verbose() {
+            $crate::console::chatter(format_args!($($arg)*))
+        }
+    };
+}
+
+pub(crate) use say;
+pub(crate) use verbose;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::parse_args;
+    use crate::csvreport::{self, CsvRow};
+    use crate::report::{self, tests::fixture};
+
+    // What --no-file with `args` puts on stdout and stderr for the fixture report
+    fn no_file_run(args: &[&str]) -> Captured {
+        let config =
+            parse_args(["--no-file"].iter().chain(args).map(|arg| arg.to_string())).unwrap();
+        assert!(config.no_file);
+        capture(|| report::write_transaction_reports(&[fixture()], &config).unwrap())
+    }
+
+    #[test]
+    fn stdout_is_exactly_the_text_report() {
+        let (stdout, stderr) = no_file_run(&[]);
+        assert_eq!(stdout, fixture().to_text());
+        assert_eq!(stderr, "");
+    }
+
+    #[test]
+    fn stdout_is_exactly_the_json_report() {
+        let (stdout, _) = no_file_run(&["--format", "json"]);
+        let parsed: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+        assert_eq!(parsed, fixture().to_json());
+        assert_eq!(
+            stdout,
+            format!(
+                "{}\n",
+                serde_json::to_string_pretty(&fixture().to_json()).unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn stdout_is_exactly_the_csv_report() {
+        let (stdout, _) = no_file_run(&["--format", "csv"]);
+        assert_eq!(
+            stdout,
+            csvreport::render(&[CsvRow::from_report(&fixture())])
+        );
+    }
+
+    #[test]
+    fn chatter_goes_to_stderr() {
+        let (stdout, stderr) = capture(|| {
+            say!("Mined {} block(s)", 101);
+            out("report\n");
+        });
+        assert_eq!(stdout, "report\n");
+        assert_eq!(stderr, "Mined 101 block(s)\n");
+        assert!(!report_only());
+    }
+
+    #[test]
+    fn verbose_speaks_only_with_verbose() {
+        let quiet = capture(|| verbose!("Skipping {}", "a probe"));
+        assert_eq!(quiet, (String::new(), String::new()));
+        let (stdout, stderr) = capture_verbose(|| verbose!("Skipping {}", "a probe"));
+        assert_eq!(stdout, "");
+        assert_eq!(stderr, "Skipping a probe\n");
+        assert!(!is_verbose());
+    }
+}
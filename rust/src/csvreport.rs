@@ -0,0 +1,158 @@
+use crate::console;
+use crate::error::Result;
+use crate::files;
+use crate::report::TransactionReport;
+use bitcoincore_rpc::bitcoin::{Amount, BlockHash, Txid};
+
+// --format csv goes next to out.txt, which stays the text report
+pub const CSV_PATH: &str = "../out.csv";
+
+const HEADER: [&str; 8] = [
+    "txid",
+    "input_addresses",
+    "input_sat",
+    "trader_sat",
+    "change_sat",
+    "fee_sat",
+    "block_height",
+    "block_hash",
+];
+
+// One transaction per row. Amounts are integer satoshis, so there is no
+// decimal point (or exponent) to get wrong.
+#[derive(Debug, Clone)]
+pub struct CsvRow {
+    pub txid: Txid,
+    pub input_addresses: Vec<String>,
+    pub input_amount: Amount,
+    pub trader_amount: Option<Amount>,
+    pub change_amount: Option<Amount>,
+    pub fee: Amount,
+    pub block_height: u64,
+    pub block_hash: BlockHash,
+}
+
+impl CsvRow {
+    pub fn from_report(report: &TransactionReport) -> Self {
+        CsvRow {
+            txid: report.txid,
+            input_addresses: report
+                .input_addresses
+                .iter()
+                .map(|address| address.to_string())
+                .collect(),
+            input_amount: report.input_amount,
+            trader_amount: report.trader_output.as_ref().map(|(amount, _)| *amount),
+            change_amount: report.change_output.as_ref().map(|(amount, _)| *amount),
+            fee: report.fee,
+            block_height: report.block_height,
+            block_hash: report.block_hash,
+        }
+    }
+
+    fn fields(&self) -> [String; 8] {
+        let sat = |amount: Option<Amount>| amount.map_or(String::new(), |a| a.to_sat().to_string());
+        [
+            self.txid.to_string(),
+            self.input_addresses.join(";"),
+            self.input_amount.to_sat().to_string(),
+            sat(self.trader_amount),
+            sat(self.change_amount),
+            self.fee.to_sat().to_string(),
+            self.block_height.to_string(),
+            self.block_hash.to_string(),
+        ]
+    }
+}
+
+// RFC 4180: a field with a comma, quote or line break is quoted, and quotes
+// inside it doubled.
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn line<S: AsRef<str>>(fields: &[S]) -> String {
+    let quoted: Vec<String> = fields.iter().map(|field| quote(field.as_ref())).collect();
+    format!("{}\r\n", quoted.join(","))
+}
+
+pub fn render(rows: &[CsvRow]) -> String {
+    let mut csv = line(&HEADER);
+    for row in rows {
+        csv.push_str(&line(&row.fields()));
+    }
+    csv
+}
+
+// Replace out.csv the way out.txt is replaced, and echo it. With --no-file
+// it only goes to stdout.
+pub fn write_csv(rows: &[CsvRow]) -> Result<()> {
+    let csv = render(rows);
+    if !console::report_only() {
+        let path = files::write_atomically(CSV_PATH, &csv, "CSV report")?;
+        eprintln!("Wrote {} row(s) to {}", rows.len(), path.display());
+    }
+    console::out(&csv);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::tests::fixture;
+
+    fn parse(csv: &str) -> Vec<Vec<String>> {
+        csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(csv.as_bytes())
+            .records()
+            .map(|record| record.unwrap().iter().map(String::from).collect())
+            .collect()
+    }
+
+    #[test]
+    fn parses_back_to_the_report() {
+        let report = fixture();
+        let records = parse(&render(&[CsvRow::from_report(&report)]));
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], HEADER);
+        assert_eq!(
+            records[1],
+            [
+                report.txid.to_string(),
+                report.input_addresses[0].to_string(),
+                "5000000000".into(),
+                "2000000000".into(),
+                "2999998590".into(),
+                "1410".into(),
+                "102".into(),
+                report.block_hash.to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn quotes_commas_quotes_and_line_breaks() {
+        let mut row = CsvRow::from_report(&fixture());
+        row.input_addresses = vec![
+            "label, with a comma".into(),
+            "label \"quoted\"".into(),
+            "two\r\nlines".into(),
+        ];
+        row.change_amount = None;
+        let records = parse(&render(&[row.clone(), row]));
+        assert_eq!(records.len(), 3);
+        for record in &records[1..] {
+            assert_eq!(record.len(), HEADER.len());
+            assert_eq!(
+                record[1],
+                "label, with a comma;label \"quoted\";two\r\nlines"
+            );
+            assert_eq!(record[4], "");
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use crate::chaintime;
+use crate::console::say;
+use crate::error::Result;
+use crate::fees;
+use crate::files;
+use crate::report::TransactionReport;
+use crate::runinfo;
+use bitcoincore_rpc::bitcoin::{Address, Amount};
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Each entry brings the schema from the version before it (PRAGMA
+// user_version) to its own: the first creates it. Only ever append.
+const MIGRATIONS: &[&str] = &["
+CREATE TABLE runs (
+    id INTEGER PRIMARY KEY,
+    started TEXT NOT NULL,
+    finished TEXT NOT NULL,
+    tool_version TEXT NOT NULL,
+    node_version TEXT,
+    network TEXT,
+    arguments TEXT NOT NULL
+);
+CREATE TABLE transactions (
+    id INTEGER PRIMARY KEY,
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    txid TEXT NOT NULL,
+    input_sat INTEGER NOT NULL,
+    trader_sat INTEGER,
+    change_sat INTEGER,
+    fee_sat INTEGER NOT NULL,
+    vsize INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    block_hash TEXT NOT NULL
+);
+CREATE INDEX transactions_txid ON transactions(txid);
+CREATE INDEX transactions_run ON transactions(run_id);
+CREATE TABLE outputs (
+    transaction_id INTEGER NOT NULL REFERENCES transactions(id),
+    vout INTEGER NOT NULL,
+    address TEXT,
+    amount_sat INTEGER NOT NULL,
+    role TEXT NOT NULL,
+    PRIMARY KEY (transaction_id, vout)
+);
+"];
+
+// Open the --db database, creating it or bringing its schema up to date.
+pub fn open(path: &str) -> Result<Connection> {
+    files::prepare(Path::new(path), "database")?;
+    let mut connection = Connection::open(path)?;
+    migrate(&mut connection)?;
+    Ok(connection)
+}
+
+fn migrate(connection: &mut Connection) -> Result<()> {
+    connection.pragma_update(None, "foreign_keys", true)?;
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version < MIGRATIONS.len() {
+        let migration = connection.transaction()?;
+        for sql in &MIGRATIONS[version..] {
+            migration.execute_batch(sql)?;
+        }
+        migration.pragma_update(None, "user_version", MIGRATIONS.len())?;
+        migration.commit()?;
+    }
+    Ok(())
+}
+
+// One run with its reports, in one transaction so a run is there whole or
+// not at all. Returns the run's id.
+pub fn insert_run(connection: &mut Connection, reports: &[TransactionReport]) -> Result<i64> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let run = reports.iter().find_map(|report| report.run.as_ref());
+    let insert = connection.transaction()?;
+    insert.execute(
+        "INSERT INTO runs (started, finished, tool_version, node_version, network, arguments)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            chaintime::rfc3339(run.map_or(now, |run| run.started)),
+            chaintime::rfc3339(run.map_or(now, |run| run.finished)),
+            run.map_or_else(runinfo::tool_version, |run| run.tool_version.clone()),
+            run.map(|run| format!("{} {}", run.node_version, run.node_subversion)),
+            run.map(|run| run.network.to_string()),
+            run.map(|run| run.arguments_summary()).unwrap_or_default(),
+        ],
+    )?;
+    let run_id = insert.last_insert_rowid();
+    for report in reports {
+        let sat = |output: &Option<(Amount, Address)>| {
+            output.as_ref().map(|(amount, _)| amount.to_sat() as i64)
+        };
+        insert.execute(
+            "INSERT INTO transactions
+             (run_id, txid, input_sat, trader_sat, change_sat, fee_sat, vsize, height, block_hash)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                run_id,
+                report.txid.to_string(),
+                report.input_amount.to_sat() as i64,
+                sat(&report.trader_output),
+                sat(&report.change_output),
+                report.fee.to_sat() as i64,
+                report.vsize as i64,
+                report.block_height as i64,
+                report.block_hash.to_string(),
+            ],
+        )?;
+        let transaction_id = insert.last_insert_rowid();
+        for (vout, role, amount, address) in &report.outputs {
+            insert.execute(
+                "INSERT INTO outputs (transaction_id, vout, address, amount_sat, role)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    transaction_id,
+                    vout,
+                    address.as_ref().map(|address| address.to_string()),
+                    amount.to_sat() as i64,
+                    role,
+                ],
+            )?;
+        }
+    }
+    insert.commit()?;
+    Ok(run_id)
+}
+
+// Record the reports of this run in --db.
+pub fn record(path: &str, reports: &[TransactionReport]) -> Result<()> {
+    let mut connection = open(path)?;
+    let run_id = insert_run(&mut connection, reports)?;
+    say!(
+        "Recorded run {} ({} transaction(s)) in {}.",
+        run_id,
+        reports.len(),
+        path
+    );
+    Ok(())
+}
+
+// One row of `db query`
+#[derive(Debug, Clone)]
+pub struct RunRow {
+    pub id: i64,
+    pub started: String,
+    pub network: Option<String>,
+    pub arguments: String,
+    // txid, fee in sat, height
+    pub transactions: Vec<(String, i64, i64)>,
+}
+
+// The `last` most recent runs, newest first.
+pub fn recent_runs(connection: &Connection, last: usize) -> Result<Vec<RunRow>> {
+    let mut runs = connection
+        .prepare("SELECT id, started, network, arguments FROM runs ORDER BY id DESC LIMIT ?1")?;
+    let mut transactions = connection
+        .prepare("SELECT txid, fee_sat, height FROM transactions WHERE run_id = ?1 ORDER BY id")?;
+    let rows = runs
+        .query_map([last as i64], |row| {
+            Ok(RunRow {
+                id: row.get(0)?,
+                started: row.get(1)?,
+                network: row.get(2)?,
+                arguments: row.get(3)?,
+                transactions: Vec::new(),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    rows.into_iter()
+        .map(|mut run| {
+            run.transactions = transactions
+                .query_map([run.id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(run)
+        })
+        .collect()
+}
+
+// Fees over every recorded transaction
+#[derive(Debug, Clone, Default)]
+pub struct FeeStats {
+    pub runs: i64,
+    pub transactions: i64,
+    pub total_sat: i64,
+    pub min_sat: i64,
+    pub max_sat: i64,
+    pub average_sat: f64,
+    // sat/kvB: total fee over total vsize
+    pub average_rate: u64,
+}
+
+pub fn fee_stats(connection: &Connection) -> Result<FeeStats> {
+    let runs = connection.query_row("SELECT COUNT(*) FROM runs", [], |row| row.get(0))?;
+    // The aggregates are NULL without transactions
+    let stats = connection.query_row(
+        "SELECT COUNT(*), SUM(fee_sat), MIN(fee_sat), MAX(fee_sat), AVG(fee_sat), SUM(vsize)
+         FROM transactions",
+        [],
+        |row| {
+            let total_sat: Option<i64> = row.get(1)?;
+            let vsize: Option<i64> = row.get(5)?;
+            Ok(FeeStats {
+                runs,
+                transactions: row.get(0)?,
+                total_sat: total_sat.unwrap_or(0),
+                min_sat: row.get::<_, Option<i64>>(2)?.unwrap_or(0),
+                max_sat: row.get::<_, Option<i64>>(3)?.unwrap_or(0),
+                average_sat: row.get::<_, Option<f64>>(4)?.unwrap_or(0.0),
+                average_rate: (total_sat.unwrap_or(0).max(0) as u64 * 1000)
+                    / vsize.unwrap_or(0).max(1) as u64,
+            })
+        },
+    )?;
+    Ok(stats)
+}
+
+// What `db query` prints for `runs`
+pub fn describe_runs(runs: &[RunRow]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for run in runs {
+        lines.push(format!(
+            "Run {} at {} on {}: {}",
+            run.id,
+            run.started,
+            run.network.as_deref().unwrap_or("unknown network"),
+            run.arguments
+        ));
+        for (txid, fee_sat, height) in &run.transactions {
+            lines.push(format!("  {} fee {} sat, height {}", txid, fee_sat, height));
+        }
+    }
+    lines
+}
+
+impl FeeStats {
+    // What `db stats` prints
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("Runs: {}", self.runs),
+            format!("Transactions: {}", self.transactions),
+        ];
+        if self.transactions > 0 {
+            lines.push(format!("Total fees: {} sat", self.total_sat));
+            lines.push(format!(
+                "Fee per transaction: min {} sat, max {} sat, average {:.1} sat",
+                self.min_sat, self.max_sat, self.average_sat
+            ));
+            lines.push(format!(
+                "Average feerate: {} sat/vB",
+                fees::format_sat_per_vb(self.average_rate)
+            ));
+        }
+        lines
+    }
+}
+
+// db query: the last `last` runs (10 unless given) with their transactions.
+pub fn run_query(path: &str, last: Option<usize>) -> Result<()> {
+    let connection = open(path)?;
+    let runs = recent_runs(&connection, last.unwrap_or(10))?;
+    if runs.is_empty() {
+        say!("No runs recorded in {}.", path);
+    }
+    for line in describe_runs(&runs) {
+        say!("{}", line);
+    }
+    Ok(())
+}
+
+// db stats: fee statistics over everything recorded.
+pub fn run_stats(path: &str) -> Result<()> {
+    for line in fee_stats(&open(path)?)?.describe() {
+        say!("{}", line);
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::report::tests::fixture;
+    use crate::runinfo::RunInfo;
+    use crate::version::CoreVersion;
+    use bitcoincore_rpc::bitcoin::Network;
+
+    fn memory() -> Connection {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        connection
+    }
+
+    fn recorded_run() -> Vec<TransactionReport> {
+        let mut report = fixture();
+        report.run = Some(RunInfo {
+            started: 1_700_000_000,
+            finished: 1_700_000_042,
+            tool_version: "0.1.0".into(),
+            node_version: CoreVersion::new(26, 1),
+            node_subversion: "/Satoshi:26.1.0/".into(),
+            network: Network::Regtest,
+            arguments: vec!["--confirm".into()],
+        });
+        let mut second = fixture();
+        second.fee = Amount::from_sat(2_820);
+        vec![report, second]
+    }
+
+    #[test]
+    fn migrating_twice_keeps_the_schema() {
+        let mut connection = memory();
+        migrate(&mut connection).unwrap();
+        let version: usize = connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn query_reads_back_a_recorded_run() {
+        let mut connection = memory();
+        let reports = recorded_run();
+        let run_id = insert_run(&mut connection, &reports).unwrap();
+        let outputs: i64 = connection
+            .query_row("SELECT COUNT(*) FROM outputs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(outputs, 4);
+
+        let runs = recent_runs(&connection, 10).unwrap();
+        let txid = reports[0].txid;
+        assert_eq!(
+            describe_runs(&runs),
+            [
+                format!(
+                    "Run {} at 2023-11-14T22:13:20Z on regtest: --confirm",
+                    run_id
+                ),
+                format!("  {} fee 1410 sat, height 102", txid),
+                format!("  {} fee 2820 sat, height 102", txid),
+            ]
+        );
+    }
+
+    #[test]
+    fn query_lists_the_newest_runs_first() {
+        let mut connection = memory();
+        let first = insert_run(&mut connection, &recorded_run()).unwrap();
+        let second = insert_run(&mut connection, &recorded_run()).unwrap();
+        let runs = recent_runs(&connection, 1).unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].id, second);
+        assert!(second > first);
+    }
+
+    #[test]
+    fn stats_over_recorded_fees() {
+        let mut connection = memory();
+        assert_eq!(
+            fee_stats(&connection).unwrap().describe(),
+            ["Runs: 0", "Transactions: 0"]
+        );
+        insert_run(&mut connection, &recorded_run()).unwrap();
+        // 4230 sat over 2 x 113 vB
+        assert_eq!(
+            fee_stats(&connection).unwrap().describe(),
+            [
+                "Runs: 1",
+                "Transactions: 2",
+                "Total fees: 4230 sat",
+                "Fee per transaction: min 1410 sat, max 2820 sat, average 2115.0 sat",
+                "Average feerate: 18.716 sat/vB",
+            ]
+        );
+    }
+}
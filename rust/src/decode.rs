@@ -0,0 +1,250 @@
+use crate::btc;
+use crate::error::Result;
+use bitcoincore_rpc::bitcoin::{Amount, BlockHash, Txid, Wtxid};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+// The parts of a verbose getrawtransaction / decoderawtransaction result that
+// show what a transaction looks like on the wire.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DecodedTx {
+    pub txid: Txid,
+    pub hash: Wtxid,
+    pub version: i32,
+    pub size: u64,
+    pub vsize: u64,
+    pub weight: u64,
+    pub locktime: u32,
+    pub vin: Vec<DecodedInput>,
+    pub vout: Vec<DecodedOutput>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DecodedInput {
+    // Absent for coinbase inputs
+    pub txid: Option<Txid>,
+    pub vout: Option<u32>,
+    pub coinbase: Option<String>,
+    #[serde(rename = "scriptSig")]
+    pub script_sig: Option<DecodedScriptSig>,
+    pub sequence: u32,
+    #[serde(default, rename = "txinwitness")]
+    pub witness: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DecodedScriptSig {
+    pub asm: String,
+    pub hex: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DecodedOutput {
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub value: Amount,
+    pub n: u32,
+    #[serde(rename = "scriptPubKey")]
+    pub script_pub_key: DecodedScript,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DecodedScript {
+    pub asm: String,
+    pub hex: String,
+    #[serde(rename = "type")]
+    pub script_type: String,
+    pub address: Option<String>,
+}
+
+// Fetch the decoded form of a transaction. The block hash lets this work for
+// confirmed transactions without -txindex.
+pub fn fetch_decoded(
+    rpc: &Client,
+    txid: &Txid,
+    block_hash: Option<&BlockHash>,
+) -> Result<DecodedTx> {
+    let mut args = vec![json!(txid), json!(true)];
+    if let Some(block_hash) = block_hash {
+        args.push(json!(block_hash));
+    }
+    Ok(rpc.call("getrawtransaction", &args)?)
+}
+
+// Decode a raw hex transaction that may not be known to the node.
+pub fn decode_hex(rpc: &Client, hex: &str) -> Result<DecodedTx> {
+    Ok(rpc.call("decoderawtransaction", &[json!(hex)])?)
+}
+
+impl DecodedTx {
+    // Human readable view of the transaction, one field per line.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&format!("txid:     {}\n", self.txid));
+        out.push_str(&format!("wtxid:    {}\n", self.hash));
+        out.push_str(&format!("version:  {}\n", self.version));
+        out.push_str(&format!("locktime: {}\n", self.locktime));
+        out.push_str(&format!(
+            "size:     {} bytes, {} vB, {} WU\n",
+            self.size, self.vsize, self.weight
+        ));
+
+        out.push_str(&format!("inputs ({}):\n", self.vin.len()));
+        for (i, input) in self.vin.iter().enumerate() {
+            let outpoint = match (&input.txid, input.vout) {
+                (Some(txid), Some(vout)) => format!("{}:{}", txid, vout),
+                _ => "coinbase".to_string(),
+            };
+            out.push_str(&format!(
+                "  #{} {} sequence 0x{:08x}, {} witness items\n",
+                i,
+                outpoint,
+                input.sequence,
+                input.witness.len()
+            ));
+        }
+
+        out.push_str(&format!("outputs ({}):\n", self.vout.len()));
+        for output in &self.vout {
+            out.push_str(&format!(
+                "  #{} {} {} -> {}\n",
+                output.n,
+                btc::amount(output.value),
+                output.script_pub_key.script_type,
+                output
+                    .script_pub_key
+                    .address
+                    .as_deref()
+                    .unwrap_or("(no address)")
+            ));
+            out.push_str(&format!("     asm: {}\n", output.script_pub_key.asm));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Units;
+    use crate::mockrpc;
+    use bitcoincore_rpc::bitcoin::consensus::encode;
+    use bitcoincore_rpc::bitcoin::hex::FromHex;
+    use bitcoincore_rpc::bitcoin::Transaction;
+    use serde_json::Value;
+
+    const SEGWIT: &str = include_str!("../tests/fixtures/rawtransaction-segwit.json");
+    const TAPROOT: &str = include_str!("../tests/fixtures/rawtransaction-taproot.json");
+
+    fn decoded(json: &str) -> DecodedTx {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn segwit_transaction_from_core() {
+        let tx = decoded(SEGWIT);
+        assert_eq!(
+            tx.txid.to_string(),
+            "29c93e605528c497d1e3b3d04d1fe21c162e1d58ef92c924b12ce7f43627bac8"
+        );
+        assert_ne!(tx.txid.to_string(), tx.hash.to_string());
+        assert_eq!((tx.size, tx.vsize, tx.weight), (222, 141, 561));
+        assert_eq!(tx.locktime, 101);
+
+        let input = &tx.vin[0];
+        assert_eq!(input.vout, Some(0));
+        assert!(input.coinbase.is_none());
+        // Native segwit leaves the scriptSig empty; signature and key are in
+        // the witness
+        assert_eq!(input.script_sig.as_ref().unwrap().hex, "");
+        assert_eq!(input.witness.len(), 2);
+        assert_eq!(input.witness[1].len(), 66);
+
+        assert_eq!(tx.vout.len(), 2);
+        assert_eq!(tx.vout[0].value, Amount::from_sat(40_000_000));
+        assert_eq!(tx.vout[1].value, Amount::from_sat(59_998_590));
+        assert_eq!(tx.vout[1].n, 1);
+        let script = &tx.vout[0].script_pub_key;
+        assert_eq!(script.script_type, "witness_v0_keyhash");
+        assert_eq!(
+            script.address.as_deref(),
+            Some("bcrt1qgar7sarvmkenkrmljk5slz0cn7ec0jak76zrj7")
+        );
+        assert_eq!(script.asm, "0 4747e8746cddb33b0f7f95a90f89f89fb387cbb6");
+    }
+
+    #[test]
+    fn taproot_transaction_from_core() {
+        let tx = decoded(TAPROOT);
+        assert_eq!(
+            tx.txid.to_string(),
+            "44e3d7bc2f86827be057e1d0cfa7286087997fff490d999bdb35456baeccf6aa"
+        );
+        assert_eq!((tx.size, tx.vsize, tx.weight), (150, 99, 396));
+        // A key path spend is a lone 64-byte Schnorr signature
+        assert_eq!(tx.vin[0].witness.len(), 1);
+        assert_eq!(tx.vin[0].witness[0].len(), 128);
+        assert_eq!(tx.vin[0].sequence, 0xffff_fffd);
+        assert_eq!(tx.vout.len(), 1);
+        assert_eq!(tx.vout[0].value, Amount::from_sat(99_999_010));
+    }
+
+    #[test]
+    fn fixtures_match_their_hex() {
+        for json in [SEGWIT, TAPROOT] {
+            let raw: Value = serde_json::from_str(json).unwrap();
+            let bytes = Vec::<u8>::from_hex(raw["hex"].as_str().unwrap()).unwrap();
+            let tx: Transaction = encode::deserialize(&bytes).unwrap();
+            let decoded = decoded(json);
+            assert_eq!(decoded.txid, tx.txid());
+            assert_eq!(decoded.hash, tx.wtxid());
+            assert_eq!(decoded.size, tx.total_size() as u64);
+            assert_eq!(decoded.vsize, tx.vsize() as u64);
+            assert_eq!(decoded.weight, tx.weight().to_wu());
+            assert_eq!(decoded.vin.len(), tx.input.len());
+            for (input, txin) in decoded.vin.iter().zip(&tx.input) {
+                assert_eq!(input.witness.len(), txin.witness.len());
+            }
+        }
+    }
+
+    #[test]
+    fn render_taproot() {
+        let text = btc::with_units(Units::Btc, || decoded(TAPROOT).render());
+        assert_eq!(
+            text,
+            "txid:     44e3d7bc2f86827be057e1d0cfa7286087997fff490d999bdb35456baeccf6aa\n\
+             wtxid:    8397efe7d0a6767ed9e2f9ba4526557ba7da405f710981be304187751b368b00\n\
+             version:  2\n\
+             locktime: 101\n\
+             size:     150 bytes, 99 vB, 396 WU\n\
+             inputs (1):\n  \
+             #0 0a13b5342a01cf77805d50a895e6eb79705c22daa7e82b75f5e637757acd2cf2:1 \
+             sequence 0xfffffffd, 1 witness items\n\
+             outputs (1):\n  \
+             #0 0.99999010 BTC witness_v0_keyhash -> bcrt1qgar7sarvmkenkrmljk5slz0cn7ec0jak76zrj7\n     \
+             asm: 0 4747e8746cddb33b0f7f95a90f89f89fb387cbb6\n"
+        );
+    }
+
+    #[test]
+    fn fetch_passes_the_block_hash() {
+        let (rpc, _) = mockrpc::client(|method, params| {
+            assert_eq!(method, "getrawtransaction");
+            assert_eq!(params[1], json!(true));
+            assert_eq!(
+                params[2],
+                json!("2eb85235a4d182e698beacdd429de9867713892597084effb8aa837a2c843334")
+            );
+            Ok(serde_json::from_str(SEGWIT).unwrap())
+        });
+        let tx = decoded(SEGWIT);
+        let block_hash: BlockHash =
+            "2eb85235a4d182e698beacdd429de9867713892597084effb8aa837a2c843334"
+                .parse()
+                .unwrap();
+        let fetched = fetch_decoded(&rpc, &tx.txid, Some(&block_hash)).unwrap();
+        assert_eq!(fetched.hash, tx.hash);
+        assert_eq!(fetched.vout.len(), 2);
+    }
+}
@@ -0,0 +1,106 @@
+use crate::error::{CapstoneError, Result};
+use bitcoincore_rpc::jsonrpc::error::Error as JsonRpcError;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+// The node doesn't know the RPC (getdeploymentinfo is Core 23+)
+const RPC_METHOD_NOT_FOUND: i32 = -32601;
+
+// One softfork as getdeploymentinfo lists it under `deployments`. Core 19-22
+// describe it the same way in getblockchaininfo's `softforks`, so one type
+// reads both.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeploymentStatus {
+    // "buried" (fixed activation height) or "bip9" (miner signalling)
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub active: bool,
+    // Activation height of a buried deployment, or of an active bip9 one
+    #[serde(default)]
+    pub height: Option<u64>,
+    #[serde(default)]
+    pub bip9: Option<Bip9Status>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Bip9Status {
+    // defined, started, locked_in, active or failed
+    pub status: String,
+    // Height the current status started at
+    #[serde(default)]
+    pub since: Option<u64>,
+    #[serde(default)]
+    pub min_activation_height: Option<u64>,
+}
+
+impl DeploymentStatus {
+    // The height it activates (or activated) at, when known
+    pub fn activation_height(&self) -> Option<u64> {
+        self.height.or_else(|| {
+            let bip9 = self.bip9.as_ref()?;
+            // Locked in: active from the next signalling period, and not
+            // before min_activation_height; only the latter is reported
+            match bip9.status.as_str() {
+                "locked_in" => bip9.min_activation_height,
+                _ => None,
+            }
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeploymentInfo {
+    deployments: BTreeMap<String, DeploymentStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LegacyBlockchainInfo {
+    #[serde(default)]
+    softforks: BTreeMap<String, DeploymentStatus>,
+}
+
+// Every deployment the node knows, by name, at the current tip.
+pub fn deployments(rpc: &Client) -> Result<BTreeMap<String, DeploymentStatus>> {
+    match rpc.call::<DeploymentInfo>("getdeploymentinfo", &[]) {
+        Ok(info) => Ok(info.deployments),
+        Err(bitcoincore_rpc::Error::JsonRpc(JsonRpcError::Rpc(e)))
+            if e.code == RPC_METHOD_NOT_FOUND =>
+        {
+            let info: LegacyBlockchainInfo = rpc.call("getblockchaininfo", &[])?;
+            Ok(info.softforks)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Names of the active deployments, for the report
+pub fn active(deployments: &BTreeMap<String, DeploymentStatus>) -> Vec<String> {
+    deployments
+        .iter()
+        .filter(|(_, status)| status.active)
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+// Fail early, before anything is mined or sent, when `name` isn't active at
+// the tip. Freshly reset regtest chains on older Core can be in that state.
+pub fn require_active(rpc: &Client, name: &str) -> Result<()> {
+    let deployments = deployments(rpc)?;
+    match deployments.get(name) {
+        Some(status) if status.active => Ok(()),
+        Some(status) => Err(CapstoneError::DeploymentInactive {
+            name: name.to_string(),
+            height: status.activation_height(),
+            status: status
+                .bip9
+                .as_ref()
+                .map_or(status.kind.clone(), |bip9| bip9.status.clone()),
+        }),
+        None => Err(CapstoneError::DeploymentInactive {
+            name: name.to_string(),
+            height: None,
+            status: "unknown to this node".to_string(),
+        }),
+    }
+}
@@ -0,0 +1,194 @@
+use crate::error::{CapstoneError, Result};
+use crate::relay::NodePolicy;
+use bitcoincore_rpc::bitcoin::{Address, Amount, Script};
+
+// Core's default -dustrelayfee, in sat/kvB.
+const DUST_RELAY_FEE_SAT_PER_KVB: u64 = 3_000;
+
+// An output the node would refuse to relay because it is below the dust limit.
+#[derive(Debug, Clone)]
+pub struct DustOutput {
+    pub address: String,
+    pub address_type: String,
+    pub amount: Amount,
+    pub threshold: Amount,
+}
+
+// Size of a serialized output paying to `script`: 8 byte value, the script length
+// prefix and the script itself.
+pub fn output_size(script: &Script) -> u64 {
+    let len = script.len() as u64;
+    let prefix = match len {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        _ => 5,
+    };
+    8 + prefix + len
+}
+
+// Same rules as Core's GetDustThreshold: an output is dust when spending it would cost
+// more than it is worth at the dust relay fee. The cost of spending is the output
+// itself plus a typical input that spends it: 148 bytes for a legacy input, and 67
+// vbytes for a segwit one (the 107 byte witness is discounted by 4).
+pub fn dust_threshold(script: &Script) -> Amount {
+    dust_threshold_at(script, DUST_RELAY_FEE_SAT_PER_KVB)
+}
+
+// The same at the node's own -dustrelayfee, see relay::NodePolicy
+pub fn dust_threshold_at(script: &Script, dust_relay_fee: u64) -> Amount {
+    if script.is_op_return() {
+        return Amount::ZERO;
+    }
+    Amount::from_sat(dust_relay_fee * spend_size(script) / 1000)
+}
+
+// The output plus a typical input spending it
+pub fn spend_size(script: &Script) -> u64 {
+    let input_size = if script.is_witness_program() {
+        32 + 4 + 1 + (107 / 4) + 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+    output_size(script) + input_size
+}
+
+// Check every recipient against its dust threshold and report all dusty outputs
+// at once, instead of letting the node reject the transaction with a generic -26.
+// Uses the node's dust relay fee when the policy probe found it.
+pub fn check_outputs(outputs: &[(Address, Amount)], policy: &NodePolicy) -> Result<()> {
+    let dust_relay_fee = policy
+        .dust_relay_fee
+        .found()
+        .unwrap_or(DUST_RELAY_FEE_SAT_PER_KVB);
+    let dusty: Vec<DustOutput> = outputs
+        .iter()
+        .filter_map(|(address, amount)| {
+            let threshold = dust_threshold_at(&address.script_pubkey(), dust_relay_fee);
+            (*amount < threshold).then(|| DustOutput {
+                address: address.to_string(),
+                address_type: address
+                    .address_type()
+                    .map(|t| t.to_string())
+                    .unwrap_or_else(|| "unknown".to_string()),
+                amount: *amount,
+                threshold,
+            })
+        })
+        .collect();
+
+    if dusty.is_empty() {
+        Ok(())
+    } else {
+        Err(CapstoneError::Dust(dusty))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::Probed;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::script::PushBytesBuf;
+    use bitcoincore_rpc::bitcoin::{
+        Network, PubkeyHash, ScriptBuf, ScriptHash, WPubkeyHash, WScriptHash, WitnessProgram,
+        WitnessVersion,
+    };
+
+    fn p2tr() -> ScriptBuf {
+        ScriptBuf::new_witness_program(&WitnessProgram::new(WitnessVersion::V1, [7; 32]).unwrap())
+    }
+
+    // Each script type with the limit Core enforces for it at the default
+    // 3 sat/vB and at 1 sat/vB
+    fn cases() -> Vec<(&'static str, ScriptBuf, u64, u64)> {
+        vec![
+            (
+                "P2PKH",
+                ScriptBuf::new_p2pkh(&PubkeyHash::all_zeros()),
+                546,
+                182,
+            ),
+            (
+                "P2WPKH",
+                ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+                294,
+                98,
+            ),
+            (
+                "P2SH",
+                ScriptBuf::new_p2sh(&ScriptHash::all_zeros()),
+                540,
+                180,
+            ),
+            (
+                "P2WSH",
+                ScriptBuf::new_p2wsh(&WScriptHash::all_zeros()),
+                330,
+                110,
+            ),
+            ("P2TR", p2tr(), 330, 110),
+        ]
+    }
+
+    #[test]
+    fn thresholds_at_the_default_dust_relay_fee() {
+        for (name, script, expected, _) in cases() {
+            assert_eq!(dust_threshold(&script).to_sat(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn thresholds_at_another_dust_relay_fee() {
+        for (name, script, default, expected) in cases() {
+            assert_eq!(
+                dust_threshold_at(&script, 1_000).to_sat(),
+                expected,
+                "{}",
+                name
+            );
+            assert_eq!(
+                dust_threshold_at(&script, 3_000).to_sat(),
+                default,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn check_outputs_reports_every_dusty_output_at_the_node_fee() {
+        let policy = NodePolicy {
+            min_relay_fee: 1_000,
+            mempool_min_fee: 1_000,
+            incremental_relay_fee: 1_000,
+            full_rbf: None,
+            max_op_return: Probed::Skipped,
+            dust_relay_fee: Probed::Found(Some(1_000)),
+        };
+        let outputs: Vec<(Address, Amount)> = cases()
+            .into_iter()
+            .map(|(_, script, _, threshold)| {
+                let address = Address::from_script(&script, Network::Regtest).unwrap();
+                (address, Amount::from_sat(threshold - 1))
+            })
+            .collect();
+        let Err(CapstoneError::Dust(dusty)) = check_outputs(&outputs, &policy) else {
+            panic!("every output is one sat below its threshold");
+        };
+        assert_eq!(dusty.len(), 5);
+        let at_threshold: Vec<(Address, Amount)> = outputs
+            .into_iter()
+            .map(|(address, amount)| (address, amount + Amount::from_sat(1)))
+            .collect();
+        assert!(check_outputs(&at_threshold, &policy).is_ok());
+    }
+
+    #[test]
+    fn op_return_is_never_dust() {
+        let data = PushBytesBuf::try_from(vec![0u8; 40]).unwrap();
+        assert_eq!(
+            dust_threshold(&ScriptBuf::new_op_return(data)),
+            Amount::ZERO
+        );
+    }
+}
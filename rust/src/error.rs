@@ -0,0 +1,462 @@
+use crate::btc;
+use crate::dust::DustOutput;
+use crate::signing::SigningError;
+use crate::version::CoreVersion;
+use bitcoincore_rpc::bitcoin::amount::ParseAmountError;
+use bitcoincore_rpc::bitcoin::consensus::encode;
+use bitcoincore_rpc::bitcoin::{Amount, BlockHash, OutPoint, Txid};
+use std::fmt;
+use std::path::PathBuf;
+
+// Every fallible step of the capstone flow returns this error, so `main` can
+// print a readable message instead of a debug dump.
+#[derive(Debug)]
+pub enum CapstoneError {
+    Rpc(bitcoincore_rpc::Error),
+    // With the file and what was being done to it, when it's one of ours
+    // (see files.rs), e.g. "create report file" and /home/me/out.txt
+    Io {
+        source: std::io::Error,
+        file: Option<(String, PathBuf)>,
+    },
+    Amount(ParseAmountError),
+    // Bad command-line input
+    Usage(String),
+    // The change address is not owned by the Miner wallet
+    ExternalChange(String),
+    // The confirmed transaction did not send change where we asked it to
+    ChangeMismatch {
+        expected: String,
+        found: Option<String>,
+    },
+    // The funded transaction would pay more than --max-fee
+    FeeTooHigh {
+        fee: Amount,
+        max_fee: Amount,
+    },
+    // signrawtransactionwithwallet could not sign every input
+    SigningIncomplete(Vec<SigningError>),
+    // `send` left inputs unsigned (a watch-only, multisig or external-signer
+    // wallet) and returned this PSBT instead of broadcasting
+    SendIncomplete {
+        psbt: String,
+        errors: Vec<SigningError>,
+    },
+    // testmempoolaccept said the node won't take the transaction
+    MempoolRejected {
+        txid: Txid,
+        reason: String,
+    },
+    // A conflict the mode set up isn't in the wallet
+    ConflictNotDetected {
+        txid: Txid,
+        reason: String,
+    },
+    // A transaction we broadcast didn't reach the mempool within --mempool-timeout
+    NotInMempool {
+        txid: Txid,
+        waited: u64,
+        reason: String,
+    },
+    // Transactions that should have confirmed in the block we just mined but didn't
+    Unconfirmed(Vec<Txid>),
+    // One or more outputs are below the dust limit for their address type
+    Dust(Vec<DustOutput>),
+    // --bump-until-confirmed gave up on the payment
+    BumpAborted {
+        txid: Txid,
+        reason: String,
+    },
+    // A PSBT file that could not be read or parsed
+    PsbtFile {
+        path: String,
+        reason: String,
+    },
+    // analyzepsbt/finalizepsbt say the PSBT still needs work; one line per problem
+    PsbtNotFinalizable(Vec<String>),
+    // The PSBT doesn't pay the Trader wallet; the addresses it does pay
+    UnexpectedPsbtOutputs(Vec<String>),
+    // sendrawtransaction refused the transaction for exceeding --max-broadcast-feerate
+    BroadcastFeeRateTooHigh {
+        txid: Txid,
+        // sat/kvB
+        fee_rate: u64,
+        // BTC/kvB
+        max_fee_rate: Amount,
+    },
+    // gettxout disagrees with what we expect of one of our outputs
+    UtxoCheckFailed {
+        outpoint: OutPoint,
+        reason: String,
+    },
+    // Not a failure: --export-psbt wrote the PSBT to this path for signing elsewhere
+    AwaitingSignature(String),
+    // The transaction didn't reach the wanted depth in time
+    ConfirmationTimeout {
+        txid: Txid,
+        confirmations: i32,
+        wanted: u32,
+    },
+    // The block a transaction was reported in is no longer in the active chain
+    StaleBlock {
+        txid: Txid,
+        block_hash: BlockHash,
+    },
+    // generateblock refused the block: the node's reason, then per-transaction ones
+    BlockRejected(Vec<String>),
+    // Mining stopped at the block cap without the Miner reaching its target balance
+    FundingStalled {
+        mined: u64,
+        balance: Amount,
+        target: Amount,
+    },
+    // None of the lookups found the output an input spends
+    PrevoutNotFound {
+        outpoint: OutPoint,
+        txindex: bool,
+        pruned: bool,
+    },
+    // A block the mode needs is below the prune height
+    BlockPruned {
+        what: String,
+        block_hash: BlockHash,
+        height: u64,
+        prune_height: u64,
+    },
+    // The confirming block's coinbase doesn't add up
+    CoinbaseCheckFailed(String),
+    // The payment's merkle inclusion proof doesn't check out
+    ProofCheckFailed(String),
+    // --verify-with-filters didn't find the payment through the block filters
+    FilterCheckFailed(String),
+    // The mempool's floor or size limit would turn the transaction away
+    MempoolLimit(String),
+    // A --template that doesn't parse or names something the report lacks
+    Template(String),
+    // The --db database
+    #[cfg(feature = "sqlite")]
+    Database(rusqlite::Error),
+    // The node runs on an assumeutxo snapshot whose background validation isn't done
+    SnapshotNotValidated {
+        snapshot_height: u64,
+        validated_height: u64,
+    },
+    // propagation-test's two nodes never connected; no address means none was
+    // given and they weren't connected already
+    PeersNotConnected {
+        address: Option<String>,
+        waited: u64,
+    },
+    // Something never reached the second node in propagation-test
+    PropagationTimeout {
+        what: String,
+        waited: u64,
+    },
+    // The node's Core release predates something the mode needs
+    CoreTooOld {
+        feature: String,
+        required: CoreVersion,
+        found: CoreVersion,
+    },
+    // A softfork the mode relies on isn't active at the tip
+    DeploymentInactive {
+        name: String,
+        height: Option<u64>,
+        status: String,
+    },
+    // A coinbase doesn't claim the subsidy its height entitles it to
+    SubsidyMismatch {
+        height: u64,
+        expected: Amount,
+        observed: Amount,
+    },
+    // Raw hex from the node that decodes to a different transaction
+    TxidMismatch {
+        expected: Txid,
+        computed: Txid,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, CapstoneError>;
+
+impl fmt::Display for CapstoneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CapstoneError::Rpc(e) => write!(f, "RPC error: {}", e),
+            CapstoneError::Io { source, file } => match file {
+                Some((operation, path)) => {
+                    write!(f, "failed to {} {}: {}", operation, path.display(), source)
+                }
+                None => write!(f, "IO error: {}", source),
+            },
+            CapstoneError::Amount(e) => write!(f, "invalid amount: {}", e),
+            CapstoneError::Usage(msg) => write!(f, "{}", msg),
+            CapstoneError::ExternalChange(addr) => write!(
+                f,
+                "change address {} does not belong to the Miner wallet (pass --allow-external-change to use it anyway)",
+                addr
+            ),
+            CapstoneError::ChangeMismatch { expected, found } => match found {
+                Some(found) => write!(
+                    f,
+                    "change was sent to {} instead of the requested {}",
+                    found, expected
+                ),
+                None => write!(
+                    f,
+                    "transaction has no change output, expected change at {}",
+                    expected
+                ),
+            },
+            CapstoneError::FeeTooHigh { fee, max_fee } => write!(f, "transaction fee of {} exceeds the --max-fee cap of {}, not signing it", btc::amount(*fee), btc::amount(*max_fee)),
+            CapstoneError::SigningIncomplete(errors) => {
+                write!(f, "signing the transaction failed:")?;
+                for e in errors {
+                    write!(f, "\n  input {}:{}: {}", e.txid, e.vout, e.error)?;
+                }
+                Ok(())
+            }
+            CapstoneError::SendIncomplete { psbt, errors } => {
+                write!(f, "the wallet could not sign every input, nothing was broadcast:")?;
+                for e in errors {
+                    write!(f, "\n  input {}:{}: {}", e.txid, e.vout, e.error)?;
+                }
+                write!(f, "\nsign this PSBT elsewhere, save it to a file and run finalize-psbt <path>:\n{}", psbt)
+            }
+            CapstoneError::MempoolRejected { txid, reason } => write!(
+                f,
+                "transaction {} would be rejected by the mempool: {}",
+                txid, reason
+            ),
+            CapstoneError::ConflictNotDetected { txid, reason } => write!(
+                f,
+                "expected {} to be conflicted: {}",
+                txid, reason
+            ),
+            CapstoneError::NotInMempool {
+                txid,
+                waited,
+                reason,
+            } => write!(
+                f,
+                "transaction {} was not in the mempool after {}s: {}",
+                txid, waited, reason
+            ),
+            CapstoneError::Unconfirmed(txids) => write!(
+                f,
+                "transactions not confirmed in the mined block: {}",
+                txids
+                    .iter()
+                    .map(|txid| txid.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            CapstoneError::Dust(outputs) => {
+                write!(f, "refusing to create dust outputs:")?;
+                for output in outputs {
+                    write!(f, "\n  {} to {} is below the {} minimum of {}", btc::amount(output.amount), output.address, output.address_type, btc::amount(output.threshold))?;
+                }
+                Ok(())
+            }
+            CapstoneError::BumpAborted { txid, reason } => {
+                write!(f, "stopped fee-bumping {}: {}", txid, reason)
+            }
+            CapstoneError::PsbtFile { path, reason } => {
+                write!(f, "cannot read PSBT from {}: {}", path, reason)
+            }
+            CapstoneError::PsbtNotFinalizable(problems) => {
+                write!(f, "PSBT is not ready to finalize:")?;
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            }
+            CapstoneError::UnexpectedPsbtOutputs(addresses) => write!(
+                f,
+                "PSBT does not pay the Trader wallet, it pays {} (pass --allow-any-outputs to broadcast it anyway)",
+                addresses.join(", ")
+            ),
+            CapstoneError::BroadcastFeeRateTooHigh {
+                txid,
+                fee_rate,
+                max_fee_rate,
+            } => write!(
+                f,
+                "transaction {} pays {} sat/vB, above the broadcast cap of {} sat/vB (raise --max-broadcast-feerate, or 0 to disable it, if that fee is intended)",
+                txid,
+                crate::fees::format_sat_per_vb(*fee_rate),
+                crate::fees::format_sat_per_vb(max_fee_rate.to_sat())
+            ),
+            CapstoneError::UtxoCheckFailed { outpoint, reason } => {
+                write!(f, "UTXO check failed for {}: {}", outpoint, reason)
+            }
+            CapstoneError::AwaitingSignature(path) => {
+                write!(f, "PSBT exported to {}, awaiting signature", path)
+            }
+            CapstoneError::ConfirmationTimeout {
+                txid,
+                confirmations,
+                wanted,
+            } => write!(
+                f,
+                "gave up waiting for {}: {} of {} confirmation(s)",
+                txid, confirmations, wanted
+            ),
+            CapstoneError::StaleBlock { txid, block_hash } => write!(
+                f,
+                "{} was confirmed in block {}, which is no longer in the active chain",
+                txid, block_hash
+            ),
+            CapstoneError::BlockRejected(reasons) => {
+                write!(f, "generateblock refused the block:")?;
+                for reason in reasons {
+                    write!(f, "\n  {}", reason)?;
+                }
+                Ok(())
+            }
+            CapstoneError::FundingStalled {
+                mined,
+                balance,
+                target,
+            } => write!(f, "mined {} blocks but the Miner's trusted balance is {} of the {} needed; is the wallet receiving the rewards?", mined, btc::amount(*balance), btc::amount(*target)),
+            CapstoneError::PrevoutNotFound {
+                outpoint,
+                txindex,
+                pruned,
+            } => {
+                write!(
+                    f,
+                    "could not find the output {} spends: it is not in the UTXO set, the mempool or a loaded wallet",
+                    outpoint
+                )?;
+                if *pruned {
+                    // -txindex can't be combined with pruning
+                    write!(f, "; the node is pruned, load the wallet that received it or use an unpruned node")?;
+                } else if !txindex {
+                    write!(f, "; restart bitcoind with -txindex=1 to look up any transaction")?;
+                }
+                Ok(())
+            }
+            CapstoneError::BlockPruned {
+                what,
+                block_hash,
+                height,
+                prune_height,
+            } => write!(
+                f,
+                "{} ({} at height {}) has been pruned, the node only keeps blocks from height {}; use an unpruned node or one with a higher -prune target",
+                what, block_hash, height, prune_height
+            ),
+            CapstoneError::SnapshotNotValidated {
+                snapshot_height,
+                validated_height,
+            } => write!(
+                f,
+                "the node is running on a UTXO snapshot at height {} that is only validated up to height {}; this mode needs the fully validated chain, wait for background validation or use a read-only mode",
+                snapshot_height, validated_height
+            ),
+            CapstoneError::CoinbaseCheckFailed(reason) => {
+                write!(f, "coinbase check failed: {}", reason)
+            }
+            CapstoneError::ProofCheckFailed(reason) => {
+                write!(f, "merkle proof check failed: {}", reason)
+            }
+            CapstoneError::FilterCheckFailed(reason) => {
+                write!(f, "block filter check failed: {}", reason)
+            }
+            CapstoneError::Template(reason) => write!(f, "template error: {}", reason),
+            #[cfg(feature = "sqlite")]
+            CapstoneError::Database(e) => write!(f, "database error: {}", e),
+            CapstoneError::MempoolLimit(reason) => {
+                write!(f, "the mempool won't take it: {}", reason)
+            }
+            CapstoneError::PeersNotConnected {
+                address: None,
+                ..
+            } => write!(
+                f,
+                "the nodes aren't connected; pass --peer-p2p <host:port> with node B's P2P address so node A can connect to it"
+            ),
+            CapstoneError::PeersNotConnected {
+                address: Some(address),
+                waited,
+            } => write!(
+                f,
+                "node A had no connection to {} after {}s; check node B is running on regtest and listening there (-listen=1, -port, -bind), and that neither node has -maxconnections=0 or -connect set",
+                address, waited
+            ),
+            CapstoneError::PropagationTimeout { what, waited } => {
+                write!(f, "gave up after {}s waiting for {}", waited, what)
+            }
+            CapstoneError::CoreTooOld {
+                feature,
+                required,
+                found,
+            } => write!(
+                f,
+                "{} requires Core >= {}, the node runs {}",
+                feature, required, found
+            ),
+            CapstoneError::DeploymentInactive {
+                name,
+                height: Some(height),
+                ..
+            } => write!(f, "{} not active until height {}", name, height),
+            CapstoneError::DeploymentInactive { name, status, .. } => {
+                write!(f, "{} is not active ({})", name, status)
+            }
+            CapstoneError::SubsidyMismatch {
+                height,
+                expected,
+                observed,
+            } => write!(f, "the coinbase at height {} claims a {} subsidy, expected {}", height, btc::amount(*observed), btc::amount(*expected)),
+            CapstoneError::TxidMismatch { expected, computed } => write!(
+                f,
+                "the raw hex the node returned for {} decodes to {}",
+                expected, computed
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CapstoneError {}
+
+impl From<bitcoincore_rpc::Error> for CapstoneError {
+    fn from(e: bitcoincore_rpc::Error) -> Self {
+        CapstoneError::Rpc(e)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for CapstoneError {
+    fn from(e: rusqlite::Error) -> Self {
+        CapstoneError::Database(e)
+    }
+}
+
+impl From<std::io::Error> for CapstoneError {
+    fn from(e: std::io::Error) -> Self {
+        CapstoneError::Io {
+            source: e,
+            file: None,
+        }
+    }
+}
+
+impl From<encode::Error> for CapstoneError {
+    fn from(e: encode::Error) -> Self {
+        CapstoneError::Rpc(e.into())
+    }
+}
+
+impl From<serde_json::Error> for CapstoneError {
+    fn from(e: serde_json::Error) -> Self {
+        CapstoneError::Rpc(e.into())
+    }
+}
+
+impl From<ParseAmountError> for CapstoneError {
+    fn from(e: ParseAmountError) -> Self {
+        CapstoneError::Amount(e)
+    }
+}
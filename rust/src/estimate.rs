@@ -0,0 +1,426 @@
+use crate::btc;
+use crate::bump;
+use crate::cli::{ChangeType, Config};
+use crate::console::say;
+use crate::dust;
+use crate::error::{CapstoneError, Result};
+use crate::fees::{FeeRateChoice, FeeSource};
+use crate::fund::{self, FundOptions};
+use crate::relay;
+use crate::session::Session;
+use crate::signing;
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::blockdata::opcodes::all::OP_PUSHNUM_1;
+use bitcoincore_rpc::bitcoin::blockdata::script::Builder;
+use bitcoincore_rpc::bitcoin::hashes::Hash;
+use bitcoincore_rpc::bitcoin::{
+    Address, Amount, PubkeyHash, Script, ScriptBuf, ScriptHash, WPubkeyHash,
+};
+use bitcoincore_rpc::json::ListUnspentResultEntry;
+use bitcoincore_rpc::RpcApi;
+use std::collections::HashMap;
+
+// What a coin's scriptPubKey needs to be spent, which fixes the input's size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum ScriptKind {
+    P2wpkh,
+    P2tr,
+    P2pkh,
+    // Assumed to wrap P2WPKH, the only kind of P2SH the wallet hands out itself
+    P2shP2wpkh,
+}
+
+impl ScriptKind {
+    fn of(script: &Script) -> Option<Self> {
+        if script.is_p2wpkh() {
+            Some(ScriptKind::P2wpkh)
+        } else if script.is_p2tr() {
+            Some(ScriptKind::P2tr)
+        } else if script.is_p2pkh() {
+            Some(ScriptKind::P2pkh)
+        } else if script.is_p2sh() {
+            Some(ScriptKind::P2shP2wpkh)
+        } else {
+            None
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ScriptKind::P2wpkh => "p2wpkh",
+            ScriptKind::P2tr => "p2tr",
+            ScriptKind::P2pkh => "p2pkh",
+            ScriptKind::P2shP2wpkh => "p2sh-p2wpkh",
+        }
+    }
+
+    fn is_segwit(self) -> bool {
+        self != ScriptKind::P2pkh
+    }
+
+    // Weight of an input spending this kind of coin. Every input has 41 bytes of
+    // outpoint, scriptSig length and sequence; signatures are counted at 72
+    // bytes, the upper end the wallet also assumes when funding.
+    fn input_weight(self) -> u64 {
+        // witness item count, then signature and public key with their lengths
+        let p2wpkh_witness = 1 + (1 + 72) + (1 + 33);
+        match self {
+            // 68 vB
+            ScriptKind::P2wpkh => 4 * 41 + p2wpkh_witness,
+            // 57.5 vB: one 64 byte Schnorr signature
+            ScriptKind::P2tr => 4 * 41 + 1 + (1 + 64),
+            // 148 vB: signature and key in the scriptSig
+            ScriptKind::P2pkh => 4 * (41 + 1 + 72 + 1 + 33),
+            // 91 vB: the 22 byte redeem script pushed in the scriptSig
+            ScriptKind::P2shP2wpkh => 4 * (41 + 1 + 22) + p2wpkh_witness,
+        }
+    }
+}
+
+// A script of the shape the wallet's change would have, for sizing only.
+fn change_script(change_type: Option<ChangeType>) -> ScriptBuf {
+    match change_type.unwrap_or(ChangeType::Bech32) {
+        ChangeType::Legacy => ScriptBuf::new_p2pkh(&PubkeyHash::all_zeros()),
+        ChangeType::P2shSegwit => ScriptBuf::new_p2sh(&ScriptHash::all_zeros()),
+        ChangeType::Bech32 => ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+        ChangeType::Bech32m => Builder::new()
+            .push_opcode(OP_PUSHNUM_1)
+            .push_slice([0u8; 32])
+            .into_script(),
+    }
+}
+
+fn compact_size_len(n: usize) -> u64 {
+    match n {
+        0..=0xfc => 1,
+        0xfd..=0xffff => 3,
+        _ => 5,
+    }
+}
+
+// Weight of a whole transaction: version and locktime, the input and output
+// counts, the segwit marker and flag if any input has a witness, and the parts.
+fn tx_weight(inputs: &[ScriptKind], output_weights: &[u64]) -> u64 {
+    let segwit = inputs.iter().any(|kind| kind.is_segwit());
+    let mut weight =
+        4 * (4 + 4 + compact_size_len(inputs.len()) + compact_size_len(output_weights.len()));
+    if segwit {
+        weight += 2;
+    }
+    weight += inputs.iter().map(|kind| kind.input_weight()).sum::<u64>();
+    // A legacy input in a segwit transaction still needs an empty witness
+    if segwit {
+        weight += inputs.iter().filter(|kind| !kind.is_segwit()).count() as u64;
+    }
+    weight + output_weights.iter().sum::<u64>()
+}
+
+fn output_weight(script: &Script) -> u64 {
+    4 * dust::output_size(script)
+}
+
+// The fee for `vsize` at `sat_per_kvb`, rounded up like the node does.
+fn fee_for(vsize: u64, sat_per_kvb: u64) -> Amount {
+    Amount::from_sat((vsize * sat_per_kvb).div_ceil(1000))
+}
+
+// The outcome of the local largest-first pick.
+struct Prediction {
+    inputs: Vec<ScriptKind>,
+    total_in: Amount,
+    change: Option<Amount>,
+    vsize: u64,
+    fee: Amount,
+}
+
+// Add coins largest first until they pay the recipients and the fee. A change
+// output is added when what is left over would not be dust; otherwise the
+// remainder goes to the fee, as the wallet does.
+fn select_largest_first(
+    coins: &[(Amount, ScriptKind)],
+    recipients: &[(Address, Amount)],
+    change: &Script,
+    sat_per_kvb: u64,
+) -> Option<Prediction> {
+    let target: Amount = recipients.iter().map(|(_, amount)| *amount).sum();
+    let mut outputs: Vec<u64> = recipients
+        .iter()
+        .map(|(address, _)| output_weight(&address.script_pubkey()))
+        .collect();
+    let without_change = outputs.clone();
+    outputs.push(output_weight(change));
+    let with_change = outputs;
+
+    let mut inputs = Vec::new();
+    let mut total_in = Amount::ZERO;
+    for (amount, kind) in coins {
+        inputs.push(*kind);
+        total_in += *amount;
+
+        let vsize = tx_weight(&inputs, &with_change).div_ceil(4);
+        let fee = fee_for(vsize, sat_per_kvb);
+        if let Some(left) = total_in.checked_sub(target + fee) {
+            if left >= dust::dust_threshold(change) {
+                return Some(Prediction {
+                    inputs,
+                    total_in,
+                    change: Some(left),
+                    vsize,
+                    fee,
+                });
+            }
+        }
+        let vsize = tx_weight(&inputs, &without_change).div_ceil(4);
+        let fee = fee_for(vsize, sat_per_kvb);
+        if total_in >= target + fee {
+            return Some(Prediction {
+                inputs,
+                total_in,
+                change: None,
+                vsize,
+                fee: total_in - target,
+            });
+        }
+    }
+    None
+}
+
+fn describe_inputs(inputs: &[ScriptKind]) -> String {
+    let mut counts: Vec<(ScriptKind, usize)> = Vec::new();
+    for kind in inputs {
+        match counts.iter_mut().find(|(k, _)| k == kind) {
+            Some((_, n)) => *n += 1,
+            None => counts.push((*kind, 1)),
+        }
+    }
+    counts
+        .iter()
+        .map(|(kind, n)| format!("{} {}", n, kind.name()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn parse_recipients(
+    recipients: &[(String, Amount)],
+    session: &Session,
+) -> Result<Vec<(Address, Amount)>> {
+    recipients
+        .iter()
+        .map(|(address, amount)| {
+            let invalid =
+                |e: String| CapstoneError::Usage(format!("invalid address '{}': {}", address, e));
+            let address = address
+                .parse::<Address<NetworkUnchecked>>()
+                .map_err(|e| invalid(e.to_string()))?
+                .require_network(session.network)
+                .map_err(|e| invalid(e.to_string()))?;
+            Ok((address, *amount))
+        })
+        .collect()
+}
+
+// estimate: predict the vsize and fee of paying `recipients` from the Miner
+// wallet with a local largest-first pick, then ask fundrawtransaction (with
+// lock_unspents=false, so nothing is reserved) what it would do instead.
+// Nothing is signed or broadcast.
+pub fn run(session: &Session, config: &Config, recipients: &[(String, Amount)]) -> Result<()> {
+    let miner_rpc = &session.miner_rpc;
+    let recipients = parse_recipients(recipients, session)?;
+    let choice = match config.fee_rate {
+        Some(sat_per_kvb) => FeeRateChoice {
+            sat_per_kvb,
+            source: FeeSource::Requested,
+        },
+        None => bump::initial_fee_rate(&session.rpc, config)?,
+    };
+
+    let utxos: Vec<ListUnspentResultEntry> = miner_rpc
+        .list_unspent(Some(1), None, None, None, None)?
+        .into_iter()
+        .filter(|utxo| utxo.spendable)
+        .collect();
+    let mut coins: Vec<(Amount, ScriptKind)> = Vec::new();
+    let mut unknown = 0;
+    for utxo in &utxos {
+        match ScriptKind::of(&utxo.script_pub_key) {
+            Some(kind) => coins.push((utxo.amount, kind)),
+            None => unknown += 1,
+        }
+    }
+    coins.sort_by_key(|(amount, _)| std::cmp::Reverse(*amount));
+
+    say!("Estimate at {}:", choice.describe());
+    // Only a warning: nothing is broadcast, the prediction still holds
+    if let Err(e) =
+        relay::policy(&session.rpc, &[])?.require_fee_rate(choice.sat_per_kvb, "--fee-rate")
+    {
+        say!("Warning: {}", e);
+    }
+    say!(
+        "Candidate coins: {} confirmed, {}",
+        utxos.len(),
+        btc::amount(utxos.iter().map(|utxo| utxo.amount).sum::<Amount>())
+    );
+    if unknown > 0 {
+        say!(
+            "Skipped {} coin(s) with a script type the size model doesn't know.",
+            unknown
+        );
+    }
+
+    let change = change_script(config.change_type);
+    let predicted = select_largest_first(&coins, &recipients, &change, choice.sat_per_kvb);
+    match &predicted {
+        Some(p) => {
+            say!(
+                "Largest first: {} input(s) ({}) worth {}, {} recipient(s), {}",
+                p.inputs.len(),
+                describe_inputs(&p.inputs),
+                btc::amount(p.total_in),
+                recipients.len(),
+                match p.change {
+                    Some(change) => format!("change {}", btc::amount(change)),
+                    None => "no change".to_string(),
+                }
+            );
+            say!("Predicted: {} vB, fee {}", p.vsize, btc::amount(p.fee));
+        }
+        None => say!("Predicted: the candidate coins can't pay for this."),
+    }
+
+    // The wallet's own answer for comparison
+    let mut outputs = HashMap::new();
+    for (address, amount) in &recipients {
+        *outputs.entry(address.to_string()).or_insert(Amount::ZERO) += *amount;
+    }
+    let unsigned = miner_rpc.create_raw_transaction_hex(&[], &outputs, None, None)?;
+    let options = FundOptions::new()
+        .lock_unspents(false)
+        .change_type(config.change_type)
+        .fee_rate(&choice);
+    match fund::fund_raw_transaction(miner_rpc, &unsigned, &options) {
+        Ok(funded) => {
+            let tx = signing::decode_tx(&funded.hex)?;
+            // The fee was set from the wallet's estimate of the signed size,
+            // so the size can be read back from it
+            let vsize = (funded.fee.to_sat() * 1000).div_ceil(choice.sat_per_kvb.max(1));
+            say!(
+                "fundrawtransaction: {} input(s), {}, fee {} (~{} vB)",
+                tx.input.len(),
+                match funded.changepos {
+                    Some(vout) => format!("change at vout {}", vout),
+                    None => "no change".to_string(),
+                },
+                btc::amount(funded.fee),
+                vsize
+            );
+            if let Some(p) = &predicted {
+                let difference = funded.fee.to_sat() as i64 - p.fee.to_sat() as i64;
+                say!(
+                    "Difference: {:+} sat ({:+} vB)",
+                    difference,
+                    vsize as i64 - p.vsize as i64
+                );
+            }
+        }
+        Err(e) => say!("fundrawtransaction failed: {}", e),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::consensus::encode;
+    use bitcoincore_rpc::bitcoin::hex::FromHex;
+    use bitcoincore_rpc::bitcoin::Transaction;
+    use serde_json::Value;
+
+    fn bytes(hex: &Value) -> Vec<u8> {
+        Vec::from_hex(hex.as_str().unwrap()).unwrap()
+    }
+
+    // The signed spends of the verbosity 3 block fixture, with the kinds of
+    // the coins they spend as their prevouts tell them
+    fn signed_spends() -> Vec<(Transaction, Vec<ScriptKind>)> {
+        let block: Value =
+            serde_json::from_str(include_str!("../tests/fixtures/block-verbose3.json")).unwrap();
+        block["tx"].as_array().unwrap()[1..]
+            .iter()
+            .map(|tx| {
+                let kinds = tx["vin"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|vin| {
+                        let script = bytes(&vin["prevout"]["scriptPubKey"]["hex"]);
+                        ScriptKind::of(Script::from_bytes(&script)).unwrap()
+                    })
+                    .collect();
+                (encode::deserialize(&bytes(&tx["hex"])).unwrap(), kinds)
+            })
+            .collect()
+    }
+
+    fn predicted_weight(tx: &Transaction, inputs: &[ScriptKind]) -> u64 {
+        let outputs: Vec<u64> = tx
+            .output
+            .iter()
+            .map(|txout| output_weight(&txout.script_pubkey))
+            .collect();
+        tx_weight(inputs, &outputs)
+    }
+
+    #[test]
+    fn p2wpkh_spend_matches_the_signed_size() {
+        let spends = signed_spends();
+        let (tx, inputs) = &spends[0];
+        assert_eq!(inputs, &[ScriptKind::P2wpkh]);
+        assert_eq!(tx.output.len(), 2);
+        // The signature came out at 71 bytes, one less than is counted for
+        let weight = predicted_weight(tx, inputs);
+        assert_eq!(weight, 562);
+        assert_eq!(tx.weight().to_wu(), 561);
+        assert_eq!(weight.div_ceil(4), tx.vsize() as u64);
+    }
+
+    #[test]
+    fn p2tr_spend_matches_the_signed_size() {
+        let spends = signed_spends();
+        let (tx, inputs) = &spends[1];
+        assert_eq!(inputs, &[ScriptKind::P2tr]);
+        // Schnorr signatures have a fixed size, so the key path spend is exact
+        assert_eq!(predicted_weight(tx, inputs), tx.weight().to_wu());
+        assert_eq!(predicted_weight(tx, inputs).div_ceil(4), 99);
+    }
+
+    #[test]
+    fn never_under_the_signed_size() {
+        for (tx, inputs) in signed_spends() {
+            let weight = predicted_weight(&tx, &inputs);
+            let actual = tx.weight().to_wu();
+            assert!(weight >= actual, "{} < {}", weight, actual);
+            // At most one byte of signature per input over
+            assert!(weight - actual <= inputs.len() as u64);
+        }
+    }
+
+    #[test]
+    fn input_sizes() {
+        let vbytes = |kind: ScriptKind| kind.input_weight() as f64 / 4.0;
+        assert_eq!(vbytes(ScriptKind::P2wpkh), 68.0);
+        assert_eq!(vbytes(ScriptKind::P2tr), 57.5);
+        assert_eq!(vbytes(ScriptKind::P2pkh), 148.0);
+        assert_eq!(vbytes(ScriptKind::P2shP2wpkh), 91.0);
+    }
+
+    #[test]
+    fn legacy_inputs_get_an_empty_witness_in_segwit_transactions() {
+        let p2wpkh_output = output_weight(&change_script(None));
+        let legacy = tx_weight(&[ScriptKind::P2pkh], &[p2wpkh_output]);
+        // No marker and flag without a witness
+        assert_eq!(legacy, 4 * (10 + 148 + 31));
+        let mixed = tx_weight(&[ScriptKind::P2pkh, ScriptKind::P2wpkh], &[p2wpkh_output]);
+        assert_eq!(mixed, legacy + ScriptKind::P2wpkh.input_weight() + 2 + 1);
+    }
+}
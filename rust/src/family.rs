@@ -0,0 +1,174 @@
+use crate::btc;
+use crate::cli::Config;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::fees;
+use crate::mempool::{self, fee_rate, MempoolEntry};
+use bitcoincore_rpc::bitcoin::{Amount, Txid};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+
+// An unconfirmed transaction with its in-mempool ancestors and descendants,
+// everything that would have to confirm with it or after it.
+#[derive(Debug, Clone)]
+pub struct TxFamily {
+    pub txid: Txid,
+    // The transaction itself included
+    pub entries: HashMap<Txid, MempoolEntry>,
+}
+
+pub fn tx_family(rpc: &Client, txid: &Txid) -> Result<TxFamily> {
+    let mut entries: HashMap<Txid, MempoolEntry> =
+        rpc.call("getmempoolancestors", &[json!(txid), json!(true)])?;
+    let descendants: HashMap<Txid, MempoolEntry> =
+        rpc.call("getmempooldescendants", &[json!(txid), json!(true)])?;
+    entries.extend(descendants);
+    entries.insert(*txid, mempool::get_mempool_entry(rpc, txid)?);
+    Ok(TxFamily {
+        txid: *txid,
+        entries,
+    })
+}
+
+impl TxFamily {
+    pub fn total_fee(&self) -> Amount {
+        self.entries.values().map(|entry| entry.fees.base).sum()
+    }
+
+    pub fn total_vsize(&self) -> u64 {
+        self.entries.values().map(|entry| entry.vsize).sum()
+    }
+
+    // Unconfirmed parents and children inside the family, sorted so the
+    // output doesn't depend on map order
+    fn parents(&self, txid: &Txid) -> Vec<Txid> {
+        let mut parents: Vec<Txid> = self.entries[txid]
+            .depends
+            .iter()
+            .filter(|parent| self.entries.contains_key(*parent))
+            .copied()
+            .collect();
+        parents.sort();
+        parents
+    }
+
+    fn children(&self, txid: &Txid) -> Vec<Txid> {
+        let mut children: Vec<Txid> = self.entries[txid]
+            .spent_by
+            .iter()
+            .filter(|child| self.entries.contains_key(*child))
+            .copied()
+            .collect();
+        children.sort();
+        children
+    }
+
+    fn describe_node(&self, txid: &Txid) -> String {
+        let entry = &self.entries[txid];
+        format!(
+            "{}{} fee {}, {} vB, {} sat/vB",
+            txid,
+            if *txid == self.txid { " (this)" } else { "" },
+            btc::amount(entry.fees.base),
+            entry.vsize,
+            fees::format_sat_per_vb(entry.individual_fee_rate())
+        )
+    }
+
+    fn render_node(
+        &self,
+        txid: &Txid,
+        depth: usize,
+        seen: &mut HashSet<Txid>,
+        lines: &mut Vec<String>,
+    ) {
+        let indent = "  ".repeat(depth);
+        // In a diamond a child hangs under each of its parents; only the first
+        // occurrence lists what follows it
+        if !seen.insert(*txid) {
+            lines.push(format!("{}{} (see above)", indent, txid));
+            return;
+        }
+        lines.push(format!("{}{}", indent, self.describe_node(txid)));
+        for child in self.children(txid) {
+            self.render_node(&child, depth + 1, seen, lines);
+        }
+    }
+
+    // An indented tree from the oldest ancestors down, then the package totals
+    pub fn render_tree(&self) -> Vec<String> {
+        let mut roots: Vec<Txid> = self
+            .entries
+            .keys()
+            .filter(|txid| self.parents(txid).is_empty())
+            .copied()
+            .collect();
+        roots.sort();
+        let mut lines = Vec::new();
+        let mut seen = HashSet::new();
+        for root in &roots {
+            self.render_node(root, 0, &mut seen, &mut lines);
+        }
+        lines.push(format!(
+            "Package: {} tx, {} vB, fee {}, {} sat/vB",
+            self.entries.len(),
+            self.total_vsize(),
+            btc::amount(self.total_fee()),
+            fees::format_sat_per_vb(fee_rate(self.total_fee(), self.total_vsize()))
+        ));
+        lines
+    }
+
+    // Graphviz: one node per transaction, an edge from each parent to each child
+    pub fn render_dot(&self) -> String {
+        let mut txids: Vec<&Txid> = self.entries.keys().collect();
+        txids.sort();
+        let mut dot = String::from("digraph family {\n");
+        for txid in &txids {
+            let entry = &self.entries[*txid];
+            dot.push_str(&format!(
+                "  \"{}\" [label=\"{}...\\n{} vB, {} sat/vB\"{}];\n",
+                txid,
+                &txid.to_string()[..12],
+                entry.vsize,
+                fees::format_sat_per_vb(entry.individual_fee_rate()),
+                if **txid == self.txid {
+                    ", style=bold"
+                } else {
+                    ""
+                }
+            ));
+        }
+        for txid in &txids {
+            for child in self.children(txid) {
+                dot.push_str(&format!("  \"{}\" -> \"{}\";\n", txid, child));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+// tx-family: print an unconfirmed transaction's in-mempool family as a tree,
+// or as DOT with --dot.
+pub fn run(rpc: &Client, config: &Config, txid: &str) -> Result<()> {
+    let txid: Txid = txid
+        .parse()
+        .map_err(|e| CapstoneError::Usage(format!("invalid txid '{}': {}", txid, e)))?;
+    if mempool::find_mempool_entry(rpc, &txid)?.is_none() {
+        return Err(CapstoneError::Usage(format!(
+            "{} is not in the mempool; tx-family only looks at unconfirmed transactions",
+            txid
+        )));
+    }
+    let family = tx_family(rpc, &txid)?;
+    if config.dot {
+        say!("{}", family.render_dot().trim_end());
+    } else {
+        for line in family.render_tree() {
+            say!("{}", line);
+        }
+    }
+    Ok(())
+}
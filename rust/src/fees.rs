@@ -0,0 +1,356 @@
+use crate::btc;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use bitcoincore_rpc::bitcoin::consensus::encode;
+use bitcoincore_rpc::bitcoin::hex::FromHex;
+use bitcoincore_rpc::bitcoin::{Amount, Transaction, Txid};
+use bitcoincore_rpc::json::EstimateMode;
+use bitcoincore_rpc::{Client, RpcApi};
+
+// Core's wallet uses `-txconfirmtarget=6` when no confirmation target is given.
+pub const DEFAULT_CONF_TARGET: u16 = 6;
+
+// Result of estimatesmartfee for a given confirmation target.
+// `fee_rate` is None when the node has no estimate, which is the normal case
+// on regtest where there is no fee history to learn from.
+#[derive(Debug, Clone)]
+pub struct SmartFeeEstimate {
+    pub conf_target: u16,
+    pub fee_rate: Option<Amount>, // BTC/kvB
+}
+
+impl SmartFeeEstimate {
+    pub fn describe(&self) -> String {
+        match self.fee_rate {
+            Some(rate) => format!("{} BTC/kvB", btc::format_btc(rate)),
+            None => "no estimate available".to_string(),
+        }
+    }
+}
+
+// Ask the node for a fee estimate. Both an RPC error and an empty result mean
+// "no estimate", neither should abort the run.
+pub fn smart_fee_estimate(
+    rpc: &Client,
+    conf_target: u16,
+    estimate_mode: Option<EstimateMode>,
+) -> SmartFeeEstimate {
+    let fee_rate = match rpc.estimate_smart_fee(conf_target, estimate_mode) {
+        Ok(result) => {
+            if let Some(errors) = &result.errors {
+                say!("estimatesmartfee: {}", errors.join("; "));
+            }
+            result.fee_rate
+        }
+        Err(e) => {
+            say!("estimatesmartfee failed: {}", e);
+            None
+        }
+    };
+
+    SmartFeeEstimate {
+        conf_target,
+        fee_rate,
+    }
+}
+
+// Size of our usual payment (one P2WPKH input, Trader output and change), used to
+// turn the absolute --max-fee cap into a feerate cap.
+pub const TYPICAL_PAYMENT_VSIZE: u64 = 141;
+
+// Where an automatically chosen feerate came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeSource {
+    SmartFee,
+    Fallback,
+    MempoolMinFee,
+    // Lowered so that a typical payment stays under --max-fee
+    MaxFeeCap,
+    // Given on the command line with --fee-rate
+    Requested,
+}
+
+impl FeeSource {
+    pub fn describe(self) -> &'static str {
+        match self {
+            FeeSource::SmartFee => "estimatesmartfee",
+            FeeSource::Fallback => "configured fallback",
+            FeeSource::MempoolMinFee => "mempoolminfee",
+            FeeSource::MaxFeeCap => "clamped to --max-fee",
+            FeeSource::Requested => "--fee-rate",
+        }
+    }
+}
+
+// A feerate picked by `fee_estimate`, kept in sat/kvB so it stays an integer.
+#[derive(Debug, Clone, Copy)]
+pub struct FeeRateChoice {
+    pub sat_per_kvb: u64,
+    pub source: FeeSource,
+}
+
+// Format sat/kvB as sat/vB with up to three decimals, no floats involved.
+pub fn format_sat_per_vb(sat_per_kvb: u64) -> String {
+    let whole = sat_per_kvb / 1000;
+    let frac = sat_per_kvb % 1000;
+    if frac == 0 {
+        whole.to_string()
+    } else {
+        format!("{}.{:03}", whole, frac)
+            .trim_end_matches('0')
+            .to_string()
+    }
+}
+
+impl FeeRateChoice {
+    // The value for the `fee_rate` (sat/vB) option of `send`
+    pub fn sat_per_vb(&self) -> String {
+        format_sat_per_vb(self.sat_per_kvb)
+    }
+
+    // The same rate as BTC/kvB, which is what fundrawtransaction's feeRate takes
+    pub fn per_kvb(&self) -> Amount {
+        Amount::from_sat(self.sat_per_kvb)
+    }
+
+    pub fn describe(&self) -> String {
+        format!("{} sat/vB ({})", self.sat_per_vb(), self.source.describe())
+    }
+}
+
+// Choose a feerate for sending. estimatesmartfee wins when it has data; otherwise
+// (the usual case on regtest) use the configured fallback, but never less than the
+// node's mempoolminfee since anything below that would not be accepted. The result
+// is finally capped so a typical payment can't exceed --max-fee.
+pub fn fee_estimate(
+    rpc: &Client,
+    estimate: &SmartFeeEstimate,
+    fallback_sat_per_kvb: u64,
+    max_fee: Amount,
+) -> Result<FeeRateChoice> {
+    let mempool_min_fee = rpc.get_mempool_info()?.mempool_min_fee.to_sat();
+
+    let choice = choose_fee_rate(
+        estimate.fee_rate.map(|rate| rate.to_sat()),
+        fallback_sat_per_kvb,
+        mempool_min_fee,
+    );
+    Ok(clamp_to_max_fee(choice, max_fee, TYPICAL_PAYMENT_VSIZE))
+}
+
+// The fallback ordering of `fee_estimate`, all rates in sat/kvB.
+pub fn choose_fee_rate(
+    smart_fee: Option<u64>,
+    fallback: u64,
+    mempool_min_fee: u64,
+) -> FeeRateChoice {
+    match smart_fee {
+        Some(rate) => FeeRateChoice {
+            sat_per_kvb: rate,
+            source: FeeSource::SmartFee,
+        },
+        None if fallback >= mempool_min_fee => FeeRateChoice {
+            sat_per_kvb: fallback,
+            source: FeeSource::Fallback,
+        },
+        None => FeeRateChoice {
+            sat_per_kvb: mempool_min_fee,
+            source: FeeSource::MempoolMinFee,
+        },
+    }
+}
+
+// Lower the rate if a transaction of `vsize` would pay more than `max_fee` at it.
+pub fn clamp_to_max_fee(choice: FeeRateChoice, max_fee: Amount, vsize: u64) -> FeeRateChoice {
+    let max_rate = max_fee.to_sat() * 1000 / vsize.max(1);
+    if choice.sat_per_kvb > max_rate {
+        FeeRateChoice {
+            sat_per_kvb: max_rate,
+            source: FeeSource::MaxFeeCap,
+        }
+    } else {
+        choice
+    }
+}
+
+// Pre-broadcast safeguard: abort before signing when the fee is over the cap.
+pub fn check_max_fee(fee: Amount, max_fee: Amount) -> Result<()> {
+    if fee > max_fee {
+        return Err(CapstoneError::FeeTooHigh { fee, max_fee });
+    }
+    Ok(())
+}
+
+// Fee rate of a not yet broadcast transaction in sat/kvB, worked out from its
+// inputs' UTXOs (which must still be unspent) and its vsize.
+pub fn transaction_fee_rate(rpc: &Client, hex: &str) -> Result<(Txid, u64)> {
+    let bytes = Vec::<u8>::from_hex(hex)
+        .map_err(|e| CapstoneError::Usage(format!("invalid transaction hex: {}", e)))?;
+    let tx: Transaction = encode::deserialize(&bytes)?;
+
+    let mut input_amount = Amount::ZERO;
+    for input in &tx.input {
+        let outpoint = input.previous_output;
+        let txout = rpc
+            .get_tx_out(&outpoint.txid, outpoint.vout, Some(true))?
+            .ok_or_else(|| {
+                CapstoneError::Usage(format!("input {} is spent or unknown", outpoint))
+            })?;
+        input_amount += txout.value;
+    }
+    let output_amount: Amount = tx.output.iter().map(|output| output.value).sum();
+    let fee = input_amount
+        .checked_sub(output_amount)
+        .unwrap_or(Amount::ZERO);
+    let vsize = (tx.vsize() as u64).max(1);
+    Ok((tx.txid(), fee.to_sat() * 1000 / vsize))
+}
+
+// The fee the wallet recorded for one of its own transactions.
+pub fn wallet_fee(rpc: &Client, txid: &Txid) -> Result<Amount> {
+    Ok(rpc
+        .get_transaction(txid, None)?
+        .fee
+        .and_then(|fee| fee.abs().to_unsigned().ok())
+        .unwrap_or(Amount::ZERO))
+}
+
+// Post-broadcast safeguard for `send`, where the wallet picks the fee and signs in
+// one go. The transaction is already out, so all we can do is shout about it.
+pub fn warn_if_fee_exceeds(rpc: &Client, txid: &Txid, max_fee: Amount) -> Result<()> {
+    for line in fee_overage_warning(txid, wallet_fee(rpc, txid)?, max_fee) {
+        say!("{}", line);
+    }
+    Ok(())
+}
+
+// The warning `warn_if_fee_exceeds` prints, empty when `fee` is within the cap
+pub fn fee_overage_warning(txid: &Txid, fee: Amount, max_fee: Amount) -> Vec<String> {
+    if fee <= max_fee {
+        return Vec::new();
+    }
+    vec![
+        "!!! WARNING !!!".to_string(),
+        format!(
+            "Transaction {} paid {} in fees, {} over the --max-fee cap of {}.",
+            txid,
+            btc::amount(fee),
+            btc::amount(fee - max_fee),
+            btc::amount(max_fee)
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sat_per_vb_formatting() {
+        let cases = [
+            (0, "0"),
+            (1, "0.001"),
+            (10, "0.01"),
+            (1_000, "1"),
+            (1_500, "1.5"),
+            (12_477, "12.477"),
+            (100_000, "100"),
+        ];
+        for (sat_per_kvb, expected) in cases {
+            assert_eq!(format_sat_per_vb(sat_per_kvb), expected);
+        }
+    }
+
+    #[test]
+    fn fee_rate_fallbacks() {
+        let choice = choose_fee_rate(Some(5_000), 2_000, 1_000);
+        assert_eq!(
+            (choice.sat_per_kvb, choice.source),
+            (5_000, FeeSource::SmartFee)
+        );
+        let choice = choose_fee_rate(None, 2_000, 1_000);
+        assert_eq!(
+            (choice.sat_per_kvb, choice.source),
+            (2_000, FeeSource::Fallback)
+        );
+        let choice = choose_fee_rate(None, 2_000, 2_000);
+        assert_eq!(choice.source, FeeSource::Fallback);
+        let choice = choose_fee_rate(None, 1_000, 3_000);
+        assert_eq!(
+            (choice.sat_per_kvb, choice.source),
+            (3_000, FeeSource::MempoolMinFee)
+        );
+        assert_eq!(choice.sat_per_vb(), "3");
+        assert_eq!(choice.per_kvb(), Amount::from_sat(3_000));
+        assert_eq!(choice.describe(), "3 sat/vB (mempoolminfee)");
+    }
+
+    #[test]
+    fn max_fee_clamp() {
+        let choice = FeeRateChoice {
+            sat_per_kvb: 20_000,
+            source: FeeSource::Requested,
+        };
+        // 20 sat/vB over 141 vB is 2820 sat
+        let kept = clamp_to_max_fee(choice, Amount::from_sat(2_820), TYPICAL_PAYMENT_VSIZE);
+        assert_eq!(
+            (kept.sat_per_kvb, kept.source),
+            (20_000, FeeSource::Requested)
+        );
+        let clamped = clamp_to_max_fee(choice, Amount::from_sat(2_819), TYPICAL_PAYMENT_VSIZE);
+        assert_eq!(
+            (clamped.sat_per_kvb, clamped.source),
+            (19_992, FeeSource::MaxFeeCap)
+        );
+        let clamped = clamp_to_max_fee(choice, Amount::ZERO, TYPICAL_PAYMENT_VSIZE);
+        assert_eq!(clamped.sat_per_kvb, 0);
+        // A zero size counts as one vbyte
+        let clamped = clamp_to_max_fee(choice, Amount::from_sat(10), 0);
+        assert_eq!(clamped.sat_per_kvb, 10_000);
+    }
+
+    #[test]
+    fn max_fee_check() {
+        let cap = Amount::from_sat(1_000);
+        assert!(check_max_fee(cap, cap).is_ok());
+        assert!(matches!(
+            check_max_fee(cap + Amount::from_sat(1), cap),
+            Err(CapstoneError::FeeTooHigh { .. })
+        ));
+    }
+
+    #[test]
+    fn over_the_cap_before_signing_names_the_fee() {
+        let config =
+            crate::cli::parse_args(["--max-fee".to_string(), "0.0001".to_string()]).unwrap();
+        assert_eq!(config.max_fee(), Amount::from_sat(10_000));
+        let error = check_max_fee(Amount::from_sat(14_100), config.max_fee()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "transaction fee of 0.00014100 BTC exceeds the --max-fee cap of 0.00010000 BTC, not signing it"
+        );
+        // 0.01 BTC unless given
+        assert_eq!(
+            crate::cli::Config::default().max_fee(),
+            Amount::from_sat(1_000_000)
+        );
+    }
+
+    #[test]
+    fn over_the_cap_after_broadcast_only_warns() {
+        use bitcoincore_rpc::bitcoin::hashes::Hash;
+        let txid = Txid::all_zeros();
+        let cap = Amount::from_sat(10_000);
+        assert!(fee_overage_warning(&txid, cap, cap).is_empty());
+        assert!(fee_overage_warning(&txid, Amount::from_sat(1_410), cap).is_empty());
+        let warning = fee_overage_warning(&txid, Amount::from_sat(12_500), cap);
+        assert_eq!(warning[0], "!!! WARNING !!!");
+        assert_eq!(
+            warning[1],
+            format!(
+                "Transaction {} paid 0.00012500 BTC in fees, 0.00002500 BTC over the --max-fee cap of 0.00010000 BTC.",
+                txid
+            )
+        );
+    }
+}
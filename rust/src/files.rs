@@ -0,0 +1,249 @@
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// --strict-paths: a missing parent directory is an error instead of being
+// created
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_strict(strict: bool) {
+    STRICT.store(strict, Ordering::Relaxed);
+}
+
+// Wrap an IO error with what was being done to which file, so it reads
+// "failed to create report file /home/me/out.txt: Permission denied"
+pub fn error(
+    operation: impl Into<String>,
+    path: &Path,
+) -> impl FnOnce(std::io::Error) -> CapstoneError {
+    let file = Some((operation.into(), path.to_path_buf()));
+    move |source| CapstoneError::Io { source, file }
+}
+
+// `path` made absolute without requiring it to exist yet: its directory is
+// canonicalized and the file name put back on. As is, if that fails.
+pub fn absolute(path: &Path) -> PathBuf {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    match (fs::canonicalize(directory), path.file_name()) {
+        (Ok(directory), Some(name)) => directory.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+// Get `path` ready to be written as the `what` (e.g. "report file"): create
+// its parent directories unless --strict-paths, and say where it will go.
+pub fn prepare(path: &Path, what: &str) -> Result<PathBuf> {
+    prepare_with(path, what, STRICT.load(Ordering::Relaxed))
+}
+
+fn prepare_with(path: &Path, what: &str, strict: bool) -> Result<PathBuf> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        if !parent.exists() {
+            if strict {
+                return Err(error(format!("write {}", what), path)(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "its directory doesn't exist and --strict-paths is set",
+                )));
+            }
+            fs::create_dir_all(parent)
+                .map_err(error(format!("create the directory of {}", what), parent))?;
+            say!("Created {} for the {}.", absolute(parent).display(), what);
+        }
+    }
+    Ok(absolute(path))
+}
+
+// Replace `path` (the `what`) with `contents`, returning its absolute path:
+// they are written and fsynced to a file next to it, which is then renamed
+// over it, so a reader (or a run that was killed halfway) never leaves half
+// a file behind. The temporary name has the process id in it, so two runs
+// don't write into the same one.
+//
+// Where the rename can't replace the file, as on some network and FUSE
+// filesystems, it is written in place instead, with a warning that this
+// write isn't atomic.
+pub fn write_atomically(
+    path: impl AsRef<Path>,
+    contents: impl AsRef<[u8]>,
+    what: &str,
+) -> Result<PathBuf> {
+    let path = path.as_ref();
+    let contents = contents.as_ref();
+    let absolute = prepare(path, what)?;
+    let partial = partial_path(path);
+    if let Err(source) = write_synced(&partial, contents) {
+        let _ = fs::remove_file(&partial);
+        return Err(error(format!("create {}", what), &absolute)(source));
+    }
+    if let Err(e) = fs::rename(&partial, path) {
+        let _ = fs::remove_file(&partial);
+        say!(
+            "Warning: couldn't rename over {} ({}), writing it in place instead.",
+            absolute.display(),
+            e
+        );
+        write_synced(path, contents).map_err(error(format!("write {}", what), &absolute))?;
+        return Ok(absolute);
+    }
+    sync_directory(path);
+    Ok(absolute)
+}
+
+// Open `path` (the `what`) for appending, creating it if it isn't there. It can
+// be read as well, e.g. to see how the last line ended.
+pub fn open_append(path: impl AsRef<Path>, what: &str) -> Result<File> {
+    let path = path.as_ref();
+    let absolute = prepare(path, what)?;
+    OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)
+        .map_err(error(format!("open {}", what), &absolute))
+}
+
+pub fn read_to_string(path: impl AsRef<Path>, what: &str) -> Result<String> {
+    let path = path.as_ref();
+    fs::read_to_string(path).map_err(error(format!("read {}", what), &absolute(path)))
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(format!(".{}.partial", std::process::id()));
+    PathBuf::from(partial)
+}
+
+fn write_synced(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+// Make the rename itself survive a power cut. Best effort: not every
+// platform lets a directory be opened and synced.
+fn sync_directory(path: &Path) {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if let Ok(directory) = File::open(directory) {
+        let _ = directory.sync_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creates_a_missing_parent_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("reports/today/out.json");
+        let absolute = prepare_with(&target, "JSON report", false).unwrap();
+        assert!(dir.path().join("reports/today").is_dir());
+        assert!(absolute.is_absolute());
+        assert!(absolute.ends_with("reports/today/out.json"));
+    }
+
+    #[test]
+    fn strict_paths_refuse_a_missing_parent_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("missing/out.json");
+        let error = prepare_with(&target, "JSON report", true).unwrap_err();
+        assert!(!dir.path().join("missing").exists());
+        let message = error.to_string();
+        assert!(
+            message.starts_with("failed to write JSON report "),
+            "{}",
+            message
+        );
+        assert!(message.contains("--strict-paths"), "{}", message);
+    }
+
+    #[test]
+    fn io_errors_name_the_operation_and_path() {
+        let path = Path::new("/var/reports/out.txt");
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let error = error("create report file", path)(denied);
+        let message = error.to_string();
+        assert!(
+            message.starts_with("failed to create report file /var/reports/out.txt: "),
+            "{}",
+            message
+        );
+        assert!(
+            matches!(error, CapstoneError::Io { file: Some((_, ref file)), .. } if file == path)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn permission_denied_names_the_file() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let locked = dir.path().join("locked");
+        fs::create_dir(&locked).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o555)).unwrap();
+        let target = locked.join("out.txt");
+        let result = write_atomically(&target, "report\n", "report file");
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        // Root writes anyway, there is no refusal to check then
+        if target.exists() {
+            return;
+        }
+        let message = result.unwrap_err().to_string();
+        let expected = format!(
+            "failed to create report file {}: ",
+            absolute(&target).display()
+        );
+        assert!(message.starts_with(&expected), "{}", message);
+    }
+
+    #[test]
+    fn replaces_the_whole_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out.txt");
+        fs::write(&target, "old report\n").unwrap();
+        let written = write_atomically(&target, "new report\n", "report file").unwrap();
+        assert_eq!(written, fs::canonicalize(&target).unwrap());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new report\n");
+        assert!(!partial_path(&target).exists());
+    }
+
+    #[test]
+    fn a_leftover_partial_file_never_replaces_the_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out.txt");
+        fs::write(&target, "old report\n").unwrap();
+        // What a run killed between writing and renaming leaves behind
+        let stray = dir.path().join("out.txt.4242.partial");
+        fs::write(&stray, "half a rep").unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "old report\n");
+
+        write_atomically(&target, "new report\n", "report file").unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new report\n");
+        assert_eq!(fs::read_to_string(&stray).unwrap(), "half a rep");
+    }
+
+    #[test]
+    fn a_failed_write_leaves_the_target_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out.txt");
+        fs::write(&target, "old report\n").unwrap();
+        // A directory where the temporary file should go makes write_synced fail
+        fs::create_dir(partial_path(&target)).unwrap();
+
+        let error = write_atomically(&target, "new report\n", "report file").unwrap_err();
+        assert!(matches!(error, CapstoneError::Io { file: Some(_), .. }));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "old report\n");
+    }
+}
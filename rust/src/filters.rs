@@ -0,0 +1,372 @@
+use crate::block;
+use crate::btc;
+use crate::cli::Config;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::session::Session;
+use crate::version::{self, Capability};
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::bip158::BlockFilter;
+use bitcoincore_rpc::bitcoin::hex::{DisplayHex, FromHex};
+use bitcoincore_rpc::bitcoin::{Address, Amount, BlockHash, OutPoint, Script, Txid};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashSet;
+
+#[derive(Debug, Deserialize)]
+struct GetBlockFilterResult {
+    filter: String,
+}
+
+// Fail unless the node keeps BIP158 basic filters for the whole chain.
+pub fn require_index(rpc: &Client) -> Result<()> {
+    // Older nodes have no getindexinfo; getblockfilter will tell
+    if !version::supports(rpc, Capability::IndexInfo)? {
+        return Ok(());
+    }
+    match rpc.get_index_info()?.basic_block_filter_index {
+        Some(status) if status.synced => Ok(()),
+        Some(status) => Err(CapstoneError::Usage(format!(
+            "the block filter index is still being built, it has reached height {}; wait for getindexinfo to report it synced",
+            status.best_block_height
+        ))),
+        None => Err(CapstoneError::Usage(
+            "the node has no block filter index; restart bitcoind with -blockfilterindex=1 to look blocks up by filter".into(),
+        )),
+    }
+}
+
+// getblockfilter's basic (BIP158) filter for `block_hash`
+pub fn block_filter(rpc: &Client, block_hash: &BlockHash) -> Result<BlockFilter> {
+    let result: GetBlockFilterResult =
+        rpc.call("getblockfilter", &[json!(block_hash), json!("basic")])?;
+    let content = Vec::<u8>::from_hex(&result.filter)
+        .map_err(|e| CapstoneError::Usage(format!("getblockfilter returned bad hex: {}", e)))?;
+    Ok(BlockFilter::new(&content))
+}
+
+// Whether `filter` may contain `script`. A GCS filter has no false negatives,
+// but about one false positive in 784931 lookups (BIP158's M), so a hit still
+// has to be confirmed against the block.
+pub fn may_contain(filter: &BlockFilter, block_hash: &BlockHash, script: &Script) -> Result<bool> {
+    filter
+        .match_any(block_hash, std::iter::once(script.as_bytes()))
+        .map_err(|e| CapstoneError::Usage(format!("can't read the block filter: {}", e)))
+}
+
+// A block whose filter matched, and what the full block showed.
+#[derive(Debug, Clone)]
+pub struct FilterHit {
+    pub height: u64,
+    pub hash: BlockHash,
+    // Outputs paying the script
+    pub funding: Vec<(OutPoint, Amount)>,
+    // Transactions spending one of its outputs, and which
+    pub spending: Vec<(Txid, OutPoint)>,
+}
+
+impl FilterHit {
+    pub fn is_false_positive(&self) -> bool {
+        self.funding.is_empty() && self.spending.is_empty()
+    }
+}
+
+// Light-client style: check every filter in `start..=end` against `script`
+// and fetch only the blocks that match.
+#[derive(Debug, Clone)]
+pub struct FilterScan {
+    pub filters_checked: u64,
+    pub hits: Vec<FilterHit>,
+}
+
+impl FilterScan {
+    pub fn false_positives(&self) -> usize {
+        self.hits
+            .iter()
+            .filter(|hit| hit.is_false_positive())
+            .count()
+    }
+
+    pub fn funded_by(&self, txid: &Txid) -> bool {
+        self.hits.iter().any(|hit| {
+            hit.funding
+                .iter()
+                .any(|(outpoint, _)| outpoint.txid == *txid)
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{} filter(s) checked, {} block(s) fetched, {} false positive(s)",
+            self.filters_checked,
+            self.hits.len(),
+            self.false_positives()
+        )
+    }
+}
+
+// Check the filters of blocks `start..=end` for `script`, then fetch the
+// matching blocks `parallelism` at a time to find its outputs and spends.
+pub fn scan(
+    rpc: &Client,
+    script: &Script,
+    start: u64,
+    end: u64,
+    parallelism: usize,
+) -> Result<FilterScan> {
+    require_index(rpc)?;
+    let script_hex = script.as_bytes().to_lower_hex_string();
+    let mut scan = FilterScan {
+        filters_checked: 0,
+        hits: Vec::new(),
+    };
+    // Outputs found so far, for spends in blocks without prevouts (before Core 23)
+    let mut funded = HashSet::new();
+    let mut matches = Vec::new();
+    for (height, hash) in block::block_hashes(rpc, start, end)? {
+        scan.filters_checked += 1;
+        if may_contain(&block_filter(rpc, &hash)?, &hash, script)? {
+            matches.push((height, hash));
+        }
+    }
+    block::for_each_listed_block(rpc, &matches, parallelism, |full| {
+        let mut hit = FilterHit {
+            height: full.height,
+            hash: full.hash,
+            funding: Vec::new(),
+            spending: Vec::new(),
+        };
+        for tx in &full.tx {
+            for input in &tx.vin {
+                let (Some(txid), Some(vout)) = (input.txid, input.vout) else {
+                    continue;
+                };
+                let outpoint = OutPoint::new(txid, vout);
+                let pays_script = input
+                    .prevout
+                    .as_ref()
+                    .is_some_and(|prevout| prevout.script_pub_key.hex == script_hex);
+                if pays_script || funded.contains(&outpoint) {
+                    hit.spending.push((tx.txid, outpoint));
+                }
+            }
+            for output in &tx.vout {
+                if output.script_pub_key.hex == script_hex {
+                    let outpoint = OutPoint::new(tx.txid, output.n);
+                    funded.insert(outpoint);
+                    hit.funding.push((outpoint, output.value));
+                }
+            }
+        }
+        scan.hits.push(hit);
+        Ok(())
+    })?;
+    Ok(scan)
+}
+
+// filter-check: find where an address was paid and spent from --from on,
+// reading block filters and only the blocks they point at.
+pub fn run(session: &Session, config: &Config, address: &str, from: u64) -> Result<()> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        CapstoneError::Usage(format!("invalid address '{}': {}", address, e))
+    };
+    let address = address
+        .parse::<Address<NetworkUnchecked>>()
+        .map_err(|e| invalid(&e))?
+        .require_network(session.network)
+        .map_err(|e| invalid(&e))?;
+    let rpc = &session.rpc;
+    let end = config.until.unwrap_or(rpc.get_block_count()?);
+    let scan = scan(
+        rpc,
+        &address.script_pubkey(),
+        from,
+        end,
+        config.parallelism(),
+    )?;
+    for hit in &scan.hits {
+        if hit.is_false_positive() {
+            say!(
+                "Block {} ({}): filter match, nothing for {} (false positive)",
+                hit.height,
+                hit.hash,
+                address
+            );
+            continue;
+        }
+        for (outpoint, amount) in &hit.funding {
+            say!(
+                "Block {} ({}): {} receives {}",
+                hit.height,
+                hit.hash,
+                outpoint,
+                btc::amount(*amount)
+            );
+        }
+        for (txid, outpoint) in &hit.spending {
+            say!(
+                "Block {} ({}): {} spends {}",
+                hit.height,
+                hit.hash,
+                txid,
+                outpoint
+            );
+        }
+    }
+    say!("Heights {} to {}: {}.", from, end, scan.describe());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::consensus::encode;
+    use bitcoincore_rpc::bitcoin::hash_types::FilterHeader;
+    use bitcoincore_rpc::bitcoin::{Block, ScriptBuf};
+
+    // BIP158's test vectors (Bitcoin Core's src/test/data/blockfilters.json):
+    // testnet blocks with the scripts their inputs spend and their filters
+    struct Vector {
+        block: Block,
+        block_hash: BlockHash,
+        prev_scripts: Vec<ScriptBuf>,
+        previous_header: FilterHeader,
+        filter: BlockFilter,
+        header: FilterHeader,
+        notes: String,
+    }
+
+    fn vectors() -> Vec<Vector> {
+        let data: Vec<serde_json::Value> =
+            serde_json::from_str(include_str!("../tests/fixtures/blockfilters.json")).unwrap();
+        let text = |value: &serde_json::Value| value.as_str().unwrap().to_string();
+        let bytes =
+            |value: &serde_json::Value| Vec::<u8>::from_hex(value.as_str().unwrap()).unwrap();
+        // The first row names the columns
+        data[1..]
+            .iter()
+            .map(|row| Vector {
+                block: encode::deserialize(&bytes(&row[2])).unwrap(),
+                block_hash: text(&row[1]).parse().unwrap(),
+                prev_scripts: row[3]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|script| ScriptBuf::from_bytes(bytes(script)))
+                    .collect(),
+                previous_header: text(&row[4]).parse().unwrap(),
+                filter: BlockFilter::new(&bytes(&row[5])),
+                header: text(&row[6]).parse().unwrap(),
+                notes: row.get(7).map(text).unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    // What BIP158 puts in a basic filter: every output script but OP_RETURNs,
+    // and every script an input spends, leaving out empty scripts
+    fn filter_scripts(vector: &Vector) -> Vec<ScriptBuf> {
+        let outputs = vector
+            .block
+            .txdata
+            .iter()
+            .flat_map(|tx| &tx.output)
+            .map(|output| output.script_pubkey.clone())
+            .filter(|script| !script.is_op_return());
+        outputs
+            .chain(vector.prev_scripts.iter().cloned())
+            .filter(|script| !script.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn vectors_are_for_their_blocks() {
+        let vectors = vectors();
+        assert_eq!(vectors.len(), 10);
+        for vector in &vectors {
+            assert_eq!(
+                vector.block.block_hash(),
+                vector.block_hash,
+                "{}",
+                vector.notes
+            );
+            assert_eq!(
+                vector.filter.filter_header(&vector.previous_header),
+                vector.header,
+                "{}",
+                vector.notes
+            );
+        }
+    }
+
+    #[test]
+    fn every_script_of_a_block_matches_its_filter() {
+        for vector in vectors() {
+            for script in filter_scripts(&vector) {
+                assert!(
+                    may_contain(&vector.filter, &vector.block_hash, &script).unwrap(),
+                    "{} in block {} ({})",
+                    script,
+                    vector.block_hash,
+                    vector.notes
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn other_scripts_do_not_match() {
+        let vectors = vectors();
+        for (i, vector) in vectors.iter().enumerate() {
+            // Scripts of the other blocks, which aren't in this one
+            let own = filter_scripts(vector);
+            for other in vectors.iter().skip(i + 1).flat_map(filter_scripts) {
+                if own.contains(&other) {
+                    continue;
+                }
+                assert!(
+                    !may_contain(&vector.filter, &vector.block_hash, &other).unwrap(),
+                    "{} against block {}",
+                    other,
+                    vector.block_hash
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn a_filter_is_keyed_by_its_block_hash() {
+        let vectors = vectors();
+        let vector = vectors
+            .iter()
+            .find(|v| v.notes == "Includes witness data")
+            .unwrap();
+        let script = &vector.prev_scripts[0];
+        assert!(may_contain(&vector.filter, &vector.block_hash, script).unwrap());
+        assert!(!may_contain(&vector.filter, &vectors[0].block_hash, script).unwrap());
+    }
+
+    #[test]
+    fn scan_counts_false_positives() {
+        let txid: Txid = vectors()[1].block.txdata[0].txid();
+        let hit = |funding: Vec<(OutPoint, Amount)>| FilterHit {
+            height: 2,
+            hash: vectors()[1].block_hash,
+            funding,
+            spending: Vec::new(),
+        };
+        let scan = FilterScan {
+            filters_checked: 5,
+            hits: vec![
+                hit(Vec::new()),
+                hit(vec![(OutPoint::new(txid, 0), Amount::ONE_BTC)]),
+            ],
+        };
+        assert_eq!(scan.false_positives(), 1);
+        assert!(scan.funded_by(&txid));
+        assert_eq!(
+            scan.describe(),
+            "5 filter(s) checked, 2 block(s) fetched, 1 false positive(s)"
+        );
+    }
+}
@@ -0,0 +1,305 @@
+use crate::accept;
+use crate::cli::{Config, SighashType};
+use crate::console::say;
+use crate::decode;
+use crate::error::{CapstoneError, Result};
+use crate::files;
+use crate::mine;
+use crate::psbt;
+use crate::raw;
+use crate::report::{self, TransactionReport};
+use crate::runinfo::RunInfo;
+use crate::session::Session;
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::{Address, Network};
+use bitcoincore_rpc::{Client, RpcApi};
+use std::path::Path;
+
+// The way back from --export-psbt: take a PSBT signed elsewhere, finalize and
+// broadcast it, confirm it and write out.txt as for a normal payment.
+pub fn run(session: &Session, config: &Config, path: &str) -> Result<()> {
+    let psbt = psbt::read_psbt_file(path)?;
+    finalize_and_report(session, config, &psbt, path)
+}
+
+// combine-psbt: merge independently signed copies of one PSBT, then finalize
+// the result as above.
+pub fn run_combine(session: &Session, config: &Config, paths: &[String]) -> Result<()> {
+    let mut psbts = Vec::with_capacity(paths.len());
+    for path in paths {
+        psbts.push((path.clone(), psbt::read_psbt_file(path)?));
+    }
+    let combined = psbt::combine_psbts(&session.rpc, &psbts)?;
+    say!("Combined {} PSBTs.", psbts.len());
+    finalize_and_report(session, config, &combined, "combined PSBT")
+}
+
+// to-psbt: push a raw unsigned transaction from other tooling through the PSBT
+// path, with the Miner wallet supplying UTXO data and signatures.
+pub fn run_to_psbt(session: &Session, config: &Config, source: &str) -> Result<()> {
+    let hex = if Path::new(source).is_file() {
+        files::read_to_string(source, "transaction file")?
+            .trim()
+            .to_string()
+    } else {
+        source.trim().to_string()
+    };
+    let psbt = signed_psbt(
+        &session.rpc,
+        &session.miner_rpc,
+        &hex,
+        config.force,
+        config.sighash(),
+    )?;
+    finalize_and_report(session, config, &psbt, source)
+}
+
+// The PSBT for raw transaction `hex`, with UTXO data and the signatures of the
+// wallet behind `wallet_rpc`. `force` drops signatures the transaction has.
+fn signed_psbt(
+    rpc: &Client,
+    wallet_rpc: &Client,
+    hex: &str,
+    force: bool,
+    sighash: SighashType,
+) -> Result<String> {
+    // converttopsbt refuses signed inputs unless told to throw the signatures away
+    let decoded = decode::decode_hex(rpc, hex)?;
+    let signed = decoded.vin.iter().any(|vin| {
+        !vin.witness.is_empty() || vin.script_sig.as_ref().is_some_and(|s| !s.hex.is_empty())
+    });
+    if signed && !force {
+        return Err(CapstoneError::Usage(format!(
+            "transaction {} already has signatures, pass --force to drop them and sign again",
+            decoded.txid
+        )));
+    }
+
+    let psbt = psbt::convert_to_psbt(rpc, hex, force)?;
+    let psbt = psbt::utxo_update(rpc, wallet_rpc, &psbt)?;
+    Ok(psbt::process_psbt(wallet_rpc, &psbt, sighash)?.psbt)
+}
+
+// `source` only names the PSBT in error messages.
+pub fn finalize_and_report(
+    session: &Session,
+    config: &Config,
+    psbt: &str,
+    source: &str,
+) -> Result<()> {
+    let rpc = &session.rpc;
+    psbt::analyze_psbt(rpc, psbt)?.ensure_finalizable()?;
+
+    // Make sure we are paying the Trader and not whoever edited the file
+    let decoded = psbt::decode_psbt(rpc, psbt)?;
+    let mut paid = Vec::new();
+    for output in &decoded.tx.vout {
+        if let Some(address) = &output.script_pub_key.address {
+            let address = address
+                .parse::<Address<NetworkUnchecked>>()
+                .map_err(|e| CapstoneError::PsbtFile {
+                    path: source.to_string(),
+                    reason: format!("output address {}: {}", address, e),
+                })?
+                .assume_checked();
+            paid.push(address);
+        }
+    }
+    let mut trader_address = None;
+    for address in &paid {
+        if session.trader_rpc.get_address_info(address)?.is_mine == Some(true) {
+            trader_address = Some(address.clone());
+            break;
+        }
+    }
+    let trader_address = match (trader_address, paid.first()) {
+        (Some(address), _) => address,
+        (None, Some(first)) if config.allow_any_outputs => {
+            say!("Warning: PSBT does not pay the Trader wallet, broadcasting it anyway.");
+            first.clone()
+        }
+        _ => {
+            return Err(CapstoneError::UnexpectedPsbtOutputs(
+                paid.iter().map(|a| a.to_string()).collect(),
+            ))
+        }
+    };
+
+    let hex = psbt::finalize_to_hex(rpc, psbt)?;
+
+    accept::ensure_accepted(
+        rpc,
+        std::slice::from_ref(&hex),
+        config.max_broadcast_feerate(),
+    )?;
+    let txid = raw::broadcast(rpc, &hex, config)?;
+    say!("Transaction sent! TXID: {}", txid);
+
+    if session.network != Network::Regtest {
+        say!("Not on regtest, leaving confirmation to the network.");
+        return Ok(());
+    }
+
+    // Mine 1 block to confirm the transaction
+    let block_hash = mine::confirm(session, &[txid], config)?;
+    say!("Block {} mined, confirming the transaction.", block_hash);
+
+    let mut report = TransactionReport::extract(rpc, &txid, &block_hash, &trader_address)?;
+    say!("Successfully fetched confirmed transaction details.");
+    report.verify_utxos(rpc)?;
+    report.run = Some(RunInfo::collect(rpc)?);
+    report::write_transaction_reports(std::slice::from_ref(&report), config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mockrpc;
+    use bitcoincore_rpc::bitcoin::consensus::encode;
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    fn captured() -> Value {
+        serde_json::from_str(include_str!("../tests/fixtures/rawtransaction-segwit.json")).unwrap()
+    }
+
+    // The fixture's spend as createrawtransaction gives it, before signing
+    fn unsigned() -> (String, Value) {
+        let mut tx = crate::signing::decode_tx(captured()["hex"].as_str().unwrap()).unwrap();
+        tx.input[0].witness.clear();
+        let mut decoded = captured();
+        for key in [
+            "in_active_chain",
+            "hex",
+            "blockhash",
+            "confirmations",
+            "time",
+            "blocktime",
+        ] {
+            decoded.as_object_mut().unwrap().remove(key);
+        }
+        decoded["vin"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("txinwitness");
+        decoded["hash"] = decoded["txid"].clone();
+        decoded["size"] = json!(tx.total_size());
+        decoded["vsize"] = json!(tx.vsize());
+        decoded["weight"] = json!(tx.weight().to_wu());
+        (encode::serialize_hex(&tx), decoded)
+    }
+
+    // What converttopsbt makes of `hex`: the transaction without its signatures
+    fn converted(hex: &str) -> String {
+        let mut tx: bitcoin::Transaction =
+            bitcoin::consensus::encode::deserialize_hex(hex).unwrap();
+        for input in &mut tx.input {
+            input.witness.clear();
+            input.script_sig = bitcoin::ScriptBuf::new();
+        }
+        bitcoin::Psbt::from_unsigned_tx(tx).unwrap().to_string()
+    }
+
+    // A node and Miner wallet that turn raw transaction `hex` into a PSBT and
+    // sign it, checking each step is handed the previous one's result. The
+    // log has each call prefixed with who answered it.
+    fn node(hex: &str, decoded: Value) -> (Client, Client, Arc<Mutex<Vec<String>>>) {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let psbt = converted(hex);
+        let (expected_hex, converted_psbt) = (hex.to_string(), psbt.clone());
+        // Only a signed transaction needs converttopsbt's permitsigdata
+        let signed = decoded["vin"][0].get("txinwitness").is_some();
+        let node_log = log.clone();
+        let (rpc, _) = mockrpc::client(move |method, params| {
+            node_log.lock().unwrap().push(format!("node {}", method));
+            match method {
+                "decoderawtransaction" => {
+                    assert_eq!(params[0], json!(expected_hex));
+                    Ok(decoded.clone())
+                }
+                "converttopsbt" => {
+                    assert_eq!(params[0], json!(expected_hex));
+                    assert_eq!(params[1], json!(signed));
+                    Ok(json!(converted_psbt))
+                }
+                "utxoupdatepsbt" => {
+                    assert_eq!(params[0], json!(converted_psbt));
+                    assert_eq!(params[1], json!(["wpkh([d34db33f/84h/1h/0h]tpub/0/*)"]));
+                    Ok(json!(format!("{}updated", converted_psbt)))
+                }
+                other => panic!("unexpected {}", other),
+            }
+        });
+        let updated = format!("{}updated", psbt);
+        let wallet_log = log.clone();
+        let (miner_rpc, _) = mockrpc::client(move |method, params| {
+            wallet_log
+                .lock()
+                .unwrap()
+                .push(format!("wallet {}", method));
+            match method {
+                "listdescriptors" => Ok(json!({
+                    "wallet_name": "Miner",
+                    "descriptors": [{ "desc": "wpkh([d34db33f/84h/1h/0h]tpub/0/*)" }],
+                })),
+                "decodepsbt" => {
+                    let mut tx = captured();
+                    tx["vin"][0].as_object_mut().unwrap().remove("txinwitness");
+                    Ok(json!({ "tx": tx, "inputs": [{}], "outputs": [{}, {}] }))
+                }
+                "walletprocesspsbt" => {
+                    assert_eq!(params[0], json!(updated));
+                    assert_eq!(params[2], json!("DEFAULT"));
+                    Ok(json!({ "psbt": "signed", "complete": true }))
+                }
+                other => panic!("unexpected {}", other),
+            }
+        });
+        (rpc, miner_rpc, log)
+    }
+
+    #[test]
+    fn createrawtransaction_output_goes_through_converttopsbt() {
+        let (hex, decoded) = unsigned();
+        let (rpc, miner_rpc, log) = node(&hex, decoded);
+        let psbt = signed_psbt(&rpc, &miner_rpc, &hex, false, SighashType::Default).unwrap();
+        assert_eq!(psbt, "signed");
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "node decoderawtransaction",
+                "node converttopsbt",
+                "wallet listdescriptors",
+                "node utxoupdatepsbt",
+                "wallet decodepsbt",
+                "wallet walletprocesspsbt",
+            ]
+        );
+    }
+
+    #[test]
+    fn signed_transactions_need_force() {
+        let hex = captured()["hex"].as_str().unwrap().to_string();
+        let (rpc, miner_rpc, log) = node(&hex, captured());
+        let err = signed_psbt(&rpc, &miner_rpc, &hex, false, SighashType::Default).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "transaction 29c93e605528c497d1e3b3d04d1fe21c162e1d58ef92c924b12ce7f43627bac8 already has signatures, pass --force to drop them and sign again"
+        );
+        assert_eq!(*log.lock().unwrap(), ["node decoderawtransaction"]);
+    }
+
+    #[test]
+    fn force_drops_the_signatures() {
+        let hex = captured()["hex"].as_str().unwrap().to_string();
+        let (rpc, miner_rpc, log) = node(&hex, captured());
+        let psbt = signed_psbt(&rpc, &miner_rpc, &hex, true, SighashType::Default).unwrap();
+        assert_eq!(psbt, "signed");
+        assert!(log
+            .lock()
+            .unwrap()
+            .contains(&"wallet walletprocesspsbt".to_string()));
+        // The PSBT is of the unsigned transaction, as for the unsigned hex
+        assert_eq!(converted(&hex), converted(&unsigned().0));
+    }
+}
@@ -0,0 +1,230 @@
+use crate::cli::ChangeType;
+use crate::error::Result;
+use crate::fees::FeeRateChoice;
+use bitcoincore_rpc::bitcoin::{Address, Amount, OutPoint, Weight};
+use bitcoincore_rpc::json::EstimateMode;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
+
+// fundrawtransaction's options object. Only fields that were set are sent, so
+// the node's defaults apply to everything else. The rpc crate's version lacks
+// input_weights and lock_unspents, hence our own.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FundOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    add_inputs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    include_watching: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lock_unspents: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    change_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    change_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    change_position: Option<u32>,
+    // sat/vB, as a decimal string
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fee_rate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conf_target: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    estimate_mode: Option<EstimateMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replaceable: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    subtract_fee_from_outputs: Vec<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    input_weights: Vec<InputWeight>,
+}
+
+// Weight of an input the wallet can't work out itself (a foreign or unusual script)
+#[derive(Debug, Clone, Serialize)]
+pub struct InputWeight {
+    txid: String,
+    vout: u32,
+    weight: u64,
+}
+
+impl FundOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_inputs(mut self, add_inputs: Option<bool>) -> Self {
+        self.add_inputs = add_inputs;
+        self
+    }
+
+    pub fn include_watching(mut self, include_watching: bool) -> Self {
+        self.include_watching = Some(include_watching);
+        self
+    }
+
+    pub fn lock_unspents(mut self, lock_unspents: bool) -> Self {
+        self.lock_unspents = Some(lock_unspents);
+        self
+    }
+
+    pub fn change_address(mut self, address: Option<&Address>) -> Self {
+        self.change_address = address.map(|a| a.to_string());
+        self
+    }
+
+    pub fn change_type(mut self, change_type: Option<ChangeType>) -> Self {
+        self.change_type = change_type.map(|t| t.as_rpc_str());
+        self
+    }
+
+    pub fn change_position(mut self, position: Option<u32>) -> Self {
+        self.change_position = position;
+        self
+    }
+
+    // An explicit feerate replaces the node's estimate, so it clears any
+    // confirmation target: the two can't be combined
+    pub fn fee_rate(mut self, fee_rate: &FeeRateChoice) -> Self {
+        self.fee_rate = Some(fee_rate.sat_per_vb());
+        self.conf_target = None;
+        self.estimate_mode = None;
+        self
+    }
+
+    pub fn estimate(mut self, conf_target: Option<u16>, mode: Option<EstimateMode>) -> Self {
+        if self.fee_rate.is_none() {
+            self.conf_target = conf_target;
+            self.estimate_mode = mode;
+        }
+        self
+    }
+
+    pub fn replaceable(mut self, replaceable: bool) -> Self {
+        self.replaceable = replaceable.then_some(true);
+        self
+    }
+
+    pub fn subtract_fee_from_outputs(mut self, outputs: Vec<usize>) -> Self {
+        self.subtract_fee_from_outputs = outputs;
+        self
+    }
+
+    pub fn input_weight(mut self, outpoint: OutPoint, weight: Weight) -> Self {
+        self.input_weights.push(InputWeight {
+            txid: outpoint.txid.to_string(),
+            vout: outpoint.vout,
+            weight: weight.to_wu(),
+        });
+        self
+    }
+}
+
+// fundrawtransaction's (and walletcreatefundedpsbt's) changepos is -1 when
+// there is no change output; as an index that would point nowhere.
+pub fn change_position<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u32>, D::Error> {
+    let position = i64::deserialize(deserializer)?;
+    Ok(u32::try_from(position).ok())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FundResult {
+    pub hex: String,
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub fee: Amount,
+    #[serde(deserialize_with = "change_position")]
+    pub changepos: Option<u32>,
+}
+
+pub fn fund_raw_transaction(rpc: &Client, hex: &str, options: &FundOptions) -> Result<FundResult> {
+    Ok(rpc.call(
+        "fundrawtransaction",
+        &[json!(hex), serde_json::to_value(options)?],
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::FeeSource;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::Txid;
+
+    #[test]
+    fn only_set_options_are_sent() {
+        assert_eq!(serde_json::to_value(FundOptions::new()).unwrap(), json!({}));
+        let unset = FundOptions::new()
+            .add_inputs(None)
+            .change_address(None)
+            .replaceable(false);
+        assert_eq!(serde_json::to_value(unset).unwrap(), json!({}));
+    }
+
+    #[test]
+    fn options_serialize_as_fundrawtransaction_expects() {
+        let outpoint = OutPoint::new(Txid::all_zeros(), 1);
+        let options = FundOptions::new()
+            .add_inputs(Some(false))
+            .include_watching(true)
+            .lock_unspents(true)
+            .change_type(Some(ChangeType::Bech32m))
+            .change_position(Some(0))
+            .estimate(Some(6), Some(EstimateMode::Economical))
+            .replaceable(true)
+            .subtract_fee_from_outputs(vec![0, 2])
+            .input_weight(outpoint, Weight::from_wu(272));
+        assert_eq!(
+            serde_json::to_value(options).unwrap(),
+            json!({
+                "add_inputs": false,
+                "include_watching": true,
+                "lock_unspents": true,
+                "change_type": "bech32m",
+                "change_position": 0,
+                "conf_target": 6,
+                "estimate_mode": "ECONOMICAL",
+                "replaceable": true,
+                "subtract_fee_from_outputs": [0, 2],
+                "input_weights": [{
+                    "txid": Txid::all_zeros().to_string(),
+                    "vout": 1,
+                    "weight": 272,
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn fee_rate_replaces_the_estimate() {
+        let fee_rate = FeeRateChoice {
+            sat_per_kvb: 2_500,
+            source: FeeSource::Requested,
+        };
+        let options = FundOptions::new()
+            .estimate(Some(6), Some(EstimateMode::Conservative))
+            .fee_rate(&fee_rate)
+            .estimate(Some(2), None);
+        assert_eq!(
+            serde_json::to_value(options).unwrap(),
+            json!({ "fee_rate": "2.5" })
+        );
+    }
+
+    #[test]
+    fn result_maps_changepos_minus_one_to_none() {
+        let result: FundResult = serde_json::from_value(json!({
+            "hex": "0200",
+            "fee": 0.0000141,
+            "changepos": -1,
+        }))
+        .unwrap();
+        assert_eq!(result.hex, "0200");
+        assert_eq!(result.fee, Amount::from_sat(1_410));
+        assert_eq!(result.changepos, None);
+
+        let result: FundResult =
+            serde_json::from_value(json!({ "hex": "", "fee": 0.0, "changepos": 1 })).unwrap();
+        assert_eq!(result.changepos, Some(1));
+    }
+}
@@ -0,0 +1,234 @@
+use crate::btc;
+use crate::error::{CapstoneError, Result};
+use bitcoincore_rpc::bitcoin::{Amount, OutPoint};
+use bitcoincore_rpc::json::ListUnspentResultEntry;
+use bitcoincore_rpc::{Client, RpcApi};
+use std::collections::BTreeMap;
+
+// vsize of one P2WPKH input, what each extra coin adds to the transaction
+const P2WPKH_INPUT_VSIZE: u64 = 68;
+
+// Coins picked so that every address that is touched is emptied completely,
+// next to how many coins a plain largest-first pick would have needed.
+#[derive(Debug, Clone)]
+pub struct InputSelection {
+    pub inputs: Vec<OutPoint>,
+    pub addresses: usize,
+    pub minimal_inputs: usize,
+}
+
+impl InputSelection {
+    // What the extra inputs cost at the feerate the transaction ended up paying
+    pub fn extra_fee(&self, fee: Amount, vsize: u64) -> Amount {
+        let extra = self.inputs.len().saturating_sub(self.minimal_inputs) as u64;
+        Amount::from_sat(extra * P2WPKH_INPUT_VSIZE * fee.to_sat() / vsize.max(1))
+    }
+
+    pub fn describe(&self, fee: Amount, vsize: u64) -> String {
+        format!(
+            "{} input(s) from {} address(es), {} would have been enough; extra fee {}",
+            self.inputs.len(),
+            self.addresses,
+            self.minimal_inputs,
+            btc::amount(self.extra_fee(fee, vsize))
+        )
+    }
+}
+
+fn outpoint(utxo: &ListUnspentResultEntry) -> OutPoint {
+    OutPoint::new(utxo.txid, utxo.vout)
+}
+
+// The wallet only groups coins by address with -avoidpartialspends or the
+// avoid_reuse wallet flag, neither of which can be set per call, so the
+// grouping is done here and handed to the wallet as preset inputs. `target`
+// is the payment plus whatever fee allowance the caller wants covered.
+pub fn select_by_address(wallet_rpc: &Client, target: Amount) -> Result<InputSelection> {
+    let utxos: Vec<ListUnspentResultEntry> = wallet_rpc
+        .list_unspent(Some(1), None, None, None, None)?
+        .into_iter()
+        .filter(|utxo| utxo.spendable)
+        .collect();
+    select(&utxos, target)
+}
+
+// `select_by_address` over the wallet's spendable coins
+fn select(utxos: &[ListUnspentResultEntry], target: Amount) -> Result<InputSelection> {
+    let mut groups: BTreeMap<String, Vec<&ListUnspentResultEntry>> = BTreeMap::new();
+    for utxo in utxos {
+        let key = match &utxo.address {
+            Some(address) => address.clone().assume_checked().to_string(),
+            // No address to group by, the coin stands alone
+            None => outpoint(utxo).to_string(),
+        };
+        groups.entry(key).or_default().push(utxo);
+    }
+    let mut groups: Vec<_> = groups.into_values().collect();
+    let group_total =
+        |group: &Vec<&ListUnspentResultEntry>| group.iter().map(|utxo| utxo.amount).sum::<Amount>();
+    groups.sort_by_key(|group| std::cmp::Reverse(group_total(group)));
+
+    let mut inputs = Vec::new();
+    let mut addresses = 0;
+    let mut total = Amount::ZERO;
+    for group in &groups {
+        if total >= target {
+            break;
+        }
+        total += group_total(group);
+        inputs.extend(group.iter().map(|utxo| outpoint(utxo)));
+        addresses += 1;
+    }
+    if total < target {
+        return Err(CapstoneError::Usage(format!(
+            "the wallet holds {} in confirmed coins, {} is needed",
+            btc::amount(total),
+            btc::amount(target)
+        )));
+    }
+
+    // Largest coins first gives the smallest possible number of inputs
+    let mut amounts: Vec<Amount> = utxos.iter().map(|utxo| utxo.amount).collect();
+    amounts.sort_by_key(|amount| std::cmp::Reverse(*amount));
+    let mut minimal_inputs = 0;
+    let mut minimal_total = Amount::ZERO;
+    for amount in amounts {
+        if minimal_total >= target {
+            break;
+        }
+        minimal_total += amount;
+        minimal_inputs += 1;
+    }
+
+    Ok(InputSelection {
+        inputs,
+        addresses,
+        minimal_inputs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{Address, ScriptBuf, Txid};
+
+    fn selection(inputs: u32, minimal_inputs: usize) -> InputSelection {
+        InputSelection {
+            inputs: (0..inputs)
+                .map(|vout| OutPoint::new(Txid::all_zeros(), vout))
+                .collect(),
+            addresses: 1,
+            minimal_inputs,
+        }
+    }
+
+    const REUSED: &str = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+    const OTHER: &str = "bcrt1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qzf4jry";
+
+    // A confirmed coin of `btc` paid to `address` by transaction `n`
+    fn utxo(n: u8, address: Option<&str>, btc: f64) -> ListUnspentResultEntry {
+        let address = address.map(|a| a.parse::<Address<_>>().unwrap());
+        ListUnspentResultEntry {
+            txid: Txid::from_byte_array([n; 32]),
+            vout: 0,
+            script_pub_key: address
+                .clone()
+                .map(|a| a.assume_checked().script_pubkey())
+                .unwrap_or_default(),
+            address,
+            label: None,
+            redeem_script: None,
+            witness_script: None,
+            amount: Amount::from_btc(btc).unwrap(),
+            confirmations: 6,
+            spendable: true,
+            solvable: true,
+            descriptor: None,
+            safe: true,
+        }
+    }
+
+    #[test]
+    fn all_three_coins_on_a_reused_address_are_spent() {
+        let utxos = vec![
+            utxo(1, Some(REUSED), 10.0),
+            utxo(2, Some(REUSED), 5.0),
+            utxo(3, Some(REUSED), 5.0),
+            utxo(4, Some(OTHER), 12.0),
+        ];
+        // 10 BTC would do with one coin, but the reused address holds 20
+        let selection = select(&utxos, Amount::from_btc(10.0).unwrap()).unwrap();
+        assert_eq!(selection.addresses, 1);
+        assert_eq!(selection.minimal_inputs, 1);
+        let mut spent: Vec<u8> = selection
+            .inputs
+            .iter()
+            .map(|outpoint| outpoint.txid.to_byte_array()[0])
+            .collect();
+        spent.sort();
+        assert_eq!(spent, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn addresses_are_added_whole_until_the_target_is_met() {
+        let utxos = vec![
+            utxo(1, Some(REUSED), 3.0),
+            utxo(2, Some(REUSED), 3.0),
+            utxo(3, Some(OTHER), 5.0),
+            utxo(4, None, 1.0),
+        ];
+        let selection = select(&utxos, Amount::from_btc(8.0).unwrap()).unwrap();
+        assert_eq!(selection.addresses, 2);
+        assert_eq!(selection.inputs.len(), 3);
+        assert_eq!(selection.minimal_inputs, 2);
+        // A coin without an address stands alone
+        let all = select(&utxos, Amount::from_btc(12.0).unwrap()).unwrap();
+        assert_eq!((all.addresses, all.inputs.len()), (3, 4));
+    }
+
+    #[test]
+    fn not_enough_coins() {
+        let utxos = vec![utxo(1, Some(REUSED), 1.0)];
+        let message = select(&utxos, Amount::from_btc(2.0).unwrap())
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            message,
+            "the wallet holds 1.00000000 BTC in confirmed coins, 2.00000000 BTC is needed"
+        );
+    }
+
+    #[test]
+    fn extra_fee_is_the_extra_inputs_share() {
+        // Three inputs where one would do, 2820 sat over 277 vB
+        let fee = Amount::from_sat(2_820);
+        assert_eq!(
+            selection(3, 1).extra_fee(fee, 277),
+            Amount::from_sat(2 * 68 * 2_820 / 277)
+        );
+        assert_eq!(selection(3, 1).extra_fee(fee, 277).to_sat(), 1_384);
+        assert_eq!(selection(1, 1).extra_fee(fee, 141), Amount::ZERO);
+        assert_eq!(selection(2, 1).extra_fee(Amount::ZERO, 209), Amount::ZERO);
+    }
+
+    #[test]
+    fn extra_fee_edge_cases() {
+        let fee = Amount::from_sat(1_000);
+        // Fewer inputs than the plain pick can't cost extra
+        assert_eq!(selection(1, 2).extra_fee(fee, 141), Amount::ZERO);
+        // A zero size counts as one vbyte instead of dividing by zero
+        assert_eq!(
+            selection(2, 1).extra_fee(fee, 0),
+            Amount::from_sat(68 * 1_000)
+        );
+    }
+
+    #[test]
+    fn describe_lists_the_counts() {
+        assert_eq!(
+            selection(3, 1).describe(Amount::from_sat(2_820), 277),
+            "3 input(s) from 1 address(es), 1 would have been enough; extra fee 0.00001384 BTC"
+        );
+    }
+}
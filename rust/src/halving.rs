@@ -0,0 +1,116 @@
+use crate::block::{self, FullBlock};
+use crate::btc;
+use crate::cli::Config;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::report;
+use crate::session::{self, Session};
+use bitcoincore_rpc::bitcoin::{Amount, BlockHash, Network};
+use bitcoincore_rpc::RpcApi;
+
+// Blocks mined on each side of the boundary to measure the balance growth
+const SAMPLE_BLOCKS: u64 = 5;
+
+// What one block's coinbase claims.
+#[derive(Debug, Clone)]
+pub struct CoinbaseClaim {
+    pub height: u64,
+    pub block_hash: BlockHash,
+    pub total: Amount,
+    pub fees: Amount,
+    pub subsidy: Amount,
+}
+
+// Take a block's coinbase apart: the subsidy is whatever the coinbase outputs
+// claim beyond the fees of the other transactions.
+fn coinbase_claim(block: &FullBlock) -> Result<CoinbaseClaim> {
+    let total = block
+        .coinbase()
+        .ok_or_else(|| CapstoneError::Usage(format!("block {} has no coinbase", block.hash)))?
+        .output_value();
+    let fees = block.total_fees();
+    Ok(CoinbaseClaim {
+        height: block.height,
+        block_hash: block.hash,
+        total,
+        fees,
+        subsidy: total - fees,
+    })
+}
+
+// Everything the reward wallet holds or will hold once its coinbases mature
+fn reward_balance(session: &Session) -> Result<Amount> {
+    let wallet_rpc = session.mining_rpc.as_ref().unwrap_or(&session.miner_rpc);
+    let balances = wallet_rpc.get_balances()?.mine;
+    Ok(balances.trusted + balances.untrusted_pending + balances.immature)
+}
+
+// halving-demo: mine across the next regtest halving, check the coinbases on
+// both sides against the subsidy schedule, and compare how fast the reward
+// wallet grows before and after.
+pub fn run(session: &Session, config: &Config) -> Result<()> {
+    if session.network != Network::Regtest {
+        return Err(CapstoneError::Usage(
+            "halving-demo mines over a hundred blocks, it only runs on regtest".into(),
+        ));
+    }
+    let rpc = &session.rpc;
+    let interval = session::halving_interval(session.network);
+
+    // The first halved block, far enough ahead for a full sample before it
+    let tip = rpc.get_block_count()?;
+    let boundary = (tip + SAMPLE_BLOCKS) / interval * interval + interval;
+    let start = boundary - SAMPLE_BLOCKS - 1;
+    say!(
+        "Halving at height {}; mining {} blocks to reach it.",
+        boundary,
+        boundary + SAMPLE_BLOCKS - 1 - tip
+    );
+    session.generate(start - tip, &session.miner_address)?;
+
+    let before = reward_balance(session)?;
+    session.generate(SAMPLE_BLOCKS, &session.miner_address)?;
+    let at_boundary = reward_balance(session)?;
+    session.generate(SAMPLE_BLOCKS, &session.miner_address)?;
+    let after = reward_balance(session)?;
+
+    let mut claims = Vec::new();
+    block::for_each_full_block(rpc, boundary - 1, boundary, config.parallelism(), |block| {
+        let claim = coinbase_claim(&block)?;
+        let expected = session::block_subsidy(claim.height, session.network);
+        if claim.subsidy != expected {
+            return Err(CapstoneError::SubsidyMismatch {
+                height: claim.height,
+                expected,
+                observed: claim.subsidy,
+            });
+        }
+        claims.push(claim);
+        Ok(())
+    })?;
+    say!("Both coinbases claim exactly the scheduled subsidy.");
+
+    let mut output_string = String::new();
+    output_string.push_str(&format!("Halving boundary height: {}\n", boundary));
+    for claim in &claims {
+        output_string.push_str(&format!(
+            "Block {} ({}): coinbase {} = subsidy {} + fees {}\n",
+            claim.height,
+            claim.block_hash,
+            btc::amount(claim.total),
+            btc::amount(claim.subsidy),
+            btc::amount(claim.fees)
+        ));
+    }
+    output_string.push_str(&format!(
+        "Reward wallet growth per block before the halving (in {}): {}\n",
+        btc::unit(),
+        btc::format((at_boundary - before) / SAMPLE_BLOCKS)
+    ));
+    output_string.push_str(&format!(
+        "Reward wallet growth per block after the halving (in {}): {}\n",
+        btc::unit(),
+        btc::format((after - at_boundary) / SAMPLE_BLOCKS)
+    ));
+    report::write_report(&output_string)
+}
@@ -0,0 +1,262 @@
+use crate::chaintime;
+use crate::console::say;
+use crate::error::Result;
+use crate::files;
+use crate::report::TransactionReport;
+use bitcoincore_rpc::bitcoin::{BlockHash, Txid};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// One line of the --history JSONL file per reported transaction, so runs
+// that overwrite out.txt still leave a trace.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    // RFC 3339, when the run wrote its report
+    pub timestamp: String,
+    pub txid: Txid,
+    // What the Trader received, 0 without a Trader output
+    pub amount_sat: u64,
+    pub fee_sat: u64,
+    pub height: u64,
+    pub block_hash: BlockHash,
+}
+
+impl HistoryRecord {
+    pub fn from_report(report: &TransactionReport) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        HistoryRecord {
+            timestamp: chaintime::rfc3339(now),
+            txid: report.txid,
+            amount_sat: report
+                .trader_output
+                .as_ref()
+                .map_or(0, |(amount, _)| amount.to_sat()),
+            fee_sat: report.fee.to_sat(),
+            height: report.block_height,
+            block_hash: report.block_hash,
+        }
+    }
+
+    // How this run compares with `previous`
+    pub fn compare(&self, previous: &HistoryRecord) -> String {
+        let delta = |now: u64, before: u64| {
+            let delta = now as i128 - before as i128;
+            if delta >= 0 {
+                format!("+{}", delta)
+            } else {
+                delta.to_string()
+            }
+        };
+        format!(
+            "Compared with the previous run ({} at {}): amount {} sat, fee {} sat, height {}",
+            previous.txid,
+            previous.timestamp,
+            delta(self.amount_sat, previous.amount_sat),
+            delta(self.fee_sat, previous.fee_sat),
+            delta(self.height, previous.height)
+        )
+    }
+}
+
+// Append `record` as one line. The file is locked for the write, so lines of
+// runs appending at the same time never interleave. A last line a crashed run
+// left without its newline is ended first, so the record isn't glued onto it.
+pub fn append(path: &str, record: &HistoryRecord) -> Result<()> {
+    let mut file = files::open_append(path, "history file")?;
+    file.lock()?;
+    let mut line = format!("{}\n", serde_json::to_string(record)?);
+    if file.metadata()?.len() > 0 {
+        let mut last = [0u8];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last != *b"\n" {
+            line.insert(0, '\n');
+        }
+    }
+    file.write_all(line.as_bytes())?;
+    file.unlock()?;
+    Ok(())
+}
+
+// Every record in the file, oldest first; none if it doesn't exist yet. A
+// line that doesn't parse, such as the half-written last line of a crashed
+// run, is skipped with a warning.
+pub fn read(path: &str) -> Result<Vec<HistoryRecord>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(files::error(
+                "read history file",
+                &files::absolute(path.as_ref()),
+            )(e))
+        }
+    };
+    let mut records = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(record) => records.push(record),
+            Err(e) => eprintln!(
+                "Warning: skipping line {} of {}, it isn't a history record: {}",
+                number + 1,
+                path,
+                e
+            ),
+        }
+    }
+    Ok(records)
+}
+
+// Add a report to the history, saying how it compares with the run before.
+pub fn record(path: &str, report: &TransactionReport) -> Result<()> {
+    let record = HistoryRecord::from_report(report);
+    if let Some(previous) = read(path)?.last() {
+        say!("{}", record.compare(previous));
+    }
+    append(path, &record)
+}
+
+// history show: the file as a table, only the last `last` runs if given.
+pub fn run_show(path: &str, last: Option<usize>) -> Result<()> {
+    let records = read(path)?;
+    let skip = last.map_or(0, |last| records.len().saturating_sub(last));
+    let rows: Vec<[String; 6]> = records[skip..]
+        .iter()
+        .map(|record| {
+            [
+                record.timestamp.clone(),
+                record.txid.to_string(),
+                record.amount_sat.to_string(),
+                record.fee_sat.to_string(),
+                record.height.to_string(),
+                record.block_hash.to_string(),
+            ]
+        })
+        .collect();
+    let header = [
+        "timestamp",
+        "txid",
+        "amount (sat)",
+        "fee (sat)",
+        "height",
+        "block hash",
+    ]
+    .map(String::from);
+    let mut widths = header.each_ref().map(|title| title.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        say!("{}", cells.join("  ").trim_end());
+    }
+    say!("{} of {} run(s) in {}.", rows.len(), records.len(), path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+
+    fn record(height: u64) -> HistoryRecord {
+        HistoryRecord {
+            timestamp: "2026-10-14T10:00:00Z".into(),
+            txid: Txid::from_byte_array([height as u8; 32]),
+            amount_sat: 2_000_000_000,
+            fee_sat: 1_410,
+            height,
+            block_hash: BlockHash::all_zeros(),
+        }
+    }
+
+    fn heights(path: &str) -> Vec<u64> {
+        read(path).unwrap().iter().map(|r| r.height).collect()
+    }
+
+    #[test]
+    fn records_read_back_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        let path = path.to_str().unwrap();
+        assert!(read(path).unwrap().is_empty());
+        for height in [101, 102, 103] {
+            append(path, &record(height)).unwrap();
+        }
+        assert_eq!(heights(path), [101, 102, 103]);
+        assert_eq!(fs::read_to_string(path).unwrap().lines().count(), 3);
+    }
+
+    #[test]
+    fn a_line_left_unfinished_is_ended_before_appending() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        let path = path.to_str().unwrap();
+        append(path, &record(101)).unwrap();
+        // A run that died halfway through its line
+        let whole = serde_json::to_string(&record(102)).unwrap();
+        let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&whole.as_bytes()[..20]).unwrap();
+        drop(file);
+
+        append(path, &record(103)).unwrap();
+        let content = fs::read_to_string(path).unwrap();
+        assert_eq!(content.lines().count(), 3);
+        assert!(content.ends_with("}\n"));
+        // Only the broken line is lost, not the record after it
+        assert_eq!(heights(path), [101, 103]);
+    }
+
+    #[test]
+    fn a_complete_last_line_gets_no_blank_line_after_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        let path = path.to_str().unwrap();
+        append(path, &record(101)).unwrap();
+        append(path, &record(102)).unwrap();
+        assert!(!fs::read_to_string(path).unwrap().contains("\n\n"));
+    }
+
+    #[test]
+    fn corrupt_lines_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        let good = |height| serde_json::to_string(&record(height)).unwrap();
+        fs::write(
+            &path,
+            format!(
+                "{}\nnot json\n\n{{\"txid\": 7}}\n{}\n{{\"timest",
+                good(101),
+                good(102)
+            ),
+        )
+        .unwrap();
+        assert_eq!(heights(path.to_str().unwrap()), [101, 102]);
+    }
+
+    #[test]
+    fn comparison_with_the_previous_run() {
+        let mut now = record(103);
+        now.fee_sat = 1_000;
+        assert_eq!(
+            now.compare(&record(101)),
+            format!(
+                "Compared with the previous run ({} at 2026-10-14T10:00:00Z): amount +0 sat, fee -410 sat, height +2",
+                record(101).txid
+            )
+        );
+    }
+}
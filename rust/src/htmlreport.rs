@@ -0,0 +1,362 @@
+use crate::btc;
+use crate::console::{self, say};
+use crate::error::Result;
+use crate::files;
+use crate::report::TransactionReport;
+use bitcoincore_rpc::bitcoin::{Address, Amount};
+
+// --format html goes next to out.txt, which stays the text report
+pub const HTML_PATH: &str = "../out.html";
+
+// Inline so the file stands alone as a ticket attachment or in a mail
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 2em; color: #222; }
+h1, h2 { font-weight: normal; }
+table { border-collapse: collapse; margin-bottom: 1.5em; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; }
+th { background: #f2f2f2; }
+td.mono, pre { font-family: monospace; }
+td.amount { text-align: right; font-family: monospace; }
+pre { background: #f7f7f7; padding: 1em; overflow-x: auto; }
+";
+
+// Text for an element or a double-quoted attribute
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn cell(class: &str, text: &str) -> String {
+    if class.is_empty() {
+        format!("<td>{}</td>", escape(text))
+    } else {
+        format!("<td class=\"{}\">{}</td>", class, escape(text))
+    }
+}
+
+// A two-column table of label / value rows
+fn key_values(rows: &[(&str, String, &str)]) -> String {
+    let mut html = String::from("<table>\n");
+    for (label, value, class) in rows {
+        html.push_str(&format!(
+            "<tr><th>{}</th>{}</tr>\n",
+            escape(label),
+            cell(class, value)
+        ));
+    }
+    html.push_str("</table>\n");
+    html
+}
+
+fn table(header: &[&str], rows: &[Vec<(String, &str)>]) -> String {
+    let mut html = String::from("<table>\n<tr>");
+    for title in header {
+        html.push_str(&format!("<th>{}</th>", escape(title)));
+    }
+    html.push_str("</tr>\n");
+    for row in rows {
+        html.push_str("<tr>");
+        for (text, class) in row {
+            html.push_str(&cell(class, text));
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n");
+    html
+}
+
+fn section(report: &TransactionReport) -> Result<String> {
+    let none = || "None".to_string();
+    let output = |output: &Option<(Amount, Address)>| match output {
+        Some((amount, address)) => (address.to_string(), btc::amount(*amount)),
+        None => (none(), btc::amount(Amount::ZERO)),
+    };
+    let vout = |vout: Option<u32>| vout.map_or_else(none, |vout| vout.to_string());
+    let (trader_address, trader_amount) = output(&report.trader_output);
+    let (change_address, change_amount) = output(&report.change_output);
+    let inputs: Vec<String> = report
+        .input_addresses
+        .iter()
+        .map(|address| address.to_string())
+        .collect();
+
+    let mut html = format!(
+        "<section>\n<h2>Transaction {}</h2>\n",
+        escape(&report.txid.to_string())
+    );
+    html.push_str("<h3>Summary</h3>\n");
+    html.push_str(&key_values(&[
+        ("Transaction ID (txid)", report.txid.to_string(), "mono"),
+        ("Miner's Input Address", inputs.join(", "), "mono"),
+        (
+            "Miner's Input Amount",
+            btc::amount(report.input_amount),
+            "amount",
+        ),
+        ("Trader's Output Address", trader_address, "mono"),
+        ("Trader's Output Amount", trader_amount, "amount"),
+        ("Miner's Change Address", change_address, "mono"),
+        ("Miner's Change Amount", change_amount, "amount"),
+        ("Transaction Fees", btc::amount(report.fee), "amount"),
+        (
+            "Inputs / outputs",
+            format!("{} / {}", report.input_count, report.output_count),
+            "",
+        ),
+        ("Trader's output vout", vout(report.trader_vout), ""),
+        ("Miner's change vout", vout(report.change_vout), ""),
+        ("Version", report.version.to_string(), ""),
+        ("Locktime", report.describe_lock_time(), ""),
+        ("Block height", report.block_height.to_string(), ""),
+        ("Block hash", report.block_hash.to_string(), "mono"),
+        (
+            "Confirmations at report time",
+            report.describe_confirmations(),
+            "",
+        ),
+    ]));
+
+    html.push_str("<h3>Inputs</h3>\n");
+    let inputs: Vec<Vec<(String, &str)>> = report
+        .input_sighashes
+        .iter()
+        .zip(&report.input_sequences)
+        .enumerate()
+        .map(|(i, (sighash, sequence))| {
+            vec![
+                (i.to_string(), ""),
+                (sighash.clone(), "mono"),
+                (format!("{:#010x}", sequence.to_consensus_u32()), "mono"),
+            ]
+        })
+        .collect();
+    html.push_str(&table(&["#", "Sighash", "nSequence"], &inputs));
+
+    html.push_str("<h3>Outputs</h3>\n");
+    let outputs: Vec<Vec<(String, &str)>> = report
+        .outputs
+        .iter()
+        .map(|(vout, role, amount, address)| {
+            vec![
+                (vout.to_string(), ""),
+                (role.to_string(), ""),
+                (
+                    address
+                        .as_ref()
+                        .map_or_else(none, |address| address.to_string()),
+                    "mono",
+                ),
+                (btc::amount(*amount), "amount"),
+            ]
+        })
+        .collect();
+    html.push_str(&table(&["vout", "Role", "Address", "Amount"], &outputs));
+
+    html.push_str("<h3>Fee and feerate</h3>\n");
+    let mut stats = vec![
+        ("Fee", btc::amount(report.fee), "amount"),
+        ("Feerate (sat/vB)", report.fee_rate_sat_per_vb(), "amount"),
+        ("Size (bytes)", report.size.to_string(), "amount"),
+        ("Virtual size (vB)", report.vsize.to_string(), "amount"),
+        ("Weight (WU)", report.weight.to_string(), "amount"),
+    ];
+    let block_stats = report
+        .block_stats
+        .describe(report.fee_rate_centisat_per_vb());
+    for line in &block_stats {
+        // The same "Label: value" lines as out.txt
+        match line.split_once(": ") {
+            Some((label, value)) => stats.push((label, value.to_string(), "")),
+            None => stats.push(("", line.clone(), "")),
+        }
+    }
+    html.push_str(&key_values(&stats));
+
+    html.push_str(&format!(
+        "<details>\n<summary>Raw JSON</summary>\n<pre>{}</pre>\n</details>\n</section>\n",
+        escape(&serde_json::to_string_pretty(&report.to_json())?)
+    ));
+    Ok(html)
+}
+
+// One page with a section per report
+pub fn render(reports: &[TransactionReport]) -> Result<String> {
+    let title = match reports {
+        [report] => format!("Transaction report {}", report.txid),
+        _ => format!("Transaction report ({} transactions)", reports.len()),
+    };
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+        escape(&title),
+        STYLE,
+        escape(&title)
+    );
+    for report in reports {
+        html.push_str(&section(report)?);
+    }
+    html.push_str("</body>\n</html>\n");
+    Ok(html)
+}
+
+// Replace out.html the way out.txt is replaced. Only the path is printed, a
+// page of markup on the console helps nobody, unless --no-file asked for the
+// page on stdout instead of the file.
+pub fn write_html(reports: &[TransactionReport]) -> Result<()> {
+    let html = render(reports)?;
+    if console::report_only() {
+        console::out(&html);
+        return Ok(());
+    }
+    let path = files::write_atomically(HTML_PATH, &html, "HTML report")?;
+    say!("Wrote the HTML report to {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Units;
+    use crate::report::tests::fixture;
+    use scraper::{ElementRef, Html, Selector};
+
+    const HOSTILE: &str = "<script>alert('x')</script> & \"co\"";
+
+    fn select<'a>(element: ElementRef<'a>, selector: &str) -> Vec<ElementRef<'a>> {
+        element
+            .select(&Selector::parse(selector).unwrap())
+            .collect()
+    }
+
+    fn text(element: ElementRef) -> String {
+        element.text().collect()
+    }
+
+    // The cells of each row of `table`, header cells included
+    fn rows(table: ElementRef) -> Vec<Vec<String>> {
+        select(table, "tr")
+            .into_iter()
+            .map(|row| select(row, "th, td").into_iter().map(text).collect())
+            .collect()
+    }
+
+    fn page(reports: &[TransactionReport]) -> Html {
+        let html = btc::with_units(Units::Btc, || render(reports).unwrap());
+        let page = Html::parse_document(&html);
+        assert!(page.errors.is_empty(), "{:?}", page.errors);
+        page
+    }
+
+    #[test]
+    fn escapes_markup_characters() {
+        assert_eq!(
+            escape(HOSTILE),
+            "&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt; &amp; &quot;co&quot;"
+        );
+    }
+
+    #[test]
+    fn labels_and_values_come_out_escaped() {
+        let html = Html::parse_fragment(&key_values(&[(HOSTILE, HOSTILE.to_string(), "mono")]));
+        let root = html.root_element();
+        assert!(select(root, "script").is_empty());
+        assert_eq!(rows(select(root, "table")[0]), [[HOSTILE, HOSTILE]]);
+        assert_eq!(select(root, "td")[0].value().attr("class"), Some("mono"));
+    }
+
+    #[test]
+    fn report_page_structure() {
+        let report = fixture();
+        let page = page(std::slice::from_ref(&report));
+        let root = page.root_element();
+        let title = format!("Transaction report {}", report.txid);
+        assert_eq!(text(select(root, "head > title")[0]), title);
+        assert_eq!(text(select(root, "body > h1")[0]), title);
+
+        let sections = select(root, "body > section");
+        assert_eq!(sections.len(), 1);
+        let section = sections[0];
+        assert_eq!(
+            text(select(section, "h2")[0]),
+            format!("Transaction {}", report.txid)
+        );
+        let headings: Vec<String> = select(section, "h3").into_iter().map(text).collect();
+        assert_eq!(
+            headings,
+            ["Summary", "Inputs", "Outputs", "Fee and feerate"]
+        );
+
+        let tables = select(section, "table");
+        assert_eq!(tables.len(), 4);
+        let summary = rows(tables[0]);
+        assert_eq!(
+            summary[0],
+            ["Transaction ID (txid)", &report.txid.to_string()]
+        );
+        assert!(summary.contains(&vec![
+            "Trader's Output Amount".to_string(),
+            "20.00000000 BTC".to_string()
+        ]));
+        assert!(summary.contains(&vec!["Miner's change vout".to_string(), "1".to_string()]));
+        assert_eq!(
+            rows(tables[2]),
+            [
+                ["vout", "Role", "Address", "Amount"],
+                [
+                    "0",
+                    "Trader",
+                    "bcrt1qak6gpu2p6zjpwrhvd4dvdnp4rt3ysm9rpst3wu",
+                    "20.00000000 BTC"
+                ],
+                [
+                    "1",
+                    "Change",
+                    "bcrt1qxw3msnuqps0kgn6dprs9ldlz79yfj63swqupd0",
+                    "29.99998590 BTC"
+                ],
+            ]
+        );
+        // Amounts are right-aligned, addresses monospaced
+        let amount = select(tables[2], "td")[3];
+        assert_eq!(amount.value().attr("class"), Some("amount"));
+
+        // The raw JSON reads back as the report's JSON
+        let raw = select(section, "details > pre")[0];
+        let json: serde_json::Value = serde_json::from_str(&text(raw)).unwrap();
+        assert_eq!(json, report.to_json());
+    }
+
+    #[test]
+    fn report_text_stays_text() {
+        let mut report = fixture();
+        report.wallet_topology = Some(HOSTILE.to_string());
+        let page = page(&[report.clone()]);
+        let root = page.root_element();
+        assert!(select(root, "script").is_empty());
+        let raw = text(select(root, "details > pre")[0]);
+        let json: serde_json::Value = serde_json::from_str(&raw).unwrap();
+        assert_eq!(json, report.to_json());
+        assert!(raw.contains(&serde_json::to_string(HOSTILE).unwrap()));
+    }
+
+    #[test]
+    fn one_section_per_report() {
+        let page = page(&[fixture(), fixture()]);
+        let root = page.root_element();
+        assert_eq!(
+            text(select(root, "title")[0]),
+            "Transaction report (2 transactions)"
+        );
+        assert_eq!(select(root, "body > section").len(), 2);
+        assert_eq!(select(root, "section > details").len(), 2);
+    }
+}
@@ -0,0 +1,335 @@
+use crate::cli::Config;
+use crate::console::say;
+use bitcoincore_rpc::bitcoin::{OutPoint, Sequence, Transaction};
+use bitcoincore_rpc::json::CreateRawTransactionInput;
+use serde_json::json;
+
+// A coin the transaction must spend, with the nSequence it should carry (None
+// leaves it to --default-sequence, or else to the wallet).
+#[derive(Debug, Clone)]
+pub struct PresetInput {
+    pub outpoint: OutPoint,
+    pub sequence: Option<Sequence>,
+}
+
+// The inputs the wallet is handed before funding. When `exclusive` it may not
+// add any of its own.
+#[derive(Debug, Clone, Default)]
+pub struct PresetInputs {
+    pub inputs: Vec<PresetInput>,
+    pub exclusive: bool,
+}
+
+impl PresetInputs {
+    // The coins picked by --avoid-partial-spends (which must be the only ones)
+    // plus any named by --input-sequence.
+    pub fn new(selected: Option<&[OutPoint]>, config: &Config) -> Self {
+        let explicit = |outpoint: &OutPoint| {
+            config
+                .input_sequences
+                .iter()
+                .find(|(o, _)| o == outpoint)
+                .map(|(_, sequence)| *sequence)
+        };
+        let mut inputs: Vec<PresetInput> = selected
+            .unwrap_or_default()
+            .iter()
+            .map(|outpoint| PresetInput {
+                outpoint: *outpoint,
+                sequence: explicit(outpoint),
+            })
+            .collect();
+        for (outpoint, sequence) in &config.input_sequences {
+            if !inputs.iter().any(|input| input.outpoint == *outpoint) {
+                inputs.push(PresetInput {
+                    outpoint: *outpoint,
+                    sequence: Some(*sequence),
+                });
+            }
+        }
+        for input in &mut inputs {
+            input.sequence = input.sequence.or(config.default_sequence);
+        }
+        PresetInputs {
+            inputs,
+            exclusive: selected.is_some(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    // The add_inputs option, only sent when there are preset inputs
+    pub fn add_inputs(&self) -> Option<bool> {
+        (!self.is_empty()).then_some(!self.exclusive)
+    }
+
+    pub fn raw_inputs(&self) -> Vec<CreateRawTransactionInput> {
+        self.inputs
+            .iter()
+            .map(|input| CreateRawTransactionInput {
+                txid: input.outpoint.txid,
+                vout: input.outpoint.vout,
+                sequence: input.sequence.map(|s| s.to_consensus_u32()),
+            })
+            .collect()
+    }
+
+    // The `inputs` array of send and walletcreatefundedpsbt
+    pub fn to_json(&self) -> serde_json::Value {
+        self.inputs
+            .iter()
+            .map(|input| {
+                let mut entry = json!({
+                    "txid": input.outpoint.txid.to_string(),
+                    "vout": input.outpoint.vout,
+                });
+                if let Some(sequence) = input.sequence {
+                    entry["sequence"] = json!(sequence.to_consensus_u32());
+                }
+                entry
+            })
+            .collect()
+    }
+}
+
+// Give the inputs the wallet added while funding the --default-sequence too.
+// Only valid before signing.
+pub fn apply_default_sequence(tx: &mut Transaction, preset: &PresetInputs, config: &Config) {
+    let Some(default) = config.default_sequence else {
+        return;
+    };
+    for input in &mut tx.input {
+        if !preset
+            .inputs
+            .iter()
+            .any(|p| p.outpoint == input.previous_output)
+        {
+            input.sequence = default;
+        }
+    }
+}
+
+// What a sequence number means for the transaction carrying it.
+pub fn describe_sequence(sequence: Sequence) -> String {
+    let value = sequence.to_consensus_u32();
+    let meaning = if sequence == Sequence::MAX {
+        "final".to_string()
+    } else if let Some(lock) = sequence.to_relative_lock_time() {
+        format!("relative lock {}, RBF", lock)
+    } else if sequence.is_rbf() {
+        "RBF, locktime enabled".to_string()
+    } else {
+        "locktime enabled".to_string()
+    };
+    format!("0x{:08x} ({})", value, meaning)
+}
+
+// nLockTime is only enforced if at least one input is non-final; say so
+// rather than let a locktime silently do nothing.
+pub fn warn_if_locktime_ignored(tx: &Transaction) {
+    if locktime_ignored(tx) {
+        say!(
+            "Warning: locktime {} is ignored, every input has the final sequence 0xffffffff.",
+            tx.lock_time
+        );
+    }
+}
+
+fn locktime_ignored(tx: &Transaction) -> bool {
+    tx.lock_time.to_consensus_u32() != 0 && tx.input.iter().all(|i| i.sequence == Sequence::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::Txid;
+
+    fn outpoint(n: u8, vout: u32) -> OutPoint {
+        OutPoint::new(Txid::from_byte_array([n; 32]), vout)
+    }
+
+    #[test]
+    fn to_json_leaves_out_an_unset_sequence() {
+        let preset = PresetInputs {
+            inputs: vec![
+                PresetInput {
+                    outpoint: outpoint(1, 0),
+                    sequence: None,
+                },
+                PresetInput {
+                    outpoint: outpoint(2, 3),
+                    sequence: Some(Sequence::ENABLE_RBF_NO_LOCKTIME),
+                },
+            ],
+            exclusive: false,
+        };
+        assert_eq!(
+            preset.to_json(),
+            json!([
+                { "txid": outpoint(1, 0).txid.to_string(), "vout": 0 },
+                {
+                    "txid": outpoint(2, 3).txid.to_string(),
+                    "vout": 3,
+                    "sequence": 0xfffffffd_u32,
+                },
+            ])
+        );
+        assert_eq!(preset.raw_inputs()[0].sequence, None);
+        assert_eq!(preset.raw_inputs()[1].sequence, Some(0xfffffffd));
+    }
+
+    #[test]
+    fn add_inputs_only_with_preset_inputs() {
+        let none = PresetInputs::default();
+        assert_eq!(none.add_inputs(), None);
+        assert_eq!(none.to_json(), json!([]));
+
+        let input = PresetInput {
+            outpoint: outpoint(1, 0),
+            sequence: None,
+        };
+        let mut preset = PresetInputs {
+            inputs: vec![input],
+            exclusive: true,
+        };
+        assert_eq!(preset.add_inputs(), Some(false));
+        preset.exclusive = false;
+        assert_eq!(preset.add_inputs(), Some(true));
+    }
+
+    #[test]
+    fn new_merges_selected_and_explicit_sequences() {
+        let config = Config {
+            input_sequences: vec![
+                (outpoint(1, 0), Sequence(5)),
+                (outpoint(9, 1), Sequence::MAX),
+            ],
+            default_sequence: Some(Sequence::ENABLE_RBF_NO_LOCKTIME),
+            ..Config::default()
+        };
+        let selected = [outpoint(1, 0), outpoint(2, 0)];
+
+        let preset = PresetInputs::new(Some(&selected), &config);
+        assert!(preset.exclusive);
+        let sequences: Vec<(OutPoint, Option<Sequence>)> = preset
+            .inputs
+            .iter()
+            .map(|input| (input.outpoint, input.sequence))
+            .collect();
+        assert_eq!(
+            sequences,
+            vec![
+                (outpoint(1, 0), Some(Sequence(5))),
+                (outpoint(2, 0), Some(Sequence::ENABLE_RBF_NO_LOCKTIME)),
+                (outpoint(9, 1), Some(Sequence::MAX)),
+            ]
+        );
+
+        let only_explicit = PresetInputs::new(None, &config);
+        assert!(!only_explicit.exclusive);
+        assert_eq!(only_explicit.inputs.len(), 2);
+        assert_eq!(only_explicit.add_inputs(), Some(true));
+    }
+
+    #[test]
+    fn requested_sequences_reach_the_node() {
+        let txid = outpoint(1, 0).txid;
+        let config = crate::cli::parse_args([
+            format!("--input-sequence={}:1=0xfffffffd", txid),
+            "--input-sequence".to_string(),
+            format!("{}:2=144", txid),
+        ])
+        .unwrap();
+        let preset = PresetInputs::new(None, &config);
+        // walletcreatefundedpsbt and send take the inputs array
+        assert_eq!(
+            preset.to_json(),
+            json!([
+                { "txid": txid.to_string(), "vout": 1, "sequence": 0xfffffffd_u32 },
+                { "txid": txid.to_string(), "vout": 2, "sequence": 144 },
+            ])
+        );
+        // createrawtransaction the raw inputs
+        assert_eq!(
+            serde_json::to_value(preset.raw_inputs()).unwrap(),
+            json!([
+                { "txid": txid.to_string(), "vout": 1, "sequence": 0xfffffffd_u32 },
+                { "txid": txid.to_string(), "vout": 2, "sequence": 144 },
+            ])
+        );
+    }
+
+    fn transaction(inputs: &[(OutPoint, Sequence)], lock_time: u32) -> Transaction {
+        use bitcoincore_rpc::bitcoin::{absolute, transaction, TxIn};
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::from_consensus(lock_time),
+            input: inputs
+                .iter()
+                .map(|(outpoint, sequence)| TxIn {
+                    previous_output: *outpoint,
+                    sequence: *sequence,
+                    ..TxIn::default()
+                })
+                .collect(),
+            output: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn the_default_sequence_only_touches_wallet_added_inputs() {
+        let preset = PresetInputs {
+            inputs: vec![PresetInput {
+                outpoint: outpoint(1, 0),
+                sequence: Some(Sequence(10)),
+            }],
+            exclusive: false,
+        };
+        let funded = [
+            (outpoint(1, 0), Sequence(10)),
+            (outpoint(2, 0), Sequence::ENABLE_RBF_NO_LOCKTIME),
+        ];
+        let mut tx = transaction(&funded, 0);
+        apply_default_sequence(&mut tx, &preset, &Config::default());
+        assert_eq!(tx.input[1].sequence, Sequence::ENABLE_RBF_NO_LOCKTIME);
+
+        let config = Config {
+            default_sequence: Some(Sequence::MAX),
+            ..Config::default()
+        };
+        apply_default_sequence(&mut tx, &preset, &config);
+        assert_eq!(tx.input[0].sequence, Sequence(10));
+        assert_eq!(tx.input[1].sequence, Sequence::MAX);
+    }
+
+    #[test]
+    fn locktime_with_only_final_sequences_is_ignored() {
+        let final_only = [
+            (outpoint(1, 0), Sequence::MAX),
+            (outpoint(2, 0), Sequence::MAX),
+        ];
+        assert!(locktime_ignored(&transaction(&final_only, 500)));
+        assert!(!locktime_ignored(&transaction(&final_only, 0)));
+        let one_open = [
+            (outpoint(1, 0), Sequence::MAX),
+            (outpoint(2, 0), Sequence::ENABLE_LOCKTIME_NO_RBF),
+        ];
+        assert!(!locktime_ignored(&transaction(&one_open, 500)));
+    }
+
+    #[test]
+    fn sequence_meanings() {
+        assert_eq!(describe_sequence(Sequence::MAX), "0xffffffff (final)");
+        assert_eq!(
+            describe_sequence(Sequence::ENABLE_LOCKTIME_NO_RBF),
+            "0xfffffffe (locktime enabled)"
+        );
+        assert_eq!(
+            describe_sequence(Sequence::from_height(10)),
+            "0x0000000a (relative lock 10, RBF)"
+        );
+    }
+}
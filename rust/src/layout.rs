@@ -0,0 +1,543 @@
+use crate::error::{CapstoneError, Result};
+use crate::files;
+use std::collections::{BTreeMap, BTreeSet};
+
+// The layout of out.txt's required lines, as readme.md gives them, followed
+// by everything else the report has ({{details}}). `TransactionReport::to_text`
+// renders it, so a --template starting from a copy of this reproduces the
+// default report exactly.
+pub const DEFAULT_TEMPLATE: &str = "\
+Transaction ID (txid): {{txid}}
+Miner's Input Address: {{inputs | join \", \"}}
+Miner's Input Amount (in {{units}}): {{input_amount_in_units}}
+{{#if trader}}Trader's Output Address: {{trader.address}}
+Trader's Output Amount (in {{units}}): {{trader.amount_in_units}}
+{{/if}}{{#if change}}Miner's Change Address: {{change.address}}
+{{else}}Miner's Change Address: None
+{{/if}}Miner's Change Amount (in {{units}}): {{change.amount_in_units}}
+Transaction Fees (in {{units}}): {{fee_in_units}}
+Block height at which the transaction is confirmed: {{block.height}}
+Block hash at which the transaction is confirmed: {{block.hash}}
+{{details}}";
+
+// What a placeholder can stand for. Null is a value the report doesn't have
+// this time, e.g. the change output of a payment without change.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    List(Vec<String>),
+    Bool(bool),
+    Null,
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Text(_) => true,
+            Value::List(items) => !items.is_empty(),
+            Value::Bool(value) => *value,
+            Value::Null => false,
+        }
+    }
+}
+
+pub type Variables = BTreeMap<String, Value>;
+
+// Add `value` under `prefix`, and every field of an object under
+// `prefix.field`, so any part of TransactionReport::to_json can be named.
+// Arrays of scalars become lists; arrays of objects are reachable by index.
+pub fn flatten(prefix: &str, value: &serde_json::Value, variables: &mut Variables) {
+    let join = |key: &str| {
+        if prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", prefix, key)
+        }
+    };
+    let scalar = |value: &serde_json::Value| match value {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        serde_json::Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    };
+    match value {
+        serde_json::Value::Object(fields) => {
+            if !prefix.is_empty() {
+                variables.insert(prefix.to_string(), Value::Bool(true));
+            }
+            for (key, field) in fields {
+                flatten(&join(key), field, variables);
+            }
+        }
+        serde_json::Value::Array(items) => {
+            match items.iter().map(scalar).collect::<Option<Vec<_>>>() {
+                Some(list) => {
+                    variables.insert(prefix.to_string(), Value::List(list));
+                }
+                None => {
+                    variables.insert(prefix.to_string(), Value::Bool(!items.is_empty()));
+                    for (i, item) in items.iter().enumerate() {
+                        flatten(&join(&i.to_string()), item, variables);
+                    }
+                }
+            }
+        }
+        serde_json::Value::Null => {
+            variables.insert(prefix.to_string(), Value::Null);
+        }
+        serde_json::Value::Bool(b) => {
+            variables.insert(prefix.to_string(), Value::Bool(*b));
+        }
+        other => {
+            if let Some(text) = scalar(other) {
+                variables.insert(prefix.to_string(), Value::Text(text));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    // Lists only: the items with this between them
+    Join(String),
+    // Used when the value is null
+    Default(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Placeholder {
+        name: String,
+        filters: Vec<Filter>,
+        line: usize,
+    },
+    If {
+        name: String,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+        line: usize,
+    },
+}
+
+// A template error at a 1-based line
+fn error(line: usize, message: String) -> CapstoneError {
+    CapstoneError::Template(format!("line {}: {}", line, message))
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+// A double-quoted filter argument: \" \\ \n and \t are the escapes.
+fn parse_string(s: &str, line: usize) -> Result<(String, &str)> {
+    let Some(body) = s.strip_prefix('"') else {
+        return Err(error(
+            line,
+            format!("expected a \"quoted\" argument, found '{}'", s),
+        ));
+    };
+    let mut out = String::new();
+    let mut chars = body.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((out, &body[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, '"')) => out.push('"'),
+                Some((_, '\\')) => out.push('\\'),
+                Some((_, 'n')) => out.push('\n'),
+                Some((_, 't')) => out.push('\t'),
+                Some((_, other)) => {
+                    return Err(error(line, format!("unknown escape '\\{}'", other)))
+                }
+                None => break,
+            },
+            c => out.push(c),
+        }
+    }
+    Err(error(line, "unterminated \"quoted\" argument".into()))
+}
+
+// `name | join ", " | default "x"`
+fn parse_placeholder(tag: &str, line: usize) -> Result<Node> {
+    let mut parts = tag.splitn(2, '|');
+    let name = parts.next().unwrap_or_default().trim().to_string();
+    if !is_name(&name) {
+        return Err(error(line, format!("'{}' is not a placeholder name", name)));
+    }
+    let mut filters = Vec::new();
+    let mut rest = parts.next().map(str::trim);
+    while let Some(filter) = rest {
+        let (word, after) = filter
+            .split_once(char::is_whitespace)
+            .unwrap_or((filter, ""));
+        let filter: fn(String) -> Filter = match word {
+            "join" => Filter::Join,
+            "default" => Filter::Default,
+            other => {
+                return Err(error(
+                    line,
+                    format!("unknown filter '{}' (expected join or default)", other),
+                ))
+            }
+        };
+        let (argument, after) = parse_string(after.trim_start(), line)?;
+        filters.push(filter(argument));
+        let after = after.trim();
+        rest = match after.strip_prefix('|') {
+            Some(next) => Some(next.trim()),
+            None if after.is_empty() => None,
+            None => {
+                return Err(error(
+                    line,
+                    format!("unexpected '{}' after a filter, expected |", after),
+                ))
+            }
+        };
+    }
+    Ok(Node::Placeholder {
+        name,
+        filters,
+        line,
+    })
+}
+
+// A block being parsed: the {{#if}} it belongs to and whether {{else}} was seen
+struct Frame {
+    name: String,
+    line: usize,
+    then: Vec<Node>,
+    otherwise: Option<Vec<Node>>,
+}
+
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    // {{name}}, {{name | filter "argument"}}, and
+    // {{#if name}}...{{else}}...{{/if}} blocks, which may nest.
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut stack: Vec<Frame> = Vec::new();
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut rest = source;
+        let mut line = 1;
+        let push =
+            |stack: &mut Vec<Frame>, nodes: &mut Vec<Node>, node: Node| match stack.last_mut() {
+                Some(frame) => frame
+                    .otherwise
+                    .as_mut()
+                    .unwrap_or(&mut frame.then)
+                    .push(node),
+                None => nodes.push(node),
+            };
+        while !rest.is_empty() {
+            let Some(start) = rest.find("{{") else {
+                push(&mut stack, &mut nodes, Node::Text(rest.to_string()));
+                break;
+            };
+            if start > 0 {
+                push(
+                    &mut stack,
+                    &mut nodes,
+                    Node::Text(rest[..start].to_string()),
+                );
+                line += rest[..start].matches('\n').count();
+            }
+            let after_open = &rest[start + 2..];
+            let Some(end) = after_open.find("}}") else {
+                return Err(error(line, "'{{' without a closing '}}'".into()));
+            };
+            let tag = after_open[..end].trim();
+            if let Some(name) = tag.strip_prefix("#if") {
+                let name = name.trim();
+                if !is_name(name) {
+                    return Err(error(line, format!("'{{{{#if {}}}}}' needs a name", name)));
+                }
+                stack.push(Frame {
+                    name: name.to_string(),
+                    line,
+                    then: Vec::new(),
+                    otherwise: None,
+                });
+            } else if tag == "else" {
+                match stack.last_mut() {
+                    Some(frame) if frame.otherwise.is_none() => frame.otherwise = Some(Vec::new()),
+                    Some(_) => return Err(error(line, "a second {{else}} in one {{#if}}".into())),
+                    None => return Err(error(line, "{{else}} outside of {{#if}}".into())),
+                }
+            } else if tag == "/if" {
+                let Some(frame) = stack.pop() else {
+                    return Err(error(line, "{{/if}} without an {{#if}}".into()));
+                };
+                let node = Node::If {
+                    name: frame.name,
+                    then: frame.then,
+                    otherwise: frame.otherwise.unwrap_or_default(),
+                    line: frame.line,
+                };
+                push(&mut stack, &mut nodes, node);
+            } else {
+                let node = parse_placeholder(tag, line)?;
+                push(&mut stack, &mut nodes, node);
+            }
+            line += after_open[..end].matches('\n').count();
+            rest = &after_open[end + 2..];
+        }
+        if let Some(frame) = stack.last() {
+            return Err(error(
+                frame.line,
+                format!(
+                    "{{{{#if {}}}}} is never closed with {{{{/if}}}}",
+                    frame.name
+                ),
+            ));
+        }
+        Ok(Template { nodes })
+    }
+
+    // Fill the template in. Every unknown name is collected so one error
+    // lists them all, with the names there are.
+    pub fn render(&self, variables: &Variables) -> Result<String> {
+        let mut out = String::new();
+        let mut unknown = BTreeSet::new();
+        render_nodes(&self.nodes, variables, &mut out, &mut unknown)?;
+        if !unknown.is_empty() {
+            return Err(CapstoneError::Template(format!(
+                "unknown placeholder(s) {}; available: {}",
+                unknown.into_iter().collect::<Vec<_>>().join(", "),
+                variables.keys().cloned().collect::<Vec<_>>().join(", ")
+            )));
+        }
+        Ok(out)
+    }
+}
+
+fn render_nodes(
+    nodes: &[Node],
+    variables: &Variables,
+    out: &mut String,
+    unknown: &mut BTreeSet<String>,
+) -> Result<()> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::If {
+                name,
+                then,
+                otherwise,
+                ..
+            } => {
+                let Some(value) = variables.get(name) else {
+                    unknown.insert(name.clone());
+                    continue;
+                };
+                let branch = if value.is_truthy() { then } else { otherwise };
+                render_nodes(branch, variables, out, unknown)?;
+            }
+            Node::Placeholder {
+                name,
+                filters,
+                line,
+            } => {
+                let Some(value) = variables.get(name) else {
+                    unknown.insert(name.clone());
+                    continue;
+                };
+                out.push_str(&apply(name, value, filters, *line)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn apply(name: &str, value: &Value, filters: &[Filter], line: usize) -> Result<String> {
+    let mut value = value.clone();
+    for filter in filters {
+        value = match (filter, value) {
+            (Filter::Join(separator), Value::List(items)) => Value::Text(items.join(separator)),
+            (Filter::Join(_), _) => {
+                return Err(error(
+                    line,
+                    format!("'join' needs a list, {} isn't one", name),
+                ))
+            }
+            (Filter::Default(fallback), Value::Null) => Value::Text(fallback.clone()),
+            (Filter::Default(_), value) => value,
+        };
+    }
+    match value {
+        Value::Text(text) => Ok(text),
+        Value::Bool(value) => Ok(value.to_string()),
+        Value::Null => Ok(String::new()),
+        Value::List(_) => Err(error(
+            line,
+            format!("{} is a list, render it with | join \", \"", name),
+        )),
+    }
+}
+
+pub fn load(path: &str) -> Result<Template> {
+    let source = files::read_to_string(path, "template")?;
+    Template::parse(&source).map_err(|e| match e {
+        CapstoneError::Template(message) => {
+            CapstoneError::Template(format!("{}: {}", path, message))
+        }
+        e => e,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(pairs: &[(&str, Value)]) -> Variables {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect()
+    }
+
+    fn render(source: &str, variables: &Variables) -> Result<String> {
+        Template::parse(source)?.render(variables)
+    }
+
+    fn template_error<T>(result: Result<T>) -> String {
+        match result {
+            Err(CapstoneError::Template(message)) => message,
+            Err(other) => panic!("expected a template error, got {}", other),
+            Ok(_) => panic!("expected a template error"),
+        }
+    }
+
+    #[test]
+    fn substitutes_placeholders() {
+        let vars = variables(&[
+            ("txid", Value::Text("abc".into())),
+            ("inputs", Value::List(vec!["x".into(), "y".into()])),
+            ("change.address", Value::Null),
+            ("confirmed", Value::Bool(true)),
+        ]);
+        assert_eq!(
+            render(
+                "id {{ txid }}, in {{inputs | join \" + \"}}, change {{change.address | default \"None\"}}{{change.address}}, {{confirmed}}\n",
+                &vars
+            )
+            .unwrap(),
+            "id abc, in x + y, change None, true\n"
+        );
+        // Escapes in filter arguments
+        assert_eq!(
+            render("{{inputs | join \"\\\"\\n\\t\\\\\"}}", &vars).unwrap(),
+            "x\"\n\t\\y"
+        );
+        assert_eq!(
+            template_error(render("{{inputs}}", &vars)),
+            "line 1: inputs is a list, render it with | join \", \""
+        );
+        assert_eq!(
+            template_error(render("\n{{txid | join \",\"}}", &vars)),
+            "line 2: 'join' needs a list, txid isn't one"
+        );
+        assert_eq!(
+            template_error(render("{{txid | upper \"x\"}}", &vars)),
+            "line 1: unknown filter 'upper' (expected join or default)"
+        );
+    }
+
+    #[test]
+    fn if_and_else_follow_truthiness() {
+        let source = "{{#if value}}yes{{else}}no{{/if}}";
+        let cases = [
+            (Value::Text(String::new()), "yes"),
+            (Value::List(vec!["a".into()]), "yes"),
+            (Value::List(Vec::new()), "no"),
+            (Value::Bool(true), "yes"),
+            (Value::Bool(false), "no"),
+            (Value::Null, "no"),
+        ];
+        for (value, expected) in cases {
+            let vars = variables(&[("value", value.clone())]);
+            assert_eq!(render(source, &vars).unwrap(), expected, "{:?}", value);
+        }
+        // Without {{else}} a false block renders nothing
+        let vars = variables(&[("value", Value::Null)]);
+        assert_eq!(render("a{{#if value}}b{{/if}}c", &vars).unwrap(), "ac");
+    }
+
+    #[test]
+    fn nested_blocks() {
+        let source =
+            "{{#if outer}}O{{#if inner}}I{{else}}i{{/if}}{{else}}o{{#if inner}}I{{/if}}{{/if}}";
+        let cases = [
+            (true, true, "OI"),
+            (true, false, "Oi"),
+            (false, true, "oI"),
+            (false, false, "o"),
+        ];
+        for (outer, inner, expected) in cases {
+            let vars = variables(&[("outer", Value::Bool(outer)), ("inner", Value::Bool(inner))]);
+            assert_eq!(render(source, &vars).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn malformed_blocks() {
+        let cases = [
+            (
+                "a\n{{#if open}}\nb\n",
+                "line 2: {{#if open}} is never closed with {{/if}}",
+            ),
+            (
+                "{{#if a}}{{#if b}}{{/if}}",
+                "line 1: {{#if a}} is never closed with {{/if}}",
+            ),
+            ("x\n\n{{/if}}", "line 3: {{/if}} without an {{#if}}"),
+            ("{{else}}", "line 1: {{else}} outside of {{#if}}"),
+            (
+                "{{#if a}}{{else}}\n{{else}}{{/if}}",
+                "line 2: a second {{else}} in one {{#if}}",
+            ),
+            ("{{#if}}{{/if}}", "line 1: '{{#if }}' needs a name"),
+            ("ok\n{{txid", "line 2: '{{' without a closing '}}'"),
+            (
+                "{{two words}}",
+                "line 1: 'two words' is not a placeholder name",
+            ),
+            (
+                "{{inputs | join \"x}}",
+                "line 1: unterminated \"quoted\" argument",
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(
+                template_error(Template::parse(source)),
+                expected,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_placeholders_are_listed_together() {
+        let vars = variables(&[("a", Value::Text("1".into())), ("b", Value::Bool(false))]);
+        assert_eq!(
+            template_error(render("{{nope}} {{a}} {{#if fee}}{{/if}} {{nope}}", &vars)),
+            "unknown placeholder(s) fee, nope; available: a, b"
+        );
+    }
+
+    #[test]
+    fn default_template_renders_the_golden_out_txt() {
+        let report = crate::report::tests::fixture();
+        let rendered = Template::parse(DEFAULT_TEMPLATE)
+            .unwrap()
+            .render(&report.variables())
+            .unwrap();
+        assert_eq!(rendered, include_str!("../tests/fixtures/out.txt"));
+    }
+}
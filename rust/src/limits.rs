@@ -0,0 +1,295 @@
+use crate::error::{CapstoneError, Result};
+use crate::fees;
+use bitcoincore_rpc::bitcoin::amount::serde::as_btc;
+use bitcoincore_rpc::bitcoin::Amount;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+
+// How close to -maxmempool counts as full: past this the node is about to
+// evict, and whatever it evicts sets the new floor
+const HEADROOM_PERCENT: u64 = 5;
+
+// getmempoolinfo. The mempool floor, mempoolminfee, rises above
+// minrelaytxfee after the node has evicted transactions to stay under
+// -maxmempool, and decays back once there is room.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MempoolInfo {
+    // false while the mempool is still being loaded from mempool.dat
+    #[serde(default)]
+    pub loaded: Option<bool>,
+    pub size: u64,
+    // Sum of virtual sizes
+    pub bytes: u64,
+    // Memory used, which is what -maxmempool limits
+    pub usage: u64,
+    #[serde(rename = "maxmempool")]
+    pub max_mempool: u64,
+    // BTC/kvB
+    #[serde(rename = "mempoolminfee", with = "as_btc")]
+    pub mempool_min_fee: Amount,
+    #[serde(rename = "minrelaytxfee", with = "as_btc")]
+    pub min_relay_tx_fee: Amount,
+    // Core 24+, taken from getnetworkinfo before that
+    #[serde(rename = "incrementalrelayfee", default, with = "as_btc::opt")]
+    pub incremental_relay_fee: Option<Amount>,
+}
+
+pub fn mempool_info(rpc: &Client) -> Result<MempoolInfo> {
+    let mut info: MempoolInfo = rpc.call("getmempoolinfo", &[])?;
+    if info.incremental_relay_fee.is_none() {
+        info.incremental_relay_fee = Some(rpc.get_network_info()?.incremental_fee);
+    }
+    Ok(info)
+}
+
+impl MempoolInfo {
+    pub fn percent_full(&self) -> u64 {
+        (self.usage * 100)
+            .checked_div(self.max_mempool)
+            .unwrap_or(0)
+    }
+
+    pub fn nearly_full(&self) -> bool {
+        self.percent_full() >= 100 - HEADROOM_PERCENT
+    }
+
+    // The lowest rate (sat/kvB) that gets in and stays in. Near the limit
+    // that's one incremental step over mempoolminfee: the node sets its floor
+    // to the evicted package's rate plus the incremental fee, and evicts the
+    // cheapest first, so paying the floor as it is now buys little.
+    pub fn floor(&self) -> u64 {
+        let floor = self.mempool_min_fee.max(self.min_relay_tx_fee).to_sat();
+        if self.nearly_full() {
+            floor + self.incremental_relay_fee.unwrap_or(Amount::ZERO).to_sat()
+        } else {
+            floor
+        }
+    }
+
+    // Why the floor is where it is
+    fn floor_reason(&self) -> String {
+        if self.nearly_full() {
+            format!(
+                "the mempool is {}% of its {} byte limit and about to evict",
+                self.percent_full(),
+                self.max_mempool
+            )
+        } else if self.mempool_min_fee > self.min_relay_tx_fee {
+            format!(
+                "mempoolminfee has risen above minrelaytxfee ({} sat/vB) after evictions",
+                fees::format_sat_per_vb(self.min_relay_tx_fee.to_sat())
+            )
+        } else {
+            "minrelaytxfee".to_string()
+        }
+    }
+
+    // The explanation when `sat_per_kvb`, paid by `what`, is under the floor
+    pub fn shortfall(&self, sat_per_kvb: u64, what: &str) -> Option<String> {
+        (sat_per_kvb < self.floor()).then(|| {
+            format!(
+                "{} pays {} sat/vB, the mempool floor is {} sat/vB: {}",
+                what,
+                fees::format_sat_per_vb(sat_per_kvb),
+                fees::format_sat_per_vb(self.floor()),
+                self.floor_reason()
+            )
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "Mempool: {} transaction(s), {} vbytes, {}% of {} bytes used; floor {} sat/vB ({})",
+            self.size,
+            self.bytes,
+            self.percent_full(),
+            self.max_mempool,
+            fees::format_sat_per_vb(self.floor()),
+            self.floor_reason()
+        )
+    }
+}
+
+// Decide the rate a payment goes out at. `sat_per_kvb` is the chosen rate,
+// None to leave it to the wallet, which already pays at least mempoolminfee
+// but doesn't know the mempool is about to evict. With --auto-fee a rate
+// under the floor is raised to it, otherwise the payment is refused.
+pub fn check(
+    info: &MempoolInfo,
+    sat_per_kvb: Option<u64>,
+    auto_fee: bool,
+    what: &str,
+) -> Result<Option<u64>> {
+    let shortfall = match sat_per_kvb {
+        Some(rate) => info.shortfall(rate, what),
+        None if info.nearly_full() => Some(format!(
+            "{} would leave the rate to the wallet, and {}",
+            what,
+            info.floor_reason()
+        )),
+        None => None,
+    };
+    match shortfall {
+        None => Ok(sat_per_kvb),
+        Some(_) if auto_fee => Ok(Some(info.floor())),
+        Some(reason) => Err(CapstoneError::MempoolLimit(format!(
+            "{}; pass --auto-fee to pay the floor, or --fee-rate {} or more",
+            reason,
+            fees::format_sat_per_vb(info.floor())
+        ))),
+    }
+}
+
+// Before sendrawtransaction: a signed transaction can't be bumped, so one
+// under the floor is refused with the floor rather than the node's
+// "mempool min fee not met".
+pub fn check_broadcast(rpc: &Client, hex: &str) -> Result<()> {
+    let info = mempool_info(rpc)?;
+    // Without pressure the floor is minrelaytxfee, and the node's own error
+    // already says so
+    if info.floor() <= info.min_relay_tx_fee.to_sat() {
+        return Ok(());
+    }
+    // A replacement's inputs are spent in the mempool already, gettxout
+    // can't price it; leave it to the node
+    let Ok((txid, rate)) = fees::transaction_fee_rate(rpc, hex) else {
+        return Ok(());
+    };
+    match info.shortfall(rate, &format!("transaction {}", txid)) {
+        None => Ok(()),
+        Some(reason) => Err(CapstoneError::MempoolLimit(format!(
+            "{}; build it again at a higher --fee-rate, or with --auto-fee",
+            reason
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mockrpc;
+    use serde_json::json;
+
+    // A 300 MB mempool using `usage` bytes, at `min_fee` BTC/kvB
+    fn info(usage: u64, min_fee: f64) -> MempoolInfo {
+        let mut info = mockrpc::mempool_info();
+        info["usage"] = json!(usage);
+        info["mempoolminfee"] = json!(min_fee);
+        serde_json::from_value(info).unwrap()
+    }
+
+    const MB: u64 = 1_000_000;
+
+    #[test]
+    fn deserializes_getmempoolinfo() {
+        let info = info(1024, 0.00001);
+        assert_eq!(info.loaded, Some(true));
+        assert_eq!(info.size, 1);
+        assert_eq!(info.bytes, 141);
+        assert_eq!(info.max_mempool, 300 * MB);
+        assert_eq!(info.mempool_min_fee, Amount::from_sat(1_000));
+        assert_eq!(info.min_relay_tx_fee, Amount::from_sat(1_000));
+        assert_eq!(info.incremental_relay_fee, Some(Amount::from_sat(1_000)));
+    }
+
+    #[test]
+    fn takes_the_incremental_fee_from_getnetworkinfo_before_core_24() {
+        let mut old = mockrpc::mempool_info();
+        let object = old.as_object_mut().unwrap();
+        for field in ["incrementalrelayfee", "fullrbf", "unbroadcastcount"] {
+            object.remove(field);
+        }
+        let mut network = mockrpc::network_info();
+        network["incrementalfee"] = json!(0.00002);
+        let (rpc, calls) = mockrpc::client(move |method, _| match method {
+            "getmempoolinfo" => Ok(old.clone()),
+            "getnetworkinfo" => Ok(network.clone()),
+            _ => panic!("unexpected {}", method),
+        });
+        let info = mempool_info(&rpc).unwrap();
+        assert_eq!(info.incremental_relay_fee, Some(Amount::from_sat(2_000)));
+        assert_eq!(*calls.lock().unwrap(), ["getmempoolinfo", "getnetworkinfo"]);
+    }
+
+    #[test]
+    fn the_floor_follows_mempool_pressure() {
+        let idle = info(MB, 0.00001);
+        assert_eq!(idle.percent_full(), 0);
+        assert!(!idle.nearly_full());
+        assert_eq!(idle.floor(), 1_000);
+
+        // Evictions raised mempoolminfee, but there is room again
+        let raised = info(200 * MB, 0.00003);
+        assert_eq!(raised.percent_full(), 66);
+        assert_eq!(raised.floor(), 3_000);
+
+        // Past 95% one incremental step is added on top
+        let full = info(290 * MB, 0.00003);
+        assert_eq!(full.percent_full(), 96);
+        assert!(full.nearly_full());
+        assert_eq!(full.floor(), 4_000);
+        assert!(info(285 * MB, 0.00001).nearly_full());
+        assert!(!info(284 * MB, 0.00001).nearly_full());
+
+        let mut unlimited = info(MB, 0.00001);
+        unlimited.max_mempool = 0;
+        assert_eq!(unlimited.percent_full(), 0);
+    }
+
+    #[test]
+    fn shortfalls_explain_the_floor() {
+        assert_eq!(info(MB, 0.00001).shortfall(1_000, "the payment"), None);
+        assert_eq!(
+            info(200 * MB, 0.00003).shortfall(2_000, "the payment").unwrap(),
+            "the payment pays 2 sat/vB, the mempool floor is 3 sat/vB: mempoolminfee has risen above minrelaytxfee (1 sat/vB) after evictions"
+        );
+        assert_eq!(
+            info(290 * MB, 0.00003).shortfall(3_000, "the payment").unwrap(),
+            "the payment pays 3 sat/vB, the mempool floor is 4 sat/vB: the mempool is 96% of its 300000000 byte limit and about to evict"
+        );
+        assert_eq!(
+            info(MB, 0.00001).describe(),
+            "Mempool: 1 transaction(s), 141 vbytes, 0% of 300000000 bytes used; floor 1 sat/vB (minrelaytxfee)"
+        );
+    }
+
+    #[test]
+    fn check_passes_raises_or_refuses() {
+        let raised = info(200 * MB, 0.00003);
+        let full = info(290 * MB, 0.00003);
+        // Enough, or left to the wallet with room in the mempool
+        assert_eq!(
+            check(&raised, Some(5_000), false, "x").unwrap(),
+            Some(5_000)
+        );
+        assert_eq!(check(&raised, None, false, "x").unwrap(), None);
+        // Under the floor: --auto-fee pays it
+        assert_eq!(check(&raised, Some(2_000), true, "x").unwrap(), Some(3_000));
+        assert_eq!(check(&full, None, true, "x").unwrap(), Some(4_000));
+        // or the payment is refused with the rate to use
+        let err = check(&raised, Some(2_000), false, "the payment").unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("; pass --auto-fee to pay the floor, or --fee-rate 3 or more"),
+            "{}",
+            err
+        );
+        let err = check(&full, None, false, "the payment").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("the payment would leave the rate to the wallet, and the mempool is 96%"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn broadcasts_without_pressure_are_left_to_the_node() {
+        let (rpc, calls) = mockrpc::client(|method, _| match method {
+            "getmempoolinfo" => Ok(mockrpc::mempool_info()),
+            _ => panic!("unexpected {}", method),
+        });
+        check_broadcast(&rpc, "00").unwrap();
+        assert_eq!(*calls.lock().unwrap(), ["getmempoolinfo"]);
+    }
+}
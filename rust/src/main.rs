@@ -1,209 +1,227 @@
 #![allow(unused)]
-use bitcoin::hex::DisplayHex;
-use bitcoincore_rpc::bitcoin::Amount;
-use bitcoincore_rpc::{Auth, Client, RpcApi};
-use serde::Deserialize;
-use serde_json::json;
-use std::fs::File;
-use std::io::Write;
-use std::collections::HashSet;
+mod accept;
+mod automine;
+mod block;
+mod btc;
+mod bump;
+mod chain;
+mod chainstats;
+mod chaintime;
+mod cli;
+mod coinbase;
+mod coinjoin;
+mod conflict;
+mod console;
+mod csvreport;
+#[cfg(feature = "sqlite")]
+mod db;
+mod decode;
+mod deployments;
+mod dust;
+mod error;
+mod estimate;
+mod family;
+mod fees;
+mod files;
+mod filters;
+mod finalize;
+mod fund;
+mod grouping;
+mod halving;
+mod history;
+mod htmlreport;
+mod inputs;
+mod layout;
+mod limits;
+mod maturity;
+mod mempool;
+mod mempooldiff;
+mod metrics;
+mod mine;
+#[cfg(test)]
+mod mockrpc;
+mod multisig;
+mod nodeinfo;
+mod notify;
+mod payment;
+mod pending;
+mod policy;
+mod prevout;
+mod prioritise;
+mod proof;
+mod propagation;
+mod prune;
+mod psbt;
+mod raw;
+mod reconcile;
+mod relay;
+mod reorg;
+mod report;
+mod runinfo;
+mod session;
+mod signing;
+mod snapshot;
+mod split;
+mod stats;
+mod stress;
+mod template;
+mod tips;
+mod txidlog;
+mod utxo;
+mod vault;
+mod verify;
+mod version;
+mod watch;
+#[cfg(feature = "zmq")]
+mod zmq;
+
+use cli::{ChangeType, Command, Config};
+use console::say;
+use error::{CapstoneError, Result};
+use session::Session;
+use std::process::ExitCode;
+use version::Capability;
 
 // Node access params
 const RPC_URL: &str = "http://127.0.0.1:18443"; // Default regtest RPC port
 const RPC_USER: &str = "alice";
 const RPC_PASS: &str = "password";
 
-// You can use calls not provided in RPC lib API using the generic `call` function.
-// An example of using the `send` RPC call, which doesn't have exposed API.
-// You can also use serde_json `Deserialize` derivation to capture the returned json result.
-fn send(rpc: &Client, addr: &str) -> bitcoincore_rpc::Result<String> {
-    let args = [
-        json!([{addr : 100 }]), // recipient address
-        json!(null),            // conf target
-        json!(null),            // estimate mode
-        json!(null),            // fee rate in sats/vb
-        json!(null),            // Empty option object
-    ];
+// Exit status after --export-psbt, so scripts can tell "go sign this" from success and failure
+const AWAITING_SIGNATURE_EXIT: u8 = 2;
 
-    #[derive(Deserialize)]
-    struct SendResult {
-        complete: bool,
-        txid: String,
-    }
-    let send_result = rpc.call::<SendResult>("send", &args)?;
-    assert!(send_result.complete);
-    Ok(send_result.txid)
-}
-
-// Helper function
-fn create_or_load_wallet(rpc: &Client, wallet_name: &str) -> bitcoincore_rpc::Result<()> {
-        // check if the wallet is loaded before
-        if rpc.list_wallets()?.contains(&wallet_name.to_string()) {
-            println!("wallet {} is already loaded", wallet_name);
-            return Ok(());
+fn main() -> ExitCode {
+    match parse_and_run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e @ CapstoneError::AwaitingSignature(_)) => {
+            say!("{}", e);
+            ExitCode::from(AWAITING_SIGNATURE_EXIT)
         }
-
-        // if it is not loaded before
-        match rpc.load_wallet(wallet_name) {
-            Ok(_) => {
-                    println!("Successfully loaded existing wallet '{}' from disk.", wallet_name);
-                    Ok(())
-                }
-            // If loading fails because it doesn't exist, create it.
-            Err(e) => {
-                println!("Wallet '{}' not found on disk. Creating a new one.", wallet_name);
-                rpc.create_wallet(wallet_name, None, None, None, None)?;
-                println!("Wallet '{}' created successfully.", wallet_name);
-                Ok(())
-            }
-
-             // Handle other potential errors during loading.
-            Err(e) => Err(e)
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
         }
     }
+}
 
-fn main() -> bitcoincore_rpc::Result<()> {
-    // Connect to Bitcoin Core RPC
-    let rpc = Client::new(
-        RPC_URL,
-        Auth::UserPass(RPC_USER.to_owned(), RPC_PASS.to_owned()),
-    )?;
-
-    // Get blockchain info
-    let blockchain_info = rpc.get_blockchain_info()?;
-    println!("Blockchain Info: {:?}", blockchain_info);
-
-    // Create/Load the wallets, named 'Miner' and 'Trader'. Have logic to optionally create/load them if they do not exist or not loaded already.
-    let miner_wallet_name = "Miner";
-    let trader_wallet_name = "Trader";
-    create_or_load_wallet(&rpc, miner_wallet_name)?;
-    create_or_load_wallet(&rpc, trader_wallet_name)?;
-
-    // We create wallet-specific RPC clients url for easier management.
-    println!("Creating wallet-specific RPC clients...");
-    let miner_auth = Auth::UserPass(RPC_USER.to_owned(), RPC_PASS.to_owned());
-    let miner_rpc = Client::new(&format!("{}/wallet/{}", RPC_URL, "Miner"), miner_auth)?;
-    
-    let trader_auth = Auth::UserPass(RPC_USER.to_owned(), RPC_PASS.to_owned());
-    let trader_rpc = Client::new(&format!("{}/wallet/{}", RPC_URL, "Trader"), trader_auth)?;
-    
-    println!("'Miner' and 'Trader' wallets are ready.");
-
+fn parse_and_run() -> Result<()> {
+    let config = cli::parse_args(std::env::args().skip(1))?;
+    run(&config)
+}
 
-    // Generate spendable balances in the Miner wallet. How many blocks needs to be mined?
-    let miner_address = miner_rpc.get_new_address(None, None)?.assume_checked();
-    let initial_balance = miner_rpc.get_balance(None, None)?;
-    if initial_balance < Amount::from_btc(50.0)? {
-        println!("Miner balance is low. Mining 101 blocks to mature coinbase rewards...");
-        let block_hashes = miner_rpc.generate_to_address(101, &miner_address)?;
-        println!("Mined {} blocks. First new block hash: {}", block_hashes.len(), block_hashes[0]);
-    } else {
-        println!("Miner already has a sufficient balance.");
+fn run(config: &Config) -> Result<()> {
+    console::set_report_only(config.no_file);
+    files::set_strict(config.strict_paths);
+    console::set_verbose(config.verbose);
+    runinfo::start();
+    btc::set_units(config.units);
+    if let Some(address) = &config.metrics_addr {
+        metrics::serve(address)?;
     }
-    let balance = miner_rpc.get_balance(None, None)?;
-    println!("Miner wallet balance: {} BTC", balance.to_btc());
-
-    // Load Trader wallet and generate a new address
-    let trader_address = trader_rpc.get_new_address(None, None)?.assume_checked();
-    println!("Generated new address for Trader: {}", trader_address);
-
-    // Send 20 BTC from Miner to Trader
-    let amount_to_send = Amount::from_btc(20.0)?;
-    println!("Sending {} BTC from Miner to Trader...", amount_to_send.to_btc());
-    let txid = miner_rpc.send_to_address(&trader_address, amount_to_send, None, None, None, None, None, None)?;
-    println!("Transaction sent! TXID: {}", txid);
-
-    // Check transaction in mempool
-    let mempool = rpc.get_raw_mempool()?;
-    if mempool.contains(&txid) {
-        println!("Success! Transaction {} found in mempool.", txid);
-    } else {
-        println!("Error! Transaction not found in mempool.");
-        // This would be an unexpected error in this script.
+    if let Some(path) = &config.txid_log {
+        txidlog::open(path)?;
     }
-
-    // Mine 1 block to confirm the transaction
-    let block_hash = miner_rpc.generate_to_address(1, &miner_address)?[0];
-    println!("Block {} mined, confirming the transaction.", block_hash);
-
-    // Extract all required transaction details
-    let tx_info = rpc.get_raw_transaction_info(&txid, Some(&block_hash))?;
-    println!("Successfully fetched confirmed transaction details.");
-
-    // 1. Get block details
-    let block_header_info = rpc.get_block_header_info(&tx_info.blockhash.unwrap())?;
-    let block_height = block_header_info.height as u64;
-
-    // 2. Calculate total input value and find input addresses
-    let mut total_input_value = Amount::ZERO;
-    let mut miner_input_addresses = HashSet::new(); // Use a HashSet to store unique addresses
-
-    for vin in &tx_info.vin {
-        if let (Some(prev_txid), Some(prev_vout)) = (vin.txid, vin.vout) {
-            // Fetch the previous transaction that this input is spending from
-            let prev_tx_info = rpc.get_raw_transaction_info(&prev_txid, None)?;
-            let spent_output = &prev_tx_info.vout[prev_vout as usize];
-            
-            total_input_value += spent_output.value;
-            if let Some(address) = &spent_output.script_pub_key.address {
-                miner_input_addresses.insert(address.clone());
-            }
-        }
+    // A template that doesn't parse fails now rather than after the payment
+    if let Some(path) = &config.template {
+        layout::load(path)?;
     }
-    let input_addresses_str = miner_input_addresses.iter().map(|a| a.clone().assume_checked().to_string()).collect::<Vec<_>>().join(", ");
-
-
-    // 3. Calculate total output value and identify Trader/Change outputs
-    let mut total_output_value = Amount::ZERO;
-    let mut trader_output = None;
-    let mut miner_change_output = None;
-
-     for vout in &tx_info.vout {
-        total_output_value += vout.value;
-        // Use `if let` to safely unwrap the address from the output
-        if let Some(output_address) = &vout.script_pub_key.address {
-            // Now, compare the inner values after converting the unchecked one
-            if output_address.clone().assume_checked() == trader_address {
-                trader_output = Some((vout.value, output_address.clone()));
-            } else {
-                miner_change_output = Some((vout.value, output_address.clone()));
-            }
-        }
+    let mut session = Session::connect()?;
+    tips::check(&session.rpc, "at startup")?;
+    if !config.command.is_read_only() {
+        snapshot::require_validated(&session.rpc)?;
     }
-
-    // 4. Calculate fees
-    let transaction_fee = total_input_value - total_output_value;
-
-    // Write the data to ../out.txt in the specified format given in readme.md
-    let mut output_string = String::new();
-    
-    output_string.push_str(&format!("Transaction ID (txid): {}\n", tx_info.txid));
-    output_string.push_str(&format!("Miner's Input Address: {}\n", input_addresses_str));
-    output_string.push_str(&format!("Miner's Input Amount (in BTC): {}\n", total_input_value.to_btc()));
-
-    if let Some((amount, address)) = trader_output {
-        output_string.push_str(&format!("Trader's Output Address: {}\n", address.assume_checked()));
-        output_string.push_str(&format!("Trader's Output Amount (in BTC): {}\n", amount.to_btc()));
+    if config.command.resubmits_pending() {
+        pending::resubmit(&session)?;
     }
-
-    if let Some((amount, address)) = miner_change_output {
-        output_string.push_str(&format!("Miner's Change Address: {}\n", address.assume_checked()));
-        output_string.push_str(&format!("Miner's Change Amount (in BTC): {}\n", amount.to_btc()));
-    } else {
-        output_string.push_str("Miner's Change Address: None\n");
-        output_string.push_str("Miner's Change Amount (in BTC): 0.0\n");
+    // bech32m change is a taproot output
+    if config.change_type == Some(ChangeType::Bech32m) {
+        version::require(&session.rpc, Capability::TaprootWallet)?;
+        deployments::require_active(&session.rpc, "taproot")?;
+    }
+    session.empty_blocks = config.empty_blocks;
+    session.zmq = config.zmq.clone();
+    if config.mining_wallet {
+        session.use_mining_wallet()?;
+    }
+    if let Some(count) = config.rotate_mining_addresses {
+        session.rotate_mining_addresses(count)?;
+    }
+    if let Some(descriptor) = &config.mine_to_descriptor {
+        session.mine_to_descriptor(descriptor)?;
+    }
+    if config.mocktime.is_some() || config.block_interval.is_some() {
+        session.set_mock_time(config.mocktime, config.block_interval())?;
     }
 
-    output_string.push_str(&format!("Transaction Fees (in BTC): {}\n", transaction_fee.to_btc()));
-    output_string.push_str(&format!("Block height at which the transaction is confirmed: {}\n", block_height));
-    output_string.push_str(&format!("Block hash at which the transaction is confirmed: {}\n", tx_info.blockhash.unwrap()));
-    
-    let file_path = "../out.txt";
-    let mut file = File::create(file_path)?;
-    file.write_all(output_string.as_bytes())?;
-    println!("Successfully wrote transaction details to {}", file_path);
-
-    println!("\n--- Content of out.txt ---\n{}", output_string);
-
+    let result = match &config.command {
+        Command::Pay => payment::run(&session, config),
+        Command::ChainSpend { count } => chain::run(&session, config, *count),
+        Command::Split {
+            wallet,
+            count,
+            amount,
+        } => split::run(&session, config, wallet, *count, *amount),
+        Command::FinalizePsbt { path } => finalize::run(&session, config, path),
+        Command::InspectPsbt { source } => psbt::run_inspect(&session.rpc, source),
+        Command::CombinePsbt { paths } => finalize::run_combine(&session, config, paths),
+        Command::CoinjoinDemo => coinjoin::run(&session, config),
+        Command::MultisigAddress { required, keys } => {
+            multisig::run(&session, *required, keys, config.register.as_deref())
+        }
+        Command::ToPsbt { source } => finalize::run_to_psbt(&session, config, source),
+        Command::PolicyDemo { policy } => policy::run(&session, config, policy),
+        Command::VaultDemo { unlock_height } => vault::run(&session, config, *unlock_height),
+        Command::Stress { count } => stress::run(&session, config, *count),
+        Command::ConflictDemo => conflict::run(&session, config),
+        Command::Estimate { recipients } => estimate::run(&session, config, recipients),
+        Command::MineWith { transactions } => mine::run(&session, config, transactions),
+        Command::ReorgDemo => reorg::run(&session, config),
+        Command::HalvingDemo => halving::run(&session, config),
+        Command::ChainStats { window } => chainstats::run(&session, *window),
+        Command::VerifyChain {
+            checklevel,
+            nblocks,
+        } => verify::run(&session, *checklevel, *nblocks),
+        Command::Template { txid } => template::run(&session, txid.as_deref()),
+        Command::Maturity { wallet } => maturity::run(&session, wallet),
+        Command::LoadSnapshot { path } => snapshot::run(&session, config, path),
+        Command::BlockAtTime { time } => chaintime::run(&session, time),
+        Command::Automine { interval } => automine::run(&session, config, *interval),
+        Command::Mempool => mempool::run(&session.rpc, config),
+        Command::MempoolSnapshot { out } => mempooldiff::run_snapshot(&session.rpc, out),
+        Command::MempoolDiff { old, new } => {
+            mempooldiff::run_diff(&session.rpc, old, new.as_deref())
+        }
+        Command::Prioritise { txid, fee_delta } => prioritise::run(&session, txid, *fee_delta),
+        Command::PrioritiseDemo => prioritise::run_demo(&session, config),
+        Command::FilterCheck { address, from } => filters::run(&session, config, address, *from),
+        Command::PropagationTest { peer_rpc } => propagation::run(&session, config, peer_rpc),
+        Command::NodeInfo => nodeinfo::run(&session.rpc),
+        Command::HistoryShow { path, last } => history::run_show(path, *last),
+        #[cfg(feature = "sqlite")]
+        Command::DbQuery { path, last } => db::run_query(path, *last),
+        #[cfg(feature = "sqlite")]
+        Command::DbStats { path } => db::run_stats(path),
+        // cli::parse_args refuses --db, which these need, without the feature
+        #[cfg(not(feature = "sqlite"))]
+        Command::DbQuery { .. } | Command::DbStats { .. } => Err(CapstoneError::Usage(
+            "db needs a build with the sqlite feature (cargo build --features sqlite)".into(),
+        )),
+        Command::Watch { txid } => {
+            watch::run(&session, config, &txidlog::txids(txid.as_deref(), config)?)
+        }
+        Command::Report { txid } => {
+            watch::run_report(&session, config, &txidlog::txids(txid.as_deref(), config)?)
+        }
+        Command::TxFamily { txid } => family::run(&session.rpc, config, txid),
+        Command::EnrichPsbt { path } => {
+            psbt::run_enrich(&session.rpc, &session.miner_rpc, config, path)
+        }
+    };
+    result?;
+    // Replacement and reorg experiments leave their traces in the wallets
+    if !config.command.is_read_only() {
+        reconcile::report(&session)?;
+    }
     Ok(())
 }
@@ -0,0 +1,127 @@
+use crate::btc;
+use crate::console::say;
+use crate::error::Result;
+use crate::session::{self, Session, COINBASE_MATURITY};
+use bitcoincore_rpc::bitcoin::{Amount, OutPoint};
+use bitcoincore_rpc::json::GetTransactionResultDetailCategory;
+use bitcoincore_rpc::{Client, RpcApi};
+
+// listtransactions page size; mining wallets easily hold hundreds of coinbases
+const PAGE_SIZE: usize = 100;
+
+// A coinbase output the wallet can't spend yet.
+#[derive(Debug, Clone)]
+pub struct ImmatureCoinbase {
+    pub outpoint: OutPoint,
+    pub amount: Amount,
+    // Height of the block the coinbase is in
+    pub height: u64,
+    // First tip height at which the wallet can spend it
+    pub mature_at: u64,
+}
+
+impl ImmatureCoinbase {
+    // Blocks still to be mined on top of `tip` before this output is spendable
+    pub fn blocks_left(&self, tip: u64) -> u64 {
+        self.mature_at.saturating_sub(tip)
+    }
+}
+
+// Every immature coinbase output of the wallet, earliest to mature first.
+// listtransactions is walked a page at a time; the wallet reports the block
+// height on Core 22+, older nodes need the header looked up.
+pub fn immature_coinbases(rpc: &Client, wallet_rpc: &Client) -> Result<Vec<ImmatureCoinbase>> {
+    let mut coinbases = Vec::new();
+    let mut skip = 0;
+    loop {
+        let page =
+            wallet_rpc.list_transactions(Some("*"), Some(PAGE_SIZE), Some(skip), Some(true))?;
+        for tx in &page {
+            if tx.detail.category != GetTransactionResultDetailCategory::Immature {
+                continue;
+            }
+            let height = match (tx.info.blockheight, &tx.info.blockhash) {
+                (Some(height), _) => height as u64,
+                (None, Some(block_hash)) => rpc.get_block_header_info(block_hash)?.height as u64,
+                // Coinbases are always in a block; a reorg may have just removed it
+                (None, None) => continue,
+            };
+            coinbases.push(ImmatureCoinbase {
+                outpoint: OutPoint::new(tx.info.txid, tx.detail.vout),
+                amount: Amount::from_sat(tx.detail.amount.to_sat().unsigned_abs()),
+                height,
+                mature_at: height + COINBASE_MATURITY,
+            });
+        }
+        if page.len() < PAGE_SIZE {
+            break;
+        }
+        skip += PAGE_SIZE;
+    }
+    coinbases.sort_by_key(|coinbase| (coinbase.mature_at, coinbase.outpoint));
+    Ok(coinbases)
+}
+
+// Blocks to mine on top of `tip` so that `shortfall` more becomes spendable:
+// when the immature coinbases cover it, just until the last one needed
+// matures; otherwise enough new `subsidy` rewards for the rest, plus their
+// maturity (which every older coinbase reaches first).
+pub fn blocks_needed(
+    coinbases: &[ImmatureCoinbase],
+    tip: u64,
+    shortfall: Amount,
+    subsidy: Amount,
+) -> u64 {
+    let mut maturing = Amount::ZERO;
+    for coinbase in coinbases {
+        maturing += coinbase.amount;
+        if maturing >= shortfall {
+            return coinbase.blocks_left(tip);
+        }
+    }
+    let rewards = (shortfall - maturing)
+        .to_sat()
+        .div_ceil(subsidy.to_sat().max(1));
+    rewards + COINBASE_MATURITY
+}
+
+// maturity: list the wallet's immature coinbases and when each can be spent.
+pub fn run(session: &Session, wallet_name: &str) -> Result<()> {
+    let other;
+    let wallet_rpc = match wallet_name {
+        "Miner" => &session.miner_rpc,
+        "Trader" => &session.trader_rpc,
+        _ => {
+            other = session::wallet_client(wallet_name)?;
+            &other
+        }
+    };
+    let tip = session.rpc.get_block_count()?;
+    let coinbases = immature_coinbases(&session.rpc, wallet_rpc)?;
+
+    if coinbases.is_empty() {
+        say!("{} has no immature coinbase outputs.", wallet_name);
+        return Ok(());
+    }
+    say!(
+        "Immature coinbase outputs of {} at height {}:",
+        wallet_name,
+        tip
+    );
+    for coinbase in &coinbases {
+        say!(
+            "{}: {} mined at height {}, spendable at height {} ({} more blocks)",
+            coinbase.outpoint,
+            btc::amount(coinbase.amount),
+            coinbase.height,
+            coinbase.mature_at,
+            coinbase.blocks_left(tip)
+        );
+    }
+    let total: Amount = coinbases.iter().map(|coinbase| coinbase.amount).sum();
+    let last = coinbases
+        .last()
+        .map_or(0, |coinbase| coinbase.blocks_left(tip));
+    say!("{} will mature within {} blocks.", btc::amount(total), last);
+    Ok(())
+}
@@ -0,0 +1,872 @@
+use crate::accept;
+use crate::btc;
+use crate::chaintime;
+use crate::cli::Config;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::fees;
+use bitcoincore_rpc::bitcoin::hex::DisplayHex;
+use bitcoincore_rpc::bitcoin::{Amount, Txid};
+use bitcoincore_rpc::jsonrpc::error::Error as JsonRpcError;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// getmempoolentry's "Transaction not in mempool"
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
+// getmempoolentry's response, the fields the package feerates and the report
+// use. Sizes are virtual sizes; the ancestor and descendant sets include the
+// transaction itself.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MempoolEntry {
+    pub vsize: u64,
+    pub weight: u64,
+    // When it entered this node's mempool, unix seconds
+    pub time: u64,
+    // Not yet announced to (or not yet requested by) any peer; Core 21+
+    #[serde(default)]
+    pub unbroadcast: Option<bool>,
+    #[serde(rename = "bip125-replaceable", default)]
+    pub bip125_replaceable: bool,
+    // Unconfirmed parents, and the mempool transactions spending this one
+    #[serde(default)]
+    pub depends: Vec<Txid>,
+    #[serde(rename = "spentby", default)]
+    pub spent_by: Vec<Txid>,
+    #[serde(rename = "ancestorcount")]
+    pub ancestor_count: u64,
+    #[serde(rename = "ancestorsize")]
+    pub ancestor_size: u64,
+    #[serde(rename = "descendantcount")]
+    pub descendant_count: u64,
+    #[serde(rename = "descendantsize")]
+    pub descendant_size: u64,
+    pub fees: MempoolFees,
+}
+
+// In BTC here, unlike getblockstats' satoshis. `modified` includes any
+// prioritisetransaction delta.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MempoolFees {
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub base: Amount,
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub modified: Amount,
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub ancestor: Amount,
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub descendant: Amount,
+}
+
+// sat/kvB, rounded down like the rest of the fee code. A zero size can't come
+// from the node but would otherwise divide by zero.
+pub fn fee_rate(fee: Amount, vsize: u64) -> u64 {
+    fee.to_sat() * 1000 / vsize.max(1)
+}
+
+impl MempoolEntry {
+    // The transaction on its own
+    pub fn individual_fee_rate(&self) -> u64 {
+        fee_rate(self.fees.base, self.vsize)
+    }
+
+    // What a miner gets for including it together with its unconfirmed ancestors
+    pub fn ancestor_fee_rate(&self) -> u64 {
+        fee_rate(self.fees.ancestor, self.ancestor_size)
+    }
+
+    pub fn descendant_fee_rate(&self) -> u64 {
+        fee_rate(self.fees.descendant, self.descendant_size)
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{} sat/vB alone, {} sat/vB with {} ancestor(s) ({} vB), {} sat/vB with {} descendant(s) ({} vB)",
+            fees::format_sat_per_vb(self.individual_fee_rate()),
+            fees::format_sat_per_vb(self.ancestor_fee_rate()),
+            self.ancestor_count - 1,
+            self.ancestor_size,
+            fees::format_sat_per_vb(self.descendant_fee_rate()),
+            self.descendant_count - 1,
+            self.descendant_size
+        )
+    }
+}
+
+pub fn get_mempool_entry(rpc: &Client, txid: &Txid) -> Result<MempoolEntry> {
+    Ok(rpc.call("getmempoolentry", &[json!(txid)])?)
+}
+
+// `get_mempool_entry`, None rather than an error when `txid` isn't in the mempool
+pub fn find_mempool_entry(rpc: &Client, txid: &Txid) -> Result<Option<MempoolEntry>> {
+    match get_mempool_entry(rpc, txid) {
+        Ok(entry) => Ok(Some(entry)),
+        Err(CapstoneError::Rpc(bitcoincore_rpc::Error::JsonRpc(JsonRpcError::Rpc(e))))
+            if e.code == RPC_INVALID_ADDRESS_OR_KEY =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Our transaction as the mempool saw it between broadcast and confirmation.
+#[derive(Debug, Clone)]
+pub enum MempoolSnapshot {
+    Entry(MempoolEntry),
+    // It was mined before getmempoolentry got to it
+    ConfirmedFirst,
+}
+
+// Capture `txid`'s mempool entry, None while it isn't there. If it has
+// already left the mempool because it confirmed (`wallet_rpc` is a wallet that
+// knows it, so this works without -txindex), that's recorded rather than
+// treated as missing.
+pub fn snapshot(rpc: &Client, wallet_rpc: &Client, txid: &Txid) -> Result<Option<MempoolSnapshot>> {
+    if let Some(entry) = find_mempool_entry(rpc, txid)? {
+        return Ok(Some(MempoolSnapshot::Entry(entry)));
+    }
+    if wallet_rpc.get_transaction(txid, None)?.info.confirmations > 0 {
+        Ok(Some(MempoolSnapshot::ConfirmedFirst))
+    } else {
+        Ok(None)
+    }
+}
+
+// Wait up to `timeout` for a transaction just broadcast to show up in the
+// mempool (or confirm), checking every `poll`: a loaded node can take a moment
+// to accept it. If it never does, testmempoolaccept on the wallet's copy says
+// why.
+pub fn wait_for_acceptance(
+    rpc: &Client,
+    wallet_rpc: &Client,
+    txid: &Txid,
+    poll: Duration,
+    timeout: Duration,
+) -> Result<MempoolSnapshot> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(snapshot) = snapshot(rpc, wallet_rpc, txid)? {
+            return Ok(snapshot);
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        thread::sleep(poll.min(remaining));
+    }
+
+    let hex = wallet_rpc
+        .get_transaction(txid, None)?
+        .hex
+        .to_lower_hex_string();
+    // No feerate cap: only why the node won't take it matters here
+    let results = accept::test_mempool_accept(rpc, &[hex], Amount::ZERO)?;
+    let reason = match results.first() {
+        Some(result) if !result.is_allowed() => result.rejection(),
+        _ => "testmempoolaccept would accept it now, it may have been evicted or replaced".into(),
+    };
+    Err(CapstoneError::NotInMempool {
+        txid: *txid,
+        waited: timeout.as_secs(),
+        reason,
+    })
+}
+
+impl MempoolSnapshot {
+    pub fn describe(&self) -> Vec<String> {
+        let MempoolSnapshot::Entry(entry) = self else {
+            return vec!["Mempool entry: confirmed before mempool snapshot".to_string()];
+        };
+        vec![
+            format!(
+                "Mempool entry size: {} vB, {} WU",
+                entry.vsize, entry.weight
+            ),
+            format!("Mempool entry time: {}", chaintime::describe(entry.time)),
+            format!(
+                "Mempool entry fees (in {}): base {}, modified {}, ancestor {}, descendant {}",
+                btc::unit(),
+                btc::format(entry.fees.base),
+                btc::format(entry.fees.modified),
+                btc::format(entry.fees.ancestor),
+                btc::format(entry.fees.descendant)
+            ),
+            format!(
+                "Mempool entry ancestors / descendants: {} / {}",
+                entry.ancestor_count, entry.descendant_count
+            ),
+            format!(
+                "Mempool entry unbroadcast: {}",
+                entry
+                    .unbroadcast
+                    .map_or("unknown".to_string(), |unbroadcast| unbroadcast.to_string())
+            ),
+        ]
+    }
+}
+
+// getrawmempool verbose: every mempool transaction's entry.
+pub fn get_raw_mempool_verbose(rpc: &Client) -> Result<HashMap<Txid, MempoolEntry>> {
+    Ok(rpc.call("getrawmempool", &[json!(true)])?)
+}
+
+// How many of the highest-feerate transactions the summary lists
+const TOP_TRANSACTIONS: usize = 10;
+
+// The mempool at a glance. Feerates are sat/kvB of each transaction alone.
+#[derive(Debug, Clone)]
+pub struct MempoolSummary {
+    pub count: usize,
+    pub total_vsize: u64,
+    pub total_fees: Amount,
+    // 10th, 50th and 90th percentile feerates, None for an empty mempool
+    pub percentiles: Option<(u64, u64, u64)>,
+    // Highest feerate first
+    pub top: Vec<(Txid, u64)>,
+    pub replaceable: usize,
+}
+
+// Nearest-rank percentile of sorted `values`
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+pub fn summarize(entries: &HashMap<Txid, MempoolEntry>) -> MempoolSummary {
+    let mut rates: Vec<(Txid, u64)> = entries
+        .iter()
+        .map(|(txid, entry)| (*txid, entry.individual_fee_rate()))
+        .collect();
+    // Ties broken by txid so the listing is stable
+    rates.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let mut sorted: Vec<u64> = rates.iter().map(|(_, rate)| *rate).collect();
+    sorted.reverse();
+    MempoolSummary {
+        count: entries.len(),
+        total_vsize: entries.values().map(|entry| entry.vsize).sum(),
+        total_fees: entries.values().map(|entry| entry.fees.base).sum(),
+        percentiles: (!sorted.is_empty()).then(|| {
+            (
+                percentile(&sorted, 10),
+                percentile(&sorted, 50),
+                percentile(&sorted, 90),
+            )
+        }),
+        top: rates.into_iter().take(TOP_TRANSACTIONS).collect(),
+        replaceable: entries
+            .values()
+            .filter(|entry| entry.bip125_replaceable)
+            .count(),
+    }
+}
+
+impl MempoolSummary {
+    // "mempool: 37 txs, median 2.1 sat/vB"
+    pub fn one_line(&self) -> String {
+        match self.percentiles {
+            Some((_, median, _)) => format!(
+                "mempool: {} txs, median {} sat/vB",
+                self.count,
+                fees::format_sat_per_vb(median)
+            ),
+            None => "mempool: empty".to_string(),
+        }
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("Transactions: {}", self.count),
+            format!("Total size: {} vB", self.total_vsize),
+            format!(
+                "Total fees (in {}): {}",
+                btc::unit(),
+                btc::format(self.total_fees)
+            ),
+        ];
+        if let Some((p10, p50, p90)) = self.percentiles {
+            lines.push(format!(
+                "Feerate p10 / p50 / p90: {} / {} / {} sat/vB",
+                fees::format_sat_per_vb(p10),
+                fees::format_sat_per_vb(p50),
+                fees::format_sat_per_vb(p90)
+            ));
+        }
+        lines.push(format!(
+            "Signaling RBF: {} of {}",
+            self.replaceable, self.count
+        ));
+        for (txid, rate) in &self.top {
+            lines.push(format!(
+                "  {} sat/vB  {}",
+                fees::format_sat_per_vb(*rate),
+                txid
+            ));
+        }
+        lines
+    }
+}
+
+// Upper edges (sat/vB, inclusive) of the histogram buckets unless
+// --histogram-edges is given; a last bucket takes everything above.
+pub const DEFAULT_HISTOGRAM_EDGES: [u64; 5] = [1, 2, 5, 10, 20];
+// Width of the longest bar
+const BAR_WIDTH: usize = 40;
+
+#[derive(Debug, Clone)]
+pub struct HistogramBucket {
+    // "3-5", "21+", ...
+    pub label: String,
+    pub count: usize,
+    pub vsize: u64,
+}
+
+// The mempool's transactions bucketed by feerate: each one's own, or with
+// `ancestor` that of its package with its unconfirmed ancestors, which is what
+// block templates go by.
+#[derive(Debug, Clone)]
+pub struct FeerateHistogram {
+    pub ancestor: bool,
+    pub buckets: Vec<HistogramBucket>,
+}
+
+pub fn histogram(
+    entries: &HashMap<Txid, MempoolEntry>,
+    edges: &[u64],
+    ancestor: bool,
+) -> FeerateHistogram {
+    let mut buckets: Vec<HistogramBucket> = Vec::with_capacity(edges.len() + 1);
+    let mut lower = 1;
+    for edge in edges {
+        let label = if lower >= *edge {
+            edge.to_string()
+        } else {
+            format!("{}-{}", lower, edge)
+        };
+        buckets.push(HistogramBucket {
+            label,
+            count: 0,
+            vsize: 0,
+        });
+        lower = edge + 1;
+    }
+    buckets.push(HistogramBucket {
+        label: format!("{}+", lower),
+        count: 0,
+        vsize: 0,
+    });
+
+    for entry in entries.values() {
+        let rate = if ancestor {
+            entry.ancestor_fee_rate()
+        } else {
+            entry.individual_fee_rate()
+        };
+        // Edges are whole sat/vB, rates sat/kvB: 2.4 sat/vB goes above the 2 edge
+        let i = edges
+            .iter()
+            .position(|edge| rate <= edge * 1000)
+            .unwrap_or(edges.len());
+        buckets[i].count += 1;
+        buckets[i].vsize += entry.vsize;
+    }
+    FeerateHistogram { ancestor, buckets }
+}
+
+impl FeerateHistogram {
+    pub fn kind(&self) -> &'static str {
+        if self.ancestor {
+            "ancestor feerate"
+        } else {
+            "feerate"
+        }
+    }
+
+    // One bar per bucket, scaled by vsize
+    pub fn render(&self) -> Vec<String> {
+        let largest = self
+            .buckets
+            .iter()
+            .map(|b| b.vsize)
+            .max()
+            .unwrap_or(0)
+            .max(1);
+        let width = self
+            .buckets
+            .iter()
+            .map(|b| b.label.len())
+            .max()
+            .unwrap_or(0);
+        let mut lines = vec![format!("Mempool by {} (sat/vB):", self.kind())];
+        for bucket in &self.buckets {
+            let bar = (bucket.vsize as usize * BAR_WIDTH).div_ceil(largest as usize);
+            lines.push(format!(
+                "{:>width$} | {:<bar_width$} {} vB, {} tx",
+                bucket.label,
+                "#".repeat(bar),
+                bucket.vsize,
+                bucket.count,
+                width = width,
+                bar_width = BAR_WIDTH
+            ));
+        }
+        lines
+    }
+
+    // "1: 3 tx / 450 vB, 2: ..." for reports
+    pub fn compact(&self) -> String {
+        self.buckets
+            .iter()
+            .map(|b| format!("{}: {} tx / {} vB", b.label, b.count, b.vsize))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "feerate": if self.ancestor { "ancestor" } else { "individual" },
+            "buckets": self
+                .buckets
+                .iter()
+                .map(|b| json!({ "label": b.label, "count": b.count, "vsize": b.vsize }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+// mempool: summarize the node's mempool, or with --histogram chart it by
+// feerate (as JSON with --json).
+pub fn run(rpc: &Client, config: &Config) -> Result<()> {
+    let entries = get_raw_mempool_verbose(rpc)?;
+    if config.histogram {
+        let histogram = histogram(&entries, config.histogram_edges(), config.ancestor_feerate);
+        if config.json {
+            say!("{}", serde_json::to_string_pretty(&histogram.to_json())?);
+        } else {
+            for line in histogram.render() {
+                say!("{}", line);
+            }
+        }
+        return Ok(());
+    }
+    for line in summarize(&entries).describe() {
+        say!("{}", line);
+    }
+    Ok(())
+}
+
+// Txids of the unconfirmed ancestors, not including `txid` itself.
+pub fn get_mempool_ancestors(rpc: &Client, txid: &Txid) -> Result<Vec<Txid>> {
+    Ok(rpc.call("getmempoolancestors", &[json!(txid)])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mockrpc;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+
+    // getmempoolentry for a child spending an unconfirmed parent: the parent
+    // paid 1 sat/vB, the child 11, so the package pays 6. Fees are in BTC.
+    const CHILD_ENTRY: &str = r#"{
+        "vsize": 141,
+        "weight": 561,
+        "time": 1700000000,
+        "height": 102,
+        "descendantcount": 1,
+        "descendantsize": 141,
+        "ancestorcount": 2,
+        "ancestorsize": 282,
+        "wtxid": "0000000000000000000000000000000000000000000000000000000000000001",
+        "fees": {
+            "base": 0.00001551,
+            "modified": 0.00001551,
+            "ancestor": 0.00001692,
+            "descendant": 0.00001551
+        },
+        "depends": ["0000000000000000000000000000000000000000000000000000000000000002"],
+        "spentby": [],
+        "bip125-replaceable": true,
+        "unbroadcast": false
+    }"#;
+
+    fn child() -> MempoolEntry {
+        serde_json::from_str(CHILD_ENTRY).unwrap()
+    }
+
+    fn entry(base: u64, vsize: u64) -> MempoolEntry {
+        let fee = Amount::from_sat(base);
+        MempoolEntry {
+            vsize,
+            weight: vsize * 4,
+            time: 0,
+            unbroadcast: None,
+            bip125_replaceable: false,
+            depends: Vec::new(),
+            spent_by: Vec::new(),
+            ancestor_count: 1,
+            ancestor_size: vsize,
+            descendant_count: 1,
+            descendant_size: vsize,
+            fees: MempoolFees {
+                base: fee,
+                modified: fee,
+                ancestor: fee,
+                descendant: fee,
+            },
+        }
+    }
+
+    #[test]
+    fn fees_are_read_as_btc() {
+        let child = child();
+        assert_eq!(child.fees.base, Amount::from_sat(1551));
+        assert_eq!(child.fees.ancestor, Amount::from_sat(1692));
+        assert_eq!(child.depends.len(), 1);
+        assert!(child.bip125_replaceable);
+        assert_eq!(child.unbroadcast, Some(false));
+    }
+
+    #[test]
+    fn package_feerates() {
+        let child = child();
+        assert_eq!(child.individual_fee_rate(), 11_000);
+        assert_eq!(child.ancestor_fee_rate(), 6_000);
+        assert_eq!(child.descendant_fee_rate(), 11_000);
+        assert_eq!(
+            child.describe(),
+            "11 sat/vB alone, 6 sat/vB with 1 ancestor(s) (282 vB), 11 sat/vB with 0 descendant(s) (141 vB)"
+        );
+    }
+
+    #[test]
+    fn fee_rate_rounds_down_and_survives_a_zero_size() {
+        assert_eq!(fee_rate(Amount::from_sat(1410), 113), 12_477);
+        assert_eq!(fee_rate(Amount::from_sat(1), 1000), 1);
+        assert_eq!(fee_rate(Amount::from_sat(1), 1001), 0);
+        assert_eq!(fee_rate(Amount::from_sat(250), 0), 250_000);
+        assert_eq!(fee_rate(Amount::ZERO, 141), 0);
+    }
+
+    #[test]
+    fn summary_percentiles_and_histogram() {
+        let entries: HashMap<Txid, MempoolEntry> = (1..=10u8)
+            .map(|n| {
+                (
+                    Txid::from_byte_array([n; 32]),
+                    entry(u64::from(n) * 100, 100),
+                )
+            })
+            .collect();
+        let summary = summarize(&entries);
+        assert_eq!(summary.count, 10);
+        assert_eq!(summary.total_vsize, 1000);
+        assert_eq!(summary.total_fees, Amount::from_sat(5500));
+        assert_eq!(summary.percentiles, Some((1_000, 5_000, 9_000)));
+        assert_eq!(summary.top[0], (Txid::from_byte_array([10; 32]), 10_000));
+        assert_eq!(summary.one_line(), "mempool: 10 txs, median 5 sat/vB");
+
+        let histogram = histogram(&entries, &DEFAULT_HISTOGRAM_EDGES, false);
+        assert_eq!(
+            histogram.compact(),
+            "1: 1 tx / 100 vB, 2: 1 tx / 100 vB, 3-5: 3 tx / 300 vB, 6-10: 5 tx / 500 vB, 11-20: 0 tx / 0 vB, 21+: 0 tx / 0 vB"
+        );
+        assert_eq!(summarize(&HashMap::new()).one_line(), "mempool: empty");
+    }
+
+    // getrawmempool true of a regtest node under a mixed load, with one CPFP
+    // pair: a 1 sat/vB parent and its 15 sat/vB child
+    fn verbose_mempool() -> HashMap<Txid, MempoolEntry> {
+        serde_json::from_str(include_str!("../tests/fixtures/rawmempool-verbose.json")).unwrap()
+    }
+
+    const PARENT: &str = "95cd603fe577fa9548ec0c9b50b067566fe07c8af6acba45f6196f3a15d511f6";
+    const CHILD: &str = "ab199cfee6eee2ce736eee608c12a5526e33fef62e4af2836ba3eed203d7f2bc";
+
+    #[test]
+    fn deserializes_the_verbose_mempool() {
+        let entries = verbose_mempool();
+        assert_eq!(entries.len(), 37);
+        let child = &entries[&CHILD.parse::<Txid>().unwrap()];
+        assert_eq!(child.depends, vec![PARENT.parse::<Txid>().unwrap()]);
+        assert_eq!(child.fees.base, Amount::from_sat(3_750));
+        assert_eq!(child.fees.ancestor, Amount::from_sat(4_034));
+        assert_eq!(child.ancestor_size, 534);
+        let parent = &entries[&PARENT.parse::<Txid>().unwrap()];
+        assert_eq!(parent.spent_by, vec![CHILD.parse::<Txid>().unwrap()]);
+        assert_eq!(parent.descendant_count, 2);
+    }
+
+    #[test]
+    fn summarizes_the_verbose_mempool() {
+        let summary = summarize(&verbose_mempool());
+        assert_eq!(summary.count, 37);
+        assert_eq!(summary.total_vsize, 18_038);
+        assert_eq!(summary.total_fees, Amount::from_sat(163_366));
+        assert_eq!(summary.percentiles, Some((1_000, 2_100, 35_000)));
+        assert_eq!(summary.replaceable, 24);
+        assert_eq!(summary.top.len(), TOP_TRANSACTIONS);
+        assert_eq!(
+            summary.top[..3],
+            [
+                (
+                    "4f449b4d68e0cf6ce74d4881e30d0a2bd42d88df2eeb2c521333252a85d3d323"
+                        .parse()
+                        .unwrap(),
+                    250_000
+                ),
+                (
+                    "6283fdbd93e7e2310846a23874e8943161a5e0624cac66274fe88941dd672ddc"
+                        .parse()
+                        .unwrap(),
+                    101_000
+                ),
+                (
+                    "f922ef5d960a6602003947a80d46fe02fb2368c42b9aa7c2e215447e194cef60"
+                        .parse()
+                        .unwrap(),
+                    50_000
+                ),
+            ]
+        );
+        assert_eq!(summary.one_line(), "mempool: 37 txs, median 2.1 sat/vB");
+        let lines = summary.describe();
+        assert_eq!(
+            lines[..5],
+            [
+                "Transactions: 37",
+                "Total size: 18038 vB",
+                "Total fees (in BTC): 0.00163366",
+                "Feerate p10 / p50 / p90: 1 / 2.1 / 35 sat/vB",
+                "Signaling RBF: 24 of 37",
+            ]
+        );
+        assert_eq!(
+            lines[5],
+            "  250 sat/vB  4f449b4d68e0cf6ce74d4881e30d0a2bd42d88df2eeb2c521333252a85d3d323"
+        );
+    }
+
+    #[test]
+    fn percentiles_are_nearest_rank() {
+        assert_eq!(percentile(&[7], 10), 7);
+        assert_eq!(percentile(&[7], 90), 7);
+        assert_eq!(percentile(&[1, 2], 10), 1);
+        assert_eq!(percentile(&[1, 2], 50), 1);
+        assert_eq!(percentile(&[1, 2], 90), 2);
+        let hundred: Vec<u64> = (1..=100).collect();
+        assert_eq!(percentile(&hundred, 10), 10);
+        assert_eq!(percentile(&hundred, 50), 50);
+        assert_eq!(percentile(&hundred, 90), 90);
+        let eleven: Vec<u64> = (1..=11).collect();
+        assert_eq!(percentile(&eleven, 10), 2);
+        assert_eq!(percentile(&eleven, 50), 6);
+        assert_eq!(percentile(&eleven, 90), 10);
+    }
+
+    // A 1 sat/vB parent, the 19 sat/vB child bumping it to 10 sat/vB as a
+    // package, and an unrelated 2.5 sat/vB transaction
+    fn cpfp_entries() -> HashMap<Txid, MempoolEntry> {
+        let parent = entry(200, 200);
+        let mut child = entry(1_900, 100);
+        child.ancestor_count = 2;
+        child.ancestor_size = 300;
+        child.fees.ancestor = Amount::from_sat(2_100);
+        [(1, parent), (2, child), (3, entry(500, 200))]
+            .into_iter()
+            .map(|(n, entry)| (Txid::from_byte_array([n; 32]), entry))
+            .collect()
+    }
+
+    #[test]
+    fn buckets_by_individual_feerate() {
+        let histogram = histogram(&cpfp_entries(), &DEFAULT_HISTOGRAM_EDGES, false);
+        assert_eq!(
+            histogram.compact(),
+            "1: 1 tx / 200 vB, 2: 0 tx / 0 vB, 3-5: 1 tx / 200 vB, 6-10: 0 tx / 0 vB, 11-20: 1 tx / 100 vB, 21+: 0 tx / 0 vB"
+        );
+        assert_eq!(histogram.kind(), "feerate");
+    }
+
+    #[test]
+    fn buckets_by_ancestor_feerate() {
+        // The child moves down to its package's rate; the parent has no
+        // ancestors, so it stays at its own
+        let histogram = histogram(&cpfp_entries(), &DEFAULT_HISTOGRAM_EDGES, true);
+        assert_eq!(
+            histogram.compact(),
+            "1: 1 tx / 200 vB, 2: 0 tx / 0 vB, 3-5: 1 tx / 200 vB, 6-10: 1 tx / 100 vB, 11-20: 0 tx / 0 vB, 21+: 0 tx / 0 vB"
+        );
+        assert_eq!(
+            histogram.to_json(),
+            json!({
+                "feerate": "ancestor",
+                "buckets": [
+                    { "label": "1", "count": 1, "vsize": 200 },
+                    { "label": "2", "count": 0, "vsize": 0 },
+                    { "label": "3-5", "count": 1, "vsize": 200 },
+                    { "label": "6-10", "count": 1, "vsize": 100 },
+                    { "label": "11-20", "count": 0, "vsize": 0 },
+                    { "label": "21+", "count": 0, "vsize": 0 },
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn verbose_mempool_histograms() {
+        let entries = verbose_mempool();
+        assert_eq!(
+            histogram(&entries, &DEFAULT_HISTOGRAM_EDGES, false).compact(),
+            "1: 6 tx / 1651 vB, 2: 7 tx / 5050 vB, 3-5: 12 tx / 7920 vB, 6-10: 4 tx / 948 vB, 11-20: 3 tx / 634 vB, 21+: 5 tx / 1835 vB"
+        );
+        assert_eq!(
+            histogram(&entries, &DEFAULT_HISTOGRAM_EDGES, true).compact(),
+            "1: 6 tx / 1651 vB, 2: 7 tx / 5050 vB, 3-5: 12 tx / 7920 vB, 6-10: 5 tx / 1198 vB, 11-20: 2 tx / 384 vB, 21+: 5 tx / 1835 vB"
+        );
+    }
+
+    #[test]
+    fn edges_are_configurable() {
+        let config = crate::cli::parse_args(
+            ["mempool", "--histogram", "--histogram-edges", "2,8"].map(String::from),
+        )
+        .unwrap();
+        assert_eq!(config.histogram_edges(), [2, 8]);
+        assert_eq!(
+            histogram(&cpfp_entries(), config.histogram_edges(), false).compact(),
+            "1-2: 1 tx / 200 vB, 3-8: 1 tx / 200 vB, 9+: 1 tx / 100 vB"
+        );
+        // No edges, one bucket
+        assert_eq!(
+            histogram(&cpfp_entries(), &[], false).compact(),
+            "1+: 3 tx / 500 vB"
+        );
+        for edges in ["5,2", "2,2"] {
+            assert!(crate::cli::parse_args(
+                ["mempool", "--histogram-edges", edges].map(String::from)
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn renders_bars_scaled_by_vsize() {
+        let lines = histogram(&cpfp_entries(), &[1, 5], false).render();
+        let bar = |n: usize| format!("{:<40}", "#".repeat(n));
+        assert_eq!(
+            lines,
+            [
+                "Mempool by feerate (sat/vB):".to_string(),
+                format!("  1 | {} 200 vB, 1 tx", bar(40)),
+                format!("2-5 | {} 200 vB, 1 tx", bar(40)),
+                format!(" 6+ | {} 100 vB, 1 tx", bar(20)),
+            ]
+        );
+        let empty = histogram(&HashMap::new(), &[1], false).render();
+        assert_eq!(empty[1], format!(" 1 | {} 0 vB, 0 tx", bar(0)));
+    }
+
+    const POLL: Duration = Duration::from_millis(1);
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn waits_for_a_transaction_that_appears_late() {
+        let tx = mockrpc::transaction();
+        let wallet_tx = mockrpc::wallet_tx(&tx, 0, true);
+        let mut lookups = 0;
+        let (rpc, calls) = mockrpc::client(move |method, _| match method {
+            // Not there on the first two polls
+            "getmempoolentry" => {
+                lookups += 1;
+                if lookups < 3 {
+                    mockrpc::NOT_IN_MEMPOOL
+                } else {
+                    Ok(serde_json::from_str(CHILD_ENTRY).unwrap())
+                }
+            }
+            "gettransaction" => Ok(wallet_tx.clone()),
+            _ => panic!("unexpected {}", method),
+        });
+        let snapshot = wait_for_acceptance(&rpc, &rpc, &tx.txid(), POLL, TIMEOUT).unwrap();
+        let MempoolSnapshot::Entry(entry) = snapshot else {
+            panic!("expected a mempool entry, got {:?}", snapshot);
+        };
+        assert_eq!(entry.vsize, 141);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "getmempoolentry",
+                "gettransaction",
+                "getmempoolentry",
+                "gettransaction",
+                "getmempoolentry",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_transaction_confirmed_before_the_check_is_accepted() {
+        let tx = mockrpc::transaction();
+        let wallet_tx = mockrpc::wallet_tx(&tx, 1, true);
+        let (rpc, calls) = mockrpc::client(move |method, _| match method {
+            "getmempoolentry" => mockrpc::NOT_IN_MEMPOOL,
+            "gettransaction" => Ok(wallet_tx.clone()),
+            _ => panic!("unexpected {}", method),
+        });
+        let snapshot = wait_for_acceptance(&rpc, &rpc, &tx.txid(), POLL, TIMEOUT).unwrap();
+        assert!(matches!(snapshot, MempoolSnapshot::ConfirmedFirst));
+        assert_eq!(calls.lock().unwrap().len(), 2);
+        assert_eq!(
+            snapshot.describe(),
+            ["Mempool entry: confirmed before mempool snapshot"]
+        );
+    }
+
+    #[test]
+    fn a_missing_transaction_times_out_with_the_reason() {
+        let tx = mockrpc::transaction();
+        let wallet_tx = mockrpc::wallet_tx(&tx, 0, true);
+        let hex = wallet_tx["hex"].clone();
+        let (txid, wtxid) = (tx.txid(), tx.wtxid());
+        let (rpc, calls) = mockrpc::client(move |method, params| match method {
+            "getmempoolentry" => mockrpc::NOT_IN_MEMPOOL,
+            "gettransaction" => Ok(wallet_tx.clone()),
+            "testmempoolaccept" => {
+                // The wallet's copy, with the feerate cap off
+                assert_eq!(params, [json!([hex.clone()]), json!(0.0)]);
+                Ok(json!([{
+                    "txid": txid,
+                    "wtxid": wtxid,
+                    "allowed": false,
+                    "reject-reason": "min relay fee not met"
+                }]))
+            }
+            _ => panic!("unexpected {}", method),
+        });
+        let timeout = Duration::from_millis(20);
+        let err = wait_for_acceptance(&rpc, &rpc, &txid, POLL, timeout).unwrap_err();
+        let CapstoneError::NotInMempool {
+            txid: missing,
+            reason,
+            ..
+        } = &err
+        else {
+            panic!("expected NotInMempool, got {}", err);
+        };
+        assert_eq!(*missing, txid);
+        assert!(reason.contains("min relay fee not met"), "{}", reason);
+        assert_eq!(calls.lock().unwrap().last().unwrap(), "testmempoolaccept");
+    }
+
+    #[test]
+    fn a_node_error_is_not_taken_for_a_missing_transaction() {
+        let (rpc, _) = mockrpc::client(|_, _| Err((-28, "Loading block index...")));
+        let txid = mockrpc::transaction().txid();
+        assert!(find_mempool_entry(&rpc, &txid).is_err());
+    }
+}
@@ -0,0 +1,473 @@
+use crate::block;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::fees;
+use crate::files;
+use crate::mempool;
+use bitcoincore_rpc::bitcoin::{BlockHash, OutPoint, Txid};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// One transaction in a snapshot. The feerate goes by the modified fee, so
+// prioritisetransaction shows up as a change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    // sat/kvB
+    pub fee_rate: u64,
+    pub vsize: u64,
+    // sat
+    pub fee: u64,
+    // What it spends, to tell a replacement from an eviction
+    pub spends: Vec<OutPoint>,
+}
+
+// The mempool at one moment, as `mempool snapshot --out` stores it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    // Unix seconds
+    pub taken_at: u64,
+    pub height: u64,
+    pub tip: BlockHash,
+    pub transactions: BTreeMap<Txid, SnapshotEntry>,
+}
+
+pub fn take(rpc: &Client) -> Result<Snapshot> {
+    let height = rpc.get_block_count()?;
+    let tip = rpc.get_best_block_hash()?;
+    let mut transactions = BTreeMap::new();
+    for (txid, entry) in mempool::get_raw_mempool_verbose(rpc)? {
+        // getrawmempool doesn't list inputs. A transaction that left in
+        // between isn't in the mempool any more, so it is left out.
+        let Ok(tx) = rpc.get_raw_transaction(&txid, None) else {
+            continue;
+        };
+        transactions.insert(
+            txid,
+            SnapshotEntry {
+                fee_rate: mempool::fee_rate(entry.fees.modified, entry.vsize),
+                vsize: entry.vsize,
+                fee: entry.fees.modified.to_sat(),
+                spends: tx.input.iter().map(|input| input.previous_output).collect(),
+            },
+        );
+    }
+    let taken_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    Ok(Snapshot {
+        taken_at,
+        height,
+        tip,
+        transactions,
+    })
+}
+
+pub fn save(snapshot: &Snapshot, path: &str) -> Result<()> {
+    files::write_atomically(
+        path,
+        serde_json::to_string_pretty(snapshot)?,
+        "mempool snapshot",
+    )?;
+    Ok(())
+}
+
+pub fn load(path: &str) -> Result<Snapshot> {
+    let content = files::read_to_string(path, "mempool snapshot")?;
+    serde_json::from_str(&content)
+        .map_err(|e| CapstoneError::Usage(format!("{} is not a mempool snapshot: {}", path, e)))
+}
+
+// A transaction whose feerate differs between the snapshots: the same txid
+// prioritised, or one replacing another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeerateChange {
+    pub from: Txid,
+    pub to: Txid,
+    // sat/kvB
+    pub from_rate: u64,
+    pub to_rate: u64,
+}
+
+// What happened between two snapshots. A transaction in the old one is
+// confirmed if a block since took it, replaced if a new transaction spends
+// one of its inputs, and otherwise evicted (expired, trimmed by -maxmempool,
+// or conflicted by something that has already left too).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MempoolDiff {
+    pub added: Vec<Txid>,
+    // With the height of the block that took it
+    pub confirmed: Vec<(Txid, u64)>,
+    pub replaced: Vec<FeerateChange>,
+    pub evicted: Vec<Txid>,
+    pub still_present: usize,
+    pub reprioritised: Vec<FeerateChange>,
+}
+
+// Compare `old` with `new`. `confirmed` maps txids found in the blocks since
+// `old` to their heights; only the txids gone from the mempool are looked up.
+pub fn diff(old: &Snapshot, new: &Snapshot, confirmed: &HashMap<Txid, u64>) -> MempoolDiff {
+    let mut diff = MempoolDiff::default();
+    let added: Vec<(&Txid, &SnapshotEntry)> = new
+        .transactions
+        .iter()
+        .filter(|(txid, _)| !old.transactions.contains_key(*txid))
+        .collect();
+    diff.added = added.iter().map(|(txid, _)| **txid).collect();
+    for (txid, entry) in &old.transactions {
+        if let Some(now) = new.transactions.get(txid) {
+            diff.still_present += 1;
+            if now.fee_rate != entry.fee_rate {
+                diff.reprioritised.push(FeerateChange {
+                    from: *txid,
+                    to: *txid,
+                    from_rate: entry.fee_rate,
+                    to_rate: now.fee_rate,
+                });
+            }
+        } else if let Some(height) = confirmed.get(txid) {
+            diff.confirmed.push((*txid, *height));
+        } else if let Some((by, replacement)) = added.iter().find(|(_, candidate)| {
+            candidate
+                .spends
+                .iter()
+                .any(|outpoint| entry.spends.contains(outpoint))
+        }) {
+            diff.replaced.push(FeerateChange {
+                from: *txid,
+                to: **by,
+                from_rate: entry.fee_rate,
+                to_rate: replacement.fee_rate,
+            });
+        } else {
+            diff.evicted.push(*txid);
+        }
+    }
+    diff
+}
+
+// The txids of `old` that blocks after it took, up to `new`'s height, by
+// reading the blocks in between. Works without -txindex.
+pub fn confirmed_since(rpc: &Client, old: &Snapshot, new: &Snapshot) -> Result<HashMap<Txid, u64>> {
+    let gone: HashSet<&Txid> = old
+        .transactions
+        .keys()
+        .filter(|txid| !new.transactions.contains_key(*txid))
+        .collect();
+    let mut confirmed = HashMap::new();
+    if gone.is_empty() || new.height <= old.height {
+        return Ok(confirmed);
+    }
+    for (height, hash) in block::block_hashes(rpc, old.height + 1, new.height)? {
+        for txid in rpc.get_block_info(&hash)?.tx {
+            if gone.contains(&txid) {
+                confirmed.insert(txid, height);
+            }
+        }
+    }
+    Ok(confirmed)
+}
+
+// `diff` for two snapshots of this node's mempool
+pub fn compare(rpc: &Client, old: &Snapshot, new: &Snapshot) -> Result<MempoolDiff> {
+    Ok(diff(old, new, &confirmed_since(rpc, old, new)?))
+}
+
+impl FeerateChange {
+    pub fn describe(&self) -> String {
+        let rates = format!(
+            "{} -> {} sat/vB",
+            fees::format_sat_per_vb(self.from_rate),
+            fees::format_sat_per_vb(self.to_rate)
+        );
+        if self.from == self.to {
+            format!("{}: {}", self.from, rates)
+        } else {
+            format!("{} replaced by {}: {}", self.from, self.to, rates)
+        }
+    }
+}
+
+impl MempoolDiff {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} added, {} confirmed, {} replaced, {} evicted, {} still present ({} with a new feerate)",
+            self.added.len(),
+            self.confirmed.len(),
+            self.replaced.len(),
+            self.evicted.len(),
+            self.still_present,
+            self.reprioritised.len()
+        )
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![format!("Mempool diff: {}", self.summary())];
+        lines.extend(self.added.iter().map(|txid| format!("Added: {}", txid)));
+        lines.extend(
+            self.confirmed
+                .iter()
+                .map(|(txid, height)| format!("Confirmed: {} at height {}", txid, height)),
+        );
+        lines.extend(
+            self.replaced
+                .iter()
+                .map(|change| format!("Replaced: {}", change.describe())),
+        );
+        lines.extend(self.evicted.iter().map(|txid| format!("Evicted: {}", txid)));
+        lines.extend(
+            self.reprioritised
+                .iter()
+                .map(|change| format!("Feerate changed: {}", change.describe())),
+        );
+        lines
+    }
+}
+
+// mempool snapshot: store the mempool as JSON at `out`.
+pub fn run_snapshot(rpc: &Client, out: &str) -> Result<()> {
+    let snapshot = take(rpc)?;
+    save(&snapshot, out)?;
+    say!(
+        "Saved {} mempool transaction(s) at height {} to {}.",
+        snapshot.transactions.len(),
+        snapshot.height,
+        out
+    );
+    Ok(())
+}
+
+// mempool diff: compare the snapshot at `old` with the one at `new`, or with
+// the mempool now.
+pub fn run_diff(rpc: &Client, old: &str, new: Option<&str>) -> Result<()> {
+    let old = load(old)?;
+    let new = match new {
+        Some(path) => load(path)?,
+        None => take(rpc)?,
+    };
+    for line in compare(rpc, &old, &new)?.describe() {
+        say!("{}", line);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+
+    fn txid(n: u8) -> Txid {
+        Txid::from_byte_array([n; 32])
+    }
+
+    // A transaction at `fee_rate` sat/kvB spending output 0 of each of `spends`
+    fn entry(fee_rate: u64, spends: &[u8]) -> SnapshotEntry {
+        SnapshotEntry {
+            fee_rate,
+            vsize: 141,
+            fee: fee_rate * 141 / 1000,
+            spends: spends.iter().map(|n| OutPoint::new(txid(*n), 0)).collect(),
+        }
+    }
+
+    fn snapshot(height: u64, transactions: Vec<(u8, SnapshotEntry)>) -> Snapshot {
+        Snapshot {
+            taken_at: 0,
+            height,
+            tip: BlockHash::all_zeros(),
+            transactions: transactions
+                .into_iter()
+                .map(|(n, entry)| (txid(n), entry))
+                .collect(),
+        }
+    }
+
+    struct Case {
+        name: &'static str,
+        old: Vec<(u8, SnapshotEntry)>,
+        new: Vec<(u8, SnapshotEntry)>,
+        // Txids found in the blocks in between, with their heights
+        confirmed: Vec<(u8, u64)>,
+        expected: MempoolDiff,
+    }
+
+    fn change(from: u8, to: u8, from_rate: u64, to_rate: u64) -> FeerateChange {
+        FeerateChange {
+            from: txid(from),
+            to: txid(to),
+            from_rate,
+            to_rate,
+        }
+    }
+
+    fn cases() -> Vec<Case> {
+        vec![
+            Case {
+                name: "nothing changed",
+                old: vec![(1, entry(1_000, &[100]))],
+                new: vec![(1, entry(1_000, &[100]))],
+                confirmed: vec![],
+                expected: MempoolDiff {
+                    still_present: 1,
+                    ..MempoolDiff::default()
+                },
+            },
+            Case {
+                name: "added",
+                old: vec![],
+                new: vec![(1, entry(1_000, &[100])), (2, entry(2_000, &[101]))],
+                confirmed: vec![],
+                expected: MempoolDiff {
+                    added: vec![txid(1), txid(2)],
+                    ..MempoolDiff::default()
+                },
+            },
+            Case {
+                name: "confirmed",
+                old: vec![(1, entry(1_000, &[100])), (2, entry(2_000, &[101]))],
+                new: vec![(2, entry(2_000, &[101]))],
+                confirmed: vec![(1, 103)],
+                expected: MempoolDiff {
+                    confirmed: vec![(txid(1), 103)],
+                    still_present: 1,
+                    ..MempoolDiff::default()
+                },
+            },
+            Case {
+                name: "evicted",
+                old: vec![(1, entry(1_000, &[100]))],
+                new: vec![],
+                confirmed: vec![],
+                expected: MempoolDiff {
+                    evicted: vec![txid(1)],
+                    ..MempoolDiff::default()
+                },
+            },
+            Case {
+                name: "replaced by a transaction spending one of its inputs",
+                old: vec![(1, entry(1_000, &[100, 101]))],
+                new: vec![(2, entry(5_000, &[101]))],
+                confirmed: vec![],
+                expected: MempoolDiff {
+                    added: vec![txid(2)],
+                    replaced: vec![change(1, 2, 1_000, 5_000)],
+                    ..MempoolDiff::default()
+                },
+            },
+            Case {
+                name: "confirmed rather than replaced when a block took it",
+                old: vec![(1, entry(1_000, &[100]))],
+                new: vec![(2, entry(5_000, &[100]))],
+                confirmed: vec![(1, 104)],
+                expected: MempoolDiff {
+                    added: vec![txid(2)],
+                    confirmed: vec![(txid(1), 104)],
+                    ..MempoolDiff::default()
+                },
+            },
+            Case {
+                name: "a new transaction spending other outputs is no replacement",
+                old: vec![(1, entry(1_000, &[100]))],
+                new: vec![(2, entry(5_000, &[102]))],
+                confirmed: vec![],
+                expected: MempoolDiff {
+                    added: vec![txid(2)],
+                    evicted: vec![txid(1)],
+                    ..MempoolDiff::default()
+                },
+            },
+            Case {
+                name: "still present with a new feerate",
+                old: vec![(1, entry(1_000, &[100])), (2, entry(2_000, &[101]))],
+                new: vec![(1, entry(3_000, &[100])), (2, entry(2_000, &[101]))],
+                confirmed: vec![],
+                expected: MempoolDiff {
+                    still_present: 2,
+                    reprioritised: vec![change(1, 1, 1_000, 3_000)],
+                    ..MempoolDiff::default()
+                },
+            },
+            Case {
+                name: "everything at once",
+                old: vec![
+                    (1, entry(1_000, &[100])),
+                    (2, entry(1_000, &[101])),
+                    (3, entry(1_000, &[102])),
+                    (4, entry(1_000, &[103])),
+                ],
+                new: vec![
+                    (4, entry(1_000, &[103])),
+                    (5, entry(4_000, &[102])),
+                    (6, entry(1_000, &[104])),
+                ],
+                confirmed: vec![(1, 105)],
+                expected: MempoolDiff {
+                    added: vec![txid(5), txid(6)],
+                    confirmed: vec![(txid(1), 105)],
+                    replaced: vec![change(3, 5, 1_000, 4_000)],
+                    evicted: vec![txid(2)],
+                    still_present: 1,
+                    reprioritised: vec![],
+                },
+            },
+        ]
+    }
+
+    #[test]
+    fn classifies_every_transaction() {
+        for case in cases() {
+            let old = snapshot(102, case.old);
+            let new = snapshot(105, case.new);
+            let confirmed: HashMap<Txid, u64> = case
+                .confirmed
+                .into_iter()
+                .map(|(n, height)| (txid(n), height))
+                .collect();
+            assert_eq!(diff(&old, &new, &confirmed), case.expected, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn describes_each_classification() {
+        let diff = MempoolDiff {
+            added: vec![txid(5)],
+            confirmed: vec![(txid(1), 105)],
+            replaced: vec![change(3, 5, 1_000, 4_000)],
+            evicted: vec![txid(2)],
+            still_present: 1,
+            reprioritised: vec![change(4, 4, 1_000, 2_500)],
+        };
+        assert_eq!(
+            diff.summary(),
+            "1 added, 1 confirmed, 1 replaced, 1 evicted, 1 still present (1 with a new feerate)"
+        );
+        let lines = diff.describe();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[2], format!("Confirmed: {} at height 105", txid(1)));
+        assert_eq!(
+            lines[3],
+            format!(
+                "Replaced: {} replaced by {}: 1 -> 4 sat/vB",
+                txid(3),
+                txid(5)
+            )
+        );
+        assert_eq!(
+            lines[5],
+            format!("Feerate changed: {}: 1 -> 2.5 sat/vB", txid(4))
+        );
+    }
+
+    #[test]
+    fn snapshots_round_trip_through_json() {
+        let old = snapshot(102, vec![(1, entry(1_000, &[100, 101]))]);
+        let json = serde_json::to_string(&old).unwrap();
+        let back: Snapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.height, 102);
+        assert_eq!(
+            back.transactions[&txid(1)].spends,
+            old.transactions[&txid(1)].spends
+        );
+    }
+}
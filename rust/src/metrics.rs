@@ -0,0 +1,413 @@
+use crate::btc;
+use crate::console::say;
+use crate::error::Result;
+use crate::session;
+use bitcoincore_rpc::jsonrpc::{self, Request, Response, Transport};
+use bitcoincore_rpc::RpcApi;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Wallets whose balance the scrape reports
+const BALANCE_WALLETS: [&str; 2] = ["Miner", "Trader"];
+// How long a scraper may take to send its request
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Calls and time spent per RPC method
+#[derive(Debug, Default, Clone, Copy)]
+struct MethodStats {
+    calls: u64,
+    errors: u64,
+    seconds: f64,
+}
+
+// Everything counted since the start of the run. The session's RPC clients
+// all go through `Metered`, so counting happens whether or not anything
+// scrapes; --metrics-addr only decides whether it is served.
+struct Registry {
+    methods: Mutex<BTreeMap<String, MethodStats>>,
+    // (kind, detail) -> count, e.g. ("rpc", "-26") or ("transport", "")
+    failures: Mutex<BTreeMap<(&'static str, String), u64>>,
+    blocks_mined: AtomicU64,
+    transactions_sent: AtomicU64,
+}
+
+static REGISTRY: Registry = Registry {
+    methods: Mutex::new(BTreeMap::new()),
+    failures: Mutex::new(BTreeMap::new()),
+    blocks_mined: AtomicU64::new(0),
+    transactions_sent: AtomicU64::new(0),
+};
+
+fn count_failure(kind: &'static str, detail: String) {
+    if let Ok(mut failures) = REGISTRY.failures.lock() {
+        *failures.entry((kind, detail)).or_default() += 1;
+    }
+}
+
+// What a successful call did, told apart by its method: the generate calls
+// return one hash per block, the send calls broadcast one transaction
+fn count_effects(method: &str, response: &Response) {
+    match method {
+        "generatetoaddress" | "generatetodescriptor" => {
+            let blocks = response
+                .result
+                .as_ref()
+                .and_then(|result| serde_json::from_str::<Vec<String>>(result.get()).ok())
+                .map_or(0, |hashes| hashes.len() as u64);
+            REGISTRY.blocks_mined.fetch_add(blocks, Ordering::Relaxed);
+        }
+        "generateblock" => {
+            REGISTRY.blocks_mined.fetch_add(1, Ordering::Relaxed);
+        }
+        "sendrawtransaction" | "sendtoaddress" | "sendmany" | "send" | "sendall" => {
+            REGISTRY.transactions_sent.fetch_add(1, Ordering::Relaxed);
+        }
+        _ => {}
+    }
+}
+
+// A call's response, or the error that stopped it from getting one
+fn record(
+    method: &str,
+    elapsed: Duration,
+    outcome: std::result::Result<&Response, &jsonrpc::Error>,
+) {
+    let failed = match outcome {
+        Ok(response) => match &response.error {
+            Some(error) => {
+                count_failure("rpc", error.code.to_string());
+                true
+            }
+            None => {
+                count_effects(method, response);
+                false
+            }
+        },
+        Err(jsonrpc::Error::Transport(_)) => {
+            count_failure("transport", String::new());
+            true
+        }
+        Err(jsonrpc::Error::Json(_)) => {
+            count_failure("json", String::new());
+            true
+        }
+        Err(_) => {
+            count_failure("other", String::new());
+            true
+        }
+    };
+    if let Ok(mut methods) = REGISTRY.methods.lock() {
+        let stats = methods.entry(method.to_string()).or_default();
+        stats.calls += 1;
+        stats.errors += u64::from(failed);
+        stats.seconds += elapsed.as_secs_f64();
+    }
+}
+
+// A transport that counts and times every call before handing it on.
+pub struct Metered<T: Transport>(pub T);
+
+impl<T: Transport> Transport for Metered<T> {
+    fn send_request(&self, request: Request) -> std::result::Result<Response, jsonrpc::Error> {
+        let method = request.method.to_string();
+        let started = Instant::now();
+        let outcome = self.0.send_request(request);
+        record(&method, started.elapsed(), outcome.as_ref());
+        outcome
+    }
+
+    fn send_batch(
+        &self,
+        requests: &[Request],
+    ) -> std::result::Result<Vec<Response>, jsonrpc::Error> {
+        let started = Instant::now();
+        let outcome = self.0.send_batch(requests);
+        // The batch's time is shared out evenly over its calls
+        let share = started.elapsed() / requests.len().max(1) as u32;
+        let missing = jsonrpc::Error::WrongBatchResponseSize;
+        for request in requests {
+            // Responses may come back in any order
+            let single = match &outcome {
+                Ok(responses) => responses
+                    .iter()
+                    .find(|response| response.id == request.id)
+                    .ok_or(&missing),
+                Err(e) => Err(e),
+            };
+            record(request.method, share, single);
+        }
+        outcome
+    }
+
+    fn fmt_target(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_target(f)
+    }
+}
+
+// Label values are quoted, with backslashes, quotes and newlines escaped
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// The Prometheus text format of the registry plus the gauges, which are read
+// from the node when asked for, so they are current rather than as of the
+// run's last call.
+pub fn render() -> String {
+    let mut out = String::new();
+    out.push_str("# HELP capstone_rpc_calls_total RPC calls made, by method.\n");
+    out.push_str("# TYPE capstone_rpc_calls_total counter\n");
+    let methods = REGISTRY
+        .methods
+        .lock()
+        .map(|methods| methods.clone())
+        .unwrap_or_default();
+    for (method, stats) in &methods {
+        out.push_str(&format!(
+            "capstone_rpc_calls_total{{method=\"{}\"}} {}\n",
+            label(method),
+            stats.calls
+        ));
+    }
+    out.push_str("# HELP capstone_rpc_errors_total RPC calls that failed, by method.\n");
+    out.push_str("# TYPE capstone_rpc_errors_total counter\n");
+    for (method, stats) in &methods {
+        out.push_str(&format!(
+            "capstone_rpc_errors_total{{method=\"{}\"}} {}\n",
+            label(method),
+            stats.errors
+        ));
+    }
+    out.push_str("# HELP capstone_rpc_seconds_total Time spent in RPC calls, by method.\n");
+    out.push_str("# TYPE capstone_rpc_seconds_total counter\n");
+    for (method, stats) in &methods {
+        out.push_str(&format!(
+            "capstone_rpc_seconds_total{{method=\"{}\"}} {:.6}\n",
+            label(method),
+            stats.seconds
+        ));
+    }
+
+    out.push_str("# HELP capstone_failures_total Failed RPC calls by error kind; detail is the RPC error code.\n");
+    out.push_str("# TYPE capstone_failures_total counter\n");
+    if let Ok(failures) = REGISTRY.failures.lock() {
+        for ((kind, detail), count) in failures.iter() {
+            out.push_str(&format!(
+                "capstone_failures_total{{kind=\"{}\",detail=\"{}\"}} {}\n",
+                kind,
+                label(detail),
+                count
+            ));
+        }
+    }
+    for (name, help, value) in [
+        (
+            "capstone_blocks_mined_total",
+            "Blocks mined by this run.",
+            &REGISTRY.blocks_mined,
+        ),
+        (
+            "capstone_transactions_sent_total",
+            "Transactions broadcast by this run.",
+            &REGISTRY.transactions_sent,
+        ),
+    ] {
+        out.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} counter\n",
+            name, help, name
+        ));
+        out.push_str(&format!("{} {}\n", name, value.load(Ordering::Relaxed)));
+    }
+
+    // Gauges, through an unmetered client so scraping doesn't count as the run's calls
+    if let Ok(rpc) = session::plain_client("") {
+        if let Ok(info) = rpc.get_mempool_info() {
+            out.push_str(
+                "# HELP capstone_mempool_transactions Transactions in the node's mempool.\n",
+            );
+            out.push_str("# TYPE capstone_mempool_transactions gauge\n");
+            out.push_str(&format!("capstone_mempool_transactions {}\n", info.size));
+            out.push_str("# HELP capstone_mempool_bytes Virtual size of the node's mempool.\n");
+            out.push_str("# TYPE capstone_mempool_bytes gauge\n");
+            out.push_str(&format!("capstone_mempool_bytes {}\n", info.bytes));
+        }
+    }
+    out.push_str("# HELP capstone_wallet_balance_btc Trusted wallet balance.\n");
+    out.push_str("# TYPE capstone_wallet_balance_btc gauge\n");
+    for wallet in BALANCE_WALLETS {
+        let balance = session::plain_client(&format!("/wallet/{}", wallet))
+            .ok()
+            .and_then(|rpc| rpc.get_balances().ok());
+        if let Some(balance) = balance {
+            out.push_str(&format!(
+                "capstone_wallet_balance_btc{{wallet=\"{}\"}} {}\n",
+                wallet,
+                btc::format_btc(balance.mine.trusted)
+            ));
+        }
+    }
+    out
+}
+
+fn answer(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    // Any path gets the metrics; only wait for the request line
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let body = render();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+// --metrics-addr: serve `render` on `address` from a background thread for
+// as long as the run lasts. Returns where it listens, the port filled in if
+// `address` left it to the system (port 0).
+pub fn serve(address: &str) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+    say!("Serving Prometheus metrics on http://{}/metrics.", address);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = answer(stream) {
+                eprintln!("Metrics scrape failed: {}", e);
+            }
+        }
+    });
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::jsonrpc::error::RpcError;
+    use bitcoincore_rpc::Client;
+    use serde_json::json;
+    use serde_json::value::RawValue;
+
+    // Answers like a node would, without one
+    struct Canned;
+
+    impl Transport for Canned {
+        fn send_request(&self, request: Request) -> std::result::Result<Response, jsonrpc::Error> {
+            let (result, error) = match request.method {
+                "getblockcount" => (Some("101"), None),
+                "generatetoaddress" => (Some(r#"["aa","bb","cc"]"#), None),
+                "sendrawtransaction" => (Some(r#""dd""#), None),
+                _ => (
+                    None,
+                    Some(RpcError {
+                        code: -5,
+                        message: "Transaction not in mempool".into(),
+                        data: None,
+                    }),
+                ),
+            };
+            Ok(Response {
+                result: result.map(|result| RawValue::from_string(result.into()).unwrap()),
+                error,
+                id: request.id.clone(),
+                jsonrpc: Some("2.0".into()),
+            })
+        }
+
+        fn send_batch(
+            &self,
+            requests: &[Request],
+        ) -> std::result::Result<Vec<Response>, jsonrpc::Error> {
+            requests
+                .iter()
+                .map(|request| self.send_request(request.clone()))
+                .collect()
+        }
+
+        fn fmt_target(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "canned")
+        }
+    }
+
+    fn scrape(address: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    // The only test that goes through Metered, so the registry's counts are
+    // all its own
+    #[test]
+    fn scrape_shows_the_counters_moving() {
+        let rpc = Client::from_jsonrpc(jsonrpc::Client::with_transport(Metered(Canned)));
+        for _ in 0..3 {
+            assert_eq!(rpc.get_block_count().unwrap(), 101);
+        }
+        let hashes: Vec<String> = rpc
+            .call("generatetoaddress", &[json!(3), json!("bcrt1q")])
+            .unwrap();
+        assert_eq!(hashes.len(), 3);
+        let _: String = rpc.call("sendrawtransaction", &[json!("00")]).unwrap();
+        assert!(rpc
+            .call::<serde_json::Value>("getmempoolentry", &[json!("dd")])
+            .is_err());
+
+        let response = scrape(serve("127.0.0.1:0").unwrap());
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Type: text/plain; version=0.0.4"));
+        let lines: Vec<&str> = body.lines().collect();
+        for sample in [
+            "capstone_rpc_calls_total{method=\"getblockcount\"} 3",
+            "capstone_rpc_calls_total{method=\"getmempoolentry\"} 1",
+            "capstone_rpc_errors_total{method=\"getblockcount\"} 0",
+            "capstone_rpc_errors_total{method=\"getmempoolentry\"} 1",
+            "capstone_failures_total{kind=\"rpc\",detail=\"-5\"} 1",
+            "capstone_blocks_mined_total 3",
+            "capstone_transactions_sent_total 1",
+        ] {
+            assert!(lines.contains(&sample), "no '{}' in\n{}", sample, body);
+        }
+
+        // Every sample follows the HELP and TYPE lines of its metric
+        let mut described = Vec::new();
+        for line in &lines {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                described.push(help.split(' ').next().unwrap().to_string());
+            } else if let Some(kind) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = kind.split_once(' ').unwrap();
+                assert_eq!(described.last().map(String::as_str), Some(name));
+                assert!(kind == "counter" || kind == "gauge", "{}", line);
+            } else {
+                let name = line.split(['{', ' ']).next().unwrap();
+                assert_eq!(described.last().map(String::as_str), Some(name), "{}", line);
+                let value = line.rsplit(' ').next().unwrap();
+                assert!(value.parse::<f64>().is_ok(), "{}", line);
+            }
+        }
+    }
+}
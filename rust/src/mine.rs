@@ -0,0 +1,596 @@
+use crate::accept;
+use crate::bump;
+use crate::cli::Config;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::fees;
+use crate::mempool;
+use crate::relay;
+use crate::session::{self, Session};
+use crate::signing;
+use crate::txidlog;
+#[cfg(feature = "zmq")]
+use crate::zmq;
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::{Address, Amount, BlockHash, Network, Txid};
+use bitcoincore_rpc::json::{Bip125Replaceable, BlockStatsFields};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Deserialize)]
+pub struct GenerateBlockResult {
+    pub hash: BlockHash,
+}
+
+// A txid is 64 hex characters; anything else is taken as a raw transaction
+fn as_txid(transaction: &str) -> Option<Txid> {
+    if transaction.len() != 64 {
+        return None;
+    }
+    transaction.parse().ok()
+}
+
+// Mine one block paying `to` that holds exactly `transactions` (txids of
+// mempool transactions or raw hex, in block order) next to the coinbase.
+// Missing txids are reported before anything is mined; when the node refuses
+// the block, each raw transaction's own testmempoolaccept verdict is added to
+// its reason.
+pub fn generate_block(
+    session: &Session,
+    to: &Address,
+    transactions: &[String],
+) -> Result<BlockHash> {
+    let rpc = &session.rpc;
+    let mempool: HashSet<Txid> = rpc.get_raw_mempool()?.into_iter().collect();
+    let missing: Vec<&str> = transactions
+        .iter()
+        .filter(|tx| as_txid(tx).is_some_and(|txid| !mempool.contains(&txid)))
+        .map(String::as_str)
+        .collect();
+    if !missing.is_empty() {
+        return Err(CapstoneError::Usage(format!(
+            "not in the mempool: {}",
+            missing.join(", ")
+        )));
+    }
+    let raw: Vec<&String> = transactions
+        .iter()
+        .filter(|tx| as_txid(tx).is_none())
+        .collect();
+    for hex in &raw {
+        signing::decode_tx(hex)?;
+    }
+
+    session.tick()?;
+    let result: std::result::Result<GenerateBlockResult, _> = rpc.call(
+        "generateblock",
+        &[json!(to.to_string()), json!(transactions)],
+    );
+    match result {
+        Ok(result) => Ok(result.hash),
+        Err(e) => {
+            let mut reasons = vec![e.to_string()];
+            for hex in raw {
+                let results =
+                    accept::test_mempool_accept(rpc, std::slice::from_ref(hex), Amount::ZERO)?;
+                for result in results.iter().filter(|r| !r.is_allowed()) {
+                    reasons.push(format!("{}: {}", result.txid, result.rejection()));
+                }
+            }
+            Err(CapstoneError::BlockRejected(reasons))
+        }
+    }
+}
+
+// Mine the block confirming `txids`: with --exclusive-block it holds only
+// them, so whatever else sits in the mempool stays there; otherwise it is a
+// normal block sweeping in the whole mempool.
+pub fn confirm(session: &Session, txids: &[Txid], config: &Config) -> Result<BlockHash> {
+    if config.exclusive_block {
+        let transactions: Vec<String> = txids.iter().map(|txid| txid.to_string()).collect();
+        return generate_block(session, &session.miner_address, &transactions);
+    }
+    Ok(session.generate(1, &session.miner_address)?[0])
+}
+
+// Where a transaction sits once it has enough confirmations.
+#[derive(Debug, Clone)]
+pub struct Confirmation {
+    // The transaction waited for, or the fee bump that replaced it
+    pub txid: Txid,
+    pub block_hash: BlockHash,
+    pub block_height: usize,
+    pub confirmations: i32,
+    // What was done about it leaving the mempool on the way, oldest first
+    pub evictions: Vec<EvictionResponse>,
+}
+
+// Rebroadcasts of an evicted transaction before it is fee bumped instead
+const MAX_REBROADCASTS: usize = 3;
+
+// What the confirmation wait did on finding its transaction neither confirmed
+// nor in the mempool, e.g. evicted by a small -maxmempool.
+#[derive(Debug, Clone)]
+pub enum EvictionResponse {
+    // sendrawtransaction of the wallet's copy, with the node's error if it refused
+    Rebroadcast { txid: Txid, error: Option<String> },
+    // A bumpfee replacement once MAX_REBROADCASTS rebroadcasts didn't keep it in
+    Bumped { from: Txid, to: bump::BumpAttempt },
+}
+
+impl EvictionResponse {
+    pub fn describe(&self) -> String {
+        match self {
+            EvictionResponse::Rebroadcast { txid, error: None } => {
+                format!("rebroadcast {} after eviction", txid)
+            }
+            EvictionResponse::Rebroadcast {
+                txid,
+                error: Some(error),
+            } => format!("rebroadcast {} after eviction, refused: {}", txid, error),
+            EvictionResponse::Bumped { from, to } => format!(
+                "replaced evicted {} with {} at {} sat/vB",
+                from,
+                to.txid,
+                fees::format_sat_per_vb(to.sat_per_kvb)
+            ),
+        }
+    }
+}
+
+// Put an evicted `txid` of `wallet_rpc`'s back in the mempool. After
+// MAX_REBROADCASTS it is fee bumped instead if it is replaceable and `max_fee`
+// leaves room, since the mempool evidently doesn't keep it at its current
+// feerate.
+fn respond_to_eviction(
+    rpc: &Client,
+    wallet_rpc: &Client,
+    txid: &Txid,
+    evictions: &[EvictionResponse],
+    max_fee: Amount,
+) -> Result<EvictionResponse> {
+    let wallet_tx = wallet_rpc.get_transaction(txid, None)?;
+    let rebroadcasts = evictions
+        .iter()
+        .filter(|response| matches!(response, EvictionResponse::Rebroadcast { txid: t, .. } if t == txid))
+        .count();
+    let replaceable = wallet_tx.info.bip125_replaceable == Bip125Replaceable::Yes;
+    if rebroadcasts >= MAX_REBROADCASTS && replaceable {
+        let vsize = wallet_tx.transaction()?.vsize() as u64;
+        let current = mempool::fee_rate(fees::wallet_fee(wallet_rpc, txid)?, vsize);
+        let policy = relay::policy(rpc, &[])?;
+        if let Some(rate) = bump::next_fee_rate(current, vsize, max_fee, &policy) {
+            let to = bump::bump_fee(wallet_rpc, txid, rate)?;
+            return Ok(EvictionResponse::Bumped { from: *txid, to });
+        }
+        say!(
+            "{} can't be bumped within --max-fee, rebroadcasting again.",
+            txid
+        );
+    }
+    let error = rpc
+        .send_raw_transaction(&wallet_tx.hex[..])
+        .err()
+        .map(|e| e.to_string());
+    match &error {
+        None => {
+            txidlog::record(txid, "rebroadcast");
+            say!("{} was evicted from the mempool, rebroadcast it.", txid)
+        }
+        Some(error) => say!(
+            "{} was evicted from the mempool and the rebroadcast was refused: {}",
+            txid,
+            error
+        ),
+    }
+    Ok(EvictionResponse::Rebroadcast { txid: *txid, error })
+}
+
+// The chain tip as waitfornewblock reports it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tip {
+    pub hash: BlockHash,
+    pub height: u64,
+}
+
+// Block until the tip moves past `current` or `deadline` passes, whichever is
+// first, without polling: waitfornewblock long-polls on `waiter` (a
+// `session::long_poll_client`). When its timeout expires first it returns the
+// unchanged tip, and the wait is renewed until the deadline. None if no new
+// block came.
+pub fn wait_for_new_block(
+    waiter: &Client,
+    current: &BlockHash,
+    deadline: Instant,
+) -> Result<Option<Tip>> {
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        let tip: Tip = waiter.call(
+            "waitfornewblock",
+            &[json!(remaining.as_millis().max(1) as u64)],
+        )?;
+        if tip.hash != *current {
+            return Ok(Some(tip));
+        }
+    }
+}
+
+// Where a wait hears about new blocks: waitfornewblock long-polls by default,
+// the node's hashblock notifications with --zmq.
+pub enum BlockWaiter {
+    LongPoll(Client),
+    #[cfg(feature = "zmq")]
+    Zmq(std::sync::mpsc::Receiver<zmq::ZmqEvent>),
+}
+
+impl BlockWaiter {
+    // `poll` is the longest single wait the caller makes
+    pub fn new(session: &Session, poll: Duration) -> Result<Self> {
+        #[cfg(feature = "zmq")]
+        if let Some(address) = &session.zmq {
+            let address = zmq::resolve_address(&session.rpc, address, "pubhashblock")?;
+            eprintln!("Listening for blocks on {}.", address);
+            return Ok(BlockWaiter::Zmq(zmq::subscribe(&address, &["hashblock"])));
+        }
+        Ok(BlockWaiter::LongPoll(session::long_poll_client(
+            poll + LONG_POLL_MARGIN,
+        )?))
+    }
+
+    // The new tip's hash once it moves past `current`, None if `deadline` comes first
+    pub fn wait(&self, current: &BlockHash, deadline: Instant) -> Result<Option<BlockHash>> {
+        match self {
+            BlockWaiter::LongPoll(waiter) => {
+                Ok(wait_for_new_block(waiter, current, deadline)?.map(|tip| tip.hash))
+            }
+            #[cfg(feature = "zmq")]
+            BlockWaiter::Zmq(events) => loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match events.recv_timeout(remaining) {
+                    Ok(zmq::ZmqEvent::HashBlock { hash, .. }) if hash != *current => {
+                        return Ok(Some(hash))
+                    }
+                    Ok(zmq::ZmqEvent::Disconnected(reason)) => {
+                        eprintln!("ZMQ connection lost ({}), reconnecting.", reason)
+                    }
+                    Ok(_) => {}
+                    Err(_) => return Ok(None),
+                }
+            },
+        }
+    }
+}
+
+// How much longer than a long-poll the HTTP client waits for its answer
+pub const LONG_POLL_MARGIN: Duration = Duration::from_secs(15);
+
+// Blocks looked at to estimate how fast the chain is moving
+const RECENT_BLOCKS: u64 = 6;
+
+// Average seconds between the last few blocks, from getblockstats. None
+// without enough blocks to tell.
+pub fn recent_block_interval(rpc: &Client) -> Result<Option<u64>> {
+    let tip = rpc.get_block_count()?;
+    let span = RECENT_BLOCKS.min(tip);
+    if span == 0 {
+        return Ok(None);
+    }
+    let time = |height| -> Result<Option<u64>> {
+        Ok(rpc
+            .get_block_stats_fields(height, &[BlockStatsFields::Time])?
+            .time)
+    };
+    Ok(match (time(tip - span)?, time(tip)?) {
+        (Some(first), Some(last)) => Some(last.saturating_sub(first) / span),
+        _ => None,
+    })
+}
+
+// Poll the Miner wallet until `txid` has `n` confirmations, for at most
+// `timeout`. A block is not final: when the count goes backwards or the
+// confirming block changes between polls, that reorg is logged, the block's
+// height is looked up again, and the wait goes on. An unconfirmed `txid` that
+// drops out of the mempool is rebroadcast, then bumped within `max_fee`
+// (see `respond_to_eviction`), and the wait follows the replacement. With `auto_mine` on regtest
+// the wait mines the missing blocks itself instead of sleeping; elsewhere it
+// only polls, printing progress and the time left at the recent block pace.
+pub fn wait_for_confirmations(
+    session: &Session,
+    txid: &Txid,
+    n: u32,
+    timeout: Duration,
+    poll: Duration,
+    auto_mine: bool,
+    max_fee: Amount,
+) -> Result<Confirmation> {
+    let mut txid = *txid;
+    let mut evictions = Vec::new();
+    let auto_mine = auto_mine && session.network == Network::Regtest;
+    let waiter = BlockWaiter::new(session, poll)?;
+    let mut tip = session.rpc.get_best_block_hash()?;
+    let started = Instant::now();
+    let mut last: Option<(i32, Option<BlockHash>)> = None;
+    loop {
+        let info = session.miner_rpc.get_transaction(&txid, None)?.info;
+        if last.as_ref().map(|(confirmations, _)| *confirmations) != Some(info.confirmations)
+            && info.confirmations < n as i32
+        {
+            let left = n as i32 - info.confirmations.max(0);
+            let eta = match recent_block_interval(&session.rpc)? {
+                Some(interval) if !auto_mine => {
+                    format!(", about {}s to go", interval * left as u64)
+                }
+                _ => String::new(),
+            };
+            say!(
+                "{}: {} of {} confirmation(s){}",
+                txid,
+                info.confirmations.max(0),
+                n,
+                eta
+            );
+        }
+        if let Some((confirmations, block_hash)) = &last {
+            if info.confirmations < *confirmations {
+                say!(
+                    "Reorg: {} went from {} to {} confirmation(s), still waiting.",
+                    txid,
+                    confirmations,
+                    info.confirmations
+                );
+            }
+            if info.blockhash != *block_hash {
+                match &info.blockhash {
+                    Some(hash) => say!(
+                        "{} is now in block {} at height {}.",
+                        txid,
+                        hash,
+                        session.rpc.get_block_header_info(hash)?.height
+                    ),
+                    None => say!("{} is no longer in a block.", txid),
+                }
+            }
+        }
+
+        if info.confirmations >= n as i32 {
+            if let Some(block_hash) = info.blockhash {
+                let block_height = session.rpc.get_block_header_info(&block_hash)?.height;
+                return Ok(Confirmation {
+                    txid,
+                    block_hash,
+                    block_height,
+                    confirmations: info.confirmations,
+                    evictions,
+                });
+            }
+        }
+        let elapsed = started.elapsed();
+        if elapsed >= timeout {
+            return Err(CapstoneError::ConfirmationTimeout {
+                txid,
+                confirmations: info.confirmations,
+                wanted: n,
+            });
+        }
+        if info.confirmations == 0
+            && mempool::snapshot(&session.rpc, &session.miner_rpc, &txid)?.is_none()
+        {
+            let response =
+                respond_to_eviction(&session.rpc, &session.miner_rpc, &txid, &evictions, max_fee)?;
+            if let EvictionResponse::Bumped { to, .. } = &response {
+                txid = to.txid;
+                last = None;
+                evictions.push(response);
+                continue;
+            }
+            evictions.push(response);
+        }
+        let in_block = info.blockhash.is_some() && info.confirmations > 0;
+        last = Some((info.confirmations, info.blockhash));
+        if auto_mine {
+            // Only the first block has to pick the transaction up; the rest
+            // just bury it, so they may be empty with --empty-blocks
+            if in_block {
+                session.advance(1, &session.miner_address)?;
+            } else {
+                session.generate(1, &session.miner_address)?;
+            }
+            continue;
+        }
+        // Confirmations only change with the tip; `poll` still bounds the
+        // wait so the wallet is asked again now and then
+        if let Some(new_tip) = waiter.wait(&tip, Instant::now() + poll.min(timeout - elapsed))? {
+            tip = new_tip;
+        }
+    }
+}
+
+// mine-with: mine one block with exactly the --tx transactions.
+pub fn run(session: &Session, config: &Config, transactions: &[String]) -> Result<()> {
+    let to = match &config.mine_to {
+        Some(address) => address
+            .parse::<Address<NetworkUnchecked>>()
+            .map_err(|e| {
+                CapstoneError::Usage(format!("invalid --to address '{}': {}", address, e))
+            })?
+            .require_network(session.network)
+            .map_err(|e| {
+                CapstoneError::Usage(format!("invalid --to address '{}': {}", address, e))
+            })?,
+        None => session.miner_address.clone(),
+    };
+
+    let block_hash = generate_block(session, &to, transactions)?;
+    let block = session.rpc.get_block_info(&block_hash)?;
+    say!(
+        "Mined block {} at height {} paying {}.",
+        block_hash,
+        block.height,
+        to
+    );
+    // The first transaction is the coinbase
+    for txid in block.tx.iter().skip(1) {
+        say!("Included {}", txid);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mockrpc;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+
+    // A node that keeps evicting the payment: every sendrawtransaction is
+    // accepted, and the wallet hands out a replacement when asked to bump it
+    fn evicting_node(replaceable: bool) -> (Client, std::sync::Arc<std::sync::Mutex<Vec<String>>>) {
+        let tx = mockrpc::transaction();
+        let wallet_tx = mockrpc::wallet_tx(&tx, 0, replaceable);
+        mockrpc::client(move |method, params| match method {
+            "gettransaction" => Ok(wallet_tx.clone()),
+            "sendrawtransaction" => {
+                assert_eq!(params[0], wallet_tx["hex"]);
+                Ok(json!(tx.txid()))
+            }
+            "getmempoolinfo" => Ok(mockrpc::mempool_info()),
+            "getnetworkinfo" => Ok(mockrpc::network_info()),
+            // Every relay policy probe passes
+            "testmempoolaccept" => Ok(json!([{
+                "txid": Txid::all_zeros(),
+                "allowed": false,
+                "reject-reason": "missing-inputs"
+            }])),
+            "bumpfee" => Ok(json!({
+                "txid": Txid::from_byte_array([7; 32]),
+                "origfee": 0.00001,
+                "fee": 0.00002,
+                "errors": []
+            })),
+            _ => panic!("unexpected {}", method),
+        })
+    }
+
+    fn evict(rpc: &Client, txid: &Txid, evictions: &mut Vec<EvictionResponse>) -> EvictionResponse {
+        let response =
+            respond_to_eviction(rpc, rpc, txid, evictions, Amount::from_sat(100_000)).unwrap();
+        evictions.push(response.clone());
+        response
+    }
+
+    #[test]
+    fn rebroadcasts_then_bumps_after_repeated_evictions() {
+        let (rpc, calls) = evicting_node(true);
+        let txid = mockrpc::transaction().txid();
+        let mut evictions = Vec::new();
+        for _ in 0..MAX_REBROADCASTS {
+            let response = evict(&rpc, &txid, &mut evictions);
+            assert!(matches!(
+                response,
+                EvictionResponse::Rebroadcast { error: None, .. }
+            ));
+        }
+        assert_eq!(
+            calls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|method| *method == "sendrawtransaction")
+                .count(),
+            MAX_REBROADCASTS
+        );
+
+        let EvictionResponse::Bumped { from, to } = evict(&rpc, &txid, &mut evictions) else {
+            panic!("expected a bump, got {:?}", evictions.last());
+        };
+        assert_eq!(from, txid);
+        assert_eq!(to.txid, Txid::from_byte_array([7; 32]));
+        assert_eq!(to.fee, Amount::from_sat(2_000));
+        // 1000 sat over the 62 vB transaction is 16.129 sat/vB, half as much again
+        assert_eq!(mockrpc::transaction().vsize(), 62);
+        assert_eq!(to.sat_per_kvb, 24_193);
+        assert_eq!(calls.lock().unwrap().last().unwrap(), "bumpfee");
+        assert_eq!(
+            evictions[3].describe(),
+            format!(
+                "replaced evicted {} with {} at 24.193 sat/vB",
+                txid,
+                Txid::from_byte_array([7; 32])
+            )
+        );
+    }
+
+    #[test]
+    fn the_report_summarizes_the_evictions() {
+        let (rpc, _) = evicting_node(true);
+        let txid = mockrpc::transaction().txid();
+        let mut report = crate::report::tests::fixture();
+        for _ in 0..=MAX_REBROADCASTS {
+            evict(&rpc, &txid, &mut report.evictions);
+        }
+        let text = report.to_text();
+        let line = text
+            .lines()
+            .find(|line| line.starts_with("Mempool evictions: "))
+            .unwrap();
+        let rebroadcast = format!("rebroadcast {} after eviction", txid);
+        assert_eq!(
+            line,
+            format!(
+                "Mempool evictions: 4 ({}; {}; {}; replaced evicted {} with {} at 24.193 sat/vB)",
+                rebroadcast,
+                rebroadcast,
+                rebroadcast,
+                txid,
+                Txid::from_byte_array([7; 32])
+            )
+        );
+        assert!(!crate::report::tests::fixture()
+            .to_text()
+            .contains("Mempool evictions"));
+    }
+
+    #[test]
+    fn a_final_transaction_is_only_ever_rebroadcast() {
+        let (rpc, calls) = evicting_node(false);
+        let txid = mockrpc::transaction().txid();
+        let mut evictions = Vec::new();
+        for _ in 0..MAX_REBROADCASTS + 2 {
+            let response = evict(&rpc, &txid, &mut evictions);
+            assert!(matches!(response, EvictionResponse::Rebroadcast { .. }));
+        }
+        assert!(!calls
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|method| method == "bumpfee"));
+    }
+
+    #[test]
+    fn a_refused_rebroadcast_is_recorded() {
+        let tx = mockrpc::transaction();
+        let wallet_tx = mockrpc::wallet_tx(&tx, 0, true);
+        let (rpc, _) = mockrpc::client(move |method, _| match method {
+            "gettransaction" => Ok(wallet_tx.clone()),
+            "sendrawtransaction" => Err((-26, "mempool min fee not met")),
+            _ => panic!("unexpected {}", method),
+        });
+        let response = evict(&rpc, &tx.txid(), &mut Vec::new());
+        let EvictionResponse::Rebroadcast {
+            error: Some(error), ..
+        } = &response
+        else {
+            panic!("expected a refused rebroadcast, got {:?}", response);
+        };
+        assert!(error.contains("mempool min fee not met"), "{}", error);
+        assert!(response.describe().starts_with(&format!(
+            "rebroadcast {} after eviction, refused: ",
+            tx.txid()
+        )));
+    }
+}
@@ -0,0 +1,276 @@
+// A node for the tests that go through the RPC client: a handler per test
+// answers each call, and every method called is logged in order.
+use bitcoincore_rpc::bitcoin::consensus::encode;
+use bitcoincore_rpc::bitcoin::hashes::Hash;
+use bitcoincore_rpc::bitcoin::{
+    absolute, transaction, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid,
+    Witness,
+};
+use bitcoincore_rpc::jsonrpc::error::RpcError;
+use bitcoincore_rpc::jsonrpc::{self, Request, Response, Transport};
+use bitcoincore_rpc::Client;
+use serde_json::value::RawValue;
+use serde_json::{json, Value};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+// The result of a call, or the node's error code and message
+pub type Reply = std::result::Result<Value, (i32, &'static str)>;
+
+type Handler = Box<dyn FnMut(&str, &[Value]) -> Reply + Send>;
+type SharedHandler = Box<dyn Fn(&str, &[Value]) -> Reply + Send + Sync>;
+
+struct Scripted {
+    handler: Mutex<Handler>,
+    calls: Arc<Mutex<Vec<String>>>,
+}
+
+fn params(request: &Request) -> Vec<Value> {
+    request
+        .params
+        .iter()
+        .map(|param| serde_json::from_str(param.get()).unwrap())
+        .collect()
+}
+
+fn respond(request: &Request, reply: Reply) -> Response {
+    let (result, error) = match reply {
+        Ok(value) => (
+            Some(RawValue::from_string(value.to_string()).unwrap()),
+            None,
+        ),
+        Err((code, message)) => (
+            None,
+            Some(RpcError {
+                code,
+                message: message.into(),
+                data: None,
+            }),
+        ),
+    };
+    Response {
+        result,
+        error,
+        id: request.id.clone(),
+        jsonrpc: Some("2.0".into()),
+    }
+}
+
+impl Transport for Scripted {
+    fn send_request(&self, request: Request) -> std::result::Result<Response, jsonrpc::Error> {
+        self.calls.lock().unwrap().push(request.method.to_string());
+        let reply = (self.handler.lock().unwrap())(request.method, &params(&request));
+        Ok(respond(&request, reply))
+    }
+
+    fn send_batch(
+        &self,
+        requests: &[Request],
+    ) -> std::result::Result<Vec<Response>, jsonrpc::Error> {
+        requests
+            .iter()
+            .map(|request| self.send_request(request.clone()))
+            .collect()
+    }
+
+    fn fmt_target(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "scripted")
+    }
+}
+
+// Like Scripted, but the handler runs without a lock, so calls made from
+// several threads overlap as they would on a real node.
+struct Concurrent {
+    handler: SharedHandler,
+    calls: Arc<Mutex<Vec<String>>>,
+}
+
+impl Transport for Concurrent {
+    fn send_request(&self, request: Request) -> std::result::Result<Response, jsonrpc::Error> {
+        self.calls.lock().unwrap().push(request.method.to_string());
+        let reply = (self.handler)(request.method, &params(&request));
+        Ok(respond(&request, reply))
+    }
+
+    fn send_batch(
+        &self,
+        requests: &[Request],
+    ) -> std::result::Result<Vec<Response>, jsonrpc::Error> {
+        requests
+            .iter()
+            .map(|request| self.send_request(request.clone()))
+            .collect()
+    }
+
+    fn fmt_target(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "concurrent")
+    }
+}
+
+// A client answered by `handler`, and the log of the methods it was asked
+pub fn client(
+    handler: impl FnMut(&str, &[Value]) -> Reply + Send + 'static,
+) -> (Client, Arc<Mutex<Vec<String>>>) {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let transport = Scripted {
+        handler: Mutex::new(Box::new(handler)),
+        calls: calls.clone(),
+    };
+    let client = Client::from_jsonrpc(jsonrpc::Client::with_transport(transport));
+    (client, calls)
+}
+
+// `client` for tests that call from several threads at once
+pub fn concurrent_client(
+    handler: impl Fn(&str, &[Value]) -> Reply + Send + Sync + 'static,
+) -> (Client, Arc<Mutex<Vec<String>>>) {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let transport = Concurrent {
+        handler: Box::new(handler),
+        calls: calls.clone(),
+    };
+    let client = Client::from_jsonrpc(jsonrpc::Client::with_transport(transport));
+    (client, calls)
+}
+
+// getmempoolentry's answer for a transaction that isn't there
+pub const NOT_IN_MEMPOOL: Reply = Err((-5, "Transaction not in mempool"));
+
+// A one-input, one-output transaction for gettransaction and
+// sendrawtransaction to carry
+pub fn transaction() -> Transaction {
+    Transaction {
+        version: transaction::Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(Txid::all_zeros(), 0),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::from_sat(49_999_000),
+            script_pubkey: ScriptBuf::new_op_return([]),
+        }],
+    }
+}
+
+// gettransaction for `tx` with `confirmations`
+pub fn wallet_tx(tx: &Transaction, confirmations: i32, replaceable: bool) -> Value {
+    json!({
+        "amount": -0.5,
+        "fee": -0.00001,
+        "confirmations": confirmations,
+        "txid": tx.txid(),
+        "time": 1700000000,
+        "timereceived": 1700000000,
+        "bip125-replaceable": if replaceable { "yes" } else { "no" },
+        "walletconflicts": [],
+        "details": [],
+        "hex": encode::serialize_hex(tx),
+    })
+}
+
+// getmempoolinfo of a regtest node with default relay settings
+pub fn mempool_info() -> Value {
+    json!({
+        "loaded": true,
+        "size": 1,
+        "bytes": 141,
+        "usage": 1024,
+        "total_fee": 0.00001,
+        "maxmempool": 300000000,
+        "mempoolminfee": 0.00001,
+        "minrelaytxfee": 0.00001,
+        "incrementalrelayfee": 0.00001,
+        "unbroadcastcount": 0,
+        "fullrbf": false
+    })
+}
+
+// getnetworkinfo of the same node
+pub fn network_info() -> Value {
+    json!({
+        "version": 250000,
+        "subversion": "/Satoshi:25.0.0/",
+        "protocolversion": 70016,
+        "localservices": "0000000000000c09",
+        "localrelay": true,
+        "timeoffset": 0,
+        "connections": 0,
+        "connections_in": 0,
+        "connections_out": 0,
+        "networkactive": true,
+        "networks": [],
+        "relayfee": 0.00001,
+        "incrementalfee": 0.00001,
+        "localaddresses": [],
+        "warnings": ""
+    })
+}
+
+// getmempoolentry for `tx` alone in the mempool at 1 sat/vB
+pub fn mempool_entry(tx: &Transaction) -> Value {
+    let vsize = tx.vsize();
+    let fee = Amount::from_sat(vsize as u64).to_btc();
+    json!({
+        "vsize": vsize,
+        "weight": tx.weight().to_wu(),
+        "time": 1700000000,
+        "height": 101,
+        "descendantcount": 1,
+        "descendantsize": vsize,
+        "ancestorcount": 1,
+        "ancestorsize": vsize,
+        "wtxid": tx.wtxid(),
+        "fees": { "base": fee, "modified": fee, "ancestor": fee, "descendant": fee },
+        "depends": [],
+        "spentby": [],
+        "bip125-replaceable": true,
+        "unbroadcast": false
+    })
+}
+
+// getblockchaininfo of a regtest node at height 101, pruned up to
+// `prune_height` if given
+pub fn blockchain_info(prune_height: Option<u64>) -> Value {
+    let mut info = json!({
+        "chain": "regtest",
+        "blocks": 101,
+        "headers": 101,
+        "bestblockhash": "3b821acd7c32c2b3da143e2c6b0134e5aa8206aeae0a54bfa4963e73ac2857a0",
+        "difficulty": 4.656542373906925e-10,
+        "time": 1700000000,
+        "mediantime": 1699999000,
+        "verificationprogress": 1,
+        "initialblockdownload": false,
+        "chainwork": "00000000000000000000000000000000000000000000000000000000000000cc",
+        "size_on_disk": 30000,
+        "pruned": prune_height.is_some(),
+        "warnings": ""
+    });
+    if let Some(prune_height) = prune_height {
+        info["pruneheight"] = json!(prune_height);
+        info["automatic_pruning"] = json!(false);
+    }
+    info
+}
+
+// getblockheader for the block `hash` at `height` on the active chain
+pub fn block_header(hash: &str, height: u64) -> Value {
+    json!({
+        "hash": hash,
+        "confirmations": 1,
+        "height": height,
+        "version": 536870912,
+        "versionHex": "20000000",
+        "merkleroot": "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+        "time": 1700000000,
+        "mediantime": 1699999000,
+        "nonce": 0,
+        "bits": "207fffff",
+        "difficulty": 4.656542373906925e-10,
+        "chainwork": "00000000000000000000000000000000000000000000000000000000000000cc",
+        "nTx": 1
+    })
+}
@@ -0,0 +1,188 @@
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::session::{self, Session};
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::blockdata::opcodes::all::OP_CHECKMULTISIG;
+use bitcoincore_rpc::bitcoin::blockdata::script::Builder;
+use bitcoincore_rpc::bitcoin::{Address, Network, PublicKey, ScriptBuf};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+
+// Core's limits for multisig in P2WSH: at most 20 keys, compressed only
+const MAX_KEYS: usize = 20;
+
+// An M-of-N sortedmulti P2WSH address and the descriptor describing it.
+#[derive(Debug, Clone)]
+pub struct Multisig {
+    pub required: usize,
+    // Sorted, as sortedmulti orders them
+    pub keys: Vec<PublicKey>,
+    pub witness_script: ScriptBuf,
+    pub address: Address,
+    // With checksum
+    pub descriptor: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateMultisigResult {
+    address: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DescriptorInfo {
+    descriptor: String,
+}
+
+// Parse and check the keys the way Core would before building anything.
+pub fn parse_keys(required: usize, keys: &[String]) -> Result<Vec<PublicKey>> {
+    if keys.is_empty() {
+        return Err(CapstoneError::Usage(
+            "multisig needs at least one key (--keys)".into(),
+        ));
+    }
+    if keys.len() > MAX_KEYS {
+        return Err(CapstoneError::Usage(format!(
+            "{} keys given, multisig allows at most {}",
+            keys.len(),
+            MAX_KEYS
+        )));
+    }
+    if required == 0 || required > keys.len() {
+        return Err(CapstoneError::Usage(format!(
+            "--require must be between 1 and the number of keys ({}), got {}",
+            keys.len(),
+            required
+        )));
+    }
+
+    let mut parsed = Vec::with_capacity(keys.len());
+    for key in keys {
+        let pubkey: PublicKey = key
+            .parse()
+            .map_err(|e| CapstoneError::Usage(format!("invalid public key '{}': {}", key, e)))?;
+        if !pubkey.compressed {
+            return Err(CapstoneError::Usage(format!(
+                "uncompressed public key '{}' is not allowed in segwit multisig",
+                key
+            )));
+        }
+        parsed.push(pubkey);
+    }
+    Ok(parsed)
+}
+
+// Build the address locally, then have createmultisig build it too and make
+// sure both agree before anything uses it.
+pub fn build(
+    rpc: &Client,
+    required: usize,
+    keys: &[PublicKey],
+    network: Network,
+) -> Result<Multisig> {
+    let mut keys = keys.to_vec();
+    keys.sort_by_key(|key| key.to_bytes());
+
+    let mut builder = Builder::new().push_int(required as i64);
+    for key in &keys {
+        builder = builder.push_key(key);
+    }
+    let witness_script = builder
+        .push_int(keys.len() as i64)
+        .push_opcode(OP_CHECKMULTISIG)
+        .into_script();
+    let address = Address::p2wsh(&witness_script, network);
+
+    let hex_keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+    let created: CreateMultisigResult = rpc.call(
+        "createmultisig",
+        &[json!(required), json!(hex_keys), json!("bech32")],
+    )?;
+    let node_address = created
+        .address
+        .parse::<Address<NetworkUnchecked>>()
+        .map_err(|e| CapstoneError::Usage(format!("createmultisig returned a bad address: {}", e)))?
+        .assume_checked();
+    if node_address != address {
+        return Err(CapstoneError::Usage(format!(
+            "createmultisig built {} but the local sortedmulti script gives {}",
+            node_address, address
+        )));
+    }
+
+    // getdescriptorinfo adds the checksum
+    let descriptor = format!("wsh(sortedmulti({},{}))", required, hex_keys.join(","));
+    let info: DescriptorInfo = rpc.call("getdescriptorinfo", &[json!(descriptor)])?;
+
+    Ok(Multisig {
+        required,
+        keys,
+        witness_script,
+        address,
+        descriptor: info.descriptor,
+    })
+}
+
+// Make `wallet` watch the multisig address: importdescriptors for descriptor
+// wallets, addmultisigaddress for legacy ones.
+pub fn register(wallet: &str, multisig: &Multisig) -> Result<()> {
+    let wallet_rpc = session::wallet_client(wallet)?;
+    let info: serde_json::Value = wallet_rpc.call("getwalletinfo", &[])?;
+
+    if info["descriptors"].as_bool().unwrap_or(false) {
+        let results: Vec<serde_json::Value> = wallet_rpc.call(
+            "importdescriptors",
+            &[json!([{ "desc": multisig.descriptor, "timestamp": "now" }])],
+        )?;
+        if let Some(failed) = results
+            .iter()
+            .find(|r| !r["success"].as_bool().unwrap_or(false))
+        {
+            return Err(CapstoneError::Usage(format!(
+                "importdescriptors into '{}' failed: {}",
+                wallet, failed["error"]["message"]
+            )));
+        }
+    } else {
+        let keys: Vec<String> = multisig.keys.iter().map(|key| key.to_string()).collect();
+        wallet_rpc.call::<serde_json::Value>(
+            "addmultisigaddress",
+            &[
+                json!(multisig.required),
+                json!(keys),
+                json!(""),
+                json!("bech32"),
+            ],
+        )?;
+    }
+    say!("Registered {} with wallet '{}'.", multisig.address, wallet);
+    Ok(())
+}
+
+// multisig-address: print the address and descriptor, and optionally register it.
+pub fn run(
+    session: &Session,
+    required: usize,
+    keys: &[String],
+    register_with: Option<&str>,
+) -> Result<()> {
+    let keys = parse_keys(required, keys)?;
+    let multisig = build(&session.rpc, required, &keys, session.network)?;
+
+    say!(
+        "{}-of-{} multisig address: {}",
+        required,
+        keys.len(),
+        multisig.address
+    );
+    say!(
+        "Witness script: {}",
+        multisig.witness_script.to_hex_string()
+    );
+    say!("Descriptor: {}", multisig.descriptor);
+
+    if let Some(wallet) = register_with {
+        register(wallet, &multisig)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use crate::console::say;
+use crate::error::Result;
+use crate::fees;
+use crate::version::CoreVersion;
+use bitcoincore_rpc::bitcoin::Amount;
+use bitcoincore_rpc::{Client, RpcApi};
+
+// The node's software and network health, from getnetworkinfo, getnettotals
+// and uptime.
+#[derive(Debug, Clone)]
+pub struct NodeInfo {
+    pub version: CoreVersion,
+    // e.g. /Satoshi:26.1.0/
+    pub subversion: String,
+    pub protocol_version: usize,
+    pub connections: usize,
+    // Split by direction on Core 21+
+    pub connections_in: Option<usize>,
+    pub connections_out: Option<usize>,
+    pub network_active: bool,
+    // BTC/kvB
+    pub relay_fee: Amount,
+    pub incremental_fee: Amount,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    // Seconds since bitcoind started
+    pub uptime: u64,
+    pub warnings: String,
+}
+
+pub fn node_info(rpc: &Client) -> Result<NodeInfo> {
+    let network = rpc.get_network_info()?;
+    let totals = rpc.get_net_totals()?;
+    Ok(NodeInfo {
+        version: CoreVersion(network.version as u64),
+        subversion: network.subversion,
+        protocol_version: network.protocol_version,
+        connections: network.connections,
+        connections_in: network.connections_in,
+        connections_out: network.connections_out,
+        network_active: network.network_active,
+        relay_fee: network.relay_fee,
+        incremental_fee: network.incremental_fee,
+        bytes_sent: totals.total_bytes_sent,
+        bytes_received: totals.total_bytes_recv,
+        uptime: rpc.uptime()?,
+        warnings: network.warnings,
+    })
+}
+
+// 1d 2h 3m 4s, leaving out leading zero units
+fn format_duration(seconds: u64) -> String {
+    let parts = [
+        (seconds / 86_400, "d"),
+        (seconds / 3_600 % 24, "h"),
+        (seconds / 60 % 60, "m"),
+        (seconds % 60, "s"),
+    ];
+    let first = parts
+        .iter()
+        .position(|(value, _)| *value != 0)
+        .unwrap_or(parts.len() - 1);
+    parts[first..]
+        .iter()
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl NodeInfo {
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!(
+                "Node version: {} ({}), protocol {}",
+                self.version, self.subversion, self.protocol_version
+            ),
+            format!(
+                "Node connections: {}{}{}",
+                self.connections,
+                match (self.connections_in, self.connections_out) {
+                    (Some(inbound), Some(outbound)) => {
+                        format!(" ({} in, {} out)", inbound, outbound)
+                    }
+                    _ => String::new(),
+                },
+                if self.network_active {
+                    ""
+                } else {
+                    ", networking disabled"
+                }
+            ),
+            format!(
+                "Node relay fee: {} sat/vB minimum, {} sat/vB incremental",
+                fees::format_sat_per_vb(self.relay_fee.to_sat()),
+                fees::format_sat_per_vb(self.incremental_fee.to_sat())
+            ),
+            format!(
+                "Node traffic: {} bytes sent, {} bytes received",
+                self.bytes_sent, self.bytes_received
+            ),
+            format!(
+                "Node uptime: {} ({}s)",
+                format_duration(self.uptime),
+                self.uptime
+            ),
+        ];
+        if !self.warnings.is_empty() {
+            lines.push(format!("Node warnings: {}", self.warnings));
+        }
+        lines
+    }
+}
+
+// node-info: print the summary.
+pub fn run(rpc: &Client) -> Result<()> {
+    for line in node_info(rpc)?.describe() {
+        say!("{}", line);
+    }
+    Ok(())
+}
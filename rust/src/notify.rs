@@ -0,0 +1,369 @@
+use crate::btc;
+use crate::error::{CapstoneError, Result};
+use crate::report::TransactionReport;
+use bitcoincore_rpc::bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoincore_rpc::bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoincore_rpc::bitcoin::Amount;
+use serde_json::json;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+// The shared secret for the signature header; unset means unsigned callbacks
+pub const SECRET_ENV: &str = "CAPSTONE_NOTIFY_SECRET";
+// `sha256=<hex HMAC-SHA256 of the body>`, as GitHub signs its webhooks
+const SIGNATURE_HEADER: &str = "X-Capstone-Signature-256";
+
+// Deliveries are tried this often, a second apart and doubling, while the
+// receiver can't be reached or answers with a 5xx
+const ATTEMPTS: u32 = 4;
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+// Per connect, write and read
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// --notify-url taken apart. Only plain HTTP: there is no TLS client to speak
+// https with, so receivers go behind a local relay or tunnel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotifyUrl {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl NotifyUrl {
+    pub fn parse(url: &str) -> Result<Self> {
+        let invalid = |reason: &str| {
+            CapstoneError::Usage(format!("invalid --notify-url '{}': {}", url, reason))
+        };
+        if url.starts_with("https://") {
+            return Err(invalid(
+                "https isn't supported, this build has no TLS client; use an http URL",
+            ));
+        }
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| invalid("expected http://host[:port]/path"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        // An IPv6 host comes in brackets, [::1]:8080
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => bracketed
+                .split_once(']')
+                .map(|(host, port)| (host, port.strip_prefix(':')))
+                .ok_or_else(|| invalid("unclosed '['"))?,
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| invalid("the port is not a number"))?,
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(invalid("no host"));
+        }
+        Ok(NotifyUrl {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl NotifyUrl {
+    // host:port for the Host header, IPv6 hosts back in brackets
+    fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl std::fmt::Display for NotifyUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}{}", self.authority(), self.path)
+    }
+}
+
+// How a callback went, for the report.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub url: String,
+    pub attempts: u32,
+    pub signed: bool,
+    // The receiver's HTTP status, or why no answer came
+    pub outcome: std::result::Result<u16, String>,
+}
+
+impl Delivery {
+    pub fn delivered(&self) -> bool {
+        matches!(self.outcome, Ok(status) if (200..300).contains(&status))
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "Webhook notification: {} to {} after {} attempt(s){}",
+            match &self.outcome {
+                Ok(status) if self.delivered() => format!("delivered (HTTP {})", status),
+                Ok(status) => format!("refused (HTTP {})", status),
+                Err(reason) => format!("failed ({})", reason),
+            },
+            self.url,
+            self.attempts,
+            if self.signed { ", signed" } else { "" }
+        )
+    }
+}
+
+// The callback body for a confirmed payment. Amounts are exact 8-decimal BTC
+// strings, as in the JSON report, with integer satoshis next to them.
+pub fn payload(report: &TransactionReport) -> serde_json::Value {
+    let amount = report.trader_output.as_ref().map(|(amount, _)| *amount);
+    json!({
+        "event": "confirmed",
+        "txid": report.txid,
+        "amount": amount.map(btc::format_btc),
+        "amount_sat": amount.map(Amount::to_sat),
+        "trader_address": report.trader_output.as_ref().map(|(_, address)| address.to_string()),
+        "block_hash": report.block_hash,
+        "height": report.block_height,
+        "confirmations": report.confirmations,
+        "fee": btc::format_btc(report.fee),
+        "fee_sat": report.fee.to_sat(),
+    })
+}
+
+// Hex HMAC-SHA256 of `body` under `secret`
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut engine = HmacEngine::<sha256::Hash>::new(secret);
+    engine.input(body);
+    Hmac::<sha256::Hash>::from_engine(engine).to_string()
+}
+
+// One POST, returning the status code of the answer
+fn post(url: &NotifyUrl, body: &str, signature: Option<&str>) -> std::io::Result<u16> {
+    let address = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::other(format!("{} doesn't resolve", url.host)))?;
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        url.path,
+        url.authority(),
+        body.len()
+    );
+    if let Some(signature) = signature {
+        request.push_str(&format!("{}: sha256={}\r\n", SIGNATURE_HEADER, signature));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes())?;
+
+    // Only the status line matters: "HTTP/1.1 200 OK"
+    let mut response = Vec::new();
+    let mut buf = [0; 512];
+    while !response.contains(&b'\n') {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..n]);
+    }
+    let status_line = String::from_utf8_lossy(&response);
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| std::io::Error::other("no HTTP status line in the answer"))
+}
+
+// POST `payload` to `url`, signed when SECRET_ENV is set. A receiver that
+// can't be reached or answers 5xx is tried again; any other answer is final.
+// Failing to deliver is recorded, not an error: the payment has confirmed
+// either way.
+pub fn notify(url: &NotifyUrl, payload: &serde_json::Value) -> Delivery {
+    let secret = std::env::var(SECRET_ENV).ok();
+    deliver(
+        url,
+        payload,
+        secret.as_deref().filter(|secret| !secret.is_empty()),
+    )
+}
+
+fn deliver(url: &NotifyUrl, payload: &serde_json::Value, secret: Option<&str>) -> Delivery {
+    let body = payload.to_string();
+    let signature = secret.map(|secret| sign(secret.as_bytes(), body.as_bytes()));
+    let mut delay = RETRY_BACKOFF;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let outcome = post(url, &body, signature.as_deref()).map_err(|e| e.to_string());
+        let retry = match &outcome {
+            Ok(status) => *status >= 500,
+            Err(_) => true,
+        };
+        if !retry || attempts == ATTEMPTS {
+            return Delivery {
+                url: url.to_string(),
+                attempts,
+                signed: signature.is_some(),
+                outcome,
+            };
+        }
+        thread::sleep(delay);
+        delay *= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::tests::fixture;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    // What the receiver got: request line, headers and body
+    struct Request {
+        line: String,
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Request {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    fn read_request(stream: &TcpStream) -> Request {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let mut headers = Vec::new();
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (key, value) = header.split_once(": ").unwrap();
+            headers.push((key.to_string(), value.to_string()));
+        }
+        let mut request = Request {
+            line: line.trim_end().to_string(),
+            headers,
+            body: String::new(),
+        };
+        let len: usize = request.header("Content-Length").unwrap().parse().unwrap();
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).unwrap();
+        request.body = String::from_utf8(body).unwrap();
+        request
+    }
+
+    // A receiver answering each connection with the next of `statuses`,
+    // handing back the requests once it has answered them all
+    fn receiver(
+        statuses: &'static [&'static str],
+    ) -> (NotifyUrl, thread::JoinHandle<Vec<Request>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            statuses
+                .iter()
+                .map(|status| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let request = read_request(&stream);
+                    write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+                    request
+                })
+                .collect()
+        });
+        let url = NotifyUrl::parse(&format!("http://127.0.0.1:{}/hook", port)).unwrap();
+        (url, handle)
+    }
+
+    #[test]
+    fn parses_urls() {
+        let url = NotifyUrl::parse("http://[::1]:8080/a/b").unwrap();
+        assert_eq!(
+            (url.host.as_str(), url.port, url.path.as_str()),
+            ("::1", 8080, "/a/b")
+        );
+        assert_eq!(url.to_string(), "http://[::1]:8080/a/b");
+        let url = NotifyUrl::parse("http://example.com").unwrap();
+        assert_eq!((url.port, url.path.as_str()), (80, "/"));
+        assert!(NotifyUrl::parse("https://example.com/").is_err());
+        assert!(NotifyUrl::parse("http://:80/").is_err());
+        assert!(NotifyUrl::parse("http://host:port/").is_err());
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn retries_after_a_500_then_delivers() {
+        let (url, receiver) = receiver(&["500 Internal Server Error", "200 OK"]);
+        let payload = payload(&fixture());
+        let delivery = deliver(&url, &payload, Some("shared secret"));
+        let requests = receiver.join().unwrap();
+
+        assert!(delivery.delivered());
+        assert_eq!(delivery.attempts, 2);
+        assert!(delivery.signed);
+        assert_eq!(requests.len(), 2);
+        for request in &requests {
+            assert_eq!(request.line, "POST /hook HTTP/1.1");
+            assert_eq!(request.header("Content-Type"), Some("application/json"));
+            let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+            assert_eq!(body, payload);
+            let expected = format!("sha256={}", sign(b"shared secret", request.body.as_bytes()));
+            assert_eq!(request.header(SIGNATURE_HEADER), Some(expected.as_str()));
+        }
+        let body: serde_json::Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(body["txid"], fixture().txid.to_string());
+        assert_eq!(body["height"], 102);
+        assert_eq!(body["amount"], "20.00000000");
+        assert_eq!(body["amount_sat"], 2_000_000_000);
+        assert_eq!(body["fee"], "0.00001410");
+        assert_eq!(body["fee_sat"], 1_410);
+        assert_eq!(
+            body["trader_address"],
+            "bcrt1qak6gpu2p6zjpwrhvd4dvdnp4rt3ysm9rpst3wu"
+        );
+    }
+
+    #[test]
+    fn a_4xx_is_final_and_unsigned_without_a_secret() {
+        let (url, receiver) = receiver(&["404 Not Found"]);
+        let delivery = deliver(&url, &payload(&fixture()), None);
+        let requests = receiver.join().unwrap();
+
+        assert!(!delivery.delivered());
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(requests[0].header(SIGNATURE_HEADER), None);
+        assert!(delivery.describe().contains("refused (HTTP 404)"));
+    }
+}
@@ -0,0 +1,775 @@
+use crate::accept;
+use crate::btc;
+use crate::bump;
+use crate::cli::Config;
+use crate::console::say;
+use crate::decode;
+use crate::deployments;
+use crate::dust;
+use crate::error::{CapstoneError, Result};
+use crate::fees::{self, FeeRateChoice};
+use crate::files;
+use crate::filters;
+use crate::grouping;
+use crate::inputs::PresetInputs;
+use crate::limits;
+use crate::mempool::{self, MempoolSnapshot};
+use crate::mine;
+use crate::nodeinfo;
+use crate::notify;
+use crate::pending;
+use crate::prioritise;
+use crate::proof;
+use crate::psbt;
+use crate::raw;
+use crate::reconcile;
+use crate::relay;
+use crate::report::{self, TransactionReport};
+use crate::runinfo::RunInfo;
+use crate::session::Session;
+use crate::signing;
+use crate::tips;
+use crate::txidlog;
+use crate::version::{self, Capability};
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::{Address, Amount, Network, Txid};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
+
+// You can use calls not provided in RPC lib API using the generic `call` function.
+// An example of using the `send` RPC call, which doesn't have exposed API.
+// You can also use serde_json `Deserialize` derivation to capture the returned json result.
+pub fn send(
+    rpc: &Client,
+    addr: &str,
+    amount: Amount,
+    config: &Config,
+    options: serde_json::Map<String, serde_json::Value>,
+) -> Result<Txid> {
+    send_to_outputs(rpc, &[(addr.to_string(), amount)], config, options)
+}
+
+// Same as `send`, paying several recipients in one transaction.
+pub fn send_to_outputs(
+    rpc: &Client,
+    outputs: &[(String, Amount)],
+    config: &Config,
+    options: serde_json::Map<String, serde_json::Value>,
+) -> Result<Txid> {
+    let send_result = call_send(rpc, outputs, config, options)?;
+    if !send_result.complete {
+        return Err(match send_result.psbt {
+            Some(psbt) => incomplete_send(rpc, psbt, config),
+            None => CapstoneError::Usage(
+                "send could not sign the transaction and returned no PSBT".into(),
+            ),
+        });
+    }
+    send_result
+        .txid
+        .ok_or_else(|| CapstoneError::Usage("send did not broadcast the transaction".into()))
+}
+
+// `send` returned a PSBT because the wallet couldn't sign every input. Ask
+// signrawtransactionwithwallet which ones and why for the diagnostics table;
+// the PSBT is handed back either way so it can be signed elsewhere.
+fn incomplete_send(rpc: &Client, psbt: String, config: &Config) -> CapstoneError {
+    let errors = psbt::unsigned_tx_hex(&psbt)
+        .and_then(|hex| signing::sign_with_wallet(rpc, &hex, config.sighash()))
+        .map(|result| {
+            result.report_errors();
+            result.errors
+        })
+        .unwrap_or_default();
+    CapstoneError::SendIncomplete { psbt, errors }
+}
+
+// What `send` returns: the txid once broadcast, or the signed hex when
+// add_to_wallet=false, or a PSBT when the wallet could not sign everything.
+#[derive(Debug, Deserialize)]
+pub struct SendResult {
+    pub complete: bool,
+    pub txid: Option<Txid>,
+    pub hex: Option<String>,
+    pub psbt: Option<String>,
+}
+
+// Call `send` and return its raw result.
+pub fn call_send(
+    rpc: &Client,
+    outputs: &[(String, Amount)],
+    config: &Config,
+    options: serde_json::Map<String, serde_json::Value>,
+) -> Result<SendResult> {
+    version::require(rpc, Capability::Send)?;
+    let args = send_args(outputs, config, options);
+    let result = rpc
+        .call::<SendResult>("send", &args)
+        .map_err(|e| change_position_context(e, config, outputs.len()))?;
+    if let Some(txid) = &result.txid {
+        txidlog::record(txid, "payment");
+    }
+    Ok(result)
+}
+
+// The positional arguments of `send`
+pub fn send_args(
+    outputs: &[(String, Amount)],
+    config: &Config,
+    options: serde_json::Map<String, serde_json::Value>,
+) -> [serde_json::Value; 5] {
+    let recipients: Vec<_> = outputs
+        .iter()
+        .map(|(addr, amount)| json!({ addr: amount.to_btc() }))
+        .collect();
+
+    // Core refuses a conf target next to an explicit fee_rate
+    let (conf_target, estimate_mode) = if options.contains_key("fee_rate") {
+        (None, None)
+    } else {
+        (config.conf_target, config.estimate_mode)
+    };
+
+    [
+        json!(recipients),    // recipient addresses
+        json!(conf_target),   // conf target
+        json!(estimate_mode), // estimate mode
+        json!(null),          // fee rate in sats/vb
+        json!(options),       // options object
+    ]
+}
+
+// The node only says "changePosition out of bounds"; say what was asked for and why
+// it can't work.
+pub fn change_position_context(
+    e: bitcoincore_rpc::Error,
+    config: &Config,
+    outputs: usize,
+) -> CapstoneError {
+    match config.change_position {
+        Some(position) if e.to_string().contains("out of bounds") => {
+            CapstoneError::Usage(format!(
+                "--change-position {} is out of range: the transaction has {} output(s) before change, so the change can go at 0 to {} ({})",
+                position, outputs, outputs, e
+            ))
+        }
+        _ => e.into(),
+    }
+}
+
+// The options object mirrors walletcreatefundedpsbt's, which is what `send` uses under the hood.
+// Modes that need more (e.g. explicit inputs or a fee_rate) add their entries before calling `send`.
+pub fn send_options(config: &Config) -> serde_json::Map<String, serde_json::Value> {
+    let mut options = serde_json::Map::new();
+    if let Some(change_address) = &config.change_address {
+        options.insert("change_address".into(), json!(change_address));
+    }
+    if let Some(change_type) = config.change_type {
+        options.insert("change_type".into(), json!(change_type.as_rpc_str()));
+    }
+    if config.bump_until_confirmed {
+        options.insert("replaceable".into(), json!(true));
+    }
+    if let Some(position) = config.change_position {
+        options.insert("change_position".into(), json!(position));
+    }
+    if let Some(locktime) = config.locktime {
+        options.insert("locktime".into(), json!(locktime));
+    }
+    options
+}
+
+// `send_options` plus the --auto-fee feerate, if one was chosen.
+pub fn send_options_with_fee_rate(
+    config: &Config,
+    fee_rate: Option<&FeeRateChoice>,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut options = send_options(config);
+    if let Some(fee_rate) = fee_rate {
+        options.insert("fee_rate".into(), json!(fee_rate.sat_per_vb()));
+    }
+    options
+}
+
+// The wallet puts change at a random position unless told otherwise; check it
+// listened when it was.
+fn check_change_position(report: &TransactionReport, config: &Config) -> Result<()> {
+    match (config.change_position, report.change_vout) {
+        (Some(position), Some(vout)) if vout != position => Err(CapstoneError::Usage(format!(
+            "change landed at vout {}, not at the requested --change-position {}",
+            vout, position
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// Make sure a requested change address is usable before we broadcast anything.
+// Sending change to an address the Miner wallet doesn't own silently gives the
+// money away, so that needs an explicit opt-in.
+pub fn check_change_address(
+    rpc: &Client,
+    config: &Config,
+    network: Network,
+) -> Result<Option<Address>> {
+    let change_address = match &config.change_address {
+        Some(change_address) => change_address,
+        None => return Ok(None),
+    };
+
+    let address = change_address
+        .parse::<Address<NetworkUnchecked>>()
+        .map_err(|e| {
+            CapstoneError::Usage(format!(
+                "invalid change address '{}': {}",
+                change_address, e
+            ))
+        })?
+        .require_network(network)
+        .map_err(|e| {
+            CapstoneError::Usage(format!(
+                "invalid change address '{}': {}",
+                change_address, e
+            ))
+        })?;
+
+    let address_info = rpc.get_address_info(&address)?;
+    if address_info.is_mine != Some(true) {
+        if !config.allow_external_change {
+            return Err(CapstoneError::ExternalChange(address.to_string()));
+        }
+        say!(
+            "Warning: change address {} is not owned by the Miner wallet.",
+            address
+        );
+    }
+
+    Ok(Some(address))
+}
+
+// Hand `send` the preset inputs; no change when there are none.
+pub fn with_inputs(
+    mut options: serde_json::Map<String, serde_json::Value>,
+    inputs: &PresetInputs,
+) -> serde_json::Map<String, serde_json::Value> {
+    if let Some(add_inputs) = inputs.add_inputs() {
+        options.insert("inputs".into(), inputs.to_json());
+        options.insert("add_inputs".into(), json!(add_inputs));
+    }
+    options
+}
+
+// Build and sign the payment without broadcasting it, and show whether the node
+// would accept it and at what fee.
+fn dry_run(
+    miner_rpc: &Client,
+    trader_address: &Address,
+    amount: Amount,
+    change_address: Option<&Address>,
+    fee_rate: Option<&FeeRateChoice>,
+    inputs: &PresetInputs,
+    config: &Config,
+) -> Result<()> {
+    let signed_hex = if config.raw {
+        raw::build_signed(
+            miner_rpc,
+            trader_address,
+            amount,
+            change_address,
+            fee_rate,
+            inputs,
+            config,
+        )?
+    } else {
+        // add_to_wallet=false makes `send` return the signed hex instead of broadcasting
+        let mut options = with_inputs(send_options_with_fee_rate(config, fee_rate), inputs);
+        options.insert("add_to_wallet".into(), json!(false));
+        let result = call_send(
+            miner_rpc,
+            &[(trader_address.to_string(), amount)],
+            config,
+            options,
+        )?;
+        result.hex.ok_or_else(|| {
+            CapstoneError::Usage("send returned no transaction hex for the dry run".into())
+        })?
+    };
+
+    say!("Dry run, nothing will be broadcast.");
+    say!("Signed transaction hex: {}", signed_hex);
+    accept::ensure_accepted(
+        miner_rpc,
+        std::slice::from_ref(&signed_hex),
+        config.max_broadcast_feerate(),
+    )?;
+    Ok(())
+}
+
+// The assignment flow: pay the Trader from the Miner wallet, confirm the
+// transaction and write its details to out.txt.
+pub fn run(session: &Session, config: &Config) -> Result<()> {
+    let rpc = &session.rpc;
+    let miner_rpc = &session.miner_rpc;
+    let trader_rpc = &session.trader_rpc;
+
+    let funding_blocks = session.fund_miner(config.amount() + config.max_fee())?;
+
+    // Load Trader wallet and generate a new address
+    let trader_address = trader_rpc.get_new_address(None, None)?.assume_checked();
+    say!("Generated new address for Trader: {}", trader_address);
+
+    // Validate the requested change address (if any) before spending anything
+    let change_address = check_change_address(miner_rpc, config, session.network)?;
+
+    if config.mempool_summary {
+        say!(
+            "{}",
+            mempool::summarize(&mempool::get_raw_mempool_verbose(rpc)?).one_line()
+        );
+    }
+    // Record what the node would have suggested for this send, to compare with the fee we end up paying
+    let fee_estimate = fees::smart_fee_estimate(
+        rpc,
+        config.conf_target.unwrap_or(fees::DEFAULT_CONF_TARGET),
+        config.estimate_mode,
+    );
+    say!(
+        "Smart fee estimate for {} blocks: {}",
+        fee_estimate.conf_target,
+        fee_estimate.describe()
+    );
+    // The rate --auto-fee would use, also reported next to the rate actually paid
+    let fee_choice = fees::fee_estimate(
+        rpc,
+        &fee_estimate,
+        config.fallback_fee_rate(),
+        config.max_fee(),
+    )?;
+
+    // Fee bumping only makes sense where someone else mines the blocks
+    let bumping = config.bump_until_confirmed && session.network != Network::Regtest;
+    if config.bump_until_confirmed && !bumping {
+        say!("--bump-until-confirmed does nothing on regtest, we mine the confirming block ourselves.");
+    }
+    let bump_start = if bumping {
+        Some(bump::initial_fee_rate(rpc, config)?)
+    } else {
+        None
+    };
+    let mut chosen_fee_rate = bump_start.or(config.auto_fee.then_some(fee_choice));
+    // Send 20 BTC (or the configured amount) from Miner to Trader
+    let amount_to_send = config.amount();
+    let node_policy = relay::policy(
+        rpc,
+        &relay::outputs(&[(trader_address.clone(), amount_to_send)]),
+    )?;
+    let mempool = limits::mempool_info(rpc)?;
+    say!("{}", mempool.describe());
+    // --max-fee can clamp the rate below what the node takes, and a mempool
+    // about to evict wants more than mempoolminfee
+    let what = chosen_fee_rate.map_or("the payment", |rate| rate.source.describe());
+    let checked = limits::check(
+        &mempool,
+        chosen_fee_rate.map(|rate| rate.sat_per_kvb),
+        config.auto_fee,
+        what,
+    )?;
+    if let Some(sat_per_kvb) = checked {
+        if chosen_fee_rate.is_none_or(|rate| rate.sat_per_kvb != sat_per_kvb) {
+            say!("Raising the fee rate to the mempool floor.");
+            chosen_fee_rate = Some(FeeRateChoice {
+                sat_per_kvb,
+                source: fees::FeeSource::MempoolMinFee,
+            });
+        }
+    }
+    if let Some(fee_rate) = &chosen_fee_rate {
+        say!("Using fee rate {}", fee_rate.describe());
+    }
+    let fee_rate = chosen_fee_rate.as_ref();
+
+    // Catch dust amounts up front, the node would only reject them with a generic error
+    dust::check_outputs(&[(trader_address.clone(), amount_to_send)], &node_policy)?;
+
+    // Empty every address we take coins from, see grouping::select_by_address
+    let selection = if config.avoid_partial_spends {
+        let selection = grouping::select_by_address(miner_rpc, amount_to_send + config.max_fee())?;
+        say!(
+            "Spending all {} coin(s) on {} address(es).",
+            selection.inputs.len(),
+            selection.addresses
+        );
+        Some(selection)
+    } else {
+        None
+    };
+    let inputs = PresetInputs::new(selection.as_ref().map(|s| &s.inputs[..]), config);
+    let inputs = &inputs;
+
+    if let Some(path) = &config.export_psbt {
+        let funded = psbt::fund_psbt(
+            miner_rpc,
+            &trader_address,
+            amount_to_send,
+            fee_rate,
+            inputs,
+            config,
+        )?;
+        psbt::export(rpc, &funded, path)?;
+        return Err(CapstoneError::AwaitingSignature(path.clone()));
+    }
+
+    if config.dry_run {
+        return dry_run(
+            miner_rpc,
+            &trader_address,
+            amount_to_send,
+            change_address.as_ref(),
+            fee_rate,
+            inputs,
+            config,
+        );
+    }
+
+    say!(
+        "Sending {} from Miner to Trader...",
+        btc::amount(amount_to_send)
+    );
+    let txid = if config.raw {
+        raw::send_raw(
+            miner_rpc,
+            &trader_address,
+            amount_to_send,
+            change_address.as_ref(),
+            fee_rate,
+            inputs,
+            config,
+        )?
+    } else {
+        let txid = send(
+            miner_rpc,
+            &trader_address.to_string(),
+            amount_to_send,
+            config,
+            with_inputs(send_options_with_fee_rate(config, fee_rate), inputs),
+        )?;
+        // `send` signs and broadcasts in one call, so the cap can only be checked afterwards
+        fees::warn_if_fee_exceeds(miner_rpc, &txid, config.max_fee())?;
+        txid
+    };
+    say!("Transaction sent! TXID: {}", txid);
+
+    // Check transaction in mempool, keeping its entry for the fee analysis in the report
+    let mempool_snapshot = mempool::wait_for_acceptance(
+        rpc,
+        miner_rpc,
+        &txid,
+        Duration::from_millis(config.mempool_poll_ms()),
+        Duration::from_secs(config.mempool_timeout()),
+    )?;
+    match &mempool_snapshot {
+        MempoolSnapshot::Entry(_) => say!("Success! Transaction {} found in mempool.", txid),
+        MempoolSnapshot::ConfirmedFirst => {
+            say!("Success! Transaction {} already confirmed.", txid)
+        }
+    }
+    // Deltas are dropped once their transaction is mined, so look now
+    let prioritisation = if config.show_decoded {
+        prioritise::prioritised(rpc)?.map(|deltas| prioritise::describe(&deltas))
+    } else {
+        None
+    };
+    if config.no_confirm {
+        // Make sure the payment outlives this run even if the node goes down
+        pending::record(miner_rpc, "Miner", &txid)?;
+        match pending::save_mempool(rpc)? {
+            Some(path) => say!("Mempool saved to {}.", path),
+            None => say!("Mempool saved."),
+        }
+        say!(
+            "Leaving {} unconfirmed (--no-confirm); the next run checks on it.",
+            txid
+        );
+        return Ok(());
+    }
+
+    let (txid, block_hash, fee_bumps) = match &bump_start {
+        // Wait for the network, following the payment through its replacements
+        Some(initial) => {
+            let outcome =
+                bump::wait_until_confirmed(miner_rpc, txid, initial, &node_policy, config)?;
+            (outcome.txid, outcome.block_hash, outcome.attempts)
+        }
+        None => {
+            // Mine 1 block to confirm the transaction
+            let block_hash = mine::confirm(session, &[txid], config)?;
+            say!("Block {} mined, confirming the transaction.", block_hash);
+            (txid, block_hash, Vec::new())
+        }
+    };
+
+    // One block is not final truth: wait for the wanted depth, following any
+    // reorg, and report against whichever block confirms the payment by then.
+    // The plain regtest flow always mines its own confirmations.
+    let confirmation = mine::wait_for_confirmations(
+        session,
+        &txid,
+        config.confirmations(),
+        Duration::from_secs(config.confirm_timeout()),
+        Duration::from_secs(config.poll_interval()),
+        config.auto_mine || bump_start.is_none(),
+        config.max_fee(),
+    )?;
+    if confirmation.block_hash != block_hash {
+        say!(
+            "Confirmed in block {} at height {} after all, not {}.",
+            confirmation.block_hash,
+            confirmation.block_height,
+            block_hash
+        );
+    }
+    let block_hash = confirmation.block_hash;
+    if confirmation.txid != txid {
+        say!("Following the fee bump {} of {}.", confirmation.txid, txid);
+    }
+    let txid = confirmation.txid;
+    // Every version the confirmed one replaced must now be conflicted
+    if fee_bumps.len() > 1 {
+        let conflicts = reconcile::scan(session)?;
+        for replaced in fee_bumps.iter().filter(|attempt| attempt.txid != txid) {
+            reconcile::expect_conflict(&conflicts, &replaced.txid, None)?;
+        }
+    }
+
+    // Extract all required transaction details
+    let mut report = TransactionReport::extract(rpc, &txid, &block_hash, &trader_address)?;
+    say!("Successfully fetched confirmed transaction details.");
+    report.fee_bumps = fee_bumps;
+    report.fee_estimate = Some(fee_estimate);
+    report.fee_choice = Some(fee_choice);
+    report.input_selection = selection;
+    report.funding_blocks = funding_blocks;
+    report.funding_txid = session.funding_txid.get();
+    report.wallet_topology = Some(session.wallet_topology());
+    report.mempool_snapshot = Some(mempool_snapshot);
+    report.evictions = confirmation.evictions;
+
+    if config.show_decoded {
+        report.node_policy = Some(node_policy);
+        report.prioritisation = prioritisation;
+        let decoded = decode::fetch_decoded(rpc, &txid, Some(&block_hash))?;
+        say!("\n--- Decoded transaction ---\n{}", decoded.render());
+        report.decoded = Some(decoded);
+    }
+
+    // If the change was forced to a specific address, check it actually landed there
+    if let Some(change_address) = &change_address {
+        report.verify_change_address(change_address)?;
+        say!(
+            "Change output landed on the requested address {}.",
+            change_address
+        );
+    }
+
+    report.verify_utxos(rpc)?;
+    check_change_position(&report, config)?;
+
+    // A fork next to the tip is the usual reason a payment "disappears" later
+    let chain_tips = tips::check(rpc, "before the report")?;
+    if config.show_decoded {
+        report.chain_tips = Some(chain_tips.summary());
+        report.node_info = Some(nodeinfo::node_info(rpc)?);
+        report.softforks = Some(deployments::active(&deployments::deployments(rpc)?));
+    }
+
+    // An SPV proof of the confirmation, so the report doesn't rest on the node's word alone
+    let inclusion_proof = proof::inclusion_proof(rpc, &txid, &block_hash)?;
+    say!(
+        "Merkle proof for {} verified against root {}.",
+        txid,
+        inclusion_proof.merkle_root
+    );
+    if let Some(path) = &config.proof_out {
+        files::write_atomically(path, &inclusion_proof.proof, "proof file")?;
+        say!("Raw merkle proof written to {}.", path);
+    }
+    report.inclusion_proof = Some(inclusion_proof);
+
+    // What a light client would see: the confirming block's filter has to
+    // match the Trader address, and the block behind it has to pay it
+    if config.verify_with_filters {
+        let height = report.block_height;
+        let scan = filters::scan(rpc, &trader_address.script_pubkey(), height, height, 1)?;
+        if !scan.funded_by(&txid) {
+            return Err(CapstoneError::FilterCheckFailed(format!(
+                "the filter of block {} doesn't lead to {} paying {}",
+                height, txid, trader_address
+            )));
+        }
+        say!(
+            "Found {} through the block filters ({}).",
+            txid,
+            scan.describe()
+        );
+        report.filter_check = Some(scan.describe());
+    }
+
+    // Tell whoever waits on the payment, once it has the depth they asked for
+    if let Some(url) = &config.notify_url {
+        let delivery = notify::notify(url, &notify::payload(&report));
+        say!("{}", delivery.describe());
+        report.notification = Some(delivery);
+    }
+
+    report.run = Some(RunInfo::collect(rpc)?);
+
+    // Write the data to ../out.txt in the specified format given in readme.md
+    report::write_transaction_reports(std::slice::from_ref(&report), config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::parse_args;
+
+    fn config(args: &[&str]) -> Config {
+        parse_args(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    fn args(
+        config: &Config,
+        options: serde_json::Map<String, serde_json::Value>,
+    ) -> Vec<serde_json::Value> {
+        let outputs = [(
+            "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080".to_string(),
+            Amount::from_btc(20.0).unwrap(),
+        )];
+        send_args(&outputs, config, options).to_vec()
+    }
+
+    #[test]
+    fn conf_target_and_estimate_mode_are_null_by_default() {
+        let args = args(&config(&[]), serde_json::Map::new());
+        assert_eq!(
+            args,
+            vec![
+                json!([{ "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080": 20.0 }]),
+                json!(null),
+                json!(null),
+                json!(null),
+                json!({}),
+            ]
+        );
+    }
+
+    #[test]
+    fn conf_target_and_estimate_mode_are_sent_when_set() {
+        let config = config(&["--conf-target", "3", "--estimate-mode", "conservative"]);
+        let args = args(&config, serde_json::Map::new());
+        assert_eq!(args[1], json!(3));
+        assert_eq!(args[2], json!("CONSERVATIVE"));
+    }
+
+    #[test]
+    fn an_unsigned_send_hands_back_the_psbt() {
+        let errors: Vec<signing::SigningError> = serde_json::from_value(json!([{
+            "txid": "54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af",
+            "vout": 0,
+            "scriptSig": "",
+            "sequence": 4294967293_u32,
+            "error": "Unable to sign input, invalid stack size (possibly missing key)"
+        }]))
+        .unwrap();
+        let error = CapstoneError::SendIncomplete {
+            psbt: "cHNidP8BAH0CAAAAAQ==".into(),
+            errors,
+        };
+        assert_eq!(
+            error.to_string(),
+            "the wallet could not sign every input, nothing was broadcast:
+  input 54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af:0: Unable to sign input, invalid stack size (possibly missing key)
+sign this PSBT elsewhere, save it to a file and run finalize-psbt <path>:
+cHNidP8BAH0CAAAAAQ=="
+        );
+    }
+
+    #[test]
+    fn an_explicit_fee_rate_drops_the_conf_target() {
+        let config = config(&["--conf-target=3", "--estimate-mode=economical"]);
+        let mut options = serde_json::Map::new();
+        options.insert("fee_rate".into(), json!("2.5"));
+        let args = args(&config, options);
+        assert_eq!(args[1], json!(null));
+        assert_eq!(args[2], json!(null));
+        assert_eq!(args[4], json!({ "fee_rate": "2.5" }));
+    }
+
+    // The fixture report with the change moved to vout 0
+    fn change_first() -> TransactionReport {
+        let mut report = report::tests::fixture();
+        report.outputs.reverse();
+        for (i, output) in report.outputs.iter_mut().enumerate() {
+            output.0 = i as u32;
+        }
+        report.change_vout = Some(0);
+        report.trader_vout = Some(1);
+        report
+    }
+
+    #[test]
+    fn the_requested_change_position_is_checked() {
+        let report = change_first();
+        let text = btc::with_units(crate::cli::Units::Btc, || report.to_text());
+        assert!(text.contains(
+            "vout 0: Change bcrt1qxw3msnuqps0kgn6dprs9ldlz79yfj63swqupd0 29.99998590 BTC\n"
+        ));
+        assert!(text.contains(
+            "vout 1: Trader bcrt1qak6gpu2p6zjpwrhvd4dvdnp4rt3ysm9rpst3wu 20.00000000 BTC\n"
+        ));
+        check_change_position(&report, &config(&["--change-position=0"])).unwrap();
+        check_change_position(&report, &config(&[])).unwrap();
+
+        let err = check_change_position(&report, &config(&["--change-position=1"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "change landed at vout 0, not at the requested --change-position 1"
+        );
+        // Without a change output there is nothing to have landed anywhere
+        let mut no_change = report;
+        no_change.change_vout = None;
+        check_change_position(&no_change, &config(&["--change-position=1"])).unwrap();
+    }
+
+    #[test]
+    fn change_position_goes_in_the_send_options() {
+        let options = send_options(&config(&["--change-position", "0"]));
+        assert_eq!(options["change_position"], json!(0));
+        assert!(!send_options(&config(&[])).contains_key("change_position"));
+    }
+
+    #[test]
+    fn out_of_range_change_positions_are_explained() {
+        let (rpc, _) = crate::mockrpc::client(|_, _| Err((-8, "changePosition out of bounds")));
+        let error = rpc
+            .call::<SendResult>("send", &[])
+            .map_err(|e| change_position_context(e, &config(&["--change-position=2"]), 1))
+            .unwrap_err();
+        assert!(
+            error.to_string().starts_with(
+                "--change-position 2 is out of range: the transaction has 1 output(s) before change, so the change can go at 0 to 1 ("
+            ),
+            "{}",
+            error
+        );
+        assert!(error.to_string().contains("changePosition out of bounds"));
+
+        // Other errors, and the same one without --change-position, pass through
+        let (rpc, _) = crate::mockrpc::client(|_, _| Err((-8, "changePosition out of bounds")));
+        let error = rpc
+            .call::<SendResult>("send", &[])
+            .map_err(|e| change_position_context(e, &config(&[]), 1))
+            .unwrap_err();
+        assert!(matches!(error, CapstoneError::Rpc(_)));
+    }
+}
@@ -0,0 +1,289 @@
+use crate::console::say;
+use crate::error::Result;
+use crate::files;
+use crate::mempool;
+use crate::session::{self, Session};
+use crate::signing;
+use crate::txidlog;
+use bitcoincore_rpc::bitcoin::Txid;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+// Transactions a run broadcast and left unconfirmed, kept next to out.txt so
+// the next run can check the node still has them
+const PENDING_PATH: &str = "../pending.json";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PendingState {
+    pub transactions: Vec<PendingTx>,
+    // Where the node wrote its mempool with savemempool, Core 23+
+    #[serde(default)]
+    pub mempool_dump: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingTx {
+    pub txid: Txid,
+    // Raw hex, to rebroadcast from if the node lost it
+    pub hex: String,
+    // The wallet that sent it, which tells whether it confirmed
+    pub wallet: String,
+}
+
+impl PendingState {
+    // An empty state when no run has left anything behind
+    pub fn load() -> Result<Self> {
+        if !Path::new(PENDING_PATH).exists() {
+            return Ok(PendingState::default());
+        }
+        Ok(serde_json::from_str(&files::read_to_string(
+            PENDING_PATH,
+            "pending transactions file",
+        )?)?)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if self.transactions.is_empty() {
+            if Path::new(PENDING_PATH).exists() {
+                fs::remove_file(PENDING_PATH)?;
+            }
+            return Ok(());
+        }
+        files::write_atomically(
+            PENDING_PATH,
+            serde_json::to_string_pretty(self)?,
+            "pending transactions file",
+        )?;
+        Ok(())
+    }
+}
+
+// Remember `txid`, sent from `wallet`, as left unconfirmed.
+pub fn record(wallet_rpc: &Client, wallet: &str, txid: &Txid) -> Result<()> {
+    let hex = signing::verified_hex(&wallet_rpc.get_transaction(txid, None)?.hex, txid)?;
+    let mut state = PendingState::load()?;
+    state.transactions.retain(|tx| tx.txid != *txid);
+    state.transactions.push(PendingTx {
+        txid: *txid,
+        hex,
+        wallet: wallet.to_string(),
+    });
+    state.save()
+}
+
+#[derive(Debug, Deserialize)]
+struct SaveMempoolResult {
+    filename: String,
+}
+
+// savemempool, so the mempool survives a node crash as well as a clean
+// shutdown. Returns the dump's path, None from nodes before Core 23 which
+// don't say.
+pub fn save_mempool(rpc: &Client) -> Result<Option<String>> {
+    let result: Option<SaveMempoolResult> = rpc.call("savemempool", &[])?;
+    let filename = result.map(|result| result.filename);
+    let mut state = PendingState::load()?;
+    state.mempool_dump = filename.clone();
+    state.save()?;
+    Ok(filename)
+}
+
+// What the node says about a recorded transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PendingStatus {
+    Confirmed,
+    // In a block that left the chain, or replaced: nothing to rebroadcast
+    Conflicted,
+    InMempool,
+    Missing,
+}
+
+// A recorded transaction is done with once it confirmed or conflicted, kept
+// if the mempool still has it, and rebroadcast otherwise.
+pub fn status(confirmations: i32, in_mempool: bool) -> PendingStatus {
+    match confirmations {
+        c if c > 0 => PendingStatus::Confirmed,
+        c if c < 0 => PendingStatus::Conflicted,
+        _ if in_mempool => PendingStatus::InMempool,
+        _ => PendingStatus::Missing,
+    }
+}
+
+// Look `tx` up, rebroadcasting it from the stored hex if the node lost it.
+fn check(rpc: &Client, wallet_rpc: &Client, tx: &PendingTx) -> Result<PendingStatus> {
+    let confirmations = match wallet_rpc.get_transaction(&tx.txid, None) {
+        Ok(wallet_tx) => wallet_tx.info.confirmations,
+        // Wallet not loaded: go by the mempool alone
+        Err(_) => 0,
+    };
+    let in_mempool = mempool::find_mempool_entry(rpc, &tx.txid)?.is_some();
+    let status = status(confirmations, in_mempool);
+    match status {
+        PendingStatus::Confirmed => say!("Pending {} has confirmed.", tx.txid),
+        PendingStatus::Conflicted => {
+            say!(
+                "Pending {} was replaced or conflicted, forgetting it.",
+                tx.txid
+            )
+        }
+        PendingStatus::InMempool => {}
+        PendingStatus::Missing => match rpc.send_raw_transaction(tx.hex.as_str()) {
+            Ok(_) => {
+                txidlog::record(&tx.txid, "rebroadcast");
+                say!(
+                    "Pending {} was no longer in the mempool, rebroadcast it.",
+                    tx.txid
+                )
+            }
+            Err(e) => say!(
+                "Pending {} was no longer in the mempool and the rebroadcast failed: {}",
+                tx.txid,
+                e
+            ),
+        },
+    }
+    Ok(status)
+}
+
+// At startup: check that what earlier runs left unconfirmed survived, e.g., a
+// node restart, rebroadcasting from the stored hex what didn't, and forget
+// what has confirmed.
+pub fn resubmit(session: &Session) -> Result<()> {
+    let mut state = PendingState::load()?;
+    if state.transactions.is_empty() {
+        return Ok(());
+    }
+    let mut still_pending = Vec::new();
+    for tx in state.transactions {
+        let wallet_rpc = session::wallet_client(&tx.wallet)?;
+        match check(&session.rpc, &wallet_rpc, &tx)? {
+            PendingStatus::Confirmed | PendingStatus::Conflicted => {}
+            PendingStatus::InMempool | PendingStatus::Missing => still_pending.push(tx),
+        }
+    }
+    state.transactions = still_pending;
+    state.save()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{parse_args, Command};
+    use crate::mockrpc;
+    use bitcoincore_rpc::bitcoin::consensus::encode;
+    use serde_json::json;
+
+    #[test]
+    fn decides_from_confirmations_and_the_mempool() {
+        assert_eq!(status(1, false), PendingStatus::Confirmed);
+        assert_eq!(status(6, true), PendingStatus::Confirmed);
+        assert_eq!(status(-1, false), PendingStatus::Conflicted);
+        assert_eq!(status(-3, true), PendingStatus::Conflicted);
+        assert_eq!(status(0, true), PendingStatus::InMempool);
+        assert_eq!(status(0, false), PendingStatus::Missing);
+    }
+
+    fn pending_tx() -> PendingTx {
+        let tx = mockrpc::transaction();
+        PendingTx {
+            txid: tx.txid(),
+            hex: encode::serialize_hex(&tx),
+            wallet: "Miner".into(),
+        }
+    }
+
+    // A node that lost `pending_tx` and whose wallet has it at `confirmations`
+    fn node(confirmations: i32) -> (Client, std::sync::Arc<std::sync::Mutex<Vec<String>>>) {
+        let tx = mockrpc::transaction();
+        let wallet_tx = mockrpc::wallet_tx(&tx, confirmations, true);
+        mockrpc::client(move |method, params| match method {
+            "gettransaction" => Ok(wallet_tx.clone()),
+            "getmempoolentry" => mockrpc::NOT_IN_MEMPOOL,
+            "sendrawtransaction" => {
+                assert_eq!(params[0], wallet_tx["hex"]);
+                Ok(json!(tx.txid()))
+            }
+            _ => panic!("unexpected {}", method),
+        })
+    }
+
+    #[test]
+    fn rebroadcasts_a_lost_transaction_from_the_stored_hex() {
+        let (rpc, calls) = node(0);
+        assert_eq!(
+            check(&rpc, &rpc, &pending_tx()).unwrap(),
+            PendingStatus::Missing
+        );
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["gettransaction", "getmempoolentry", "sendrawtransaction"]
+        );
+    }
+
+    #[test]
+    fn leaves_confirmed_and_conflicted_transactions_alone() {
+        for (confirmations, expected) in [
+            (2, PendingStatus::Confirmed),
+            (-1, PendingStatus::Conflicted),
+        ] {
+            let (rpc, calls) = node(confirmations);
+            assert_eq!(check(&rpc, &rpc, &pending_tx()).unwrap(), expected);
+            assert!(!calls
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|method| method == "sendrawtransaction"));
+        }
+    }
+
+    #[test]
+    fn an_unloaded_wallet_goes_by_the_mempool() {
+        let (rpc, calls) = mockrpc::client(|method, _| match method {
+            "gettransaction" => Err((-18, "Requested wallet does not exist or is not loaded")),
+            "getmempoolentry" => Ok(mockrpc::mempool_entry(&mockrpc::transaction())),
+            _ => panic!("unexpected {}", method),
+        });
+        assert_eq!(
+            check(&rpc, &rpc, &pending_tx()).unwrap(),
+            PendingStatus::InMempool
+        );
+        assert_eq!(calls.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn state_without_a_dump_path_loads() {
+        let tx = pending_tx();
+        let state: PendingState = serde_json::from_value(json!({
+            "transactions": [{ "txid": tx.txid, "hex": tx.hex, "wallet": "Miner" }]
+        }))
+        .unwrap();
+        assert_eq!(state.transactions[0].txid, tx.txid);
+        assert_eq!(state.mempool_dump, None);
+    }
+
+    fn command(args: &[&str]) -> Command {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+            .unwrap()
+            .command
+    }
+
+    #[test]
+    fn sending_modes_and_watch_and_report_resubmit() {
+        let txid = mockrpc::transaction().txid().to_string();
+        for args in [
+            vec![],
+            vec!["--no-confirm"],
+            vec!["watch", &txid],
+            vec!["report", &txid],
+        ] {
+            assert!(command(&args).resubmits_pending(), "{:?}", args);
+        }
+        for args in [vec!["mempool"], vec!["tx-family", &txid], vec!["node-info"]] {
+            let command = command(&args);
+            assert!(command.is_read_only(), "{:?}", args);
+            assert!(!command.resubmits_pending(), "{:?}", args);
+        }
+    }
+}
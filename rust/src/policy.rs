@@ -0,0 +1,917 @@
+use crate::accept;
+use crate::btc;
+use crate::cli::Config;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::raw;
+use crate::report;
+use crate::session::{self, Session};
+use crate::signing;
+use crate::txidlog;
+use crate::version::{self, Capability};
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::{Message, Secp256k1};
+use bitcoin::sighash::{EcdsaSighashType, SighashCache};
+use bitcoin::Psbt;
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::{Address, Amount, OutPoint, ScriptBuf};
+use bitcoincore_rpc::{Client, RpcApi};
+use miniscript::policy::Concrete;
+use miniscript::psbt::PsbtExt;
+use miniscript::{
+    DefiniteDescriptorKey, Descriptor, DescriptorPublicKey, MiniscriptKey, Segwitv0, Translator,
+};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+// Watch-only wallet the policy descriptor is imported into
+const WATCH_WALLET: &str = "PolicyWatch";
+const DEFAULT_AMOUNT: Amount = Amount::ONE_BTC;
+
+// A miniscript policy with the keys still named, e.g. pk(A)
+pub type Policy = Concrete<String>;
+
+// One way of satisfying the policy: the keys that sign and the timelocks
+// that must have passed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Branch {
+    pub description: String,
+    pub keys: Vec<String>,
+    pub older: Option<u32>,
+    pub after: Option<u32>,
+}
+
+impl Branch {
+    fn leaf(description: String) -> Self {
+        Branch {
+            description,
+            ..Branch::default()
+        }
+    }
+
+    // Both branches at once; the empty branch leaves the other unchanged.
+    fn and(&self, other: &Branch) -> Branch {
+        if other.description.is_empty() {
+            return self.clone();
+        }
+        let mut keys = self.keys.clone();
+        keys.extend(
+            other
+                .keys
+                .iter()
+                .filter(|k| !self.keys.contains(k))
+                .cloned(),
+        );
+        Branch {
+            description: format!("{} and {}", self.description, other.description),
+            keys,
+            older: self.older.max(other.older),
+            after: self.after.max(other.after),
+        }
+    }
+
+    // Why the branch can't be used yet, given the funding output's confirmations
+    // and the chain tip.
+    pub fn blocked_by(&self, confirmations: u32, height: u32) -> Option<String> {
+        if let Some(older) = self.older.filter(|older| *older > confirmations) {
+            return Some(format!(
+                "needs older({}), the funding output has {} confirmation(s)",
+                older, confirmations
+            ));
+        }
+        if let Some(after) = self.after.filter(|after| *after > height) {
+            return Some(format!(
+                "needs after({}), the chain is at height {}",
+                after, height
+            ));
+        }
+        None
+    }
+}
+
+pub fn parse(input: &str) -> Result<Policy> {
+    Policy::from_str(input)
+        .map_err(|e| CapstoneError::Usage(format!("invalid policy '{}': {}", input, e)))
+}
+
+// Every way to satisfy the policy. The demo waits for timelocks by mining, so
+// only block-height ones are supported, and there are no preimages for hash
+// locks.
+pub fn branches(policy: &Policy) -> Result<Vec<Branch>> {
+    let unsupported =
+        |what: &str| CapstoneError::Usage(format!("{} is not supported in policy-demo", what));
+    match policy {
+        Policy::Unsatisfiable => Ok(Vec::new()),
+        Policy::Trivial => Ok(vec![Branch::leaf("1".to_string())]),
+        Policy::Key(name) => Ok(vec![Branch {
+            keys: vec![name.clone()],
+            ..Branch::leaf(format!("pk({})", name))
+        }]),
+        Policy::Older(older) if older.is_height_locked() => {
+            let n = older.to_consensus_u32();
+            Ok(vec![Branch {
+                older: Some(n),
+                ..Branch::leaf(format!("older({})", n))
+            }])
+        }
+        Policy::After(after) if after.is_block_height() => {
+            let n = after.to_consensus_u32();
+            Ok(vec![Branch {
+                after: Some(n),
+                ..Branch::leaf(format!("after({})", n))
+            }])
+        }
+        Policy::Older(_) | Policy::After(_) => Err(unsupported("a time-based timelock")),
+        Policy::Sha256(_) | Policy::Hash256(_) | Policy::Ripemd160(_) | Policy::Hash160(_) => {
+            Err(unsupported("a hash lock"))
+        }
+        Policy::And(subs) => Ok(threshold(subs.len(), &sub_branches(subs.iter())?)),
+        Policy::Or(subs) => Ok(threshold(
+            1,
+            &sub_branches(subs.iter().map(|(_, sub)| sub))?,
+        )),
+        Policy::Thresh(thresh) => Ok(threshold(thresh.k(), &sub_branches(thresh.iter())?)),
+    }
+}
+
+fn sub_branches<'a>(
+    subs: impl Iterator<Item = &'a std::sync::Arc<Policy>>,
+) -> Result<Vec<Vec<Branch>>> {
+    subs.map(|sub| branches(sub)).collect()
+}
+
+// The branches that satisfy `k` of the sub-policies: every choice of k of
+// them, combining one branch of each.
+fn threshold(k: usize, subs: &[Vec<Branch>]) -> Vec<Branch> {
+    if k == 0 {
+        return vec![Branch::default()];
+    }
+    let Some((first, rest)) = subs.split_first() else {
+        return Vec::new();
+    };
+    let mut branches = Vec::new();
+    for tail in threshold(k - 1, rest) {
+        branches.extend(first.iter().map(|head| head.and(&tail)));
+    }
+    branches.extend(threshold(k, rest));
+    branches
+}
+
+// Policy key names to the wallet keys standing in for them
+struct NamedKeys<'a>(&'a BTreeMap<String, DescriptorPublicKey>);
+
+type KeyHash<T> = std::result::Result<T, CapstoneError>;
+
+impl Translator<String, DescriptorPublicKey, CapstoneError> for NamedKeys<'_> {
+    fn pk(&mut self, name: &String) -> Result<DescriptorPublicKey> {
+        self.0
+            .get(name)
+            .cloned()
+            .ok_or_else(|| CapstoneError::Usage(format!("no key for '{}' in the policy", name)))
+    }
+
+    fn sha256(&mut self, _: &String) -> KeyHash<<DescriptorPublicKey as MiniscriptKey>::Sha256> {
+        Err(hash_lock())
+    }
+
+    fn hash256(&mut self, _: &String) -> KeyHash<<DescriptorPublicKey as MiniscriptKey>::Hash256> {
+        Err(hash_lock())
+    }
+
+    fn ripemd160(
+        &mut self,
+        _: &String,
+    ) -> KeyHash<<DescriptorPublicKey as MiniscriptKey>::Ripemd160> {
+        Err(hash_lock())
+    }
+
+    fn hash160(&mut self, _: &String) -> KeyHash<<DescriptorPublicKey as MiniscriptKey>::Hash160> {
+        Err(hash_lock())
+    }
+}
+
+fn hash_lock() -> CapstoneError {
+    CapstoneError::Usage("a hash lock is not supported in policy-demo".to_string())
+}
+
+// The policy compiled to miniscript for P2WSH, with each name replaced by
+// its key.
+pub fn compile(
+    policy: &Policy,
+    keys: &BTreeMap<String, DescriptorPublicKey>,
+) -> Result<Descriptor<DescriptorPublicKey>> {
+    let policy = policy.translate_pk(&mut NamedKeys(keys))?;
+    let miniscript = policy
+        .compile::<Segwitv0>()
+        .map_err(|e| CapstoneError::Usage(format!("cannot compile the policy: {}", e)))?;
+    Descriptor::new_wsh(miniscript)
+        .map_err(|e| CapstoneError::Usage(format!("cannot make a wsh() descriptor: {}", e)))
+}
+
+// A key of the wallet standing in for a policy name. The origin lets the
+// wallet recognise the key as its own when it is asked to sign.
+struct WalletKey<'a> {
+    wallet_rpc: &'a Client,
+    address: Address,
+    public_key: bitcoin::PublicKey,
+    descriptor_key: DescriptorPublicKey,
+}
+
+// Keys named A or Miner come from the Miner wallet, B or Trader from the Trader.
+fn key_wallet<'a>(session: &'a Session, name: &str) -> Result<&'a Client> {
+    match name {
+        "A" | "Miner" => Ok(&session.miner_rpc),
+        "B" | "Trader" => Ok(&session.trader_rpc),
+        other => Err(CapstoneError::Usage(format!(
+            "unknown key '{}' in policy (use A/Miner or B/Trader)",
+            other
+        ))),
+    }
+}
+
+fn wallet_key(wallet_rpc: &Client) -> Result<WalletKey<'_>> {
+    let address = wallet_rpc.get_new_address(None, None)?.assume_checked();
+    let info: serde_json::Value = wallet_rpc.call("getaddressinfo", &[json!(address)])?;
+    let field = |name: &str| {
+        info[name].as_str().ok_or_else(|| {
+            CapstoneError::Usage(format!("getaddressinfo for {} has no {}", address, name))
+        })
+    };
+    let (pubkey, path, fingerprint) = (
+        field("pubkey")?,
+        field("hdkeypath")?,
+        field("hdmasterfingerprint")?,
+    );
+    let origin = format!(
+        "[{}{}]{}",
+        fingerprint,
+        path.strip_prefix('m').unwrap_or(path),
+        pubkey
+    );
+    let bad_key = |e: String| CapstoneError::Usage(format!("wallet key {}: {}", origin, e));
+    Ok(WalletKey {
+        wallet_rpc,
+        public_key: bitcoin::PublicKey::from_str(pubkey).map_err(|e| bad_key(e.to_string()))?,
+        descriptor_key: DescriptorPublicKey::from_str(&origin)
+            .map_err(|e| bad_key(e.to_string()))?,
+        address,
+    })
+}
+
+// The descriptor with Core's checksum. Core also checks the miniscript is
+// sane (every branch needs a signature, no malleability) on the way.
+fn checked_descriptor(rpc: &Client, descriptor: &str) -> Result<String> {
+    let info: serde_json::Value = rpc.call("getdescriptorinfo", &[json!(descriptor)])?;
+    info["descriptor"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| {
+            CapstoneError::Usage(format!(
+                "getdescriptorinfo returned no descriptor for {}",
+                descriptor
+            ))
+        })
+}
+
+fn watch_wallet(rpc: &Client) -> Result<Client> {
+    if !rpc.list_wallets()?.iter().any(|w| w == WATCH_WALLET)
+        && rpc.load_wallet(WATCH_WALLET).is_err()
+    {
+        rpc.create_wallet(WATCH_WALLET, Some(true), Some(true), None, None)?;
+        say!("Created watch-only wallet '{}'.", WATCH_WALLET);
+    }
+    session::wallet_client(WATCH_WALLET)
+}
+
+// The unsigned spend of the funding output down `branch`, paying `fee`, with
+// the witness script and key origins the signers need.
+fn spend_psbt(
+    descriptor: &Descriptor<DefiniteDescriptorKey>,
+    funding: (bitcoin::OutPoint, bitcoin::Amount),
+    branch: &Branch,
+    to: bitcoin::ScriptBuf,
+    fee: bitcoin::Amount,
+) -> Result<Psbt> {
+    let (outpoint, value) = funding;
+    let sequence = match branch.older {
+        Some(older) => bitcoin::Sequence::from_height(older as u16),
+        None => bitcoin::Sequence::ENABLE_RBF_NO_LOCKTIME,
+    };
+    let lock_time = match branch.after {
+        Some(after) => bitcoin::absolute::LockTime::from_height(after)
+            .map_err(|e| CapstoneError::Usage(format!("after({}): {}", after, e)))?,
+        None => bitcoin::absolute::LockTime::ZERO,
+    };
+    let tx = bitcoin::Transaction {
+        version: bitcoin::transaction::Version::TWO,
+        lock_time,
+        input: vec![bitcoin::TxIn {
+            previous_output: outpoint,
+            sequence,
+            ..bitcoin::TxIn::default()
+        }],
+        output: vec![bitcoin::TxOut {
+            value: value - fee,
+            script_pubkey: to,
+        }],
+    };
+    let psbt_error = |e: String| CapstoneError::Usage(format!("spend PSBT: {}", e));
+    let mut psbt = Psbt::from_unsigned_tx(tx).map_err(|e| psbt_error(e.to_string()))?;
+    psbt.inputs[0].witness_utxo = Some(bitcoin::TxOut {
+        value,
+        script_pubkey: descriptor.script_pubkey(),
+    });
+    psbt.update_input_with_descriptor(0, descriptor)
+        .map_err(|e| psbt_error(e.to_string()))?;
+    Ok(psbt)
+}
+
+// The fee at `fee_rate` sat/kvB for the largest witness the descriptor can need
+fn spend_fee(
+    psbt: &Psbt,
+    descriptor: &Descriptor<DefiniteDescriptorKey>,
+    fee_rate: u64,
+) -> Result<Amount> {
+    let satisfaction = descriptor
+        .max_weight_to_satisfy()
+        .map_err(|e| CapstoneError::Usage(format!("cannot size the witness: {}", e)))?;
+    // The segwit marker and flag aren't in the unsigned transaction
+    let weight = psbt.unsigned_tx.weight() + bitcoin::Weight::from_wu(2) + satisfaction;
+    Ok(Amount::from_sat(
+        (weight.to_vbytes_ceil() * fee_rate).div_ceil(1000),
+    ))
+}
+
+// Ask the wallet behind each key to sign through walletprocesspsbt, and keep
+// the signatures it adds. Returns the keys that got no signature.
+fn collect_signatures<'a>(
+    psbt: &mut Psbt,
+    keys: impl IntoIterator<Item = &'a WalletKey<'a>>,
+) -> Result<Vec<&'a WalletKey<'a>>> {
+    let mut missing = Vec::new();
+    for key in keys {
+        let processed: serde_json::Value = key.wallet_rpc.call(
+            "walletprocesspsbt",
+            &[
+                json!(psbt.to_string()),
+                json!(true),
+                json!("ALL"),
+                json!(true),
+            ],
+        )?;
+        let processed = processed["psbt"]
+            .as_str()
+            .and_then(|text| Psbt::from_str(text).ok())
+            .ok_or_else(|| {
+                CapstoneError::Usage("walletprocesspsbt returned no PSBT".to_string())
+            })?;
+        match processed.inputs[0].partial_sigs.get(&key.public_key) {
+            Some(signature) => {
+                psbt.inputs[0]
+                    .partial_sigs
+                    .insert(key.public_key, *signature);
+            }
+            None => missing.push(key),
+        }
+    }
+    Ok(missing)
+}
+
+// Sign the policy input with `private_key`, as walletprocesspsbt would,
+// grinding for a low R so the signature fits the size the fee allows for.
+fn sign_input(psbt: &mut Psbt, private_key: &bitcoin::PrivateKey) -> Result<()> {
+    let input = &psbt.inputs[0];
+    let (Some(witness_script), Some(utxo)) = (&input.witness_script, &input.witness_utxo) else {
+        return Err(CapstoneError::Usage(
+            "the spend PSBT has no witness script or UTXO".to_string(),
+        ));
+    };
+    let sighash = SighashCache::new(&psbt.unsigned_tx)
+        .p2wsh_signature_hash(0, witness_script, utxo.value, EcdsaSighashType::All)
+        .map_err(|e| CapstoneError::Usage(format!("sighash: {}", e)))?;
+    let secp = Secp256k1::new();
+    let message = Message::from_digest(sighash.to_byte_array());
+    let signature =
+        bitcoin::ecdsa::Signature::sighash_all(secp.sign_ecdsa_low_r(&message, &private_key.inner));
+    psbt.inputs[0]
+        .partial_sigs
+        .insert(private_key.public_key(&secp), signature);
+    Ok(())
+}
+
+// Build the witness from the signatures, through whichever branch they and
+// the transaction's timelocks satisfy.
+fn finalize(mut psbt: Psbt) -> Result<bitcoin::Transaction> {
+    let secp = Secp256k1::verification_only();
+    psbt.finalize_mut(&secp).map_err(|errors| {
+        CapstoneError::PsbtNotFinalizable(errors.iter().map(ToString::to_string).collect())
+    })?;
+    psbt.extract(&secp)
+        .map_err(|e| CapstoneError::PsbtNotFinalizable(vec![e.to_string()]))
+}
+
+// policy-demo: compile the policy with the miniscript compiler, lock Miner
+// coins to it, and spend them back to the Trader through whichever branch is
+// satisfiable right now. The wallets sign through walletprocesspsbt; Core
+// only signs for miniscript from 25.0, so older nodes need the keys exported.
+pub fn run(session: &Session, config: &Config, policy_text: &str) -> Result<()> {
+    let rpc = &session.rpc;
+    let policy = parse(policy_text)?;
+    let all_branches = branches(&policy)?;
+
+    let mut names: Vec<String> = Vec::new();
+    for name in policy.keys() {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+    let mut keys = BTreeMap::new();
+    for name in &names {
+        let key = wallet_key(key_wallet(session, name)?)?;
+        say!("Key {}: {}", name, key.descriptor_key);
+        keys.insert(name.clone(), key);
+    }
+    let descriptor_keys = keys
+        .iter()
+        .map(|(name, key)| (name.clone(), key.descriptor_key.clone()))
+        .collect();
+    let compiled = compile(&policy, &descriptor_keys)?;
+    let descriptor = checked_descriptor(rpc, &compiled.to_string())?;
+    say!("Descriptor: {}", descriptor);
+
+    // The keys have no wildcards, so index 0 just makes them definite
+    let definite = compiled
+        .at_derivation_index(0)
+        .map_err(|e| CapstoneError::Usage(format!("descriptor keys: {}", e)))?;
+    let address = Address::from_script(
+        &ScriptBuf::from_bytes(definite.script_pubkey().to_bytes()),
+        session.network,
+    )
+    .map_err(|e| CapstoneError::Usage(format!("descriptor address: {}", e)))?;
+    let derived: Vec<Address<NetworkUnchecked>> =
+        rpc.call("deriveaddresses", &[json!(descriptor)])?;
+    if derived.first().map(|a| a.clone().assume_checked()) != Some(address.clone()) {
+        return Err(CapstoneError::Usage(format!(
+            "the node derives {:?} from the descriptor but the local script gives {}",
+            derived, address
+        )));
+    }
+
+    let watch_rpc = watch_wallet(rpc)?;
+    let imported: Vec<serde_json::Value> = watch_rpc.call(
+        "importdescriptors",
+        &[json!([{ "desc": descriptor, "timestamp": "now" }])],
+    )?;
+    if let Some(failed) = imported
+        .iter()
+        .find(|r| !r["success"].as_bool().unwrap_or(false))
+    {
+        return Err(CapstoneError::Usage(format!(
+            "importdescriptors into '{}' failed: {}",
+            WATCH_WALLET, failed["error"]["message"]
+        )));
+    }
+
+    // Lock the coins and confirm them
+    let amount = config.amount.unwrap_or(DEFAULT_AMOUNT);
+    session.fund_miner(amount + config.max_fee())?;
+    let funding_txid = session
+        .miner_rpc
+        .send_to_address(&address, amount, None, None, None, None, None, None)?;
+    txidlog::record(&funding_txid, "funding");
+    session.generate(1, &session.miner_address)?;
+    let funding_tx = session.miner_rpc.get_transaction(&funding_txid, None)?;
+    let vout = funding_tx
+        .transaction()?
+        .output
+        .iter()
+        .position(|output| output.script_pubkey == address.script_pubkey())
+        .ok_or_else(|| {
+            CapstoneError::Usage(format!(
+                "funding transaction {} does not pay {}",
+                funding_txid, address
+            ))
+        })?;
+    let outpoint = OutPoint::new(funding_txid, vout as u32);
+    say!(
+        "Locked {} to {} in {}.",
+        btc::amount(amount),
+        address,
+        outpoint
+    );
+    // Watch-only descriptor wallets count their coins as "mine", legacy ones as watchonly
+    let balances = watch_rpc.get_balances()?;
+    let watched = balances.mine.trusted + balances.watchonly.map_or(Amount::ZERO, |b| b.trusted);
+    say!(
+        "Watch wallet '{}' sees {}.",
+        WATCH_WALLET,
+        btc::amount(watched)
+    );
+
+    // Pick the first branch the chain allows, and say why the others don't work
+    let height = rpc.get_block_count()? as u32;
+    let confirmations = funding_tx.info.confirmations.max(0) as u32;
+    let mut chosen = None;
+    let mut branch_lines = Vec::new();
+    for branch in all_branches {
+        match branch.blocked_by(confirmations, height) {
+            Some(reason) => branch_lines.push(format!(
+                "Branch {}: unsatisfiable at height {}, {}\n",
+                branch.description, height, reason
+            )),
+            None if chosen.is_none() => chosen = Some(branch),
+            None => branch_lines.push(format!(
+                "Branch {}: satisfiable, not used\n",
+                branch.description
+            )),
+        }
+    }
+    let branch = chosen.ok_or_else(|| {
+        CapstoneError::Usage(format!(
+            "no branch of {} is satisfiable at height {}",
+            policy_text, height
+        ))
+    })?;
+    say!("Spending through branch {}.", branch.description);
+
+    let to = session
+        .trader_rpc
+        .get_new_address(None, None)?
+        .assume_checked();
+    let funding = (
+        bitcoin::OutPoint::from_str(&outpoint.to_string())
+            .map_err(|e| CapstoneError::Usage(format!("funding outpoint: {}", e)))?,
+        bitcoin::Amount::from_sat(amount.to_sat()),
+    );
+    let to_script = bitcoin::ScriptBuf::from_bytes(to.script_pubkey().to_bytes());
+    let unsized_psbt = spend_psbt(
+        &definite,
+        funding,
+        &branch,
+        to_script.clone(),
+        bitcoin::Amount::ZERO,
+    )?;
+    let fee = spend_fee(&unsized_psbt, &definite, config.fallback_fee_rate())?;
+    let mut psbt = spend_psbt(
+        &definite,
+        funding,
+        &branch,
+        to_script,
+        bitcoin::Amount::from_sat(fee.to_sat()),
+    )?;
+
+    // Only the branch's keys sign, so the finalizer can't take another branch
+    let missing = collect_signatures(&mut psbt, branch.keys.iter().map(|name| &keys[name]))?;
+    if !missing.is_empty() {
+        if version::supports(rpc, Capability::MiniscriptSigning)? {
+            return Err(CapstoneError::PsbtNotFinalizable(
+                missing
+                    .iter()
+                    .map(|key| format!("no signature for {}", key.descriptor_key))
+                    .collect(),
+            ));
+        }
+        say!("This node can't sign for miniscript; signing with keys exported from the wallets.");
+        for key in missing {
+            let (_, private_key) = signing::wallet_key(key.wallet_rpc, &key.address)?;
+            let private_key = bitcoin::PrivateKey::from_wif(&private_key.to_wif())
+                .map_err(|e| CapstoneError::Usage(format!("exported key: {}", e)))?;
+            sign_input(&mut psbt, &private_key)?;
+        }
+    }
+    let hex = bitcoin::consensus::encode::serialize_hex(&finalize(psbt)?);
+
+    accept::ensure_accepted(
+        rpc,
+        std::slice::from_ref(&hex),
+        config.max_broadcast_feerate(),
+    )?;
+    let spend_txid = raw::broadcast(rpc, &hex, config)?;
+    say!("Spend transaction sent! TXID: {}", spend_txid);
+    let block_hash = session.generate(1, &session.miner_address)?[0];
+    let block_height = rpc.get_block_header_info(&block_hash)?.height;
+
+    let mut output_string = String::new();
+    output_string.push_str(&format!("Policy: {}\n", policy_text));
+    output_string.push_str(&format!("Descriptor: {}\n", descriptor));
+    output_string.push_str(&format!("Address: {}\n", address));
+    output_string.push_str(&format!(
+        "Funding transaction ID (txid): {}\n",
+        funding_txid
+    ));
+    output_string.push_str(&format!("Spend transaction ID (txid): {}\n", spend_txid));
+    output_string.push_str(&format!("Branch used: {}\n", branch.description));
+    for line in &branch_lines {
+        output_string.push_str(line);
+    }
+    output_string.push_str(&format!(
+        "Spend fee (in {}): {}\n",
+        btc::unit(),
+        btc::format(fee)
+    ));
+    output_string.push_str(&format!(
+        "Block height at which the spend is confirmed: {}\n",
+        block_height
+    ));
+    output_string.push_str(&format!(
+        "Block hash at which the spend is confirmed: {}\n",
+        block_hash
+    ));
+    report::write_report(&output_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mockrpc;
+    use bitcoincore_rpc::bitcoin::Network;
+
+    // The keys for private keys 1 and 2
+    const A: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const B: &str = "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5";
+
+    fn private_key(n: u8) -> bitcoin::PrivateKey {
+        let mut secret = [0; 32];
+        secret[31] = n;
+        bitcoin::PrivateKey::from_slice(&secret, bitcoin::Network::Regtest).unwrap()
+    }
+
+    fn named_keys() -> BTreeMap<String, DescriptorPublicKey> {
+        [("A", A), ("B", B)]
+            .into_iter()
+            .map(|(name, key)| {
+                (
+                    name.to_string(),
+                    DescriptorPublicKey::from_str(key).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    fn definite(policy: &str) -> Descriptor<DefiniteDescriptorKey> {
+        compile(&parse(policy).unwrap(), &named_keys())
+            .unwrap()
+            .at_derivation_index(0)
+            .unwrap()
+    }
+
+    #[test]
+    fn compiles_known_vectors() {
+        let cases = [
+            (
+                "or(pk(A),and(pk(B),older(10)))",
+                format!("wsh(or_d(pk({A}),and_v(v:pk({B}),older(10))))#g7wcvdqr"),
+                // <A> CHECKSIG IFDUP NOTIF <B> CHECKSIGVERIFY 10 CSV ENDIF
+                format!("21{A}ac736421{B}ad5ab268"),
+            ),
+            (
+                "and(pk(A),after(100))",
+                format!("wsh(and_v(v:pk({A}),after(100)))#lzs5tx7c"),
+                // <A> CHECKSIGVERIFY 100 CLTV
+                format!("21{A}ad0164b1"),
+            ),
+        ];
+        for (policy, descriptor, witness_script) in cases {
+            let compiled = compile(&parse(policy).unwrap(), &named_keys()).unwrap();
+            assert_eq!(compiled.to_string(), descriptor, "{}", policy);
+            let definite = compiled.at_derivation_index(0).unwrap();
+            assert_eq!(
+                definite.explicit_script().unwrap().to_hex_string(),
+                witness_script,
+                "{}",
+                policy
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_policies() {
+        let error = parse("or(pk(A)").unwrap_err().to_string();
+        assert!(error.contains("invalid policy 'or(pk(A)'"), "{}", error);
+        // older(4194305) is one 512-second interval
+        for policy in [
+            "and(pk(A),older(4194305))",
+            "and(pk(A),sha256(6c60f404f8167a38fc70eaf8aa17ac351023bef86bcb9d1086a19afe95bd5333))",
+        ] {
+            let error = branches(&parse(policy).unwrap()).unwrap_err().to_string();
+            assert!(error.contains("not supported in policy-demo"), "{}", error);
+        }
+        let error = compile(&parse("pk(C)").unwrap(), &named_keys())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("no key for 'C'"), "{}", error);
+    }
+
+    #[test]
+    fn enumerates_branches() {
+        let policy = parse("or(pk(A),and(pk(B),older(10)))").unwrap();
+        let found = branches(&policy).unwrap();
+        assert_eq!(
+            found,
+            vec![
+                Branch {
+                    description: "pk(A)".to_string(),
+                    keys: vec!["A".to_string()],
+                    older: None,
+                    after: None,
+                },
+                Branch {
+                    description: "pk(B) and older(10)".to_string(),
+                    keys: vec!["B".to_string()],
+                    older: Some(10),
+                    after: None,
+                },
+            ]
+        );
+        assert_eq!(found[0].blocked_by(1, 101), None);
+        assert_eq!(
+            found[1].blocked_by(1, 101).unwrap(),
+            "needs older(10), the funding output has 1 confirmation(s)"
+        );
+        assert_eq!(found[1].blocked_by(10, 110), None);
+
+        let after = branches(&parse("and(pk(A),after(200))").unwrap()).unwrap();
+        assert_eq!(
+            after[0].blocked_by(1, 150).unwrap(),
+            "needs after(200), the chain is at height 150"
+        );
+
+        let two_of_three = parse("thresh(2,pk(A),pk(B),older(5))").unwrap();
+        let descriptions: Vec<String> = branches(&two_of_three)
+            .unwrap()
+            .into_iter()
+            .map(|branch| branch.description)
+            .collect();
+        assert_eq!(
+            descriptions,
+            [
+                "pk(A) and pk(B)",
+                "pk(A) and older(5)",
+                "pk(B) and older(5)"
+            ]
+        );
+    }
+
+    // The spend down `branch`, signed by `signers` and finalized
+    fn spend(
+        descriptor: &Descriptor<DefiniteDescriptorKey>,
+        branch: &Branch,
+        signers: &[u8],
+    ) -> Result<bitcoin::Transaction> {
+        let funding = (
+            bitcoin::OutPoint::new(bitcoin::Txid::all_zeros(), 0),
+            bitcoin::Amount::from_sat(100_000),
+        );
+        let to = descriptor.script_pubkey();
+        let mut psbt = spend_psbt(descriptor, funding, branch, to, bitcoin::Amount::ZERO)?;
+        for n in signers {
+            sign_input(&mut psbt, &private_key(*n))?;
+        }
+        finalize(psbt)
+    }
+
+    #[test]
+    fn satisfies_each_branch() {
+        let descriptor = definite("or(pk(A),and(pk(B),older(10)))");
+        let witness_script = descriptor.explicit_script().unwrap().to_bytes();
+        let found = branches(&parse("or(pk(A),and(pk(B),older(10)))").unwrap()).unwrap();
+
+        // pk(A): A's signature alone
+        let tx = spend(&descriptor, &found[0], &[1]).unwrap();
+        let stack: Vec<&[u8]> = tx.input[0].witness.iter().collect();
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack[1], witness_script.as_slice());
+        assert_eq!(
+            tx.input[0].sequence,
+            bitcoin::Sequence::ENABLE_RBF_NO_LOCKTIME
+        );
+
+        // pk(B) and older(10): B's signature, then an empty one for A
+        let tx = spend(&descriptor, &found[1], &[2]).unwrap();
+        let stack: Vec<&[u8]> = tx.input[0].witness.iter().collect();
+        assert_eq!(stack.len(), 3);
+        assert!(!stack[0].is_empty());
+        assert!(stack[1].is_empty());
+        assert_eq!(stack[2], witness_script.as_slice());
+        assert_eq!(tx.input[0].sequence, bitcoin::Sequence::from_height(10));
+
+        // B's signature without the relative lock satisfies nothing
+        let unlocked = Branch {
+            older: None,
+            ..found[1].clone()
+        };
+        assert!(matches!(
+            spend(&descriptor, &unlocked, &[2]),
+            Err(CapstoneError::PsbtNotFinalizable(_))
+        ));
+    }
+
+    #[test]
+    fn spend_fee_covers_the_largest_witness() {
+        let descriptor = definite("or(pk(A),and(pk(B),older(10)))");
+        let found = branches(&parse("or(pk(A),and(pk(B),older(10)))").unwrap()).unwrap();
+        let psbt = spend_psbt(
+            &descriptor,
+            (
+                bitcoin::OutPoint::new(bitcoin::Txid::all_zeros(), 0),
+                bitcoin::Amount::from_sat(100_000),
+            ),
+            &found[1],
+            descriptor.script_pubkey(),
+            bitcoin::Amount::ZERO,
+        )
+        .unwrap();
+        let fee = spend_fee(&psbt, &descriptor, 1000).unwrap();
+        for (branch, signer) in found.iter().zip([1, 2]) {
+            let vsize = spend(&descriptor, branch, &[signer]).unwrap().vsize() as u64;
+            assert!(fee.to_sat() >= vsize, "{} < {}", fee.to_sat(), vsize);
+            assert!(
+                fee.to_sat() <= vsize + 2,
+                "{} > {} + 2",
+                fee.to_sat(),
+                vsize
+            );
+        }
+    }
+
+    #[test]
+    fn collects_wallet_signatures() {
+        let descriptor = definite("or(pk(A),and(pk(B),older(10)))");
+        let found = branches(&parse("or(pk(A),and(pk(B),older(10)))").unwrap()).unwrap();
+        let mut psbt = spend_psbt(
+            &descriptor,
+            (
+                bitcoin::OutPoint::new(bitcoin::Txid::all_zeros(), 0),
+                bitcoin::Amount::from_sat(100_000),
+            ),
+            &found[0],
+            descriptor.script_pubkey(),
+            bitcoin::Amount::ZERO,
+        )
+        .unwrap();
+
+        // A's wallet signs; B's (an older node) hands the PSBT back unchanged
+        let wallet = |signer: Option<u8>| {
+            mockrpc::client(move |method, params| match method {
+                "walletprocesspsbt" => {
+                    let mut psbt = Psbt::from_str(params[0].as_str().unwrap()).unwrap();
+                    if let Some(n) = signer {
+                        sign_input(&mut psbt, &private_key(n)).unwrap();
+                    }
+                    Ok(json!({ "psbt": psbt.to_string(), "complete": signer.is_some() }))
+                }
+                other => panic!("unexpected {}", other),
+            })
+        };
+        let (a_rpc, a_calls) = wallet(Some(1));
+        let (b_rpc, _) = wallet(None);
+        let key = |wallet_rpc, public_key: &str| WalletKey {
+            wallet_rpc,
+            address: Address::p2wpkh(
+                &bitcoincore_rpc::bitcoin::PublicKey::from_str(public_key).unwrap(),
+                Network::Regtest,
+            )
+            .unwrap(),
+            public_key: bitcoin::PublicKey::from_str(public_key).unwrap(),
+            descriptor_key: DescriptorPublicKey::from_str(public_key).unwrap(),
+        };
+        let keys = [key(&a_rpc, A), key(&b_rpc, B)];
+
+        let missing = collect_signatures(&mut psbt, &keys).unwrap();
+        assert_eq!(*a_calls.lock().unwrap(), ["walletprocesspsbt"]);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].public_key, keys[1].public_key);
+        assert_eq!(
+            psbt.inputs[0].partial_sigs.keys().collect::<Vec<_>>(),
+            [&keys[0].public_key]
+        );
+        let tx = finalize(psbt).unwrap();
+        assert_eq!(tx.input[0].witness.len(), 2);
+    }
+
+    #[test]
+    fn descriptor_needs_the_node_checksum() {
+        let descriptor = format!("wsh(pk({A}))");
+        let (rpc, _) = mockrpc::client(|_, params| {
+            Ok(json!({ "descriptor": format!("{}#abcdefgh", params[0].as_str().unwrap()) }))
+        });
+        assert_eq!(
+            checked_descriptor(&rpc, &descriptor).unwrap(),
+            format!("{}#abcdefgh", descriptor)
+        );
+
+        let (rpc, _) = mockrpc::client(|_, _| Ok(json!({ "checksum": "abcdefgh" })));
+        let error = checked_descriptor(&rpc, &descriptor)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains(&format!(
+                "getdescriptorinfo returned no descriptor for {}",
+                descriptor
+            )),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,361 @@
+use crate::block;
+use crate::error::{CapstoneError, Result};
+use crate::prune;
+use crate::session;
+use crate::version::{self, Capability};
+use bitcoincore_rpc::bitcoin::{Address, Amount, BlockHash, Network, OutPoint, ScriptBuf};
+use bitcoincore_rpc::{Client, RpcApi};
+use std::collections::HashMap;
+
+// Whether the node keeps a transaction index. Without one, getrawtransaction
+// only finds mempool transactions and those in a block we name.
+pub fn has_txindex(rpc: &Client) -> Result<bool> {
+    Ok(rpc.get_index_info()?.txindex.is_some())
+}
+
+// The output an input spends.
+#[derive(Debug, Clone)]
+pub struct Prevout {
+    pub value: Amount,
+    pub script_pubkey: ScriptBuf,
+    pub address: Option<Address>,
+}
+
+// Finds the outputs spent by a transaction's inputs. Prevouts of a block
+// read with `use_block` come first; then with -txindex one
+// getrawtransaction does it; without, the UTXO set (for outputs not spent
+// yet) and then every loaded wallet's copy of the funding transaction are
+// tried, and getrawtransaction last for a funding transaction still in the
+// mempool. The fallbacks don't need the funding block, so they also work on a
+// pruned node.
+pub struct PrevoutResolver<'a> {
+    rpc: &'a Client,
+    network: Network,
+    txindex: bool,
+    pruned: bool,
+    // Loaded lazily, only without -txindex
+    wallets: Option<Vec<Client>>,
+    // From getblock verbosity 3 of the blocks passed to `use_block`
+    in_blocks: HashMap<OutPoint, Prevout>,
+}
+
+impl<'a> PrevoutResolver<'a> {
+    pub fn new(rpc: &'a Client) -> Result<Self> {
+        Ok(PrevoutResolver {
+            rpc,
+            network: rpc.get_blockchain_info()?.chain,
+            txindex: has_txindex(rpc)?,
+            pruned: prune::prune_state(rpc)?.pruned,
+            wallets: None,
+            in_blocks: HashMap::new(),
+        })
+    }
+
+    // Read the prevouts of every input in `block_hash`, for the transactions
+    // it confirms. Core 23+ lists them (getblock verbosity 3) without
+    // -txindex and whether or not the outputs are spent; older nodes, and a
+    // pruned node without the block, leave the other ways.
+    pub fn use_block(&mut self, block_hash: &BlockHash) -> Result<()> {
+        if !version::supports(self.rpc, Capability::BlockPrevouts)? {
+            return Ok(());
+        }
+        let Ok(block) = block::get_block_full(self.rpc, block_hash) else {
+            return Ok(());
+        };
+        for vin in block.tx.iter().flat_map(|tx| &tx.vin) {
+            let (Some(txid), Some(vout), Some(prevout)) = (vin.txid, vin.vout, &vin.prevout) else {
+                continue;
+            };
+            let script_pubkey = ScriptBuf::from_hex(&prevout.script_pub_key.hex).map_err(|e| {
+                CapstoneError::Usage(format!("prevout script of {}:{}: {}", txid, vout, e))
+            })?;
+            let prevout = self.prevout(prevout.value, script_pubkey);
+            self.in_blocks.insert(OutPoint::new(txid, vout), prevout);
+        }
+        Ok(())
+    }
+
+    fn prevout(&self, value: Amount, script_pubkey: ScriptBuf) -> Prevout {
+        Prevout {
+            address: Address::from_script(&script_pubkey, self.network).ok(),
+            value,
+            script_pubkey,
+        }
+    }
+
+    fn in_raw_transaction(&self, outpoint: &OutPoint) -> Option<Prevout> {
+        let tx = self.rpc.get_raw_transaction(&outpoint.txid, None).ok()?;
+        let txout = tx.output.get(outpoint.vout as usize)?;
+        Some(self.prevout(txout.value, txout.script_pubkey.clone()))
+    }
+
+    fn in_utxo_set(&self, outpoint: &OutPoint) -> Result<Option<Prevout>> {
+        Ok(self
+            .rpc
+            .get_tx_out(&outpoint.txid, outpoint.vout, Some(true))?
+            .map(|txout| {
+                self.prevout(txout.value, ScriptBuf::from_bytes(txout.script_pub_key.hex))
+            }))
+    }
+
+    fn in_wallets(&mut self, outpoint: &OutPoint) -> Result<Option<Prevout>> {
+        if self.wallets.is_none() {
+            let wallets = self
+                .rpc
+                .list_wallets()?
+                .iter()
+                .map(|name| session::wallet_client(name))
+                .collect::<Result<Vec<_>>>()?;
+            self.wallets = Some(wallets);
+        }
+        for wallet_rpc in self.wallets.iter().flatten() {
+            // Any wallet that saw the funding transaction keeps its hex
+            let Ok(wallet_tx) = wallet_rpc.get_transaction(&outpoint.txid, None) else {
+                continue;
+            };
+            let tx = wallet_tx.transaction()?;
+            if let Some(txout) = tx.output.get(outpoint.vout as usize) {
+                return Ok(Some(self.prevout(txout.value, txout.script_pubkey.clone())));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn resolve(&mut self, outpoint: &OutPoint) -> Result<Prevout> {
+        if let Some(prevout) = self.in_blocks.get(outpoint) {
+            return Ok(prevout.clone());
+        }
+        if self.txindex {
+            if let Some(prevout) = self.in_raw_transaction(outpoint) {
+                return Ok(prevout);
+            }
+        }
+        if let Some(prevout) = self.in_utxo_set(outpoint)? {
+            return Ok(prevout);
+        }
+        if let Some(prevout) = self.in_wallets(outpoint)? {
+            return Ok(prevout);
+        }
+        if let Some(prevout) = self.in_raw_transaction(outpoint) {
+            return Ok(prevout);
+        }
+        Err(CapstoneError::PrevoutNotFound {
+            outpoint: *outpoint,
+            txindex: self.txindex,
+            pruned: self.pruned,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mockrpc::{self, Reply};
+    use bitcoincore_rpc::bitcoin::consensus::encode;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{Transaction, Txid};
+    use serde_json::{json, Value};
+
+    const PAID: &str = "bcrt1qak6gpu2p6zjpwrhvd4dvdnp4rt3ysm9rpst3wu";
+    const BLOCK: &str = "3b821acd7c32c2b3da143e2c6b0134e5aa8206aeae0a54bfa4963e73ac2857a0";
+
+    fn paid() -> Address {
+        PAID.parse::<Address<_>>().unwrap().assume_checked()
+    }
+
+    // The funding transaction: its output 0 pays PAID
+    fn funding() -> Transaction {
+        let mut tx = mockrpc::transaction();
+        tx.output[0].script_pubkey = paid().script_pubkey();
+        tx
+    }
+
+    fn outpoint() -> OutPoint {
+        OutPoint::new(funding().txid(), 0)
+    }
+
+    // The calls every resolver makes, on a Core 25 node with or without -txindex
+    fn node(method: &str, txindex: bool) -> Option<Reply> {
+        Some(Ok(match method {
+            "getblockchaininfo" => mockrpc::blockchain_info(None),
+            "getindexinfo" if txindex => {
+                json!({ "txindex": { "synced": true, "best_block_height": 101 } })
+            }
+            "getindexinfo" => json!({}),
+            "getnetworkinfo" => mockrpc::network_info(),
+            _ => return None,
+        }))
+    }
+
+    fn assert_paid(prevout: &Prevout) {
+        assert_eq!(prevout.value, Amount::from_sat(49_999_000));
+        assert_eq!(prevout.script_pubkey, paid().script_pubkey());
+        assert_eq!(prevout.address, Some(paid()));
+    }
+
+    #[test]
+    fn txindex_finds_any_transaction() {
+        let (rpc, calls) = mockrpc::client(|method, params| {
+            node(method, true).unwrap_or_else(|| match method {
+                "getrawtransaction" => {
+                    assert_eq!(params[0], json!(funding().txid().to_string()));
+                    Ok(json!(encode::serialize_hex(&funding())))
+                }
+                other => panic!("unexpected {}", other),
+            })
+        });
+        let mut resolver = PrevoutResolver::new(&rpc).unwrap();
+        assert_paid(&resolver.resolve(&outpoint()).unwrap());
+        assert_eq!(calls.lock().unwrap().last().unwrap(), "getrawtransaction");
+    }
+
+    #[test]
+    fn without_txindex_the_utxo_set_comes_first() {
+        let (rpc, calls) = mockrpc::client(|method, _| {
+            node(method, false).unwrap_or_else(|| match method {
+                "gettxout" => Ok(json!({
+                    "bestblock": BLOCK,
+                    "confirmations": 1,
+                    "value": 0.49999,
+                    "scriptPubKey": {
+                        "asm": "",
+                        "hex": paid().script_pubkey().to_hex_string(),
+                        "type": "witness_v0_keyhash",
+                        "address": PAID
+                    },
+                    "coinbase": false
+                })),
+                other => panic!("unexpected {}", other),
+            })
+        });
+        let mut resolver = PrevoutResolver::new(&rpc).unwrap();
+        assert_paid(&resolver.resolve(&outpoint()).unwrap());
+        assert!(!calls
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|m| m == "getrawtransaction"));
+    }
+
+    #[test]
+    fn spent_outputs_come_from_the_wallet_that_received_them() {
+        let (rpc, _) = mockrpc::client(|method, _| {
+            node(method, false).unwrap_or_else(|| match method {
+                "gettxout" => Ok(Value::Null),
+                other => panic!("unexpected {}", other),
+            })
+        });
+        // The first wallet never saw it; the second did
+        let (stranger, _) =
+            mockrpc::client(|_, _| Err((-5, "Invalid or non-wallet transaction id")));
+        let (receiver, receiver_calls) =
+            mockrpc::client(|_, _| Ok(mockrpc::wallet_tx(&funding(), 1, false)));
+        let mut resolver = PrevoutResolver::new(&rpc).unwrap();
+        resolver.wallets = Some(vec![stranger, receiver]);
+        assert_paid(&resolver.resolve(&outpoint()).unwrap());
+        assert_eq!(*receiver_calls.lock().unwrap(), ["gettransaction"]);
+    }
+
+    // getblock verbosity 3 of the block confirming a spend of outpoint()
+    fn spending_block() -> Value {
+        json!({
+            "hash": BLOCK,
+            "height": 102,
+            "time": 1700000000,
+            "mediantime": 1699999000,
+            "weight": 1000,
+            "tx": [
+                {
+                    "txid": Txid::all_zeros(),
+                    "hash": Txid::all_zeros(),
+                    "size": 100, "vsize": 100, "weight": 400,
+                    "vin": [{ "coinbase": "0166", "sequence": 4294967295u32 }],
+                    "vout": []
+                },
+                {
+                    "txid": Txid::all_zeros(),
+                    "hash": Txid::all_zeros(),
+                    "size": 110, "vsize": 110, "weight": 440,
+                    "vin": [{
+                        "txid": funding().txid(),
+                        "vout": 0,
+                        "sequence": 4294967293u32,
+                        "prevout": {
+                            "generated": false,
+                            "height": 101,
+                            "value": 0.49999,
+                            "scriptPubKey": {
+                                "hex": paid().script_pubkey().to_hex_string(),
+                                "type": "witness_v0_keyhash",
+                                "address": PAID
+                            }
+                        }
+                    }],
+                    "vout": [],
+                    "fee": 0.00001
+                }
+            ]
+        })
+    }
+
+    #[test]
+    fn block_prevouts_need_no_index() {
+        let (rpc, calls) = mockrpc::client(|method, params| {
+            node(method, false).unwrap_or_else(|| match method {
+                "getblock" => {
+                    assert_eq!(params[1], json!(3));
+                    Ok(spending_block())
+                }
+                other => panic!("unexpected {}", other),
+            })
+        });
+        let mut resolver = PrevoutResolver::new(&rpc).unwrap();
+        resolver.use_block(&BLOCK.parse().unwrap()).unwrap();
+        calls.lock().unwrap().clear();
+        assert_paid(&resolver.resolve(&outpoint()).unwrap());
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn block_prevouts_are_skipped_before_core_23() {
+        let (rpc, calls) = mockrpc::client(|method, _| match method {
+            "getnetworkinfo" => {
+                let mut info = mockrpc::network_info();
+                info["version"] = json!(220000);
+                Ok(info)
+            }
+            other => node(other, true).unwrap_or_else(|| panic!("unexpected {}", other)),
+        });
+        let mut resolver = PrevoutResolver::new(&rpc).unwrap();
+        resolver.use_block(&BLOCK.parse().unwrap()).unwrap();
+        assert!(!calls.lock().unwrap().iter().any(|m| m == "getblock"));
+    }
+
+    #[test]
+    fn every_way_failing_suggests_txindex() {
+        let (rpc, calls) = mockrpc::client(|method, _| {
+            node(method, false).unwrap_or_else(|| match method {
+                "gettxout" => Ok(Value::Null),
+                "getrawtransaction" => Err((
+                    -5,
+                    "No such mempool transaction. Use -txindex or provide a block hash to enable blockchain transaction queries.",
+                )),
+                other => panic!("unexpected {}", other),
+            })
+        });
+        let mut resolver = PrevoutResolver::new(&rpc).unwrap();
+        resolver.wallets = Some(Vec::new());
+        calls.lock().unwrap().clear();
+        let error = resolver.resolve(&outpoint()).unwrap_err();
+        assert!(matches!(
+            error,
+            CapstoneError::PrevoutNotFound {
+                txindex: false,
+                pruned: false,
+                ..
+            }
+        ));
+        assert!(error.to_string().contains("txindex"), "{}", error);
+        assert_eq!(*calls.lock().unwrap(), ["gettxout", "getrawtransaction"]);
+    }
+}
@@ -0,0 +1,257 @@
+use crate::cli::Config;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::fees;
+use crate::mempool;
+use crate::mine;
+use crate::payment;
+use crate::relay;
+use crate::report;
+use crate::session::Session;
+use crate::template;
+use crate::version::{self, Capability};
+use bitcoincore_rpc::bitcoin::{Amount, Network, Txid};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+// What prioritise-demo pays the Trader, and the delta it applies unless
+// --fee-delta says otherwise
+const DEMO_AMOUNT: Amount = Amount::from_sat(1_000_000);
+const DEFAULT_DEMO_DELTA_SAT: i64 = 10_000;
+
+// One entry of getprioritisedtransactions: the summed delta of every
+// prioritisetransaction call for the txid, in sat.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FeeDelta {
+    pub fee_delta: i64,
+    pub in_mempool: bool,
+    // Base fee plus the delta, only while the transaction is in the mempool
+    #[serde(default)]
+    pub modified_fee: Option<i64>,
+}
+
+impl FeeDelta {
+    pub fn describe(&self, txid: &Txid) -> String {
+        format!(
+            "{}: {:+} sat{}",
+            txid,
+            self.fee_delta,
+            match self.modified_fee {
+                Some(modified) => format!(", modified fee {} sat", modified),
+                None if self.in_mempool => String::new(),
+                None => ", not in the mempool".to_string(),
+            }
+        )
+    }
+}
+
+// The deltas this node holds, None before Core 26.
+pub fn prioritised(rpc: &Client) -> Result<Option<BTreeMap<Txid, FeeDelta>>> {
+    if !version::supports(rpc, Capability::ListPrioritised)? {
+        return Ok(None);
+    }
+    Ok(Some(rpc.call("getprioritisedtransactions", &[])?))
+}
+
+// Add `delta_sat` to the fee the node's block assembly and eviction use for
+// `txid`, without touching the transaction. Deltas add up over calls and stay
+// on this node: they aren't relayed, so peers and other miners still see the
+// real fee. A delta that would take the modified fee of a mempool transaction
+// below zero is refused; the node would accept it, but would then treat the
+// transaction as paying a negative fee.
+pub fn prioritise(rpc: &Client, txid: &Txid, delta_sat: i64) -> Result<()> {
+    if let Some(entry) = mempool::find_mempool_entry(rpc, txid)? {
+        let modified = entry.fees.modified.to_sat() as i64;
+        if modified + delta_sat < 0 {
+            return Err(CapstoneError::Usage(format!(
+                "a {} sat delta would take {}'s modified fee from {} sat to {} sat (base fee {} sat); the most it can lower it by is {} sat",
+                delta_sat,
+                txid,
+                modified,
+                modified + delta_sat,
+                entry.fees.base.to_sat(),
+                modified
+            )));
+        }
+    }
+    // The middle argument is a leftover priority field, always 0
+    rpc.call::<bool>(
+        "prioritisetransaction",
+        &[json!(txid), json!(0), json!(delta_sat)],
+    )?;
+    Ok(())
+}
+
+// The report line for the deltas the node holds
+pub fn describe(deltas: &BTreeMap<Txid, FeeDelta>) -> String {
+    if deltas.is_empty() {
+        return "Prioritisation deltas: none".to_string();
+    }
+    let deltas: Vec<String> = deltas
+        .iter()
+        .map(|(txid, delta)| delta.describe(txid))
+        .collect();
+    format!("Prioritisation deltas: {}", deltas.join("; "))
+}
+
+fn print_deltas(rpc: &Client) -> Result<()> {
+    match prioritised(rpc)? {
+        Some(deltas) => say!("{}", describe(&deltas)),
+        None => {
+            say!("This node can't list its deltas (getprioritisedtransactions is Core 26+).")
+        }
+    }
+    Ok(())
+}
+
+// prioritise: apply a fee delta to one transaction and show what the node now
+// holds.
+pub fn run(session: &Session, txid: &str, delta_sat: i64) -> Result<()> {
+    let txid: Txid = txid
+        .parse()
+        .map_err(|e| CapstoneError::Usage(format!("invalid txid '{}': {}", txid, e)))?;
+    let rpc = &session.rpc;
+    prioritise(rpc, &txid, delta_sat)?;
+    match mempool::find_mempool_entry(rpc, &txid)? {
+        Some(entry) => say!(
+            "{}: base fee {} sat, modified fee {} sat",
+            txid,
+            entry.fees.base.to_sat(),
+            entry.fees.modified.to_sat()
+        ),
+        None => say!(
+            "{} is not in the mempool; the delta applies if it arrives.",
+            txid
+        ),
+    }
+    say!("The delta is local to this node: it isn't relayed and peers still see the real fee.");
+    print_deltas(rpc)
+}
+
+// Whether the next block template has `txid`, and how that reads in the demo
+fn template_status(rpc: &Client, txid: &Txid) -> Result<(bool, String)> {
+    let template = template::block_template(rpc)?;
+    let included = template
+        .as_ref()
+        .is_some_and(|template| template.position(txid).is_some());
+    let status = match &template {
+        Some(template) => match template.position(txid) {
+            Some(i) => format!(
+                "included as transaction {} of {} at height {}",
+                i + 1,
+                template.transactions.len(),
+                template.height
+            ),
+            None => format!(
+                "not included ({} other transaction(s) at height {})",
+                template.transactions.len(),
+                template.height
+            ),
+        },
+        None => "no template".to_string(),
+    };
+    Ok((included, status))
+}
+
+// prioritise-demo: send a payment at the mempool's minimum feerate, show
+// getblocktemplate leaving it out, prioritise it and show it taken in. Only
+// the node's view of the fee changes: txid and fee are the same throughout.
+pub fn run_demo(session: &Session, config: &Config) -> Result<()> {
+    if session.network != Network::Regtest {
+        return Err(CapstoneError::Usage(
+            "prioritise-demo mines its payment, it only runs on regtest".into(),
+        ));
+    }
+    let rpc = &session.rpc;
+    let delta = config.fee_delta.unwrap_or(DEFAULT_DEMO_DELTA_SAT);
+    if delta <= 0 {
+        return Err(CapstoneError::Usage(
+            "prioritise-demo raises a transaction's priority, --fee-delta must be positive".into(),
+        ));
+    }
+    session.fund_miner(DEMO_AMOUNT + config.max_fee())?;
+
+    let floor = relay::policy(rpc, &[])?.mempool_min_fee;
+    let mut options = payment::send_options(config);
+    options.insert("fee_rate".into(), json!(floor as f64 / 1000.0));
+    let trader_address = session
+        .trader_rpc
+        .get_new_address(None, None)?
+        .assume_checked();
+    let txid = payment::send(
+        &session.miner_rpc,
+        &trader_address.to_string(),
+        DEMO_AMOUNT,
+        config,
+        options,
+    )?;
+    let entry = mempool::get_mempool_entry(rpc, &txid)?;
+    let base_fee = entry.fees.base;
+
+    let mut narrative = vec![format!(
+        "Low-fee payment {}: {} sat fee, {} sat/vB (the mempool minimum)",
+        txid,
+        base_fee.to_sat(),
+        fees::format_sat_per_vb(entry.individual_fee_rate())
+    )];
+    let (mut included, status) = template_status(rpc, &txid)?;
+    narrative.push(format!("Block template before: {}", status));
+    if included {
+        // -blockmintxfee is normally at or below the relay floor, so a
+        // transaction the mempool takes also makes the template. Zeroing its
+        // modified fee puts it under -blockmintxfee instead.
+        let demotion = -(base_fee.to_sat() as i64);
+        prioritise(rpc, &txid, demotion)?;
+        let status;
+        (included, status) = template_status(rpc, &txid)?;
+        narrative.push(format!(
+            "Block template at a modified fee of 0 sat ({:+} sat, it paid above -blockmintxfee): {}",
+            demotion, status
+        ));
+    }
+    if included {
+        return Err(CapstoneError::Usage(format!(
+            "{} is in the block template even at a modified fee of 0; is the node running with -blockmintxfee=0?",
+            txid
+        )));
+    }
+
+    prioritise(rpc, &txid, delta)?;
+    let entry = mempool::get_mempool_entry(rpc, &txid)?;
+    let (_, status) = template_status(rpc, &txid)?;
+    narrative.push(format!(
+        "Block template after prioritising by {:+} sat (modified fee {} sat): {}",
+        delta,
+        entry.fees.modified.to_sat(),
+        status
+    ));
+    if let Some(deltas) = prioritised(rpc)? {
+        narrative.push(describe(&deltas));
+    }
+    // The transaction itself is untouched
+    narrative.push(format!(
+        "Base fee still {} sat, txid still {}; the delta is local to this node and never relayed",
+        entry.fees.base.to_sat(),
+        txid
+    ));
+    if entry.fees.base != base_fee {
+        return Err(CapstoneError::Usage(format!(
+            "{}'s base fee changed from {} to {} sat",
+            txid,
+            base_fee.to_sat(),
+            entry.fees.base.to_sat()
+        )));
+    }
+
+    let block_hash = mine::confirm(session, &[txid], config)?;
+    narrative.push(format!("Mined in block {}", block_hash));
+
+    let mut output_string = String::new();
+    for (i, line) in narrative.iter().enumerate() {
+        say!("{}", line);
+        output_string.push_str(&format!("Step {}: {}\n", i + 1, line));
+    }
+    report::write_report(&output_string)
+}
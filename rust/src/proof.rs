@@ -0,0 +1,205 @@
+use crate::error::{CapstoneError, Result};
+use bitcoincore_rpc::bitcoin::consensus::encode;
+use bitcoincore_rpc::bitcoin::hex::DisplayHex;
+use bitcoincore_rpc::bitcoin::{BlockHash, MerkleBlock, TxMerkleNode, Txid};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde_json::json;
+
+// An SPV proof that the payment is in its block: the block header plus the
+// partial merkle tree linking the txid to the header's merkle root.
+#[derive(Debug, Clone)]
+pub struct InclusionProof {
+    pub proof: Vec<u8>,
+    pub merkle_root: TxMerkleNode,
+    // Position of the transaction in the block
+    pub index: u32,
+    pub block_transactions: u32,
+    // verifytxoutproof gave the txid back
+    pub node_verified: bool,
+    // The root recomputed here from the partial tree matches the header's
+    pub locally_verified: bool,
+}
+
+// Check a serialized merkle block on our own: it has to parse, its tree has to
+// hash up to the root in its header, the header has to be `block_hash`'s, and
+// `txid` has to be the one matched leaf. Returns the leaf's index and the
+// number of transactions in the block.
+pub fn verify_locally(proof: &[u8], txid: &Txid, block_hash: &BlockHash) -> Result<(u32, u32)> {
+    let failed = |reason: String| CapstoneError::ProofCheckFailed(reason);
+    let merkle_block: MerkleBlock = encode::deserialize(proof)
+        .map_err(|e| failed(format!("the proof doesn't parse: {}", e)))?;
+    if merkle_block.header.block_hash() != *block_hash {
+        return Err(failed(format!(
+            "the proof is for block {}, not {}",
+            merkle_block.header.block_hash(),
+            block_hash
+        )));
+    }
+    let (mut matches, mut indexes) = (Vec::new(), Vec::new());
+    let root = merkle_block
+        .txn
+        .extract_matches(&mut matches, &mut indexes)
+        .map_err(|e| failed(format!("the partial merkle tree is invalid: {}", e)))?;
+    if root != merkle_block.header.merkle_root {
+        return Err(failed(format!(
+            "the tree hashes to {}, the header commits to {}",
+            root, merkle_block.header.merkle_root
+        )));
+    }
+    match (matches.as_slice(), indexes.as_slice()) {
+        ([matched], [index]) if matched == txid => {
+            Ok((*index, merkle_block.txn.num_transactions()))
+        }
+        _ => Err(failed(format!(
+            "the proof matches {:?}, not just {}",
+            matches, txid
+        ))),
+    }
+}
+
+// gettxoutproof for `txid` in `block_hash` (which also works without
+// -txindex), checked both by the node with verifytxoutproof and locally.
+pub fn inclusion_proof(
+    rpc: &Client,
+    txid: &Txid,
+    block_hash: &BlockHash,
+) -> Result<InclusionProof> {
+    let proof = rpc.get_tx_out_proof(&[*txid], Some(block_hash))?;
+    let verified: Vec<Txid> =
+        rpc.call("verifytxoutproof", &[json!(proof.to_lower_hex_string())])?;
+    let node_verified = verified == [*txid];
+    if !node_verified {
+        return Err(CapstoneError::ProofCheckFailed(format!(
+            "verifytxoutproof doesn't return {} for its own proof",
+            txid
+        )));
+    }
+    let (index, block_transactions) = verify_locally(&proof, txid, block_hash)?;
+    let merkle_root = rpc.get_block_header(block_hash)?.merkle_root;
+    Ok(InclusionProof {
+        proof,
+        merkle_root,
+        index,
+        block_transactions,
+        node_verified,
+        locally_verified: true,
+    })
+}
+
+impl InclusionProof {
+    pub fn hex(&self) -> String {
+        self.proof.to_lower_hex_string()
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        let yes_no = |ok: bool| if ok { "yes" } else { "no" };
+        vec![
+            format!("Merkle root: {}", self.merkle_root),
+            format!(
+                "Merkle proof: transaction {} of {} in the block, {} bytes",
+                self.index + 1,
+                self.block_transactions,
+                self.proof.len()
+            ),
+            format!("Merkle proof (hex): {}", self.hex()),
+            format!(
+                "Merkle proof verified by the node: {}, independently verified: {}",
+                yes_no(self.node_verified),
+                yes_no(self.locally_verified)
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::block::{Header, Version};
+    use bitcoincore_rpc::bitcoin::{CompactTarget, MerkleBlock};
+
+    // Mainnet block 100000 and its four transactions
+    const BLOCK_HASH: &str = "000000000003ba27aa200b1cecaad478d2b00432346c3f1f3986da1afd33e506";
+    const TXIDS: [&str; 4] = [
+        "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+        "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+        "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+        "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+    ];
+
+    fn header() -> Header {
+        Header {
+            version: Version::ONE,
+            prev_blockhash: "000000000002d01c1fccc21636b607dfd930d31d01c3a62104612a1719011250"
+                .parse()
+                .unwrap(),
+            merkle_root: "f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766"
+                .parse()
+                .unwrap(),
+            time: 1_293_623_863,
+            bits: CompactTarget::from_consensus(0x1b04_864c),
+            nonce: 274_148_111,
+        }
+    }
+
+    fn txids() -> Vec<Txid> {
+        TXIDS.iter().map(|txid| txid.parse().unwrap()).collect()
+    }
+
+    // What gettxoutproof returns for the `index`th transaction
+    fn proof_for(index: usize) -> Vec<u8> {
+        let txids = txids();
+        let merkle_block =
+            MerkleBlock::from_header_txids_with_predicate(&header(), &txids, |txid| {
+                *txid == txids[index]
+            });
+        encode::serialize(&merkle_block)
+    }
+
+    #[test]
+    fn a_valid_proof_verifies() {
+        let block_hash: BlockHash = BLOCK_HASH.parse().unwrap();
+        assert_eq!(header().block_hash(), block_hash);
+        for (index, txid) in txids().iter().enumerate() {
+            let proof = proof_for(index);
+            assert_eq!(
+                verify_locally(&proof, txid, &block_hash).unwrap(),
+                (index as u32, 4)
+            );
+        }
+    }
+
+    #[test]
+    fn a_proof_for_another_block_is_refused() {
+        let proof = proof_for(2);
+        let other = header().prev_blockhash;
+        let error = verify_locally(&proof, &txids()[2], &other).unwrap_err();
+        assert!(
+            error.to_string().contains("the proof is for block"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn a_proof_for_another_transaction_is_refused() {
+        let block_hash: BlockHash = BLOCK_HASH.parse().unwrap();
+        let error = verify_locally(&proof_for(1), &txids()[2], &block_hash).unwrap_err();
+        assert!(error.to_string().contains("the proof matches"), "{}", error);
+    }
+
+    #[test]
+    fn a_tampered_proof_is_refused() {
+        let block_hash: BlockHash = BLOCK_HASH.parse().unwrap();
+        let proof = proof_for(3);
+        // 80 byte header, 4 byte transaction count, then the hash count and
+        // the first hash of the tree
+        let mut tampered = proof.clone();
+        tampered[80 + 4 + 1] ^= 0x01;
+        let error = verify_locally(&tampered, &txids()[3], &block_hash).unwrap_err();
+        assert!(error.to_string().contains("hashes to"), "{}", error);
+
+        let error =
+            verify_locally(&proof[..proof.len() - 1], &txids()[3], &block_hash).unwrap_err();
+        assert!(error.to_string().contains("doesn't parse"), "{}", error);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::cli::Config;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::mine;
+use crate::payment;
+use crate::report;
+use crate::session::{self, Session};
+use bitcoincore_rpc::bitcoin::{Amount, BlockHash, Network};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// What the payment sends the Trader
+const PAYMENT_AMOUNT: Amount = Amount::from_sat(1_000_000);
+// How long the nodes get to connect, to catch up with each other, and to
+// relay the payment and the block
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const SYNC_TIMEOUT: Duration = Duration::from_secs(60);
+const RELAY_TIMEOUT: Duration = Duration::from_secs(60);
+// How often node B is asked whether something arrived; also the resolution
+// of the latencies
+const POLL: Duration = Duration::from_millis(10);
+
+// The getpeerinfo fields we look at; the result for each peer is large and
+// changes between releases.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Peer {
+    pub id: u64,
+    pub addr: String,
+    pub inbound: bool,
+    // "outbound-full-relay", "manual", ... (Core 21+)
+    #[serde(default)]
+    pub connection_type: Option<String>,
+}
+
+pub fn peers(rpc: &Client) -> Result<Vec<Peer>> {
+    Ok(rpc.call("getpeerinfo", &[])?)
+}
+
+pub fn connection_count(rpc: &Client) -> Result<usize> {
+    Ok(rpc.get_connection_count()?)
+}
+
+// Ask the node to connect to `address` (host:port of the peer's P2P socket)
+// once, without adding it to the list it keeps reconnecting to.
+pub fn connect_once(rpc: &Client, address: &str) -> Result<()> {
+    Ok(rpc.onetry_node(address)?)
+}
+
+// Poll `check` until it gives a value or `timeout` passes. The time is how
+// long it took, from `started`.
+fn wait_for<T>(
+    started: Instant,
+    timeout: Duration,
+    mut check: impl FnMut() -> Result<Option<T>>,
+) -> Result<Option<(T, Duration)>> {
+    loop {
+        if let Some(value) = check()? {
+            return Ok(Some((value, started.elapsed())));
+        }
+        if started.elapsed() >= timeout {
+            return Ok(None);
+        }
+        thread::sleep(POLL);
+    }
+}
+
+// Connect node A (the session's) to B's P2P `address` unless they already
+// are, and wait for B to see the connection too.
+fn connect(a: &Client, b: &Client, address: Option<&str>) -> Result<Peer> {
+    let find = |a: &Client| -> Result<Option<Peer>> {
+        Ok(peers(a)?
+            .into_iter()
+            .find(|peer| address.is_none_or(|address| peer.addr == address)))
+    };
+    if let Some(peer) = find(a)? {
+        return Ok(peer);
+    }
+    let Some(address) = address else {
+        return Err(CapstoneError::PeersNotConnected {
+            address: None,
+            waited: 0,
+        });
+    };
+    connect_once(a, address)?;
+    let connected = wait_for(Instant::now(), CONNECT_TIMEOUT, || {
+        Ok(match find(a)? {
+            Some(peer) if connection_count(b)? > 0 => Some(peer),
+            _ => None,
+        })
+    })?;
+    connected
+        .map(|(peer, _)| peer)
+        .ok_or_else(|| CapstoneError::PeersNotConnected {
+            address: Some(address.to_string()),
+            waited: CONNECT_TIMEOUT.as_secs(),
+        })
+}
+
+fn wait_for_tip(
+    b: &Client,
+    tip: &BlockHash,
+    started: Instant,
+    timeout: Duration,
+) -> Result<Option<Duration>> {
+    Ok(wait_for(started, timeout, || {
+        Ok((b.get_best_block_hash()? == *tip).then_some(()))
+    })?
+    .map(|(_, elapsed)| elapsed))
+}
+
+// propagation-test: pay the Trader on the session's node (A), time how long
+// the transaction takes to reach the mempool of the node at --peer-rpc (B),
+// then mine it on A and time how long B takes to reach the new tip.
+pub fn run(session: &Session, config: &Config, peer_rpc: &str) -> Result<()> {
+    if session.network != Network::Regtest {
+        return Err(CapstoneError::Usage(
+            "propagation-test mines its own blocks, it only runs on regtest".into(),
+        ));
+    }
+    let a = &session.rpc;
+    let b = session::node_client(peer_rpc)?;
+    let b_chain = b.get_blockchain_info()?.chain;
+    if b_chain != session.network {
+        return Err(CapstoneError::Usage(format!(
+            "--peer-rpc {} is on {}, node A is on {}",
+            peer_rpc, b_chain, session.network
+        )));
+    }
+
+    let peer = connect(a, &b, config.peer_p2p.as_deref())?;
+    say!(
+        "Node A is connected to {} (peer {}, {}{}).",
+        peer.addr,
+        peer.id,
+        if peer.inbound { "inbound" } else { "outbound" },
+        peer.connection_type
+            .as_ref()
+            .map_or(String::new(), |kind| format!(", {}", kind))
+    );
+
+    // B has to be at A's tip before anything is timed, or the payment's
+    // coins may be unknown to it
+    session.fund_miner(PAYMENT_AMOUNT + config.max_fee())?;
+    let a_tip = a.get_best_block_hash()?;
+    if wait_for_tip(&b, &a_tip, Instant::now(), SYNC_TIMEOUT)?.is_none() {
+        return Err(CapstoneError::PropagationTimeout {
+            what: format!("node B catching up with node A's tip {}", a_tip),
+            waited: SYNC_TIMEOUT.as_secs(),
+        });
+    }
+
+    let trader_address = session
+        .trader_rpc
+        .get_new_address(None, None)?
+        .assume_checked();
+    let txid = payment::send(
+        &session.miner_rpc,
+        &trader_address.to_string(),
+        PAYMENT_AMOUNT,
+        config,
+        payment::send_options(config),
+    )?;
+    let sent = Instant::now();
+    say!("Payment {} broadcast on node A.", txid);
+    let tx_latency = wait_for(sent, RELAY_TIMEOUT, || {
+        Ok(b.get_raw_mempool()?.contains(&txid).then_some(()))
+    })?
+    .map(|(_, elapsed)| elapsed)
+    .ok_or_else(|| CapstoneError::PropagationTimeout {
+        what: format!("payment {} reaching node B's mempool", txid),
+        waited: RELAY_TIMEOUT.as_secs(),
+    })?;
+    say!(
+        "Payment reached node B's mempool after {} ms.",
+        tx_latency.as_millis()
+    );
+
+    let block_hash = mine::confirm(session, &[txid], config)?;
+    let mined = Instant::now();
+    let block_latency = wait_for_tip(&b, &block_hash, mined, RELAY_TIMEOUT)?.ok_or_else(|| {
+        CapstoneError::PropagationTimeout {
+            what: format!("node B reaching block {}", block_hash),
+            waited: RELAY_TIMEOUT.as_secs(),
+        }
+    })?;
+    say!(
+        "Node B reached block {} after {} ms.",
+        block_hash,
+        block_latency.as_millis()
+    );
+
+    let lines = [
+        format!("Peer: {}", peer.addr),
+        format!("Transaction ID (txid): {}", txid),
+        format!(
+            "Transaction propagation latency (ms): {}",
+            tx_latency.as_millis()
+        ),
+        format!("Block hash: {}", block_hash),
+        format!(
+            "Block propagation latency (ms): {}",
+            block_latency.as_millis()
+        ),
+        // Core delays transaction announcements on purpose (about 2s to
+        // outbound peers, 5s to inbound ones, on average), blocks go out at once
+        "Note: transaction relay includes Core's deliberate announcement delay".to_string(),
+    ];
+    let mut output_string = String::new();
+    for line in &lines {
+        output_string.push_str(line);
+        output_string.push('\n');
+    }
+    report::write_report(&output_string)
+}
@@ -0,0 +1,190 @@
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use bitcoincore_rpc::bitcoin::BlockHash;
+use bitcoincore_rpc::jsonrpc::error::Error as JsonRpcError;
+use bitcoincore_rpc::{Client, RpcApi};
+
+// getblock's and getrawtransaction's code for a block they no longer have
+const RPC_MISC_ERROR: i32 = -1;
+
+// Whether the node prunes old blocks, and the lowest height it still has
+// blocks (and undo data) for.
+#[derive(Debug, Clone, Copy)]
+pub struct PruneState {
+    pub pruned: bool,
+    pub prune_height: Option<u64>,
+}
+
+pub fn prune_state(rpc: &Client) -> Result<PruneState> {
+    let info = rpc.get_blockchain_info()?;
+    Ok(PruneState {
+        pruned: info.pruned,
+        prune_height: info.prune_height.filter(|_| info.pruned),
+    })
+}
+
+impl PruneState {
+    pub fn has_block(&self, height: u64) -> bool {
+        self.prune_height
+            .is_none_or(|prune_height| height >= prune_height)
+    }
+}
+
+// Fail with an explanation, rather than the bare -1 "Block not available"
+// getblock and getrawtransaction give, when `block_hash` has been pruned.
+// `what` names what was needed from it, e.g. "the confirmation block".
+pub fn require_block(rpc: &Client, block_hash: &BlockHash, what: &str) -> Result<()> {
+    let state = prune_state(rpc)?;
+    let height = rpc.get_block_header_info(block_hash)?.height as u64;
+    match state.prune_height {
+        Some(prune_height) if !state.has_block(height) => Err(CapstoneError::BlockPruned {
+            what: what.to_string(),
+            block_hash: *block_hash,
+            height,
+            prune_height,
+        }),
+        _ => Ok(()),
+    }
+}
+
+// `result` of a call that read `block_hash`, with the node's "Block not
+// available (pruned data)" explained as `require_block` would. Pruning can
+// pass the block between the check and the call.
+pub fn explain_pruned<T>(
+    rpc: &Client,
+    block_hash: &BlockHash,
+    what: &str,
+    result: std::result::Result<T, bitcoincore_rpc::Error>,
+) -> Result<T> {
+    match result {
+        Err(bitcoincore_rpc::Error::JsonRpc(JsonRpcError::Rpc(e)))
+            if e.code == RPC_MISC_ERROR && e.message.starts_with("Block not available") =>
+        {
+            let height = rpc.get_block_header_info(block_hash)?.height as u64;
+            Err(CapstoneError::BlockPruned {
+                what: what.to_string(),
+                block_hash: *block_hash,
+                height,
+                prune_height: prune_state(rpc)?.prune_height.unwrap_or(height + 1),
+            })
+        }
+        result => Ok(result?),
+    }
+}
+
+// The first height a block scan can start from: `start`, or the prune height
+// if that is higher (with a warning, since the scan then covers less).
+pub fn clamp_start(rpc: &Client, start: u64) -> Result<u64> {
+    match prune_state(rpc)?.prune_height {
+        Some(prune_height) if start < prune_height => {
+            say!(
+                "Warning: blocks below height {} are pruned, starting at {} instead of {}.",
+                prune_height,
+                prune_height,
+                start
+            );
+            Ok(prune_height)
+        }
+        _ => Ok(start),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console;
+    use crate::mockrpc::{self, Reply};
+    use serde_json::json;
+
+    const HASH: &str = "3b821acd7c32c2b3da143e2c6b0134e5aa8206aeae0a54bfa4963e73ac2857a0";
+    const PRUNED: Reply = Err((-1, "Block not available (pruned data)"));
+
+    // A node pruned below `prune_height` (if any) whose block HASH is at
+    // `height`
+    fn node(prune_height: Option<u64>, height: u64) -> Client {
+        mockrpc::client(move |method, _| match method {
+            "getnetworkinfo" => Ok(mockrpc::network_info()),
+            "getblockchaininfo" => Ok(mockrpc::blockchain_info(prune_height)),
+            "getblockheader" => Ok(mockrpc::block_header(HASH, height)),
+            "getblock" => PRUNED,
+            other => panic!("unexpected {}", other),
+        })
+        .0
+    }
+
+    fn hash() -> BlockHash {
+        HASH.parse().unwrap()
+    }
+
+    #[test]
+    fn prune_height_only_counts_when_pruned() {
+        let state = prune_state(&node(Some(50), 0)).unwrap();
+        assert!(state.pruned);
+        assert_eq!(state.prune_height, Some(50));
+        assert!(!state.has_block(49));
+        assert!(state.has_block(50));
+
+        let state = prune_state(&node(None, 0)).unwrap();
+        assert!(!state.pruned);
+        assert!(state.has_block(0));
+    }
+
+    #[test]
+    fn scans_start_at_the_prune_height() {
+        let rpc = node(Some(50), 0);
+        let mut clamped = 0;
+        let (_, stderr) = console::capture(|| clamped = clamp_start(&rpc, 10).unwrap());
+        assert_eq!(clamped, 50);
+        assert_eq!(
+            stderr,
+            "Warning: blocks below height 50 are pruned, starting at 50 instead of 10.\n"
+        );
+
+        let (_, stderr) = console::capture(|| clamped = clamp_start(&rpc, 60).unwrap());
+        assert_eq!(clamped, 60);
+        assert_eq!(stderr, "");
+        assert_eq!(clamp_start(&node(None, 0), 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn pruned_blocks_are_explained() {
+        let error = require_block(&node(Some(50), 20), &hash(), "the confirmation block")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            format!("the confirmation block ({} at height 20) has been pruned, the node only keeps blocks from height 50; use an unpruned node or one with a higher -prune target", HASH)
+        );
+        require_block(&node(Some(50), 50), &hash(), "the confirmation block").unwrap();
+        require_block(&node(None, 20), &hash(), "the confirmation block").unwrap();
+    }
+
+    #[test]
+    fn block_not_available_becomes_block_pruned() {
+        // The node pruned the block after require_block looked
+        let rpc = node(Some(50), 20);
+        let error = crate::block::get_block_full(&rpc, &hash()).unwrap_err();
+        assert!(matches!(
+            error,
+            CapstoneError::BlockPruned {
+                height: 20,
+                prune_height: 50,
+                ..
+            }
+        ));
+
+        // Other errors, and successes, pass through
+        let not_found: std::result::Result<(), _> =
+            mockrpc::client(|_, _| Err((-5, "Block not found")))
+                .0
+                .call("getblock", &[json!(HASH)]);
+        assert!(matches!(
+            explain_pruned(&rpc, &hash(), "the block", not_found),
+            Err(CapstoneError::Rpc(_))
+        ));
+        assert_eq!(
+            explain_pruned(&rpc, &hash(), "the block", Ok(7)).unwrap(),
+            7
+        );
+    }
+}
@@ -0,0 +1,850 @@
+use crate::btc;
+use crate::cli::{Config, SighashType};
+use crate::console::say;
+use crate::decode::{DecodedScript, DecodedScriptSig, DecodedTx};
+use crate::error::{CapstoneError, Result};
+use crate::fees::{self, FeeRateChoice};
+use crate::files;
+use crate::inputs::PresetInputs;
+use crate::payment;
+use crate::signing;
+use bitcoincore_rpc::bitcoin::{Address, Amount, Txid};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+// What walletcreatefundedpsbt returns.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FundedPsbt {
+    pub psbt: String,
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub fee: Amount,
+    // None when the wallet added no change
+    #[serde(deserialize_with = "crate::fund::change_position")]
+    pub changepos: Option<u32>,
+}
+
+// The parts of decodepsbt we use. Most per-input fields only appear once the
+// PSBT has reached the matching stage, hence all the Options.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DecodedPsbt {
+    pub tx: DecodedTx,
+    pub inputs: Vec<PsbtInput>,
+    #[serde(default)]
+    pub outputs: Vec<PsbtOutput>,
+    // Only present when every input has its UTXO
+    #[serde(default, with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc::opt")]
+    pub fee: Option<Amount>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PsbtInput {
+    pub witness_utxo: Option<PsbtUtxo>,
+    pub non_witness_utxo: Option<DecodedTx>,
+    // pubkey -> signature
+    pub partial_signatures: Option<HashMap<String, String>>,
+    #[serde(rename = "final_scriptSig")]
+    pub final_script_sig: Option<DecodedScriptSig>,
+    pub final_scriptwitness: Option<Vec<String>>,
+    #[serde(default)]
+    pub bip32_derivs: Vec<Bip32Derivation>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PsbtOutput {
+    #[serde(default)]
+    pub bip32_derivs: Vec<Bip32Derivation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Bip32Derivation {
+    pub pubkey: String,
+    pub master_fingerprint: String,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PsbtUtxo {
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub amount: Amount,
+    #[serde(rename = "scriptPubKey")]
+    pub script_pub_key: DecodedScript,
+}
+
+impl DecodedPsbt {
+    // Amount and address of the coin spent by input `i`, from whichever UTXO
+    // record the PSBT carries.
+    pub fn spent_output(&self, i: usize) -> Option<(Amount, Option<String>)> {
+        let input = self.inputs.get(i)?;
+        if let Some(utxo) = &input.witness_utxo {
+            return Some((utxo.amount, utxo.script_pub_key.address.clone()));
+        }
+        let vout = self.tx.vin.get(i)?.vout?;
+        let output = input.non_witness_utxo.as_ref()?.vout.get(vout as usize)?;
+        Some((output.value, output.script_pub_key.address.clone()))
+    }
+}
+
+// Sidecar written next to an exported PSBT so it can be reviewed without a node.
+// Amounts are BTC strings, exact where JSON numbers would go through floats.
+#[derive(Debug, Serialize)]
+struct PsbtSidecar {
+    unsigned_txid: Txid,
+    fee: Option<String>,
+    inputs: Vec<SidecarInput>,
+    outputs: Vec<SidecarOutput>,
+}
+
+#[derive(Debug, Serialize)]
+struct SidecarInput {
+    txid: Option<Txid>,
+    vout: Option<u32>,
+    amount: Option<String>,
+    address: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SidecarOutput {
+    n: u32,
+    amount: String,
+    address: Option<String>,
+}
+
+// Fund a PSBT paying `amount` to `address` from the wallet behind `rpc`. The
+// options are the same as for `send`, except that conf_target/estimate_mode
+// also go in the options object here.
+pub fn fund_psbt(
+    rpc: &Client,
+    address: &Address,
+    amount: Amount,
+    fee_rate: Option<&FeeRateChoice>,
+    inputs: &PresetInputs,
+    config: &Config,
+) -> Result<FundedPsbt> {
+    let mut options = payment::send_options_with_fee_rate(config, fee_rate);
+    // The locktime is a positional argument here, not an option
+    options.remove("locktime");
+    if let Some(add_inputs) = inputs.add_inputs() {
+        options.insert("add_inputs".into(), json!(add_inputs));
+    }
+    if fee_rate.is_none() {
+        if let Some(conf_target) = config.conf_target {
+            options.insert("conf_target".into(), json!(conf_target));
+        }
+        if let Some(estimate_mode) = config.estimate_mode {
+            options.insert("estimate_mode".into(), json!(estimate_mode));
+        }
+    }
+
+    let args = [
+        inputs.to_json(),                                  // empty: let the wallet pick
+        json!([{ address.to_string(): amount.to_btc() }]), // outputs
+        json!(config.locktime.unwrap_or(0)),               // locktime
+        json!(options),                                    // options object
+        json!(true),                                       // include bip32 derivation paths
+    ];
+    let funded: FundedPsbt = rpc
+        .call("walletcreatefundedpsbt", &args)
+        .map_err(|e| payment::change_position_context(e, config, 1))?;
+    say!("Funded PSBT, fee: {}", btc::amount(funded.fee));
+
+    fees::check_max_fee(funded.fee, config.max_fee())?;
+    Ok(funded)
+}
+
+pub fn decode_psbt(rpc: &Client, psbt: &str) -> Result<DecodedPsbt> {
+    Ok(rpc.call("decodepsbt", &[json!(psbt)])?)
+}
+
+// What analyzepsbt reports about a PSBT and each of its inputs.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AnalyzedPsbt {
+    #[serde(default)]
+    pub inputs: Vec<AnalyzedInput>,
+    // The role that has to act next: creator, updater, signer, finalizer or extractor
+    pub next: String,
+    pub estimated_vsize: Option<u64>,
+    // BTC/kvB
+    #[serde(default, with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc::opt")]
+    pub estimated_feerate: Option<Amount>,
+    #[serde(default, with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc::opt")]
+    pub fee: Option<Amount>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AnalyzedInput {
+    pub has_utxo: bool,
+    pub is_final: bool,
+    pub missing: Option<MissingData>,
+    pub next: Option<String>,
+}
+
+// Keys, signatures and scripts an input still needs, by key id/hash.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MissingData {
+    #[serde(default)]
+    pub pubkeys: Vec<String>,
+    #[serde(default)]
+    pub signatures: Vec<String>,
+    pub redeemscript: Option<String>,
+    pub witnessscript: Option<String>,
+}
+
+impl AnalyzedPsbt {
+    // Every signature already in place: finalizepsbt can take it from here
+    pub fn is_finalizable(&self) -> bool {
+        self.error.is_none() && (self.next == "finalizer" || self.next == "extractor")
+    }
+
+    // An error listing what's missing unless the PSBT is ready for finalizepsbt.
+    pub fn ensure_finalizable(&self) -> Result<()> {
+        if self.is_finalizable() {
+            return Ok(());
+        }
+        let mut problems = vec![format!("next role: {}", self.next)];
+        problems.extend(self.error.clone());
+        problems.extend(self.describe_missing());
+        Err(CapstoneError::PsbtNotFinalizable(problems))
+    }
+
+    // One line per input that is not final yet, saying what it lacks.
+    pub fn describe_missing(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (i, input) in self.inputs.iter().enumerate().filter(|(_, i)| !i.is_final) {
+            let mut missing = Vec::new();
+            if !input.has_utxo {
+                missing.push("UTXO information".to_string());
+            }
+            if let Some(data) = &input.missing {
+                if !data.signatures.is_empty() {
+                    missing.push(format!("signatures for {}", data.signatures.join(", ")));
+                }
+                if !data.pubkeys.is_empty() {
+                    missing.push(format!("public keys {}", data.pubkeys.join(", ")));
+                }
+                if let Some(script) = &data.redeemscript {
+                    missing.push(format!("redeem script {}", script));
+                }
+                if let Some(script) = &data.witnessscript {
+                    missing.push(format!("witness script {}", script));
+                }
+            }
+            lines.push(format!(
+                "input #{}: next {}, missing {}",
+                i,
+                input.next.as_deref().unwrap_or("?"),
+                if missing.is_empty() {
+                    "nothing reported".to_string()
+                } else {
+                    missing.join("; ")
+                }
+            ));
+        }
+        lines
+    }
+}
+
+pub fn analyze_psbt(rpc: &Client, psbt: &str) -> Result<AnalyzedPsbt> {
+    Ok(rpc.call("analyzepsbt", &[json!(psbt)])?)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FinalizedPsbt {
+    pub psbt: Option<String>,
+    pub hex: Option<String>,
+    pub complete: bool,
+}
+
+// finalizepsbt with extraction, so a complete result carries the network hex.
+pub fn finalize_psbt(rpc: &Client, psbt: &str) -> Result<FinalizedPsbt> {
+    Ok(rpc.call("finalizepsbt", &[json!(psbt), json!(true)])?)
+}
+
+// Merge copies of the same PSBT carrying different signatures. Each PSBT is
+// paired with the file it came from, so a copy of some other transaction can be
+// named before the node gives a generic error.
+pub fn combine_psbts(rpc: &Client, psbts: &[(String, String)]) -> Result<String> {
+    let mut expected = None;
+    for (path, psbt) in psbts {
+        let txid = bitcoin::Psbt::from_str(psbt)
+            .map_err(|e| CapstoneError::PsbtFile {
+                path: path.clone(),
+                reason: e.to_string(),
+            })?
+            .unsigned_tx
+            .compute_txid();
+        match &expected {
+            None => expected = Some((path, txid)),
+            Some((first, first_txid)) if *first_txid != txid => {
+                return Err(CapstoneError::PsbtFile {
+                    path: path.clone(),
+                    reason: format!(
+                        "spends unsigned transaction {}, but {} spends {}",
+                        txid, first, first_txid
+                    ),
+                })
+            }
+            Some(_) => {}
+        }
+    }
+
+    let psbts: Vec<&str> = psbts.iter().map(|(_, psbt)| psbt.as_str()).collect();
+    Ok(rpc.call("combinepsbt", &[json!(psbts)])?)
+}
+
+// Turn an unsigned network-format transaction into a PSBT. With `permit_sig_data`
+// any existing signatures are dropped instead of making the RPC fail.
+pub fn convert_to_psbt(rpc: &Client, hex: &str, permit_sig_data: bool) -> Result<String> {
+    Ok(rpc.call("converttopsbt", &[json!(hex), json!(permit_sig_data)])?)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProcessedPsbt {
+    pub psbt: String,
+    pub complete: bool,
+}
+
+// Sign what the wallet can, adding derivation paths as it goes. The sighash type
+// is checked against the taproot inputs first, see `signing::check_sighash`.
+pub fn process_psbt(rpc: &Client, psbt: &str, sighash: SighashType) -> Result<ProcessedPsbt> {
+    let decoded = decode_psbt(rpc, psbt)?;
+    let taproot: Vec<bool> = decoded
+        .inputs
+        .iter()
+        .map(|input| {
+            input
+                .witness_utxo
+                .as_ref()
+                .is_some_and(|utxo| utxo.script_pub_key.script_type == "witness_v1_taproot")
+        })
+        .collect();
+    signing::check_sighash(sighash, &taproot, decoded.tx.vout.len())?;
+
+    Ok(rpc.call(
+        "walletprocesspsbt",
+        &[
+            json!(psbt),
+            json!(true),
+            json!(sighash.as_rpc_str()),
+            json!(true),
+        ],
+    )?)
+}
+
+#[derive(Debug, Deserialize)]
+struct ListDescriptorsResult {
+    descriptors: Vec<WalletDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WalletDescriptor {
+    desc: String,
+    range: Option<(u64, u64)>,
+}
+
+// Fill in UTXO and bip32 derivation data from the public descriptors of the
+// wallet behind `wallet_rpc`, so it can sign a PSBT that was created elsewhere.
+pub fn utxo_update(rpc: &Client, wallet_rpc: &Client, psbt: &str) -> Result<String> {
+    let listed: ListDescriptorsResult = wallet_rpc.call("listdescriptors", &[])?;
+    let descriptors: Vec<_> = listed
+        .descriptors
+        .iter()
+        .map(|d| match d.range {
+            Some((start, end)) => json!({ "desc": d.desc, "range": [start, end] }),
+            None => json!(d.desc),
+        })
+        .collect();
+    Ok(rpc.call("utxoupdatepsbt", &[json!(psbt), json!(descriptors)])?)
+}
+
+// What utxoupdatepsbt added, one line per input/output that changed.
+pub fn describe_enrichment(before: &DecodedPsbt, after: &DecodedPsbt) -> Vec<String> {
+    let mut changes = Vec::new();
+    for (i, (old, new)) in before.inputs.iter().zip(&after.inputs).enumerate() {
+        let mut added = Vec::new();
+        if old.witness_utxo.is_none() && new.witness_utxo.is_some() {
+            added.push("witness UTXO".to_string());
+        }
+        if old.non_witness_utxo.is_none() && new.non_witness_utxo.is_some() {
+            added.push("non-witness UTXO".to_string());
+        }
+        let derivations = new
+            .bip32_derivs
+            .len()
+            .saturating_sub(old.bip32_derivs.len());
+        if derivations > 0 {
+            added.push(format!("{} bip32 derivation(s)", derivations));
+        }
+        if !added.is_empty() {
+            changes.push(format!("input #{}: added {}", i, added.join(", ")));
+        }
+    }
+    for (i, (old, new)) in before.outputs.iter().zip(&after.outputs).enumerate() {
+        let derivations = new
+            .bip32_derivs
+            .len()
+            .saturating_sub(old.bip32_derivs.len());
+        if derivations > 0 {
+            changes.push(format!(
+                "output #{}: added {} bip32 derivation(s)",
+                i, derivations
+            ));
+        }
+    }
+    changes
+}
+
+// enrich-psbt: add what the Miner wallet knows about a foreign PSBT, sign it, and
+// write the result to --psbt-out (or print it).
+pub fn run_enrich(rpc: &Client, wallet_rpc: &Client, config: &Config, path: &str) -> Result<()> {
+    let psbt = read_psbt_file(path)?;
+    let before = decode_psbt(rpc, &psbt)?;
+    let enriched = utxo_update(rpc, wallet_rpc, &psbt)?;
+    let after = decode_psbt(rpc, &enriched)?;
+
+    let changes = describe_enrichment(&before, &after);
+    if changes.is_empty() {
+        say!("utxoupdatepsbt added nothing, the PSBT already had all the wallet knows.");
+    } else {
+        say!("utxoupdatepsbt added:");
+        for change in &changes {
+            say!("  {}", change);
+        }
+    }
+
+    let processed = process_psbt(wallet_rpc, &enriched, config.sighash())?;
+    say!(
+        "walletprocesspsbt: {}",
+        if processed.complete {
+            "complete, ready to finalize"
+        } else {
+            "signed what the wallet could, more signatures needed"
+        }
+    );
+    match &config.psbt_out {
+        Some(out) => {
+            files::write_atomically(out, processed.psbt.as_bytes(), "PSBT file")?;
+            say!("Wrote PSBT to {}", out);
+        }
+        None => say!("{}", processed.psbt),
+    }
+    Ok(())
+}
+
+// Human readable status of a PSBT: what each input has, who acts next, and the
+// size and fee once they can be worked out.
+pub fn render_status(analysis: &AnalyzedPsbt, decoded: &DecodedPsbt) -> String {
+    let yes_no = |b: bool| if b { "yes" } else { "no" };
+    let mut out = String::new();
+    out.push_str(&format!("unsigned txid: {}\n", decoded.tx.txid));
+    out.push_str(&format!("next role:     {}\n", analysis.next));
+    if let Some(error) = &analysis.error {
+        out.push_str(&format!("error:         {}\n", error));
+    }
+    match analysis.estimated_vsize {
+        Some(vsize) => out.push_str(&format!("est. vsize:    {} vB\n", vsize)),
+        None => out.push_str("est. vsize:    unknown\n"),
+    }
+    match analysis.fee.or(decoded.fee) {
+        Some(fee) => out.push_str(&format!("fee:           {}\n", btc::amount(fee))),
+        None => out.push_str("fee:           unknown (missing UTXO information)\n"),
+    }
+    if let Some(rate) = analysis.estimated_feerate {
+        out.push_str(&format!(
+            "est. feerate:  {} BTC/kvB\n",
+            btc::format_btc(rate)
+        ));
+    }
+
+    out.push_str(&format!("inputs ({}):\n", decoded.inputs.len()));
+    for (i, input) in decoded.inputs.iter().enumerate() {
+        let outpoint = match decoded.tx.vin.get(i).map(|vin| (vin.txid, vin.vout)) {
+            Some((Some(txid), Some(vout))) => format!("{}:{}", txid, vout),
+            _ => "?".to_string(),
+        };
+        let analyzed = analysis.inputs.get(i);
+        let signatures = input.partial_signatures.as_ref().map_or(0, |s| s.len());
+        out.push_str(&format!(
+            "  #{} {} utxo: {}, partial signatures: {}, final witness: {}, next: {}\n",
+            i,
+            outpoint,
+            yes_no(input.witness_utxo.is_some() || input.non_witness_utxo.is_some()),
+            signatures,
+            yes_no(input.final_scriptwitness.is_some() || input.final_script_sig.is_some()),
+            analyzed.and_then(|a| a.next.as_deref()).unwrap_or(
+                if analyzed.is_some_and(|a| a.is_final) {
+                    "done"
+                } else {
+                    "?"
+                }
+            )
+        ));
+    }
+    for line in analysis.describe_missing() {
+        out.push_str(&format!("  {}\n", line));
+    }
+    out
+}
+
+// A PSBT given on the command line: a file in either encoding, or base64 text.
+pub fn load_psbt(source: &str) -> Result<String> {
+    if Path::new(source).is_file() {
+        return read_psbt_file(source);
+    }
+    bitcoin::Psbt::from_str(source.trim())
+        .map(|psbt| psbt.to_string())
+        .map_err(|e| CapstoneError::PsbtFile {
+            path: "<command line>".to_string(),
+            reason: format!("not a file and not base64 PSBT: {}", e),
+        })
+}
+
+// inspect-psbt: print the status of a PSBT without changing anything.
+pub fn run_inspect(rpc: &Client, source: &str) -> Result<()> {
+    let psbt = load_psbt(source)?;
+    let analysis = analyze_psbt(rpc, &psbt)?;
+    let decoded = decode_psbt(rpc, &psbt)?;
+    say!("{}", render_status(&analysis, &decoded).trim_end());
+    Ok(())
+}
+
+// The transaction a PSBT spends in, without any signatures, as hex.
+pub fn unsigned_tx_hex(psbt: &str) -> Result<String> {
+    let psbt = bitcoin::Psbt::from_str(psbt)
+        .map_err(|e| CapstoneError::Usage(format!("node returned an unreadable PSBT: {}", e)))?;
+    Ok(bitcoin::consensus::encode::serialize_hex(&psbt.unsigned_tx))
+}
+
+// Finalize and extract, failing unless every input could be completed.
+pub fn finalize_to_hex(rpc: &Client, psbt: &str) -> Result<String> {
+    let finalized = finalize_psbt(rpc, psbt)?;
+    match finalized.hex {
+        Some(hex) if finalized.complete => Ok(hex),
+        _ => Err(CapstoneError::PsbtNotFinalizable(vec![
+            "finalizepsbt could not complete every input".to_string(),
+        ])),
+    }
+}
+
+// Read a PSBT file in either encoding and return it as base64, which is what
+// the RPCs take. Binary files are recognised by the "psbt\xff" magic.
+pub fn read_psbt_file(path: &str) -> Result<String> {
+    let bad_file = |reason: String| CapstoneError::PsbtFile {
+        path: path.to_string(),
+        reason,
+    };
+    let contents = fs::read(path).map_err(|e| bad_file(e.to_string()))?;
+
+    let psbt = if contents.starts_with(b"psbt\xff") {
+        bitcoin::Psbt::deserialize(&contents).map_err(|e| bad_file(e.to_string()))?
+    } else {
+        let text = std::str::from_utf8(&contents)
+            .map_err(|_| bad_file("neither a binary PSBT nor base64 text".into()))?;
+        bitcoin::Psbt::from_str(text.trim()).map_err(|e| bad_file(e.to_string()))?
+    };
+    Ok(psbt.to_string())
+}
+
+// Write a funded PSBT for signing elsewhere: base64 at `path`, the binary form
+// next to it with a .psbt extension, and a JSON description with a .json one.
+pub fn export(rpc: &Client, funded: &FundedPsbt, path: &str) -> Result<()> {
+    let path = Path::new(path);
+    let binary_path = path.with_extension("psbt");
+    let sidecar_path = path.with_extension("json");
+    if binary_path == path || sidecar_path == path {
+        return Err(CapstoneError::Usage(format!(
+            "--export-psbt {} would clash with the .psbt/.json files written next to it, use another extension (e.g. .b64)",
+            path.display()
+        )));
+    }
+
+    // The rpc crate's bitcoin version is built without base64 support, so
+    // decode with the newer one; the bytes are the same either way.
+    let binary = bitcoin::Psbt::from_str(&funded.psbt)
+        .map_err(|e| CapstoneError::Usage(format!("node returned an unreadable PSBT: {}", e)))?
+        .serialize();
+
+    let decoded = decode_psbt(rpc, &funded.psbt)?;
+    let sidecar = PsbtSidecar {
+        unsigned_txid: decoded.tx.txid,
+        fee: decoded.fee.map(btc::format_btc),
+        inputs: decoded
+            .tx
+            .vin
+            .iter()
+            .enumerate()
+            .map(|(i, vin)| {
+                let spent = decoded.spent_output(i);
+                SidecarInput {
+                    txid: vin.txid,
+                    vout: vin.vout,
+                    amount: spent.as_ref().map(|(amount, _)| btc::format_btc(*amount)),
+                    address: spent.and_then(|(_, address)| address),
+                }
+            })
+            .collect(),
+        outputs: decoded
+            .tx
+            .vout
+            .iter()
+            .map(|vout| SidecarOutput {
+                n: vout.n,
+                amount: btc::format_btc(vout.value),
+                address: vout.script_pub_key.address.clone(),
+            })
+            .collect(),
+    };
+
+    files::write_atomically(path, funded.psbt.as_bytes(), "PSBT file")?;
+    files::write_atomically(&binary_path, &binary, "binary PSBT file")?;
+    files::write_atomically(
+        &sidecar_path,
+        serde_json::to_string_pretty(&sidecar)?.as_bytes(),
+        "PSBT description",
+    )?;
+    say!(
+        "Wrote PSBT to {} (binary: {}, details: {})",
+        path.display(),
+        binary_path.display(),
+        sidecar_path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // decodepsbt and analyzepsbt for a 2-of-2 P2WSH spend, stored together
+    fn fixture(json: &str) -> (DecodedPsbt, AnalyzedPsbt) {
+        let value: serde_json::Value = serde_json::from_str(json).unwrap();
+        (
+            serde_json::from_value(value["decodepsbt"].clone()).unwrap(),
+            serde_json::from_value(value["analyzepsbt"].clone()).unwrap(),
+        )
+    }
+
+    fn half_signed() -> (DecodedPsbt, AnalyzedPsbt) {
+        fixture(include_str!("../tests/fixtures/psbt-half-signed.json"))
+    }
+
+    fn signed() -> (DecodedPsbt, AnalyzedPsbt) {
+        fixture(include_str!("../tests/fixtures/psbt-signed.json"))
+    }
+
+    #[test]
+    fn half_signed_psbt_deserializes() {
+        let (decoded, analysis) = half_signed();
+        assert_eq!(decoded.fee, Some(Amount::from_sat(10_000)));
+        let input = &decoded.inputs[0];
+        assert_eq!(input.partial_signatures.as_ref().map(|s| s.len()), Some(1));
+        assert!(input.final_scriptwitness.is_none());
+        assert_eq!(input.bip32_derivs.len(), 2);
+        assert_eq!(
+            decoded.spent_output(0),
+            Some((
+                Amount::ONE_BTC,
+                Some("bcrt1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qzf4jry".into())
+            ))
+        );
+        assert_eq!(analysis.estimated_feerate, Some(Amount::from_sat(71_428)));
+        assert!(!analysis.is_finalizable());
+    }
+
+    #[test]
+    fn half_signed_psbt_reports_the_missing_signature() {
+        let (_, analysis) = half_signed();
+        assert_eq!(
+            analysis.describe_missing(),
+            vec![
+                "input #0: next signer, missing signatures for 06afd46bcdfd22ef94ac122aa11f241244a37ecc"
+            ]
+        );
+        let Err(CapstoneError::PsbtNotFinalizable(problems)) = analysis.ensure_finalizable() else {
+            panic!("one signature is still missing");
+        };
+        assert_eq!(problems[0], "next role: signer");
+        assert_eq!(problems.len(), 2);
+    }
+
+    #[test]
+    fn half_signed_status() {
+        let (decoded, analysis) = half_signed();
+        assert_eq!(
+            render_status(&analysis, &decoded),
+            "\
+unsigned txid: 5a2b1f2e4d5c8a9b7e6f3d2c1b0a99887766554433221100ffeeddccbbaa9988
+next role:     signer
+est. vsize:    140 vB
+fee:           0.00010000 BTC
+est. feerate:  0.00071428 BTC/kvB
+inputs (1):
+  #0 e2a4b6c8d0f1e3a5b7c9d1e3f5a7b9c1d3e5f7a9b1c3d5e7f9a1b3c5d7e9f1a3:1 utxo: yes, partial signatures: 1, final witness: no, next: signer
+  input #0: next signer, missing signatures for 06afd46bcdfd22ef94ac122aa11f241244a37ecc
+"
+        );
+    }
+
+    #[test]
+    fn signed_psbt_is_ready_to_extract() {
+        let (decoded, analysis) = signed();
+        assert_eq!(
+            decoded.inputs[0].final_scriptwitness.as_ref().map(Vec::len),
+            Some(4)
+        );
+        assert!(analysis.is_finalizable());
+        assert!(analysis.ensure_finalizable().is_ok());
+        assert!(analysis.describe_missing().is_empty());
+    }
+
+    #[test]
+    fn signed_status() {
+        let (decoded, analysis) = signed();
+        assert_eq!(
+            render_status(&analysis, &decoded),
+            "\
+unsigned txid: 5a2b1f2e4d5c8a9b7e6f3d2c1b0a99887766554433221100ffeeddccbbaa9988
+next role:     extractor
+est. vsize:    140 vB
+fee:           0.00010000 BTC
+est. feerate:  0.00071428 BTC/kvB
+inputs (1):
+  #0 e2a4b6c8d0f1e3a5b7c9d1e3f5a7b9c1d3e5f7a9b1c3d5e7f9a1b3c5d7e9f1a3:1 utxo: yes, partial signatures: 0, final witness: yes, next: extractor
+"
+        );
+    }
+
+    #[test]
+    fn status_without_utxo_information() {
+        let (mut decoded, mut analysis) = half_signed();
+        decoded.inputs[0].witness_utxo = None;
+        decoded.fee = None;
+        analysis.fee = None;
+        analysis.estimated_vsize = None;
+        analysis.estimated_feerate = None;
+        analysis.next = "updater".into();
+        analysis.inputs[0].has_utxo = false;
+        analysis.inputs[0].next = Some("updater".into());
+        analysis.inputs[0].missing = None;
+        let status = render_status(&analysis, &decoded);
+        assert!(status.contains("est. vsize:    unknown\n"));
+        assert!(status.contains("fee:           unknown (missing UTXO information)\n"));
+        assert!(!status.contains("est. feerate"));
+        assert!(status.contains("utxo: no"));
+        assert!(status.ends_with("  input #0: next updater, missing UTXO information\n"));
+    }
+
+    // The unsigned half of the fixture's spend as a PSBT the wallet could have
+    // funded, and the node's decodepsbt of it
+    fn funded() -> (FundedPsbt, serde_json::Value) {
+        use bitcoin::{absolute, transaction, OutPoint, Sequence, TxIn, TxOut, Witness};
+
+        let (decoded, _) = half_signed();
+        let spent = &decoded.inputs[0]
+            .witness_utxo
+            .as_ref()
+            .unwrap()
+            .script_pub_key;
+        let paid = &decoded.tx.vout[0];
+        let tx = bitcoin::Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(
+                    decoded.tx.vin[0].txid.unwrap().to_string().parse().unwrap(),
+                    1,
+                ),
+                script_sig: bitcoin::ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: bitcoin::Amount::from_sat(paid.value.to_sat()),
+                script_pubkey: bitcoin::ScriptBuf::from_hex(&paid.script_pub_key.hex).unwrap(),
+            }],
+        };
+        let mut psbt = bitcoin::Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: bitcoin::Amount::ONE_BTC,
+            script_pubkey: bitcoin::ScriptBuf::from_hex(&spent.hex).unwrap(),
+        });
+
+        let mut decodepsbt: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/fixtures/psbt-half-signed.json")).unwrap();
+        let mut decodepsbt = decodepsbt["decodepsbt"].take();
+        let txid = psbt.unsigned_tx.compute_txid().to_string();
+        decodepsbt["tx"]["txid"] = json!(txid);
+        decodepsbt["tx"]["hash"] = json!(txid);
+        let funded = FundedPsbt {
+            psbt: psbt.to_string(),
+            fee: decoded.fee.unwrap(),
+            changepos: None,
+        };
+        (funded, decodepsbt)
+    }
+
+    #[test]
+    fn export_writes_the_funded_psbt_three_ways() {
+        let (funded, decodepsbt) = funded();
+        let expected = funded.psbt.clone();
+        let (rpc, calls) = crate::mockrpc::client(move |method, params| {
+            assert_eq!(method, "decodepsbt");
+            assert_eq!(params[0], json!(expected));
+            Ok(decodepsbt.clone())
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spend.b64");
+        crate::console::capture(|| export(&rpc, &funded, path.to_str().unwrap()).unwrap());
+        assert_eq!(*calls.lock().unwrap(), ["decodepsbt"]);
+
+        let base64 = fs::read_to_string(&path).unwrap();
+        assert_eq!(base64, funded.psbt);
+        let binary = fs::read(dir.path().join("spend.psbt")).unwrap();
+        let from_binary = bitcoin::Psbt::deserialize(&binary).unwrap();
+        assert_eq!(from_binary, bitcoin::Psbt::from_str(&base64).unwrap());
+        // Either file reads back as the PSBT the wallet funded
+        for file in ["spend.b64", "spend.psbt"] {
+            let path = dir.path().join(file);
+            assert_eq!(read_psbt_file(path.to_str().unwrap()).unwrap(), funded.psbt);
+        }
+
+        let sidecar: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.path().join("spend.json")).unwrap())
+                .unwrap();
+        assert_eq!(
+            sidecar["unsigned_txid"],
+            json!(from_binary.unsigned_tx.compute_txid().to_string())
+        );
+        assert_eq!(sidecar["fee"], json!("0.00010000"));
+        assert_eq!(sidecar["inputs"][0]["vout"], json!(1));
+        assert_eq!(sidecar["inputs"][0]["amount"], json!("1.00000000"));
+        assert_eq!(
+            sidecar["inputs"][0]["address"],
+            json!("bcrt1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3qzf4jry")
+        );
+        assert_eq!(sidecar["outputs"][0]["amount"], json!("0.99990000"));
+        assert_eq!(
+            sidecar["outputs"][0]["address"],
+            json!("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080")
+        );
+    }
+
+    #[test]
+    fn export_refuses_a_path_it_would_overwrite() {
+        let (funded, _) = funded();
+        let (rpc, calls) = crate::mockrpc::client(|method, _| panic!("unexpected {}", method));
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["spend.psbt", "spend.json"] {
+            let path = dir.path().join(name);
+            let err = export(&rpc, &funded, path.to_str().unwrap()).unwrap_err();
+            assert!(err.to_string().contains("use another extension"), "{}", err);
+            assert!(!path.exists());
+        }
+        assert!(calls.lock().unwrap().is_empty());
+    }
+}
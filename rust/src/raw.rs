@@ -0,0 +1,367 @@
+use crate::accept;
+use crate::btc;
+use crate::cli::Config;
+use crate::cli::SighashType;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::fees::{self, FeeRateChoice};
+use crate::fund::{self, FundOptions};
+use crate::inputs::{self, PresetInputs};
+use crate::limits;
+use crate::payment;
+use crate::signing;
+use crate::txidlog;
+use bitcoincore_rpc::bitcoin::consensus::encode;
+use bitcoincore_rpc::bitcoin::hex::DisplayHex;
+use bitcoincore_rpc::bitcoin::{
+    Address, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, Txid, Witness,
+};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde_json::json;
+use std::collections::HashMap;
+
+// Build the payment step by step instead of through `send`: create an unfunded
+// transaction, let the wallet fund it, check the fee, sign and broadcast.
+// Having each stage separate lets us inspect the transaction before it is signed.
+pub fn send_raw(
+    rpc: &Client,
+    address: &Address,
+    amount: Amount,
+    change_address: Option<&Address>,
+    fee_rate: Option<&FeeRateChoice>,
+    inputs: &PresetInputs,
+    config: &Config,
+) -> Result<Txid> {
+    let signed_hex = build_signed(
+        rpc,
+        address,
+        amount,
+        change_address,
+        fee_rate,
+        inputs,
+        config,
+    )?;
+
+    // 5. Make sure the node would accept it, so rejections come with a reason
+    accept::ensure_accepted(
+        rpc,
+        std::slice::from_ref(&signed_hex),
+        config.max_broadcast_feerate(),
+    )?;
+
+    // 6. Broadcast
+    broadcast(rpc, &signed_hex, config)
+}
+
+// sendrawtransaction with --max-broadcast-feerate. Core only says the fee is too
+// high, so on that rejection work out the actual feerate to put in the error.
+pub fn broadcast(rpc: &Client, hex: &str, config: &Config) -> Result<Txid> {
+    limits::check_broadcast(rpc, hex)?;
+    let max_fee_rate = config.max_broadcast_feerate();
+    match rpc.call::<Txid>("sendrawtransaction", &broadcast_args(hex, max_fee_rate)) {
+        Ok(txid) => {
+            txidlog::record(&txid, "broadcast");
+            Ok(txid)
+        }
+        Err(e) if is_fee_cap_rejection(&e.to_string()) => {
+            let (txid, fee_rate) = fees::transaction_fee_rate(rpc, hex)?;
+            Err(CapstoneError::BroadcastFeeRateTooHigh {
+                txid,
+                fee_rate,
+                max_fee_rate,
+            })
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// sendrawtransaction's arguments; a zero cap turns the check off
+fn broadcast_args(hex: &str, max_fee_rate: Amount) -> [serde_json::Value; 2] {
+    [json!(hex), json!(max_fee_rate.to_btc())]
+}
+
+// Whether sendrawtransaction's error is the maxfeerate check. Core words it
+// "Fee exceeds maximum configured by user (e.g. -maxtxfee, maxfeerate)", and
+// "max-fee-exceeded" where it passes the reject reason through.
+fn is_fee_cap_rejection(message: &str) -> bool {
+    message.contains("maxfeerate") || message.contains("max-fee")
+}
+
+// Steps 1-4 of the raw pipeline: everything up to, but not including, broadcast.
+// `inputs` are spent in any case, see inputs::PresetInputs.
+pub fn build_signed(
+    rpc: &Client,
+    address: &Address,
+    amount: Amount,
+    change_address: Option<&Address>,
+    fee_rate: Option<&FeeRateChoice>,
+    inputs: &PresetInputs,
+    config: &Config,
+) -> Result<String> {
+    let funded = fund(
+        rpc,
+        address,
+        amount,
+        change_address,
+        fee_rate,
+        inputs,
+        config,
+    )?;
+
+    // 3. Refuse to sign anything that pays more than the configured cap
+    fees::check_max_fee(funded.fee, config.max_fee())?;
+
+    // The wallet picks sequences for the inputs it added; override them if asked
+    let mut funded_tx = signing::decode_tx(&funded.hex)?;
+    inputs::apply_default_sequence(&mut funded_tx, inputs, config);
+    inputs::warn_if_locktime_ignored(&funded_tx);
+
+    // 4. Sign with the Miner wallet's keys, or with the key given by --sign-with-key
+    let hex = encode::serialize_hex(&funded_tx);
+    let sighash = config.sighash();
+    signing::check_sighash_for_tx(rpc, &hex, sighash)?;
+    let signed = match &config.sign_with_key {
+        Some(source) => {
+            let key = signing::SigningKey::load(source)?;
+            signing::sign_with_key(rpc, &hex, &key, sighash)?
+        }
+        None => signing::sign_with_wallet(rpc, &hex, sighash)?,
+    };
+    let signed_hex = signed.into_signed_hex()?;
+
+    if sighash.anyone_can_pay() {
+        demonstrate_anyone_can_pay(rpc, &signed_hex, sighash)?;
+    }
+    Ok(signed_hex)
+}
+
+// Steps 1-2 of the raw pipeline: the transaction paying `address`, funded by
+// the wallet, with the change at --change-position if one was asked for.
+fn fund(
+    rpc: &Client,
+    address: &Address,
+    amount: Amount,
+    change_address: Option<&Address>,
+    fee_rate: Option<&FeeRateChoice>,
+    inputs: &PresetInputs,
+    config: &Config,
+) -> Result<fund::FundResult> {
+    // 1. Create a transaction with only the Trader output and any preset inputs
+    let mut outputs = HashMap::new();
+    outputs.insert(address.to_string(), amount);
+    let unfunded = rpc.create_raw_transaction_hex(
+        &inputs.raw_inputs(),
+        &outputs,
+        config.locktime.map(i64::from),
+        None,
+    )?;
+
+    // 2. Let the wallet pick inputs and add change. An explicit feerate can't be
+    // combined with conf_target/estimate_mode.
+    let mut options = FundOptions::new()
+        .change_address(change_address)
+        .change_type(config.change_type)
+        .replaceable(config.bump_until_confirmed)
+        .change_position(config.change_position)
+        .add_inputs(inputs.add_inputs())
+        .estimate(config.conf_target, config.estimate_mode);
+    if let Some(fee_rate) = fee_rate {
+        options = options.fee_rate(fee_rate);
+    }
+    let funded = fund::fund_raw_transaction(rpc, &unfunded, &options).map_err(|e| match e {
+        CapstoneError::Rpc(e) => payment::change_position_context(e, config, 1),
+        e => e,
+    })?;
+    say!(
+        "Funded raw transaction, fee: {}, {}",
+        btc::amount(funded.fee),
+        funded
+            .changepos
+            .map_or("no change".to_string(), |vout| format!(
+                "change at vout {}",
+                vout
+            ))
+    );
+    Ok(funded)
+}
+
+// Show what ANYONECANPAY allows: append one more wallet coin to the signed
+// transaction, sign just that, and check with testmempoolaccept that the
+// original signatures still hold. The extended copy is never broadcast; its
+// extra input is all fee, so the feerate cap is lifted for the check.
+fn demonstrate_anyone_can_pay(rpc: &Client, signed_hex: &str, sighash: SighashType) -> Result<()> {
+    let mut tx = signing::decode_tx(signed_hex)?;
+    let original_inputs = tx.input.clone();
+
+    let spent: Vec<OutPoint> = tx.input.iter().map(|input| input.previous_output).collect();
+    let extra = rpc
+        .list_unspent(Some(1), None, None, None, None)?
+        .into_iter()
+        .filter(|utxo| !spent.contains(&OutPoint::new(utxo.txid, utxo.vout)))
+        .min_by_key(|utxo| utxo.amount);
+    let extra = match extra {
+        Some(extra) => extra,
+        None => {
+            say!("ANYONECANPAY demo skipped: no spare confirmed coin in the wallet.");
+            return Ok(());
+        }
+    };
+    tx.input.push(TxIn {
+        previous_output: OutPoint::new(extra.txid, extra.vout),
+        script_sig: ScriptBuf::new(),
+        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+        witness: Witness::new(),
+    });
+
+    let extended =
+        signing::sign_with_wallet(rpc, &encode::serialize_hex(&tx), sighash)?.into_signed_hex()?;
+    let extended_tx = signing::decode_tx(&extended)?;
+    let untouched = extended_tx.input[..original_inputs.len()]
+        .iter()
+        .zip(&original_inputs)
+        .all(|(now, before)| now.witness == before.witness && now.script_sig == before.script_sig);
+    say!(
+        "ANYONECANPAY demo: appended input {}:{} ({}), original signatures {}.",
+        extra.txid,
+        extra.vout,
+        btc::amount(extra.amount),
+        if untouched {
+            "unchanged"
+        } else {
+            "were replaced"
+        }
+    );
+    accept::ensure_accepted(rpc, &[extended], Amount::ZERO)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::parse_args;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use serde_json::Value;
+    use std::sync::{Arc, Mutex};
+
+    fn config(args: &[&str]) -> Config {
+        parse_args(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    #[test]
+    fn the_broadcast_cap_defaults_to_cores() {
+        let config = config(&[]);
+        assert_eq!(
+            broadcast_args("00", config.max_broadcast_feerate()),
+            [json!("00"), json!(0.1)]
+        );
+    }
+
+    #[test]
+    fn a_zero_cap_disables_the_check() {
+        let config = config(&["--max-broadcast-feerate", "0"]);
+        assert_eq!(config.max_broadcast_feerate(), Amount::ZERO);
+        assert_eq!(
+            broadcast_args("00", config.max_broadcast_feerate())[1],
+            json!(0.0)
+        );
+    }
+
+    #[test]
+    fn fee_cap_rejections_are_recognised() {
+        assert!(is_fee_cap_rejection(
+            "JSON-RPC error: RPC error response: RpcError { code: -25, message: \"Fee exceeds maximum configured by user (e.g. -maxtxfee, maxfeerate)\", data: None }"
+        ));
+        assert!(is_fee_cap_rejection("max-fee-exceeded"));
+        assert!(!is_fee_cap_rejection("min relay fee not met"));
+        assert!(!is_fee_cap_rejection(
+            "insufficient fee, rejecting replacement"
+        ));
+    }
+
+    #[test]
+    fn the_rejection_names_both_feerates() {
+        let config = config(&["--max-broadcast-feerate=0.0001"]);
+        let error = CapstoneError::BroadcastFeeRateTooHigh {
+            txid: Txid::all_zeros(),
+            fee_rate: 12_477,
+            max_fee_rate: config.max_broadcast_feerate(),
+        };
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "transaction {} pays 12.477 sat/vB, above the broadcast cap of 10 sat/vB (raise --max-broadcast-feerate, or 0 to disable it, if that fee is intended)",
+                Txid::all_zeros()
+            )
+        );
+    }
+
+    fn trader() -> Address {
+        "bcrt1qak6gpu2p6zjpwrhvd4dvdnp4rt3ysm9rpst3wu"
+            .parse::<Address<_>>()
+            .unwrap()
+            .assume_checked()
+    }
+
+    // A node that funds the Trader payment, adding change at `changepos`
+    // unless fundrawtransaction's options have no say in it
+    fn node(reply: crate::mockrpc::Reply) -> (Client, Arc<Mutex<Vec<Value>>>) {
+        let options = Arc::new(Mutex::new(Vec::new()));
+        let seen = options.clone();
+        let (rpc, _) = crate::mockrpc::client(move |method, params| match method {
+            "createrawtransaction" => {
+                assert_eq!(params[1], json!({ trader().to_string(): 20.0 }));
+                Ok(json!("unfunded"))
+            }
+            "fundrawtransaction" => {
+                assert_eq!(params[0], json!("unfunded"));
+                seen.lock().unwrap().push(params[1].clone());
+                reply.clone()
+            }
+            other => panic!("unexpected {}", other),
+        });
+        (rpc, options)
+    }
+
+    fn fund_trader(rpc: &Client, config: &Config) -> Result<fund::FundResult> {
+        let mut funded = None;
+        crate::console::capture(|| {
+            funded = Some(fund(
+                rpc,
+                &trader(),
+                Amount::from_sat(2_000_000_000),
+                None,
+                None,
+                &PresetInputs::default(),
+                config,
+            ))
+        });
+        funded.unwrap()
+    }
+
+    #[test]
+    fn the_change_position_is_passed_to_fundrawtransaction() {
+        let (rpc, options) = node(Ok(
+            json!({ "hex": "funded", "fee": 0.0000141, "changepos": 0 }),
+        ));
+        let funded = fund_trader(&rpc, &config(&["--change-position", "0"])).unwrap();
+        assert_eq!(funded.changepos, Some(0));
+        assert_eq!(funded.hex, "funded");
+        assert_eq!(options.lock().unwrap()[0]["change_position"], json!(0));
+
+        // Left to the wallet otherwise
+        let (rpc, options) = node(Ok(
+            json!({ "hex": "funded", "fee": 0.0000141, "changepos": -1 }),
+        ));
+        assert_eq!(fund_trader(&rpc, &config(&[])).unwrap().changepos, None);
+        assert!(options.lock().unwrap()[0].get("change_position").is_none());
+    }
+
+    #[test]
+    fn an_out_of_range_change_position_says_where_change_can_go() {
+        let (rpc, _) = node(Err((-8, "changePosition out of bounds")));
+        let err = fund_trader(&rpc, &config(&["--change-position=5"])).unwrap_err();
+        assert!(err.to_string().starts_with(
+            "--change-position 5 is out of range: the transaction has 1 output(s) before change, so the change can go at 0 to 1 ("
+        ), "{}", err);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::session::Session;
+use bitcoincore_rpc::bitcoin::Txid;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+// listtransactions page size
+const PAGE_SIZE: usize = 100;
+
+// One listtransactions entry, with the conflict fields the rpc crate's
+// ListTransactionResult leaves out.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WalletEntry {
+    pub txid: Txid,
+    pub category: String,
+    // Negative: that many blocks deep on the other side of a conflict
+    pub confirmations: i32,
+    #[serde(rename = "walletconflicts", default)]
+    pub wallet_conflicts: Vec<Txid>,
+    // Set on sends only
+    #[serde(default)]
+    pub abandoned: Option<bool>,
+    #[serde(default)]
+    pub replaced_by_txid: Option<Txid>,
+    #[serde(default)]
+    pub replaces_txid: Option<Txid>,
+}
+
+// Every entry of the wallet, a page at a time. A transaction has one entry
+// per output the wallet is involved in.
+pub fn wallet_entries(wallet_rpc: &Client) -> Result<Vec<WalletEntry>> {
+    let mut entries = Vec::new();
+    let mut skip = 0;
+    loop {
+        let page: Vec<WalletEntry> = wallet_rpc.call(
+            "listtransactions",
+            &[json!("*"), json!(PAGE_SIZE), json!(skip), json!(true)],
+        )?;
+        let len = page.len();
+        entries.extend(page);
+        if len < PAGE_SIZE {
+            return Ok(entries);
+        }
+        skip += PAGE_SIZE;
+    }
+}
+
+// A wallet transaction that lost out to another, or is about to.
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub wallet: String,
+    pub txid: Txid,
+    pub confirmations: i32,
+    pub conflicts: Vec<Txid>,
+    // The replacement the wallet recorded, else a conflicting transaction that confirmed
+    pub superseded_by: Option<Txid>,
+    pub abandoned: bool,
+}
+
+impl Conflict {
+    pub fn describe(&self) -> String {
+        let mut line = format!(
+            "{} ({}): {} confirmation(s)",
+            self.txid, self.wallet, self.confirmations
+        );
+        match &self.superseded_by {
+            Some(winner) => line.push_str(&format!(", superseded by {}", winner)),
+            None if !self.conflicts.is_empty() => {
+                let conflicts: Vec<String> =
+                    self.conflicts.iter().map(|txid| txid.to_string()).collect();
+                line.push_str(&format!(", conflicts with {}", conflicts.join(", ")));
+            }
+            None => {}
+        }
+        if self.abandoned {
+            line.push_str(", abandoned");
+        }
+        line
+    }
+}
+
+// The wallet's entries with a conflict: negative confirmations, or
+// unconfirmed with walletconflicts set. A transaction that confirmed despite
+// conflicts is the winner, not a conflict.
+pub fn wallet_conflicts(wallet: &str, wallet_rpc: &Client) -> Result<Vec<Conflict>> {
+    let entries = wallet_entries(wallet_rpc)?;
+    let confirmations: BTreeMap<Txid, i32> = entries
+        .iter()
+        .map(|entry| (entry.txid, entry.confirmations))
+        .collect();
+    let mut conflicts: BTreeMap<Txid, Conflict> = BTreeMap::new();
+    for entry in entries {
+        let conflicted = entry.confirmations < 0
+            || (entry.confirmations == 0 && !entry.wallet_conflicts.is_empty());
+        if !conflicted || conflicts.contains_key(&entry.txid) {
+            continue;
+        }
+        let superseded_by = entry.replaced_by_txid.or_else(|| {
+            entry
+                .wallet_conflicts
+                .iter()
+                .find(|txid| confirmations.get(*txid).is_some_and(|c| *c > 0))
+                .copied()
+        });
+        conflicts.insert(
+            entry.txid,
+            Conflict {
+                wallet: wallet.to_string(),
+                txid: entry.txid,
+                confirmations: entry.confirmations,
+                conflicts: entry.wallet_conflicts,
+                superseded_by,
+                abandoned: entry.abandoned.unwrap_or(false),
+            },
+        );
+    }
+    Ok(conflicts.into_values().collect())
+}
+
+// Conflicts in the Miner and Trader wallets.
+pub fn scan(session: &Session) -> Result<Vec<Conflict>> {
+    let mut conflicts = wallet_conflicts("Miner", &session.miner_rpc)?;
+    conflicts.extend(wallet_conflicts("Trader", &session.trader_rpc)?);
+    Ok(conflicts)
+}
+
+// The "Conflicts detected" section, empty when there are none.
+pub fn describe(conflicts: &[Conflict]) -> Vec<String> {
+    if conflicts.is_empty() {
+        return Vec::new();
+    }
+    let mut lines = vec!["Conflicts detected:".to_string()];
+    lines.extend(
+        conflicts
+            .iter()
+            .map(|conflict| format!("  {}", conflict.describe())),
+    );
+    lines
+}
+
+// Fail unless `txid` shows up as a conflict, superseded by `winner` if given.
+pub fn expect_conflict(conflicts: &[Conflict], txid: &Txid, winner: Option<&Txid>) -> Result<()> {
+    let found = conflicts.iter().find(|conflict| conflict.txid == *txid);
+    match (found, winner) {
+        (Some(conflict), Some(winner)) if conflict.superseded_by.as_ref() != Some(winner) => {
+            Err(CapstoneError::ConflictNotDetected {
+                txid: *txid,
+                reason: format!(
+                    "the wallet has it superseded by {:?}, not {}",
+                    conflict.superseded_by, winner
+                ),
+            })
+        }
+        (Some(_), _) => Ok(()),
+        (None, _) => Err(CapstoneError::ConflictNotDetected {
+            txid: *txid,
+            reason: "no wallet lists it as conflicted".into(),
+        }),
+    }
+}
+
+// After a run: print what the wallets say was replaced or conflicted.
+pub fn report(session: &Session) -> Result<()> {
+    for line in describe(&scan(session)?) {
+        say!("{}", line);
+    }
+    Ok(())
+}
@@ -0,0 +1,401 @@
+use crate::accept;
+use crate::console::verbose;
+use crate::dust;
+use crate::error::{CapstoneError, Result};
+use crate::fees;
+use bitcoincore_rpc::bitcoin::absolute::LockTime;
+use bitcoincore_rpc::bitcoin::consensus::encode::serialize_hex;
+use bitcoincore_rpc::bitcoin::hashes::Hash;
+use bitcoincore_rpc::bitcoin::script::PushBytesBuf;
+use bitcoincore_rpc::bitcoin::transaction::Version;
+use bitcoincore_rpc::bitcoin::{
+    Address, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, WPubkeyHash,
+    Witness,
+};
+use bitcoincore_rpc::{Client, RpcApi};
+
+// Probes stop here; a node allowing more is reported as allowing this much
+const MAX_PROBED_OP_RETURN_DATA: usize = 10_000;
+const MAX_PROBED_DUST_SAT: u64 = 100_000;
+
+// What a testmempoolaccept probe found. Each probe is a binary search of about
+// 15 calls, so it is Skipped when no output being built depends on its answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probed<T> {
+    Skipped,
+    Found(T),
+}
+
+impl<T> Probed<Option<T>> {
+    // What the probe found, None if it found nothing or didn't run
+    pub fn found(self) -> Option<T> {
+        match self {
+            Probed::Found(value) => value,
+            Probed::Skipped => None,
+        }
+    }
+}
+
+// The relay rules of the node we talk to, so modes that build edge-case
+// transactions can fail before the node does, with a clearer message. All
+// feerates in sat/kvB.
+#[derive(Debug, Clone)]
+pub struct NodePolicy {
+    // -minrelaytxfee: the floor for any transaction
+    pub min_relay_fee: u64,
+    // The floor right now: minrelaytxfee, raised while the mempool is full
+    pub mempool_min_fee: u64,
+    // What a replacement must add on top of the fee it replaces, per vB
+    pub incremental_relay_fee: u64,
+    // -mempoolfullrbf; None when the node doesn't report it (before Core 24)
+    pub full_rbf: Option<bool>,
+    // Largest standard OP_RETURN script in bytes, None if -datacarrier=0.
+    // Found with testmempoolaccept probes, getmempoolinfo doesn't say.
+    pub max_op_return: Probed<Option<usize>>,
+    // -dustrelayfee, worked back from the probed P2WPKH dust threshold; None
+    // if even MAX_PROBED_DUST_SAT is dust
+    pub dust_relay_fee: Probed<Option<u64>>,
+}
+
+impl NodePolicy {
+    // Fail early when `sat_per_kvb` can't get into the mempool. `what` names
+    // where the rate came from.
+    pub fn require_fee_rate(&self, sat_per_kvb: u64, what: &str) -> Result<()> {
+        if sat_per_kvb >= self.mempool_min_fee {
+            return Ok(());
+        }
+        Err(CapstoneError::Usage(format!(
+            "node requires ≥{} sat/vB ({}), {} asks for {}",
+            fees::format_sat_per_vb(self.mempool_min_fee),
+            if self.mempool_min_fee > self.min_relay_fee {
+                "mempoolminfee, the mempool is full"
+            } else {
+                "minrelaytxfee"
+            },
+            what,
+            fees::format_sat_per_vb(sat_per_kvb)
+        )))
+    }
+
+    // Whether a spend that doesn't signal BIP125 can still be replaced
+    pub fn replaces_without_signaling(&self) -> bool {
+        self.full_rbf == Some(true)
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!(
+                "Node minrelaytxfee (sat/vB): {}",
+                fees::format_sat_per_vb(self.min_relay_fee)
+            ),
+            format!(
+                "Node mempoolminfee (sat/vB): {}",
+                fees::format_sat_per_vb(self.mempool_min_fee)
+            ),
+            format!(
+                "Node incremental relay fee (sat/vB): {}",
+                fees::format_sat_per_vb(self.incremental_relay_fee)
+            ),
+            format!(
+                "Node full RBF: {}",
+                match self.full_rbf {
+                    Some(true) => "on",
+                    Some(false) => "off",
+                    None => "not reported",
+                }
+            ),
+        ];
+        lines.push(match self.max_op_return {
+            Probed::Found(Some(size)) => format!("Node max OP_RETURN script (bytes): {}", size),
+            Probed::Found(None) => {
+                "Node max OP_RETURN script (bytes): none, datacarrier is off".to_string()
+            }
+            Probed::Skipped => {
+                "Node max OP_RETURN script (bytes): not probed, no OP_RETURN output".to_string()
+            }
+        });
+        match self.dust_relay_fee {
+            Probed::Found(Some(rate)) => lines.push(format!(
+                "Node dust relay fee (sat/vB): {}",
+                fees::format_sat_per_vb(rate)
+            )),
+            Probed::Found(None) => {}
+            Probed::Skipped => lines
+                .push("Node dust relay fee (sat/vB): not probed, no output near dust".to_string()),
+        }
+        lines
+    }
+}
+
+// A transaction spending a coin that doesn't exist, with `output` next to a
+// plain 1 BTC one (which keeps it above the minimum standard size). The node
+// checks standardness before looking up inputs, so "missing-inputs" means the
+// output passed; any other reason is returned.
+fn probe(rpc: &Client, output: TxOut) -> Result<Option<String>> {
+    let padding = TxOut {
+        value: Amount::ONE_BTC,
+        script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+    };
+    let tx = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(Txid::from_byte_array([1; 32]), 0),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![padding, output],
+    };
+    let results = accept::test_mempool_accept(rpc, &[serialize_hex(&tx)], Amount::ZERO)?;
+    Ok(results
+        .first()
+        .filter(|result| !result.is_allowed())
+        .map(|result| result.rejection())
+        .filter(|reason| reason != "missing-inputs"))
+}
+
+// The smallest `n` in `0..=max` for which `passes` holds, if any. `passes`
+// must be monotone: false up to some point and true from there on.
+fn boundary(max: u64, passes: impl Fn(u64) -> Result<bool>) -> Result<Option<u64>> {
+    if !passes(max)? {
+        return Ok(None);
+    }
+    let (mut lo, mut hi) = (0, max);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if passes(mid)? {
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    Ok(Some(lo))
+}
+
+fn op_return(data_len: usize) -> ScriptBuf {
+    let data = PushBytesBuf::try_from(vec![0u8; data_len]).expect("under the push limit");
+    ScriptBuf::new_op_return(data)
+}
+
+fn probe_max_op_return(rpc: &Client) -> Result<Option<usize>> {
+    let fits = |data_len: usize| -> Result<bool> {
+        let output = TxOut {
+            value: Amount::ZERO,
+            script_pubkey: op_return(data_len),
+        };
+        Ok(probe(rpc, output)?.as_deref() != Some("scriptpubkey"))
+    };
+    if !fits(0)? {
+        return Ok(None);
+    }
+    // The first data length that no longer fits, one past the largest that does
+    let too_long = boundary(MAX_PROBED_OP_RETURN_DATA as u64, |n| Ok(!fits(n as usize)?))?;
+    let data_len = match too_long {
+        Some(n) => n as usize - 1,
+        None => MAX_PROBED_OP_RETURN_DATA,
+    };
+    Ok(Some(op_return(data_len).len()))
+}
+
+fn probe_dust_relay_fee(rpc: &Client) -> Result<Option<u64>> {
+    let script = ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros());
+    let threshold = boundary(MAX_PROBED_DUST_SAT, |sat| {
+        let output = TxOut {
+            value: Amount::from_sat(sat),
+            script_pubkey: script.clone(),
+        };
+        Ok(probe(rpc, output)?.as_deref() != Some("dust"))
+    })?;
+    // dust::dust_threshold is the rate times the size of the output and the
+    // input spending it, so dividing by that size gives the rate back
+    Ok(threshold.map(|sat| sat * 1000 / dust::spend_size(&script)))
+}
+
+// The outputs paying `payments`, for `policy`
+pub fn outputs(payments: &[(Address, Amount)]) -> Vec<TxOut> {
+    payments
+        .iter()
+        .map(|(address, amount)| TxOut {
+            value: *amount,
+            script_pubkey: address.script_pubkey(),
+        })
+        .collect()
+}
+
+// Which probes `outputs` need answered: (OP_RETURN limit, dust relay fee).
+// Only an OP_RETURN output can be too big, and only an output the dust probe
+// could find dust, at most MAX_PROBED_DUST_SAT, can be too small.
+fn probes_needed(outputs: &[TxOut]) -> (bool, bool) {
+    let op_return = outputs
+        .iter()
+        .any(|output| output.script_pubkey.is_op_return());
+    let dust = outputs.iter().any(|output| {
+        !output.script_pubkey.is_op_return() && output.value.to_sat() <= MAX_PROBED_DUST_SAT
+    });
+    (op_return, dust)
+}
+
+// Gather the node's relay policy for a transaction with `outputs`:
+// getmempoolinfo and getnetworkinfo for the feerates, testmempoolaccept
+// probes for what neither reports, where an output depends on it. Modes that
+// only need the feerates pass no outputs.
+pub fn policy(rpc: &Client, outputs: &[TxOut]) -> Result<NodePolicy> {
+    let mempool = rpc.get_mempool_info()?;
+    let network = rpc.get_network_info()?;
+    let (op_return, dust) = probes_needed(outputs);
+    let max_op_return = if op_return {
+        Probed::Found(probe_max_op_return(rpc)?)
+    } else {
+        verbose!("Skipping the OP_RETURN size probe, no output is an OP_RETURN.");
+        Probed::Skipped
+    };
+    let dust_relay_fee = if dust {
+        Probed::Found(probe_dust_relay_fee(rpc)?)
+    } else {
+        verbose!(
+            "Skipping the dust relay fee probe, no output is at or below {} sat.",
+            MAX_PROBED_DUST_SAT
+        );
+        Probed::Skipped
+    };
+
+    Ok(NodePolicy {
+        min_relay_fee: mempool.min_relay_tx_fee.max(network.relay_fee).to_sat(),
+        mempool_min_fee: mempool.mempool_min_fee.to_sat(),
+        incremental_relay_fee: mempool
+            .incremental_relay_fee
+            .unwrap_or(network.incremental_fee)
+            .to_sat(),
+        full_rbf: mempool.full_rbf,
+        max_op_return,
+        dust_relay_fee,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::capture_verbose;
+    use crate::mockrpc::{self, Reply};
+    use bitcoincore_rpc::bitcoin::consensus::encode;
+    use bitcoincore_rpc::bitcoin::hex::FromHex;
+    use serde_json::{json, Value};
+
+    fn p2wpkh(sat: u64) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(sat),
+            script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+        }
+    }
+
+    fn data(data_len: usize) -> TxOut {
+        TxOut {
+            value: Amount::ZERO,
+            script_pubkey: op_return(data_len),
+        }
+    }
+
+    // A node with Core's default policy: -dustrelayfee 3000 sat/kvB (294 sat
+    // for P2WPKH) and OP_RETURN scripts up to 83 bytes. Every probe spends a
+    // missing coin, so what passes is refused for that instead.
+    fn default_node(method: &str, params: &[Value]) -> Reply {
+        match method {
+            "getmempoolinfo" => Ok(mockrpc::mempool_info()),
+            "getnetworkinfo" => Ok(mockrpc::network_info()),
+            "testmempoolaccept" => {
+                let hex = params[0][0].as_str().unwrap();
+                let tx: Transaction =
+                    encode::deserialize(&Vec::<u8>::from_hex(hex).unwrap()).unwrap();
+                let output = &tx.output[1];
+                let reason = match &output.script_pubkey {
+                    script if script.is_op_return() && script.len() > 83 => "scriptpubkey",
+                    script if !script.is_op_return() && output.value.to_sat() < 294 => "dust",
+                    _ => "missing-inputs",
+                };
+                Ok(json!([{ "txid": tx.txid(), "allowed": false, "reject-reason": reason }]))
+            }
+            other => panic!("unexpected call {}", other),
+        }
+    }
+
+    fn probes(calls: &[String]) -> usize {
+        calls
+            .iter()
+            .filter(|method| *method == "testmempoolaccept")
+            .count()
+    }
+
+    #[test]
+    fn only_outputs_the_policy_decides_need_probes() {
+        assert_eq!(probes_needed(&[]), (false, false));
+        assert_eq!(probes_needed(&[p2wpkh(2_000_000_000)]), (false, false));
+        assert_eq!(probes_needed(&[p2wpkh(100_001)]), (false, false));
+        assert_eq!(probes_needed(&[p2wpkh(100_000)]), (false, true));
+        assert_eq!(probes_needed(&[p2wpkh(546)]), (false, true));
+        assert_eq!(probes_needed(&[data(40)]), (true, false));
+        assert_eq!(
+            probes_needed(&[p2wpkh(2_000_000_000), data(40), p2wpkh(330)]),
+            (true, true)
+        );
+    }
+
+    #[test]
+    fn the_default_payment_sends_no_probes() {
+        let (rpc, calls) = mockrpc::client(default_node);
+        let mut policy = None;
+        let (stdout, stderr) = capture_verbose(|| {
+            policy = Some(relay_policy(&rpc, &[p2wpkh(2_000_000_000)]));
+        });
+        let policy = policy.unwrap();
+        assert_eq!(*calls.lock().unwrap(), ["getmempoolinfo", "getnetworkinfo"]);
+        assert_eq!(policy.max_op_return, Probed::Skipped);
+        assert_eq!(policy.dust_relay_fee, Probed::Skipped);
+        assert_eq!(policy.dust_relay_fee.found(), None);
+        assert_eq!(policy.min_relay_fee, 1_000);
+        assert_eq!(policy.incremental_relay_fee, 1_000);
+
+        assert_eq!(stdout, "");
+        assert_eq!(
+            stderr,
+            "Skipping the OP_RETURN size probe, no output is an OP_RETURN.\n\
+             Skipping the dust relay fee probe, no output is at or below 100000 sat.\n"
+        );
+        let lines = policy.describe();
+        assert_eq!(
+            lines[4..],
+            [
+                "Node max OP_RETURN script (bytes): not probed, no OP_RETURN output",
+                "Node dust relay fee (sat/vB): not probed, no output near dust",
+            ]
+        );
+    }
+
+    #[test]
+    fn probes_find_the_default_policy() {
+        let (rpc, calls) = mockrpc::client(default_node);
+        let policy = relay_policy(&rpc, &[p2wpkh(300)]);
+        assert_eq!(policy.dust_relay_fee, Probed::Found(Some(3_000)));
+        assert_eq!(policy.max_op_return, Probed::Skipped);
+        // One try at the top of the range, then a binary search over it
+        assert_eq!(probes(&calls.lock().unwrap()), 18);
+
+        let (rpc, calls) = mockrpc::client(default_node);
+        let policy = relay_policy(&rpc, &[data(40)]);
+        assert_eq!(policy.max_op_return, Probed::Found(Some(83)));
+        assert_eq!(policy.dust_relay_fee, Probed::Skipped);
+        // An empty OP_RETURN first, then the same search
+        assert_eq!(probes(&calls.lock().unwrap()), 15);
+        assert_eq!(
+            policy.describe()[4..],
+            [
+                "Node max OP_RETURN script (bytes): 83",
+                "Node dust relay fee (sat/vB): not probed, no output near dust",
+            ]
+        );
+    }
+
+    fn relay_policy(rpc: &Client, outputs: &[TxOut]) -> NodePolicy {
+        policy(rpc, outputs).unwrap()
+    }
+}
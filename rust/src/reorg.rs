@@ -0,0 +1,148 @@
+use crate::cli::Config;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::mine;
+use crate::payment;
+use crate::report::{self, TransactionReport};
+use crate::session::Session;
+use crate::tips;
+use bitcoincore_rpc::bitcoin::{Amount, BlockHash, Txid};
+use bitcoincore_rpc::{Client, RpcApi};
+
+// The reorg pays the Trader less than the main flow so the Miner can repeat it
+const DEFAULT_AMOUNT: Amount = Amount::ONE_BTC;
+
+// The block the wallet currently sees `txid` confirmed in, None while it is
+// unconfirmed. Asked fresh each time: after a reorg the old answer is stale.
+fn confirming_block(wallet_rpc: &Client, txid: &Txid) -> Result<Option<BlockHash>> {
+    let info = wallet_rpc.get_transaction(txid, None)?.info;
+    Ok(match info.confirmations {
+        n if n > 0 => info.blockhash,
+        _ => None,
+    })
+}
+
+fn height_of(rpc: &Client, block_hash: &BlockHash) -> Result<usize> {
+    Ok(rpc.get_block_header_info(block_hash)?.height)
+}
+
+// reorg-demo: confirm a payment in block B, invalidate B, and confirm the
+// payment again on a longer replacement chain at a different height. B is
+// reconsidered at the end (it stays a stale fork, the new chain has more
+// work) unless --keep-invalidated.
+pub fn run(session: &Session, config: &Config) -> Result<()> {
+    let rpc = &session.rpc;
+    let miner_rpc = &session.miner_rpc;
+    let amount = config.amount.unwrap_or(DEFAULT_AMOUNT);
+    session.fund_miner(amount + config.max_fee())?;
+
+    let trader_address = session
+        .trader_rpc
+        .get_new_address(None, None)?
+        .assume_checked();
+    let txid = payment::send(
+        miner_rpc,
+        &trader_address.to_string(),
+        amount,
+        config,
+        payment::send_options(config),
+    )?;
+    say!("Payment sent! TXID: {}", txid);
+
+    let original_block = mine::confirm(session, &[txid], config)?;
+    let original_height = height_of(rpc, &original_block)?;
+    let original = TransactionReport::extract(rpc, &txid, &original_block, &trader_address)?;
+    say!("Successfully fetched confirmed transaction details.");
+    say!(
+        "Confirmed in block {} at height {}.",
+        original_block,
+        original_height
+    );
+
+    let mut narrative = Vec::new();
+    rpc.invalidate_block(&original_block)?;
+    let in_mempool = rpc.get_raw_mempool()?.contains(&txid);
+    let confirmations = miner_rpc.get_transaction(&txid, None)?.info.confirmations;
+    narrative.push(format!(
+        "After invalidateblock {}: {} (wallet confirmations {})",
+        original_block,
+        if in_mempool {
+            "back in the mempool"
+        } else {
+            "not in the mempool"
+        },
+        confirmations
+    ));
+    if !in_mempool && confirmations != 0 {
+        return Err(CapstoneError::Usage(format!(
+            "{} neither returned to the mempool nor lost its confirmations after invalidating {}",
+            txid, original_block
+        )));
+    }
+
+    // An empty block first takes B's height, so the payment confirms one
+    // block later and the replacement chain is longer than the one it replaces
+    let empty = mine::generate_block(session, &session.miner_address, &[])?;
+    let replacement = mine::confirm(session, &[txid], config)?;
+    narrative.push(format!(
+        "Replacement blocks: {} (empty) and {}",
+        empty, replacement
+    ));
+    let final_block = confirming_block(miner_rpc, &txid)?
+        .ok_or_else(|| CapstoneError::Unconfirmed(vec![txid]))?;
+    let final_height = height_of(rpc, &final_block)?;
+
+    if config.keep_invalidated {
+        narrative.push(format!("Left {} invalidated", original_block));
+    } else {
+        rpc.reconsider_block(&original_block)?;
+        let tip = rpc.get_best_block_hash()?;
+        narrative.push(format!(
+            "Reconsidered {}: the tip stays {}, {}",
+            original_block,
+            tip,
+            if confirming_block(miner_rpc, &txid)? == Some(final_block) {
+                "the replacement confirmation stands"
+            } else {
+                "the original chain is back"
+            }
+        ));
+    }
+
+    // B's branch shows up as an invalid tip, or a valid fork once reconsidered
+    narrative.push(format!("Chain tips: {}", tips::chain_tips(rpc)?.summary()));
+
+    // The report is extracted again against the block that confirms it now
+    let final_hash = confirming_block(miner_rpc, &txid)?.unwrap_or(final_block);
+    let mut final_report = TransactionReport::extract(rpc, &txid, &final_hash, &trader_address)?;
+    say!("Successfully fetched confirmed transaction details.");
+    final_report.verify_utxos(rpc)?;
+
+    let mut output_string = final_report.to_text();
+    output_string.push_str(&format!(
+        "Original block height at which the transaction was confirmed: {}\n",
+        original.block_height
+    ));
+    output_string.push_str(&format!(
+        "Original block hash at which the transaction was confirmed: {}\n",
+        original.block_hash
+    ));
+    output_string.push_str(&format!(
+        "Final block height at which the transaction is confirmed: {}\n",
+        final_report.block_height
+    ));
+    output_string.push_str(&format!(
+        "Final block hash at which the transaction is confirmed: {}\n",
+        final_report.block_hash
+    ));
+    for (i, line) in narrative.iter().enumerate() {
+        say!("{}", line);
+        output_string.push_str(&format!("Reorg step {}: {}\n", i + 1, line));
+    }
+    say!(
+        "Moved from height {} to height {}.",
+        original_height,
+        final_height
+    );
+    report::write_report(&output_string)
+}
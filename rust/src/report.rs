@@ -0,0 +1,1158 @@
+use crate::btc;
+use crate::bump::BumpAttempt;
+use crate::chaintime;
+use crate::cli::{Config, OutputFormat};
+use crate::coinbase::{self, CoinbaseAnalysis};
+use crate::console::{self, say};
+use crate::csvreport::{self, CsvRow};
+#[cfg(feature = "sqlite")]
+use crate::db;
+use crate::decode::DecodedTx;
+use crate::error::{CapstoneError, Result};
+use crate::fees::{self, FeeRateChoice, SmartFeeEstimate};
+use crate::files;
+use crate::grouping::InputSelection;
+use crate::history;
+use crate::htmlreport;
+use crate::inputs;
+use crate::layout::{self, Template, Value, Variables};
+use crate::mempool::MempoolSnapshot;
+use crate::mine::EvictionResponse;
+use crate::nodeinfo::NodeInfo;
+use crate::notify::Delivery;
+use crate::prevout::PrevoutResolver;
+use crate::proof::InclusionProof;
+use crate::prune;
+use crate::relay::NodePolicy;
+use crate::runinfo::RunInfo;
+use crate::signing;
+use crate::stats::{self, BlockStats};
+use crate::utxo;
+use bitcoincore_rpc::bitcoin::{Address, Amount, BlockHash, OutPoint, Sequence, Txid};
+use bitcoincore_rpc::json::GetRawTransactionResult;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde_json::json;
+use std::collections::HashSet;
+
+// The report is written next to the repo root, where the autograder expects it
+pub const REPORT_PATH: &str = "../out.txt";
+
+// Replace out.txt with a rendered report; nothing with --no-file.
+pub fn save_report(output_string: &str) -> Result<()> {
+    if console::report_only() {
+        return Ok(());
+    }
+    files::write_atomically(REPORT_PATH, output_string, "report file")?;
+    Ok(())
+}
+
+// Write a rendered report to out.txt and echo it to the console. With
+// --no-file it only goes to stdout, as is.
+pub fn write_report(output_string: &str) -> Result<()> {
+    if console::report_only() {
+        console::out(output_string);
+        return Ok(());
+    }
+    let path = files::write_atomically(REPORT_PATH, output_string, "report file")?;
+    println!(
+        "Successfully wrote transaction details to {}",
+        path.display()
+    );
+
+    println!("\n--- Content of out.txt ---\n{}", output_string);
+    Ok(())
+}
+
+// Bumped whenever a field of `TransactionReport::to_json` is renamed, removed
+// or changes meaning; new fields don't bump it. 2: confirmations is null
+// when the transaction is unconfirmed at report time.
+pub const JSON_SCHEMA_VERSION: u32 = 2;
+
+// Write out.txt, and --json-out if given, for transaction reports, echoing
+// them as --format asks. Several reports follow each other in out.txt and
+// make a JSON array; a single one is a JSON object.
+pub fn write_transaction_reports(reports: &[TransactionReport], config: &Config) -> Result<()> {
+    let template = config.template.as_deref().map(layout::load).transpose()?;
+    let text = reports
+        .iter()
+        .map(|report| {
+            let mut text = match &template {
+                Some(template) => report.render(template)?,
+                None => report.to_text(),
+            };
+            // Last, after the run metadata too
+            if config.include_hex {
+                text.push_str(&format!("Raw transaction hex: {}\n", report.hex));
+            }
+            Ok(text)
+        })
+        .collect::<Result<Vec<_>>>()?
+        .join("\n");
+    let json = match reports {
+        [report] => report.to_json(),
+        _ => json!(reports
+            .iter()
+            .map(|report| report.to_json())
+            .collect::<Vec<_>>()),
+    };
+    let json = serde_json::to_string_pretty(&json)?;
+    if let Some(path) = &config.history {
+        for report in reports {
+            history::record(path, report)?;
+        }
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &config.db {
+        db::record(path, reports)?;
+    }
+    if let Some(path) = &config.json_out {
+        let path = files::write_atomically(path, format!("{}\n", json), "JSON report")?;
+        say!("Wrote the JSON report to {}", path.display());
+    }
+    match config.format {
+        OutputFormat::Text => write_report(&text),
+        OutputFormat::Json => {
+            save_report(&text)?;
+            console::out(&format!("{}\n", json));
+            Ok(())
+        }
+        OutputFormat::Csv => {
+            save_report(&text)?;
+            let rows: Vec<CsvRow> = reports.iter().map(CsvRow::from_report).collect();
+            csvreport::write_csv(&rows)
+        }
+        OutputFormat::Html => {
+            save_report(&text)?;
+            htmlreport::write_html(reports)
+        }
+    }
+}
+
+// Amounts go into the JSON both ways: the exact 8-decimal BTC string, so no
+// float rounding creeps in, and integer satoshis.
+fn amount_json(amount: Amount) -> serde_json::Value {
+    json!({
+        "btc": btc::format_btc(amount),
+        "sat": amount.to_sat(),
+    })
+}
+
+fn output_json(vout: Option<u32>, output: &Option<(Amount, Address)>) -> serde_json::Value {
+    match output {
+        Some((amount, address)) => json!({
+            "vout": vout,
+            "address": address.to_string(),
+            "amount": amount_json(*amount),
+        }),
+        None => serde_json::Value::Null,
+    }
+}
+
+// The transaction's depth per getrawtransaction, none unless it is in a block
+// of the active chain
+fn confirmations(tx_info: &GetRawTransactionResult) -> Option<u32> {
+    if tx_info.blockhash.is_none() || tx_info.in_active_chain == Some(false) {
+        return None;
+    }
+    tx_info
+        .confirmations
+        .filter(|confirmations| *confirmations > 0)
+}
+
+// Everything we write to out.txt about the confirmed Miner -> Trader payment.
+#[derive(Debug, Clone)]
+pub struct TransactionReport {
+    pub txid: Txid,
+    pub input_addresses: Vec<Address>,
+    pub input_amount: Amount,
+    pub trader_output: Option<(Amount, Address)>,
+    pub change_output: Option<(Amount, Address)>,
+    // Every output in index order: (vout, role, amount, address)
+    pub outputs: Vec<(u32, &'static str, Amount, Option<Address>)>,
+    // Output indexes of the Trader and change outputs
+    pub trader_vout: Option<u32>,
+    pub change_vout: Option<u32>,
+    // Structure of the transaction as getrawtransaction decodes it
+    pub input_count: usize,
+    pub output_count: usize,
+    pub version: u32,
+    pub lock_time: u32,
+    pub fee: Amount,
+    pub block_height: u64,
+    // Timestamp of the confirming block, unix seconds
+    pub block_time: u64,
+    // Median time past at the confirming block, and the node's adjusted time
+    // when the report was extracted, unix seconds
+    pub block_median_time: Option<u64>,
+    pub adjusted_time: u64,
+    // Depth of the transaction and the chain tip when the report was extracted
+    // or last refreshed; no confirmations when it was unconfirmed by then,
+    // as after a reorg
+    pub confirmations: Option<u32>,
+    pub tip_height: u64,
+    pub tip_hash: BlockHash,
+    // Chain state from getblockchaininfo at report time; chainwork is kept as
+    // the big-endian bytes the node returns, see `chainwork_hex`
+    pub chain_difficulty: f64,
+    pub chainwork: Vec<u8>,
+    pub headers: u64,
+    pub blocks: u64,
+    // Difficulty in the confirming block's own header
+    pub block_difficulty: f64,
+    // getblockstats of the confirming block
+    pub block_stats: BlockStats,
+    // The confirming block's coinbase, checked against the block and our fee
+    pub coinbase: CoinbaseAnalysis,
+    pub block_hash: BlockHash,
+    pub size: u64,
+    pub vsize: u64,
+    pub weight: u64,
+    // estimatesmartfee at send time, if it was queried
+    pub fee_estimate: Option<SmartFeeEstimate>,
+    // The rate fees::fee_estimate picked (and --auto-fee paid), to compare with the achieved one
+    pub fee_choice: Option<FeeRateChoice>,
+    // Every version broadcast by --bump-until-confirmed, oldest first; empty otherwise
+    pub fee_bumps: Vec<BumpAttempt>,
+    // Set once gettxout confirmed both outputs, see `verify_utxos`
+    pub utxo_verified: bool,
+    // Sighash type each input was signed with
+    pub input_sighashes: Vec<String>,
+    pub input_sequences: Vec<Sequence>,
+    // The address-grouped coin selection, with --avoid-partial-spends
+    pub input_selection: Option<InputSelection>,
+    // Full decoded transaction, with --show-decoded
+    pub decoded: Option<DecodedTx>,
+    // The relay policy of the node, also with --show-decoded
+    pub node_policy: Option<NodePolicy>,
+    // tips::ChainTips::summary just before the report, also with --show-decoded
+    pub chain_tips: Option<String>,
+    // Names of the active softforks, also with --show-decoded
+    pub softforks: Option<Vec<String>>,
+    // prioritise::describe of the node's fee deltas right after broadcast, also
+    // with --show-decoded; mining clears the payment's own
+    pub prioritisation: Option<String>,
+    // The --notify-url callback
+    pub notification: Option<Delivery>,
+    // gettxoutproof for the payment, checked by the node and here
+    pub inclusion_proof: Option<InclusionProof>,
+    // Version, connections and traffic of the node, with --show-decoded
+    pub node_info: Option<NodeInfo>,
+    // filters::FilterScan::describe of the confirming block, with --verify-with-filters
+    pub filter_check: Option<String>,
+    // getmempoolentry right after broadcast
+    pub mempool_snapshot: Option<MempoolSnapshot>,
+    // Rebroadcasts and bumps after the payment was evicted, see mine::wait_for_confirmations
+    pub evictions: Vec<EvictionResponse>,
+    // Blocks mined before the payment so the Miner could afford it
+    pub funding_blocks: u64,
+    // With --mining-wallet, the Mining -> Miner transfer that funded the payment
+    pub funding_txid: Option<Txid>,
+    // Which wallet mines, pays and receives, see Session::wallet_topology
+    pub wallet_topology: Option<String>,
+    // When, with which build and against which node the report was made
+    pub run: Option<RunInfo>,
+    // The raw transaction, checked to decode to `txid`, so the report alone
+    // is enough to verify or rebroadcast it elsewhere
+    pub hex: String,
+}
+
+impl TransactionReport {
+    // Extract all required transaction details from the node once the
+    // payment is confirmed in `block_hash`.
+    pub fn extract(
+        rpc: &Client,
+        txid: &Txid,
+        block_hash: &BlockHash,
+        trader_address: &Address,
+    ) -> Result<Self> {
+        prune::require_block(rpc, block_hash, "the confirmation block")?;
+        let tx_info = prune::explain_pruned(
+            rpc,
+            block_hash,
+            "the confirmation block",
+            rpc.get_raw_transaction_info(txid, Some(block_hash)),
+        )?;
+        let hex = signing::verified_hex(&tx_info.hex, txid)?;
+
+        // 1. Get block details
+        let block_hash = tx_info.blockhash.unwrap_or(*block_hash);
+        let block_header_info = rpc.get_block_header_info(&block_hash)?;
+        // After a reorg the block we were told about may have left the active
+        // chain; reporting it would describe a confirmation that no longer exists
+        if tx_info.in_active_chain == Some(false) || block_header_info.confirmations < 0 {
+            return Err(CapstoneError::StaleBlock {
+                txid: *txid,
+                block_hash,
+            });
+        }
+        let block_height = block_header_info.height as u64;
+
+        // 2. Calculate total input value and find input addresses
+        let mut input_amount = Amount::ZERO;
+        let mut seen = HashSet::new(); // Use a HashSet to skip duplicate addresses
+        let mut input_addresses = Vec::new();
+        let mut input_sighashes = Vec::new();
+        let mut input_sequences = Vec::new();
+
+        let mut prevouts = PrevoutResolver::new(rpc)?;
+        prevouts.use_block(&block_hash)?;
+        for vin in &tx_info.vin {
+            input_sequences.push(Sequence(vin.sequence));
+            input_sighashes.push(signing::input_sighash(
+                vin.txinwitness.as_deref().unwrap_or_default(),
+                vin.script_sig.as_ref().map_or(&[][..], |s| &s.hex[..]),
+            ));
+            if let (Some(prev_txid), Some(prev_vout)) = (vin.txid, vin.vout) {
+                // Look up the output this input is spending
+                let spent_output = prevouts.resolve(&OutPoint::new(prev_txid, prev_vout))?;
+
+                input_amount += spent_output.value;
+                if let Some(address) = spent_output.address {
+                    if seen.insert(address.clone()) {
+                        input_addresses.push(address);
+                    }
+                }
+            }
+        }
+
+        // 3. Calculate total output value and identify Trader/Change outputs
+        let mut output_amount = Amount::ZERO;
+        let mut trader_output = None;
+        let mut change_output = None;
+        let mut trader_vout = None;
+        let mut change_vout = None;
+        let mut outputs = Vec::new();
+
+        for vout in &tx_info.vout {
+            output_amount += vout.value;
+            // Use `if let` to safely unwrap the address from the output
+            if let Some(output_address) = &vout.script_pub_key.address {
+                let output_address = output_address.clone().assume_checked();
+                if &output_address == trader_address {
+                    trader_output = Some((vout.value, output_address.clone()));
+                    trader_vout = Some(vout.n);
+                    outputs.push((vout.n, "Trader", vout.value, Some(output_address)));
+                } else {
+                    change_output = Some((vout.value, output_address.clone()));
+                    change_vout = Some(vout.n);
+                    outputs.push((vout.n, "Change", vout.value, Some(output_address)));
+                }
+            } else {
+                outputs.push((vout.n, "Other", vout.value, None));
+            }
+        }
+
+        // 4. Calculate fees
+        let fee = input_amount - output_amount;
+
+        // 5. Size of the transaction; getrawtransaction doesn't report the weight
+        // in the rpc crate's struct, so take it from the transaction itself
+        let weight = tx_info.transaction()?.weight().to_wu();
+        let chain_info = rpc.get_blockchain_info()?;
+        let block_stats = stats::block_stats(rpc, block_height)?;
+        let coinbase = coinbase::analyze(
+            rpc,
+            &block_hash,
+            chain_info.chain,
+            fee,
+            block_stats.total_fee,
+        )?;
+
+        Ok(TransactionReport {
+            txid: tx_info.txid,
+            input_addresses,
+            input_amount,
+            trader_output,
+            change_output,
+            outputs,
+            trader_vout,
+            change_vout,
+            input_count: tx_info.vin.len(),
+            output_count: tx_info.vout.len(),
+            version: tx_info.version,
+            lock_time: tx_info.locktime,
+            fee,
+            block_height,
+            block_time: block_header_info.time as u64,
+            block_median_time: block_header_info.median_time.map(|time| time as u64),
+            adjusted_time: chaintime::adjusted_time(rpc)?,
+            confirmations: confirmations(&tx_info),
+            tip_height: chain_info.blocks,
+            tip_hash: chain_info.best_block_hash,
+            chain_difficulty: chain_info.difficulty,
+            chainwork: chain_info.chain_work,
+            headers: chain_info.headers,
+            blocks: chain_info.blocks,
+            block_difficulty: block_header_info.difficulty,
+            block_stats,
+            coinbase,
+            block_hash,
+            size: tx_info.size as u64,
+            vsize: tx_info.vsize as u64,
+            weight,
+            fee_estimate: None,
+            fee_choice: None,
+            fee_bumps: Vec::new(),
+            utxo_verified: false,
+            input_sighashes,
+            input_sequences,
+            input_selection: None,
+            decoded: None,
+            node_policy: None,
+            chain_tips: None,
+            mempool_snapshot: None,
+            evictions: Vec::new(),
+            softforks: None,
+            prioritisation: None,
+            notification: None,
+            inclusion_proof: None,
+            filter_check: None,
+            node_info: None,
+            funding_blocks: 0,
+            funding_txid: None,
+            wallet_topology: None,
+            run: None,
+            hex,
+        })
+    }
+
+    // Bring the confirmations and chain state up to date with the node's tip,
+    // as watch does after every block
+    pub fn refresh(&mut self, rpc: &Client) -> Result<()> {
+        let tx_info = rpc.get_raw_transaction_info(&self.txid, Some(&self.block_hash))?;
+        let chain_info = rpc.get_blockchain_info()?;
+        self.confirmations = confirmations(&tx_info);
+        self.tip_height = chain_info.blocks;
+        self.tip_hash = chain_info.best_block_hash;
+        self.headers = chain_info.headers;
+        self.blocks = chain_info.blocks;
+        self.chain_difficulty = chain_info.difficulty;
+        self.chainwork = chain_info.chain_work;
+        self.adjusted_time = chaintime::adjusted_time(rpc)?;
+        Ok(())
+    }
+
+    // nLockTime below 500000000 is a block height, from there on a unix time
+    pub fn describe_lock_time(&self) -> String {
+        match self.lock_time {
+            0 => "0 (none)".into(),
+            height if height < 500_000_000 => format!("{} (block height)", height),
+            time => format!("{} ({})", time, chaintime::rfc3339(u64::from(time))),
+        }
+    }
+
+    // "unconfirmed" rather than a count that no longer holds
+    pub fn describe_confirmations(&self) -> String {
+        match self.confirmations {
+            Some(confirmations) => confirmations.to_string(),
+            None => "unconfirmed".into(),
+        }
+    }
+
+    // The feerate the transaction paid, in hundredths of a sat/vB. Computed from
+    // sats with integer math and rounded half up to 2 decimal places.
+    pub fn fee_rate_centisat_per_vb(&self) -> u64 {
+        let vsize = self.vsize.max(1);
+        (self.fee.to_sat() * 100 + vsize / 2) / vsize
+    }
+
+    // Chainwork as the 64 hex digit string bitcoind prints.
+    pub fn chainwork_hex(&self) -> String {
+        self.chainwork
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    // `fee_rate_centisat_per_vb` as a sat/vB string with exactly 2 decimals.
+    pub fn fee_rate_sat_per_vb(&self) -> String {
+        let rate = self.fee_rate_centisat_per_vb();
+        format!("{}.{:02}", rate / 100, rate % 100)
+    }
+
+    // Final integrity check: both outputs are in the UTXO set, confirmed, and
+    // hold what the transaction says they do.
+    pub fn verify_utxos(&mut self, rpc: &Client) -> Result<()> {
+        let outputs = [
+            (self.trader_vout, &self.trader_output),
+            (self.change_vout, &self.change_output),
+        ];
+        for (vout, output) in outputs {
+            if let (Some(vout), Some((amount, address))) = (vout, output) {
+                utxo::verify_utxo(
+                    rpc,
+                    &OutPoint::new(self.txid, vout),
+                    *amount,
+                    &address.script_pubkey(),
+                    false,
+                )?;
+            }
+        }
+        self.utxo_verified = true;
+        Ok(())
+    }
+
+    // Make sure the change went to the address the user asked for.
+    pub fn verify_change_address(&self, expected: &Address) -> Result<()> {
+        match &self.change_output {
+            Some((_, address)) if address == expected => Ok(()),
+            Some((_, address)) => Err(CapstoneError::ChangeMismatch {
+                expected: expected.to_string(),
+                found: Some(address.to_string()),
+            }),
+            None => Err(CapstoneError::ChangeMismatch {
+                expected: expected.to_string(),
+                found: None,
+            }),
+        }
+    }
+
+    // Render the report in the format given in readme.md, one attribute per
+    // line: layout::DEFAULT_TEMPLATE's required lines, then `details`.
+    pub fn to_text(&self) -> String {
+        Template::parse(layout::DEFAULT_TEMPLATE)
+            .and_then(|template| template.render(&self.variables()))
+            .expect("the built-in template renders every report")
+    }
+
+    // The report rendered through a --template
+    pub fn render(&self, template: &Template) -> Result<String> {
+        template.render(&self.variables())
+    }
+
+    // What a template can name: every field of `to_json` by its dotted path,
+    // plus the shorter names the default layout uses. The *_in_units amounts
+    // follow --units, with `units` their label; a missing output's is zero.
+    pub fn variables(&self) -> Variables {
+        let mut variables = Variables::new();
+        layout::flatten("", &self.to_json(), &mut variables);
+        let text = |value: String| Value::Text(value);
+        let mut output = |name: &str, output: &Option<(Amount, Address)>| {
+            variables.insert(name.to_string(), Value::Bool(output.is_some()));
+            let (address, btc, sat) = match output {
+                Some((amount, address)) => (
+                    text(address.to_string()),
+                    text(btc::format_btc(*amount)),
+                    text(amount.to_sat().to_string()),
+                ),
+                None => (Value::Null, Value::Null, Value::Null),
+            };
+            let amount = output.as_ref().map_or(Amount::ZERO, |(amount, _)| *amount);
+            variables.insert(format!("{}.address", name), address);
+            variables.insert(format!("{}.amount_btc", name), btc);
+            variables.insert(format!("{}.amount_sat", name), sat);
+            variables.insert(
+                format!("{}.amount_in_units", name),
+                text(btc::format(amount)),
+            );
+        };
+        output("trader", &self.trader_output);
+        output("change", &self.change_output);
+        let inputs = self
+            .input_addresses
+            .iter()
+            .map(|address| address.to_string())
+            .collect();
+        variables.insert("inputs".into(), Value::List(inputs));
+        variables.insert(
+            "input_amount_btc".into(),
+            text(btc::format_btc(self.input_amount)),
+        );
+        variables.insert(
+            "input_amount_sat".into(),
+            text(self.input_amount.to_sat().to_string()),
+        );
+        variables.insert(
+            "input_amount_in_units".into(),
+            text(btc::format(self.input_amount)),
+        );
+        variables.insert("fee_btc".into(), text(btc::format_btc(self.fee)));
+        variables.insert("fee_sat".into(), text(self.fee.to_sat().to_string()));
+        variables.insert("fee_in_units".into(), text(btc::format(self.fee)));
+        variables.insert("units".into(), text(btc::unit().to_string()));
+        variables.insert("details".into(), text(self.details()));
+        variables
+    }
+
+    // Every line after the ones readme.md requires
+    pub fn details(&self) -> String {
+        let mut output_string = String::new();
+
+        // Extra details go after the fields required by readme.md so line-based parsers keep working
+        output_string.push_str(&format!(
+            "Number of inputs / outputs: {} / {}\n",
+            self.input_count, self.output_count
+        ));
+        let vout = |vout: Option<u32>| vout.map_or("none".to_string(), |vout| vout.to_string());
+        output_string.push_str(&format!(
+            "Trader's output vout: {}\n",
+            vout(self.trader_vout)
+        ));
+        output_string.push_str(&format!(
+            "Miner's change vout: {}\n",
+            vout(self.change_vout)
+        ));
+        output_string.push_str(&format!("Transaction version: {}\n", self.version));
+        output_string.push_str(&format!(
+            "Transaction locktime: {}\n",
+            self.describe_lock_time()
+        ));
+        output_string.push_str(&format!(
+            "Block time at which the transaction is confirmed: {}\n",
+            self.block_time
+        ));
+        output_string.push_str(&format!(
+            "Block time (RFC 3339): {}\n",
+            chaintime::rfc3339(self.block_time)
+        ));
+        if let Some(median_time) = self.block_median_time {
+            output_string.push_str(&format!(
+                "Block median time past: {}\n",
+                chaintime::describe(median_time)
+            ));
+        }
+        output_string.push_str(&format!(
+            "Node adjusted time at report time: {}\n",
+            chaintime::describe(self.adjusted_time)
+        ));
+        output_string.push_str(&format!(
+            "Confirmations at report time: {}\n",
+            self.describe_confirmations()
+        ));
+        output_string.push_str(&format!(
+            "Chain tip height / hash at report time: {} / {}\n",
+            self.tip_height, self.tip_hash
+        ));
+        // f64's Display is the shortest string that parses back to the same
+        // value, so the difficulty keeps its full precision
+        output_string.push_str(&format!(
+            "Chain difficulty at report time: {}\n",
+            self.chain_difficulty
+        ));
+        output_string.push_str(&format!(
+            "Chainwork at report time: {}\n",
+            self.chainwork_hex()
+        ));
+        output_string.push_str(&format!(
+            "Headers / blocks at report time: {} / {}\n",
+            self.headers, self.blocks
+        ));
+        output_string.push_str(&format!(
+            "Difficulty of the confirming block: {}\n",
+            self.block_difficulty
+        ));
+        for line in self.block_stats.describe(self.fee_rate_centisat_per_vb()) {
+            output_string.push_str(&line);
+            output_string.push('\n');
+        }
+        for line in self.coinbase.describe() {
+            output_string.push_str(&line);
+            output_string.push('\n');
+        }
+        output_string.push_str(&format!(
+            "UTXO verified: {}\n",
+            if self.utxo_verified { "yes" } else { "no" }
+        ));
+        for (vout, role, amount, address) in &self.outputs {
+            output_string.push_str(&format!(
+                "vout {}: {} {} {}\n",
+                vout,
+                role,
+                address
+                    .as_ref()
+                    .map_or("(no address)".to_string(), |a| a.to_string()),
+                btc::amount(*amount)
+            ));
+        }
+        for (i, sighash) in self.input_sighashes.iter().enumerate() {
+            output_string.push_str(&format!("Input {} sighash: {}\n", i, sighash));
+        }
+        for (i, sequence) in self.input_sequences.iter().enumerate() {
+            output_string.push_str(&format!(
+                "Input {} sequence: {}\n",
+                i,
+                inputs::describe_sequence(*sequence)
+            ));
+        }
+        output_string.push_str(&format!("Transaction vsize (vB): {}\n", self.vsize));
+        output_string.push_str(&format!("Transaction weight (WU): {}\n", self.weight));
+        output_string.push_str(&format!(
+            "Fee rate (sat/vB): {}\n",
+            self.fee_rate_sat_per_vb()
+        ));
+        if let Some(estimate) = &self.fee_estimate {
+            output_string.push_str(&format!(
+                "Smart fee estimate at send time (target {} blocks): {}\n",
+                estimate.conf_target,
+                estimate.describe()
+            ));
+        }
+        if let Some(choice) = &self.fee_choice {
+            output_string.push_str(&format!(
+                "Fee rate estimate vs achieved (sat/vB): {} vs {}\n",
+                choice.describe(),
+                self.fee_rate_sat_per_vb()
+            ));
+        }
+        if let Some(selection) = &self.input_selection {
+            output_string.push_str(&format!(
+                "Avoid partial spends: {}\n",
+                selection.describe(self.fee, self.vsize)
+            ));
+        }
+        for (i, attempt) in self.fee_bumps.iter().enumerate() {
+            output_string.push_str(&format!(
+                "Fee bump attempt {}: {} paying {} at {} sat/vB{}\n",
+                i,
+                attempt.txid,
+                btc::amount(attempt.fee),
+                fees::format_sat_per_vb(attempt.sat_per_kvb),
+                if attempt.txid == self.txid {
+                    " (confirmed)"
+                } else {
+                    ""
+                }
+            ));
+        }
+        output_string.push_str(&format!(
+            "Blocks mined to fund the Miner: {}\n",
+            self.funding_blocks
+        ));
+        if let Some(txid) = &self.funding_txid {
+            output_string.push_str(&format!("Funding transaction ID (txid): {}\n", txid));
+        }
+        if let Some(proof) = &self.inclusion_proof {
+            for line in proof.describe() {
+                output_string.push_str(&line);
+                output_string.push('\n');
+            }
+        }
+        if let Some(filter_check) = &self.filter_check {
+            output_string.push_str(&format!("Block filter check: found, {}\n", filter_check));
+        }
+        if let Some(notification) = &self.notification {
+            output_string.push_str(&notification.describe());
+            output_string.push('\n');
+        }
+        if let Some(topology) = &self.wallet_topology {
+            output_string.push_str(&format!("Wallet topology: {}\n", topology));
+        }
+        if let Some(snapshot) = &self.mempool_snapshot {
+            for line in snapshot.describe() {
+                output_string.push_str(&line);
+                output_string.push('\n');
+            }
+        }
+        if !self.evictions.is_empty() {
+            let responses: Vec<String> = self.evictions.iter().map(|e| e.describe()).collect();
+            output_string.push_str(&format!(
+                "Mempool evictions: {} ({})\n",
+                self.evictions.len(),
+                responses.join("; ")
+            ));
+        }
+        if let Some(policy) = &self.node_policy {
+            for line in policy.describe() {
+                output_string.push_str(&line);
+                output_string.push('\n');
+            }
+        }
+        if let Some(node_info) = &self.node_info {
+            for line in node_info.describe() {
+                output_string.push_str(&line);
+                output_string.push('\n');
+            }
+        }
+        if let Some(tips) = &self.chain_tips {
+            output_string.push_str(&format!("Chain tips: {}\n", tips));
+        }
+        if let Some(softforks) = &self.softforks {
+            output_string.push_str(&format!("Active softforks: {}\n", softforks.join(", ")));
+        }
+        if let Some(prioritisation) = &self.prioritisation {
+            output_string.push_str(prioritisation);
+            output_string.push('\n');
+        }
+        // Last, under its own heading, so nothing shifts the lines above
+        if let Some(run) = &self.run {
+            output_string.push_str("\n--- Run metadata ---\n");
+            for line in run.describe() {
+                output_string.push_str(&line);
+                output_string.push('\n');
+            }
+        }
+
+        output_string
+    }
+
+    // The report as JSON with stable field names, for tools that would rather
+    // not parse the text lines. Optional parts are null when not collected.
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "schema_version": JSON_SCHEMA_VERSION,
+            "txid": self.txid.to_string(),
+            "input_addresses": self
+                .input_addresses
+                .iter()
+                .map(|address| address.to_string())
+                .collect::<Vec<_>>(),
+            "input_amount": amount_json(self.input_amount),
+            "trader_output": output_json(self.trader_vout, &self.trader_output),
+            "change_output": output_json(self.change_vout, &self.change_output),
+            "structure": {
+                "input_count": self.input_count,
+                "output_count": self.output_count,
+                "version": self.version,
+                "locktime": self.lock_time,
+            },
+            "outputs": self
+                .outputs
+                .iter()
+                .map(|(vout, role, amount, address)| {
+                    json!({
+                        "vout": vout,
+                        "role": role,
+                        "address": address.as_ref().map(|address| address.to_string()),
+                        "amount": amount_json(*amount),
+                    })
+                })
+                .collect::<Vec<_>>(),
+            "fee": amount_json(self.fee),
+            "fee_rate_sat_per_vb": self.fee_rate_sat_per_vb(),
+            "size": self.size,
+            "vsize": self.vsize,
+            "weight": self.weight,
+            "block": {
+                "height": self.block_height,
+                "hash": self.block_hash.to_string(),
+                "time": self.block_time,
+                "median_time": self.block_median_time,
+                "difficulty": self.block_difficulty,
+            },
+            "confirmations": self.confirmations,
+            "chain": {
+                "tip_height": self.tip_height,
+                "tip_hash": self.tip_hash.to_string(),
+                "headers": self.headers,
+                "blocks": self.blocks,
+                "difficulty": self.chain_difficulty,
+                "chainwork": self.chainwork_hex(),
+                "adjusted_time": self.adjusted_time,
+            },
+            "inputs": self
+                .input_sighashes
+                .iter()
+                .zip(&self.input_sequences)
+                .map(|(sighash, sequence)| {
+                    json!({
+                        "sighash": sighash,
+                        "sequence": sequence.to_consensus_u32(),
+                    })
+                })
+                .collect::<Vec<_>>(),
+            "utxo_verified": self.utxo_verified,
+            "fee_estimate": self.fee_estimate.as_ref().map(|estimate| estimate.describe()),
+            "fee_choice": self.fee_choice.as_ref().map(|choice| {
+                json!({
+                    "sat_per_kvb": choice.sat_per_kvb,
+                    "source": choice.source.describe(),
+                })
+            }),
+            "fee_bumps": self
+                .fee_bumps
+                .iter()
+                .map(|attempt| {
+                    json!({
+                        "txid": attempt.txid.to_string(),
+                        "fee": amount_json(attempt.fee),
+                        "sat_per_kvb": attempt.sat_per_kvb,
+                    })
+                })
+                .collect::<Vec<_>>(),
+            "funding_blocks": self.funding_blocks,
+            "funding_txid": self.funding_txid.map(|txid| txid.to_string()),
+            "inclusion_proof": self.inclusion_proof.as_ref().map(|proof| {
+                json!({
+                    "hex": proof.hex(),
+                    "merkle_root": proof.merkle_root.to_string(),
+                    "index": proof.index,
+                    "block_transactions": proof.block_transactions,
+                    "node_verified": proof.node_verified,
+                    "locally_verified": proof.locally_verified,
+                })
+            }),
+            // The rest as the same lines the text report has
+            "block_stats": json!(self.block_stats.describe(self.fee_rate_centisat_per_vb())),
+            "coinbase": json!(self.coinbase.describe()),
+            "input_selection": self
+                .input_selection
+                .as_ref()
+                .map(|selection| selection.describe(self.fee, self.vsize)),
+            "filter_check": self.filter_check,
+            "notification": self.notification.as_ref().map(|delivery| delivery.describe()),
+            "wallet_topology": self.wallet_topology,
+            "mempool_snapshot": self.mempool_snapshot.as_ref().map(|snapshot| json!(snapshot.describe())),
+            "evictions": self
+                .evictions
+                .iter()
+                .map(|response| response.describe())
+                .collect::<Vec<_>>(),
+            "node_policy": self.node_policy.as_ref().map(|policy| json!(policy.describe())),
+            "node_info": self.node_info.as_ref().map(|info| json!(info.describe())),
+            "chain_tips": self.chain_tips,
+            "softforks": self.softforks,
+            "prioritisation": self.prioritisation,
+            "decoded": self.decoded,
+            "run": self.run.as_ref().map(|run| run.to_json()),
+            "hex": self.hex,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+    use bitcoincore_rpc::bitcoin::consensus::encode;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::hex::FromHex;
+    use bitcoincore_rpc::bitcoin::{
+        absolute, transaction, ScriptBuf, Transaction, TxIn, TxOut, Witness,
+    };
+
+    pub fn address(address: &str) -> Address {
+        address
+            .parse::<Address<NetworkUnchecked>>()
+            .unwrap()
+            .assume_checked()
+    }
+
+    // The payment from readme.md's sample: 50 BTC in, 20 to the Trader,
+    // 29.9999859 back as change. The transaction is built for real so that
+    // txid, hex and size agree with each other.
+    pub fn fixture() -> TransactionReport {
+        let miner = address("bcrt1qv5plgft75j0hegtvf6zs5pajh7k0gxg2dhj224");
+        let trader = address("bcrt1qak6gpu2p6zjpwrhvd4dvdnp4rt3ysm9rpst3wu");
+        let change = address("bcrt1qxw3msnuqps0kgn6dprs9ldlz79yfj63swqupd0");
+        let trader_amount = Amount::from_sat(2_000_000_000);
+        let change_amount = Amount::from_sat(2_999_998_590);
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::from_height(101).unwrap(),
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output: vec![
+                TxOut {
+                    value: trader_amount,
+                    script_pubkey: trader.script_pubkey(),
+                },
+                TxOut {
+                    value: change_amount,
+                    script_pubkey: change.script_pubkey(),
+                },
+            ],
+        };
+        let block_hash: BlockHash =
+            "3b821acd7c32c2b3da143e2c6b0134e5aa8206aeae0a54bfa4963e73ac2857a0"
+                .parse()
+                .unwrap();
+        TransactionReport {
+            txid: tx.txid(),
+            input_addresses: vec![miner.clone()],
+            input_amount: Amount::from_sat(5_000_000_000),
+            trader_output: Some((trader_amount, trader.clone())),
+            change_output: Some((change_amount, change.clone())),
+            outputs: vec![
+                (0, "Trader", trader_amount, Some(trader)),
+                (1, "Change", change_amount, Some(change)),
+            ],
+            trader_vout: Some(0),
+            change_vout: Some(1),
+            input_count: 1,
+            output_count: 2,
+            version: 2,
+            lock_time: 101,
+            fee: Amount::from_sat(1_410),
+            block_height: 102,
+            block_time: 1_700_000_000,
+            block_median_time: Some(1_699_999_000),
+            adjusted_time: 1_700_000_060,
+            confirmations: Some(1),
+            tip_height: 102,
+            tip_hash: block_hash,
+            chain_difficulty: 4.656542373906925e-10,
+            chainwork: vec![0, 0, 0, 0xce],
+            headers: 102,
+            blocks: 102,
+            block_difficulty: 4.656542373906925e-10,
+            block_stats: BlockStats {
+                txs: Some(2),
+                total_size: Some(222),
+                total_weight: Some(561),
+                subsidy: Some(Amount::from_sat(5_000_000_000)),
+                total_fee: Some(Amount::from_sat(1_410)),
+                min_fee_rate: Some(10),
+                avg_fee_rate: Some(10),
+                max_fee_rate: Some(10),
+            },
+            coinbase: CoinbaseAnalysis {
+                txid: Txid::all_zeros(),
+                total: Amount::from_sat(5_000_001_410),
+                subsidy: Amount::from_sat(5_000_000_000),
+                fees: Amount::from_sat(1_410),
+                outputs: vec![(
+                    Amount::from_sat(5_000_001_410),
+                    Some(miner),
+                    "witness_v0_keyhash".into(),
+                )],
+                bip34_height: Some(102),
+            },
+            block_hash,
+            size: tx.total_size() as u64,
+            vsize: tx.vsize() as u64,
+            weight: tx.weight().to_wu(),
+            fee_estimate: None,
+            fee_choice: None,
+            fee_bumps: Vec::new(),
+            utxo_verified: true,
+            input_sighashes: vec!["ALL".into()],
+            input_sequences: vec![Sequence::ENABLE_RBF_NO_LOCKTIME],
+            input_selection: None,
+            decoded: None,
+            node_policy: None,
+            chain_tips: None,
+            softforks: None,
+            prioritisation: None,
+            notification: None,
+            inclusion_proof: None,
+            node_info: None,
+            filter_check: None,
+            mempool_snapshot: None,
+            evictions: Vec::new(),
+            funding_blocks: 101,
+            funding_txid: None,
+            wallet_topology: None,
+            run: Some(crate::runinfo::tests::fixture()),
+            hex: encode::serialize_hex(&tx),
+        }
+    }
+
+    // tests/fixtures/report.json is the JSON of `fixture`: a change to it is a
+    // change to the schema, and needs JSON_SCHEMA_VERSION bumped if it renames,
+    // removes or changes the meaning of a field
+    #[test]
+    fn json_matches_the_golden_file() {
+        let golden: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/fixtures/report.json")).unwrap();
+        assert_eq!(fixture().to_json(), golden);
+    }
+
+    #[test]
+    fn text_follows_units_and_json_carries_both() {
+        use crate::cli::Units;
+        let report = fixture();
+        let btc_text = btc::with_units(Units::Btc, || report.to_text());
+        let sats_text = btc::with_units(Units::Sats, || report.to_text());
+        let btc_lines: Vec<&str> = btc_text.lines().collect();
+        let sats_lines: Vec<&str> = sats_text.lines().collect();
+        assert_eq!(btc_lines.len(), sats_lines.len());
+        assert_eq!(
+            btc_lines[..8],
+            [
+                format!("Transaction ID (txid): {}", report.txid).as_str(),
+                "Miner's Input Address: bcrt1qv5plgft75j0hegtvf6zs5pajh7k0gxg2dhj224",
+                "Miner's Input Amount (in BTC): 50.00000000",
+                "Trader's Output Address: bcrt1qak6gpu2p6zjpwrhvd4dvdnp4rt3ysm9rpst3wu",
+                "Trader's Output Amount (in BTC): 20.00000000",
+                "Miner's Change Address: bcrt1qxw3msnuqps0kgn6dprs9ldlz79yfj63swqupd0",
+                "Miner's Change Amount (in BTC): 29.99998590",
+                "Transaction Fees (in BTC): 0.00001410",
+            ]
+        );
+        assert_eq!(
+            sats_lines[..8],
+            [
+                format!("Transaction ID (txid): {}", report.txid).as_str(),
+                "Miner's Input Address: bcrt1qv5plgft75j0hegtvf6zs5pajh7k0gxg2dhj224",
+                "Miner's Input Amount (in sats): 5000000000",
+                "Trader's Output Address: bcrt1qak6gpu2p6zjpwrhvd4dvdnp4rt3ysm9rpst3wu",
+                "Trader's Output Amount (in sats): 2000000000",
+                "Miner's Change Address: bcrt1qxw3msnuqps0kgn6dprs9ldlz79yfj63swqupd0",
+                "Miner's Change Amount (in sats): 2999998590",
+                "Transaction Fees (in sats): 1410",
+            ]
+        );
+        // Every other amount follows too
+        for (btc_line, sats_line) in [
+            (
+                "Confirmation block subsidy (in BTC): 50.00000000",
+                "Confirmation block subsidy (in sats): 5000000000",
+            ),
+            (
+                "vout 1: Change bcrt1qxw3msnuqps0kgn6dprs9ldlz79yfj63swqupd0 29.99998590 BTC",
+                "vout 1: Change bcrt1qxw3msnuqps0kgn6dprs9ldlz79yfj63swqupd0 2999998590 sats",
+            ),
+        ] {
+            assert!(btc_lines.contains(&btc_line), "{}", btc_line);
+            assert!(sats_lines.contains(&sats_line), "{}", sats_line);
+        }
+        assert!(!sats_text.contains("BTC"), "{}", sats_text);
+
+        let btc_json = btc::with_units(Units::Btc, || report.to_json());
+        let sats_json = btc::with_units(Units::Sats, || report.to_json());
+        assert_eq!(btc_json["fee"], json!({ "btc": "0.00001410", "sat": 1410 }));
+        assert_eq!(btc_json["input_amount"], sats_json["input_amount"]);
+        assert_eq!(btc_json["fee"], sats_json["fee"]);
+    }
+
+    // The run metadata comes after every other line, under its own heading
+    #[test]
+    fn text_keeps_the_run_metadata_apart() {
+        let with_run = fixture().to_text();
+        let without_run = TransactionReport {
+            run: None,
+            ..fixture()
+        }
+        .to_text();
+        let (before, header) = with_run.split_once("\n--- Run metadata ---\n").unwrap();
+        assert_eq!(before, without_run);
+        assert_eq!(
+            header.lines().collect::<Vec<_>>(),
+            crate::runinfo::tests::fixture().describe()
+        );
+    }
+
+    #[test]
+    fn json_parses_back() {
+        let report = fixture();
+        let text = serde_json::to_string_pretty(&report.to_json()).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(parsed, report.to_json());
+        assert_eq!(parsed["schema_version"], JSON_SCHEMA_VERSION);
+        assert_eq!(
+            parsed["txid"].as_str().unwrap().parse::<Txid>().unwrap(),
+            report.txid
+        );
+        let fee = &parsed["fee"];
+        assert_eq!(
+            btc::parse_btc(fee["btc"].as_str().unwrap())
+                .unwrap()
+                .to_sat(),
+            fee["sat"].as_u64().unwrap()
+        );
+        assert_eq!(parsed["trader_output"]["vout"], 0);
+        assert_eq!(
+            address(parsed["change_output"]["address"].as_str().unwrap()),
+            report.change_output.unwrap().1
+        );
+        let bytes = Vec::<u8>::from_hex(parsed["hex"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            signing::verified_hex(&bytes, &report.txid).unwrap(),
+            report.hex
+        );
+    }
+}
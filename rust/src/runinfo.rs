@@ -0,0 +1,163 @@
+use crate::chaintime;
+use crate::error::Result;
+use crate::version::CoreVersion;
+use bitcoincore_rpc::bitcoin::Network;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde_json::json;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Unix seconds when `run` began, set once by `start`
+static STARTED: OnceLock<u64> = OnceLock::new();
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+pub fn start() {
+    STARTED.get_or_init(now);
+}
+
+// The package version, with `git describe` of the checkout it was built from
+// when build.rs could find one.
+pub fn tool_version() -> String {
+    match option_env!("CAPSTONE_GIT_DESCRIBE") {
+        Some(describe) => format!("{} ({})", env!("CARGO_PKG_VERSION"), describe),
+        None => env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+// Which run of which build against which node produced a report. There are no
+// config files or profiles, so the arguments given are the configuration.
+#[derive(Debug, Clone)]
+pub struct RunInfo {
+    // Unix seconds
+    pub started: u64,
+    pub finished: u64,
+    pub tool_version: String,
+    pub node_version: CoreVersion,
+    // getnetworkinfo's subversion, e.g. /Satoshi:26.1.0/
+    pub node_subversion: String,
+    pub network: Network,
+    pub arguments: Vec<String>,
+}
+
+impl RunInfo {
+    // The run so far: it "finishes" when the report is collected.
+    pub fn collect(rpc: &Client) -> Result<Self> {
+        let network_info = rpc.get_network_info()?;
+        let network = rpc.get_blockchain_info()?.chain;
+        let finished = now();
+        Ok(RunInfo {
+            started: STARTED.get().copied().unwrap_or(finished),
+            finished,
+            tool_version: tool_version(),
+            node_version: CoreVersion(network_info.version as u64),
+            node_subversion: network_info.subversion,
+            network,
+            arguments: std::env::args().skip(1).collect(),
+        })
+    }
+
+    pub fn arguments_summary(&self) -> String {
+        if self.arguments.is_empty() {
+            "(none, the default payment)".to_string()
+        } else {
+            self.arguments.join(" ")
+        }
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        vec![
+            format!("Run started: {}", chaintime::rfc3339(self.started)),
+            format!("Run finished: {}", chaintime::rfc3339(self.finished)),
+            format!("Tool version: {}", self.tool_version),
+            format!(
+                "Node version: {} {}",
+                self.node_version, self.node_subversion
+            ),
+            format!("Network: {}", self.network),
+            format!("Arguments: {}", self.arguments_summary()),
+        ]
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "started": chaintime::rfc3339(self.started),
+            "finished": chaintime::rfc3339(self.finished),
+            "tool_version": self.tool_version,
+            "node_version": self.node_version.to_string(),
+            "node_subversion": self.node_subversion,
+            "network": self.network.to_string(),
+            "arguments": self.arguments,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // A regtest run of two minutes with one argument
+    pub fn fixture() -> RunInfo {
+        RunInfo {
+            started: 1_699_999_940,
+            finished: 1_700_000_060,
+            tool_version: "0.1.0 (v0.1.0-3-g1a2b3c4)".into(),
+            node_version: CoreVersion(250_000),
+            node_subversion: "/Satoshi:25.0.0/".into(),
+            network: Network::Regtest,
+            arguments: vec!["--confirmations".into(), "1".into()],
+        }
+    }
+
+    #[test]
+    fn describes_the_run() {
+        assert_eq!(
+            fixture().describe(),
+            [
+                "Run started: 2023-11-14T22:12:20Z",
+                "Run finished: 2023-11-14T22:14:20Z",
+                "Tool version: 0.1.0 (v0.1.0-3-g1a2b3c4)",
+                "Node version: 25.0 /Satoshi:25.0.0/",
+                "Network: regtest",
+                "Arguments: --confirmations 1",
+            ]
+        );
+        let default = RunInfo {
+            arguments: Vec::new(),
+            ..fixture()
+        };
+        assert_eq!(default.arguments_summary(), "(none, the default payment)");
+    }
+
+    #[test]
+    fn the_header_parses_back_out_of_json() {
+        let run = fixture();
+        let text = serde_json::to_string(&run.to_json()).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&text).unwrap();
+        let time = |field: &str| chaintime::parse_time(parsed[field].as_str().unwrap()).unwrap();
+        assert_eq!(time("started"), run.started);
+        assert_eq!(time("finished"), run.finished);
+        assert_eq!(parsed["tool_version"], run.tool_version);
+        assert_eq!(parsed["node_version"], "25.0");
+        assert_eq!(parsed["node_subversion"], run.node_subversion);
+        assert_eq!(
+            parsed["network"]
+                .as_str()
+                .unwrap()
+                .parse::<Network>()
+                .unwrap(),
+            run.network
+        );
+        let arguments: Vec<String> = serde_json::from_value(parsed["arguments"].clone()).unwrap();
+        assert_eq!(arguments, run.arguments);
+    }
+
+    #[test]
+    fn tool_version_starts_with_the_package_version() {
+        assert!(tool_version().starts_with(env!("CARGO_PKG_VERSION")));
+    }
+}
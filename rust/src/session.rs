@@ -0,0 +1,842 @@
+use crate::btc;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::maturity;
+use crate::metrics;
+use crate::mine;
+use crate::prevout;
+use crate::txidlog;
+use crate::{RPC_PASS, RPC_URL, RPC_USER};
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::{Address, Amount, Network};
+use bitcoincore_rpc::bitcoin::{BlockHash, Txid};
+use bitcoincore_rpc::jsonrpc;
+use bitcoincore_rpc::jsonrpc::simple_http::SimpleHttpTransport;
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::value::to_raw_value;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+// Node connection plus the Miner and Trader wallets, shared by every mode of the tool.
+pub struct Session {
+    pub rpc: Client,
+    pub miner_rpc: Client,
+    pub trader_rpc: Client,
+    pub network: Network,
+    // Where block rewards (and confirmation blocks) go
+    pub miner_address: Address,
+    // Where fund_miner's rewards go, the Miner address unless --mine-to-descriptor
+    pub mining_target: MiningTarget,
+    // Set by --mocktime/--block-interval, see `set_mock_time`
+    clock: Option<MockClock>,
+    // --empty-blocks: `advance` mines coinbase-only blocks
+    pub empty_blocks: bool,
+    // --zmq: wait for blocks on the node's hashblock notifications, see mine::BlockWaiter
+    pub zmq: Option<String>,
+    // --mining-wallet: the wallet receiving block rewards, see `use_mining_wallet`
+    pub mining_rpc: Option<Client>,
+    // The Mining -> Miner transfer of the last `fund_miner`, if there was one
+    pub funding_txid: Cell<Option<Txid>>,
+}
+
+// The node's mocked time and how far it moves per block.
+#[derive(Debug)]
+struct MockClock {
+    now: Cell<u64>,
+    interval: u64,
+}
+
+// Leave the node on its real clock however the run ends
+impl Drop for Session {
+    fn drop(&mut self) {
+        if self.clock.is_some() {
+            if let Err(e) = self
+                .rpc
+                .call::<serde_json::Value>("setmocktime", &[json!(0)])
+            {
+                eprintln!("Warning: could not clear the node's mock time: {}", e);
+            }
+        }
+    }
+}
+
+// What fund_miner mines to.
+#[derive(Debug, Clone)]
+pub enum MiningTarget {
+    Address(Address),
+    // --rotate-mining-addresses: the reward wallet's labeled addresses, taking
+    // turns in equal batches
+    Rotation(Vec<Address>),
+    // A checksummed descriptor, and the loaded wallet that owns it if any
+    Descriptor {
+        descriptor: String,
+        ranged: bool,
+        wallet: Option<&'static str>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct DescriptorInfo {
+    checksum: String,
+    isrange: bool,
+}
+
+impl Session {
+    pub fn connect() -> Result<Self> {
+        // Connect to Bitcoin Core RPC
+        let rpc = client(RPC_URL, None)?;
+
+        // Get blockchain info
+        let blockchain_info = rpc.get_blockchain_info()?;
+        say!("Blockchain Info: {:?}", blockchain_info);
+        if !prevout::has_txindex(&rpc)? {
+            say!("The node has no -txindex; spent outputs are looked up in the wallets instead.");
+        }
+
+        // Create/Load the wallets, named 'Miner' and 'Trader'. Have logic to optionally create/load them if they do not exist or not loaded already.
+        let miner_wallet_name = "Miner";
+        let trader_wallet_name = "Trader";
+        create_or_load_wallet(&rpc, miner_wallet_name)?;
+        create_or_load_wallet(&rpc, trader_wallet_name)?;
+
+        // We create wallet-specific RPC clients url for easier management.
+        say!("Creating wallet-specific RPC clients...");
+        let miner_rpc = wallet_client(miner_wallet_name)?;
+        let trader_rpc = wallet_client(trader_wallet_name)?;
+
+        say!("'Miner' and 'Trader' wallets are ready.");
+
+        let miner_address = miner_rpc.get_new_address(None, None)?.assume_checked();
+
+        Ok(Session {
+            rpc,
+            miner_rpc,
+            trader_rpc,
+            network: blockchain_info.chain,
+            mining_target: MiningTarget::Address(miner_address.clone()),
+            clock: None,
+            empty_blocks: false,
+            zmq: None,
+            mining_rpc: None,
+            funding_txid: Cell::new(None),
+            miner_address,
+        })
+    }
+
+    // Mine until the Miner's trusted balance covers `target` (the amount to
+    // send plus a fee buffer), and return how many blocks that took. Each batch
+    // is sized from the wallet's immature coinbases (see
+    // `maturity::blocks_needed`). With --mine-to-descriptor the wallet owning
+    // the descriptor is checked instead, and a foreign descriptor gets a single
+    // batch sized as if starting from nothing. With --mining-wallet the rewards
+    // pile up in the Mining wallet, which then sends the Miner a round amount.
+    pub fn fund_miner(&self, target: Amount) -> Result<u64> {
+        if let Some(mining_rpc) = &self.mining_rpc {
+            return self.fund_from_mining_wallet(mining_rpc, target);
+        }
+        let (wallet_name, wallet_rpc) = match &self.mining_target {
+            MiningTarget::Address(_) | MiningTarget::Rotation(_) => ("Miner", &self.miner_rpc),
+            MiningTarget::Descriptor { wallet, .. } => match wallet {
+                Some("Trader") => ("Trader", &self.trader_rpc),
+                Some(_) => ("Miner", &self.miner_rpc),
+                None => {
+                    let height = self.rpc.get_block_count()?;
+                    let subsidy = block_subsidy(height + 1, self.network);
+                    let rewards = target.to_sat().div_ceil(subsidy.to_sat().max(1));
+                    let batch = (COINBASE_MATURITY + rewards).min(MAX_FUNDING_BLOCKS);
+                    say!(
+                        "Warning: no loaded wallet owns the mining descriptor, mining {} blocks without checking any balance.",
+                        batch
+                    );
+                    return Ok(self.mine_rewards(batch)?.len() as u64);
+                }
+            },
+        };
+        self.fund_wallet(wallet_name, wallet_rpc, target)
+    }
+
+    // The --mining-wallet version of `fund_miner`: top up the Miner with one
+    // transfer of whole BTC from the Mining wallet, mining for that first.
+    fn fund_from_mining_wallet(&self, mining_rpc: &Client, target: Amount) -> Result<u64> {
+        let balance = self.miner_rpc.get_balances()?.mine.trusted;
+        if balance >= target {
+            say!("Miner already has a sufficient balance.");
+            return Ok(0);
+        }
+        let transfer = Amount::from_int_btc((target - balance).to_sat().div_ceil(COIN));
+        let mut mined = self.fund_wallet("Mining", mining_rpc, transfer + FUNDING_FEE_BUFFER)?;
+
+        let address = self.miner_rpc.get_new_address(None, None)?.assume_checked();
+        let txid =
+            mining_rpc.send_to_address(&address, transfer, None, None, None, None, None, None)?;
+        txidlog::record(&txid, "funding");
+        say!(
+            "Funding transaction sent! Mining -> Miner {}, TXID: {}",
+            btc::amount(transfer),
+            txid
+        );
+        let block_hash = self.generate(1, &self.miner_address)?[0];
+        mined += 1;
+        say!(
+            "Block {} mined, confirming the funding transaction.",
+            block_hash
+        );
+        self.funding_txid.set(Some(txid));
+        Ok(mined)
+    }
+
+    // Mine until `wallet_rpc`'s trusted balance covers `target`.
+    fn fund_wallet(&self, wallet_name: &str, wallet_rpc: &Client, target: Amount) -> Result<u64> {
+        let mut mined = 0;
+        loop {
+            let trusted = wallet_rpc.get_balances()?.mine.trusted;
+            if trusted >= target {
+                break;
+            }
+            let height = self.rpc.get_block_count()?;
+            let subsidy = block_subsidy(height + 1, self.network);
+            let batch = funding_batch(trusted, target, height, subsidy, mined, || {
+                maturity::immature_coinbases(&self.rpc, wallet_rpc)
+            })?;
+
+            let block_hashes = self.mine_rewards(batch)?;
+            mined += block_hashes.len() as u64;
+            let trusted = wallet_rpc.get_balances()?.mine.trusted;
+            say!(
+                "Mined {} blocks to height {} ({} so far), {} trusted balance {} of {}.",
+                block_hashes.len(),
+                height + batch,
+                mined,
+                wallet_name,
+                btc::format(trusted),
+                btc::amount(target)
+            );
+        }
+        if mined == 0 {
+            say!("{} already has a sufficient balance.", wallet_name);
+        }
+        let balance = wallet_rpc.get_balance(None, None)?;
+        say!("{} wallet balance: {}", wallet_name, btc::amount(balance));
+        Ok(mined)
+    }
+
+    // Mine `blocks` blocks to `address`, one at a time when the clock is mocked
+    // so that each gets its own timestamp.
+    pub fn generate(&self, blocks: u64, address: &Address) -> Result<Vec<BlockHash>> {
+        if self.clock.is_none() {
+            return Ok(self.rpc.generate_to_address(blocks, address)?);
+        }
+        let mut block_hashes = Vec::new();
+        for _ in 0..blocks {
+            self.tick()?;
+            block_hashes.extend(self.rpc.generate_to_address(1, address)?);
+        }
+        Ok(block_hashes)
+    }
+
+    // Set the node's clock to `start` (the tip's timestamp if None) and move it
+    // on by `interval` seconds before every block mined through the session,
+    // so block times are reproducible. Cleared again when the session drops.
+    pub fn set_mock_time(&mut self, start: Option<u64>, interval: u64) -> Result<()> {
+        let start = match start {
+            Some(start) => start,
+            None => {
+                let tip = self.rpc.get_best_block_hash()?;
+                self.rpc.get_block_header_info(&tip)?.time as u64
+            }
+        };
+        self.rpc
+            .call::<serde_json::Value>("setmocktime", &[json!(start)])?;
+        say!("Mock time set to {}, {}s per block.", start, interval);
+        self.clock = Some(MockClock {
+            now: Cell::new(start),
+            interval,
+        });
+        Ok(())
+    }
+
+    // Advance the mock clock ahead of the next block. Nothing without a mock clock.
+    pub fn tick(&self) -> Result<()> {
+        if let Some(clock) = &self.clock {
+            let now = clock.now.get() + clock.interval;
+            self.rpc
+                .call::<serde_json::Value>("setmocktime", &[json!(now)])?;
+            clock.now.set(now);
+        }
+        Ok(())
+    }
+
+    // Mine `blocks` blocks that are only there to move the chain on (maturing
+    // coinbases, reaching a lock height). With --empty-blocks they hold
+    // nothing but the coinbase, so the mempool is left as it was.
+    pub fn advance(&self, blocks: u64, address: &Address) -> Result<Vec<BlockHash>> {
+        if self.empty_blocks {
+            self.mine_empty(blocks, &address.to_string())
+        } else {
+            self.generate(blocks, address)
+        }
+    }
+
+    // generateblock with no transactions, paying `output` (an address or a
+    // descriptor). Fails if the mempool changed size meanwhile.
+    fn mine_empty(&self, blocks: u64, output: &str) -> Result<Vec<BlockHash>> {
+        let before = self.rpc.get_mempool_info()?.size;
+        let mut block_hashes = Vec::new();
+        for _ in 0..blocks {
+            self.tick()?;
+            let block: mine::GenerateBlockResult = self
+                .rpc
+                .call("generateblock", &[json!(output), json!([])])?;
+            block_hashes.push(block.hash);
+        }
+        let after = self.rpc.get_mempool_info()?.size;
+        if after != before {
+            return Err(CapstoneError::Usage(format!(
+                "the mempool went from {} to {} transactions while mining empty blocks",
+                before, after
+            )));
+        }
+        Ok(block_hashes)
+    }
+
+    // Mine `blocks` blocks paying the mining target.
+    fn mine_rewards(&self, blocks: u64) -> Result<Vec<BlockHash>> {
+        match &self.mining_target {
+            MiningTarget::Address(address) => self.advance(blocks, address),
+            // The first address takes the first ceil(blocks / N) blocks, and so on
+            MiningTarget::Rotation(addresses) => {
+                let batch = blocks.div_ceil(addresses.len() as u64).max(1);
+                let mut block_hashes = Vec::with_capacity(blocks as usize);
+                for address in addresses {
+                    let n = batch.min(blocks - block_hashes.len() as u64);
+                    if n == 0 {
+                        break;
+                    }
+                    block_hashes.extend(self.advance(n, address)?);
+                }
+                Ok(block_hashes)
+            }
+            MiningTarget::Descriptor {
+                descriptor,
+                ranged: false,
+                ..
+            } => {
+                if self.empty_blocks {
+                    return self.mine_empty(blocks, descriptor);
+                }
+                if self.clock.is_none() {
+                    return Ok(self
+                        .rpc
+                        .call("generatetodescriptor", &[json!(blocks), json!(descriptor)])?);
+                }
+                let mut block_hashes = Vec::new();
+                for _ in 0..blocks {
+                    self.tick()?;
+                    let hashes: Vec<BlockHash> = self
+                        .rpc
+                        .call("generatetodescriptor", &[json!(1), json!(descriptor)])?;
+                    block_hashes.extend(hashes);
+                }
+                Ok(block_hashes)
+            }
+            // generatetodescriptor refuses ranged descriptors, so each block pays
+            // the address at its own height's index
+            MiningTarget::Descriptor { descriptor, .. } => {
+                let height = self.rpc.get_block_count()?;
+                let addresses: Vec<Address<NetworkUnchecked>> = self.rpc.call(
+                    "deriveaddresses",
+                    &[json!(descriptor), json!([height + 1, height + blocks])],
+                )?;
+                let mut block_hashes = Vec::with_capacity(addresses.len());
+                for address in addresses {
+                    block_hashes.extend(self.advance(1, &address.assume_checked())?);
+                }
+                Ok(block_hashes)
+            }
+        }
+    }
+
+    // Whether --mocktime/--block-interval set the node's clock this run
+    pub fn is_mock_time(&self) -> bool {
+        self.clock.is_some()
+    }
+
+    // Keep block rewards out of the Miner wallet: load (or create) a third
+    // wallet, Mining, and send every reward there. `fund_miner` then pays the
+    // Miner from it, so payments spend ordinary outputs instead of coinbases.
+    pub fn use_mining_wallet(&mut self) -> Result<()> {
+        create_or_load_wallet(&self.rpc, MINING_WALLET)?;
+        let mining_rpc = wallet_client(MINING_WALLET)?;
+        self.miner_address = mining_rpc.get_new_address(None, None)?.assume_checked();
+        self.mining_target = MiningTarget::Address(self.miner_address.clone());
+        self.mining_rpc = Some(mining_rpc);
+        say!("'Mining' wallet is ready, block rewards go to it.");
+        Ok(())
+    }
+
+    // Spread fund_miner's rewards over `count` addresses of the reward wallet,
+    // labeled mining-0, mining-1, ... The labels are looked up first and only
+    // missing ones get a new address, so later runs mine to the same
+    // addresses in the same order. Lookups and creations each go in one
+    // JSON-RPC batch.
+    pub fn rotate_mining_addresses(&mut self, count: u64) -> Result<()> {
+        if count == 0 {
+            return Err(CapstoneError::Usage(
+                "--rotate-mining-addresses needs at least one address".into(),
+            ));
+        }
+        let wallet_rpc = self.mining_rpc.as_ref().unwrap_or(&self.miner_rpc);
+        let labels: Vec<String> = (0..count).map(|i| format!("mining-{}", i)).collect();
+
+        let lookups: Vec<Vec<serde_json::Value>> =
+            labels.iter().map(|label| vec![json!(label)]).collect();
+        let existing = batch_call::<HashMap<String, serde_json::Value>>(
+            wallet_rpc,
+            "getaddressesbylabel",
+            &lookups,
+        )?;
+        // An unknown label is an error; those get a new address
+        let mut addresses: Vec<Option<Address<NetworkUnchecked>>> = existing
+            .into_iter()
+            .map(|found| {
+                found
+                    .ok()
+                    .and_then(|found| found.into_keys().min())
+                    .and_then(|address| address.parse().ok())
+            })
+            .collect();
+        let missing: Vec<usize> = (0..addresses.len())
+            .filter(|&i| addresses[i].is_none())
+            .collect();
+        if !missing.is_empty() {
+            let requests: Vec<Vec<serde_json::Value>> =
+                missing.iter().map(|&i| vec![json!(labels[i])]).collect();
+            let created =
+                batch_call::<Address<NetworkUnchecked>>(wallet_rpc, "getnewaddress", &requests)?;
+            for (i, address) in missing.into_iter().zip(created) {
+                addresses[i] = Some(address.map_err(bitcoincore_rpc::Error::JsonRpc)?);
+            }
+        }
+
+        let addresses = addresses
+            .into_iter()
+            .flatten()
+            .map(|address| {
+                address
+                    .require_network(self.network)
+                    .map_err(|e| CapstoneError::Usage(format!("mining address: {}", e)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        say!(
+            "Mining rewards rotate over {} addresses, {} to {}.",
+            addresses.len(),
+            addresses[0],
+            addresses[addresses.len() - 1]
+        );
+        self.mining_target = MiningTarget::Rotation(addresses);
+        Ok(())
+    }
+
+    // Which wallet does what, for the report.
+    pub fn wallet_topology(&self) -> String {
+        match &self.mining_rpc {
+            Some(_) => "Mining (block rewards) -> Miner (payer) -> Trader (payee)".into(),
+            None => "Miner (block rewards and payer) -> Trader (payee)".into(),
+        }
+    }
+
+    // Send fund_miner's rewards to `descriptor` instead of the Miner address.
+    // The descriptor's checksum is checked (or added) by getdescriptorinfo, and
+    // its first address tells which of our wallets, if any, owns it.
+    pub fn mine_to_descriptor(&mut self, descriptor: &str) -> Result<()> {
+        let info: DescriptorInfo = self
+            .rpc
+            .call("getdescriptorinfo", &[json!(descriptor)])
+            .map_err(|e| {
+                CapstoneError::Usage(format!("invalid descriptor '{}': {}", descriptor, e))
+            })?;
+        if let Some((_, given)) = descriptor.rsplit_once('#') {
+            if given != info.checksum {
+                return Err(CapstoneError::Usage(format!(
+                    "descriptor checksum '{}' does not match the computed '{}'",
+                    given, info.checksum
+                )));
+            }
+        }
+        // getdescriptorinfo normalizes private keys away, keep what was given
+        let base = descriptor.split('#').next().unwrap_or(descriptor);
+        let descriptor = format!("{}#{}", base, info.checksum);
+
+        let range = info.isrange.then(|| json!([0, 0]));
+        let mut params = vec![json!(descriptor)];
+        params.extend(range);
+        let first: Vec<Address<NetworkUnchecked>> = self.rpc.call("deriveaddresses", &params)?;
+        let first = first
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                CapstoneError::Usage(format!("descriptor '{}' has no address", descriptor))
+            })?
+            .require_network(self.network)
+            .map_err(|e| CapstoneError::Usage(format!("descriptor '{}': {}", descriptor, e)))?;
+        let owns = |wallet_rpc: &Client| -> Result<bool> {
+            Ok(wallet_rpc.get_address_info(&first)?.is_mine == Some(true))
+        };
+        let wallet = if owns(&self.miner_rpc)? {
+            Some("Miner")
+        } else if owns(&self.trader_rpc)? {
+            Some("Trader")
+        } else {
+            None
+        };
+        say!(
+            "Mining rewards go to {} ({}, {}).",
+            descriptor,
+            if info.isrange {
+                "ranged"
+            } else {
+                "single address"
+            },
+            wallet.map_or("not owned by a loaded wallet".to_string(), |w| format!(
+                "owned by {}",
+                w
+            ))
+        );
+
+        self.mining_target = MiningTarget::Descriptor {
+            descriptor,
+            ranged: info.isrange,
+            wallet,
+        };
+        Ok(())
+    }
+}
+
+// The wallet receiving block rewards with --mining-wallet
+pub const MINING_WALLET: &str = "Mining";
+
+// A coinbase output can be spent once it has this many confirmations
+pub const COINBASE_MATURITY: u64 = 100;
+// Satoshis per BTC
+const COIN: u64 = 100_000_000;
+// What the Mining wallet keeps on top of a transfer to pay its fee
+const FUNDING_FEE_BUFFER: Amount = Amount::from_sat(100_000);
+// fund_miner gives up after this many blocks
+const MAX_FUNDING_BLOCKS: u64 = 1_000;
+
+// How many blocks `fund_wallet` mines next towards `target`, `mined` in. The
+// immature coinbases are only looked up when the balance falls short.
+fn funding_batch(
+    trusted: Amount,
+    target: Amount,
+    height: u64,
+    subsidy: Amount,
+    mined: u64,
+    coinbases: impl FnOnce() -> Result<Vec<maturity::ImmatureCoinbase>>,
+) -> Result<u64> {
+    let remaining = MAX_FUNDING_BLOCKS.saturating_sub(mined);
+    if remaining == 0 || subsidy == Amount::ZERO {
+        return Err(CapstoneError::FundingStalled {
+            mined,
+            balance: trusted,
+            target,
+        });
+    }
+    let shortfall = target.checked_sub(trusted).unwrap_or(Amount::ZERO);
+    Ok(
+        maturity::blocks_needed(&coinbases()?, height, shortfall, subsidy)
+            .max(1)
+            .min(remaining),
+    )
+}
+
+// Blocks between subsidy halvings: 150 on regtest, 210,000 elsewhere.
+pub fn halving_interval(network: Network) -> u64 {
+    match network {
+        Network::Regtest => 150,
+        _ => 210_000,
+    }
+}
+
+// The block reward at `height`: 50 BTC, halved every `halving_interval` blocks.
+pub fn block_subsidy(height: u64, network: Network) -> Amount {
+    let halvings = height / halving_interval(network);
+    if halvings >= 64 {
+        return Amount::ZERO;
+    }
+    Amount::from_sat(Amount::from_int_btc(50).to_sat() >> halvings)
+}
+
+// One `method` call per parameter list, sent as a single JSON-RPC batch. Each
+// call succeeds or fails on its own; only the batch itself failing is an error.
+pub fn batch_call<T: for<'de> Deserialize<'de>>(
+    rpc: &Client,
+    method: &str,
+    params: &[Vec<serde_json::Value>],
+) -> Result<Vec<std::result::Result<T, jsonrpc::Error>>> {
+    let client = rpc.get_jsonrpc_client();
+    let raw = params
+        .iter()
+        .map(|params| params.iter().map(to_raw_value).collect())
+        .collect::<serde_json::Result<Vec<Vec<_>>>>()?;
+    let requests: Vec<_> = raw
+        .iter()
+        .map(|params| client.build_request(method, params))
+        .collect();
+    let responses = client
+        .send_batch(&requests)
+        .map_err(bitcoincore_rpc::Error::JsonRpc)?;
+    Ok(responses
+        .into_iter()
+        .map(|response| match response {
+            Some(response) => response.result(),
+            None => Err(jsonrpc::Error::WrongBatchResponseSize),
+        })
+        .collect())
+}
+
+// First pause of `retry`, doubled after every failed attempt
+const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+// Run `f` up to `attempts` times, backing off between failures, for calls that
+// may fail transiently, e.g. while the node is busy or restarting. `what`
+// names the call in the warnings; the last error is returned.
+pub fn retry<T>(what: &str, attempts: u32, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut pause = RETRY_BACKOFF;
+    for _ in 1..attempts {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                say!("Warning: {} failed ({}), retrying in {:?}.", what, e, pause);
+                thread::sleep(pause);
+                pause *= 2;
+            }
+        }
+    }
+    f()
+}
+
+// RPC client for `url`, with the transport's default 15s HTTP timeout unless
+// `timeout` is given. Its calls are counted for --metrics-addr.
+fn client(url: &str, timeout: Option<Duration>) -> Result<Client> {
+    let mut builder = SimpleHttpTransport::builder()
+        .url(url)
+        .map_err(|e| bitcoincore_rpc::Error::JsonRpc(e.into()))?
+        .auth(RPC_USER, Some(RPC_PASS));
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    Ok(Client::from_jsonrpc(jsonrpc::Client::with_transport(
+        metrics::Metered(builder.build()),
+    )))
+}
+
+// RPC client for another node, e.g. the second one in propagation-test. It
+// takes the same credentials as ours.
+pub fn node_client(url: &str) -> Result<Client> {
+    client(url, None)
+}
+
+// RPC client bound to a single wallet's endpoint
+pub fn wallet_client(wallet_name: &str) -> Result<Client> {
+    client(&format!("{}/wallet/{}", RPC_URL, wallet_name), None)
+}
+
+// A client whose calls aren't counted, for the metrics scrape's own lookups.
+// `path` is appended to the node's URL, e.g. "/wallet/Miner".
+pub fn plain_client(path: &str) -> Result<Client> {
+    let auth = Auth::UserPass(RPC_USER.to_owned(), RPC_PASS.to_owned());
+    Ok(Client::new(&format!("{}{}", RPC_URL, path), auth)?)
+}
+
+// RPC client for calls the node holds open, like waitfornewblock. It has an
+// HTTP timeout longer than the default 15s and, since a held call blocks its
+// connection, shouldn't be shared with anything else.
+pub fn long_poll_client(timeout: Duration) -> Result<Client> {
+    client(RPC_URL, Some(timeout))
+}
+
+// Helper function
+fn create_or_load_wallet(rpc: &Client, wallet_name: &str) -> bitcoincore_rpc::Result<()> {
+    // check if the wallet is loaded before
+    if rpc.list_wallets()?.contains(&wallet_name.to_string()) {
+        say!("wallet {} is already loaded", wallet_name);
+        return Ok(());
+    }
+
+    // if it is not loaded before
+    match rpc.load_wallet(wallet_name) {
+        Ok(_) => {
+            say!(
+                "Successfully loaded existing wallet '{}' from disk.",
+                wallet_name
+            );
+            Ok(())
+        }
+        // If loading fails because it doesn't exist, create it.
+        Err(e) => {
+            say!(
+                "Wallet '{}' not found on disk. Creating a new one.",
+                wallet_name
+            );
+            rpc.create_wallet(wallet_name, None, None, None, None)?;
+            say!("Wallet '{}' created successfully.", wallet_name);
+            Ok(())
+        }
+
+        // Handle other potential errors during loading.
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::OutPoint;
+    use maturity::ImmatureCoinbase;
+
+    // A regtest wallet receiving every block reward, as `fund_wallet` sees it
+    struct Chain {
+        height: u64,
+        trusted: Amount,
+        immature: Vec<ImmatureCoinbase>,
+    }
+
+    impl Chain {
+        fn mine(&mut self, blocks: u64) {
+            for _ in 0..blocks {
+                self.height += 1;
+                self.immature.push(ImmatureCoinbase {
+                    outpoint: OutPoint::new(Txid::all_zeros(), self.height as u32),
+                    amount: block_subsidy(self.height, Network::Regtest),
+                    height: self.height,
+                    mature_at: self.height + COINBASE_MATURITY,
+                });
+                let height = self.height;
+                let (matured, immature): (Vec<_>, Vec<_>) = self
+                    .immature
+                    .drain(..)
+                    .partition(|coinbase| coinbase.mature_at <= height);
+                self.trusted += matured.iter().map(|coinbase| coinbase.amount).sum();
+                self.immature = immature;
+            }
+        }
+
+        // `fund_wallet`'s loop, returning the batches it mined
+        fn fund(&mut self, target: Amount) -> Result<Vec<u64>> {
+            let mut batches = Vec::new();
+            let mut mined = 0;
+            while self.trusted < target {
+                let subsidy = block_subsidy(self.height + 1, Network::Regtest);
+                let immature = self.immature.clone();
+                let batch =
+                    funding_batch(self.trusted, target, self.height, subsidy, mined, || {
+                        Ok(immature)
+                    })?;
+                self.mine(batch);
+                mined += batch;
+                batches.push(batch);
+            }
+            Ok(batches)
+        }
+    }
+
+    fn btc(btc: u64) -> Amount {
+        Amount::from_int_btc(btc)
+    }
+
+    fn target() -> Amount {
+        btc(20) + FUNDING_FEE_BUFFER
+    }
+
+    #[test]
+    fn from_zero_mines_one_reward_and_its_maturity() {
+        let mut chain = Chain {
+            height: 0,
+            trusted: Amount::ZERO,
+            immature: Vec::new(),
+        };
+        assert_eq!(chain.fund(target()).unwrap(), vec![101]);
+        assert_eq!(chain.trusted, btc(50));
+    }
+
+    #[test]
+    fn already_sufficient_mines_nothing() {
+        let mut chain = Chain {
+            height: 200,
+            trusted: btc(40),
+            immature: Vec::new(),
+        };
+        assert!(chain.fund(target()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn partially_funded_waits_for_immature_rewards() {
+        // 10 BTC spendable, and a reward mined 70 blocks ago
+        let mut chain = Chain {
+            height: 0,
+            trusted: Amount::ZERO,
+            immature: Vec::new(),
+        };
+        chain.mine(70);
+        chain.trusted = btc(10);
+        chain.immature.truncate(1);
+        assert_eq!(chain.fund(target()).unwrap(), vec![31]);
+        assert_eq!(chain.trusted, btc(60));
+    }
+
+    #[test]
+    fn partially_funded_mines_new_rewards_for_the_rest() {
+        // 15 BTC short with nothing maturing: one reward, after the halving at 150
+        let mut chain = Chain {
+            height: 149,
+            trusted: btc(5),
+            immature: Vec::new(),
+        };
+        assert_eq!(chain.fund(target()).unwrap(), vec![101]);
+        assert_eq!(chain.trusted, btc(30));
+    }
+
+    #[test]
+    fn gives_up_at_the_block_cap() {
+        let stalled = funding_batch(
+            Amount::ZERO,
+            target(),
+            5_000,
+            btc(1),
+            MAX_FUNDING_BLOCKS,
+            || Ok(Vec::new()),
+        );
+        assert!(matches!(
+            stalled,
+            Err(CapstoneError::FundingStalled { mined: 1_000, .. })
+        ));
+        let no_subsidy = funding_batch(Amount::ZERO, target(), 9_600, Amount::ZERO, 0, || {
+            Ok(Vec::new())
+        });
+        assert!(no_subsidy.is_err());
+        // The last batch is cut to what the cap leaves
+        let last = funding_batch(
+            Amount::ZERO,
+            target(),
+            5_000,
+            btc(1),
+            990,
+            || Ok(Vec::new()),
+        );
+        assert_eq!(last.unwrap(), 10);
+    }
+
+    #[test]
+    fn subsidy_halves() {
+        assert_eq!(block_subsidy(149, Network::Regtest), btc(50));
+        assert_eq!(block_subsidy(150, Network::Regtest), btc(25));
+        assert_eq!(block_subsidy(210_000, Network::Bitcoin), btc(25));
+        assert_eq!(block_subsidy(150 * 64, Network::Regtest), Amount::ZERO);
+    }
+}
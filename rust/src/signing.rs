@@ -0,0 +1,650 @@
+use crate::cli::SighashType;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::files;
+use bitcoincore_rpc::bitcoin::bip32::{DerivationPath, Xpriv};
+use bitcoincore_rpc::bitcoin::consensus::encode;
+use bitcoincore_rpc::bitcoin::hashes::Hash;
+use bitcoincore_rpc::bitcoin::hex::{DisplayHex, FromHex};
+use bitcoincore_rpc::bitcoin::secp256k1::{Message, Secp256k1};
+use bitcoincore_rpc::bitcoin::sighash::{EcdsaSighashType, SighashCache};
+use bitcoincore_rpc::bitcoin::{
+    ecdsa, Address, Amount, PrivateKey, PublicKey, Script, ScriptBuf, Transaction, Txid,
+};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+use std::fmt;
+
+// Result of signrawtransactionwithwallet (and signrawtransactionwithkey, which
+// returns the same shape).
+#[derive(Debug, Clone, Deserialize)]
+pub struct SigningResult {
+    pub hex: String,
+    pub complete: bool,
+    #[serde(default)]
+    pub errors: Vec<SigningError>,
+}
+
+// Why one input could not be signed.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SigningError {
+    pub txid: Txid,
+    pub vout: u32,
+    #[serde(rename = "scriptSig")]
+    pub script_sig: String,
+    pub sequence: u32,
+    pub error: String,
+}
+
+impl SigningError {
+    // Short category for Core's error text, so the table is readable at a glance.
+    pub fn kind(&self) -> &'static str {
+        let error = self.error.to_lowercase();
+        if error.contains("missing key") || error.contains("unable to sign") {
+            "missing key"
+        } else if error.contains("not found or already spent") {
+            "missing UTXO"
+        } else if error.contains("need more signatures") {
+            "needs more signatures"
+        } else if error.contains("script") || error.contains("witness") {
+            "invalid script"
+        } else {
+            "other"
+        }
+    }
+}
+
+impl SigningResult {
+    // Per-input table of what failed and why.
+    pub fn render_errors(&self) -> String {
+        let mut out = format!(
+            "{:<70} {:<12} {:<22} {}\n",
+            "input", "sequence", "problem", "node error"
+        );
+        for e in &self.errors {
+            out.push_str(&format!(
+                "{:<70} {:<12} {:<22} {}\n",
+                format!("{}:{}", e.txid, e.vout),
+                format!("0x{:08x}", e.sequence),
+                e.kind(),
+                e.error
+            ));
+        }
+        out
+    }
+
+    pub fn report_errors(&self) {
+        say!("Signing incomplete, {} input(s) failed:", self.errors.len());
+        say!("{}", self.render_errors().trim_end());
+    }
+
+    // The signed hex, or an error after printing which inputs failed.
+    pub fn into_signed_hex(self) -> Result<String> {
+        if self.complete {
+            return Ok(self.hex);
+        }
+        self.report_errors();
+        Err(CapstoneError::SigningIncomplete(self.errors))
+    }
+}
+
+// Sign every input the wallet has keys for.
+pub fn sign_with_wallet(rpc: &Client, hex: &str, sighash: SighashType) -> Result<SigningResult> {
+    Ok(rpc.call(
+        "signrawtransactionwithwallet",
+        &[json!(hex), json!(null), json!(sighash.as_rpc_str())],
+    )?)
+}
+
+// Name of the sighash type in the last byte of a signature.
+pub fn sighash_name(byte: u8) -> String {
+    let base = match byte & 0x7f {
+        0x01 => "ALL",
+        0x02 => "NONE",
+        0x03 => "SINGLE",
+        _ => return format!("unknown (0x{:02x})", byte),
+    };
+    if byte & 0x80 != 0 {
+        format!("{}|ANYONECANPAY", base)
+    } else {
+        base.to_string()
+    }
+}
+
+// Sighash type an input was signed with, read off its first signature: the
+// witness for segwit inputs (a bare 64-byte taproot signature means DEFAULT),
+// the first push of the scriptSig otherwise.
+pub fn input_sighash(witness: &[Vec<u8>], script_sig: &[u8]) -> String {
+    let signature = match witness.first() {
+        Some(first) if first.len() == 64 => return "DEFAULT".to_string(),
+        Some(first) => Some(first.clone()),
+        None => Script::from_bytes(script_sig)
+            .instructions()
+            .next()
+            .and_then(|instruction| instruction.ok())
+            .and_then(|instruction| instruction.push_bytes().map(|b| b.as_bytes().to_vec())),
+    };
+    match signature.as_deref().and_then(|sig| sig.last()) {
+        Some(byte) => sighash_name(*byte),
+        None => "unsigned".to_string(),
+    }
+}
+
+// Catch the combinations Core would only fail on while signing: a taproot input
+// signed with SIGHASH_SINGLE must have an output at the same index (legacy and
+// segwit v0 inputs fall back to the old "sign the number 1" behaviour instead).
+pub fn check_sighash(sighash: SighashType, taproot_inputs: &[bool], outputs: usize) -> Result<()> {
+    if !sighash.is_single() {
+        return Ok(());
+    }
+    if let Some(i) = taproot_inputs
+        .iter()
+        .enumerate()
+        .position(|(i, taproot)| *taproot && i >= outputs)
+    {
+        return Err(CapstoneError::Usage(format!(
+            "{} is invalid for taproot input #{}: there is no output #{} to commit to",
+            sighash.as_rpc_str(),
+            i,
+            i
+        )));
+    }
+    Ok(())
+}
+
+pub fn decode_tx(hex: &str) -> Result<Transaction> {
+    let bytes = Vec::<u8>::from_hex(hex)
+        .map_err(|e| CapstoneError::Usage(format!("invalid transaction hex: {}", e)))?;
+    Ok(encode::deserialize(&bytes)?)
+}
+
+// `bytes` as hex, once they decode to a transaction whose txid is `txid`:
+// a report or state file never carries hex for some other transaction.
+pub fn verified_hex(bytes: &[u8], txid: &Txid) -> Result<String> {
+    let tx: Transaction = encode::deserialize(bytes)?;
+    let computed = tx.txid();
+    if computed != *txid {
+        return Err(CapstoneError::TxidMismatch {
+            expected: *txid,
+            computed,
+        });
+    }
+    Ok(bytes.to_lower_hex_string())
+}
+
+// Value and scriptPubKey of each coin `tx` spends, from gettxout.
+pub fn spent_outputs(rpc: &Client, tx: &Transaction) -> Result<Vec<(Amount, ScriptBuf)>> {
+    let mut spent = Vec::with_capacity(tx.input.len());
+    for input in &tx.input {
+        let outpoint = input.previous_output;
+        let txout = rpc
+            .get_tx_out(&outpoint.txid, outpoint.vout, Some(true))?
+            .ok_or_else(|| {
+                CapstoneError::Usage(format!("input {} is spent or unknown", outpoint))
+            })?;
+        spent.push((txout.value, ScriptBuf::from_bytes(txout.script_pub_key.hex)));
+    }
+    Ok(spent)
+}
+
+// `check_sighash` for a raw transaction, looking up which inputs are taproot.
+pub fn check_sighash_for_tx(rpc: &Client, hex: &str, sighash: SighashType) -> Result<()> {
+    let tx = decode_tx(hex)?;
+    let taproot: Vec<bool> = spent_outputs(rpc, &tx)?
+        .iter()
+        .map(|(_, script)| script.is_p2tr())
+        .collect();
+    check_sighash(sighash, &taproot, tx.output.len())
+}
+
+// A WIF private key supplied by the user. It is never printed, and the memory
+// is overwritten when it is dropped.
+pub struct SigningKey(String);
+
+impl SigningKey {
+    // Load from `file:<path>` or `env:<VAR>`; keys are never taken from the
+    // command line itself, where they would end up in shell history.
+    pub fn load(source: &str) -> Result<Self> {
+        let wif = match source.split_once(':') {
+            Some(("file", path)) => files::read_to_string(path, "key file")?,
+            Some(("env", var)) => std::env::var(var).map_err(|_| {
+                CapstoneError::Usage(format!("environment variable {} is not set", var))
+            })?,
+            _ => {
+                return Err(CapstoneError::Usage(
+                    "--sign-with-key expects file:<path> or env:<VAR>".into(),
+                ))
+            }
+        };
+        Ok(SigningKey(wif))
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SigningKey(<redacted>)")
+    }
+}
+
+impl Drop for SigningKey {
+    fn drop(&mut self) {
+        let mut bytes = std::mem::take(&mut self.0).into_bytes();
+        bytes.iter_mut().for_each(|b| *b = 0);
+        // Keep the zeroing from being optimised away
+        std::hint::black_box(&bytes);
+    }
+}
+
+// The prevtxs entries signrawtransactionwithkey needs for each input of `hex`,
+// looked up with gettxout. Segwit signatures commit to the amount, so it is
+// always included.
+pub fn prevtxs_for(rpc: &Client, hex: &str) -> Result<Vec<serde_json::Value>> {
+    let tx = decode_tx(hex)?;
+    let spent = spent_outputs(rpc, &tx)?;
+    Ok(tx
+        .input
+        .iter()
+        .zip(spent)
+        .map(|(input, (amount, script))| {
+            json!({
+                "txid": input.previous_output.txid,
+                "vout": input.previous_output.vout,
+                "scriptPubKey": script.as_bytes().to_lower_hex_string(),
+                "amount": amount.to_btc(),
+            })
+        })
+        .collect())
+}
+
+// Sign with a key no wallet holds.
+pub fn sign_with_key(
+    rpc: &Client,
+    hex: &str,
+    key: &SigningKey,
+    sighash: SighashType,
+) -> Result<SigningResult> {
+    let prevtxs = prevtxs_for(rpc, hex)?;
+    Ok(rpc.call(
+        "signrawtransactionwithkey",
+        // serde_json's copy of the key for the request can't be wiped, only ours
+        &[
+            json!(hex),
+            json!([key.0.trim()]),
+            json!(prevtxs),
+            json!(sighash.as_rpc_str()),
+        ],
+    )?)
+}
+
+// The private key behind one of a wallet's addresses, for scripts the wallet
+// can't sign itself. Descriptor wallets hand out their xprvs through
+// listdescriptors; legacy wallets still have dumpprivkey.
+pub fn wallet_key(wallet_rpc: &Client, address: &Address) -> Result<(PublicKey, PrivateKey)> {
+    let info = wallet_rpc.get_address_info(address)?;
+    let pubkey = info
+        .pubkey
+        .ok_or_else(|| CapstoneError::Usage(format!("{} is not a single-key address", address)))?;
+    let not_found = || {
+        CapstoneError::Usage(format!(
+            "no private key for {} in the wallet (is it watch-only?)",
+            address
+        ))
+    };
+
+    let listed: serde_json::Value = match wallet_rpc.call("listdescriptors", &[json!(true)]) {
+        Ok(listed) => listed,
+        Err(_) => {
+            let wif: String = wallet_rpc.call("dumpprivkey", &[json!(address)])?;
+            let key = PrivateKey::from_wif(&wif).map_err(|_| not_found())?;
+            return Ok((pubkey, key));
+        }
+    };
+    let key_path = info.hd_key_path.ok_or_else(not_found)?;
+
+    let secp = Secp256k1::new();
+    let descriptors = listed["descriptors"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    for descriptor in descriptors {
+        let desc = descriptor["desc"].as_str().unwrap_or_default();
+        let Some(xpriv) = descriptor_xpriv(desc) else {
+            continue;
+        };
+        let (origin, xpriv) = xpriv;
+        // The key in the descriptor sits at `origin`; derive the rest from it
+        let Some(rest) = key_path.as_ref().strip_prefix(origin.as_ref()) else {
+            continue;
+        };
+        let Ok(child) = xpriv.derive_priv(&secp, &rest.to_vec()) else {
+            continue;
+        };
+        let key = child.to_priv();
+        if PublicKey::from_private_key(&secp, &key) == pubkey {
+            return Ok((pubkey, key));
+        }
+    }
+    Err(not_found())
+}
+
+// The xprv in a private descriptor such as `wpkh([fp/84h/1h/0h]tprv.../0/*)`
+// or `wpkh(tprv.../84h/1h/0h/0/*)`, with the path it sits at relative to the
+// master key (empty when there is no origin).
+fn descriptor_xpriv(desc: &str) -> Option<(DerivationPath, Xpriv)> {
+    let start = desc.find("prv")?.checked_sub(1)?;
+    let before = &desc[..start];
+    let origin = match (before.rfind('['), before.rfind(']')) {
+        (Some(open), Some(close)) if close + 1 == start => {
+            let path = &before[open + 1..close];
+            let path = path.split_once('/').map_or("", |(_, path)| path);
+            format!("m/{}", path).replace('h', "'").parse().ok()?
+        }
+        _ => DerivationPath::master(),
+    };
+    let key: String = desc[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect();
+    Some((origin, key.parse().ok()?))
+}
+
+// SIGHASH_ALL signature for spending a P2WSH output with `witness_script`,
+// DER-encoded with the sighash byte appended as it goes in the witness. BIP143
+// signs P2WPKH the same way, with the key's P2PKH script as `witness_script`.
+pub fn sign_p2wsh(
+    tx: &Transaction,
+    input_index: usize,
+    witness_script: &Script,
+    value: Amount,
+    key: &PrivateKey,
+) -> Result<Vec<u8>> {
+    let sighash = SighashCache::new(tx)
+        .p2wsh_signature_hash(input_index, witness_script, value, EcdsaSighashType::All)
+        .map_err(|e| CapstoneError::Usage(format!("sighash for input {}: {}", input_index, e)))?;
+    let message = Message::from_digest(sighash.to_byte_array());
+    let signature =
+        ecdsa::Signature::sighash_all(Secp256k1::new().sign_ecdsa(&message, &key.inner));
+    Ok(signature.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mockrpc;
+    use bitcoincore_rpc::bitcoin::{
+        absolute, secp256k1, transaction, Network, OutPoint, Sequence, TxIn, TxOut, Witness,
+    };
+
+    // signrawtransactionwithwallet on a watch-only wallet for a two-input
+    // transaction, the second input spending a coin that is already gone
+    const INCOMPLETE: &str = r#"{
+        "hex": "0200000002af8b1b7eba2237bbe544dca3851de5de251d67dff500a5a69f0e4324b405f0540000000000fdffffffa05728ac3e96a4bf540aaeae0682aae534016b2c3e1443dab3c2327ccd1a823b0100000000fdffffff0100e1f505000000001600147b4d578d3dbb8e739e378e4f3fb3f95ae01f5c2d65000000",
+        "complete": false,
+        "errors": [
+            {
+                "txid": "54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af",
+                "vout": 0,
+                "witness": [],
+                "scriptSig": "",
+                "sequence": 4294967293,
+                "error": "Unable to sign input, invalid stack size (possibly missing key)"
+            },
+            {
+                "txid": "3b821acd7c32c2b3da143e2c6b0134e5aa8206aeae0a54bfa4963e73ac2857a0",
+                "vout": 1,
+                "scriptSig": "",
+                "sequence": 4294967293,
+                "error": "Input not found or already spent"
+            }
+        ]
+    }"#;
+
+    // BIP143's native P2WPKH example: the second input spends 6 BTC from a
+    // P2WPKH output
+    const BIP143_UNSIGNED: &str = "0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000";
+    const BIP143_KEY: &str = "619c335025c7f4012e556c2a58b2506e30b8511b53ade95ea316fd8c3286feb9";
+    const BIP143_SIGHASH: &str = "c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670";
+    const BIP143_SIGNATURE: &str = "304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee01";
+    const BIP143_PUBKEY: &str =
+        "025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee6357";
+
+    fn bip143_key() -> PrivateKey {
+        let secret =
+            secp256k1::SecretKey::from_slice(&Vec::<u8>::from_hex(BIP143_KEY).unwrap()).unwrap();
+        PrivateKey::new(secret, Network::Testnet)
+    }
+
+    #[test]
+    fn signs_the_bip143_p2wpkh_example() {
+        let tx = decode_tx(BIP143_UNSIGNED).unwrap();
+        let key = bip143_key();
+        let pubkey = PublicKey::from_private_key(&Secp256k1::new(), &key);
+        assert_eq!(pubkey.to_string(), BIP143_PUBKEY);
+        let value = Amount::from_int_btc(6);
+        let script_pubkey = ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash().unwrap());
+        let script_code = script_pubkey.p2wpkh_script_code().unwrap();
+        let sighash = SighashCache::new(&tx)
+            .p2wpkh_signature_hash(1, &script_pubkey, value, EcdsaSighashType::All)
+            .unwrap();
+        assert_eq!(sighash.to_string(), BIP143_SIGHASH);
+
+        let signature = sign_p2wsh(&tx, 1, &script_code, value, &key).unwrap();
+        assert_eq!(signature.to_lower_hex_string(), BIP143_SIGNATURE);
+        let witness = Witness::from_slice(&[signature, pubkey.to_bytes()]);
+        assert_eq!(
+            witness.iter().map(<[u8]>::to_vec).collect::<Vec<_>>(),
+            [
+                Vec::<u8>::from_hex(BIP143_SIGNATURE).unwrap(),
+                Vec::<u8>::from_hex(BIP143_PUBKEY).unwrap()
+            ]
+        );
+    }
+
+    // A descriptor wallet holding `wpkh(tprv/84h/1h/0h/0/*)` from a fixed seed,
+    // asked about its address at index 3
+    fn descriptor_wallet() -> (Client, Address, PrivateKey) {
+        let secp = Secp256k1::new();
+        let master = Xpriv::new_master(Network::Regtest, &[7; 32]).unwrap();
+        let account_path: DerivationPath = "m/84'/1'/0'".parse().unwrap();
+        let account = master.derive_priv(&secp, &account_path).unwrap();
+        let key_path: DerivationPath = "m/84'/1'/0'/0/3".parse().unwrap();
+        let key = master.derive_priv(&secp, &key_path).unwrap().to_priv();
+        let pubkey = PublicKey::from_private_key(&secp, &key);
+        let address = Address::p2wpkh(&pubkey, Network::Regtest).unwrap();
+        let descriptor = format!(
+            "wpkh([{}/84h/1h/0h]{}/0/*)",
+            master.fingerprint(&secp),
+            account
+        );
+        let info = json!({
+            "address": address,
+            "scriptPubKey": address.script_pubkey().as_bytes().to_lower_hex_string(),
+            "ismine": true,
+            "iswatchonly": false,
+            "isscript": false,
+            "iswitness": true,
+            "pubkey": pubkey,
+            "hdkeypath": "m/84h/1h/0h/0/3",
+            "labels": [""]
+        });
+        let (rpc, _) = mockrpc::client(move |method, _| match method {
+            "getaddressinfo" => Ok(info.clone()),
+            "listdescriptors" => Ok(json!({
+                "wallet_name": "Trader",
+                "descriptors": [
+                    { "desc": "pkh(tpubD6NzVbkrYhZ4WaWSyoBvQwbpLkojyoTZPRsgXELWz3Popb3qkjcJyJUGLnL4qHHoQvao8ESaAstxYSnhyswJ76uZPStJRJCTKvosUCJZL5B/0/*)" },
+                    { "desc": descriptor },
+                ]
+            })),
+            _ => panic!("unexpected {}", method),
+        });
+        (rpc, address, key)
+    }
+
+    #[test]
+    fn signs_a_p2wpkh_spend_with_a_wallet_key() {
+        let (rpc, address, expected) = descriptor_wallet();
+        let (pubkey, key) = wallet_key(&rpc, &address).unwrap();
+        // The node's network doesn't matter, the secret does
+        assert_eq!(key.inner, expected.inner);
+
+        // One input from the address, one output back to it
+        let value = Amount::from_sat(100_000);
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_byte_array([9; 32]), 1),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: value - Amount::from_sat(1_000),
+                script_pubkey: address.script_pubkey(),
+            }],
+        };
+        let script_code = address.script_pubkey().p2wpkh_script_code().unwrap();
+        let signature = sign_p2wsh(&tx, 0, &script_code, value, &key).unwrap();
+        assert_eq!(signature.last(), Some(&0x01));
+
+        let sighash = SighashCache::new(&tx)
+            .p2wpkh_signature_hash(0, &address.script_pubkey(), value, EcdsaSighashType::All)
+            .unwrap();
+        let parsed = ecdsa::Signature::from_slice(&signature).unwrap();
+        Secp256k1::new()
+            .verify_ecdsa(
+                &Message::from_digest(sighash.to_byte_array()),
+                &parsed.sig,
+                &pubkey.inner,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn legacy_wallets_hand_out_keys_with_dumpprivkey() {
+        let key = bip143_key();
+        let pubkey = PublicKey::from_private_key(&Secp256k1::new(), &key);
+        let address = Address::p2wpkh(&pubkey, Network::Regtest).unwrap();
+        let info = json!({
+            "address": address,
+            "scriptPubKey": address.script_pubkey().as_bytes().to_lower_hex_string(),
+            "pubkey": pubkey,
+            "labels": []
+        });
+        let wif = key.to_wif();
+        let (rpc, calls) = mockrpc::client(move |method, _| match method {
+            "getaddressinfo" => Ok(info.clone()),
+            "listdescriptors" => Err((
+                -4,
+                "listdescriptors is not available for non-descriptor wallets",
+            )),
+            "dumpprivkey" => Ok(json!(wif)),
+            _ => panic!("unexpected {}", method),
+        });
+        assert_eq!(wallet_key(&rpc, &address).unwrap(), (pubkey, key));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["getaddressinfo", "listdescriptors", "dumpprivkey"]
+        );
+    }
+
+    #[test]
+    fn prevtxs_carry_the_segwit_amount() {
+        let tx = decode_tx(BIP143_UNSIGNED).unwrap();
+        let (rpc, _) = mockrpc::client(|method, params| match method {
+            "gettxout" => Ok(json!({
+                "bestblock": "3b821acd7c32c2b3da143e2c6b0134e5aa8206aeae0a54bfa4963e73ac2857a0",
+                "confirmations": 6,
+                "value": if params[1] == 0 { 6.25 } else { 6.0 },
+                "scriptPubKey": {
+                    "asm": "",
+                    "hex": "00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1",
+                    "type": "witness_v0_keyhash"
+                },
+                "coinbase": false
+            })),
+            _ => panic!("unexpected {}", method),
+        });
+        let prevtxs = prevtxs_for(&rpc, BIP143_UNSIGNED).unwrap();
+        assert_eq!(
+            prevtxs[1],
+            json!({
+                "txid": tx.input[1].previous_output.txid,
+                "vout": 1,
+                "scriptPubKey": "00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1",
+                "amount": 6.0
+            })
+        );
+        assert_eq!(prevtxs[0]["amount"], 6.25);
+    }
+
+    #[test]
+    fn signing_keys_are_never_printed() {
+        let key = SigningKey(bip143_key().to_wif());
+        assert_eq!(format!("{:?}", key), "SigningKey(<redacted>)");
+    }
+
+    fn incomplete() -> SigningResult {
+        serde_json::from_str(INCOMPLETE).unwrap()
+    }
+
+    #[test]
+    fn incomplete_signing_deserializes() {
+        let result = incomplete();
+        assert!(!result.complete);
+        assert_eq!(result.errors.len(), 2);
+        assert_eq!(result.errors[1].vout, 1);
+        assert_eq!(result.errors[1].sequence, 0xfffffffd);
+        let kinds: Vec<&str> = result.errors.iter().map(SigningError::kind).collect();
+        assert_eq!(kinds, vec!["missing key", "missing UTXO"]);
+    }
+
+    #[test]
+    fn error_kinds() {
+        let kind = |error: &str| {
+            let mut e = incomplete().errors.remove(0);
+            e.error = error.to_string();
+            e.kind()
+        };
+        assert_eq!(
+            kind("CHECK(MULTI)SIG failing with non-zero signature (possibly need more signatures)"),
+            "needs more signatures"
+        );
+        assert_eq!(
+            kind("Witness program was passed an empty witness"),
+            "invalid script"
+        );
+        assert_eq!(kind("Locktime requirement not satisfied"), "other");
+    }
+
+    #[test]
+    fn the_table_has_a_row_per_failed_input() {
+        let table = incomplete().render_errors();
+        let rows: Vec<&str> = table.lines().collect();
+        assert_eq!(rows.len(), 3);
+        assert!(rows[0].starts_with("input "));
+        assert!(rows[1]
+            .starts_with("54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af:0"));
+        assert!(rows[1].contains(" 0xfffffffd "));
+        assert!(rows[1].ends_with(
+            "missing key            Unable to sign input, invalid stack size (possibly missing key)"
+        ));
+        assert!(rows[2].contains("missing UTXO"));
+    }
+
+    #[test]
+    fn incomplete_signing_is_an_error() {
+        let Err(CapstoneError::SigningIncomplete(errors)) = incomplete().into_signed_hex() else {
+            panic!("signing was incomplete");
+        };
+        assert_eq!(errors.len(), 2);
+        let complete = SigningResult {
+            hex: "00".into(),
+            complete: true,
+            errors: Vec::new(),
+        };
+        assert_eq!(complete.into_signed_hex().unwrap(), "00");
+    }
+}
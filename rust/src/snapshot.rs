@@ -0,0 +1,149 @@
+use crate::cli::Config;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::session::{self, Session};
+use crate::version::{self, Capability};
+use bitcoincore_rpc::bitcoin::BlockHash;
+use bitcoincore_rpc::jsonrpc::error::Error as JsonRpcError;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The node predates assumeutxo (getchainstates and loadtxoutset are Core 26+)
+const RPC_METHOD_NOT_FOUND: i32 = -32601;
+// Loading a signet snapshot takes minutes; the default 15s HTTP timeout won't do
+const LOAD_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoadedSnapshot {
+    pub coins_loaded: u64,
+    pub tip_hash: BlockHash,
+    pub base_height: u64,
+    pub path: String,
+}
+
+// getchainstates: one chainstate normally, two while a snapshot is in use
+// (the background one validating from genesis, and the snapshot one).
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChainStates {
+    pub headers: u64,
+    pub chainstates: Vec<ChainState>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChainState {
+    pub blocks: u64,
+    #[serde(rename = "bestblockhash")]
+    pub best_block_hash: BlockHash,
+    #[serde(rename = "verificationprogress")]
+    pub verification_progress: f64,
+    // Set on the chainstate built from a snapshot
+    #[serde(default)]
+    pub snapshot_blockhash: Option<BlockHash>,
+    // False for the snapshot chainstate until the background one catches up to it
+    pub validated: bool,
+}
+
+impl ChainStates {
+    // The chainstate loaded from a snapshot that background validation hasn't
+    // caught up with yet, if any
+    pub fn unvalidated_snapshot(&self) -> Option<&ChainState> {
+        self.chainstates
+            .iter()
+            .find(|state| state.snapshot_blockhash.is_some() && !state.validated)
+    }
+
+    // The background chainstate, validating from genesis
+    pub fn background(&self) -> Option<&ChainState> {
+        self.chainstates
+            .iter()
+            .find(|state| state.snapshot_blockhash.is_none() && self.chainstates.len() > 1)
+    }
+}
+
+// getchainstates, or None on a node without assumeutxo support.
+pub fn chain_states(rpc: &Client) -> Result<Option<ChainStates>> {
+    match rpc.call::<ChainStates>("getchainstates", &[]) {
+        Ok(states) => Ok(Some(states)),
+        Err(bitcoincore_rpc::Error::JsonRpc(JsonRpcError::Rpc(e)))
+            if e.code == RPC_METHOD_NOT_FOUND =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Modes that create, sign or mine transactions need the fully validated
+// chainstate: they are refused while a snapshot is still being checked.
+pub fn require_validated(rpc: &Client) -> Result<()> {
+    let Some(states) = chain_states(rpc)? else {
+        return Ok(());
+    };
+    match states.unvalidated_snapshot() {
+        Some(snapshot) => Err(CapstoneError::SnapshotNotValidated {
+            snapshot_height: snapshot.blocks,
+            validated_height: states.background().map_or(0, |state| state.blocks),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn print_status(states: &ChainStates) {
+    for state in &states.chainstates {
+        say!(
+            "{} chainstate: {} blocks (of {} headers), tip {}, {:.2}% verified{}",
+            if state.snapshot_blockhash.is_some() {
+                "Snapshot"
+            } else {
+                "Background"
+            },
+            state.blocks,
+            states.headers,
+            state.best_block_hash,
+            state.verification_progress * 100.0,
+            if state.validated { ", validated" } else { "" }
+        );
+    }
+}
+
+// load-snapshot: load a UTXO snapshot with loadtxoutset, then follow
+// getchainstates until background validation has caught up with the snapshot
+// or --confirm-timeout passes. Read-only modes work against the snapshot
+// chainstate as soon as it is loaded.
+pub fn run(session: &Session, config: &Config, path: &str) -> Result<()> {
+    version::require(&session.rpc, Capability::AssumeUtxo)?;
+    let client = session::long_poll_client(LOAD_TIMEOUT)?;
+    let loaded: LoadedSnapshot = client.call("loadtxoutset", &[json!(path)])?;
+    say!(
+        "Loaded {} coins from {}: the chain now starts at height {} ({}).",
+        loaded.coins_loaded,
+        loaded.path,
+        loaded.base_height,
+        loaded.tip_hash
+    );
+
+    let timeout = Duration::from_secs(config.confirm_timeout());
+    let poll = Duration::from_secs(config.poll_interval());
+    let started = Instant::now();
+    loop {
+        let Some(states) = chain_states(&session.rpc)? else {
+            return Ok(());
+        };
+        print_status(&states);
+        if states.unvalidated_snapshot().is_none() {
+            say!("Background validation is done, every mode is available.");
+            return Ok(());
+        }
+        let elapsed = started.elapsed();
+        if elapsed >= timeout {
+            say!(
+                "Background validation is still running; read-only modes (inspect-psbt, estimate, template, maturity, chain-stats, verify-chain) work meanwhile."
+            );
+            return Ok(());
+        }
+        thread::sleep(poll.min(timeout - elapsed));
+    }
+}
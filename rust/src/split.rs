@@ -0,0 +1,157 @@
+use crate::btc;
+use crate::cli::Config;
+use crate::console::say;
+use crate::dust;
+use crate::error::{CapstoneError, Result};
+use crate::fees;
+use crate::files;
+use crate::payment;
+use crate::relay;
+use crate::session::{self, Session};
+use bitcoincore_rpc::bitcoin::{Address, Amount, Txid};
+use bitcoincore_rpc::RpcApi;
+use serde::Serialize;
+use serde_json::json;
+
+// Keep each transaction well inside standardness limits (100k vB) even with
+// several inputs; larger splits are spread over more transactions.
+const MAX_OUTPUTS_PER_TX: usize = 250;
+
+// Rough size model for the up-front balance check: one P2WPKH input, the
+// P2WPKH outputs and a change output.
+const TX_OVERHEAD_VBYTES: u64 = 11;
+const INPUT_VBYTES: u64 = 68;
+const OUTPUT_VBYTES: u64 = 31;
+
+// Used for the up-front check when the node has no fee estimate (regtest),
+// matching fallbackfee=0.00001 in our bitcoin.conf.
+const FALLBACK_FEE_RATE_SAT_VB: u64 = 1;
+
+const DEFAULT_OUTPOINTS_PATH: &str = "../utxos.json";
+
+// A UTXO created by the split, as recorded in the outpoints file.
+#[derive(Debug, Serialize)]
+struct SplitOutpoint {
+    txid: Txid,
+    vout: u32,
+    address: String,
+    amount_sat: u64,
+}
+
+// Split a wallet's funds into `count` outputs of `amount` each, paying fresh
+// addresses of the same wallet, so later runs have many UTXOs to choose from.
+pub fn run(
+    session: &Session,
+    config: &Config,
+    wallet: &str,
+    count: usize,
+    amount: Amount,
+) -> Result<()> {
+    if count == 0 {
+        return Err(CapstoneError::Usage("split requires --count > 0".into()));
+    }
+    let wallet_rpc = session::wallet_client(wallet)?;
+
+    let chunks = count.div_ceil(MAX_OUTPUTS_PER_TX);
+    let total = amount * count as u64;
+
+    // Check up front that the wallet can cover N x A plus the fees
+    let fee_rate = fees::smart_fee_estimate(
+        &session.rpc,
+        config.conf_target.unwrap_or(fees::DEFAULT_CONF_TARGET),
+        config.estimate_mode,
+    )
+    .fee_rate
+    .map(|rate| rate.to_sat() / 1000)
+    .unwrap_or(FALLBACK_FEE_RATE_SAT_VB)
+    .max(1);
+    let vbytes = chunks as u64 * (TX_OVERHEAD_VBYTES + INPUT_VBYTES + OUTPUT_VBYTES)
+        + count as u64 * OUTPUT_VBYTES;
+    let estimated_fee = Amount::from_sat(vbytes * fee_rate);
+    // With --subtract-fee the outputs pay the fee themselves
+    let required = if config.subtract_fee {
+        total
+    } else {
+        total + estimated_fee
+    };
+    let balance = wallet_rpc.get_balances()?.mine.trusted;
+    if balance < required {
+        return Err(CapstoneError::Usage(format!(
+            "wallet '{}' has {} but splitting into {} x {} needs about {} (estimated fee {})",
+            wallet,
+            btc::amount(balance),
+            count,
+            btc::amount(amount),
+            btc::amount(required),
+            btc::amount(estimated_fee)
+        )));
+    }
+
+    say!("Generating {} addresses in wallet '{}'...", count, wallet);
+    let mut addresses = Vec::with_capacity(count);
+    for _ in 0..count {
+        addresses.push(wallet_rpc.get_new_address(None, None)?.assume_checked());
+    }
+    let outputs: Vec<(Address, Amount)> = addresses.iter().map(|a| (a.clone(), amount)).collect();
+    dust::check_outputs(
+        &outputs,
+        &relay::policy(&session.rpc, &relay::outputs(&outputs))?,
+    )?;
+
+    let mut txids = Vec::new();
+    for (i, chunk) in outputs.chunks(MAX_OUTPUTS_PER_TX).enumerate() {
+        let mut options = payment::send_options(config);
+        if config.subtract_fee {
+            let indices: Vec<usize> = (0..chunk.len()).collect();
+            options.insert("subtract_fee_from_outputs".into(), json!(indices));
+        }
+
+        let recipients: Vec<(String, Amount)> = chunk
+            .iter()
+            .map(|(address, amount)| (address.to_string(), *amount))
+            .collect();
+        let txid = payment::send_to_outputs(&wallet_rpc, &recipients, config, options)?;
+        say!(
+            "Split transaction {}/{}: {} ({} outputs)",
+            i + 1,
+            chunks,
+            txid,
+            chunk.len()
+        );
+        txids.push(txid);
+    }
+
+    if config.confirm {
+        let block_hash = session.generate(1, &session.miner_address)?[0];
+        say!("Block {} mined, confirming the split.", block_hash);
+    }
+
+    // Record every created outpoint so coin control can pick them later
+    let mut outpoints = Vec::with_capacity(count);
+    for txid in &txids {
+        let tx = wallet_rpc.get_transaction(txid, None)?.transaction()?;
+        for (vout, output) in tx.output.iter().enumerate() {
+            if let Some(address) = addresses
+                .iter()
+                .find(|a| a.script_pubkey() == output.script_pubkey)
+            {
+                outpoints.push(SplitOutpoint {
+                    txid: *txid,
+                    vout: vout as u32,
+                    address: address.to_string(),
+                    amount_sat: output.value.to_sat(),
+                });
+            }
+        }
+    }
+
+    let path = config
+        .outpoints_out
+        .as_deref()
+        .unwrap_or(DEFAULT_OUTPOINTS_PATH);
+    let json = serde_json::to_string_pretty(&outpoints)?;
+    files::write_atomically(path, json, "outpoints file")?;
+    say!("Recorded {} outpoints in {}", outpoints.len(), path);
+
+    Ok(())
+}
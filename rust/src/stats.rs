@@ -0,0 +1,202 @@
+use crate::btc;
+use crate::error::Result;
+use bitcoincore_rpc::bitcoin::Amount;
+use bitcoincore_rpc::json::{BlockStatsFields, GetBlockStatsResultPartial};
+use bitcoincore_rpc::{Client, RpcApi};
+
+// Stats that only need the block itself
+const BLOCK_FIELDS: [BlockStatsFields; 4] = [
+    BlockStatsFields::Txs,
+    BlockStatsFields::TotalSize,
+    BlockStatsFields::TotalWeight,
+    BlockStatsFields::Subsidy,
+];
+// Fee stats also need the block's undo data, which a pruned node may have dropped
+const FEE_FIELDS: [BlockStatsFields; 4] = [
+    BlockStatsFields::TotalFee,
+    BlockStatsFields::AverageFeeRate,
+    BlockStatsFields::MinFeeRate,
+    BlockStatsFields::MaxFeeRate,
+];
+
+// getblockstats for the confirming block. Unlike most RPCs it answers in
+// satoshis, and its feerates are whole sat/vB; the rpc crate's struct parks
+// those in `Amount`s, so they're taken out as plain integers here.
+#[derive(Debug, Clone, Default)]
+pub struct BlockStats {
+    pub txs: Option<usize>,
+    pub total_size: Option<usize>,
+    pub total_weight: Option<usize>,
+    pub subsidy: Option<Amount>,
+    pub total_fee: Option<Amount>,
+    // sat/vB, the coinbase excluded
+    pub min_fee_rate: Option<u64>,
+    pub avg_fee_rate: Option<u64>,
+    pub max_fee_rate: Option<u64>,
+}
+
+impl BlockStats {
+    // Where a feerate, in hundredths of a sat/vB, falls among the block's
+    pub fn position(&self, centisat_per_vb: u64) -> Option<&'static str> {
+        let (min, avg, max) = (
+            self.min_fee_rate? * 100,
+            self.avg_fee_rate? * 100,
+            self.max_fee_rate? * 100,
+        );
+        Some(match centisat_per_vb {
+            rate if rate < min => "below the block's minimum",
+            rate if rate > max => "above the block's maximum",
+            rate if rate < avg => "between the block's minimum and average",
+            rate if rate > avg => "between the block's average and maximum",
+            _ => "at the block's average",
+        })
+    }
+
+    // The "Confirmation block stats" section, given our feerate in hundredths of a sat/vB
+    pub fn describe(&self, centisat_per_vb: u64) -> Vec<String> {
+        let unknown = || "unknown".to_string();
+        let mut lines = vec![
+            format!(
+                "Confirmation block stats: {} transactions, {} bytes, {} WU",
+                self.txs.map_or_else(unknown, |n| n.to_string()),
+                self.total_size.map_or_else(unknown, |n| n.to_string()),
+                self.total_weight.map_or_else(unknown, |n| n.to_string())
+            ),
+            format!(
+                "Confirmation block subsidy (in {}): {}",
+                btc::unit(),
+                self.subsidy.map_or_else(unknown, btc::format)
+            ),
+        ];
+        match (self.total_fee, self.position(centisat_per_vb)) {
+            (Some(total_fee), Some(position)) => {
+                lines.push(format!("Confirmation block total fees (in {}): {}", btc::unit(), btc::format(total_fee)));
+                lines.push(format!(
+                    "Confirmation block feerates (sat/vB): min {}, avg {}, max {}; ours ({}.{:02}) is {}",
+                    self.min_fee_rate.unwrap_or_default(),
+                    self.avg_fee_rate.unwrap_or_default(),
+                    self.max_fee_rate.unwrap_or_default(),
+                    centisat_per_vb / 100,
+                    centisat_per_vb % 100,
+                    position
+                ));
+            }
+            _ => lines.push(
+                "Confirmation block fee stats: unavailable (the node has no undo data for the block)"
+                    .to_string(),
+            ),
+        }
+        lines
+    }
+}
+
+// getblockstats at `height`, asking for the fee stats only if the node can
+// still compute them.
+pub fn block_stats(rpc: &Client, height: u64) -> Result<BlockStats> {
+    let all: Vec<BlockStatsFields> = BLOCK_FIELDS.iter().chain(&FEE_FIELDS).cloned().collect();
+    let (partial, with_fees) = match rpc.get_block_stats_fields(height, &all) {
+        Ok(partial) => (partial, true),
+        Err(_) => (rpc.get_block_stats_fields(height, &BLOCK_FIELDS)?, false),
+    };
+    Ok(from_partial(partial, with_fees))
+}
+
+// The stats out of getblockstats' answer, dropping the fee stats unless they
+// were asked for
+fn from_partial(partial: GetBlockStatsResultPartial, with_fees: bool) -> BlockStats {
+    let rate = |rate: Option<Amount>| rate.filter(|_| with_fees).map(|rate| rate.to_sat());
+    BlockStats {
+        txs: partial.txs,
+        total_size: partial.total_size,
+        total_weight: partial.total_weight,
+        subsidy: partial.subsidy,
+        total_fee: partial.total_fee.filter(|_| with_fees),
+        min_fee_rate: rate(partial.min_fee_rate),
+        avg_fee_rate: rate(partial.avg_fee_rate),
+        max_fee_rate: rate(partial.max_fee_rate),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // getblockstats for a regtest block at height 102 holding the readme
+    // payment: amounts in satoshis, feerates in whole sat/vB
+    const BLOCK_STATS: &str = r#"{
+        "avgfeerate": 12,
+        "maxfeerate": 12,
+        "minfeerate": 12,
+        "subsidy": 5000000000,
+        "total_size": 412,
+        "total_weight": 1432,
+        "totalfee": 1410,
+        "txs": 2
+    }"#;
+
+    fn partial() -> GetBlockStatsResultPartial {
+        serde_json::from_str(BLOCK_STATS).unwrap()
+    }
+
+    #[test]
+    fn amounts_are_read_as_satoshis() {
+        let stats = from_partial(partial(), true);
+        assert_eq!(stats.subsidy, Some(Amount::from_btc(50.0).unwrap()));
+        assert_eq!(stats.total_fee, Some(Amount::from_sat(1410)));
+        assert_eq!(stats.avg_fee_rate, Some(12));
+        assert_eq!(stats.txs, Some(2));
+        assert_eq!(stats.total_weight, Some(1432));
+    }
+
+    #[test]
+    fn fee_stats_are_dropped_unless_asked_for() {
+        let stats = from_partial(partial(), false);
+        assert_eq!(stats.subsidy, Some(Amount::from_sat(5_000_000_000)));
+        assert_eq!(stats.total_fee, None);
+        assert_eq!(stats.min_fee_rate, None);
+        assert_eq!(stats.position(1247), None);
+        assert_eq!(
+            stats.describe(1247)[2],
+            "Confirmation block fee stats: unavailable (the node has no undo data for the block)"
+        );
+    }
+
+    #[test]
+    fn describe_converts_satoshis_to_btc() {
+        let lines = from_partial(partial(), true).describe(1247);
+        assert_eq!(
+            lines[0],
+            "Confirmation block stats: 2 transactions, 412 bytes, 1432 WU"
+        );
+        assert_eq!(lines[1], "Confirmation block subsidy (in BTC): 50.00000000");
+        assert_eq!(
+            lines[2],
+            "Confirmation block total fees (in BTC): 0.00001410"
+        );
+        assert_eq!(
+            lines[3],
+            "Confirmation block feerates (sat/vB): min 12, avg 12, max 12; ours (12.47) is above the block's maximum"
+        );
+    }
+
+    #[test]
+    fn position_among_the_block_feerates() {
+        let stats = BlockStats {
+            min_fee_rate: Some(2),
+            avg_fee_rate: Some(5),
+            max_fee_rate: Some(9),
+            ..BlockStats::default()
+        };
+        let cases = [
+            (199, "below the block's minimum"),
+            (200, "between the block's minimum and average"),
+            (500, "at the block's average"),
+            (501, "between the block's average and maximum"),
+            (900, "between the block's average and maximum"),
+            (901, "above the block's maximum"),
+        ];
+        for (rate, expected) in cases {
+            assert_eq!(stats.position(rate), Some(expected), "{}", rate);
+        }
+    }
+}
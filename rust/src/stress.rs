@@ -0,0 +1,295 @@
+use crate::btc;
+use crate::chainstats;
+use crate::cli::{Config, OutputFormat};
+use crate::console::say;
+use crate::csvreport::{self, CsvRow};
+use crate::error::{CapstoneError, Result};
+use crate::fees;
+use crate::limits;
+use crate::mempool;
+use crate::mempooldiff;
+use crate::mine;
+use crate::prevout::PrevoutResolver;
+use crate::report;
+use crate::session::{self, Session};
+use crate::txidlog;
+use bitcoincore_rpc::bitcoin::{Amount, Txid};
+use bitcoincore_rpc::{Client, RpcApi};
+use std::collections::{BTreeMap, HashSet};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Each payment is small so the Miner's balance lasts
+const DEFAULT_AMOUNT: Amount = Amount::from_sat(100_000);
+// Mine a block after this many payments to keep the mempool from filling up
+const MINE_EVERY: usize = 50;
+// Only every SAMPLE_EVERY-th payment is fetched and decoded for its feerate
+const SAMPLE_EVERY: usize = 10;
+// How often a payment is retried after mining a block to make room
+const MAX_RETRIES: usize = 3;
+
+// Group node errors so the failure table stays short.
+fn failure_kind(error: &CapstoneError) -> &'static str {
+    let message = error.to_string().to_lowercase();
+    if message.contains("insufficient funds") {
+        "insufficient funds"
+    } else if message.contains("too-long-mempool-chain") {
+        "too-long-mempool-chain"
+    } else if message.contains("fee") {
+        "fee"
+    } else if matches!(error, CapstoneError::Rpc(_)) {
+        "other rpc error"
+    } else {
+        "other"
+    }
+}
+
+// Get one more block on top of the pending payments: mine it, or with
+// --external-miner (`waiter` set) wait up to --confirm-timeout for whatever
+// else is mining, such as an automine running alongside. Returns how many
+// blocks this run mined.
+fn next_block(session: &Session, config: &Config, waiter: Option<&Client>) -> Result<u64> {
+    let Some(waiter) = waiter else {
+        session.generate(1, &session.miner_address)?;
+        return Ok(1);
+    };
+    let tip = session.rpc.get_best_block_hash()?;
+    let deadline = Instant::now() + Duration::from_secs(config.confirm_timeout());
+    match mine::wait_for_new_block(waiter, &tip, deadline)? {
+        Some(_) => Ok(0),
+        None => Err(CapstoneError::Usage(format!(
+            "no block came within {}s; is the external miner (e.g. automine) running?",
+            config.confirm_timeout()
+        ))),
+    }
+}
+
+// stress: fire off `count` payments from the Miner to fresh Trader addresses,
+// at most `rate` per second, and report aggregate numbers rather than
+// per-transaction detail.
+pub fn run(session: &Session, config: &Config, count: usize) -> Result<()> {
+    let rpc = &session.rpc;
+    let miner_rpc = &session.miner_rpc;
+    let amount = config.amount.unwrap_or(DEFAULT_AMOUNT);
+    let funding_blocks = session.fund_miner(amount * count as u64 + config.max_fee())?;
+    let interval = config
+        .rate
+        .filter(|rate| *rate > 0)
+        .map(|rate| Duration::from_secs_f64(1.0 / f64::from(rate)));
+    let edges = config.histogram_edges();
+    let pressure_before = mempool::histogram(
+        &mempool::get_raw_mempool_verbose(rpc)?,
+        edges,
+        config.ancestor_feerate,
+    );
+    let mempool_before = mempooldiff::take(rpc)?;
+    let start_block = rpc.get_best_block_hash()?;
+    let start_height = rpc.get_block_count()?;
+    let started = Instant::now();
+
+    let mut sent: Vec<Txid> = Vec::with_capacity(count);
+    let mut failures: BTreeMap<&'static str, usize> = BTreeMap::new();
+    let mut blocks_mined = funding_blocks;
+    let waiter = if config.external_miner {
+        Some(session::long_poll_client(
+            Duration::from_secs(config.confirm_timeout()) + mine::LONG_POLL_MARGIN,
+        )?)
+    } else {
+        None
+    };
+
+    for i in 0..count {
+        if let Some(interval) = interval {
+            let due = interval * i as u32;
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+        // A mempool about to evict loses the cheapest payments again right
+        // away: make room with a block first, and stop if that doesn't help
+        if limits::mempool_info(rpc)?.nearly_full() {
+            blocks_mined += next_block(session, config, waiter.as_ref())?;
+            let mempool = limits::mempool_info(rpc)?;
+            if mempool.nearly_full() {
+                say!("Stress: stopping, {}.", mempool.describe());
+                *failures.entry("mempool full").or_default() += count - i;
+                break;
+            }
+        }
+        let address = session
+            .trader_rpc
+            .get_new_address(None, None)?
+            .assume_checked();
+
+        let mut attempt = 0;
+        loop {
+            let result = miner_rpc
+                .send_to_address(&address, amount, None, None, None, None, None, None)
+                .map_err(CapstoneError::from);
+            match result {
+                Ok(txid) => {
+                    txidlog::record(&txid, "stress");
+                    sent.push(txid);
+                    break;
+                }
+                Err(e) => {
+                    let kind = failure_kind(&e);
+                    let retry = matches!(kind, "insufficient funds" | "too-long-mempool-chain");
+                    if retry && attempt < MAX_RETRIES {
+                        // Either way a block helps: it confirms the chain of
+                        // unconfirmed change, and matures another coinbase
+                        attempt += 1;
+                        blocks_mined += next_block(session, config, waiter.as_ref())?;
+                        continue;
+                    }
+                    *failures.entry(kind).or_default() += 1;
+                    break;
+                }
+            }
+        }
+
+        if (i + 1) % MINE_EVERY == 0 {
+            if waiter.is_none() {
+                session.generate(1, &session.miner_address)?;
+                blocks_mined += 1;
+            }
+            say!("Stress: {} of {} sent.", sent.len(), count);
+        }
+    }
+    let elapsed = started.elapsed();
+    // What the run left waiting, before the last block takes it
+    let pressure_after = mempool::histogram(
+        &mempool::get_raw_mempool_verbose(rpc)?,
+        edges,
+        config.ancestor_feerate,
+    );
+    let mempool_diff = mempooldiff::compare(rpc, &mempool_before, &mempooldiff::take(rpc)?)?;
+
+    // Confirm whatever is left, then collect every fee with a single call
+    blocks_mined += next_block(session, config, waiter.as_ref())?;
+    let ours: HashSet<Txid> = sent.iter().copied().collect();
+    let mut total_fee = Amount::ZERO;
+    for tx in miner_rpc
+        .list_since_block(Some(&start_block), None, None, None)?
+        .transactions
+    {
+        if ours.contains(&tx.info.txid) {
+            if let Some(fee) = tx.detail.fee {
+                total_fee += fee.abs().to_unsigned().unwrap_or(Amount::ZERO);
+            }
+        }
+    }
+
+    // Feerates from a sample rather than every transaction
+    let mut sampled = Vec::new();
+    for txid in sent.iter().step_by(SAMPLE_EVERY) {
+        let wallet_tx = miner_rpc.get_transaction(txid, None)?;
+        let fee = wallet_tx
+            .fee
+            .and_then(|fee| fee.abs().to_unsigned().ok())
+            .unwrap_or(Amount::ZERO);
+        let vsize = wallet_tx.transaction()?.vsize() as u64;
+        sampled.push(fee.to_sat() * 1000 / vsize.max(1));
+    }
+
+    let mut output_string = String::new();
+    output_string.push_str(&format!("Transactions sent: {} of {}\n", sent.len(), count));
+    output_string.push_str(&format!(
+        "Total sent (in {}): {}\n",
+        btc::unit(),
+        btc::format(amount * sent.len() as u64)
+    ));
+    output_string.push_str(&format!(
+        "Total fees (in {}): {}\n",
+        btc::unit(),
+        btc::format(total_fee)
+    ));
+    if let (Some(min), Some(max)) = (sampled.iter().min(), sampled.iter().max()) {
+        let avg = sampled.iter().sum::<u64>() / sampled.len() as u64;
+        output_string.push_str(&format!(
+            "Feerate min/avg/max (sat/vB, {} sampled): {} / {} / {}\n",
+            sampled.len(),
+            fees::format_sat_per_vb(*min),
+            fees::format_sat_per_vb(avg),
+            fees::format_sat_per_vb(*max)
+        ));
+    }
+    for (kind, n) in &failures {
+        output_string.push_str(&format!("Failures ({}): {}\n", kind, n));
+    }
+    output_string.push_str(&format!("Blocks mined: {}\n", blocks_mined));
+    output_string.push_str(&format!(
+        "Elapsed time (s): {}.{:03}\n",
+        elapsed.as_secs(),
+        elapsed.subsec_millis()
+    ));
+    output_string.push_str(&format!(
+        "Throughput (tx/s): {:.2}\n",
+        sent.len() as f64 / elapsed.as_secs_f64().max(0.001)
+    ));
+    output_string.push_str(&format!(
+        "Mempool before ({}): {}\n",
+        pressure_before.kind(),
+        pressure_before.compact()
+    ));
+    output_string.push_str(&format!(
+        "Mempool after ({}): {}\n",
+        pressure_after.kind(),
+        pressure_after.compact()
+    ));
+    output_string.push_str(&format!(
+        "Mempool diff before/after: {}\n",
+        mempool_diff.summary()
+    ));
+    // What the chain itself took in over the blocks of the run
+    let window = rpc.get_block_count()? - start_height;
+    let chain_stats = chainstats::chain_tx_stats(rpc, Some(window))?;
+    output_string.push_str(&format!("Chain throughput: {}\n", chain_stats.summary()));
+
+    if config.format == OutputFormat::Csv {
+        report::save_report(&output_string)?;
+        return csvreport::write_csv(&csv_rows(session, &sent, amount)?);
+    }
+    report::write_report(&output_string)
+}
+
+// A --format csv row for each confirmed payment of the run
+fn csv_rows(session: &Session, sent: &[Txid], amount: Amount) -> Result<Vec<CsvRow>> {
+    let mut resolver = PrevoutResolver::new(&session.rpc)?;
+    let mut rows = Vec::with_capacity(sent.len());
+    for txid in sent {
+        let wallet_tx = session.miner_rpc.get_transaction(txid, None)?;
+        let (Some(block_hash), Some(block_height)) =
+            (wallet_tx.info.blockhash, wallet_tx.info.blockheight)
+        else {
+            continue;
+        };
+        let fee = wallet_tx
+            .fee
+            .and_then(|fee| fee.abs().to_unsigned().ok())
+            .unwrap_or(Amount::ZERO);
+        let mut input_addresses = Vec::new();
+        let mut input_amount = Amount::ZERO;
+        for input in &wallet_tx.transaction()?.input {
+            let prevout = resolver.resolve(&input.previous_output)?;
+            input_amount += prevout.value;
+            if let Some(address) = prevout.address {
+                input_addresses.push(address.to_string());
+            }
+        }
+        let change = input_amount
+            .checked_sub(amount + fee)
+            .filter(|change| *change > Amount::ZERO);
+        rows.push(CsvRow {
+            txid: *txid,
+            input_addresses,
+            input_amount,
+            trader_amount: Some(amount),
+            change_amount: change,
+            fee,
+            block_height: u64::from(block_height),
+            block_hash,
+        });
+    }
+    Ok(rows)
+}
@@ -0,0 +1,218 @@
+use crate::btc;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::session::Session;
+use bitcoincore_rpc::bitcoin::{Amount, Txid};
+use bitcoincore_rpc::jsonrpc::error::Error as JsonRpcError;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+
+// getblocktemplate refuses to answer off the test chains when the node has no
+// peers (-9) or is still syncing (-10); regtest, signet and testnet nodes
+// never return these.
+const RPC_CLIENT_NOT_CONNECTED: i32 = -9;
+const RPC_CLIENT_IN_INITIAL_DOWNLOAD: i32 = -10;
+
+// The parts of getblocktemplate we summarize. The response also carries the
+// raw transaction data, the target, mutable fields and so on; serde skips them.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BlockTemplate {
+    pub height: u64,
+    pub transactions: Vec<TemplateTransaction>,
+    #[serde(
+        rename = "coinbasevalue",
+        with = "bitcoincore_rpc::bitcoin::amount::serde::as_sat"
+    )]
+    pub coinbase_value: Amount,
+    #[serde(rename = "weightlimit")]
+    pub weight_limit: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TemplateTransaction {
+    pub txid: Txid,
+    // in sat
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_sat")]
+    pub fee: Amount,
+    pub weight: u64,
+    // 1-based indexes of the template transactions this one spends
+    #[serde(default)]
+    pub depends: Vec<usize>,
+}
+
+// The coinbase and block header aren't listed in the template, but count
+// towards the weight limit. Core reserves 4000 weight units for them.
+const COINBASE_RESERVED_WEIGHT: u64 = 4_000;
+
+impl BlockTemplate {
+    pub fn total_fees(&self) -> Amount {
+        self.transactions.iter().map(|tx| tx.fee).sum()
+    }
+
+    pub fn weight(&self) -> u64 {
+        COINBASE_RESERVED_WEIGHT + self.transactions.iter().map(|tx| tx.weight).sum::<u64>()
+    }
+
+    pub fn position(&self, txid: &Txid) -> Option<usize> {
+        self.transactions.iter().position(|tx| tx.txid == *txid)
+    }
+
+    // The summary `template` prints, and where each of `pending` stands
+    pub fn describe(&self, pending: &[Txid]) -> Vec<String> {
+        let weight = self.weight();
+        let mut lines = vec![
+            format!("Block template for height {}:", self.height),
+            format!("Transactions selected: {}", self.transactions.len()),
+            format!(
+                "Total fees (in {}): {}",
+                btc::unit(),
+                btc::format(self.total_fees())
+            ),
+            format!(
+                "Coinbase value (in {}): {}",
+                btc::unit(),
+                btc::format(self.coinbase_value)
+            ),
+            format!(
+                "Weight used: {} of {} WU ({}.{:02}%)",
+                weight,
+                self.weight_limit,
+                weight * 100 / self.weight_limit.max(1),
+                weight * 10_000 / self.weight_limit.max(1) % 100
+            ),
+        ];
+
+        if pending.is_empty() {
+            lines.push("The Miner wallet has no pending transaction to look for.".to_string());
+        }
+        for txid in pending {
+            match self.position(txid) {
+                Some(i) => {
+                    let tx = &self.transactions[i];
+                    lines.push(format!(
+                        "{}: included as transaction {}, fee {}, {} WU{}",
+                        txid,
+                        i + 1,
+                        btc::amount(tx.fee),
+                        tx.weight,
+                        if tx.depends.is_empty() {
+                            String::new()
+                        } else {
+                            format!(", after transaction(s) {:?}", tx.depends)
+                        }
+                    ));
+                }
+                None => lines.push(format!("{}: not included", txid)),
+            }
+        }
+        lines
+    }
+}
+
+// The node's next block template, None (after saying why) when it won't build
+// one before it has peers and a synced chain.
+pub fn block_template(rpc: &Client) -> Result<Option<BlockTemplate>> {
+    let result = rpc.call::<BlockTemplate>("getblocktemplate", &[json!({ "rules": ["segwit"] })]);
+    match result {
+        Ok(template) => Ok(Some(template)),
+        Err(bitcoincore_rpc::Error::JsonRpc(JsonRpcError::Rpc(e)))
+            if e.code == RPC_CLIENT_NOT_CONNECTED || e.code == RPC_CLIENT_IN_INITIAL_DOWNLOAD =>
+        {
+            say!(
+                "The node won't build a block template: {} (only mainnet nodes need peers and a synced chain for this).",
+                e.message
+            );
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// template: ask the node which transactions it would mine next and whether
+// our pending ones are among them. Without a txid, every unconfirmed Miner
+// wallet transaction is looked up.
+pub fn run(session: &Session, txid: Option<&str>) -> Result<()> {
+    let pending: Vec<Txid> = match txid {
+        Some(txid) => vec![txid
+            .parse()
+            .map_err(|e| CapstoneError::Usage(format!("invalid txid '{}': {}", txid, e)))?],
+        None => session
+            .miner_rpc
+            .list_transactions(None, Some(1000), None, None)?
+            .into_iter()
+            .filter(|tx| tx.info.confirmations == 0)
+            .map(|tx| tx.info.txid)
+            .collect(),
+    };
+
+    let Some(template) = block_template(&session.rpc)? else {
+        return Ok(());
+    };
+    for line in template.describe(&pending) {
+        say!("{}", line);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A regtest getblocktemplate with the readme payment and a child of it
+    fn template() -> BlockTemplate {
+        serde_json::from_str(include_str!("../tests/fixtures/blocktemplate.json")).unwrap()
+    }
+
+    fn txid(hex: &str) -> Txid {
+        hex.parse().unwrap()
+    }
+
+    #[test]
+    fn deserializes_the_trimmed_fields() {
+        let template = template();
+        assert_eq!(template.height, 103);
+        assert_eq!(template.transactions.len(), 2);
+        assert_eq!(template.coinbase_value, Amount::from_sat(5_000_004_230));
+        assert_eq!(template.transactions[1].depends, vec![1]);
+        assert_eq!(template.total_fees(), Amount::from_sat(4_230));
+        assert_eq!(template.weight(), 4_000 + 452 + 561);
+    }
+
+    #[test]
+    fn summary_with_pending_transactions() {
+        let pending = [
+            txid("aa11bb22cc33dd44ee55ff6600771188229933aa44bb55cc66dd77ee88ff9900"),
+            txid("0000000000000000000000000000000000000000000000000000000000000001"),
+        ];
+        assert_eq!(
+            template().describe(&pending),
+            vec![
+                "Block template for height 103:",
+                "Transactions selected: 2",
+                "Total fees (in BTC): 0.00004230",
+                "Coinbase value (in BTC): 50.00004230",
+                "Weight used: 5013 of 4000000 WU (0.12%)",
+                "aa11bb22cc33dd44ee55ff6600771188229933aa44bb55cc66dd77ee88ff9900: included as transaction 2, fee 0.00002820 BTC, 561 WU, after transaction(s) [1]",
+                "0000000000000000000000000000000000000000000000000000000000000001: not included",
+            ]
+        );
+    }
+
+    #[test]
+    fn summary_without_pending_transactions() {
+        let lines = template().describe(&[]);
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[5],
+            "The Miner wallet has no pending transaction to look for."
+        );
+        let included = template().describe(&[txid(
+            "54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af",
+        )]);
+        assert_eq!(
+            included[5],
+            "54f005b424430e9fa6a500f5df671d25dee51d85a3dc44e5bb3722ba7eb1b8af: included as transaction 1, fee 0.00001410 BTC, 452 WU"
+        );
+    }
+}
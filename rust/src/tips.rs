@@ -0,0 +1,101 @@
+use crate::console::say;
+use crate::error::Result;
+use bitcoincore_rpc::json::{GetChainTipsResultStatus, GetChainTipsResultTip};
+use bitcoincore_rpc::{Client, RpcApi};
+
+// Forks whose tip is at most this far below the active tip are worth a warning
+const NEARBY_BLOCKS: u64 = 6;
+
+fn status_name(status: GetChainTipsResultStatus) -> &'static str {
+    match status {
+        GetChainTipsResultStatus::Invalid => "invalid",
+        GetChainTipsResultStatus::HeadersOnly => "headers-only",
+        GetChainTipsResultStatus::ValidHeaders => "valid-headers",
+        GetChainTipsResultStatus::ValidFork => "valid-fork",
+        GetChainTipsResultStatus::Active => "active",
+    }
+}
+
+// getchaintips, split into the active tip and the other tips close to it.
+#[derive(Debug, Clone)]
+pub struct ChainTips {
+    pub active: Option<GetChainTipsResultTip>,
+    pub nearby: Vec<GetChainTipsResultTip>,
+}
+
+impl ChainTips {
+    // Forks that can take over or did fail to: a valid fork may become the
+    // active chain with one more block, and an invalid one is a block someone
+    // mined (or invalidateblock marked) that the node refuses. Headers-only
+    // tips are just blocks the node hasn't downloaded and are left alone.
+    pub fn forks(&self) -> impl Iterator<Item = &GetChainTipsResultTip> {
+        self.nearby.iter().filter(|tip| {
+            matches!(
+                tip.status,
+                GetChainTipsResultStatus::ValidFork | GetChainTipsResultStatus::Invalid
+            )
+        })
+    }
+
+    pub fn warn(&self, when: &str) {
+        for tip in self.forks() {
+            say!(
+                "Warning ({}): {} tip {} at height {}, {} block(s) off the active chain; transactions confirmed there are not confirmed here.",
+                when,
+                status_name(tip.status),
+                tip.hash,
+                tip.height,
+                tip.branch_length
+            );
+        }
+    }
+
+    // One line for the report
+    pub fn summary(&self) -> String {
+        let active = match &self.active {
+            Some(tip) => format!("active {} at height {}", tip.hash, tip.height),
+            None => "no active tip".to_string(),
+        };
+        if self.nearby.is_empty() {
+            return format!("{}, no other tip within {} blocks", active, NEARBY_BLOCKS);
+        }
+        let others: Vec<String> = self
+            .nearby
+            .iter()
+            .map(|tip| {
+                format!(
+                    "{} {} at height {} (branch length {})",
+                    status_name(tip.status),
+                    tip.hash,
+                    tip.height,
+                    tip.branch_length
+                )
+            })
+            .collect();
+        format!("{}; {}", active, others.join("; "))
+    }
+}
+
+pub fn chain_tips(rpc: &Client) -> Result<ChainTips> {
+    let tips = rpc.get_chain_tips()?;
+    let active = tips
+        .iter()
+        .find(|tip| tip.status == GetChainTipsResultStatus::Active)
+        .cloned();
+    let floor = active
+        .as_ref()
+        .map_or(0, |tip| tip.height.saturating_sub(NEARBY_BLOCKS));
+    let nearby = tips
+        .into_iter()
+        .filter(|tip| tip.status != GetChainTipsResultStatus::Active && tip.height >= floor)
+        .collect();
+    Ok(ChainTips { active, nearby })
+}
+
+// Look at the chain tips and warn about nearby forks; `when` says which
+// check this is.
+pub fn check(rpc: &Client, when: &str) -> Result<ChainTips> {
+    let tips = chain_tips(rpc)?;
+    tips.warn(when);
+    Ok(tips)
+}
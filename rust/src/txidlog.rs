@@ -0,0 +1,206 @@
+use crate::chaintime;
+use crate::cli::Config;
+use crate::error::{CapstoneError, Result};
+use crate::files;
+use bitcoincore_rpc::bitcoin::Txid;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// --txid-log, once opened. Kept open for the whole run so a reader on a FIFO
+// doesn't see end-of-file between two lines.
+static LOG: Mutex<Option<File>> = Mutex::new(None);
+
+// Open --txid-log for appending, creating a regular file if nothing is there.
+// Opening a FIFO waits for its reader.
+pub fn open(path: &str) -> Result<()> {
+    if fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo()) {
+        eprintln!("--txid-log {} is a FIFO, waiting for a reader.", path);
+    }
+    let file = files::open_append(path, "txid log")?;
+    *LOG.lock().unwrap_or_else(PoisonError::into_inner) = Some(file);
+    Ok(())
+}
+
+// Append `timestamp txid event` when --txid-log is set. Each line goes out in
+// one unbuffered write, which O_APPEND (and a pipe, for lines this short)
+// keeps whole next to other runs writing the same file. A failing log never
+// fails the broadcast it records.
+pub fn record(txid: &Txid, event: &str) {
+    let mut log = LOG.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(file) = log.as_mut() else {
+        return;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let line = log_line(now, txid, event);
+    match file.write_all(line.as_bytes()) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => {
+            eprintln!("Warning: the --txid-log reader went away, no more txids are logged.");
+            *log = None;
+        }
+        Err(e) => eprintln!("Warning: couldn't log {} to --txid-log: {}", txid, e),
+    }
+}
+
+// One --txid-log line, `now` in seconds since the epoch
+fn log_line(now: u64, txid: &Txid, event: &str) -> String {
+    format!("{} {} {}\n", chaintime::rfc3339(now), txid, event)
+}
+
+// The txids a watch or report run covers: its <txid>, or those in --txids-from
+pub fn txids(txid: Option<&str>, config: &Config) -> Result<Vec<Txid>> {
+    match (txid, &config.txids_from) {
+        (Some(txid), _) => Ok(vec![txid.parse().map_err(|e| {
+            CapstoneError::Usage(format!("invalid txid '{}': {}", txid, e))
+        })?]),
+        (None, Some(path)) => read_txids(path),
+        (None, None) => Err(CapstoneError::Usage(
+            "give a <txid> or --txids-from <path>".into(),
+        )),
+    }
+}
+
+// The txids in a --txids-from file, in order and without repeats: one per
+// line, either a bare txid or a --txid-log line. Blank lines and lines
+// starting with # are skipped.
+pub fn read_txids(path: &str) -> Result<Vec<Txid>> {
+    let content = files::read_to_string(path, "txid list")?;
+    let mut txids: Vec<Txid> = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let field = match fields.as_slice() {
+            [txid] => txid,
+            [_, txid, _] => txid,
+            _ => {
+                return Err(CapstoneError::Usage(format!(
+                    "{} line {}: expected a txid or 'timestamp txid event'",
+                    path,
+                    number + 1
+                )))
+            }
+        };
+        let txid: Txid = field.parse().map_err(|e| {
+            CapstoneError::Usage(format!(
+                "{} line {}: invalid txid '{}': {}",
+                path,
+                number + 1,
+                field,
+                e
+            ))
+        })?;
+        if !txids.contains(&txid) {
+            txids.push(txid);
+        }
+    }
+    if txids.is_empty() {
+        return Err(CapstoneError::Usage(format!("{} lists no txids", path)));
+    }
+    Ok(txids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::parse_args;
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+
+    fn txid(n: u8) -> Txid {
+        Txid::from_byte_array([n; 32])
+    }
+
+    #[test]
+    fn log_lines_are_timestamp_txid_event() {
+        assert_eq!(
+            log_line(1_700_000_000, &txid(1), "payment"),
+            format!("2023-11-14T22:13:20Z {} payment\n", txid(1))
+        );
+    }
+
+    #[test]
+    fn a_log_of_several_runs_reads_back_as_a_txid_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("txids.log");
+        let path = path.to_str().unwrap();
+        // Two runs appending to the same log, the second rebroadcasting the first's payment
+        for events in [
+            [(txid(1), "payment"), (txid(2), "replacement")],
+            [(txid(3), "stress"), (txid(1), "rebroadcast")],
+        ] {
+            let mut file = files::open_append(path, "txid log").unwrap();
+            for (txid, event) in events {
+                file.write_all(log_line(1_700_000_000, &txid, event).as_bytes())
+                    .unwrap();
+            }
+        }
+        let content = fs::read_to_string(path).unwrap();
+        assert_eq!(content.lines().count(), 4);
+        assert!(content
+            .lines()
+            .all(|line| line.split(' ').count() == 3 && line.starts_with("2023-11-14T22:13:20Z ")));
+
+        let config = parse_args(["report", "--txids-from", path].map(String::from)).unwrap();
+        assert_eq!(txids(None, &config).unwrap(), [txid(1), txid(2), txid(3)]);
+    }
+
+    #[test]
+    fn txid_lists_mix_bare_txids_and_comments() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("txids.txt");
+        fs::write(
+            &path,
+            format!(
+                "# to report\n{}\n\n  {}  \n2023-11-14T22:13:20Z {} payment\n",
+                txid(4),
+                txid(5),
+                txid(4)
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            read_txids(path.to_str().unwrap()).unwrap(),
+            [txid(4), txid(5)]
+        );
+    }
+
+    #[test]
+    fn bad_txid_lists_name_the_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("txids.txt");
+        let path_str = path.to_str().unwrap();
+        for (content, expected) in [
+            (
+                format!("{}\nnot-a-txid\n", txid(1)),
+                "line 2: invalid txid 'not-a-txid'",
+            ),
+            (
+                "a b\n".to_string(),
+                "line 1: expected a txid or 'timestamp txid event'",
+            ),
+            ("# nothing\n\n".to_string(), "lists no txids"),
+        ] {
+            fs::write(&path, content).unwrap();
+            let err = read_txids(path_str).unwrap_err().to_string();
+            assert!(err.contains(expected), "{}", err);
+        }
+    }
+
+    #[test]
+    fn a_txid_argument_wins() {
+        let config = parse_args(["report", &txid(6).to_string()].map(String::from)).unwrap();
+        assert_eq!(
+            txids(Some(&txid(6).to_string()), &config).unwrap(),
+            [txid(6)]
+        );
+        assert!(txids(Some("zz"), &config).is_err());
+        assert!(txids(None, &config).is_err());
+    }
+}
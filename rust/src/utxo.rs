@@ -0,0 +1,95 @@
+use crate::btc;
+use crate::error::{CapstoneError, Result};
+use bitcoincore_rpc::bitcoin::hex::DisplayHex;
+use bitcoincore_rpc::bitcoin::{Amount, BlockHash, OutPoint, ScriptBuf};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+
+// gettxout's response. Kept here rather than using the rpc crate's so the
+// scriptPubKey comes back as a script we can compare directly.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UtxoEntry {
+    pub bestblock: BlockHash,
+    pub confirmations: u32,
+    #[serde(with = "bitcoincore_rpc::bitcoin::amount::serde::as_btc")]
+    pub value: Amount,
+    #[serde(rename = "scriptPubKey")]
+    pub script_pub_key: UtxoScript,
+    pub coinbase: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UtxoScript {
+    pub hex: ScriptBuf,
+    pub address: Option<String>,
+}
+
+// What the UTXO set says about an output we created.
+#[derive(Debug, Clone)]
+pub enum UtxoStatus {
+    Unspent(UtxoEntry),
+    // gettxout returned null: already spent (or never existed)
+    Spent,
+}
+
+impl UtxoStatus {
+    pub fn describe(&self) -> String {
+        match self {
+            UtxoStatus::Unspent(entry) => format!(
+                "unspent, {} confirmation(s) as of block {}",
+                entry.confirmations, entry.bestblock
+            ),
+            UtxoStatus::Spent => "spent".to_string(),
+        }
+    }
+}
+
+// Look up an output, ignoring the mempool so "unspent" means unspent on chain.
+pub fn get_utxo(rpc: &Client, outpoint: &OutPoint) -> Result<UtxoStatus> {
+    let entry: Option<UtxoEntry> = rpc.call(
+        "gettxout",
+        &[json!(outpoint.txid), json!(outpoint.vout), json!(false)],
+    )?;
+    Ok(entry.map_or(UtxoStatus::Spent, UtxoStatus::Unspent))
+}
+
+// Check that an output is in the UTXO set, confirmed, and holds exactly what we
+// expect. A spent output is only acceptable when the caller says so.
+pub fn verify_utxo(
+    rpc: &Client,
+    outpoint: &OutPoint,
+    value: Amount,
+    script_pubkey: &ScriptBuf,
+    allow_spent: bool,
+) -> Result<UtxoStatus> {
+    let fail = |reason: String| CapstoneError::UtxoCheckFailed {
+        outpoint: *outpoint,
+        reason,
+    };
+    let status = get_utxo(rpc, outpoint)?;
+    match &status {
+        UtxoStatus::Spent if allow_spent => {}
+        UtxoStatus::Spent => return Err(fail("not in the UTXO set".into())),
+        UtxoStatus::Unspent(entry) => {
+            if entry.confirmations == 0 {
+                return Err(fail("has no confirmations".into()));
+            }
+            if entry.value != value {
+                return Err(fail(format!(
+                    "holds {}, expected {}",
+                    btc::amount(entry.value),
+                    btc::amount(value)
+                )));
+            }
+            if &entry.script_pub_key.hex != script_pubkey {
+                return Err(fail(format!(
+                    "has scriptPubKey {}, expected {}",
+                    entry.script_pub_key.hex.as_bytes().to_lower_hex_string(),
+                    script_pubkey.as_bytes().to_lower_hex_string()
+                )));
+            }
+        }
+    }
+    Ok(status)
+}
@@ -0,0 +1,227 @@
+use crate::accept;
+use crate::btc;
+use crate::chaintime;
+use crate::cli::Config;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::raw;
+use crate::report;
+use crate::session::Session;
+use crate::signing;
+use crate::txidlog;
+use bitcoincore_rpc::bitcoin::blockdata::opcodes::all::{OP_CHECKSIG, OP_CLTV, OP_DROP};
+use bitcoincore_rpc::bitcoin::blockdata::script::Builder;
+use bitcoincore_rpc::bitcoin::consensus::encode;
+use bitcoincore_rpc::bitcoin::{
+    absolute, transaction, Address, Amount, OutPoint, PrivateKey, PublicKey, ScriptBuf, Sequence,
+    Transaction, TxIn, TxOut, Witness,
+};
+use bitcoincore_rpc::RpcApi;
+
+const DEFAULT_AMOUNT: Amount = Amount::ONE_BTC;
+
+// <H> OP_CHECKLOCKTIMEVERIFY OP_DROP <trader_pubkey> OP_CHECKSIG
+pub fn vault_script(unlock_height: u32, trader_key: &PublicKey) -> ScriptBuf {
+    Builder::new()
+        .push_int(i64::from(unlock_height))
+        .push_opcode(OP_CLTV)
+        .push_opcode(OP_DROP)
+        .push_key(trader_key)
+        .push_opcode(OP_CHECKSIG)
+        .into_script()
+}
+
+// The spend out of the vault. CLTV wants nLockTime at least H and an input
+// sequence below 0xffffffff, otherwise the locktime is not enforced at all.
+fn build_spend(
+    funding: (OutPoint, Amount),
+    unlock_height: u32,
+    witness_script: &ScriptBuf,
+    key: &PrivateKey,
+    to: &Address,
+    fee: Amount,
+) -> Result<Transaction> {
+    let (outpoint, value) = funding;
+    let lock_time = absolute::LockTime::from_height(unlock_height)
+        .map_err(|e| CapstoneError::Usage(format!("--unlock-height {}: {}", unlock_height, e)))?;
+    let mut tx = Transaction {
+        version: transaction::Version::TWO,
+        lock_time,
+        input: vec![TxIn {
+            previous_output: outpoint,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_LOCKTIME_NO_RBF,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: value - fee,
+            script_pubkey: to.script_pubkey(),
+        }],
+    };
+    let signature = signing::sign_p2wsh(&tx, 0, witness_script, value, key)?;
+    tx.input[0].witness = Witness::from_slice(&[signature, witness_script.to_bytes()]);
+    Ok(tx)
+}
+
+// vault-demo: lock Miner coins until block H for the Trader, show the node
+// refusing the spend while it is still early, then mine up to H and spend.
+pub fn run(session: &Session, config: &Config, unlock_height: u32) -> Result<()> {
+    let rpc = &session.rpc;
+    session.fund_miner(config.amount.unwrap_or(DEFAULT_AMOUNT) + config.max_fee())?;
+
+    // The funding confirms in the next block, after which the spend must still
+    // be non-final for the early attempt to show anything
+    let height = rpc.get_block_count()? as u32;
+    if unlock_height <= height + 1 {
+        return Err(CapstoneError::Usage(format!(
+            "--unlock-height must be above {} (the chain is at {} and funding takes a block)",
+            height + 1,
+            height
+        )));
+    }
+
+    let trader_address = session
+        .trader_rpc
+        .get_new_address(None, None)?
+        .assume_checked();
+    let (trader_key, private_key) = signing::wallet_key(&session.trader_rpc, &trader_address)?;
+    let witness_script = vault_script(unlock_height, &trader_key);
+    let vault_address = Address::p2wsh(&witness_script, session.network);
+    say!(
+        "Vault address {} unlocks at height {}.",
+        vault_address,
+        unlock_height
+    );
+
+    // Lock the coins and confirm them
+    let amount = config.amount.unwrap_or(DEFAULT_AMOUNT);
+    let funding_txid = session.miner_rpc.send_to_address(
+        &vault_address,
+        amount,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )?;
+    txidlog::record(&funding_txid, "funding");
+    session.generate(1, &session.miner_address)?;
+    let vout = session
+        .miner_rpc
+        .get_transaction(&funding_txid, None)?
+        .transaction()?
+        .output
+        .iter()
+        .position(|output| output.script_pubkey == vault_address.script_pubkey())
+        .ok_or_else(|| {
+            CapstoneError::Usage(format!(
+                "funding transaction {} does not pay {}",
+                funding_txid, vault_address
+            ))
+        })?;
+    let funding = (OutPoint::new(funding_txid, vout as u32), amount);
+    say!("Locked {} in {}.", btc::amount(amount), funding.0);
+
+    // Sign once to learn the size, then again with the fee that size needs
+    let to = session
+        .trader_rpc
+        .get_new_address(None, None)?
+        .assume_checked();
+    let sized = build_spend(
+        funding,
+        unlock_height,
+        &witness_script,
+        &private_key,
+        &to,
+        Amount::ZERO,
+    )?;
+    // One extra vbyte in case the final signature is a byte longer
+    let fee =
+        Amount::from_sat(((sized.vsize() as u64 + 1) * config.fallback_fee_rate()).div_ceil(1000));
+    let spend = build_spend(
+        funding,
+        unlock_height,
+        &witness_script,
+        &private_key,
+        &to,
+        fee,
+    )?;
+    let hex = encode::serialize_hex(&spend);
+
+    // Too early: nLockTime H is only final once the next block is above H
+    let early_height = rpc.get_block_count()? as u32;
+    let early = accept::test_mempool_accept(
+        rpc,
+        std::slice::from_ref(&hex),
+        config.max_broadcast_feerate(),
+    )?;
+    let early = early
+        .first()
+        .ok_or_else(|| CapstoneError::Usage("testmempoolaccept returned no result".into()))?;
+    if early.is_allowed() {
+        return Err(CapstoneError::Usage(format!(
+            "the node accepted the vault spend at height {}, before the unlock height {}",
+            early_height, unlock_height
+        )));
+    }
+    let early_rejection = early.rejection();
+    say!(
+        "Spend at height {} rejected as expected: {}",
+        early_height,
+        early_rejection
+    );
+
+    // Mine until the tip reaches H, then the same signed transaction is final
+    let blocks = u64::from(unlock_height - early_height);
+    session.advance(blocks, &session.miner_address)?;
+    say!(
+        "Mined {} block(s) to reach height {}.",
+        blocks,
+        unlock_height
+    );
+    if !chaintime::lock_time_satisfied(rpc, spend.lock_time)? {
+        return Err(CapstoneError::Usage(format!(
+            "the chain is at height {} and still below the lock {}",
+            rpc.get_block_count()?,
+            spend.lock_time
+        )));
+    }
+
+    accept::ensure_accepted(
+        rpc,
+        std::slice::from_ref(&hex),
+        config.max_broadcast_feerate(),
+    )?;
+    let spend_txid = raw::broadcast(rpc, &hex, config)?;
+    say!("Vault spend sent! TXID: {}", spend_txid);
+    let block_hash = session.generate(1, &session.miner_address)?[0];
+    let block_height = rpc.get_block_header_info(&block_hash)?.height;
+
+    let mut output_string = String::new();
+    output_string.push_str(&format!("Vault address: {}\n", vault_address));
+    output_string.push_str(&format!("Lock height: {}\n", unlock_height));
+    output_string.push_str(&format!(
+        "Funding transaction ID (txid): {}\n",
+        funding_txid
+    ));
+    output_string.push_str(&format!(
+        "Early spend at height {} rejected: {}\n",
+        early_height, early_rejection
+    ));
+    output_string.push_str(&format!("Spend transaction ID (txid): {}\n", spend_txid));
+    output_string.push_str(&format!(
+        "Spend fee (in {}): {}\n",
+        btc::unit(),
+        btc::format(fee)
+    ));
+    output_string.push_str(&format!(
+        "Block height at which the spend is confirmed: {}\n",
+        block_height
+    ));
+    output_string.push_str(&format!(
+        "Block hash at which the spend is confirmed: {}\n",
+        block_hash
+    ));
+    report::write_report(&output_string)
+}
@@ -0,0 +1,184 @@
+use crate::error::{CapstoneError, Result};
+use bitcoincore_rpc::{Client, RpcApi};
+use std::fmt;
+
+// getnetworkinfo's `version`: major * 10000 + minor * 100 + patch, e.g.
+// 260100 for 26.1.0, and 180100 for 0.18.1 before the leading 0 was dropped
+// with 22.0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CoreVersion(pub u64);
+
+impl CoreVersion {
+    pub const fn new(major: u64, minor: u64) -> Self {
+        CoreVersion(major * 10_000 + minor * 100)
+    }
+
+    pub fn major(self) -> u64 {
+        self.0 / 10_000
+    }
+
+    pub fn minor(self) -> u64 {
+        self.0 / 100 % 100
+    }
+
+    pub fn patch(self) -> u64 {
+        self.0 % 100
+    }
+}
+
+impl fmt::Display for CoreVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Releases were 0.x up to 0.21; the number's major is the x
+        match self.major() {
+            major if major >= 22 => write!(f, "{}.{}", major, self.minor())?,
+            major => write!(f, "0.{}.{}", major, self.minor())?,
+        }
+        if self.patch() != 0 {
+            write!(f, ".{}", self.patch())?;
+        }
+        Ok(())
+    }
+}
+
+// RPCs and options the modes use that not every supported node has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    // The wallet's `send` RPC
+    Send,
+    // getindexinfo
+    IndexInfo,
+    // bech32m change: tr() descriptors in the default wallet
+    TaprootWallet,
+    // getblock verbosity 3, which adds each input's prevout
+    BlockPrevouts,
+    // -mempoolfullrbf, reported by getmempoolinfo
+    FullRbfOption,
+    // walletprocesspsbt signing for miniscript descriptors
+    MiniscriptSigning,
+    // getprioritisedtransactions
+    ListPrioritised,
+    // loadtxoutset and getchainstates
+    AssumeUtxo,
+}
+
+impl Capability {
+    pub fn min_version(self) -> CoreVersion {
+        match self {
+            Capability::Send | Capability::IndexInfo => CoreVersion::new(21, 0),
+            Capability::TaprootWallet => CoreVersion::new(22, 0),
+            Capability::BlockPrevouts => CoreVersion::new(23, 0),
+            Capability::FullRbfOption => CoreVersion::new(24, 0),
+            Capability::MiniscriptSigning => CoreVersion::new(25, 0),
+            Capability::ListPrioritised | Capability::AssumeUtxo => CoreVersion::new(26, 0),
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            Capability::Send => "the send RPC",
+            Capability::IndexInfo => "getindexinfo",
+            Capability::TaprootWallet => "bech32m (taproot) wallet addresses",
+            Capability::BlockPrevouts => "getblock with prevouts (verbosity 3)",
+            Capability::FullRbfOption => "-mempoolfullrbf",
+            Capability::MiniscriptSigning => "signing for miniscript descriptors",
+            Capability::ListPrioritised => "getprioritisedtransactions",
+            Capability::AssumeUtxo => "assumeutxo snapshots",
+        }
+    }
+}
+
+pub fn node_version(rpc: &Client) -> Result<CoreVersion> {
+    Ok(CoreVersion(rpc.get_network_info()?.version as u64))
+}
+
+pub fn supports(rpc: &Client, capability: Capability) -> Result<bool> {
+    Ok(node_version(rpc)? >= capability.min_version())
+}
+
+// Fail with "X requires Core >= N" before the node fails with an unknown
+// method or parameter.
+pub fn require(rpc: &Client, capability: Capability) -> Result<()> {
+    let found = node_version(rpc)?;
+    if found < capability.min_version() {
+        return Err(CapstoneError::CoreTooOld {
+            feature: capability.describe().to_string(),
+            required: capability.min_version(),
+            found,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Capability; 8] = [
+        Capability::Send,
+        Capability::IndexInfo,
+        Capability::TaprootWallet,
+        Capability::BlockPrevouts,
+        Capability::FullRbfOption,
+        Capability::MiniscriptSigning,
+        Capability::ListPrioritised,
+        Capability::AssumeUtxo,
+    ];
+
+    #[test]
+    fn version_numbers() {
+        let cases = [
+            (180100, (18, 1, 0), "0.18.1"),
+            (210000, (21, 0, 0), "0.21.0"),
+            (220000, (22, 0, 0), "22.0"),
+            (240001, (24, 0, 1), "24.0.1"),
+            (250000, (25, 0, 0), "25.0"),
+            (270100, (27, 1, 0), "27.1"),
+        ];
+        for (number, (major, minor, patch), display) in cases {
+            let version = CoreVersion(number);
+            assert_eq!(
+                (version.major(), version.minor(), version.patch()),
+                (major, minor, patch)
+            );
+            assert_eq!(version.to_string(), display);
+        }
+        assert_eq!(CoreVersion::new(26, 1), CoreVersion(260100));
+    }
+
+    // The capabilities each version has
+    fn supported(version: CoreVersion) -> Vec<Capability> {
+        ALL.into_iter()
+            .filter(|capability| version >= capability.min_version())
+            .collect()
+    }
+
+    #[test]
+    fn capability_map() {
+        assert_eq!(supported(CoreVersion(200100)), vec![]);
+        assert_eq!(
+            supported(CoreVersion(210000)),
+            vec![Capability::Send, Capability::IndexInfo]
+        );
+        assert_eq!(
+            supported(CoreVersion(240001)),
+            vec![
+                Capability::Send,
+                Capability::IndexInfo,
+                Capability::TaprootWallet,
+                Capability::BlockPrevouts,
+                Capability::FullRbfOption,
+            ]
+        );
+        assert_eq!(
+            supported(CoreVersion(250000)),
+            [
+                supported(CoreVersion(240001)),
+                vec![Capability::MiniscriptSigning]
+            ]
+            .concat()
+        );
+        assert_eq!(supported(CoreVersion(270100)), ALL.to_vec());
+        // A patch release below a minor doesn't count
+        assert!(CoreVersion(259999) < Capability::AssumeUtxo.min_version());
+    }
+}
@@ -0,0 +1,265 @@
+use crate::automine;
+use crate::cli::Config;
+use crate::console::say;
+use crate::error::{CapstoneError, Result};
+use crate::mine::BlockWaiter;
+use crate::report::{self, TransactionReport};
+use crate::runinfo::RunInfo;
+use crate::session::Session;
+use bitcoincore_rpc::bitcoin::{Address, BlockHash, Txid};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+// The longest single wait for a block, so Ctrl-C is noticed within about this long
+const STOP_CHECK: Duration = Duration::from_secs(1);
+
+// Where the transaction stands: wallet confirmations (0 unconfirmed,
+// negative when conflicted) and the block that has it
+type State = (i32, Option<BlockHash>);
+
+// One line of JSON per event on stdout, for other tools to follow
+fn emit(event: serde_json::Value) {
+    say!("{}", event);
+}
+
+// The first of the Miner and Trader wallets that knows `txid`
+fn wallet_for<'a>(session: &'a Session, txid: &Txid) -> Result<&'a Client> {
+    for wallet_rpc in [&session.miner_rpc, &session.trader_rpc] {
+        if wallet_rpc.get_transaction(txid, None).is_ok() {
+            return Ok(wallet_rpc);
+        }
+    }
+    Err(CapstoneError::Usage(format!(
+        "neither the Miner nor the Trader wallet knows {}",
+        txid
+    )))
+}
+
+// The output paying the Trader wallet, which the report is built around
+fn trader_address(session: &Session, wallet_rpc: &Client, txid: &Txid) -> Result<Address> {
+    let tx = wallet_rpc.get_transaction(txid, None)?.transaction()?;
+    for output in &tx.output {
+        let Ok(address) = Address::from_script(&output.script_pubkey, session.network) else {
+            continue;
+        };
+        if session.trader_rpc.get_address_info(&address)?.is_mine == Some(true) {
+            return Ok(address);
+        }
+    }
+    Err(CapstoneError::Usage(format!(
+        "{} doesn't pay the Trader wallet, there is no report to keep up to date",
+        txid
+    )))
+}
+
+// Rewrite out.txt with the latest report of every watched transaction
+fn rewrite(reports: &BTreeMap<usize, TransactionReport>) -> Result<()> {
+    let texts: Vec<String> = reports.values().map(|report| report.to_text()).collect();
+    report::save_report(&texts.join("\n"))
+}
+
+// One transaction being watched. `slot` is its place in the list, and so of
+// its report in out.txt.
+struct Watched<'a> {
+    slot: usize,
+    txid: Txid,
+    wallet_rpc: &'a Client,
+    trader_address: Address,
+    last: Option<State>,
+    done: bool,
+}
+
+impl Watched<'_> {
+    // Say what changed since the last state, and rewrite the report while
+    // the transaction is confirmed. `reports` holds the latest report of
+    // every watched transaction that has one.
+    fn update(
+        &mut self,
+        rpc: &Client,
+        state: &State,
+        wanted: u32,
+        reports: &mut BTreeMap<usize, TransactionReport>,
+    ) -> Result<()> {
+        let txid = &self.txid;
+        let (confirmations, block_hash) = state;
+        if let Some((last_confirmations, last_block)) = &self.last {
+            let moved = last_block.is_some() && last_block != block_hash;
+            if moved || confirmations < last_confirmations {
+                eprintln!(
+                    "Reorg: {} went from {} to {} confirmation(s).",
+                    txid, last_confirmations, confirmations
+                );
+                emit(json!({
+                    "event": "reorg",
+                    "txid": txid,
+                    "from_confirmations": last_confirmations,
+                    "from_block_hash": last_block,
+                    "confirmations": confirmations,
+                    "block_hash": block_hash,
+                }));
+            }
+        }
+        match block_hash {
+            Some(block_hash) if *confirmations > 0 => {
+                let report =
+                    TransactionReport::extract(rpc, txid, block_hash, &self.trader_address)?;
+                let height = report.block_height;
+                reports.insert(self.slot, report);
+                rewrite(reports)?;
+                eprintln!(
+                    "{}: {} of {} confirmation(s), block {} at height {}; {} rewritten.",
+                    txid,
+                    confirmations,
+                    wanted,
+                    block_hash,
+                    height,
+                    report::REPORT_PATH
+                );
+                emit(json!({
+                    "event": "confirmed",
+                    "txid": txid,
+                    "confirmations": confirmations,
+                    "height": height,
+                    "block_hash": block_hash,
+                }));
+            }
+            _ if *confirmations < 0 => {
+                eprintln!(
+                    "{} is conflicted, a conflicting transaction is {} block(s) deep.",
+                    txid, -confirmations
+                );
+                emit(json!({
+                    "event": "conflicted",
+                    "txid": txid,
+                    "confirmations": confirmations,
+                }));
+            }
+            _ => {
+                eprintln!("{}: unconfirmed, waiting for a block.", txid);
+                emit(json!({ "event": "unconfirmed", "txid": txid }));
+            }
+        }
+        // A report written while it was confirmed now says it isn't
+        if *confirmations <= 0 {
+            if let Some(report) = reports.get_mut(&self.slot) {
+                report.refresh(rpc)?;
+                rewrite(reports)?;
+            }
+        }
+        self.last = Some(*state);
+        Ok(())
+    }
+}
+
+// watch: follow transactions block by block until each has --confirmations
+// (1 unless set) or Ctrl-C, rewriting out.txt on every change so it always
+// describes the blocks that confirm them now, reorgs included; with several
+// txids it holds their reports one after the other. Status goes to stderr,
+// stdout carries one JSON event per line.
+pub fn run(session: &Session, config: &Config, txids: &[Txid]) -> Result<()> {
+    let rpc = &session.rpc;
+    let mut watched = Vec::with_capacity(txids.len());
+    for (slot, txid) in txids.iter().enumerate() {
+        let wallet_rpc = wallet_for(session, txid)?;
+        watched.push(Watched {
+            slot,
+            txid: *txid,
+            wallet_rpc,
+            trader_address: trader_address(session, wallet_rpc, txid)?,
+            last: None,
+            done: false,
+        });
+    }
+    let wanted = config.confirmations();
+    automine::catch_ctrl_c();
+    match txids {
+        [txid] => eprintln!(
+            "Watching {} until {} confirmation(s); Ctrl-C to stop.",
+            txid, wanted
+        ),
+        _ => eprintln!(
+            "Watching {} transactions until {} confirmation(s) each; Ctrl-C to stop.",
+            txids.len(),
+            wanted
+        ),
+    }
+
+    let waiter = BlockWaiter::new(session, STOP_CHECK)?;
+    let mut tip = rpc.get_best_block_hash()?;
+    let mut reports = BTreeMap::new();
+    loop {
+        for watched in watched.iter_mut().filter(|watched| !watched.done) {
+            let txid = watched.txid;
+            let info = watched.wallet_rpc.get_transaction(&txid, None)?.info;
+            let state = (info.confirmations, info.blockhash);
+            if watched.last.as_ref() != Some(&state) {
+                watched.update(rpc, &state, wanted, &mut reports)?;
+            }
+            if info.confirmations >= wanted as i32 {
+                eprintln!("{} reached {} confirmation(s).", txid, wanted);
+                emit(json!({
+                    "event": "done",
+                    "txid": txid,
+                    "confirmations": info.confirmations,
+                }));
+                watched.done = true;
+            }
+        }
+        if watched.iter().all(|watched| watched.done) {
+            return Ok(());
+        }
+        if automine::stop_requested() {
+            for watched in watched.iter().filter(|watched| !watched.done) {
+                eprintln!("Stopped watching {}.", watched.txid);
+                emit(json!({
+                    "event": "stopped",
+                    "txid": watched.txid,
+                    "confirmations": watched.last.map(|(confirmations, _)| confirmations),
+                }));
+            }
+            return Ok(());
+        }
+        if let Some(new_tip) = waiter.wait(&tip, Instant::now() + STOP_CHECK)? {
+            tip = new_tip;
+            // Transactions that are done aren't extracted again, keep their
+            // confirmations and tip current in out.txt all the same
+            let mut refreshed = false;
+            for watched in watched.iter().filter(|watched| watched.done) {
+                if let Some(report) = reports.get_mut(&watched.slot) {
+                    report.refresh(rpc)?;
+                    refreshed = true;
+                }
+            }
+            if refreshed {
+                rewrite(&reports)?;
+            }
+        }
+    }
+}
+
+// report: write out.txt for transactions that have already confirmed, one
+// report after the other for several txids. Unconfirmed ones are skipped.
+pub fn run_report(session: &Session, config: &Config, txids: &[Txid]) -> Result<()> {
+    let mut reports = Vec::new();
+    for txid in txids {
+        let wallet_rpc = wallet_for(session, txid)?;
+        let info = wallet_rpc.get_transaction(txid, None)?.info;
+        let Some(block_hash) = info.blockhash.filter(|_| info.confirmations > 0) else {
+            eprintln!("{} is not confirmed, leaving it out of the report.", txid);
+            continue;
+        };
+        let trader_address = trader_address(session, wallet_rpc, txid)?;
+        let mut report =
+            TransactionReport::extract(&session.rpc, txid, &block_hash, &trader_address)?;
+        report.run = Some(RunInfo::collect(&session.rpc)?);
+        reports.push(report);
+    }
+    if reports.is_empty() {
+        return Err(CapstoneError::Usage(
+            "none of the transactions has confirmed, there is nothing to report".into(),
+        ));
+    }
+    report::write_transaction_reports(&reports, config)
+}
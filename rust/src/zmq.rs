@@ -0,0 +1,367 @@
+use crate::error::{CapstoneError, Result};
+use bitcoincore_rpc::bitcoin::hashes::Hash;
+use bitcoincore_rpc::bitcoin::{BlockHash, Txid};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+// How long the subscriber waits before connecting again after the socket drops
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// ZMTP frame flags
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+// One of the node's -zmqpub* endpoints, from getzmqnotifications.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ZmqNotification {
+    // "pubhashblock", "pubhashtx", ...
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub address: String,
+    pub hwm: u64,
+}
+
+pub fn notifications(rpc: &Client) -> Result<Vec<ZmqNotification>> {
+    Ok(rpc.call("getzmqnotifications", &[])?)
+}
+
+// --zmq: an endpoint such as tcp://127.0.0.1:28332, or "auto" for the one the
+// node publishes `kind` (e.g. "pubhashblock") on.
+pub fn resolve_address(rpc: &Client, address: &str, kind: &str) -> Result<String> {
+    if address != "auto" {
+        return Ok(address.to_string());
+    }
+    notifications(rpc)?
+        .into_iter()
+        .find(|notification| notification.kind == kind)
+        .map(|notification| notification.address)
+        .ok_or_else(|| {
+            CapstoneError::Usage(format!(
+                "--zmq auto: the node has no -zmq{} endpoint, start it with one or pass the address",
+                kind
+            ))
+        })
+}
+
+// A notification from the node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ZmqEvent {
+    HashBlock { hash: BlockHash, sequence: u32 },
+    HashTx { txid: Txid, sequence: u32 },
+    // The socket dropped, or the connection failed; the subscriber tries again
+    // after RECONNECT_DELAY. Notifications sent meanwhile are lost.
+    Disconnected(String),
+}
+
+// A hashblock/hashtx message: the topic, the 32-byte hash in the byte order
+// RPC displays it in, and a little-endian sequence number per topic. None for
+// other topics or malformed messages.
+pub fn parse_message(frames: &[Vec<u8>]) -> Option<ZmqEvent> {
+    let [topic, body, sequence] = frames else {
+        return None;
+    };
+    let sequence = u32::from_le_bytes(sequence.as_slice().try_into().ok()?);
+    let mut hash: [u8; 32] = body.as_slice().try_into().ok()?;
+    hash.reverse();
+    match topic.as_slice() {
+        b"hashblock" => Some(ZmqEvent::HashBlock {
+            hash: BlockHash::from_byte_array(hash),
+            sequence,
+        }),
+        b"hashtx" => Some(ZmqEvent::HashTx {
+            txid: Txid::from_byte_array(hash),
+            sequence,
+        }),
+        _ => None,
+    }
+}
+
+// The ZMTP 3.0 greeting of a client using the NULL mechanism: signature,
+// version 3.0, mechanism name padded to 20 bytes, as-server 0, filler.
+fn greeting() -> [u8; 64] {
+    let mut greeting = [0u8; 64];
+    greeting[0] = 0xff;
+    greeting[9] = 0x7f;
+    greeting[10] = 3;
+    greeting[12..16].copy_from_slice(b"NULL");
+    greeting
+}
+
+fn write_frame(stream: &mut impl Write, flags: u8, body: &[u8]) -> io::Result<()> {
+    match u8::try_from(body.len()) {
+        Ok(len) => stream.write_all(&[flags, len])?,
+        Err(_) => {
+            stream.write_all(&[flags | FLAG_LONG])?;
+            stream.write_all(&(body.len() as u64).to_be_bytes())?;
+        }
+    }
+    stream.write_all(body)
+}
+
+fn read_frame(stream: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut flags = [0u8; 1];
+    stream.read_exact(&mut flags)?;
+    let len = if flags[0] & FLAG_LONG != 0 {
+        let mut len = [0u8; 8];
+        stream.read_exact(&mut len)?;
+        u64::from_be_bytes(len) as usize
+    } else {
+        let mut len = [0u8; 1];
+        stream.read_exact(&mut len)?;
+        usize::from(len[0])
+    };
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body)?;
+    Ok((flags[0], body))
+}
+
+// The frames of the next message, skipping any commands in between
+fn read_message(stream: &mut impl Read) -> io::Result<Vec<Vec<u8>>> {
+    let mut frames = Vec::new();
+    loop {
+        let (flags, body) = read_frame(stream)?;
+        if flags & FLAG_COMMAND != 0 {
+            continue;
+        }
+        frames.push(body);
+        if flags & FLAG_MORE == 0 {
+            return Ok(frames);
+        }
+    }
+}
+
+// The peer's greeting, which must be ZMTP 3 or later
+fn read_greeting(stream: &mut impl Read) -> io::Result<()> {
+    let mut peer = [0u8; 64];
+    stream.read_exact(&mut peer)?;
+    if peer[0] != 0xff || peer[9] & 0x01 == 0 || peer[10] < 3 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the peer does not speak ZMTP 3",
+        ));
+    }
+    Ok(())
+}
+
+// Connect as a SUB socket and subscribe to `topics`
+fn connect(address: &str, topics: &[String]) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(address.trim_start_matches("tcp://"))?;
+    handshake(&mut stream, topics)?;
+    Ok(stream)
+}
+
+// Greetings, READY commands both ways, then one subscription message
+// (0x01 + topic) per topic
+fn handshake(stream: &mut (impl Read + Write), topics: &[String]) -> io::Result<()> {
+    stream.write_all(&greeting())?;
+    read_greeting(stream)?;
+
+    let mut ready = vec![5];
+    ready.extend_from_slice(b"READY");
+    ready.push(11);
+    ready.extend_from_slice(b"Socket-Type");
+    ready.extend_from_slice(&3u32.to_be_bytes());
+    ready.extend_from_slice(b"SUB");
+    write_frame(stream, FLAG_COMMAND, &ready)?;
+    let (flags, _) = read_frame(stream)?;
+    if flags & FLAG_COMMAND == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expected the peer's READY command",
+        ));
+    }
+
+    for topic in topics {
+        let mut subscription = vec![1];
+        subscription.extend_from_slice(topic.as_bytes());
+        write_frame(stream, 0, &subscription)?;
+    }
+    Ok(())
+}
+
+// Forward events until the connection fails or the receiver goes away
+fn forward(stream: &mut TcpStream, sender: &Sender<ZmqEvent>) -> io::Error {
+    loop {
+        match read_message(stream) {
+            Ok(frames) => {
+                if let Some(event) = parse_message(&frames) {
+                    if sender.send(event).is_err() {
+                        return io::Error::other("receiver dropped");
+                    }
+                }
+            }
+            Err(e) => return e,
+        }
+    }
+}
+
+// Subscribe to `topics` ("hashblock", "hashtx") at `address` on a background
+// thread, reconnecting whenever the socket drops, and deliver the events over
+// a channel. The thread ends once the receiver is dropped and it next has
+// something to send.
+pub fn subscribe(address: &str, topics: &[&str]) -> Receiver<ZmqEvent> {
+    let (sender, receiver) = mpsc::channel();
+    let address = address.to_string();
+    let topics: Vec<String> = topics.iter().map(|topic| topic.to_string()).collect();
+    thread::spawn(move || loop {
+        let error = match connect(&address, &topics) {
+            Ok(mut stream) => forward(&mut stream, &sender),
+            Err(e) => e,
+        };
+        if sender
+            .send(ZmqEvent::Disconnected(error.to_string()))
+            .is_err()
+        {
+            return;
+        }
+        thread::sleep(RECONNECT_DELAY);
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // A peer that answers with `input` and keeps what we send it
+    struct Peer {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Peer {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Peer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frame(flags: u8, body: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, flags, body).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn short_frames_have_a_one_byte_length() {
+        assert_eq!(frame(0, b"abc"), [0, 3, b'a', b'b', b'c']);
+        let body = [7u8; 255];
+        let bytes = frame(FLAG_MORE, &body);
+        assert_eq!(&bytes[..2], [FLAG_MORE, 255]);
+        assert_eq!(
+            read_frame(&mut &bytes[..]).unwrap(),
+            (FLAG_MORE, body.to_vec())
+        );
+    }
+
+    #[test]
+    fn long_frames_have_an_eight_byte_length() {
+        let body = vec![9u8; 300];
+        let bytes = frame(0, &body);
+        assert_eq!(bytes[0], FLAG_LONG);
+        assert_eq!(bytes[1..9], 300u64.to_be_bytes());
+        assert_eq!(bytes.len(), 9 + 300);
+        assert_eq!(read_frame(&mut &bytes[..]).unwrap(), (FLAG_LONG, body));
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let bytes = frame(0, b"hashblock");
+        let error = read_frame(&mut &bytes[..5]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        let error = read_frame(&mut &[FLAG_LONG, 0, 0][..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn more_flag_joins_frames_into_a_message() {
+        let hash = [0x11u8; 32];
+        let mut bytes = frame(FLAG_COMMAND, b"\x04PING");
+        bytes.extend(frame(FLAG_MORE, b"hashblock"));
+        bytes.extend(frame(FLAG_MORE, &hash));
+        bytes.extend(frame(0, &7u32.to_le_bytes()));
+        bytes.extend(frame(0, b"next"));
+        let mut stream = &bytes[..];
+
+        let frames = read_message(&mut stream).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(
+            parse_message(&frames),
+            Some(ZmqEvent::HashBlock {
+                hash: BlockHash::from_byte_array(hash),
+                sequence: 7,
+            })
+        );
+        assert_eq!(read_message(&mut stream).unwrap(), [b"next".to_vec()]);
+    }
+
+    #[test]
+    fn hashes_arrive_in_display_order() {
+        let mut body = [0u8; 32];
+        body[0] = 0xab;
+        let frames = [
+            b"hashtx".to_vec(),
+            body.to_vec(),
+            1u32.to_le_bytes().to_vec(),
+        ];
+        let Some(ZmqEvent::HashTx { txid, sequence }) = parse_message(&frames) else {
+            panic!("a hashtx message");
+        };
+        assert!(txid.to_string().starts_with("ab"));
+        assert_eq!(sequence, 1);
+        assert_eq!(parse_message(&[b"rawtx".to_vec(), vec![], vec![]]), None);
+        assert_eq!(parse_message(&frames[..2]), None);
+    }
+
+    #[test]
+    fn truncated_or_foreign_greetings_are_refused() {
+        let error = read_greeting(&mut &greeting()[..30]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        let mut old = greeting();
+        old[10] = 2;
+        let error = read_greeting(&mut &old[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(read_greeting(&mut &greeting()[..]).is_ok());
+    }
+
+    #[test]
+    fn handshake_subscribes_to_each_topic() {
+        let mut input = greeting().to_vec();
+        input.extend(frame(FLAG_COMMAND, b"\x05READY"));
+        let mut peer = Peer {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        let topics = ["hashblock".to_string(), "hashtx".to_string()];
+        handshake(&mut peer, &topics).unwrap();
+
+        let mut sent = &peer.output[..];
+        read_greeting(&mut sent).unwrap();
+        let (flags, ready) = read_frame(&mut sent).unwrap();
+        assert_eq!(flags, FLAG_COMMAND);
+        assert!(ready.starts_with(b"\x05READY\x0bSocket-Type"));
+        assert!(ready.ends_with(b"SUB"));
+        assert_eq!(
+            read_frame(&mut sent).unwrap(),
+            (0, b"\x01hashblock".to_vec())
+        );
+        assert_eq!(read_frame(&mut sent).unwrap(), (0, b"\x01hashtx".to_vec()));
+        assert!(sent.is_empty());
+    }
+}
@@ -1,4 +1,78 @@
-import { readFileSync } from "fs";
+import { spawn, spawnSync } from "child_process";
+import { mkdtempSync, readFileSync, writeFileSync } from "fs";
+import { tmpdir } from "os";
+import { join, resolve } from "path";
+
+// The readme's fields, in order, one per line of out.txt as "<label>: <value>"
+const LABELS = [
+    "Transaction ID (txid)",
+    "Miner's Input Address",
+    "Miner's Input Amount (in BTC)",
+    "Trader's Output Address",
+    "Trader's Output Amount (in BTC)",
+    "Miner's Change Address",
+    "Miner's Change Amount (in BTC)",
+    "Transaction Fees (in BTC)",
+    "Block height at which the transaction is confirmed",
+    "Block hash at which the transaction is confirmed",
+];
+
+// The binary run.sh built, for the modes beyond the payment
+const BIN = resolve(__dirname, '../rust/target/debug/rust');
+
+// Long enough for the modes that mine and wait on blocks
+const FLOW_TIMEOUT = 120_000;
+
+async function rpc(method: string, params: any[] = [], wallet?: string): Promise<any> {
+    const url = 'http://127.0.0.1:18443' + (wallet ? `/wallet/${wallet}` : '');
+    const response = await fetch(url, {
+        method: 'post',
+        body: JSON.stringify({ jsonrpc: '1.0', id: 'spec', method, params }),
+        headers: {
+            'Content-Type': 'text/plain',
+            'Authorization': 'Basic ' + Buffer.from('alice:password').toString('base64'),
+        }
+    });
+    const { result, error } = await response.json() as any;
+    if (error) throw new Error(`${method}: ${error.message}`);
+    return result;
+}
+
+// A directory of its own for each run, so the out.txt checked above stays as
+// the payment wrote it
+function scratch(): string {
+    return mkdtempSync(join(tmpdir(), 'capstone-'));
+}
+
+// Run a mode to completion
+function run(args: string[], cwd = scratch()) {
+    const result = spawnSync(BIN, args, { cwd, encoding: 'utf8', timeout: FLOW_TIMEOUT });
+    return { status: result.status, stdout: result.stdout, stderr: result.stderr, cwd };
+}
+
+// Start a long-running mode, collecting its output as it comes
+function start(args: string[], cwd = scratch()) {
+    const child = spawn(BIN, args, { cwd });
+    const started = {
+        child,
+        cwd,
+        stdout: '',
+        stderr: '',
+        exited: new Promise<number>(resolve => child.on('exit', resolve)),
+    };
+    child.stdout.on('data', chunk => { started.stdout += chunk; });
+    child.stderr.on('data', chunk => { started.stderr += chunk; });
+    return started;
+}
+
+// Wait until `found` says yes to what a started mode has written to stdout
+async function waitFor(started: ReturnType<typeof start>, found: (stdout: string) => boolean) {
+    const deadline = Date.now() + FLOW_TIMEOUT;
+    while (!found(started.stdout)) {
+        if (Date.now() > deadline) throw new Error(`timed out, stderr: ${started.stderr}`);
+        await new Promise(resolve => setTimeout(resolve, 100));
+    }
+}
 
 describe('Evaluate submission', () => {
     let txid: string;
@@ -11,12 +85,19 @@ describe('Evaluate submission', () => {
     let fee: number;
     let blockHeight: number;
     let blockHash: string;
+    let extras: string[];
     let tx: any;
 
     it('should read data from out.txt and perform sanity checks', () => {
-        // read txid from out.txt
-        const data = readFileSync('out.txt', 'utf8').trim().split('\n');
-        expect(data.length).toBe(10);
+        // read txid from out.txt: the readme's fields come first, the details
+        // after them
+        const lines = readFileSync('out.txt', 'utf8').trim().split('\n');
+        expect(lines.length).toBeGreaterThanOrEqual(LABELS.length);
+        const data = LABELS.map((label, i) => {
+            expect(lines[i].startsWith(`${label}: `)).toBe(true);
+            return lines[i].slice(label.length + 2);
+        });
+        extras = lines.slice(LABELS.length);
 
         txid = data[0].trim();
         expect(txid).toBeDefined();
@@ -57,6 +138,22 @@ describe('Evaluate submission', () => {
         expect(blockHash).toHaveLength(64);
     });
 
+    it('should report the size and fee rate after the required fields', () => {
+        expect(extras).toContainEqual(expect.stringMatching(/^Transaction vsize \(vB\): \d+$/));
+        expect(extras).toContainEqual(expect.stringMatching(/^Transaction weight \(WU\): \d+$/));
+        expect(extras).toContainEqual(expect.stringMatching(/^Fee rate \(sat\/vB\): \d+\.\d{2}$/));
+    });
+
+    it('should only have labeled details after the required fields', () => {
+        // A blank line and a "--- Run metadata ---" heading set the run
+        // metadata apart, everything else is "<label>: <value>"
+        for (const line of extras) {
+            expect(line === '' || /^--- .+ ---$/.test(line) || /^[^:]+: \S/.test(line)).toBe(true);
+        }
+        expect(extras).toContain('Number of inputs / outputs: 1 / 2');
+        expect(extras).toContain('UTXO verified: yes');
+    });
+
     it('should get transaction details from node', async () => {
         const RPC_USER = "alice";
         const RPC_PASSWORD = "password";
@@ -113,4 +210,183 @@ describe('Evaluate submission', () => {
     it('should have the correct fee', () => {
         expect(Math.abs(tx.fee)).toBe(fee);
     });
-});
\ No newline at end of file
+});
+
+describe('Parallel signing with combine-psbt', () => {
+    let psbt: string;
+    let traderAddress: string;
+
+    it('should confirm a PSBT the Miner and Trader signed separately', async () => {
+        // One input from each wallet, the whole amount less the fee to the Trader
+        const [minerUtxo] = await rpc('listunspent', [1], 'Miner');
+        const [traderUtxo] = await rpc('listunspent', [1], 'Trader');
+        expect(minerUtxo).toBeDefined();
+        expect(traderUtxo).toBeDefined();
+        traderAddress = await rpc('getnewaddress', [], 'Trader');
+        const amount = Number((minerUtxo.amount + traderUtxo.amount - 0.0001).toFixed(8));
+        psbt = await rpc('createpsbt', [
+            [minerUtxo, traderUtxo].map(({ txid, vout }) => ({ txid, vout })),
+            [{ [traderAddress]: amount }],
+        ]);
+
+        // Each wallet signs its own copy and only its own input
+        const dir = scratch();
+        const paths = [];
+        for (const wallet of ['Miner', 'Trader']) {
+            const signed = await rpc('walletprocesspsbt', [psbt, true, 'ALL', true, false], wallet);
+            expect(signed.complete).toBe(false);
+            const path = join(dir, `${wallet}.psbt`);
+            writeFileSync(path, signed.psbt);
+            paths.push(path);
+        }
+
+        const { status, stdout, stderr } = run(['combine-psbt', ...paths, '--no-file', '--format', 'json'], dir);
+        expect(stderr).toContain('Combined 2 PSBTs.');
+        expect(status).toBe(0);
+        const report = JSON.parse(stdout);
+        const tx = await rpc('gettransaction', [report.txid, null, true], 'Trader');
+        expect(tx.confirmations).toBeGreaterThan(0);
+        expect(tx.decoded.vin.length).toBe(2);
+        expect(tx.decoded.vout.length).toBe(1);
+        expect(tx.decoded.vout[0].scriptPubKey.address).toBe(traderAddress);
+    }, FLOW_TIMEOUT);
+
+    it('should name the file whose PSBT spends another transaction', async () => {
+        const dir = scratch();
+        const signed = join(dir, 'signed.psbt');
+        writeFileSync(signed, psbt);
+        const [utxo] = await rpc('listunspent', [1], 'Miner');
+        const other = join(dir, 'other.psbt');
+        writeFileSync(other, await rpc('createpsbt', [
+            [{ txid: utxo.txid, vout: utxo.vout }],
+            [{ [traderAddress]: 1 }],
+        ]));
+
+        const { status, stderr } = run(['combine-psbt', signed, other, '--no-file'], dir);
+        expect(status).not.toBe(0);
+        expect(stderr).toContain(`cannot read PSBT from ${other}: spends unsigned transaction`);
+    }, FLOW_TIMEOUT);
+});
+
+describe('Timelock demo with --empty-blocks', () => {
+    it('should reach the unlock height on empty blocks and leave the mempool alone', async () => {
+        // A stray payment no block template takes while its fee is written down
+        const stray = await rpc('sendtoaddress', [await rpc('getnewaddress', [], 'Trader'), 0.5], 'Trader');
+        await rpc('prioritisetransaction', [stray, 0, -100_000_000]);
+        const before = await rpc('getmempoolinfo');
+
+        const unlockHeight = (await rpc('getblockcount')) + 5;
+        const { status, stdout, stderr } = run(['vault-demo', '--unlock-height', `${unlockHeight}`, '--empty-blocks', '--no-file']);
+        expect(stderr).toContain(`to reach height ${unlockHeight}.`);
+        expect(status).toBe(0);
+        expect(stdout).toContain(`Lock height: ${unlockHeight}\n`);
+
+        // The blocks between the early attempt and the unlock height hold
+        // only their coinbase, and the stray is still waiting
+        const earlyHeight = parseInt(stdout.match(/^Early spend at height (\d+) rejected/m)[1]);
+        expect(earlyHeight).toBeLessThan(unlockHeight);
+        for (let height = earlyHeight + 1; height <= unlockHeight; height++) {
+            const block = await rpc('getblock', [await rpc('getblockhash', [height])]);
+            expect(block.nTx).toBe(1);
+        }
+        const after = await rpc('getmempoolinfo');
+        expect(after.size).toBe(before.size);
+        expect(await rpc('getrawmempool')).toContain(stray);
+
+        // Let the stray confirm so the modes below start from an empty mempool
+        await rpc('prioritisetransaction', [stray, 0, 100_000_000]);
+        await rpc('generatetoaddress', [1, await rpc('getnewaddress', [], 'Miner')]);
+    }, FLOW_TIMEOUT);
+});
+
+describe('verify-chain after the reorg demo', () => {
+    it('should find the chain consistent once the reorg has settled', async () => {
+        const reorg = run(['reorg-demo', '--no-file']);
+        expect(reorg.stderr).toContain('Moved from height');
+        expect(reorg.status).toBe(0);
+        const original = reorg.stdout.match(/^Original block hash at which the transaction was confirmed: (\w{64})$/m)[1];
+        const final = reorg.stdout.match(/^Final block hash at which the transaction is confirmed: (\w{64})$/m)[1];
+        expect(final).not.toBe(original);
+        expect(await rpc('getbestblockhash')).not.toBe(original);
+
+        // The whole active chain, now with the replacement blocks, at the
+        // most thorough level
+        const { status, stdout } = run(['verify-chain', '--checklevel', '4', '--nblocks', '0']);
+        expect(stdout).toContain('Warning: verifychain holds the node\'s validation lock');
+        expect(stdout).toMatch(/^Chain verification \(checklevel 4, all blocks\): passed$/m);
+        expect(stdout).toMatch(/^Elapsed time \(s\): \d+\.\d{3}$/m);
+        expect(status).toBe(0);
+    }, FLOW_TIMEOUT);
+});
+
+describe('stress with automine running alongside', () => {
+    it('should leave the mining to automine and see every payment confirmed', async () => {
+        const automine = start(['automine', '--interval', '1', '--max-blocks', '100']);
+        await waitFor(automine, stdout => stdout.includes('Ctrl-C to stop.'));
+
+        const dir = scratch();
+        const log = join(dir, 'txids.log');
+        const { status, stdout, stderr } = run(['stress', '--count', '10', '--external-miner', '--txid-log', log, '--no-file'], dir);
+        expect(stderr).not.toContain('Error');
+        expect(status).toBe(0);
+        expect(stdout).toContain('Transactions sent: 10 of 10\n');
+        expect(stdout).not.toContain('Failures');
+        // The Miner has long been funded, so stress never mined a block itself
+        expect(stdout).toContain('Blocks mined: 0\n');
+
+        const txids = readFileSync(log, 'utf8').trim().split('\n')
+            .map(line => line.split(' '))
+            .filter(([, , event]) => event === 'stress')
+            .map(([, txid]) => txid);
+        expect(txids).toHaveLength(10);
+        for (const txid of txids) {
+            const tx = await rpc('gettransaction', [txid], 'Miner');
+            expect(tx.confirmations).toBeGreaterThan(0);
+        }
+
+        automine.child.kill('SIGINT');
+        expect(await automine.exited).toBe(0);
+        expect(automine.stdout).toMatch(/^Mined block \w{64} at height \d+\.$/m);
+        expect(automine.stdout).toMatch(/^Stopped after mining \d+ block\(s\)\.$/m);
+    }, FLOW_TIMEOUT);
+});
+
+describe('watch across a reorg', () => {
+    // The JSON events among what watch has written so far, whole lines only
+    const events = (stdout: string) => stdout.split('\n').slice(0, -1)
+        .filter(line => line.startsWith('{'))
+        .map(line => JSON.parse(line));
+
+    it('should follow the payment out of an invalidated block and into its replacement', async () => {
+        const minerAddress = await rpc('getnewaddress', [], 'Miner');
+        const txid = await rpc('sendtoaddress', [await rpc('getnewaddress', [], 'Trader'), 1], 'Miner');
+        const [block] = await rpc('generatetoaddress', [1, minerAddress]);
+
+        const watch = start(['watch', txid, '--confirmations', '3']);
+        await waitFor(watch, stdout => events(stdout).some(event =>
+            event.event === 'confirmed' && event.block_hash === block && event.confirmations === 1));
+
+        // Back to the mempool, and watch says so
+        await rpc('invalidateblock', [block]);
+        await waitFor(watch, stdout => events(stdout).some(event => event.event === 'unconfirmed'));
+        const reorg = events(watch.stdout).find(event => event.event === 'reorg');
+        expect(reorg).toMatchObject({ txid, from_confirmations: 1, from_block_hash: block, confirmations: 0 });
+
+        // Confirmed again on the replacement chain, until it has enough
+        await rpc('generatetoaddress', [3, minerAddress]);
+        expect(await watch.exited).toBe(0);
+        const [done, confirmed] = [...events(watch.stdout)].reverse();
+        expect(done).toMatchObject({ event: 'done', txid, confirmations: 3 });
+        expect(confirmed).toMatchObject({ event: 'confirmed', txid, confirmations: 3 });
+        expect(confirmed.block_hash).not.toBe(block);
+
+        // out.txt now describes the replacement block
+        const report = readFileSync(join(watch.cwd, 'out.txt'), 'utf8');
+        expect(report).toContain(`Block hash at which the transaction is confirmed: ${confirmed.block_hash}\n`);
+        expect(report).toContain('Confirmations at report time: 3\n');
+
+        // The longer replacement chain stays the tip
+        await rpc('reconsiderblock', [block]);
+        expect(await rpc('getbestblockhash')).not.toBe(block);
+    }, FLOW_TIMEOUT);
+});